@@ -0,0 +1,58 @@
+//! Batch decoding of small gzip objects against a decoder per object.
+//!
+//! `cargo bench --bench gzip_batch`
+#![allow(clippy::needless_return)]
+use std::io::{Read, Write};
+use std::time::{Duration, Instant};
+use final_compression::GzipBatchDecoder;
+
+const OBJECTS: usize = 20_000;
+
+fn objects() -> Vec<Vec<u8>> {
+    return (0..OBJECTS).map(|i| {
+        let line = format!("2024-01-01T00:00:{:02}Z GET /object/{} 200 {}\n", i % 60, i, i * 31 % 9973);
+        let mut encoder = flate2::write::GzEncoder::new(Vec::new(), flate2::Compression::new(6));
+        encoder.write_all(line.repeat(1 + i % 8).as_bytes()).unwrap();
+        encoder.finish().unwrap()
+    }).collect();
+}
+
+/// Best of 5 runs
+fn measure(name: &str, mut run: impl FnMut() -> usize) {
+    let mut best = Duration::MAX;
+    let mut bytes = 0;
+    for _ in 0..5 {
+        let started = Instant::now();
+        bytes = run();
+        best = best.min(started.elapsed());
+    }
+    println!("{:<24} {:>8.1} ms  {:>8.0} objects/s  ({} bytes)", name, best.as_secs_f64() * 1000.0,
+        OBJECTS as f64 / best.as_secs_f64(), bytes);
+}
+
+fn main() {
+    let inputs = objects();
+    measure("GzDecoder per object", || {
+        let mut total = 0;
+        for input in &inputs {
+            let mut plain = Vec::new();
+            flate2::read::GzDecoder::new(&input[..]).read_to_end(&mut plain).unwrap();
+            total += plain.len();
+        }
+        total
+    });
+    measure("GzipBatchDecoder", || {
+        let mut total = 0;
+        let failures = GzipBatchDecoder::new().decode_many(inputs.iter().cloned(), |_, data| total += data.len());
+        assert!(failures.is_empty());
+        total
+    });
+    measure("decode_many_parallel", || {
+        let total = std::sync::atomic::AtomicUsize::new(0);
+        let failures = GzipBatchDecoder::decode_many_parallel(&inputs, 0, |_, data| {
+            total.fetch_add(data.len(), std::sync::atomic::Ordering::Relaxed);
+        });
+        assert!(failures.is_empty());
+        total.into_inner()
+    });
+}
@@ -0,0 +1,219 @@
+use std::error::Error;
+use std::io::Write;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use crate::clock::{Clock, SystemClock};
+use crate::ParamSet;
+
+/// Default `level_ladder` of `adaptive_writer`
+pub const DEFAULT_LEVEL_LADDER: &str = "19,15,9,5,3,1";
+/// Default `segment_size` of `adaptive_writer`
+pub const DEFAULT_SEGMENT_SIZE: u64 = 4 * 1024 * 1024;
+
+/// A level step taken by `AdaptiveWriter`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct LevelChange {
+    /// Uncompressed bytes written before the change
+    pub at_input: u64,
+    /// Time since the writer was created
+    pub elapsed: Duration,
+    /// Projected total duration at the old level that triggered the change
+    pub projected: Duration,
+    pub from: i32,
+    pub to: i32,
+}
+
+/// zstd writer stepping its level down when it would miss a deadline, see `adaptive_writer`
+pub struct AdaptiveWriter {
+    encoder: Option<zstd::Encoder<'static, Box<dyn Write>>>,
+    clock: Arc<dyn Clock>,
+    started: Instant,
+    deadline: Duration,
+    estimated_input: u64,
+    ladder: Vec<i32>,
+    rung: usize,
+    segment_size: u64,
+    segment_written: u64,
+    total_in: u64,
+    changes: Vec<LevelChange>,
+}
+
+/// zstd writer for a fixed time window: it starts at the first level of the ladder and, at
+/// segment boundaries, steps down the ladder while the throughput measured so far projects
+/// finishing `estimated_input` bytes after the deadline.
+///
+/// Parameters:
+///     adaptive_deadline=seconds (required)
+///     estimated_input=bytes (required)
+///     level_ladder=19,15,9,5,3,1 (levels to descend, from the first)
+///     segment_size=bytes (uncompressed bytes per frame, default 4 MiB)
+///
+/// A level can only change between frames, so every segment is a zstd frame of its own; the
+/// output is a concatenation of frames that any zstd decoder reads.
+pub fn adaptive_writer<T: Into<ParamSet>>(out: Box<dyn Write>, option: T) -> Result<AdaptiveWriter, Box<dyn Error>> {
+    return adaptive_writer_with_clock(out, option, Arc::new(SystemClock));
+}
+
+/// Like `adaptive_writer`, but reading time from `clock`.
+pub fn adaptive_writer_with_clock<T: Into<ParamSet>>(out: Box<dyn Write>, option: T, clock: Arc<dyn Clock>) -> Result<AdaptiveWriter, Box<dyn Error>> {
+    let param_set: ParamSet = option.into();
+    let seconds: f64 = param_set.get_string("adaptive_deadline", "").parse()
+        .map_err(|_| "adaptive_deadline must be a number of seconds")?;
+    let deadline = Duration::try_from_secs_f64(seconds).map_err(|_| "adaptive_deadline must be a number of seconds")?;
+    let estimated_input = param_set.get_size("estimated_input", 0)?;
+    if estimated_input == 0 {
+        return Err("estimated_input must be a positive number of bytes".into());
+    }
+    let ladder = param_set.get_string("level_ladder", DEFAULT_LEVEL_LADDER).split(',')
+        .map(|level| level.trim().parse::<i32>())
+        .collect::<Result<Vec<i32>, _>>()
+        .map_err(|_| "level_ladder must be a comma separated list of levels")?;
+    if ladder.is_empty() {
+        return Err("level_ladder must not be empty".into());
+    }
+    let segment_size = param_set.get_size("segment_size", DEFAULT_SEGMENT_SIZE)?.max(1);
+    let encoder = zstd::Encoder::new(out, ladder[0])?;
+    return Ok(AdaptiveWriter {
+        encoder: Some(encoder),
+        started: clock.now(),
+        clock,
+        deadline,
+        estimated_input,
+        ladder,
+        rung: 0,
+        segment_size,
+        segment_written: 0,
+        total_in: 0,
+        changes: Vec::new(),
+    });
+}
+
+impl AdaptiveWriter {
+    /// Level used for the current frame
+    pub fn level(&self) -> i32 {
+        return self.ladder[self.rung];
+    }
+
+    /// Every level change so far, oldest first
+    pub fn changes(&self) -> &[LevelChange] {
+        return &self.changes;
+    }
+
+    fn encoder(&mut self) -> std::io::Result<&mut zstd::Encoder<'static, Box<dyn Write>>> {
+        return self.encoder.as_mut().ok_or_else(|| std::io::Error::other("encoder lost by a failed frame boundary"));
+    }
+
+    /// Finish the frame and start the next, one rung lower if the deadline is at risk
+    fn end_segment(&mut self) -> std::io::Result<()> {
+        self.segment_written = 0;
+        let elapsed = self.clock.now().saturating_duration_since(self.started);
+        let remaining = self.estimated_input.saturating_sub(self.total_in);
+        let projected = elapsed + elapsed.mul_f64(remaining as f64 / self.total_in as f64);
+        let out = self.encoder.take().unwrap().finish()?;
+        if projected > self.deadline && self.rung + 1 < self.ladder.len() {
+            self.changes.push(LevelChange {
+                at_input: self.total_in,
+                elapsed,
+                projected,
+                from: self.ladder[self.rung],
+                to: self.ladder[self.rung + 1],
+            });
+            self.rung += 1;
+        }
+        self.encoder = Some(zstd::Encoder::new(out, self.ladder[self.rung])?);
+        return Ok(());
+    }
+}
+
+impl Write for AdaptiveWriter {
+    fn write(&mut self, data: &[u8]) -> std::io::Result<usize> {
+        let room = (self.segment_size - self.segment_written).min(data.len() as u64) as usize;
+        let written = self.encoder()?.write(&data[..room])?;
+        self.segment_written += written as u64;
+        self.total_in += written as u64;
+        if self.segment_written == self.segment_size {
+            self.end_segment()?;
+        }
+        return Ok(written);
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        return self.encoder()?.flush();
+    }
+}
+
+impl Drop for AdaptiveWriter {
+    fn drop(&mut self) {
+        if let Some(encoder) = self.encoder.take() {
+            let _ = encoder.finish();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::clock::ManualClock;
+
+    /// Sink taking `per_byte` of simulated time for every byte
+    struct SlowSink {
+        clock: Arc<ManualClock>,
+        per_byte: Duration,
+        data: crate::buffer::SharedBuffer,
+    }
+
+    impl Write for SlowSink {
+        fn write(&mut self, data: &[u8]) -> std::io::Result<usize> {
+            self.clock.advance(self.per_byte * data.len() as u32);
+            return self.data.write(data);
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            return Ok(());
+        }
+    }
+
+    fn input() -> Vec<u8> {
+        return (0..1024 * 1024u32).map(|i| ((i % 251) ^ (i / 4093)) as u8).collect();
+    }
+
+    fn run(per_byte: Duration) -> (Vec<LevelChange>, Vec<u8>) {
+        let clock = Arc::new(ManualClock::new());
+        let data = crate::buffer::SharedBuffer::default();
+        let sink = SlowSink { clock: clock.clone(), per_byte, data: data.clone() };
+        let options = "adaptive_deadline=1;estimated_input=1048576;level_ladder=5,3,1;segment_size=131072";
+        let mut writer = adaptive_writer_with_clock(Box::new(sink), options, clock.clone()).unwrap();
+        for chunk in input().chunks(10_000) {
+            writer.write_all(chunk).unwrap();
+        }
+        let changes = writer.changes().to_vec();
+        drop(writer);
+        return (changes, data.take());
+    }
+
+    #[test]
+    pub fn test_ladder_descended() {
+        let (changes, compressed) = run(Duration::from_millis(1));
+        assert_eq!(changes.iter().map(|c| (c.from, c.to)).collect::<Vec<_>>(), vec![(5, 3), (3, 1)]);
+        for (i, change) in changes.iter().enumerate() {
+            assert_eq!(change.at_input % 131072, 0);
+            assert!(change.projected > Duration::from_secs(1));
+            // the simulated time is the output written so far, at one millisecond a byte
+            let expected = change.elapsed.mul_f64(1048576.0 / change.at_input as f64);
+            assert!(change.projected.abs_diff(expected) < Duration::from_millis(1), "{:?}", change);
+            if i > 0 {
+                assert!(change.elapsed > changes[i - 1].elapsed);
+            }
+        }
+        assert_eq!(zstd::decode_all(&compressed[..]).unwrap(), input());
+    }
+
+    #[test]
+    pub fn test_fast_sink_keeps_level() {
+        let (changes, compressed) = run(Duration::ZERO);
+        assert!(changes.is_empty());
+        assert_eq!(zstd::decode_all(&compressed[..]).unwrap(), input());
+        assert!(adaptive_writer(Box::new(std::io::sink()), "estimated_input=10").is_err());
+        assert!(adaptive_writer(Box::new(std::io::sink()), "adaptive_deadline=5;estimated_input=10;level_ladder=9,x").is_err());
+    }
+}
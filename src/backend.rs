@@ -0,0 +1,134 @@
+use std::ffi::CStr;
+use std::os::raw::c_char;
+use crate::CompressionType;
+
+/// How the library implementing a codec got into the binary
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Linking {
+    /// C library built from bundled source and linked statically
+    Static,
+    /// System C library, linked dynamically
+    Dynamic,
+    /// System C library if the build found one, otherwise built from bundled source
+    SystemIfFound,
+    /// Implemented in Rust, nothing to link
+    PureRust,
+}
+
+/// Library behind one built-in codec
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct BackendInfo {
+    pub compression_type: CompressionType,
+    /// Rust crate implementing the codec
+    pub backend_crate: &'static str,
+    /// Version reported at runtime by the C library, None for pure Rust backends
+    pub library_version: Option<String>,
+    /// Recorded from the cargo features at compile time
+    pub linking: Linking,
+}
+
+extern "C" {
+    // provided by libbz2, which bzip2-sys links but does not declare
+    fn BZ2_bzlibVersion() -> *const c_char;
+}
+
+fn c_version(version: *const c_char) -> Option<String> {
+    if version.is_null() {
+        return None;
+    }
+    // SAFETY: the libraries return a pointer to a static NUL terminated string
+    let version = unsafe { CStr::from_ptr(version) };
+    return Some(version.to_string_lossy().into_owned());
+}
+
+fn lz4_version() -> String {
+    let number = lz4::version();
+    return format!("{}.{}.{}", number / 10000, number / 100 % 100, number % 100);
+}
+
+fn zstd_linking() -> Linking {
+    if cfg!(feature = "zstd-pkg-config") {
+        return Linking::Dynamic;
+    }
+    return Linking::Static;
+}
+
+fn static_or_system(forced_static: bool) -> Linking {
+    if forced_static {
+        return Linking::Static;
+    }
+    return Linking::SystemIfFound;
+}
+
+/// Report, for every built-in codec, which library implements it, the version of the C
+/// library actually loaded, and how it was linked.
+///
+/// The cargo features `zstd-pkg-config`, `xz-static` and `bzip2-static` select the linking.
+pub fn backend_info() -> Vec<BackendInfo> {
+    // SAFETY: version queries without arguments or preconditions
+    let (lzma, bzip2) = unsafe { (lzma_sys::lzma_version_string(), BZ2_bzlibVersion()) };
+    // libbz2 reports e.g. "1.0.8, 13-Jul-2019"
+    let bzip2 = c_version(bzip2).map(|v| v.split(',').next().unwrap_or_default().to_string());
+    let rust = |compression_type, backend_crate| BackendInfo {
+        compression_type,
+        backend_crate,
+        library_version: None,
+        linking: Linking::PureRust,
+    };
+    return vec![
+        BackendInfo {
+            compression_type: CompressionType::Zstd,
+            backend_crate: "zstd",
+            library_version: Some(zstd::zstd_safe::version_string().to_string()),
+            linking: zstd_linking(),
+        },
+        rust(CompressionType::Snappy, "snap"),
+        rust(CompressionType::Gzip, "flate2"),
+        rust(CompressionType::Zlib, "flate2"),
+        rust(CompressionType::Deflate, "flate2"),
+        BackendInfo {
+            compression_type: CompressionType::Bzip2,
+            backend_crate: "bzip2",
+            library_version: bzip2,
+            linking: static_or_system(cfg!(feature = "bzip2-static")),
+        },
+        BackendInfo {
+            compression_type: CompressionType::LZ4,
+            backend_crate: "lz4",
+            library_version: Some(lz4_version()),
+            linking: Linking::Static,
+        },
+        BackendInfo {
+            compression_type: CompressionType::XZ,
+            backend_crate: "xz2",
+            library_version: c_version(lzma),
+            linking: static_or_system(cfg!(feature = "xz-static")),
+        },
+        rust(CompressionType::Stored, "final_compression"),
+        rust(CompressionType::Brotli, "brotli"),
+        rust(CompressionType::Lzo, "rust-lzo"),
+    ];
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::describe::BUILTIN_CODECS;
+
+    fn is_semver_like(version: &str) -> bool {
+        let parts: Vec<&str> = version.split('.').collect();
+        return parts.len() == 3 && parts.iter().all(|p| !p.is_empty() && p.chars().all(|c| c.is_ascii_digit()));
+    }
+
+    #[test]
+    pub fn test_versions() {
+        let info = backend_info();
+        for backend in info.iter().filter(|b| b.linking != Linking::PureRust) {
+            let version = backend.library_version.as_deref().unwrap_or_default();
+            assert!(is_semver_like(version), "{:?} reports version {:?}", backend.compression_type, version);
+        }
+        for codec in BUILTIN_CODECS.iter().filter(|c| c.compression_type != CompressionType::None) {
+            assert!(info.iter().any(|b| b.compression_type == codec.compression_type), "{} not reported", codec.name);
+        }
+    }
+}
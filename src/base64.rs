@@ -0,0 +1,383 @@
+use std::io::{ErrorKind, Read, Write};
+
+/// Line length of MIME (RFC 2045) base64, for `Base64Writer::new`
+pub const MIME_LINE_LENGTH: usize = 76;
+
+/// Input encoded per chunk, 1024 characters
+const CHUNK: usize = 768;
+/// Encoded bytes read from the source at a time
+const INPUT_CHUNK: usize = 4096;
+const INVALID: u8 = 0xff;
+
+/// Base64 alphabets of RFC 4648
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Alphabet {
+    /// `A-Z a-z 0-9 + /`
+    Standard,
+    /// `A-Z a-z 0-9 - _`, safe in URLs and file names
+    UrlSafe,
+}
+
+impl Alphabet {
+    fn symbols(&self) -> &'static [u8; 64] {
+        return match self {
+            Alphabet::Standard => b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/",
+            Alphabet::UrlSafe => b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789-_",
+        };
+    }
+
+    /// Value of every byte, `INVALID` for those outside the alphabet
+    fn values(&self) -> [u8; 256] {
+        let mut values = [INVALID; 256];
+        for (value, symbol) in self.symbols().iter().enumerate() {
+            values[*symbol as usize] = value as u8;
+        }
+        return values;
+    }
+}
+
+/// Writer encoding what it is given as padded base64 into `out`, to embed compressed data in
+/// JSON, YAML or mail: `compressed_writer(Box::new(Base64Writer::new(..)), ..)`.
+///
+/// Only the up to 2 bytes that do not make a full group yet are kept between writes. With a
+/// `line_length`, like `MIME_LINE_LENGTH`, lines are separated by CRLF, and the output does not
+/// end with one. `finish`, or dropping, writes the last group.
+pub struct Base64Writer {
+    out: Box<dyn Write>,
+    symbols: &'static [u8; 64],
+    line_length: usize,
+    column: usize,
+    carry: [u8; 2],
+    carry_len: usize,
+    /// Characters of the chunk being written, reused
+    encoded: Vec<u8>,
+    finished: bool,
+}
+
+impl Base64Writer {
+    /// Writer into `out`, with lines of `line_length` characters or 0 for a single line
+    pub fn new(out: Box<dyn Write>, alphabet: Alphabet, line_length: usize) -> Base64Writer {
+        return Base64Writer {
+            out,
+            symbols: alphabet.symbols(),
+            line_length,
+            column: 0,
+            carry: [0; 2],
+            carry_len: 0,
+            encoded: Vec::new(),
+            finished: false,
+        };
+    }
+
+    fn push(&mut self, symbol: u8) {
+        if self.line_length > 0 && self.column == self.line_length {
+            self.encoded.extend_from_slice(b"\r\n");
+            self.column = 0;
+        }
+        self.encoded.push(symbol);
+        self.column += 1;
+    }
+
+    fn push_group(&mut self, group: &[u8]) {
+        let bits = (group[0] as u32) << 16 | (*group.get(1).unwrap_or(&0) as u32) << 8 | *group.get(2).unwrap_or(&0) as u32;
+        for i in 0..4 {
+            if i <= group.len() {
+                self.push(self.symbols[(bits >> (18 - 6 * i) & 63) as usize]);
+            } else {
+                self.push(b'=');
+            }
+        }
+    }
+
+    fn write_encoded(&mut self) -> std::io::Result<()> {
+        self.out.write_all(&self.encoded)?;
+        self.encoded.clear();
+        return Ok(());
+    }
+
+    /// Write the last group, padded, and flush `out`. Later writes fail; calling it again is a
+    /// no-op.
+    pub fn finish(&mut self) -> std::io::Result<()> {
+        if self.finished {
+            return Ok(());
+        }
+        self.finished = true;
+        if self.carry_len > 0 {
+            let carry = self.carry;
+            self.push_group(&carry[..self.carry_len]);
+            self.carry_len = 0;
+            self.write_encoded()?;
+        }
+        return self.out.flush();
+    }
+}
+
+impl Write for Base64Writer {
+    fn write(&mut self, data: &[u8]) -> std::io::Result<usize> {
+        if self.finished {
+            return Err(std::io::Error::other("base64 stream already finished"));
+        }
+        let mut rest = data;
+        if self.carry_len > 0 {
+            let taken = rest.len().min(3 - self.carry_len);
+            if self.carry_len + taken < 3 {
+                self.carry[self.carry_len..self.carry_len + taken].copy_from_slice(&rest[..taken]);
+                self.carry_len += taken;
+                return Ok(data.len());
+            }
+            let mut group = [0u8; 3];
+            group[..self.carry_len].copy_from_slice(&self.carry[..self.carry_len]);
+            group[self.carry_len..].copy_from_slice(&rest[..taken]);
+            self.push_group(&group);
+            self.carry_len = 0;
+            rest = &rest[taken..];
+        }
+        let whole = rest.len() - rest.len() % 3;
+        for chunk in rest[..whole].chunks(CHUNK) {
+            for group in chunk.chunks(3) {
+                self.push_group(group);
+            }
+            self.write_encoded()?;
+        }
+        self.write_encoded()?;
+        self.carry_len = rest.len() - whole;
+        self.carry[..self.carry_len].copy_from_slice(&rest[whole..]);
+        return Ok(data.len());
+    }
+
+    /// Flush what makes whole groups; a carried partial group is only written by `finish`
+    fn flush(&mut self) -> std::io::Result<()> {
+        return self.out.flush();
+    }
+}
+
+impl Drop for Base64Writer {
+    fn drop(&mut self) {
+        if std::thread::panicking() {
+            return;
+        }
+        let _ = self.finish();
+    }
+}
+
+/// Reader decoding the base64 of `src`, for `decompressed_reader(Box::new(Base64Reader::new(..)), ..)`.
+///
+/// Strict, it fails with `ErrorKind::InvalidData` on any byte outside the alphabet, line breaks
+/// included, on missing padding and on data after it, naming the offset of the byte in the
+/// encoded stream. Lenient, it skips whitespace and other bytes outside the alphabet, accepts
+/// unpadded input and ignores what follows the padding.
+pub struct Base64Reader {
+    src: Box<dyn Read>,
+    values: [u8; 256],
+    strict: bool,
+    input: Vec<u8>,
+    /// Offset in the encoded stream of `input[0]`
+    input_offset: u64,
+    quad: [u8; 4],
+    quad_len: usize,
+    /// `=` seen in the current group
+    equals: usize,
+    /// The padding ended the data
+    ended: bool,
+    source_done: bool,
+    output: Vec<u8>,
+    pos: usize,
+}
+
+impl Base64Reader {
+    pub fn new(src: Box<dyn Read>, alphabet: Alphabet, strict: bool) -> Base64Reader {
+        return Base64Reader {
+            src,
+            values: alphabet.values(),
+            strict,
+            input: vec![0u8; INPUT_CHUNK],
+            input_offset: 0,
+            quad: [0; 4],
+            quad_len: 0,
+            equals: 0,
+            ended: false,
+            source_done: false,
+            output: Vec::with_capacity(INPUT_CHUNK / 4 * 3),
+            pos: 0,
+        };
+    }
+
+    fn invalid(what: &str, offset: u64) -> std::io::Error {
+        return std::io::Error::new(ErrorKind::InvalidData, format!("base64 {} at offset {}", what, offset));
+    }
+
+    /// Append the bytes of the `quad_len` characters of the group
+    fn emit_quad(&mut self) {
+        let bits = self.quad.iter().fold(0u32, |bits, value| bits << 6 | *value as u32);
+        let bytes = bits.to_be_bytes();
+        self.output.extend_from_slice(&bytes[1..self.quad_len]);
+        self.quad = [0; 4];
+        self.quad_len = 0;
+    }
+
+    fn decode(&mut self, len: usize) -> std::io::Result<()> {
+        for i in 0..len {
+            let byte = self.input[i];
+            let offset = self.input_offset + i as u64;
+            let value = self.values[byte as usize];
+            if self.ended {
+                if self.strict {
+                    return Err(Self::invalid(&format!("byte {:#04x} after the padding", byte), offset));
+                }
+                continue;
+            }
+            if byte == b'=' {
+                if self.quad_len + self.equals < 2 {
+                    return Err(Self::invalid("padding after fewer than 2 characters", offset));
+                }
+                self.equals += 1;
+                if self.quad_len + self.equals == 4 {
+                    self.emit_quad();
+                    self.ended = true;
+                }
+            } else if value == INVALID {
+                if self.strict {
+                    return Err(Self::invalid(&format!("invalid byte {:#04x}", byte), offset));
+                }
+            } else if self.equals > 0 {
+                return Err(Self::invalid("data inside the padding", offset));
+            } else {
+                self.quad[self.quad_len] = value;
+                self.quad_len += 1;
+                if self.quad_len == 4 {
+                    self.emit_quad();
+                }
+            }
+        }
+        self.input_offset += len as u64;
+        return Ok(());
+    }
+
+    /// Check the end of the encoded stream, decoding an unpadded last group when lenient
+    fn end(&mut self) -> std::io::Result<()> {
+        self.source_done = true;
+        if self.ended || (self.quad_len == 0 && self.equals == 0) {
+            return Ok(());
+        }
+        if self.quad_len == 1 {
+            return Err(Self::invalid("stream ends with a single character group", self.input_offset));
+        }
+        if self.strict {
+            return Err(Self::invalid("stream ends without padding", self.input_offset));
+        }
+        self.emit_quad();
+        return Ok(());
+    }
+}
+
+impl Read for Base64Reader {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        while self.pos == self.output.len() {
+            if self.source_done || buf.is_empty() {
+                return Ok(0);
+            }
+            self.output.clear();
+            self.pos = 0;
+            let read = match self.src.read(&mut self.input) {
+                Ok(read) => read,
+                Err(e) if e.kind() == ErrorKind::Interrupted => continue,
+                Err(e) => return Err(e),
+            };
+            if read == 0 {
+                self.end()?;
+            } else {
+                self.decode(read)?;
+            }
+        }
+        let n = buf.len().min(self.output.len() - self.pos);
+        buf[..n].copy_from_slice(&self.output[self.pos..self.pos + n]);
+        self.pos += n;
+        return Ok(n);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::buffer::SharedBuffer;
+    use crate::{compressed_writer, decompressed_reader, CompressionType};
+
+    fn encode(data: &[u8], alphabet: Alphabet, line_length: usize) -> Vec<u8> {
+        let out = SharedBuffer::default();
+        let mut w = Base64Writer::new(Box::new(out.clone()), alphabet, line_length);
+        w.write_all(data).unwrap();
+        w.finish().unwrap();
+        return out.take();
+    }
+
+    fn decode(encoded: &[u8], alphabet: Alphabet, strict: bool) -> std::io::Result<Vec<u8>> {
+        let mut plain = Vec::new();
+        Base64Reader::new(Box::new(std::io::Cursor::new(encoded.to_vec())), alphabet, strict).read_to_end(&mut plain)?;
+        return Ok(plain);
+    }
+
+    #[test]
+    pub fn test_vectors() {
+        let vectors: [(&[u8], &[u8]); 7] = [
+            (b"", b""), (b"f", b"Zg=="), (b"fo", b"Zm8="), (b"foo", b"Zm9v"),
+            (b"foob", b"Zm9vYg=="), (b"fooba", b"Zm9vYmE="), (b"foobar", b"Zm9vYmFy"),
+        ];
+        for (plain, encoded) in vectors {
+            assert_eq!(encode(plain, Alphabet::Standard, 0), encoded);
+            assert_eq!(decode(encoded, Alphabet::Standard, true).unwrap(), plain);
+        }
+        assert_eq!(encode(&[0xfb, 0xff], Alphabet::Standard, 0), b"+/8=");
+        assert_eq!(encode(&[0xfb, 0xff], Alphabet::UrlSafe, 0), b"-_8=");
+        assert!(decode(b"+/8=", Alphabet::UrlSafe, true).is_err());
+
+        // byte at a time gives the same as all at once
+        let data: Vec<u8> = (0..1000u32).map(|i| (i * 7 % 256) as u8).collect();
+        let out = SharedBuffer::default();
+        let mut w = Base64Writer::new(Box::new(out.clone()), Alphabet::Standard, MIME_LINE_LENGTH);
+        for byte in &data {
+            w.write_all(&[*byte]).unwrap();
+        }
+        drop(w);
+        assert_eq!(out.take(), encode(&data, Alphabet::Standard, MIME_LINE_LENGTH));
+
+        assert!(decode(b"Zm9vY", Alphabet::Standard, false).is_err());
+        assert!(decode(b"Zm9vYg", Alphabet::Standard, true).is_err());
+        assert_eq!(decode(b"Zm9vYg", Alphabet::Standard, false).unwrap(), b"foob");
+        assert!(decode(b"Zg==Zg==", Alphabet::Standard, true).is_err());
+        assert!(decode(b"Z===", Alphabet::Standard, false).is_err());
+    }
+
+    #[test]
+    pub fn test_compressed_armor() {
+        let mut state = 0x9e37_79b9_7f4a_7c15u64;
+        let data: Vec<u8> = (0..2_000_000u32).map(|i| {
+            state ^= state << 13;
+            state ^= state >> 7;
+            state ^= state << 17;
+            if i % 3 == 0 { (state % 256) as u8 } else { b"armor"[(i % 5) as usize] }
+        }).collect();
+        for line_length in [0, MIME_LINE_LENGTH] {
+            let out = SharedBuffer::default();
+            let armor = Base64Writer::new(Box::new(out.clone()), Alphabet::UrlSafe, line_length);
+            let mut w = compressed_writer(Box::new(armor), CompressionType::Zstd, "level=3").unwrap();
+            w.write_all(&data).unwrap();
+            drop(w);
+            let encoded = out.take();
+            assert!(encoded.split(|b| *b == b'\n').all(|line| line.len() <= MIME_LINE_LENGTH + 1 || line_length == 0));
+
+            let armor = Base64Reader::new(Box::new(std::io::Cursor::new(encoded.clone())), Alphabet::UrlSafe, false);
+            let mut plain = Vec::new();
+            decompressed_reader(Box::new(armor), CompressionType::Zstd).unwrap().read_to_end(&mut plain).unwrap();
+            assert!(plain == data, "line length {}", line_length);
+
+            let strict = decode(&encoded, Alphabet::UrlSafe, true);
+            if line_length == 0 {
+                assert!(strict.is_ok());
+            } else {
+                let err = strict.unwrap_err();
+                assert_eq!(err.kind(), ErrorKind::InvalidData);
+                assert_eq!(err.to_string(), "base64 invalid byte 0x0d at offset 76");
+            }
+        }
+    }
+}
@@ -0,0 +1,656 @@
+use std::error::Error;
+use std::io::{Read, Write};
+use std::pin::Pin;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{mpsc, Arc, Mutex};
+use std::task::{Context, Poll, Waker};
+use bytes::{Buf, Bytes};
+use http::header::{HeaderMap, HeaderValue, ACCEPT_ENCODING, CONTENT_ENCODING, CONTENT_LENGTH, VARY};
+use http_body::{Body, Frame, SizeHint};
+use crate::{compressed_writer, decompressed_reader_untrusted, validate_params, CompressionType, ParamSet};
+
+/// Error type of the bodies, the one hyper and axum expect
+pub type BoxError = Box<dyn Error + Send + Sync>;
+
+/// Input chunks handed to a worker and not consumed yet, before the inner body is polled again
+const MAX_QUEUED_CHUNKS: usize = 4;
+/// Output chunks a worker may produce ahead of the consumer
+const MAX_OUTPUT_CHUNKS: usize = 4;
+/// Size of the output chunks
+const CHUNK_SIZE: usize = 64 * 1024;
+
+/// Content codings `negotiate` picks from, in order of preference
+const CODINGS: [(&str, CompressionType); 4] = [
+    ("zstd", CompressionType::Zstd),
+    ("br", CompressionType::Brotli),
+    ("gzip", CompressionType::Gzip),
+    ("deflate", CompressionType::Zlib),
+];
+
+/// Codec of a `Content-Encoding` token, `None` for `identity`. HTTP's `deflate` is zlib.
+pub fn coding_compression_type(coding: &str) -> Result<CompressionType, Box<dyn Error>> {
+    let coding = coding.trim().to_ascii_lowercase();
+    if coding == "identity" {
+        return Ok(CompressionType::None);
+    }
+    if coding == "x-gzip" {
+        return Ok(CompressionType::Gzip);
+    }
+    return CODINGS.iter().find(|(name, _)| *name == coding).map(|(_, ct)| *ct)
+        .ok_or_else(|| format!("unsupported Content-Encoding `{}`", coding).into());
+}
+
+/// `Content-Encoding` token of `compression_type`, `None` for codecs HTTP has no name for
+pub fn content_coding(compression_type: CompressionType) -> Option<&'static str> {
+    return CODINGS.iter().find(|(_, ct)| *ct == compression_type).map(|(name, _)| *name);
+}
+
+/// Codec to answer a request with, from its `Accept-Encoding` header: the supported coding with
+/// the highest q-value, ties going to zstd, then br, then gzip, then deflate. `*` stands for the codings
+/// not listed. Returns `CompressionType::None` when the response should not be compressed.
+pub fn negotiate(accept_encoding: &str) -> CompressionType {
+    let mut wildcard = None;
+    let mut listed: Vec<(String, u32)> = Vec::new();
+    for item in accept_encoding.split(',') {
+        let mut parts = item.split(';');
+        let coding = parts.next().unwrap_or("").trim().to_ascii_lowercase();
+        if coding.is_empty() {
+            continue;
+        }
+        // q-values in thousandths; malformed ones count as 0, refusing the coding
+        let mut quality = 1000;
+        for parameter in parts {
+            if let Some((name, value)) = parameter.split_once('=') {
+                if name.trim().eq_ignore_ascii_case("q") {
+                    quality = value.trim().parse::<f64>().ok()
+                        .filter(|q| (0.0..=1.0).contains(q))
+                        .map(|q| (q * 1000.0).round() as u32)
+                        .unwrap_or(0);
+                }
+            }
+        }
+        if coding == "*" {
+            wildcard = Some(quality);
+        } else {
+            listed.push((if coding == "x-gzip" { "gzip".into() } else { coding }, quality));
+        }
+    }
+    let mut best = (CompressionType::None, 0);
+    for (name, compression_type) in CODINGS {
+        let quality = listed.iter().find(|(coding, _)| coding == name).map(|(_, q)| *q)
+            .or(wildcard).unwrap_or(0);
+        if quality > best.1 {
+            best = (compression_type, quality);
+        }
+    }
+    return best.0;
+}
+
+/// State shared by a body and its worker thread
+#[derive(Default)]
+struct Shared {
+    queued: AtomicUsize,
+    /// Task waiting for `queued` to drop below `MAX_QUEUED_CHUNKS`
+    waker: Mutex<Option<Waker>>,
+}
+
+/// Blocking reader of the chunks sent to a worker
+struct ChunkReader {
+    chunks: mpsc::Receiver<Bytes>,
+    current: Bytes,
+    shared: Arc<Shared>,
+}
+
+impl ChunkReader {
+    /// Next chunk, waiting for it unless `wait` is false; `Ok(None)` at the end of the input
+    fn next_chunk(&mut self, wait: bool) -> Result<Option<Bytes>, mpsc::TryRecvError> {
+        let chunk = if wait {
+            self.chunks.recv().map_err(|_| mpsc::TryRecvError::Disconnected)
+        } else {
+            self.chunks.try_recv()
+        };
+        match chunk {
+            Ok(chunk) => {
+                self.shared.queued.fetch_sub(1, Ordering::SeqCst);
+                if let Some(waker) = self.shared.waker.lock().unwrap_or_else(|e| e.into_inner()).take() {
+                    waker.wake();
+                }
+                return Ok(Some(chunk));
+            },
+            Err(mpsc::TryRecvError::Disconnected) => return Ok(None),
+            Err(e) => return Err(e),
+        }
+    }
+}
+
+impl Read for ChunkReader {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        while self.current.is_empty() {
+            match self.next_chunk(true) {
+                Ok(Some(chunk)) => self.current = chunk,
+                _ => return Ok(0),
+            }
+        }
+        let n = buf.len().min(self.current.len());
+        self.current.copy_to_slice(&mut buf[..n]);
+        return Ok(n);
+    }
+}
+
+/// Writer sending a worker's output to its body, blocking while the consumer is behind
+struct ChunkWriter(tokio::sync::mpsc::Sender<std::io::Result<Bytes>>);
+
+impl Write for ChunkWriter {
+    fn write(&mut self, data: &[u8]) -> std::io::Result<usize> {
+        if !data.is_empty() {
+            self.0.blocking_send(Ok(Bytes::copy_from_slice(data)))
+                .map_err(|_| std::io::Error::new(std::io::ErrorKind::BrokenPipe, "body dropped"))?;
+        }
+        return Ok(data.len());
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        return Ok(());
+    }
+}
+
+/// Where a worker reads its input from
+enum WorkerInput {
+    Chunks(ChunkReader),
+    Reader(Box<dyn Read + Send>),
+}
+
+/// Thread running the blocking codec of a body. Readers and writers of this crate are not
+/// `Send`, so they are created and used on the thread, never by the body itself.
+struct Worker {
+    /// Closed once the inner body ended, `None` when the worker reads from a `Read`
+    input: Option<mpsc::Sender<Bytes>>,
+    output: tokio::sync::mpsc::Receiver<std::io::Result<Bytes>>,
+    shared: Arc<Shared>,
+}
+
+impl Worker {
+    fn spawn<F>(reader: Option<Box<dyn Read + Send>>, job: F) -> Worker
+    where F: FnOnce(WorkerInput, ChunkWriter) -> Result<(), Box<dyn Error>> + Send + 'static {
+        let shared = Arc::new(Shared::default());
+        let (input, chunks) = mpsc::channel();
+        let (output, receiver) = tokio::sync::mpsc::channel(MAX_OUTPUT_CHUNKS);
+        let (worker_input, input) = match reader {
+            Some(reader) => (WorkerInput::Reader(reader), None),
+            None => (WorkerInput::Chunks(ChunkReader { chunks, current: Bytes::new(), shared: shared.clone() }), Some(input)),
+        };
+        std::thread::spawn(move || {
+            let errors = output.clone();
+            let writer = ChunkWriter(output);
+            // a panic must not look like the end of the body
+            let result = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| job(worker_input, writer)))
+                .unwrap_or_else(|_| Err("body codec panicked".into()));
+            if let Err(e) = result {
+                let error = match e.downcast::<std::io::Error>() {
+                    Ok(error) => *error,
+                    Err(e) => std::io::Error::other(e.to_string()),
+                };
+                let _ = errors.blocking_send(Err(error));
+            }
+        });
+        return Worker { input, output: receiver, shared };
+    }
+
+    /// Whether another chunk may be queued, registering the task for a wake up if not
+    fn has_room(&self, cx: &mut Context<'_>) -> bool {
+        if self.shared.queued.load(Ordering::SeqCst) < MAX_QUEUED_CHUNKS {
+            return true;
+        }
+        *self.shared.waker.lock().unwrap_or_else(|e| e.into_inner()) = Some(cx.waker().clone());
+        return self.shared.queued.load(Ordering::SeqCst) < MAX_QUEUED_CHUNKS;
+    }
+
+    fn send(&mut self, chunk: Bytes) {
+        if let Some(input) = &self.input {
+            self.shared.queued.fetch_add(1, Ordering::SeqCst);
+            // a worker that quit reports why through its output
+            let _ = input.send(chunk);
+        }
+    }
+}
+
+/// Frames of an inner body run through a worker, or passed on as they are without one
+struct Pipe<B> {
+    inner: Option<Pin<Box<B>>>,
+    worker: Option<Worker>,
+    trailers: Option<HeaderMap>,
+    failed: bool,
+}
+
+impl<B> Pipe<B> where B: Body, B::Error: Into<BoxError> {
+    fn poll_frame(&mut self, cx: &mut Context<'_>) -> Poll<Option<Result<Frame<Bytes>, BoxError>>> {
+        if self.failed {
+            return Poll::Ready(None);
+        }
+        loop {
+            if let Some(worker) = &mut self.worker {
+                match worker.output.poll_recv(cx) {
+                    Poll::Ready(Some(Ok(chunk))) => return Poll::Ready(Some(Ok(Frame::data(chunk)))),
+                    Poll::Ready(Some(Err(e))) => return self.fail(e.into()),
+                    Poll::Ready(None) => {
+                        self.worker = None;
+                        return Poll::Ready(self.trailers.take().map(|trailers| Ok(Frame::trailers(trailers))));
+                    },
+                    Poll::Pending => {},
+                }
+                if self.inner.is_none() || !worker.has_room(cx) {
+                    return Poll::Pending;
+                }
+            }
+            let Some(inner) = &mut self.inner else {
+                return Poll::Ready(self.trailers.take().map(|trailers| Ok(Frame::trailers(trailers))));
+            };
+            match inner.as_mut().poll_frame(cx) {
+                Poll::Pending => return Poll::Pending,
+                Poll::Ready(None) => {
+                    self.inner = None;
+                    if let Some(worker) = &mut self.worker {
+                        worker.input = None;
+                    }
+                },
+                Poll::Ready(Some(Err(e))) => return self.fail(e.into()),
+                Poll::Ready(Some(Ok(frame))) => match frame.into_data() {
+                    Ok(mut data) => {
+                        let chunk = data.copy_to_bytes(data.remaining());
+                        match &mut self.worker {
+                            Some(worker) => if !chunk.is_empty() {
+                                worker.send(chunk);
+                            },
+                            None => return Poll::Ready(Some(Ok(Frame::data(chunk)))),
+                        }
+                    },
+                    Err(frame) => if let Ok(trailers) = frame.into_trailers() {
+                        self.trailers.get_or_insert_with(HeaderMap::new).extend(trailers);
+                    },
+                },
+            }
+        }
+    }
+
+    fn fail(&mut self, error: BoxError) -> Poll<Option<Result<Frame<Bytes>, BoxError>>> {
+        self.failed = true;
+        self.inner = None;
+        self.worker = None;
+        self.trailers = None;
+        return Poll::Ready(Some(Err(error)));
+    }
+
+    fn is_end_stream(&self) -> bool {
+        return self.failed || (self.inner.is_none() && self.worker.is_none() && self.trailers.is_none());
+    }
+}
+
+/// Inner body of a `CompressedBody` reading from a `Read`; it has no frames of its own
+pub struct NoBody;
+
+impl Body for NoBody {
+    type Data = Bytes;
+    type Error = std::convert::Infallible;
+
+    fn poll_frame(self: Pin<&mut Self>, _: &mut Context<'_>) -> Poll<Option<Result<Frame<Bytes>, Self::Error>>> {
+        return Poll::Ready(None);
+    }
+
+    fn is_end_stream(&self) -> bool {
+        return true;
+    }
+}
+
+/// `http_body::Body` compressing the data frames of an inner body, see `compress_response`.
+///
+/// The codec runs on a thread of its own, which flushes the encoder whenever it waits for input,
+/// so a slowly produced body (server-sent events, progress output) reaches the client as it is
+/// written. At most a few chunks are buffered on either side: the inner body is only polled
+/// while the consumer polls this one. Trailers of the inner body follow the compressed data.
+/// The size hint is unknown.
+pub struct CompressedBody<B> {
+    pipe: Pipe<B>,
+}
+
+impl<B> CompressedBody<B> where B: Body, B::Error: Into<BoxError> {
+    /// Compress `inner` with `compression_type` and the parameters of `compressed_writer`.
+    /// `CompressionType::None` passes the frames on unchanged.
+    pub fn new<T: Into<ParamSet>>(inner: B, compression_type: CompressionType, option: T) -> Result<CompressedBody<B>, Box<dyn Error>> {
+        return Ok(CompressedBody { pipe: Pipe {
+            inner: Some(Box::pin(inner)),
+            worker: compress_worker(None, compression_type, option)?,
+            trailers: None,
+            failed: false,
+        }});
+    }
+}
+
+impl CompressedBody<NoBody> {
+    /// Compress what `src` reads, read on the codec thread. A `src` that blocks forever keeps
+    /// that thread alive after the body is dropped.
+    pub fn from_reader<T: Into<ParamSet>>(src: Box<dyn Read + Send>, compression_type: CompressionType, option: T) -> Result<CompressedBody<NoBody>, Box<dyn Error>> {
+        let worker = match compress_worker(Some(src), compression_type, option)? {
+            Some(worker) => worker,
+            None => return Err("from_reader needs a compression type other than None".into()),
+        };
+        return Ok(CompressedBody { pipe: Pipe { inner: Some(Box::pin(NoBody)), worker: Some(worker), trailers: None, failed: false } });
+    }
+}
+
+fn compress_worker<T: Into<ParamSet>>(reader: Option<Box<dyn Read + Send>>, compression_type: CompressionType, option: T) -> Result<Option<Worker>, Box<dyn Error>> {
+    let param_set: ParamSet = option.into();
+    if compression_type == CompressionType::None {
+        return Ok(None);
+    }
+    validate_params(compression_type, param_set.clone())?;
+    return Ok(Some(Worker::spawn(reader, move |input, output| {
+        let output = std::io::BufWriter::with_capacity(CHUNK_SIZE, output);
+        let mut encoder = compressed_writer(Box::new(output), compression_type, param_set)?;
+        match input {
+            WorkerInput::Reader(mut reader) => {
+                std::io::copy(&mut reader, &mut encoder)?;
+            },
+            WorkerInput::Chunks(mut chunks) => {
+                let mut unflushed = false;
+                loop {
+                    let chunk = match chunks.next_chunk(false) {
+                        Ok(chunk) => chunk,
+                        Err(_) => {
+                            if unflushed {
+                                encoder.flush()?;
+                            }
+                            chunks.next_chunk(true).unwrap_or(None)
+                        }
+                    };
+                    let Some(chunk) = chunk else {
+                        break;
+                    };
+                    encoder.write_all(&chunk)?;
+                    unflushed = true;
+                }
+            },
+        }
+        encoder.flush()?;
+        return Ok(());
+    })));
+}
+
+impl<B> Body for CompressedBody<B> where B: Body, B::Error: Into<BoxError> {
+    type Data = Bytes;
+    type Error = BoxError;
+
+    fn poll_frame(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Result<Frame<Bytes>, BoxError>>> {
+        return self.get_mut().pipe.poll_frame(cx);
+    }
+
+    fn is_end_stream(&self) -> bool {
+        return self.pipe.is_end_stream();
+    }
+
+    fn size_hint(&self) -> SizeHint {
+        if self.pipe.worker.is_none() {
+            if let Some(inner) = &self.pipe.inner {
+                return inner.size_hint();
+            }
+        }
+        return SizeHint::default();
+    }
+}
+
+/// Compress `response` with the codec `negotiate` picks from `request_headers`' `Accept-Encoding`.
+///
+/// Sets `Content-Encoding`, removes `Content-Length` and adds `Accept-Encoding` to `Vary`.
+/// Responses that already have a `Content-Encoding` are left alone.
+pub fn compress_response<B, T>(request_headers: &HeaderMap, response: http::Response<B>, option: T) -> Result<http::Response<CompressedBody<B>>, Box<dyn Error>>
+where B: Body, B::Error: Into<BoxError>, T: Into<ParamSet> {
+    let (mut parts, body) = response.into_parts();
+    let accept_encoding = request_headers.get_all(ACCEPT_ENCODING).iter()
+        .filter_map(|value| value.to_str().ok())
+        .collect::<Vec<_>>()
+        .join(",");
+    let mut compression_type = negotiate(&accept_encoding);
+    if parts.headers.contains_key(CONTENT_ENCODING) {
+        compression_type = CompressionType::None;
+    }
+    if let Some(coding) = content_coding(compression_type) {
+        parts.headers.insert(CONTENT_ENCODING, HeaderValue::from_static(coding));
+        parts.headers.remove(CONTENT_LENGTH);
+    }
+    parts.headers.append(VARY, HeaderValue::from_static("accept-encoding"));
+    let body = CompressedBody::new(body, compression_type, option)?;
+    return Ok(http::Response::from_parts(parts, body));
+}
+
+/// `http_body::Body` decompressing an incoming body per its `Content-Encoding`, with the guards
+/// of `decompressed_reader_untrusted`: a body whose output exceeds `max_output` (256 MiB unless
+/// overridden) or `max_ratio`, or that is truncated or followed by garbage, ends with an error
+/// frame. Decoding runs on a thread of its own, like in `CompressedBody`.
+pub struct DecodedBody<B> {
+    pipe: Pipe<B>,
+}
+
+impl<B> DecodedBody<B> where B: Body, B::Error: Into<BoxError> {
+    /// Decode `inner` as `headers`' `Content-Encoding` says, with the parameters of
+    /// `decompressed_reader_untrusted`. A missing header and `identity` pass the body through,
+    /// codings without support and stacked codings (`gzip, zstd`) are errors.
+    pub fn new<T: Into<ParamSet>>(headers: &HeaderMap, inner: B, option: T) -> Result<DecodedBody<B>, Box<dyn Error>> {
+        let mut codings = Vec::new();
+        for value in headers.get_all(CONTENT_ENCODING) {
+            let value = value.to_str().map_err(|_| "Content-Encoding is not ASCII")?;
+            codings.extend(value.split(',').map(|coding| coding.trim()).filter(|coding| !coding.is_empty()));
+        }
+        let compression_types = codings.iter()
+            .map(|coding| coding_compression_type(coding))
+            .filter(|ct| !matches!(ct, Ok(CompressionType::None)))
+            .collect::<Result<Vec<_>, _>>()?;
+        if compression_types.len() > 1 {
+            return Err(format!("stacked Content-Encoding `{}` is not supported", codings.join(", ")).into());
+        }
+        let param_set: ParamSet = option.into();
+        let worker = match compression_types.first() {
+            Some(&compression_type) => {
+                param_set.check()?;
+                Some(Worker::spawn(None, move |input, mut output| {
+                    let WorkerInput::Chunks(chunks) = input else {
+                        return Err("decoder without chunk input".into());
+                    };
+                    let (mut reader, _) = decompressed_reader_untrusted(Box::new(chunks), compression_type, param_set)?;
+                    let mut buffer = vec![0u8; CHUNK_SIZE];
+                    loop {
+                        let read = reader.read(&mut buffer)?;
+                        if read == 0 {
+                            return Ok(());
+                        }
+                        output.write_all(&buffer[..read])?;
+                    }
+                }))
+            },
+            None => None,
+        };
+        return Ok(DecodedBody { pipe: Pipe { inner: Some(Box::pin(inner)), worker, trailers: None, failed: false } });
+    }
+}
+
+impl<B> Body for DecodedBody<B> where B: Body, B::Error: Into<BoxError> {
+    type Data = Bytes;
+    type Error = BoxError;
+
+    fn poll_frame(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Result<Frame<Bytes>, BoxError>>> {
+        return self.get_mut().pipe.poll_frame(cx);
+    }
+
+    fn is_end_stream(&self) -> bool {
+        return self.pipe.is_end_stream();
+    }
+
+    fn size_hint(&self) -> SizeHint {
+        if self.pipe.worker.is_none() {
+            if let Some(inner) = &self.pipe.inner {
+                return inner.size_hint();
+            }
+        }
+        return SizeHint::default();
+    }
+}
+
+/// Decode the body of `request`, removing its `Content-Encoding` and `Content-Length`
+pub fn decode_request<B, T>(request: http::Request<B>, option: T) -> Result<http::Request<DecodedBody<B>>, Box<dyn Error>>
+where B: Body, B::Error: Into<BoxError>, T: Into<ParamSet> {
+    let (mut parts, body) = request.into_parts();
+    let body = DecodedBody::new(&parts.headers, body, option)?;
+    if body.pipe.worker.is_some() {
+        parts.headers.remove(CONTENT_ENCODING);
+        parts.headers.remove(CONTENT_LENGTH);
+    }
+    return Ok(http::Request::from_parts(parts, body));
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::VecDeque;
+    use std::sync::atomic::AtomicU64;
+
+    /// Body yielding `chunks`, then `trailers`
+    struct Chunks {
+        chunks: VecDeque<Bytes>,
+        trailers: Option<HeaderMap>,
+    }
+
+    impl Body for Chunks {
+        type Data = Bytes;
+        type Error = std::convert::Infallible;
+
+        fn poll_frame(mut self: Pin<&mut Self>, _: &mut Context<'_>) -> Poll<Option<Result<Frame<Bytes>, Self::Error>>> {
+            if let Some(chunk) = self.chunks.pop_front() {
+                return Poll::Ready(Some(Ok(Frame::data(chunk))));
+            }
+            return Poll::Ready(self.trailers.take().map(|trailers| Ok(Frame::trailers(trailers))));
+        }
+    }
+
+    /// Frames of `body`, polled one at a time like hyper does
+    fn collect_frames<B: Body<Data = Bytes>>(body: B) -> Vec<Result<Frame<Bytes>, String>> where B::Error: std::fmt::Display {
+        let runtime = tokio::runtime::Builder::new_current_thread().build().unwrap();
+        return runtime.block_on(async {
+            let mut body = Box::pin(body);
+            let mut frames = Vec::new();
+            while let Some(frame) = std::future::poll_fn(|cx| body.as_mut().poll_frame(cx)).await {
+                frames.push(frame.map_err(|e| e.to_string()));
+            }
+            assert!(body.is_end_stream());
+            return frames;
+        });
+    }
+
+    fn data(frames: &[Result<Frame<Bytes>, String>]) -> Vec<u8> {
+        return frames.iter().filter_map(|f| f.as_ref().ok()?.data_ref()).flat_map(|d| d.to_vec()).collect();
+    }
+
+    #[test]
+    pub fn test_negotiate() {
+        assert_eq!(negotiate("gzip, deflate, br"), CompressionType::Brotli);
+        assert_eq!(negotiate("gzip, deflate, br;q=0.9"), CompressionType::Gzip);
+        assert_eq!(negotiate("gzip;q=0.5, zstd"), CompressionType::Zstd);
+        assert_eq!(negotiate("gzip, zstd"), CompressionType::Zstd);
+        assert_eq!(negotiate("zstd;q=0.2, deflate;q=0.8"), CompressionType::Zlib);
+        assert_eq!(negotiate("br"), CompressionType::Brotli);
+        assert_eq!(negotiate("compress"), CompressionType::None);
+        assert_eq!(negotiate(""), CompressionType::None);
+        assert_eq!(negotiate("*;q=0.1, zstd;q=0"), CompressionType::Brotli);
+        assert_eq!(negotiate("*;q=0.1, zstd;q=0, br;q=0"), CompressionType::Gzip);
+        assert_eq!(negotiate("X-GZIP"), CompressionType::Gzip);
+        assert_eq!(negotiate("gzip;q=2"), CompressionType::None);
+    }
+
+    #[test]
+    pub fn test_gzip_response_round_trip() {
+        let chunks: VecDeque<Bytes> = (0..50).map(|i| Bytes::from(format!("line {} of the response\n", i).repeat(100))).collect();
+        let expected: Vec<u8> = chunks.iter().flat_map(|c| c.to_vec()).collect();
+        let mut trailers = HeaderMap::new();
+        trailers.insert("x-checksum", HeaderValue::from_static("1234"));
+        let mut request = HeaderMap::new();
+        request.insert(ACCEPT_ENCODING, HeaderValue::from_static("br;q=0.8, gzip;q=0.9"));
+        let response = http::Response::builder().header(CONTENT_LENGTH, expected.len())
+            .body(Chunks { chunks, trailers: Some(trailers) }).unwrap();
+        let response = compress_response(&request, response, "level=6").unwrap();
+        assert_eq!(response.headers()[CONTENT_ENCODING], "gzip");
+        assert!(!response.headers().contains_key(CONTENT_LENGTH));
+        assert_eq!(response.headers()[VARY], "accept-encoding");
+        assert_eq!(response.body().size_hint().upper(), None);
+        fn assert_send<T: Send>(_: &T) {}
+        assert_send(response.body());
+        let (parts, body) = response.into_parts();
+        let frames = collect_frames(body);
+        assert!(frames.last().unwrap().as_ref().unwrap().trailers_ref().unwrap()["x-checksum"] == "1234");
+        let compressed = data(&frames);
+        assert!(compressed.len() < expected.len() / 5);
+        let mut plain = Vec::new();
+        flate2::read::GzDecoder::new(&compressed[..]).read_to_end(&mut plain).unwrap();
+        assert!(plain == expected);
+
+        // and back through the request side
+        let chunks = compressed.chunks(1000).map(Bytes::copy_from_slice).collect();
+        let mut request = http::Request::new(Chunks { chunks, trailers: None });
+        *request.headers_mut() = parts.headers;
+        let request = decode_request(request, "").unwrap();
+        assert!(!request.headers().contains_key(CONTENT_ENCODING));
+        assert!(data(&collect_frames(request.into_body())) == expected);
+    }
+
+    #[test]
+    pub fn test_over_limit_request_rejected() {
+        let buffer = crate::buffer::SharedBuffer::default();
+        let mut w = compressed_writer(Box::new(buffer.clone()), CompressionType::Zstd, "").unwrap();
+        w.write_all(&vec![0u8; 10 << 20]).unwrap();
+        drop(w);
+        let bomb = buffer.take();
+        let mut headers = HeaderMap::new();
+        headers.insert(CONTENT_ENCODING, HeaderValue::from_static("zstd"));
+        let body = DecodedBody::new(&headers, Chunks { chunks: [Bytes::from(bomb)].into(), trailers: None }, "max_output=1MiB").unwrap();
+        let frames = collect_frames(body);
+        let error = frames.last().unwrap().as_ref().unwrap_err();
+        assert!(error.contains("exceeds the limit"), "{}", error);
+        assert!(data(&frames).len() <= 1 << 20);
+
+        headers.insert(CONTENT_ENCODING, HeaderValue::from_static("compress"));
+        assert!(DecodedBody::new(&headers, Chunks { chunks: VecDeque::new(), trailers: None }, "").is_err());
+        headers.insert(CONTENT_ENCODING, HeaderValue::from_static("identity"));
+        let body = DecodedBody::new(&headers, Chunks { chunks: [Bytes::from_static(b"plain")].into(), trailers: None }, "").unwrap();
+        assert_eq!(data(&collect_frames(body)), b"plain");
+    }
+
+    /// Endless body of incompressible chunks, counting them
+    struct Endless(Arc<AtomicU64>);
+
+    impl Body for Endless {
+        type Data = Bytes;
+        type Error = std::convert::Infallible;
+
+        fn poll_frame(self: Pin<&mut Self>, _: &mut Context<'_>) -> Poll<Option<Result<Frame<Bytes>, Self::Error>>> {
+            let mut state = self.0.fetch_add(1, Ordering::SeqCst) + 1;
+            let chunk: Vec<u8> = (0..CHUNK_SIZE).map(|_| {
+                state ^= state << 13;
+                state ^= state >> 7;
+                state ^= state << 17;
+                state as u8
+            }).collect();
+            return Poll::Ready(Some(Ok(Frame::data(Bytes::from(chunk)))));
+        }
+    }
+
+    #[test]
+    pub fn test_backpressure() {
+        let produced = Arc::new(AtomicU64::new(0));
+        let mut body = Box::pin(CompressedBody::new(Endless(produced.clone()), CompressionType::Gzip, "level=1").unwrap());
+        let runtime = tokio::runtime::Builder::new_current_thread().enable_time().build().unwrap();
+        runtime.block_on(async {
+            for _ in 0..3 {
+                std::future::poll_fn(|cx| body.as_mut().poll_frame(cx)).await.unwrap().unwrap();
+            }
+            tokio::time::sleep(std::time::Duration::from_millis(300)).await;
+        });
+        let produced = produced.load(Ordering::SeqCst);
+        assert!(produced < 3 + (MAX_QUEUED_CHUNKS + MAX_OUTPUT_CHUNKS) as u64 * 2, "{} chunks", produced);
+    }
+}
@@ -0,0 +1,154 @@
+use std::error::Error;
+use std::fmt;
+use crate::CompressionType;
+
+/// Whether a declared decompressed length fits the compressed bytes it comes from
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Plausibility {
+    Plausible,
+    /// Beyond what encoders reach on real data (runs of zeros included), but not beyond what the
+    /// format can express: at most `max_theoretical` bytes
+    Suspicious { max_theoretical: u64 },
+    /// More than the format can decode from that many bytes
+    Impossible,
+}
+
+/// Output bytes per input bytes, as a fraction
+type Ratio = (u64, u64);
+
+/// No bound: the format can express any ratio a length can hold
+const UNBOUNDED: Ratio = (u64::MAX, 1);
+
+/// Largest expansion of `compression_type`, theoretical then practical. None for registered
+/// codecs, which this crate knows nothing about.
+///
+/// - stored (and none): blocks hold the data as is
+/// - deflate, zlib, gzip: 258 bytes per 2 bits of a fixed Huffman block, 1032:1
+/// - snappy: a 3 byte copy yields at most 64 bytes
+/// - LZ4 and LZO: every extra byte of a match length adds at most 255 bytes
+/// - zstd: a block decodes to at most 128 KiB and takes at least 4 bytes (an RLE block)
+/// - xz: an LZMA2 chunk decodes to at most 2 MiB and takes at least 10 bytes
+/// - bzip2: 45899236:1, reached by its run-length stages on a block of one repeated byte
+/// - brotli: none, its copy commands repeat any length
+///
+/// The practical bounds are above what the encoders of this crate reach on zeros at any level:
+/// about 1030:1 for deflate, 32500:1 for zstd, 6800:1 for xz, 850000:1 for bzip2 and 660000:1
+/// for brotli.
+fn bounds(compression_type: CompressionType) -> Option<(Ratio, Ratio)> {
+    return Some(match compression_type {
+        CompressionType::None | CompressionType::Stored => ((1, 1), (1, 1)),
+        CompressionType::Gzip | CompressionType::Zlib | CompressionType::Deflate => ((1032, 1), (1032, 1)),
+        CompressionType::Snappy => ((64, 3), (64, 3)),
+        CompressionType::LZ4 | CompressionType::Lzo => ((255, 1), (255, 1)),
+        CompressionType::Zstd => ((32768, 1), (32768, 1)),
+        CompressionType::XZ => ((209715, 1), (10_000, 1)),
+        CompressionType::Bzip2 => ((45_899_236, 1), (1_000_000, 1)),
+        CompressionType::Brotli => (UNBOUNDED, (1_000_000, 1)),
+        CompressionType::Custom(_) => return None,
+    });
+}
+
+fn max_output(compressed_len: u64, (output, input): Ratio) -> u64 {
+    return u64::try_from(compressed_len as u128 * output as u128 / input as u128).unwrap_or(u64::MAX);
+}
+
+/// Whether `compressed_len` bytes of `compression_type` can decode to
+/// `declared_uncompressed_len` bytes, to check a length read from untrusted input before
+/// allocating it. Framing overheads are ignored, so a plausible length can still be a lie;
+/// registered codecs are always plausible.
+pub fn plausible_expansion(compression_type: CompressionType, compressed_len: u64, declared_uncompressed_len: u64) -> Plausibility {
+    let Some((theoretical, practical)) = bounds(compression_type) else {
+        return Plausibility::Plausible;
+    };
+    let max_theoretical = max_output(compressed_len, theoretical);
+    if declared_uncompressed_len > max_theoretical {
+        return Plausibility::Impossible;
+    }
+    if declared_uncompressed_len > max_output(compressed_len, practical) {
+        return Plausibility::Suspicious { max_theoretical };
+    }
+    return Plausibility::Plausible;
+}
+
+/// Fewest compressed bytes of `compression_type` that can decode to `uncompressed_len` bytes:
+/// any less is `Plausibility::Impossible`. 0 for registered codecs.
+pub fn min_compressed_len_for(compression_type: CompressionType, uncompressed_len: u64) -> u64 {
+    let Some(((output, input), _)) = bounds(compression_type) else {
+        return 0;
+    };
+    return u64::try_from((uncompressed_len as u128 * input as u128).div_ceil(output as u128)).unwrap_or(u64::MAX);
+}
+
+/// `InvalidData` error if `declared` bytes cannot come from `compressed_len` bytes
+pub(crate) fn check_declared(compression_type: CompressionType, compressed_len: u64, declared: u64) -> std::io::Result<()> {
+    if plausible_expansion(compression_type, compressed_len, declared) == Plausibility::Impossible {
+        return Err(std::io::Error::new(std::io::ErrorKind::InvalidData,
+            format!("{} bytes declared for {} bytes of {}, more than the format can decode", declared, compressed_len, compression_type)));
+    }
+    return Ok(());
+}
+
+/// A zstd or lz4 frame decoded to another size than its header declares, from
+/// `decompress_bytes_limited`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ContentSizeMismatch {
+    pub declared: u64,
+    /// Bytes decoded, up to where the decoder failed if it did
+    pub actual: u64,
+}
+
+impl fmt::Display for ContentSizeMismatch {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "frame declares {} bytes of content, {} decoded", self.declared, self.actual)
+    }
+}
+
+impl Error for ContentSizeMismatch {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Write;
+    use crate::describe::BUILTIN_CODECS;
+
+    #[test]
+    pub fn test_bounds() {
+        assert_eq!(min_compressed_len_for(CompressionType::Gzip, 1032 * 1000), 1000);
+        assert_eq!(min_compressed_len_for(CompressionType::Gzip, 1032 * 1000 + 1), 1001);
+        assert_eq!(min_compressed_len_for(CompressionType::Snappy, 64), 3);
+        assert_eq!(min_compressed_len_for(CompressionType::LZ4, 255), 1);
+        assert_eq!(min_compressed_len_for(CompressionType::Zstd, 128 * 1024), 4);
+        assert_eq!(min_compressed_len_for(CompressionType::Stored, 77), 77);
+        assert_eq!(min_compressed_len_for(CompressionType::Brotli, 1 << 40), 1);
+        assert_eq!(min_compressed_len_for(CompressionType::Brotli, 0), 0);
+        assert_eq!(min_compressed_len_for(CompressionType::Custom(1), 1 << 40), 0);
+
+        // a 100 byte LZ4 blob cannot hold a megabyte
+        assert_eq!(plausible_expansion(CompressionType::LZ4, 100, 1 << 20), Plausibility::Impossible);
+        assert_eq!(plausible_expansion(CompressionType::LZ4, 100, 25500), Plausibility::Plausible);
+        assert_eq!(plausible_expansion(CompressionType::LZ4, 100, 25501), Plausibility::Impossible);
+        assert!(check_declared(CompressionType::LZ4, 100, 1 << 20).unwrap_err().to_string().contains("1048576 bytes declared for 100 bytes of lz4"));
+        assert_eq!(plausible_expansion(CompressionType::None, 10, 11), Plausibility::Impossible);
+        assert_eq!(plausible_expansion(CompressionType::XZ, 10, 200_000), Plausibility::Suspicious { max_theoretical: 2_097_150 });
+        assert_eq!(plausible_expansion(CompressionType::Bzip2, 1, 50_000_000), Plausibility::Impossible);
+        assert_eq!(plausible_expansion(CompressionType::Brotli, 1, u64::MAX), Plausibility::Suspicious { max_theoretical: u64::MAX });
+        assert_eq!(plausible_expansion(CompressionType::Custom(1), 0, 1 << 40), Plausibility::Plausible);
+    }
+
+    #[test]
+    pub fn test_zeros_plausible() {
+        // the most compressible input there is, at the fastest and the strongest level
+        let zeros = vec![0u8; 16 << 20];
+        for codec in BUILTIN_CODECS {
+            for level in ["level=1", "level=9"] {
+                let buffer = crate::buffer::SharedBuffer::default();
+                let mut w = crate::compressed_writer(Box::new(buffer.clone()), codec.compression_type, level).unwrap();
+                w.write_all(&zeros).unwrap();
+                drop(w);
+                let compressed = buffer.len() as u64;
+                assert_eq!(plausible_expansion(codec.compression_type, compressed, zeros.len() as u64), Plausibility::Plausible, "{} {}", codec.name, level);
+                assert!(min_compressed_len_for(codec.compression_type, zeros.len() as u64) <= compressed, "{} {}", codec.name, level);
+            }
+        }
+    }
+}
@@ -0,0 +1,190 @@
+use std::error::Error;
+use std::io::{Read, Write};
+use std::sync::Arc;
+use crate::{compressed_writer, decompressed_reader, CompressionType, ParamSet};
+use crate::buffer::SharedBuffer;
+
+/// Values shorter than this many bytes are stored raw unless `raw_below` says otherwise
+pub const DEFAULT_RAW_BELOW: usize = 64;
+
+/// Byte buffer kept compressed in memory and decompressed on access.
+///
+/// Meant for large numbers of rarely read values, e.g. cache entries. Values shorter than
+/// `raw_below` bytes (parameter, default 64), and values that do not shrink when compressed, are
+/// stored raw. All other parameters are passed to `compressed_writer`.
+///
+/// Cloning is cheap: clones share the stored bytes.
+#[derive(Debug, Clone)]
+pub struct CompressedBox {
+    data: Arc<[u8]>,
+    /// `CompressionType::None` when stored raw
+    compression_type: CompressionType,
+    uncompressed_len: usize,
+}
+
+impl CompressedBox {
+    pub fn new<T: Into<ParamSet>>(bytes: &[u8], compression_type: CompressionType, option: T) -> Result<CompressedBox, Box<dyn Error>> {
+        let param_set: ParamSet = option.into();
+        let raw = CompressedBox {
+            data: bytes.into(),
+            compression_type: CompressionType::None,
+            uncompressed_len: bytes.len(),
+        };
+        if bytes.len() < param_set.get_size("raw_below", DEFAULT_RAW_BELOW)? || compression_type == CompressionType::None {
+            return Ok(raw);
+        }
+        let buffer = SharedBuffer::with_capacity(bytes.len() / 2);
+        let mut writer = compressed_writer(Box::new(buffer.clone()), compression_type, param_set)?;
+        writer.write_all(bytes)?;
+        writer.flush()?;
+        drop(writer);
+        let compressed = buffer.take();
+        if compressed.len() >= bytes.len() {
+            return Ok(raw);
+        }
+        return Ok(CompressedBox {
+            data: compressed.into(),
+            compression_type,
+            uncompressed_len: bytes.len(),
+        });
+    }
+
+    /// Decompress into a new buffer
+    pub fn get(&self) -> Result<Vec<u8>, Box<dyn Error>> {
+        let mut result = Vec::new();
+        self.get_with(&mut result)?;
+        return Ok(result);
+    }
+
+    /// Decompress into `scratch`, replacing its content but reusing its allocation
+    pub fn get_with(&self, scratch: &mut Vec<u8>) -> Result<(), Box<dyn Error>> {
+        scratch.clear();
+        if self.compression_type == CompressionType::None {
+            scratch.extend_from_slice(&self.data);
+            return Ok(());
+        }
+        scratch.reserve(self.uncompressed_len);
+        let src = Box::new(std::io::Cursor::new(self.data.clone()));
+        decompressed_reader(src, self.compression_type)?.read_to_end(scratch)?;
+        if scratch.len() != self.uncompressed_len {
+            return Err(format!("compressed value decoded to {} bytes, expected {}", scratch.len(), self.uncompressed_len).into());
+        }
+        return Ok(());
+    }
+
+    /// Bytes held in memory
+    pub fn compressed_len(&self) -> usize {
+        return self.data.len();
+    }
+
+    pub fn uncompressed_len(&self) -> usize {
+        return self.uncompressed_len;
+    }
+
+    /// `compressed_len / uncompressed_len`, 1.0 for an empty value
+    pub fn ratio(&self) -> f64 {
+        if self.uncompressed_len == 0 {
+            return 1.0;
+        }
+        return self.data.len() as f64 / self.uncompressed_len as f64;
+    }
+
+    /// Whether the value was stored without compression
+    pub fn is_raw(&self) -> bool {
+        return self.compression_type == CompressionType::None;
+    }
+}
+
+/// `CompressedBox` holding UTF-8 text
+#[derive(Debug, Clone)]
+pub struct CompressedString {
+    inner: CompressedBox,
+}
+
+impl CompressedString {
+    pub fn new<T: Into<ParamSet>>(text: &str, compression_type: CompressionType, option: T) -> Result<CompressedString, Box<dyn Error>> {
+        return Ok(CompressedString { inner: CompressedBox::new(text.as_bytes(), compression_type, option)? });
+    }
+
+    pub fn get(&self) -> Result<String, Box<dyn Error>> {
+        return Ok(String::from_utf8(self.inner.get()?)?);
+    }
+
+    /// Decompress into `scratch`, replacing its content but reusing its allocation
+    pub fn get_with(&self, scratch: &mut String) -> Result<(), Box<dyn Error>> {
+        let mut bytes = std::mem::take(scratch).into_bytes();
+        self.inner.get_with(&mut bytes)?;
+        *scratch = String::from_utf8(bytes)?;
+        return Ok(());
+    }
+
+    /// The underlying byte box, for its size accessors
+    pub fn as_box(&self) -> &CompressedBox {
+        return &self.inner;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn blob(i: usize) -> String {
+        let items: Vec<String> = (0..20).map(|j| format!("{{\"id\":{},\"tag\":\"item-{}\",\"enabled\":{}}}", i * 20 + j, j % 7, j % 2 == 0)).collect();
+        return format!("{{\"user\":{},\"items\":[{}]}}", i, items.join(","));
+    }
+
+    #[test]
+    pub fn test_round_trip() {
+        for ct in [CompressionType::Zstd, CompressionType::Gzip, CompressionType::LZ4, CompressionType::Snappy] {
+            let text = blob(1);
+            let boxed = CompressedString::new(&text, ct, "").unwrap();
+            assert!(!boxed.as_box().is_raw(), "{:?}", ct);
+            assert_eq!(boxed.get().unwrap(), text);
+            let mut scratch = String::from("previous content");
+            boxed.get_with(&mut scratch).unwrap();
+            assert_eq!(scratch, text);
+            assert_eq!(boxed.as_box().uncompressed_len(), text.len());
+            assert!(boxed.as_box().ratio() < 1.0);
+        }
+    }
+
+    #[test]
+    pub fn test_stored_raw() {
+        let small = CompressedBox::new(b"{\"id\":1}", CompressionType::Zstd, "").unwrap();
+        assert!(small.is_raw());
+        assert_eq!(small.compressed_len(), 8);
+        assert_eq!(small.get().unwrap(), b"{\"id\":1}");
+
+        let forced = CompressedBox::new(b"{\"id\":1}", CompressionType::Zstd, "raw_below=0").unwrap();
+        // too small to shrink
+        assert!(forced.is_raw());
+
+        let mut state = 0x2545f4914f6cdd1du64;
+        let noise: Vec<u8> = (0..1000).map(|_| {
+            state ^= state << 13;
+            state ^= state >> 7;
+            state ^= state << 17;
+            (state >> 32) as u8
+        }).collect();
+        let incompressible = CompressedBox::new(&noise, CompressionType::Gzip, "").unwrap();
+        assert!(incompressible.is_raw());
+        assert_eq!(incompressible.get().unwrap(), noise);
+        assert_eq!(CompressedBox::new(b"", CompressionType::Gzip, "").unwrap().ratio(), 1.0);
+    }
+
+    #[test]
+    pub fn test_send_sync() {
+        fn assert_send_sync<T: Send + Sync>() {}
+        assert_send_sync::<CompressedBox>();
+        assert_send_sync::<CompressedString>();
+    }
+
+    #[test]
+    pub fn test_memory_savings() {
+        let corpus: Vec<String> = (0..500).map(blob).collect();
+        let boxes: Vec<CompressedString> = corpus.iter().map(|b| CompressedString::new(b, CompressionType::Zstd, "").unwrap()).collect();
+        let raw: usize = corpus.iter().map(|b| b.len()).sum();
+        let compressed: usize = boxes.iter().map(|b| b.as_box().compressed_len()).sum();
+        assert!(compressed < raw / 2, "{} vs {}", compressed, raw);
+    }
+}
@@ -0,0 +1,187 @@
+use std::error::Error;
+use std::io::Read;
+use std::sync::mpsc::{sync_channel, Receiver, RecvTimeoutError};
+use std::time::Duration;
+use crate::{decompressed_reader_with, CompressionType, ParamSet};
+
+/// Decompressed bytes handed from the decoding thread at a time
+const CHUNK_SIZE: usize = 64 * 1024;
+/// Chunks decoded ahead of the consumer
+const READ_AHEAD: usize = 4;
+
+/// Reads that give up after a time budget instead of blocking on a slow source
+pub trait BudgetedRead: Read {
+    /// Read whatever decompressed bytes are available within `budget`. Returns `Ok(0)` when
+    /// nothing arrived in time, as well as at the end of the stream; `is_finished` tells them apart.
+    fn read_available(&mut self, buf: &mut [u8], budget: Duration) -> std::io::Result<usize>;
+
+    /// Whether the end of the stream was returned
+    fn is_finished(&self) -> bool;
+}
+
+/// Reader decompressing on a thread of its own, see `budgeted_reader`
+pub struct BudgetedReader {
+    /// An empty chunk marks the end of the stream
+    receiver: Receiver<std::io::Result<Vec<u8>>>,
+    chunk: Vec<u8>,
+    pos: usize,
+    finished: bool,
+}
+
+/// Decompression reader with `BudgetedRead`, for callers that must never stall, like a UI
+/// rendering whatever is available every frame.
+///
+/// Source reads and decoding run on a dedicated thread, at most 256 KiB ahead of the consumer,
+/// so `read_available` only waits for the budget and never for the source. `option` is passed to
+/// `decompressed_reader_with`; as the decoder is built on the thread, invalid parameters are
+/// reported by the first read. The thread stops once the reader is dropped and its pending
+/// source read returns.
+pub fn budgeted_reader<T: Into<ParamSet>>(
+    src: Box<dyn Read + Send>,
+    compression_type: CompressionType,
+    option: T) -> Result<BudgetedReader, Box<dyn Error>> {
+    let param_set: ParamSet = option.into();
+    let (sender, receiver) = sync_channel(READ_AHEAD);
+    std::thread::spawn(move || {
+        let mut decoder = match decompressed_reader_with(src, compression_type, param_set) {
+            Ok(decoder) => decoder,
+            Err(e) => {
+                let _ = sender.send(Err(std::io::Error::other(e.to_string())));
+                return;
+            }
+        };
+        loop {
+            let mut chunk = vec![0u8; CHUNK_SIZE];
+            let message = decoder.read(&mut chunk).map(|read| {
+                chunk.truncate(read);
+                chunk
+            });
+            let last = !matches!(&message, Ok(chunk) if !chunk.is_empty());
+            if sender.send(message).is_err() || last {
+                return;
+            }
+        }
+    });
+    return Ok(BudgetedReader { receiver, chunk: Vec::new(), pos: 0, finished: false });
+}
+
+impl BudgetedReader {
+    fn receive(&mut self, message: Result<std::io::Result<Vec<u8>>, RecvTimeoutError>, buf: &mut [u8]) -> std::io::Result<usize> {
+        match message {
+            Ok(Ok(chunk)) if chunk.is_empty() => {
+                self.finished = true;
+                return Ok(0);
+            },
+            Ok(Ok(chunk)) => {
+                self.chunk = chunk;
+                self.pos = 0;
+                return Ok(self.copy_to(buf));
+            },
+            Ok(Err(e)) => {
+                self.finished = true;
+                return Err(e);
+            },
+            Err(RecvTimeoutError::Timeout) => return Ok(0),
+            Err(RecvTimeoutError::Disconnected) => {
+                self.finished = true;
+                return Err(std::io::Error::other("decompression thread panicked"));
+            },
+        }
+    }
+
+    fn copy_to(&mut self, buf: &mut [u8]) -> usize {
+        let n = buf.len().min(self.chunk.len() - self.pos);
+        buf[..n].copy_from_slice(&self.chunk[self.pos..self.pos + n]);
+        self.pos += n;
+        return n;
+    }
+}
+
+impl Read for BudgetedReader {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        if self.pos < self.chunk.len() || buf.is_empty() {
+            return Ok(self.copy_to(buf));
+        }
+        if self.finished {
+            return Ok(0);
+        }
+        let message = self.receiver.recv().map_err(|_| RecvTimeoutError::Disconnected);
+        return self.receive(message, buf);
+    }
+}
+
+impl BudgetedRead for BudgetedReader {
+    fn read_available(&mut self, buf: &mut [u8], budget: Duration) -> std::io::Result<usize> {
+        if self.pos < self.chunk.len() || buf.is_empty() {
+            return Ok(self.copy_to(buf));
+        }
+        if self.finished {
+            return Ok(0);
+        }
+        let message = self.receiver.recv_timeout(budget);
+        return self.receive(message, buf);
+    }
+
+    fn is_finished(&self) -> bool {
+        return self.finished;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Write;
+    use std::time::Instant;
+    use crate::compressed_writer;
+
+    /// Source taking 20 ms for every 500 bytes
+    struct SlowSource {
+        inner: std::io::Cursor<Vec<u8>>,
+    }
+
+    impl Read for SlowSource {
+        fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+            std::thread::sleep(Duration::from_millis(20));
+            let n = buf.len().min(500);
+            return self.inner.read(&mut buf[..n]);
+        }
+    }
+
+    #[test]
+    pub fn test_budget() {
+        let data: Vec<u8> = (0..200_000u32).map(|i| (i * 7 % 256) as u8 ^ (i / 5000) as u8).collect();
+        let buffer = crate::buffer::SharedBuffer::default();
+        let mut w = compressed_writer(Box::new(buffer.clone()), CompressionType::Zstd, "").unwrap();
+        w.write_all(&data).unwrap();
+        drop(w);
+        let compressed = buffer.take();
+        assert!(compressed.len() > 2000);
+
+        let src = Box::new(SlowSource { inner: std::io::Cursor::new(compressed) });
+        let mut reader = budgeted_reader(src, CompressionType::Zstd, "").unwrap();
+        let (mut out, mut empty_calls) = (Vec::new(), 0);
+        let mut buf = vec![0u8; 4096];
+        while !reader.is_finished() {
+            let started = Instant::now();
+            let read = reader.read_available(&mut buf, Duration::from_millis(5)).unwrap();
+            assert!(started.elapsed() < Duration::from_millis(100), "{:?}", started.elapsed());
+            if read == 0 && !reader.is_finished() {
+                empty_calls += 1;
+            }
+            out.extend_from_slice(&buf[..read]);
+        }
+        assert!(empty_calls > 0);
+        assert_eq!(out, data);
+        assert_eq!(reader.read(&mut buf).unwrap(), 0);
+    }
+
+    #[test]
+    pub fn test_errors() {
+        let mut out = Vec::new();
+        let mut reader = budgeted_reader(Box::new(std::io::empty()), CompressionType::Gzip, "eof_policy=never").unwrap();
+        assert!(reader.read_to_end(&mut out).unwrap_err().to_string().contains("eof_policy"));
+        let mut reader = budgeted_reader(Box::new(std::io::Cursor::new(b"not gzip".to_vec())), CompressionType::Gzip, "").unwrap();
+        assert!(reader.read_to_end(&mut out).is_err());
+        assert!(reader.is_finished());
+    }
+}
@@ -0,0 +1,32 @@
+use std::io::Write;
+use std::sync::{Arc, Mutex};
+
+/// In-memory sink whose content stays reachable after the encoder owning it is dropped.
+#[derive(Clone, Default)]
+pub(crate) struct SharedBuffer(Arc<Mutex<Vec<u8>>>);
+
+impl SharedBuffer {
+    pub(crate) fn with_capacity(capacity: usize) -> SharedBuffer {
+        return SharedBuffer(Arc::new(Mutex::new(Vec::with_capacity(capacity))));
+    }
+
+    pub(crate) fn len(&self) -> usize {
+        return self.0.lock().unwrap_or_else(|e| e.into_inner()).len();
+    }
+
+    /// Move the collected bytes out, leaving the buffer empty
+    pub(crate) fn take(&self) -> Vec<u8> {
+        return std::mem::take(&mut *self.0.lock().unwrap_or_else(|e| e.into_inner()));
+    }
+}
+
+impl Write for SharedBuffer {
+    fn write(&mut self, data: &[u8]) -> std::io::Result<usize> {
+        self.0.lock().unwrap_or_else(|e| e.into_inner()).extend_from_slice(data);
+        return Ok(data.len());
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        return Ok(());
+    }
+}
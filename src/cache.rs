@@ -0,0 +1,306 @@
+use std::error::Error;
+use std::fs::{File, OpenOptions};
+use std::io::{ErrorKind, Read, Write};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use crate::{compressed_writer, CompressionType, ParamSet};
+
+/// Default age in seconds after which a lock file is considered abandoned
+pub const DEFAULT_STALE_LOCK_TIMEOUT: u64 = 60;
+const LOCK_POLL_INTERVAL: Duration = Duration::from_millis(10);
+
+static TEMP_COUNTER: AtomicU64 = AtomicU64::new(0);
+
+fn now_millis() -> u128 {
+    return SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default().as_millis();
+}
+
+fn sidecar(path: &Path, suffix: &str) -> PathBuf {
+    let mut name = path.file_name().map(|n| n.to_os_string()).unwrap_or_default();
+    name.push(suffix);
+    return path.with_file_name(name);
+}
+
+/// Advisory lock held through the existence of `<target>.lock`, containing "pid millis".
+struct CacheLock {
+    path: PathBuf,
+}
+
+impl CacheLock {
+    fn acquire(path: PathBuf, stale_timeout: Duration) -> Result<CacheLock, std::io::Error> {
+        loop {
+            match OpenOptions::new().write(true).create_new(true).open(&path) {
+                Ok(mut file) => {
+                    let written = write!(file, "{} {}", std::process::id(), now_millis());
+                    let lock = CacheLock { path };
+                    written?;
+                    return Ok(lock);
+                },
+                Err(e) if e.kind() == ErrorKind::AlreadyExists => {
+                    if CacheLock::is_stale(&path, stale_timeout) {
+                        let _ = std::fs::remove_file(&path);
+                        continue;
+                    }
+                    std::thread::sleep(LOCK_POLL_INTERVAL);
+                },
+                Err(e) => return Err(e),
+            }
+        }
+    }
+
+    fn is_stale(path: &Path, stale_timeout: Duration) -> bool {
+        let mut content = String::new();
+        let Ok(mut file) = File::open(path) else {
+            // released meanwhile
+            return false;
+        };
+        let taken = match file.read_to_string(&mut content).ok()
+            .and_then(|_| content.split_whitespace().nth(1)?.parse::<u128>().ok()) {
+            Some(taken) => taken,
+            // holder has not written its stamp yet, fall back to the file age
+            None => match file.metadata().and_then(|m| m.modified()) {
+                Ok(modified) => modified.duration_since(UNIX_EPOCH).unwrap_or_default().as_millis(),
+                Err(_) => return false,
+            }
+        };
+        return now_millis().saturating_sub(taken) > stale_timeout.as_millis();
+    }
+}
+
+impl Drop for CacheLock {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_file(&self.path);
+    }
+}
+
+/// File sink remembering whether any write to it failed, since compressors finishing on drop
+/// cannot report errors.
+struct FailureTracking {
+    inner: File,
+    failed: Arc<AtomicBool>,
+}
+
+impl Write for FailureTracking {
+    fn write(&mut self, data: &[u8]) -> std::io::Result<usize> {
+        return self.inner.write(data).inspect_err(|_| self.failed.store(true, Ordering::Relaxed));
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        return self.inner.flush().inspect_err(|_| self.failed.store(true, Ordering::Relaxed));
+    }
+}
+
+/// Compressing writer replacing a shared cache file atomically.
+///
+/// `create` takes an advisory lock on the sidecar file `<path>.lock`, and compressed data is
+/// written to a unique temporary file next to `path`. `commit` completes the stream, fsyncs it
+/// and renames it over `path`, so readers opening `path` see either the previous or the new file,
+/// never a partial one. Concurrent writers, in this or other processes, are serialized by the
+/// lock. Dropping the writer without `commit` discards the temporary file.
+///
+/// The lock file records the holder's pid and a timestamp. A lock older than
+/// `stale_lock_timeout` seconds (parameter, default 60) is assumed to belong to a dead process
+/// and is broken, so a writer must not hold the lock longer than that.
+pub struct CacheFileWriter {
+    writer: Option<Box<dyn Write>>,
+    temp_file: File,
+    temp_path: PathBuf,
+    path: PathBuf,
+    failed: Arc<AtomicBool>,
+    _lock: CacheLock,
+}
+
+impl CacheFileWriter {
+    /// Lock `path` and start writing its new content. Blocks while another writer holds the lock.
+    /// All parameters other than `stale_lock_timeout` are passed to `compressed_writer`.
+    pub fn create<P: AsRef<Path>, T: Into<ParamSet>>(
+        path: P,
+        compression_type: CompressionType,
+        option: T) -> Result<CacheFileWriter, Box<dyn Error>> {
+        let path = path.as_ref().to_path_buf();
+        let param_set: ParamSet = option.into();
+        let stale_timeout = Duration::from_secs(param_set.get_integer("stale_lock_timeout", DEFAULT_STALE_LOCK_TIMEOUT)?);
+        let lock = CacheLock::acquire(sidecar(&path, ".lock"), stale_timeout)?;
+        let temp_path = sidecar(&path, &format!(".{}.{}.tmp", std::process::id(), TEMP_COUNTER.fetch_add(1, Ordering::Relaxed)));
+        let temp_file = File::create(&temp_path)?;
+        let failed = Arc::new(AtomicBool::new(false));
+        let sink = FailureTracking { inner: temp_file.try_clone()?, failed: failed.clone() };
+        let writer = match compressed_writer(Box::new(sink), compression_type, param_set) {
+            Ok(writer) => writer,
+            Err(e) => {
+                let _ = std::fs::remove_file(&temp_path);
+                return Err(e);
+            }
+        };
+        return Ok(CacheFileWriter {
+            writer: Some(writer),
+            temp_file,
+            temp_path,
+            path,
+            failed,
+            _lock: lock,
+        });
+    }
+
+    /// Complete the compressed stream and atomically replace the target file with it.
+    pub fn commit(mut self) -> Result<(), Box<dyn Error>> {
+        let mut writer = self.writer.take().unwrap();
+        writer.flush()?;
+        drop(writer);
+        if self.failed.load(Ordering::Relaxed) {
+            return Err("writing the compressed cache file failed".into());
+        }
+        self.temp_file.sync_all()?;
+        std::fs::rename(&self.temp_path, &self.path)?;
+        #[cfg(unix)]
+        if let Some(dir) = self.path.parent() {
+            let dir = if dir.as_os_str().is_empty() { Path::new(".") } else { dir };
+            File::open(dir)?.sync_all()?;
+        }
+        return Ok(());
+    }
+}
+
+impl Write for CacheFileWriter {
+    fn write(&mut self, data: &[u8]) -> std::io::Result<usize> {
+        return self.writer.as_mut().unwrap().write(data);
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        return self.writer.as_mut().unwrap().flush();
+    }
+}
+
+impl Drop for CacheFileWriter {
+    fn drop(&mut self) {
+        if self.writer.take().is_some() {
+            // not committed
+            let _ = std::fs::remove_file(&self.temp_path);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::decompressed_reader;
+
+    const CHILD_ENV: &str = "FINAL_COMPRESSION_CACHE_CHILD";
+
+    fn payload(id: u8) -> Vec<u8> {
+        return (0..300_000u32).map(|i| id ^ (i % 97) as u8).collect();
+    }
+
+    fn write_cache(path: &Path, id: u8) {
+        let mut writer = CacheFileWriter::create(path, CompressionType::Zstd, "level=1").unwrap();
+        for chunk in payload(id).chunks(10_000) {
+            writer.write_all(chunk).unwrap();
+        }
+        writer.commit().unwrap();
+    }
+
+    /// Returns the writer id the complete file was written by
+    fn verify(path: &Path) -> u8 {
+        let mut reader = decompressed_reader(Box::new(File::open(path).unwrap()), CompressionType::Zstd).unwrap();
+        let mut content = Vec::new();
+        reader.read_to_end(&mut content).unwrap();
+        let id = content[0];
+        assert!(content == payload(id), "cache file mixes writers");
+        return id;
+    }
+
+    fn test_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("final_compression.cache.{}.{}", name, std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        return dir;
+    }
+
+    #[test]
+    pub fn test_concurrent_threads() {
+        let dir = test_dir("threads");
+        let path = dir.join("artifact.zst");
+        let done = Arc::new(AtomicBool::new(false));
+        let reader = {
+            let (path, done) = (path.clone(), done.clone());
+            std::thread::spawn(move || {
+                while !done.load(Ordering::Relaxed) {
+                    if path.exists() {
+                        verify(&path);
+                    }
+                }
+            })
+        };
+        let writers: Vec<_> = (1..=8u8)
+            .map(|id| {
+                let path = path.clone();
+                std::thread::spawn(move || write_cache(&path, id))
+            })
+            .collect();
+        for writer in writers {
+            writer.join().unwrap();
+        }
+        done.store(true, Ordering::Relaxed);
+        reader.join().unwrap();
+        assert!((1..=8).contains(&verify(&path)));
+        let leftovers: Vec<_> = std::fs::read_dir(&dir).unwrap().collect();
+        assert_eq!(leftovers.len(), 1, "lock or temp files left behind");
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    pub fn test_uncommitted_is_discarded() {
+        let dir = test_dir("discard");
+        let path = dir.join("artifact.zst");
+        write_cache(&path, 1);
+        let mut writer = CacheFileWriter::create(&path, CompressionType::Zstd, "").unwrap();
+        writer.write_all(&payload(2)).unwrap();
+        drop(writer);
+        assert_eq!(verify(&path), 1);
+        assert_eq!(std::fs::read_dir(&dir).unwrap().count(), 1);
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    pub fn test_stale_lock_is_broken() {
+        let dir = test_dir("stale");
+        let path = dir.join("artifact.zst");
+        std::fs::write(sidecar(&path, ".lock"), "999999 0").unwrap();
+        write_cache(&path, 3);
+        assert_eq!(verify(&path), 3);
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+
+    /// Body of the subprocesses spawned by `test_concurrent_processes`
+    #[test]
+    pub fn test_child_writer() {
+        if let Ok(spec) = std::env::var(CHILD_ENV) {
+            let (id, path) = spec.split_once(':').unwrap();
+            write_cache(Path::new(path), id.parse().unwrap());
+        }
+    }
+
+    #[cfg(unix)]
+    #[test]
+    pub fn test_concurrent_processes() {
+        let dir = test_dir("processes");
+        let path = dir.join("artifact.zst");
+        let exe = std::env::current_exe().unwrap();
+        let children: Vec<_> = (1..=4u8)
+            .map(|id| std::process::Command::new(&exe)
+                .args(["--exact", "cache::tests::test_child_writer", "--test-threads=1"])
+                .env(CHILD_ENV, format!("{}:{}", id, path.display()))
+                .stdout(std::process::Stdio::null())
+                .spawn()
+                .unwrap())
+            .collect();
+        for mut child in children {
+            assert!(child.wait().unwrap().success());
+        }
+        assert!((1..=4).contains(&verify(&path)));
+        assert_eq!(std::fs::read_dir(&dir).unwrap().count(), 1);
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+}
@@ -0,0 +1,117 @@
+use std::error::Error;
+use std::fmt;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::Instant;
+
+/// Cooperative cancellation shared by long running helpers: the jobs of `CompressionQueue`,
+/// `compress_copy_with_token`, `decompress_copy_with_token`, `compress_file_with_token`,
+/// `decompress_file` through `Preflight::cancel`, `compress_tree_with_token` and
+/// `ResumableCompress::with_token`.
+///
+/// Cloning is cheap and every clone observes the same cancellation. Helpers check the token
+/// once per 64 KiB buffer they copy, so a cancelled operation stops within one buffer of work
+/// and reports `Cancelled` with the progress made so far. The file helpers remove their partial
+/// output, unless `Preflight::resume` keeps it for later, and the queue the file of a
+/// `JobOutput::Path`.
+#[derive(Debug, Clone, Default)]
+pub struct CancelToken {
+    state: Arc<TokenState>,
+}
+
+#[derive(Debug, Default)]
+struct TokenState {
+    cancelled: AtomicBool,
+    deadline: Option<Instant>,
+    parent: Option<Arc<TokenState>>,
+}
+
+impl TokenState {
+    fn is_cancelled(&self) -> bool {
+        if self.cancelled.load(Ordering::SeqCst) {
+            return true;
+        }
+        if matches!(self.deadline, Some(deadline) if Instant::now() >= deadline) {
+            return true;
+        }
+        return self.parent.as_ref().is_some_and(|parent| parent.is_cancelled());
+    }
+}
+
+impl CancelToken {
+    /// A token that is only cancelled by `cancel`
+    pub fn new() -> CancelToken {
+        return CancelToken::default();
+    }
+
+    /// A token that is also cancelled once `deadline` passed
+    pub fn with_deadline(deadline: Instant) -> CancelToken {
+        return CancelToken { state: Arc::new(TokenState { deadline: Some(deadline), ..TokenState::default() }) };
+    }
+
+    /// A token cancelled with this one, that can also be cancelled on its own
+    pub fn child(&self) -> CancelToken {
+        return CancelToken { state: Arc::new(TokenState { parent: Some(self.state.clone()), ..TokenState::default() }) };
+    }
+
+    /// Cancel this token, its clones and its children
+    pub fn cancel(&self) {
+        self.state.cancelled.store(true, Ordering::SeqCst);
+    }
+
+    /// Whether this token, a parent of it, or a deadline on the way cancelled it
+    pub fn is_cancelled(&self) -> bool {
+        return self.state.is_cancelled();
+    }
+
+    /// `Err(Cancelled)` carrying the given progress if cancelled, for the checks of helpers
+    pub fn check(&self, bytes_in: u64, bytes_out: u64) -> Result<(), Cancelled> {
+        if self.is_cancelled() {
+            return Err(Cancelled { bytes_in, bytes_out });
+        }
+        return Ok(());
+    }
+}
+
+/// An operation stopped by its `CancelToken`, with its progress at the stop point
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Cancelled {
+    /// Bytes consumed: uncompressed when compressing, compressed when decompressing
+    pub bytes_in: u64,
+    /// Bytes produced
+    pub bytes_out: u64,
+}
+
+impl fmt::Display for Cancelled {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "cancelled after {} bytes in, {} bytes out", self.bytes_in, self.bytes_out)
+    }
+}
+
+impl Error for Cancelled {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    #[test]
+    pub fn test_tokens() {
+        let parent = CancelToken::new();
+        let child = parent.child();
+        let grandchild = child.child();
+        let clone = child.clone();
+        assert!(!grandchild.is_cancelled());
+        clone.cancel();
+        assert!(child.is_cancelled() && grandchild.is_cancelled());
+        assert!(!parent.is_cancelled());
+        assert_eq!(grandchild.check(3, 1), Err(Cancelled { bytes_in: 3, bytes_out: 1 }));
+        parent.cancel();
+        assert!(parent.child().is_cancelled());
+
+        let expired = CancelToken::with_deadline(Instant::now());
+        assert!(expired.is_cancelled() && expired.child().is_cancelled());
+        let later = CancelToken::with_deadline(Instant::now() + Duration::from_secs(3600));
+        assert!(later.check(0, 0).is_ok());
+    }
+}
@@ -0,0 +1,130 @@
+//! Canonical output, for caches and artifact stores keyed on compressed bytes.
+//!
+//! With `canonical=true`, `compressed_writer` output depends only on the input, the codec and
+//! its parameters: not on the machine, the time or the thread count. The guarantee:
+//!
+//! - for every built-in codec, the output of `canonical=true` at the default parameters only
+//!   changes with the major version of the crate;
+//! - `fingerprint` changes whenever that output does, so storing it next to cached bytes tells
+//!   when they can no longer be reproduced.
+//!
+//! The golden outputs under `tests/golden/v<major>` enforce it. A backend upgrade changing them
+//! fails the tests; updating the files (`FINAL_COMPRESSION_BLESS=1 cargo test golden`) and the
+//! pinned fingerprint is then an explicit change, only acceptable in a major version.
+//!
+//! Parameters with a machine dependent effect are refused: `workers=0` of zstd, and codecs
+//! registered at runtime, whose output this crate cannot vouch for.
+use std::error::Error;
+use std::io::Write;
+use std::sync::OnceLock;
+use crate::describe::BUILTIN_CODECS;
+use crate::{compressed_writer, CompressionType, ParamSet};
+
+/// Size of `canonical_input`
+const INPUT_SIZE: usize = 16 * 1024;
+
+/// Fail if `param_set` asks for canonical output that `compression_type` cannot guarantee
+pub(crate) fn check(compression_type: CompressionType, param_set: &ParamSet) -> Result<(), Box<dyn Error>> {
+    if !param_set.get_flag("canonical", false)? {
+        return Ok(());
+    }
+    if let CompressionType::Custom(_) = compression_type {
+        return Err("canonical=true needs a built-in codec, registered codecs may not be deterministic".into());
+    }
+    if compression_type == CompressionType::Zstd && param_set.get_integer("workers", 1)? == 0 {
+        return Err("canonical=true needs a fixed zstd workers count, not 0".into());
+    }
+    return Ok(());
+}
+
+/// The input of the golden outputs: text, then bytes of mixed entropy, the same on every run
+pub fn canonical_input() -> Vec<u8> {
+    let mut data = b"canonical output is keyed on these bytes; ".repeat(INPUT_SIZE / 2 / 42 + 1);
+    data.truncate(INPUT_SIZE / 2);
+    let mut state = 0x2545_f491_4f6c_dd1du64;
+    while data.len() < INPUT_SIZE {
+        state ^= state << 13;
+        state ^= state >> 7;
+        state ^= state << 17;
+        data.push(if state.is_multiple_of(4) { state as u8 } else { (state % 16) as u8 });
+    }
+    return data;
+}
+
+/// Output of `compression_type` for `canonical_input` with `canonical=true` and the defaults
+pub fn canonical_output(compression_type: CompressionType) -> Result<Vec<u8>, Box<dyn Error>> {
+    let buffer = crate::buffer::SharedBuffer::default();
+    let mut w = compressed_writer(Box::new(buffer.clone()), compression_type, "canonical=true")?;
+    w.write_all(&canonical_input())?;
+    drop(w);
+    return Ok(buffer.take());
+}
+
+/// Identifier of the canonical output of all built-in codecs: the major version and a hash of
+/// every `canonical_output`. Computed once per process.
+pub fn fingerprint() -> &'static str {
+    static FINGERPRINT: OnceLock<String> = OnceLock::new();
+    return FINGERPRINT.get_or_init(|| {
+        // FNV-1a, stable across Rust versions unlike `DefaultHasher`
+        let mut hash = 0xcbf2_9ce4_8422_2325u64;
+        for codec in BUILTIN_CODECS {
+            let output = canonical_output(codec.compression_type).expect("built-in codecs support canonical output");
+            let length = (output.len() as u64).to_le_bytes();
+            for byte in codec.name.as_bytes().iter().chain(&length).chain(&output) {
+                hash = (hash ^ *byte as u64).wrapping_mul(0x0100_0000_01b3);
+            }
+        }
+        format!("v{}-{:016x}", env!("CARGO_PKG_VERSION_MAJOR"), hash)
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Read;
+    use std::path::PathBuf;
+
+    /// Changes only with the golden files, in a major version
+    const PINNED_FINGERPRINT: &str = "v1-2214f997cf64fb85";
+
+    fn golden_dir() -> PathBuf {
+        return PathBuf::from(env!("CARGO_MANIFEST_DIR")).join("tests").join("golden")
+            .join(format!("v{}", env!("CARGO_PKG_VERSION_MAJOR")));
+    }
+
+    #[test]
+    pub fn test_golden_outputs() {
+        let bless = std::env::var_os("FINAL_COMPRESSION_BLESS").is_some();
+        let dir = golden_dir();
+        let mut changed = Vec::new();
+        for codec in BUILTIN_CODECS {
+            let output = canonical_output(codec.compression_type).unwrap();
+            assert!(output == canonical_output(codec.compression_type).unwrap(), "{} is not deterministic", codec.name);
+            let mut plain = Vec::new();
+            crate::decompressed_reader(Box::new(std::io::Cursor::new(output.clone())), codec.compression_type).unwrap()
+                .read_to_end(&mut plain).unwrap();
+            assert!(plain == canonical_input(), "{}", codec.name);
+
+            let path = dir.join(format!("{}.bin", codec.name));
+            if bless {
+                std::fs::create_dir_all(&dir).unwrap();
+                std::fs::write(&path, &output).unwrap();
+            } else if std::fs::read(&path).map_err(|e| format!("{}: {}", path.display(), e)).unwrap() != output {
+                changed.push(codec.name);
+            }
+        }
+        assert!(changed.is_empty(), "canonical output changed for {:?}, see the policy of `canonical`", changed);
+        if !bless {
+            assert_eq!(fingerprint(), PINNED_FINGERPRINT);
+        }
+    }
+
+    #[test]
+    pub fn test_canonical_params() {
+        assert!(check(CompressionType::Zstd, &"canonical=true;workers=0".into()).is_err());
+        assert!(check(CompressionType::Zstd, &"canonical=true;workers=2".into()).is_ok());
+        assert!(check(CompressionType::Zstd, &"workers=0".into()).is_ok());
+        assert!(check(CompressionType::Custom(7), &"canonical=true".into()).is_err());
+        assert!(compressed_writer(Box::new(std::io::sink()), CompressionType::Gzip, "canonical=yes").is_err());
+    }
+}
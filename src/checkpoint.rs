@@ -0,0 +1,404 @@
+use std::error::Error;
+use std::fs::File;
+use std::io::{BufRead, BufReader, ErrorKind, Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::Ordering;
+use std::time::UNIX_EPOCH;
+use crate::files::{open_file, PathErrors};
+use crate::indexed::{member_decoder, Counting};
+use crate::preflight::{decompress_file_with_progress, decompressed_size_hint, Preflight, SpaceReport};
+use crate::progress::{copy_with_progress, Progress, ProgressTracker};
+use crate::summary::CountingReader;
+use crate::CompressionType;
+
+/// Suffix of the checkpoint file a resumable `decompress_file` keeps next to its output
+pub const CHECKPOINT_SUFFIX: &str = ".fcresume";
+const CHECKPOINT_HEADER: &str = "final_compression resume 2";
+
+/// Capacity of the buffer the source is read through
+const READ_BUFFER: usize = 8 * 1024;
+
+/// Output decompressed between two checkpoints, at least
+pub const CHECKPOINT_INTERVAL: u64 = 1024 * 1024;
+
+/// Output before a checkpoint whose CRC-32 it records
+const CHECKED_TAIL: u64 = 64 * 1024;
+
+#[cfg(test)]
+thread_local! {
+    /// Checkpoints after which a test interrupts the decompression, as a crash would
+    static INTERRUPT_AFTER: std::cell::Cell<Option<usize>> = const { std::cell::Cell::new(None) };
+}
+
+/// Where a `decompress_file` with `Preflight::resume` started from
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ResumeStart {
+    /// From the start, no checkpoint being there
+    Fresh,
+    /// From a checkpoint, the output before it being kept
+    Resumed { compressed_offset: u64, uncompressed_offset: u64 },
+    /// From the start, the checkpoint there not matching the source or the output, for this
+    /// reason
+    Restarted(String),
+    /// From the start, the codec having no member or frame boundaries to take checkpoints at
+    NoCheckpoints,
+}
+
+/// A gzip member or zstd frame boundary of the source and the output written up to it
+struct Checkpoint {
+    compressed_offset: u64,
+    uncompressed_offset: u64,
+    /// CRC-32 of the `CHECKED_TAIL` bytes of source before `compressed_offset`
+    source_crc: u32,
+    /// CRC-32 of the `CHECKED_TAIL` bytes of output before `uncompressed_offset`
+    output_crc: u32,
+}
+
+fn checkpoint_path(dst: &Path) -> PathBuf {
+    let mut name = dst.as_os_str().to_owned();
+    name.push(CHECKPOINT_SUFFIX);
+    return PathBuf::from(name);
+}
+
+/// Length and modification time of the source, as recorded in its checkpoints
+fn source_stamp(src: &File) -> std::io::Result<String> {
+    let metadata = src.metadata()?;
+    let modified = match metadata.modified().ok().and_then(|time| time.duration_since(UNIX_EPOCH).ok()) {
+        Some(since_epoch) => since_epoch.as_nanos().to_string(),
+        None => "-".into(),
+    };
+    return Ok(format!("{} {}", metadata.len(), modified));
+}
+
+/// CRC-32 of the `CHECKED_TAIL` bytes of `file` before `end`, leaving the file at `end`
+fn tail_crc(mut file: &File, end: u64) -> std::io::Result<u32> {
+    let start = end.saturating_sub(CHECKED_TAIL);
+    file.seek(SeekFrom::Start(start))?;
+    let mut tail = Vec::with_capacity((end - start) as usize);
+    file.take(end - start).read_to_end(&mut tail)?;
+    if tail.len() as u64 != end - start {
+        return Err(std::io::Error::new(ErrorKind::UnexpectedEof, "shorter than the checkpoint"));
+    }
+    let mut crc = flate2::Crc::new();
+    crc.update(&tail);
+    return Ok(crc.sum());
+}
+
+/// The checkpoint of `dst` if there is one, or why it does not describe a decompression of
+/// `source`, the file of `stamp`
+fn load_checkpoint(dst: &Path, source: &File, compression_type: CompressionType, stamp: &str) -> Result<Option<Checkpoint>, String> {
+    let content = match std::fs::read_to_string(checkpoint_path(dst)) {
+        Ok(content) => content,
+        Err(e) if e.kind() == ErrorKind::NotFound => return Ok(None),
+        Err(e) => return Err(format!("checkpoint unreadable: {}", e)),
+    };
+    let mut lines = content.lines();
+    if lines.next() != Some(CHECKPOINT_HEADER) {
+        return Err("checkpoint of another version".into());
+    }
+    if lines.next() != Some(format!("{} {}", compression_type.as_str(), stamp).as_str()) {
+        return Err("checkpoint of another source".into());
+    }
+    let fields: Vec<&str> = lines.next().unwrap_or("").split(' ').collect();
+    let checkpoint = match fields[..] {
+        [compressed, uncompressed, source_crc, output_crc] => (|| Some(Checkpoint {
+            compressed_offset: compressed.parse().ok()?,
+            uncompressed_offset: uncompressed.parse().ok()?,
+            source_crc: u32::from_str_radix(source_crc, 16).ok()?,
+            output_crc: u32::from_str_radix(output_crc, 16).ok()?,
+        }))(),
+        _ => None,
+    };
+    let Some(checkpoint) = checkpoint else {
+        return Err("checkpoint malformed".into());
+    };
+    // a source rewritten with the same length and time still fails here
+    match tail_crc(source, checkpoint.compressed_offset) {
+        Ok(crc) if crc == checkpoint.source_crc => {},
+        Ok(_) => return Err("source changed before the checkpoint".into()),
+        Err(e) => return Err(format!("source not checked: {}", e)),
+    }
+    let matches = File::open(dst).and_then(|file| tail_crc(&file, checkpoint.uncompressed_offset));
+    return match matches {
+        Ok(crc) if crc == checkpoint.output_crc => Ok(Some(checkpoint)),
+        Ok(_) => Err("output does not match the checkpoint".into()),
+        Err(e) => Err(format!("output not checked: {}", e)),
+    };
+}
+
+/// Replace the checkpoint of `dst`, through a temporary file so that a crash leaves the old one
+/// or the new one
+fn save_checkpoint(dst: &Path, compression_type: CompressionType, stamp: &str, checkpoint: &Checkpoint) -> std::io::Result<()> {
+    let path = checkpoint_path(dst);
+    let mut temp_name = path.as_os_str().to_owned();
+    temp_name.push(".tmp");
+    let temp = PathBuf::from(temp_name);
+    let mut file = File::create(&temp)?;
+    write!(file, "{}\n{} {}\n{} {} {:08x} {:08x}\n", CHECKPOINT_HEADER, compression_type.as_str(), stamp,
+        checkpoint.compressed_offset, checkpoint.uncompressed_offset, checkpoint.source_crc, checkpoint.output_crc)?;
+    file.sync_all()?;
+    return std::fs::rename(&temp, &path);
+}
+
+/// `decompress_file_with_progress` for `Preflight::resume`, picking up where an interrupted run
+/// stopped.
+///
+/// Gzip files of several members and zstd files of several frames are decompressed a member at
+/// a time. Once `CHECKPOINT_INTERVAL` bytes were written since the last checkpoint, the output is
+/// synced and a checkpoint saved to `<dst>.fcresume`: the offsets of the boundary in the source
+/// and the output, the length and modification time of the source, and the CRC-32 of the 64 KiB
+/// of source and of output before the boundary. A later call finding that file checks the source
+/// and the output against it, truncates the output to the checkpoint and decompresses from there.
+/// A checkpoint of another source, or an output that changed, restarts from the start.
+///
+/// On an error the output and the checkpoint are kept for the next call once a checkpoint
+/// exists, otherwise the output is removed. The checkpoint is removed when the decompression
+/// completes. The other codecs have no boundaries to resume at and are decompressed from the
+/// start. Only the remaining output is checked against the free space of a resumed run, when
+/// its size is known.
+pub(crate) fn decompress_resumable(
+    src: &Path,
+    dst: &Path,
+    compression_type: CompressionType,
+    preflight: &Preflight,
+    on_progress: &mut dyn FnMut(&Progress)) -> Result<SpaceReport, Box<dyn Error>> {
+    if !matches!(compression_type, CompressionType::Gzip | CompressionType::Zstd) {
+        let preflight = Preflight { resume: false, ..preflight.clone() };
+        let report = decompress_file_with_progress(src, dst, compression_type, &preflight, on_progress)?;
+        return Ok(SpaceReport { resume: Some(ResumeStart::NoCheckpoints), ..report });
+    }
+    let mut input = open_file(src, |path| File::open(path))?;
+    // read by the checks, the position of `input` belonging to the decoder
+    let source = open_file(src, |path| File::open(path))?;
+    let (total_in, stamp) = input.metadata().and_then(|metadata| Ok((metadata.len(), source_stamp(&input)?)))
+        .map_err(|e| format!("{}: {}", src.display(), e))?;
+    let dir = match dst.parent() {
+        Some(parent) if !parent.as_os_str().is_empty() => parent,
+        _ => Path::new("."),
+    };
+    let (from, start) = match load_checkpoint(dst, &source, compression_type, &stamp) {
+        Ok(Some(checkpoint)) => {
+            let start = ResumeStart::Resumed {
+                compressed_offset: checkpoint.compressed_offset,
+                uncompressed_offset: checkpoint.uncompressed_offset,
+            };
+            (checkpoint, start)
+        },
+        Ok(None) => (Checkpoint { compressed_offset: 0, uncompressed_offset: 0, source_crc: 0, output_crc: 0 }, ResumeStart::Fresh),
+        Err(reason) => (Checkpoint { compressed_offset: 0, uncompressed_offset: 0, source_crc: 0, output_crc: 0 }, ResumeStart::Restarted(reason)),
+    };
+    let resumed = matches!(start, ResumeStart::Resumed { .. });
+    input.seek(SeekFrom::Start(from.compressed_offset)).map_err(|e| format!("{}: {}", src.display(), e))?;
+    let size = decompressed_size_hint(&mut input, compression_type).map_err(|e| format!("{}: {}", src.display(), e))?;
+    let mut report = preflight.check(dir, size)?;
+    report.resume = Some(start);
+    let out = if resumed {
+        let out = open_file(dst, |path| File::options().read(true).write(true).open(path))?;
+        out.set_len(from.uncompressed_offset).map_err(|e| format!("{}: {}", dst.display(), e))?;
+        out
+    } else {
+        let _ = std::fs::remove_file(checkpoint_path(dst));
+        open_file(dst, |path| File::options().read(true).write(true).create(true).truncate(true).open(path))?
+    };
+    let mut saved = resumed;
+    let mut fill = || -> Result<(), Box<dyn Error>> {
+        if let Some(size) = size {
+            preflight.preallocate(&out, from.uncompressed_offset + size, &mut report);
+        }
+        (&out).seek(SeekFrom::Start(from.uncompressed_offset)).map_err(|e| format!("{}: {}", dst.display(), e))?;
+        let (counted, consumed) = CountingReader::new(PathErrors::new(&input, src));
+        let read = || from.compressed_offset + consumed.load(Ordering::Relaxed);
+        let mut source_reader = Counting { inner: BufReader::with_capacity(READ_BUFFER, counted), consumed: from.compressed_offset };
+        let mut tracker = ProgressTracker::new(Some(total_in));
+        let mut written = from.uncompressed_offset;
+        let mut last = written;
+        #[cfg(test)]
+        let mut taken = 0;
+        loop {
+            let next = source_reader.fill_buf()?;
+            // like `decompressed_reader`, gzip stops before trailing data that is not a member.
+            // The buffer can end after the first byte of a magic, so only that byte is checked.
+            if next.is_empty() || (compression_type == CompressionType::Gzip && next[0] != 0x1f) {
+                break;
+            }
+            let member_start = source_reader.consumed;
+            let before = written;
+            written += copy_with_progress(&mut member_decoder(&mut source_reader, compression_type)?, &mut PathErrors::new(&out, dst),
+                &mut tracker, |copied| (read(), before + copied), on_progress, &preflight.cancel)?;
+            if source_reader.consumed == member_start {
+                return Err(format!("{}: empty member at offset {}", src.display(), member_start).into());
+            }
+            if written - last < CHECKPOINT_INTERVAL || source_reader.fill_buf()?.is_empty() {
+                continue;
+            }
+            let checkpoint = (|| -> std::io::Result<()> {
+                out.sync_data()?;
+                let checkpoint = Checkpoint {
+                    compressed_offset: source_reader.consumed,
+                    uncompressed_offset: written,
+                    source_crc: tail_crc(&source, source_reader.consumed)?,
+                    output_crc: tail_crc(&out, written)?,
+                };
+                return save_checkpoint(dst, compression_type, &stamp, &checkpoint);
+            })();
+            checkpoint.map_err(|e| format!("{}: {}", checkpoint_path(dst).display(), e))?;
+            saved = true;
+            last = written;
+            #[cfg(test)]
+            {
+                taken += 1;
+                if INTERRUPT_AFTER.with(|n| n.get()) == Some(taken) {
+                    return Err("interrupted".into());
+                }
+            }
+        }
+        out.set_len(written).map_err(|e| format!("{}: {}", dst.display(), e))?;
+        report.written = written;
+        on_progress(&tracker.finish(read(), written));
+        return Ok(());
+    };
+    if let Err(e) = fill() {
+        if !saved {
+            drop(out);
+            let _ = std::fs::remove_file(dst);
+        }
+        return Err(e);
+    }
+    let _ = std::fs::remove_file(checkpoint_path(dst));
+    return Ok(report);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Write;
+    use crate::{compress_bytes, decompress_file, CompressionType};
+
+    /// 4 MiB of text that does not repeat within a member
+    fn content() -> Vec<u8> {
+        let mut out = Vec::new();
+        let mut n = 0u64;
+        while out.len() < 4 * 1024 * 1024 {
+            out.extend_from_slice(format!("line {} of a file decompressed with checkpoints\n", n.wrapping_mul(2654435761) % 1_000_003).as_bytes());
+            n += 1;
+        }
+        return out;
+    }
+
+    fn resumable() -> Preflight {
+        return Preflight { resume: true, ..Preflight::default() };
+    }
+
+    /// Decompress `src` into `dst`, returning where it started from and the output size
+    fn resume(src: &Path, dst: &Path, compression_type: CompressionType) -> (ResumeStart, u64) {
+        let report = decompress_file(src, dst, compression_type, &resumable()).unwrap();
+        return (report.resume.unwrap(), report.written);
+    }
+
+    fn interrupted(src: &Path, dst: &Path, compression_type: CompressionType, after: usize) {
+        INTERRUPT_AFTER.with(|n| n.set(Some(after)));
+        let err = decompress_file(src, dst, compression_type, &resumable()).unwrap_err();
+        INTERRUPT_AFTER.with(|n| n.set(None));
+        assert_eq!(err.to_string(), "interrupted");
+        assert!(dst.exists() && checkpoint_path(dst).exists());
+    }
+
+    #[test]
+    pub fn test_resume() {
+        let dir = std::env::temp_dir().join(format!("final_compression_checkpoint_{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let text = content();
+        let gzip = compress_bytes(&text, CompressionType::Gzip, "member_max_uncompressed=256KiB").unwrap();
+        let mut zstd = Vec::new();
+        for chunk in text.chunks(300_000) {
+            zstd.extend(compress_bytes(chunk, CompressionType::Zstd, "level=3").unwrap());
+        }
+        for (ct, compressed) in [(CompressionType::Gzip, gzip), (CompressionType::Zstd, zstd)] {
+            let src = dir.join(format!("source.{}", ct));
+            let dst = dir.join(format!("plain.{}", ct));
+            std::fs::write(&src, &compressed).unwrap();
+
+            assert_eq!(resume(&src, &dst, ct), (ResumeStart::Fresh, text.len() as u64), "{}", ct);
+            assert!(std::fs::read(&dst).unwrap() == text && !checkpoint_path(&dst).exists(), "{}", ct);
+
+            interrupted(&src, &dst, ct, 2);
+            let (start, written) = resume(&src, &dst, ct);
+            let ResumeStart::Resumed { compressed_offset, uncompressed_offset } = start else {
+                panic!("{}: {:?}", ct, start);
+            };
+            assert!(compressed_offset > 0 && compressed_offset < compressed.len() as u64, "{}", ct);
+            assert!(uncompressed_offset >= 2 * CHECKPOINT_INTERVAL, "{}", ct);
+            assert_eq!(written, text.len() as u64, "{}", ct);
+            assert!(std::fs::read(&dst).unwrap() == text && !checkpoint_path(&dst).exists(), "{}", ct);
+
+            // an output changed before the checkpoint is not trusted
+            interrupted(&src, &dst, ct, 1);
+            let mut changed = std::fs::read(&dst).unwrap();
+            let end = changed.len() - 10;
+            changed[end] ^= 1;
+            std::fs::write(&dst, &changed).unwrap();
+            let (start, _) = resume(&src, &dst, ct);
+            assert_eq!(start, ResumeStart::Restarted("output does not match the checkpoint".into()), "{}", ct);
+            assert!(std::fs::read(&dst).unwrap() == text, "{}", ct);
+
+            // a checkpoint of another source is not used either
+            interrupted(&src, &dst, ct, 1);
+            std::fs::write(&src, [&compressed[..], &compressed[..]].concat()).unwrap();
+            let (start, written) = resume(&src, &dst, ct);
+            assert_eq!(start, ResumeStart::Restarted("checkpoint of another source".into()), "{}", ct);
+            assert_eq!(written, 2 * text.len() as u64, "{}", ct);
+        }
+
+        let src = dir.join("source.xz");
+        std::fs::write(&src, compress_bytes(&text, CompressionType::XZ, "level=1").unwrap()).unwrap();
+        assert_eq!(resume(&src, &dir.join("plain.xz"), CompressionType::XZ), (ResumeStart::NoCheckpoints, text.len() as u64));
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    pub fn test_regenerated_source() {
+        let dir = std::env::temp_dir().join(format!("final_compression_checkpoint_regenerated_{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let text = content();
+        let other: Vec<u8> = text.iter().map(|b| if *b == b'l' { b'L' } else { *b }).collect();
+        // stored blocks: both sources have the same length
+        let first = compress_bytes(&text, CompressionType::Gzip, "level=0;member_max_uncompressed=256KiB").unwrap();
+        let second = compress_bytes(&other, CompressionType::Gzip, "level=0;member_max_uncompressed=256KiB").unwrap();
+        assert_eq!(first.len(), second.len());
+        let src = dir.join("source.gz");
+        let dst = dir.join("plain");
+        std::fs::write(&src, &first).unwrap();
+        interrupted(&src, &dst, CompressionType::Gzip, 1);
+
+        // rewritten with its length and modification time kept
+        let modified = std::fs::metadata(&src).unwrap().modified().unwrap();
+        std::fs::write(&src, &second).unwrap();
+        File::options().write(true).open(&src).unwrap().set_modified(modified).unwrap();
+        let (start, _) = resume(&src, &dst, CompressionType::Gzip);
+        assert_eq!(start, ResumeStart::Restarted("source changed before the checkpoint".into()));
+        assert!(std::fs::read(&dst).unwrap() == other);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    pub fn test_magic_split_by_the_buffer() {
+        let dir = std::env::temp_dir().join(format!("final_compression_checkpoint_split_{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        // a first member whose comment pads it to one byte short of the read buffer, so that only
+        // the first byte of the next magic is buffered after it
+        let member = |comment_len: usize| {
+            let mut encoder = flate2::GzBuilder::new().comment(vec![b'c'; comment_len]).write(Vec::new(), flate2::Compression::default());
+            encoder.write_all(b"first member\n").unwrap();
+            return encoder.finish().unwrap();
+        };
+        let first = member(1 + READ_BUFFER - 1 - member(1).len());
+        assert_eq!(first.len(), READ_BUFFER - 1);
+        let second = compress_bytes(b"second member\n", CompressionType::Gzip, "").unwrap();
+        let src = dir.join("split.gz");
+        let dst = dir.join("split");
+        std::fs::write(&src, [first, second].concat()).unwrap();
+        assert_eq!(resume(&src, &dst, CompressionType::Gzip), (ResumeStart::Fresh, 27));
+        assert_eq!(std::fs::read(&dst).unwrap(), b"first member\nsecond member\n");
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
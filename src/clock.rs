@@ -0,0 +1,48 @@
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+/// Source of the current time, so time based behavior can be tested without sleeping.
+pub trait Clock: Send + Sync {
+    fn now(&self) -> Instant;
+}
+
+/// The real monotonic clock
+#[derive(Debug, Clone, Copy, Default)]
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now(&self) -> Instant {
+        return Instant::now();
+    }
+}
+
+/// A clock that only moves when told to
+#[derive(Debug)]
+pub struct ManualClock {
+    now: Mutex<Instant>,
+}
+
+impl ManualClock {
+    /// Create a clock frozen at the current instant
+    pub fn new() -> ManualClock {
+        return ManualClock { now: Mutex::new(Instant::now()) };
+    }
+
+    /// Move the clock forward by `by`
+    pub fn advance(&self, by: Duration) {
+        let mut now = self.now.lock().unwrap_or_else(|e| e.into_inner());
+        *now += by;
+    }
+}
+
+impl Default for ManualClock {
+    fn default() -> Self {
+        return ManualClock::new();
+    }
+}
+
+impl Clock for ManualClock {
+    fn now(&self) -> Instant {
+        return *self.now.lock().unwrap_or_else(|e| e.into_inner());
+    }
+}
@@ -0,0 +1,197 @@
+use std::error::Error;
+use std::fmt;
+use std::io::{Read, Write};
+use std::process::{Command, ExitStatus, Stdio};
+use std::time::Instant;
+use crate::{durable_writer, CompressionType, ParamSet};
+use crate::summary::OperationSummary;
+
+const COPY_BUFFER_SIZE: usize = 64 * 1024;
+/// Default number of stderr bytes kept by `compress_command_output`
+pub const DEFAULT_MAX_STDERR: usize = 64 * 1024;
+
+/// Error of `compress_command_output` when the process exited unsuccessfully.
+///
+/// The compressed stream was still completed, `summary` tells how much of it was written.
+#[derive(Debug)]
+pub struct CommandFailed {
+    pub status: ExitStatus,
+    /// First `max_stderr` bytes the process wrote to stderr
+    pub stderr: Vec<u8>,
+    /// Whether stderr output beyond `max_stderr` was discarded
+    pub stderr_truncated: bool,
+    pub summary: OperationSummary,
+}
+
+impl fmt::Display for CommandFailed {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "command failed with {} after {} bytes", self.status, self.summary.bytes_in)?;
+        let stderr = String::from_utf8_lossy(&self.stderr);
+        let stderr = stderr.trim_end();
+        if !stderr.is_empty() {
+            write!(f, ": {}", stderr)?;
+            if self.stderr_truncated {
+                write!(f, "...")?;
+            }
+        }
+        return Ok(());
+    }
+}
+
+impl Error for CommandFailed {}
+
+/// Keep the first `max` bytes of `src`, discarding (but still reading) the rest so the process
+/// never blocks on a full stderr pipe.
+fn drain_stderr(mut src: impl Read, max: usize) -> (Vec<u8>, bool) {
+    let mut kept = Vec::new();
+    let mut truncated = false;
+    let mut buffer = [0u8; 4096];
+    loop {
+        let read = match src.read(&mut buffer) {
+            Ok(0) => break,
+            Ok(read) => read,
+            Err(e) if e.kind() == std::io::ErrorKind::Interrupted => continue,
+            Err(_) => break,
+        };
+        let room = max - kept.len();
+        if read > room {
+            truncated = true;
+        }
+        kept.extend_from_slice(&buffer[..read.min(room)]);
+    }
+    return (kept, truncated);
+}
+
+/// Run `cmd` and compress its stdout into `dst` while it streams.
+///
+/// stderr is drained concurrently, so a chatty process cannot dead lock on a full pipe; the first
+/// `max_stderr` bytes (parameter, default 64 KiB) are kept for the error report. All other
+/// parameters are passed to `compressed_writer`.
+///
+/// A non-zero exit status is reported as a `CommandFailed` error. Output produced before the
+/// failure is still compressed into a complete stream; failing to complete it is an error of
+/// its own, reported instead.
+pub fn compress_command_output<T: Into<ParamSet>>(
+    cmd: &mut Command,
+    dst: Box<dyn Write>,
+    compression_type: CompressionType,
+    option: T) -> Result<OperationSummary, Box<dyn Error>> {
+    let started = Instant::now();
+    let param_set: ParamSet = option.into();
+    let max_stderr = param_set.get_size("max_stderr", DEFAULT_MAX_STDERR)?;
+    let mut writer = durable_writer(dst, compression_type, param_set)?;
+
+    let mut child = cmd.stdin(Stdio::null()).stdout(Stdio::piped()).stderr(Stdio::piped()).spawn()?;
+    let mut stdout = child.stdout.take().unwrap();
+    let stderr = child.stderr.take().unwrap();
+    let stderr_thread = std::thread::spawn(move || drain_stderr(stderr, max_stderr));
+
+    let mut buffer = vec![0u8; COPY_BUFFER_SIZE];
+    let mut bytes_in = 0u64;
+    let copied: Result<(), std::io::Error> = loop {
+        let read = match stdout.read(&mut buffer) {
+            Ok(0) => break Ok(()),
+            Ok(read) => read,
+            Err(e) if e.kind() == std::io::ErrorKind::Interrupted => continue,
+            Err(e) => break Err(e),
+        };
+        if let Err(e) = writer.write_all(&buffer[..read]) {
+            break Err(e);
+        }
+        bytes_in += read as u64;
+    };
+    if let Err(e) = copied {
+        let _ = child.kill();
+        let _ = child.wait();
+        let _ = stderr_thread.join();
+        return Err(e.into());
+    }
+    // errors writing the trailer are reported, once the process is reaped
+    let finished = writer.finish();
+    drop(stdout);
+
+    let status = child.wait()?;
+    let (stderr, stderr_truncated) = stderr_thread.join().unwrap_or_default();
+    let summary = OperationSummary {
+        bytes_in,
+        bytes_out: finished?,
+        elapsed: started.elapsed(),
+    };
+    if !status.success() {
+        return Err(Box::new(CommandFailed { status, stderr, stderr_truncated, summary }));
+    }
+    return Ok(summary);
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+    use super::*;
+    use crate::decompressed_reader;
+
+    fn run(script: &str, ct: CompressionType, params: &str) -> (Result<OperationSummary, Box<dyn Error>>, Vec<u8>) {
+        let file_name = std::env::temp_dir().join(format!("final_compression.command.{}.{:?}.{}", std::process::id(), ct, script.len()));
+        let out = std::fs::File::create(&file_name).unwrap();
+        let result = compress_command_output(Command::new("sh").arg("-c").arg(script), Box::new(out), ct, params);
+        let compressed = std::fs::File::open(&file_name).unwrap();
+        let mut reader = decompressed_reader(Box::new(compressed), ct).unwrap();
+        let mut output = Vec::new();
+        reader.read_to_end(&mut output).unwrap();
+        let _ = std::fs::remove_file(&file_name);
+        return (result, output);
+    }
+
+    #[test]
+    pub fn test_large_output() {
+        // stderr is written too, larger than a pipe buffer, to catch dead locks
+        let script = "yes 'hello world' | head -c 10485760; yes oops | head -c 200000 >&2";
+        let (result, output) = run(script, CompressionType::Zstd, "max_stderr=10");
+        let summary = result.unwrap();
+        assert_eq!(summary.bytes_in, 10 * 1024 * 1024);
+        assert!(summary.bytes_out > 0 && summary.bytes_out < summary.bytes_in / 100);
+        assert_eq!(output.len(), 10 * 1024 * 1024);
+        assert!(output.chunks(12).all(|line| line == &b"hello world\n"[..line.len()]));
+    }
+
+    #[test]
+    pub fn test_failing_command() {
+        let (result, output) = run("echo partial; echo 'no such table' >&2; exit 3", CompressionType::Gzip, "max_stderr=7");
+        let err = result.unwrap_err();
+        let failed = err.downcast_ref::<CommandFailed>().unwrap();
+        assert_eq!(failed.status.code(), Some(3));
+        assert_eq!(failed.stderr, b"no such");
+        assert!(failed.stderr_truncated);
+        assert_eq!(failed.summary.bytes_in, 8);
+        assert_eq!(output, b"partial\n");
+        assert!(err.to_string().contains("no such..."));
+    }
+
+    /// Sink refusing everything after its first `room` bytes
+    struct Full {
+        room: usize,
+    }
+
+    impl Write for Full {
+        fn write(&mut self, data: &[u8]) -> std::io::Result<usize> {
+            if self.room == 0 {
+                return Err(std::io::Error::new(std::io::ErrorKind::StorageFull, "no space left"));
+            }
+            let n = data.len().min(self.room);
+            self.room -= n;
+            return Ok(n);
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            return Ok(());
+        }
+    }
+
+    #[test]
+    pub fn test_failed_finish() {
+        for ct in [CompressionType::Gzip, CompressionType::Zstd] {
+            // the output fits the encoder buffers: the sink only fails on the trailer
+            let result = compress_command_output(Command::new("sh").arg("-c").arg("echo short"), Box::new(Full { room: 10 }), ct, "");
+            let err = result.unwrap_err();
+            assert!(err.to_string().contains("no space left"), "{}: {}", ct, err);
+        }
+    }
+}
@@ -0,0 +1,391 @@
+use std::error::Error;
+use std::fs::File;
+use std::io::{ErrorKind, Write};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::Duration;
+
+/// Suffix of the files a `Transaction` stages, after its id
+pub const STAGED_SUFFIX: &str = ".fcstaged";
+/// Prefix of the journal of a `Transaction`, in its directory
+pub const JOURNAL_PREFIX: &str = ".fcjournal.";
+const JOURNAL_HEADER: &str = "final_compression journal 1";
+/// Last line of a journal written in full
+const JOURNAL_END: &str = "end";
+/// Name of the file `lock_dir` creates in the directory it locks
+pub const LOCK_FILE: &str = ".fclock";
+const LOCK_POLL_INTERVAL: Duration = Duration::from_millis(10);
+
+static TRANSACTION_COUNTER: AtomicU64 = AtomicU64::new(0);
+
+/// Points of `Transaction::commit` where a test can simulate a crash
+#[cfg(test)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum Stage {
+    /// Every file staged, nothing synced
+    Staged,
+    /// Staged files synced, no journal
+    Synced,
+    /// Journal written, nothing renamed
+    Journaled,
+    /// First file renamed
+    Renamed,
+    /// Every file renamed, journal still there
+    Done,
+}
+
+#[cfg(test)]
+thread_local! {
+    pub(crate) static CRASH_AT: std::cell::Cell<Option<Stage>> = const { std::cell::Cell::new(None) };
+}
+
+#[cfg(test)]
+macro_rules! failpoint {
+    ($transaction:expr, $stage:ident) => {
+        if CRASH_AT.with(|crash| crash.get()) == Some(Stage::$stage) {
+            // a crash runs no cleanup
+            $transaction.abandoned = true;
+            return Err(format!("crash at {:?}", Stage::$stage).into());
+        }
+    };
+}
+
+#[cfg(not(test))]
+macro_rules! failpoint {
+    ($transaction:expr, $stage:ident) => {};
+}
+
+/// Write of several files under one directory that all appear, or none does, even across a
+/// crash: the manifests and sidecars of an output must never describe files that are missing.
+///
+/// Files are written to the paths `stage` returns. `commit` syncs them, writes a journal listing
+/// the renames to their targets, renames them and removes the journal. After a crash,
+/// `recover_pending` completes the transactions whose journal is whole and rolls back the others.
+/// A transaction dropped without `commit` removes its staged files.
+pub struct Transaction {
+    dir: PathBuf,
+    id: String,
+    /// Staged file and target, relative to `dir` with `/` separators
+    renames: Vec<(String, String)>,
+    journaled: bool,
+    abandoned: bool,
+}
+
+impl Transaction {
+    /// Transaction over the files under `dir`, which is created if missing
+    pub fn begin(dir: &Path) -> std::io::Result<Transaction> {
+        std::fs::create_dir_all(dir)?;
+        let id = format!("{}-{}", std::process::id(), TRANSACTION_COUNTER.fetch_add(1, Ordering::Relaxed));
+        return Ok(Transaction { dir: dir.to_path_buf(), id, renames: Vec::new(), journaled: false, abandoned: false });
+    }
+
+    /// Path to write the new content of `target` to, a file under the directory of the
+    /// transaction. Its parent directories are created.
+    pub fn stage(&mut self, target: &Path) -> Result<PathBuf, Box<dyn Error>> {
+        let relative = target.strip_prefix(&self.dir)
+            .map_err(|_| format!("{} is not under {}", target.display(), self.dir.display()))?;
+        let relative = relative.components().map(|c| c.as_os_str().to_str()).collect::<Option<Vec<_>>>()
+            .filter(|parts| !parts.is_empty() && parts.iter().all(|p| !p.contains(['\t', '\n'])))
+            .ok_or_else(|| format!("{}: not a name a journal can hold", target.display()))?
+            .join("/");
+        let staged = format!("{}.{}{}", relative, self.id, STAGED_SUFFIX);
+        if let Some(parent) = target.parent() {
+            std::fs::create_dir_all(parent)?;
+        }
+        self.renames.push((staged.clone(), relative));
+        return Ok(self.dir.join(staged));
+    }
+
+    fn journal(&self) -> PathBuf {
+        return self.dir.join(format!("{}{}", JOURNAL_PREFIX, self.id));
+    }
+
+    /// Put every staged file in place
+    pub fn commit(mut self) -> Result<(), Box<dyn Error>> {
+        failpoint!(self, Staged);
+        for (staged, _) in &self.renames {
+            let path = self.dir.join(staged);
+            File::open(&path).and_then(|file| file.sync_all()).map_err(|e| format!("{}: {}", path.display(), e))?;
+        }
+        failpoint!(self, Synced);
+        let mut journal = format!("{}\n", JOURNAL_HEADER);
+        for (staged, target) in &self.renames {
+            journal.push_str(&format!("{}\t{}\n", staged, target));
+        }
+        journal.push_str(JOURNAL_END);
+        journal.push('\n');
+        let mut file = File::create(self.journal())?;
+        file.write_all(journal.as_bytes())?;
+        file.sync_all()?;
+        sync_dir(&self.dir)?;
+        // from here on, recover_pending completes the transaction
+        self.journaled = true;
+        failpoint!(self, Journaled);
+        for (i, (staged, target)) in self.renames.iter().enumerate() {
+            std::fs::rename(self.dir.join(staged), self.dir.join(target))?;
+            if i == 0 {
+                failpoint!(self, Renamed);
+            }
+        }
+        for (_, target) in &self.renames {
+            sync_dir(self.dir.join(target).parent().unwrap())?;
+        }
+        failpoint!(self, Done);
+        std::fs::remove_file(self.journal())?;
+        sync_dir(&self.dir)?;
+        return Ok(());
+    }
+}
+
+impl Drop for Transaction {
+    fn drop(&mut self) {
+        if self.journaled || self.abandoned {
+            return;
+        }
+        for (staged, _) in &self.renames {
+            let _ = std::fs::remove_file(self.dir.join(staged));
+        }
+    }
+}
+
+#[cfg(unix)]
+fn sync_dir(dir: &Path) -> std::io::Result<()> {
+    return File::open(dir)?.sync_all();
+}
+
+#[cfg(not(unix))]
+fn sync_dir(_: &Path) -> std::io::Result<()> {
+    return Ok(());
+}
+
+/// What `recover_pending` did
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Recovery {
+    /// Transactions completed from their journal
+    pub completed: usize,
+    /// Files put in place by the completed transactions
+    pub restored: Vec<PathBuf>,
+    /// Staged files of transactions rolled back, removed
+    pub discarded: Vec<PathBuf>,
+}
+
+/// Lock over the transactions of a directory, taken by `lock_dir` and released when dropped
+pub struct DirLock {
+    path: PathBuf,
+}
+
+impl Drop for DirLock {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_file(&self.path);
+    }
+}
+
+#[cfg(unix)]
+fn is_running(pid: u32) -> bool {
+    let Ok(pid) = libc::pid_t::try_from(pid) else {
+        return false;
+    };
+    // signal 0 only checks the process exists, EPERM that it belongs to another user
+    return unsafe { libc::kill(pid, 0) } == 0 || std::io::Error::last_os_error().raw_os_error() == Some(libc::EPERM);
+}
+
+#[cfg(not(unix))]
+fn is_running(_: u32) -> bool {
+    return true;
+}
+
+/// Wait for the lock of `dir`, created if missing, and take it: the file `dir/.fclock` holding
+/// the pid of its owner. On Unix the lock of a process that is gone is broken; elsewhere a
+/// crashed owner leaves `.fclock` behind, to remove by hand.
+///
+/// `recover_pending` removes the staged files of every transaction on `dir`, so a writer that
+/// runs it holds this lock from the recovery until its own transaction is committed or dropped,
+/// as `tree::compress_tree` does.
+pub fn lock_dir(dir: &Path) -> std::io::Result<DirLock> {
+    std::fs::create_dir_all(dir)?;
+    let path = dir.join(LOCK_FILE);
+    loop {
+        match File::options().write(true).create_new(true).open(&path) {
+            Ok(mut file) => {
+                let lock = DirLock { path };
+                write!(file, "{}", std::process::id())?;
+                return Ok(lock);
+            },
+            Err(e) if e.kind() == ErrorKind::AlreadyExists => {
+                // an empty file is a lock whose owner has not written its pid yet
+                let owner = std::fs::read_to_string(&path).ok().and_then(|pid| pid.trim().parse::<u32>().ok());
+                if owner.is_some_and(|pid| !is_running(pid)) {
+                    let _ = std::fs::remove_file(&path);
+                    continue;
+                }
+                std::thread::sleep(LOCK_POLL_INTERVAL);
+            },
+            Err(e) => return Err(e),
+        }
+    }
+}
+
+/// Finish the transactions interrupted under `dir`: the ones with a whole journal are completed,
+/// the staged files of the others are removed. Running it again changes nothing. It must not
+/// run while a transaction on `dir` is in progress, whose staged files it would remove: see
+/// `lock_dir`.
+pub fn recover_pending(dir: &Path) -> Result<Recovery, Box<dyn Error>> {
+    let mut recovery = Recovery::default();
+    let entries = match std::fs::read_dir(dir) {
+        Ok(entries) => entries,
+        Err(e) if e.kind() == ErrorKind::NotFound => return Ok(recovery),
+        Err(e) => return Err(format!("{}: {}", dir.display(), e).into()),
+    };
+    let mut journals: Vec<PathBuf> = entries.map(|e| e.map(|e| e.path())).collect::<Result<_, _>>()?;
+    journals.retain(|path| path.file_name().and_then(|n| n.to_str()).is_some_and(|n| n.starts_with(JOURNAL_PREFIX)));
+    journals.sort();
+    for journal in journals {
+        let content = std::fs::read_to_string(&journal).unwrap_or_default();
+        let lines: Vec<&str> = content.lines().collect();
+        if lines.first() == Some(&JOURNAL_HEADER) && lines.last() == Some(&JOURNAL_END) {
+            for line in &lines[1..lines.len() - 1] {
+                let (staged, target) = line.split_once('\t').ok_or_else(|| format!("{}: invalid line {:?}", journal.display(), line))?;
+                let (staged, target) = (dir.join(staged), dir.join(target));
+                // renamed before the crash when missing
+                if staged.exists() {
+                    std::fs::rename(&staged, &target)?;
+                    recovery.restored.push(target);
+                }
+            }
+            recovery.completed += 1;
+        }
+        // an incomplete journal renamed nothing: its files are removed with the other stages
+        std::fs::remove_file(&journal)?;
+    }
+    discard_staged(dir, &mut recovery.discarded)?;
+    sync_dir(dir)?;
+    return Ok(recovery);
+}
+
+fn discard_staged(dir: &Path, discarded: &mut Vec<PathBuf>) -> std::io::Result<()> {
+    let mut children: Vec<PathBuf> = std::fs::read_dir(dir)?.map(|e| e.map(|e| e.path())).collect::<Result<_, _>>()?;
+    children.sort();
+    for child in children {
+        if std::fs::symlink_metadata(&child)?.is_dir() {
+            discard_staged(&child, discarded)?;
+        } else if child.file_name().and_then(|n| n.to_str()).is_some_and(|n| n.ends_with(STAGED_SUFFIX)) {
+            std::fs::remove_file(&child)?;
+            discarded.push(child);
+        }
+    }
+    return Ok(());
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tree::{compress_tree, verify_tree, MANIFEST_FILE};
+    use crate::CompressionType;
+
+    /// Files under `dir`, relative
+    fn listing(dir: &Path) -> Vec<String> {
+        let mut files = Vec::new();
+        let mut pending = vec![dir.to_path_buf()];
+        while let Some(current) = pending.pop() {
+            for entry in std::fs::read_dir(&current).unwrap() {
+                let path = entry.unwrap().path();
+                if path.is_dir() {
+                    pending.push(path);
+                } else {
+                    files.push(path.strip_prefix(dir).unwrap().to_string_lossy().replace('\\', "/"));
+                }
+            }
+        }
+        files.sort();
+        return files;
+    }
+
+    #[test]
+    pub fn test_crash_recovery() {
+        let root = std::env::temp_dir().join(format!("final_compression_commit_{}", std::process::id()));
+        let src = root.join("src");
+        std::fs::create_dir_all(src.join("sub")).unwrap();
+        std::fs::write(src.join("a.txt"), b"first file\n".repeat(500)).unwrap();
+        std::fs::write(src.join("sub/b.txt"), b"second file\n".repeat(700)).unwrap();
+        let complete = ["a.txt.gz", MANIFEST_FILE, "sub/b.txt.gz"];
+
+        for (stage, present) in [(Stage::Staged, false), (Stage::Synced, false), (Stage::Journaled, true), (Stage::Renamed, true), (Stage::Done, true)] {
+            let dst = root.join(format!("{:?}", stage));
+            CRASH_AT.with(|crash| crash.set(Some(stage)));
+            let err = compress_tree(&src, &dst, CompressionType::Gzip, "", |_| true).unwrap_err();
+            CRASH_AT.with(|crash| crash.set(None));
+            assert_eq!(err.to_string(), format!("crash at {:?}", stage));
+
+            // the crash left a mixed state, that recovery settles
+            let recovery = recover_pending(&dst).unwrap();
+            assert_eq!(recovery.completed, present as usize, "{:?}", stage);
+            assert_eq!(recovery.discarded.is_empty(), present, "{:?}", stage);
+            assert_eq!(recover_pending(&dst).unwrap(), Recovery::default());
+            let files = listing(&dst);
+            if present {
+                assert_eq!(files, complete, "{:?}", stage);
+                let json = std::fs::read_to_string(dst.join(MANIFEST_FILE)).unwrap();
+                assert!(json.contains("\"compressed_path\": \"sub/b.txt.gz\""));
+            } else {
+                assert!(files.is_empty(), "{:?} {:?}", stage, files);
+            }
+
+            // and the next run starts from a clean directory
+            let manifest = compress_tree(&src, &dst, CompressionType::Gzip, "", |_| true).unwrap();
+            verify_tree(&dst, &manifest).unwrap();
+            assert_eq!(listing(&dst), complete);
+        }
+
+        // a journal cut short renamed nothing: its transaction is rolled back
+        let dir = root.join("torn");
+        let mut transaction = Transaction::begin(&dir).unwrap();
+        let staged = transaction.stage(&dir.join("x.bin")).unwrap();
+        std::fs::write(&staged, b"x").unwrap();
+        std::fs::write(dir.join(format!("{}torn", JOURNAL_PREFIX)), format!("{}\n{}\tx.bin\n", JOURNAL_HEADER, staged.file_name().unwrap().to_str().unwrap())).unwrap();
+        std::mem::forget(transaction);
+        let recovery = recover_pending(&dir).unwrap();
+        assert_eq!((recovery.completed, recovery.discarded), (0, vec![staged]));
+        assert!(listing(&dir).is_empty());
+
+        // dropped without commit, nothing stays
+        let mut transaction = Transaction::begin(&dir).unwrap();
+        std::fs::write(transaction.stage(&dir.join("y.bin")).unwrap(), b"y").unwrap();
+        assert!(transaction.stage(&root.join("elsewhere")).is_err());
+        drop(transaction);
+        assert!(listing(&dir).is_empty());
+        assert_eq!(recover_pending(&root.join("missing")).unwrap(), Recovery::default());
+        std::fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[test]
+    pub fn test_concurrent_runs() {
+        let root = std::env::temp_dir().join(format!("final_compression_commit_lock_{}", std::process::id()));
+        let (src, dst) = (root.join("src"), root.join("dst"));
+        std::fs::create_dir_all(&src).unwrap();
+        std::fs::write(src.join("a.txt"), b"locked run\n".repeat(100)).unwrap();
+
+        // a run waits for the transaction in progress instead of discarding its staged files
+        let lock = lock_dir(&dst).unwrap();
+        let mut transaction = Transaction::begin(&dst).unwrap();
+        std::fs::write(transaction.stage(&dst.join("other.bin")).unwrap(), b"other").unwrap();
+        let run = {
+            let (src, dst) = (src.clone(), dst.clone());
+            std::thread::spawn(move || compress_tree(&src, &dst, CompressionType::Gzip, "", |_| true).map_err(|e| e.to_string()))
+        };
+        std::thread::sleep(Duration::from_millis(200));
+        assert!(!run.is_finished());
+        transaction.commit().unwrap();
+        drop(lock);
+        run.join().unwrap().unwrap();
+        assert_eq!(listing(&dst), ["a.txt.gz", MANIFEST_FILE, "other.bin"]);
+
+        // the lock of a process that is gone is broken
+        #[cfg(unix)]
+        {
+            std::fs::write(dst.join(LOCK_FILE), "999999999").unwrap();
+            compress_tree(&src, &dst, CompressionType::Gzip, "", |_| true).unwrap();
+            assert!(!dst.join(LOCK_FILE).exists());
+        }
+        std::fs::remove_dir_all(&root).unwrap();
+    }
+}
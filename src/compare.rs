@@ -0,0 +1,159 @@
+use std::error::Error;
+use std::io::Read;
+use crate::{decompressed_reader, CompressionType};
+
+const COMPARE_BUFFER_SIZE: usize = 64 * 1024;
+/// Maximum number of bytes reported around a difference
+pub const CONTEXT_SIZE: usize = 32;
+
+/// Outcome of `compare`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum CompareResult {
+    /// Both streams decompress to the same bytes
+    Equal,
+    /// The streams differ at uncompressed `offset`. The contexts hold up to `CONTEXT_SIZE` bytes
+    /// of each stream starting at `offset`.
+    FirstDifference { offset: u64, a_context: Vec<u8>, b_context: Vec<u8> },
+    /// One stream is a strict prefix of the other
+    LengthMismatch { a_len: u64, b_len: u64 },
+}
+
+/// One of the compared streams, with its own buffer so the two sides can be read in lockstep
+/// no matter how their decoders chunk the output.
+struct Side {
+    reader: Box<dyn Read>,
+    buffer: Vec<u8>,
+    start: usize,
+    end: usize,
+    consumed: u64,
+}
+
+impl Side {
+    fn new(reader: Box<dyn Read>) -> Side {
+        return Side { reader, buffer: vec![0u8; COMPARE_BUFFER_SIZE], start: 0, end: 0, consumed: 0 };
+    }
+
+    /// Buffered bytes not compared yet, empty at the end of the stream
+    fn available(&mut self) -> Result<&[u8], std::io::Error> {
+        while self.start == self.end {
+            match self.reader.read(&mut self.buffer) {
+                Ok(0) => break,
+                Ok(read) => {
+                    self.start = 0;
+                    self.end = read;
+                },
+                Err(e) if e.kind() == std::io::ErrorKind::Interrupted => continue,
+                Err(e) => return Err(e),
+            }
+        }
+        return Ok(&self.buffer[self.start..self.end]);
+    }
+
+    fn consume(&mut self, n: usize) {
+        self.start += n;
+        self.consumed += n as u64;
+    }
+
+    fn context(&mut self) -> Result<Vec<u8>, std::io::Error> {
+        let mut context = Vec::with_capacity(CONTEXT_SIZE);
+        while context.len() < CONTEXT_SIZE {
+            let available = self.available()?;
+            if available.is_empty() {
+                break;
+            }
+            let n = available.len().min(CONTEXT_SIZE - context.len());
+            context.extend_from_slice(&available[..n]);
+            self.consume(n);
+        }
+        return Ok(context);
+    }
+
+    fn length(&mut self) -> Result<u64, std::io::Error> {
+        loop {
+            let n = self.available()?.len();
+            if n == 0 {
+                return Ok(self.consumed);
+            }
+            self.consume(n);
+        }
+    }
+}
+
+/// Compare the uncompressed content of two streams, which may use different compression types.
+///
+/// Both streams are decompressed in lockstep with bounded memory; comparison stops at the first
+/// difference.
+pub fn compare(a: Box<dyn Read>, a_ct: CompressionType, b: Box<dyn Read>, b_ct: CompressionType)
+    -> Result<CompareResult, Box<dyn Error>> {
+    let mut a = Side::new(decompressed_reader(a, a_ct)?);
+    let mut b = Side::new(decompressed_reader(b, b_ct)?);
+    loop {
+        let a_data = a.available()?;
+        let a_len = a_data.len();
+        let b_data = b.available()?;
+        if a_len == 0 || b_data.is_empty() {
+            if a_len == 0 && b_data.is_empty() {
+                return Ok(CompareResult::Equal);
+            }
+            return Ok(CompareResult::LengthMismatch { a_len: a.length()?, b_len: b.length()? });
+        }
+        let n = a_len.min(b_data.len());
+        let a_data = &a.buffer[a.start..a.start + n];
+        let mismatch = a_data.iter().zip(&b_data[..n]).position(|(x, y)| x != y);
+        if let Some(i) = mismatch {
+            a.consume(i);
+            b.consume(i);
+            return Ok(CompareResult::FirstDifference {
+                offset: a.consumed,
+                a_context: a.context()?,
+                b_context: b.context()?,
+            });
+        }
+        a.consume(n);
+        b.consume(n);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::compress_bytes;
+
+    fn compressed(ct: CompressionType, data: &[u8]) -> Box<dyn Read> {
+        return Box::new(std::io::Cursor::new(compress_bytes(data, ct, "").unwrap()));
+    }
+
+    fn sample() -> Vec<u8> {
+        return (0..6_000_000u32).map(|i| ((i % 251) as u8) ^ ((i / 4096) as u8)).collect();
+    }
+
+    #[test]
+    pub fn test_equal_across_codecs() {
+        let data = sample();
+        let result = compare(compressed(CompressionType::Gzip, &data), CompressionType::Gzip,
+            compressed(CompressionType::Zstd, &data), CompressionType::Zstd).unwrap();
+        assert_eq!(result, CompareResult::Equal);
+    }
+
+    #[test]
+    pub fn test_difference_at_large_offset() {
+        let data = sample();
+        let mut changed = data.clone();
+        changed[5_000_000] ^= 0xff;
+        let result = compare(compressed(CompressionType::LZ4, &data), CompressionType::LZ4,
+            compressed(CompressionType::Snappy, &changed), CompressionType::Snappy).unwrap();
+        assert_eq!(result, CompareResult::FirstDifference {
+            offset: 5_000_000,
+            a_context: data[5_000_000..5_000_000 + CONTEXT_SIZE].to_vec(),
+            b_context: changed[5_000_000..5_000_000 + CONTEXT_SIZE].to_vec(),
+        });
+    }
+
+    #[test]
+    pub fn test_length_mismatch() {
+        let data = sample();
+        let result = compare(compressed(CompressionType::Bzip2, &data[..1_000_000]), CompressionType::Bzip2,
+            compressed(CompressionType::None, &data[..1_000_100]), CompressionType::None).unwrap();
+        assert_eq!(result, CompareResult::LengthMismatch { a_len: 1_000_000, b_len: 1_000_100 });
+    }
+}
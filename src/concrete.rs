@@ -0,0 +1,634 @@
+use std::error::Error;
+use std::io::{BufReader, ErrorKind, Read, Write};
+use std::time::Duration;
+use crate::idle::IdleFlushWriter;
+use crate::member::{GzipHeader, Members, XzStreamReader};
+use crate::raw::{DeflateWriter, Framing};
+use crate::{canonical, liblz4, liblzo, libbrotli, libstored, lz4_block_format, minimal, snappy_raw_format, tags, text, untrusted};
+use crate::{xz_alone, xz_stream_decoder, zstd_dictionary, zstd_encoder, CompressionType, ParamSet, ZstdSettings};
+
+/// The encoder of a `CompressedWriter`, writing into `W` directly. One per stream, its size
+/// does not matter more than an allocation would.
+#[allow(clippy::large_enum_variant)]
+enum Encoder<W: Write> {
+    None(W),
+    Gzip(DeflateWriter<W>),
+    Zlib(DeflateWriter<W>),
+    Deflate(DeflateWriter<W>),
+    Bzip2(bzip2::write::BzEncoder<W>),
+    Zstd(zstd::Encoder<'static, W>),
+    XZ(xz2::write::XzEncoder<W>),
+    /// `format=alone`, whose encoder cannot flush
+    XzAlone(xz2::write::XzEncoder<W>),
+    Snappy(snap::write::FrameEncoder<W>),
+    LZ4(lz4::Encoder<W>),
+    Stored(libstored::StoredWriter<W>),
+    Brotli(libbrotli::BrotliWrapper<W>),
+    Lzo(liblzo::LZOWrapperW<W>),
+}
+
+/// Compressing writer over a `W` of any type, from `compressed_writer_into`: no trait object
+/// nor allocation between the caller and the encoder, `Send` when `W` is, and `W` is given back
+/// by `finish`.
+///
+/// Dropping it finishes the stream too, losing `W` and the errors.
+pub struct CompressedWriter<W: Write> {
+    encoder: Option<Encoder<W>>,
+}
+
+/// Same as `compressed_writer`, generic over the sink.
+///
+/// ```
+/// use std::io::Write;
+/// use final_compression::{compressed_writer_into, CompressionType};
+/// let mut w = compressed_writer_into(Vec::new(), CompressionType::Zstd, "level=5").unwrap();
+/// w.write_all(b"hello world").unwrap();
+/// let compressed: Vec<u8> = w.finish().unwrap();
+/// ```
+///
+/// It covers every built-in codec with its codec parameters and tags. Registered codecs, raw
+/// snappy and lz4 blocks, and the parameters implemented by wrapping the sink (`rsyncable`,
+/// `member_max_uncompressed`, `minimal_overhead`, `text_mode`) are an error: `compressed_writer`
+/// handles them. So is `idle_flush`, which `compressed_writer_send` handles.
+pub fn compressed_writer_into<W: Write + 'static, T: Into<ParamSet>>(
+    out: W,
+    compression_type: CompressionType,
+    option: T) -> Result<CompressedWriter<W>, Box<dyn Error>> {
+    let param_set: ParamSet = option.into();
+    check_into(compression_type, &param_set)?;
+    if idle_flush(&param_set)?.is_some() {
+        return Err("compressed_writer_into does not support idle_flush, use compressed_writer_send".into());
+    }
+    return encoder_into(out, compression_type, &param_set);
+}
+
+/// The `idle_flush` parameter, None when unset or 0
+pub(crate) fn idle_flush(param_set: &ParamSet) -> Result<Option<Duration>, Box<dyn Error>> {
+    let millis = param_set.get_integer("idle_flush", 0)?;
+    if millis <= 0 {
+        return Ok(None);
+    }
+    return Ok(Some(Duration::from_millis(millis as u64)));
+}
+
+/// The checks of `compressed_writer_into` that come before any encoder
+fn check_into(compression_type: CompressionType, param_set: &ParamSet) -> Result<(), Box<dyn Error>> {
+    param_set.check()?;
+    crate::describe::check_params(compression_type, param_set)?;
+    tags::check_supported(compression_type, param_set)?;
+    canonical::check(compression_type, param_set)?;
+    let unsupported = |what: &str| format!("compressed_writer_into does not support {}, use compressed_writer", what);
+    if text::TextMode::from_params(param_set)? != text::TextMode::Binary {
+        return Err(unsupported("text_mode").into());
+    }
+    if minimal::is_minimal(param_set)? {
+        return Err(unsupported("minimal_overhead").into());
+    }
+    let wrapped = match compression_type {
+        CompressionType::Gzip | CompressionType::Zstd if param_set.get_flag("rsyncable", false)? => "rsyncable".to_string(),
+        CompressionType::Gzip if param_set.get_size("member_max_uncompressed", 0)? > 0 => "member_max_uncompressed".to_string(),
+        CompressionType::Snappy if snappy_raw_format(param_set.get_string("format", "frame"))? => "format=raw".to_string(),
+        CompressionType::LZ4 if lz4_block_format(param_set.get_string("format", "frame"))? => "format=block".to_string(),
+        CompressionType::Custom(_) => format!("{} streams", compression_type),
+        _ => return Ok(()),
+    };
+    return Err(unsupported(&wrapped).into());
+}
+
+/// The encoder of `compression_type` over `out`, from its codec parameters. The one place they
+/// are parsed: `compressed_writer` boxes it for the codecs and parameters it covers, which
+/// `check_into` tells apart.
+pub(crate) fn encoder_into<W: Write + 'static>(
+    mut out: W,
+    compression_type: CompressionType,
+    param_set: &ParamSet) -> Result<CompressedWriter<W>, Box<dyn Error>> {
+    let encoder = match compression_type {
+        CompressionType::None => Encoder::None(out),
+        CompressionType::Gzip => {
+            let level = param_set.get_integer("level", 3)?;
+            let header = GzipHeader::from_params(param_set)?.encode(param_set, level)?;
+            Encoder::Gzip(DeflateWriter::gzip(out, level, &header)?)
+        },
+        CompressionType::Zlib => {
+            Encoder::Zlib(DeflateWriter::new(out, param_set.get_integer("level", 3)?, Framing::Zlib)?)
+        },
+        CompressionType::Deflate => {
+            Encoder::Deflate(DeflateWriter::new(out, param_set.get_integer("level", 3)?, Framing::Raw)?)
+        },
+        CompressionType::Bzip2 => {
+            let level = bzip2::Compression::new(param_set.get_integer("level", 3)?);
+            Encoder::Bzip2(bzip2::write::BzEncoder::new(out, level))
+        },
+        CompressionType::Zstd => {
+            let ZstdSettings { level, dictionary, workers } = ZstdSettings::from_params(param_set)?;
+            tags::write_zstd_tags(&mut out, param_set)?;
+            Encoder::Zstd(zstd_encoder(out, level, &dictionary, workers)?)
+        },
+        CompressionType::XZ => {
+            let level = param_set.get_integer("level", 6)?;
+            let threads: u32 = param_set.get_integer("threads", 1)?;
+            let block_size: u64 = param_set.get_size("block_size", 0)?;
+            if threads > 16384 {
+                return Err(format!("xz needs threads in 0..=16384, not {}", threads).into());
+            }
+            if xz_alone(param_set.get_string("format", "xz"))? {
+                if threads > 1 {
+                    return Err("format=alone has no blocks to compress in parallel, use threads=1".into());
+                }
+                let stream = xz2::stream::Stream::new_lzma_encoder(&xz2::stream::LzmaOptions::new_preset(level)?)?;
+                Encoder::XzAlone(xz2::write::XzEncoder::new_stream(out, stream))
+            } else {
+                let stream = if threads > 1 {
+                    xz2::stream::MtStreamBuilder::new().threads(threads).block_size(block_size).preset(level)
+                        .check(xz2::stream::Check::Crc64).encoder()?
+                } else {
+                    xz2::stream::Stream::new_easy_encoder(level, xz2::stream::Check::Crc64)?
+                };
+                Encoder::XZ(xz2::write::XzEncoder::new_stream(out, stream))
+            }
+        },
+        CompressionType::Snappy => Encoder::Snappy(snap::write::FrameEncoder::new(out)),
+        CompressionType::LZ4 => {
+            let mut builder = lz4::EncoderBuilder::new();
+            builder.auto_flush(true);
+            builder.block_mode(match param_set.get_string("block_mode", "linked") {
+                "independent" => lz4::BlockMode::Independent,
+                _ => lz4::BlockMode::Linked,
+            });
+            builder.checksum(lz4::ContentChecksum::ChecksumEnabled);
+            builder.level(param_set.get_integer("level", 1)?);
+            Encoder::LZ4(builder.build(out)?)
+        },
+        CompressionType::Stored => {
+            let block_size = param_set.get_size("block_size", libstored::DEFAULT_BLOCK_SIZE)?;
+            Encoder::Stored(libstored::StoredWriter::new(out, block_size))
+        },
+        CompressionType::Brotli => {
+            let level = param_set.get_integer("level", 6)?;
+            let window = param_set.get_integer("window", 22)?;
+            if level > 11 || !(10..=24).contains(&window) {
+                return Err(format!("brotli needs level in 0..=11 and window in 10..=24, not {} and {}", level, window).into());
+            }
+            Encoder::Brotli(libbrotli::BrotliWrapper::new(out, level, window))
+        },
+        CompressionType::Lzo => Encoder::Lzo(liblzo::LZOWrapperW::new(out)),
+        CompressionType::Custom(id) => return Err(crate::registry::unknown_codec(id)),
+    };
+    return Ok(CompressedWriter { encoder: Some(encoder) });
+}
+
+impl<W: Write> CompressedWriter<W> {
+    fn encoder(&mut self) -> std::io::Result<&mut Encoder<W>> {
+        return self.encoder.as_mut().ok_or_else(|| std::io::Error::other("stream already finished"));
+    }
+
+    /// The sink, holding what was compressed so far
+    pub fn get_ref(&self) -> &W {
+        return match self.encoder.as_ref().expect("the encoder is only taken by finish") {
+            Encoder::None(w) => w,
+            Encoder::Gzip(e) => e.get_ref(),
+            Encoder::Zlib(e) => e.get_ref(),
+            Encoder::Deflate(e) => e.get_ref(),
+            Encoder::Bzip2(e) => e.get_ref(),
+            Encoder::Zstd(e) => e.get_ref(),
+            Encoder::XZ(e) | Encoder::XzAlone(e) => e.get_ref(),
+            Encoder::Snappy(e) => e.get_ref(),
+            Encoder::LZ4(e) => e.writer(),
+            Encoder::Stored(e) => e.get_ref(),
+            Encoder::Brotli(e) => e.get_ref(),
+            Encoder::Lzo(e) => e.get_ref(),
+        };
+    }
+
+    /// End the stream, flush the sink and return it
+    pub fn finish(mut self) -> std::io::Result<W> {
+        let mut out = match self.encoder.take().unwrap() {
+            Encoder::None(w) => w,
+            Encoder::Gzip(e) => e.finish()?,
+            Encoder::Zlib(e) => e.finish()?,
+            Encoder::Deflate(e) => e.finish()?,
+            Encoder::Bzip2(e) => e.finish()?,
+            Encoder::Zstd(e) => e.finish()?,
+            Encoder::XZ(e) | Encoder::XzAlone(e) => e.finish()?,
+            Encoder::Snappy(e) => e.into_inner().map_err(|e| std::io::Error::new(e.error().kind(), e.error().to_string()))?,
+            Encoder::LZ4(e) => {
+                let (w, result) = e.finish();
+                result?;
+                w
+            },
+            Encoder::Stored(e) => e.into_inner()?,
+            Encoder::Brotli(e) => e.into_inner()?,
+            Encoder::Lzo(e) => e.into_inner()?,
+        };
+        out.flush()?;
+        return Ok(out);
+    }
+}
+
+impl<W: Write + 'static> CompressedWriter<W> {
+    /// End the current stream, trailer included, and start one of `compression_type` with
+    /// `option`, the parameters of `compressed_writer_into`, on the same sink: codecs can change
+    /// on a long-lived connection without reconnecting. The sink is flushed in between.
+    ///
+    /// Invalid parameters fail before anything is written, leaving the current stream going.
+    /// Failing to end it, or to start the new encoder (a dictionary file missing), leaves the
+    /// writer finished, failing every later write. `DecompressedReader::restart` follows the switch
+    /// on the reading side.
+    pub fn restart<T: Into<ParamSet>>(&mut self, compression_type: CompressionType, option: T) -> std::io::Result<()> {
+        let param_set: ParamSet = option.into();
+        check_into(compression_type, &param_set).map_err(|e| std::io::Error::new(ErrorKind::InvalidInput, e.to_string()))?;
+        let encoder = self.encoder.take().ok_or_else(|| std::io::Error::other("stream already finished"))?;
+        let out = CompressedWriter { encoder: Some(encoder) }.finish()?;
+        let mut next = encoder_into(out, compression_type, &param_set).map_err(|e| std::io::Error::other(e.to_string()))?;
+        self.encoder = next.encoder.take();
+        return Ok(());
+    }
+}
+
+impl<W: Write> Write for CompressedWriter<W> {
+    fn write(&mut self, data: &[u8]) -> std::io::Result<usize> {
+        return match self.encoder()? {
+            Encoder::None(w) => w.write(data),
+            Encoder::Gzip(e) => e.write(data),
+            Encoder::Zlib(e) => e.write(data),
+            Encoder::Deflate(e) => e.write(data),
+            Encoder::Bzip2(e) => e.write(data),
+            Encoder::Zstd(e) => e.write(data),
+            Encoder::XZ(e) | Encoder::XzAlone(e) => e.write(data),
+            Encoder::Snappy(e) => e.write(data),
+            Encoder::LZ4(e) => e.write(data),
+            Encoder::Stored(e) => e.write(data),
+            Encoder::Brotli(e) => e.write(data),
+            Encoder::Lzo(e) => e.write(data),
+        };
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        return match self.encoder()? {
+            Encoder::None(w) => w.flush(),
+            Encoder::Gzip(e) => e.flush(),
+            Encoder::Zlib(e) => e.flush(),
+            Encoder::Deflate(e) => e.flush(),
+            Encoder::Bzip2(e) => e.flush(),
+            Encoder::Zstd(e) => e.flush(),
+            Encoder::XZ(e) => e.flush(),
+            // liblzma has no flush for the alone format, xz2 panics on it: the data stays buffered
+            Encoder::XzAlone(e) => e.get_mut().flush(),
+            Encoder::Snappy(e) => e.flush(),
+            Encoder::LZ4(e) => e.flush(),
+            Encoder::Stored(e) => e.flush(),
+            Encoder::Brotli(e) => e.flush(),
+            Encoder::Lzo(e) => e.flush(),
+        };
+    }
+}
+
+impl<W: Write> Drop for CompressedWriter<W> {
+    fn drop(&mut self) {
+        if self.encoder.is_none() || std::thread::panicking() {
+            return;
+        }
+        let _ = CompressedWriter { encoder: self.encoder.take() }.finish();
+    }
+}
+
+/// `compressed_writer` for a sink that is `Send`, returning a writer that is `Send` too, to be
+/// moved to another thread. It is a boxed `compressed_writer_into`, see there for the parameters
+/// it does not support.
+///
+/// It also takes `idle_flush=<millis>`: the writer is wrapped in an `IdleFlushWriter` whose
+/// watchdog thread flushes the encoder once nothing was written for that long. The thread ends
+/// with the writer.
+pub fn compressed_writer_send<T: Into<ParamSet>>(
+    out: Box<dyn Write + Send>,
+    compression_type: CompressionType,
+    option: T) -> Result<Box<dyn Write + Send>, Box<dyn Error>> {
+    let param_set: ParamSet = option.into();
+    check_into(compression_type, &param_set)?;
+    let writer = encoder_into(out, compression_type, &param_set)?;
+    if let Some(idle) = idle_flush(&param_set)? {
+        let writer = IdleFlushWriter::new(writer, idle);
+        writer.spawn_watchdog();
+        return Ok(Box::new(writer));
+    }
+    return Ok(Box::new(writer));
+}
+
+/// `decompressed_reader` for a source that is `Send`, returning a reader that is `Send` too. It is
+/// a boxed `decompressed_reader_from`: every built-in codec, no registered one.
+pub fn decompressed_reader_send(src: Box<dyn Read + Send>, compression_type: CompressionType) -> Result<Box<dyn Read + Send>, Box<dyn Error>> {
+    return Ok(Box::new(decompressed_reader_from(src, compression_type)?));
+}
+
+/// The decoder of a `DecompressedReader`, reading from `R` directly
+#[allow(clippy::large_enum_variant)]
+enum Decoder<R: Read> {
+    None(R),
+    Gzip(Members<BufReader<R>, flate2::bufread::GzDecoder<BufReader<R>>>),
+    Zlib(flate2::bufread::ZlibDecoder<BufReader<R>>),
+    Deflate(flate2::bufread::DeflateDecoder<BufReader<R>>),
+    Bzip2(Members<BufReader<R>, bzip2::bufread::BzDecoder<BufReader<R>>>),
+    Zstd(zstd::Decoder<'static, BufReader<R>>),
+    XZ(Members<BufReader<R>, XzStreamReader<BufReader<R>>>),
+    Snappy(snap::read::FrameDecoder<R>),
+    LZ4(liblz4::Lz4ReaderWrapper<R>),
+    Stored(libstored::StoredReader<R>),
+    Brotli(brotli::Decompressor<R>),
+    Lzo(liblzo::LZOWrapperR<R>),
+    /// The source went with a restart that failed
+    Lost,
+}
+
+/// Decompressing reader over an `R` of any type, from `decompressed_reader_from`: no trait object
+/// between the caller and the decoder, `Send` when `R` is, and `R` may borrow, like a `&[u8]`.
+pub struct DecompressedReader<R: Read> {
+    decoder: Decoder<R>,
+    memory_limit: Option<u64>,
+    /// The last read returned 0
+    ended: bool,
+}
+
+/// Same as `decompressed_reader`, generic over the source.
+///
+/// ```
+/// use std::io::Read;
+/// use final_compression::{decompressed_reader_from, CompressionType};
+/// let compressed = zstd::encode_all(&b"hello world"[..], 3).unwrap();
+/// let mut plain = String::new();
+/// decompressed_reader_from(&compressed[..], CompressionType::Zstd).unwrap().read_to_string(&mut plain).unwrap();
+/// assert_eq!(plain, "hello world");
+/// ```
+///
+/// `decompressed_reader` builds its decoders with it, so both read every built-in codec the same
+/// way. Registered codecs, whose readers take a boxed source, are an error.
+pub fn decompressed_reader_from<R: Read>(src: R, compression_type: CompressionType) -> Result<DecompressedReader<R>, Box<dyn Error>> {
+    if let CompressionType::Custom(_) = compression_type {
+        return Err("decompressed_reader_from does not support registered codecs, use decompressed_reader".into());
+    }
+    return DecompressedReader::open(src, compression_type, &"".into(), false);
+}
+
+impl<R: Read> DecompressedReader<R> {
+    /// Decoder of a built-in `compression_type` with the codec parameters of `param_set`, except
+    /// raw snappy and lz4 blocks and the trailing data checks, which `build_decoder` handles.
+    /// `strict` reports lz4 frames missing their end mark.
+    pub(crate) fn open(src: R, compression_type: CompressionType, param_set: &ParamSet, strict: bool) -> Result<DecompressedReader<R>, Box<dyn Error>> {
+        let memory_limit = untrusted::memory_limit(param_set)?;
+        let decoder = match compression_type {
+            CompressionType::None => Decoder::None(src),
+            CompressionType::Gzip | CompressionType::Zlib | CompressionType::Deflate | CompressionType::Bzip2
+                | CompressionType::Zstd | CompressionType::XZ => buffered_decoder(BufReader::new(src), compression_type, param_set, memory_limit)?,
+            CompressionType::Snappy => Decoder::Snappy(snap::read::FrameDecoder::new(src)),
+            CompressionType::LZ4 => Decoder::LZ4(liblz4::Lz4ReaderWrapper::new(lz4::Decoder::new(src)?, strict)),
+            CompressionType::Stored => {
+                let max_block_size = param_set.get_size("max_block_size", libstored::MAX_BLOCK_SIZE)?;
+                Decoder::Stored(libstored::StoredReader::with_max_block_size(src, max_block_size))
+            },
+            CompressionType::Brotli => Decoder::Brotli(libbrotli::brotli_reader(src)),
+            CompressionType::Lzo => Decoder::Lzo(liblzo::LZOWrapperR::new(src)),
+            CompressionType::Custom(id) => return Err(crate::registry::unknown_codec(id)),
+        };
+        return Ok(DecompressedReader { decoder, memory_limit, ended: false });
+    }
+
+    /// Decode a stream of `compression_type`, with default parameters and the memory limit of this
+    /// reader, from where the current one ended: the counterpart of `CompressedWriter::restart`
+    /// on a long-lived connection. Read the current stream to its end (`read` returning 0) first;
+    /// the bytes of the next one wait in the buffer meanwhile. A gzip, bzip2 or xz stream followed
+    /// by one of the same codec reads on as concatenated members instead.
+    ///
+    /// Only decoders that take no byte past the end of their stream hand over: gzip, zlib,
+    /// deflate, bzip2 and xz, followed by any of them or zstd. Other pairs fail with
+    /// `Unsupported`, a stream not read to its end with `InvalidInput`, both leaving the reader
+    /// as it was. Failing to start the new decoder fails every later read.
+    pub fn restart(&mut self, compression_type: CompressionType) -> std::io::Result<()> {
+        if !self.ended {
+            return Err(std::io::Error::new(ErrorKind::InvalidInput, "the current stream was not read to its end"));
+        }
+        let unsupported = || std::io::Error::new(ErrorKind::Unsupported, format!("cannot restart with {} after this stream", compression_type));
+        if !matches!(compression_type, CompressionType::Gzip | CompressionType::Zlib | CompressionType::Deflate
+            | CompressionType::Bzip2 | CompressionType::Zstd | CompressionType::XZ) {
+            return Err(unsupported());
+        }
+        let src = match std::mem::replace(&mut self.decoder, Decoder::Lost) {
+            Decoder::Gzip(d) => d.into_inner(),
+            Decoder::Zlib(d) => d.into_inner(),
+            Decoder::Deflate(d) => d.into_inner(),
+            Decoder::Bzip2(d) => d.into_inner(),
+            Decoder::XZ(d) => d.into_inner(),
+            other => {
+                self.decoder = other;
+                return Err(unsupported());
+            },
+        };
+        self.decoder = buffered_decoder(src, compression_type, &"".into(), self.memory_limit)
+            .map_err(|e| std::io::Error::other(e.to_string()))?;
+        self.ended = false;
+        return Ok(());
+    }
+}
+
+/// Decoder of a codec reading through the `BufReader` of `DecompressedReader`, which a restart
+/// hands from one stream to the next with the bytes it holds
+fn buffered_decoder<R: Read>(src: BufReader<R>, compression_type: CompressionType, param_set: &ParamSet, memory_limit: Option<u64>) -> Result<Decoder<R>, Box<dyn Error>> {
+    return Ok(match compression_type {
+        CompressionType::Gzip => Decoder::Gzip(crate::member::gzip_members(src, param_set.get_flag("concat", true)?)),
+        CompressionType::Zlib => Decoder::Zlib(flate2::bufread::ZlibDecoder::new(src)),
+        CompressionType::Deflate => Decoder::Deflate(flate2::bufread::DeflateDecoder::new(src)),
+        CompressionType::Bzip2 => Decoder::Bzip2(crate::member::bzip2_members(src, param_set.get_flag("concat", true)?)),
+        CompressionType::Zstd => {
+            let dictionary = zstd_dictionary(param_set, param_set.get_string("dict_path", ""))?;
+            let mut decoder = zstd::Decoder::with_dictionary(src, &dictionary)?;
+            if let Some(limit) = memory_limit {
+                decoder.window_log_max(untrusted::zstd_window_log(limit))?;
+            }
+            Decoder::Zstd(decoder)
+        },
+        CompressionType::XZ => {
+            let stream = xz_stream_decoder(param_set, memory_limit)?;
+            // LZMA-alone streams have no magic to find the next one by
+            let concat = param_set.get_flag("concat", true)? && !xz_alone(param_set.get_string("format", "xz"))?;
+            Decoder::XZ(crate::member::xz_members(src, stream, memory_limit.unwrap_or(u64::MAX), concat))
+        },
+        _ => return Err(format!("{} does not read through a buffer", compression_type).into()),
+    });
+}
+
+impl<R: Read> Read for DecompressedReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        let read = match &mut self.decoder {
+            Decoder::None(r) => r.read(buf),
+            Decoder::Gzip(d) => d.read(buf),
+            Decoder::Zlib(d) => d.read(buf),
+            Decoder::Deflate(d) => d.read(buf),
+            Decoder::Bzip2(d) => d.read(buf),
+            Decoder::Zstd(d) => d.read(buf),
+            Decoder::XZ(d) => d.read(buf),
+            Decoder::Snappy(d) => d.read(buf),
+            Decoder::LZ4(d) => d.read(buf),
+            Decoder::Stored(d) => d.read(buf),
+            Decoder::Brotli(d) => d.read(buf),
+            Decoder::Lzo(d) => d.read(buf),
+            Decoder::Lost => Err(std::io::Error::other("source lost by a failed restart")),
+        }?;
+        if !buf.is_empty() {
+            self.ended = read == 0;
+        }
+        return Ok(read);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Read;
+    use crate::{compressed_writer, decompressed_reader};
+
+    fn assert_send<T: Send>(_: &T) {}
+
+    const BUILTIN: [CompressionType; 12] = [CompressionType::None, CompressionType::Gzip, CompressionType::Zlib,
+        CompressionType::Deflate, CompressionType::Bzip2, CompressionType::Zstd, CompressionType::XZ, CompressionType::Snappy,
+        CompressionType::LZ4, CompressionType::Stored, CompressionType::Brotli, CompressionType::Lzo];
+
+    #[test]
+    pub fn test_compressed_writer_into() {
+        let data = b"generic writer, no box in sight ".repeat(5000);
+        for ct in BUILTIN {
+            let mut w = compressed_writer_into(Vec::new(), ct, "level=5").unwrap();
+            assert_send(&w);
+            w.write_all(&data).unwrap();
+            assert!(ct == CompressionType::None || w.get_ref().len() < data.len(), "{}", ct);
+            let compressed: Vec<u8> = w.finish().unwrap();
+            let mut plain = Vec::new();
+            decompressed_reader(Box::new(std::io::Cursor::new(compressed.clone())), ct).unwrap().read_to_end(&mut plain).unwrap();
+            assert!(plain == data, "{}", ct);
+
+            // the same bytes as the boxed writer
+            let buffer = crate::buffer::SharedBuffer::default();
+            let mut boxed = compressed_writer(Box::new(buffer.clone()), ct, "level=5").unwrap();
+            boxed.write_all(&data).unwrap();
+            drop(boxed);
+            assert!(buffer.take() == compressed, "{}", ct);
+        }
+
+        // dropping finishes the stream as well
+        let buffer = crate::buffer::SharedBuffer::default();
+        let mut w = compressed_writer_into(buffer.clone(), CompressionType::Zstd, crate::tags::set_tag("", "k", b"v")).unwrap();
+        w.write_all(&data).unwrap();
+        drop(w);
+        let compressed = buffer.take();
+        assert_eq!(crate::read_tags(Box::new(std::io::Cursor::new(compressed.clone())), CompressionType::Zstd).unwrap().len(), 1);
+        assert_eq!(zstd::decode_all(&compressed[..]).unwrap(), data);
+
+        for (ct, option) in [(CompressionType::Custom(7), ""), (CompressionType::Gzip, "rsyncable=true"), (CompressionType::Zstd, "text_mode=utf8"),
+            (CompressionType::LZ4, "format=block"), (CompressionType::Gzip, "member_max_uncompressed=1MiB")] {
+            let err = compressed_writer_into(Vec::new(), ct, option).err().unwrap();
+            assert!(err.to_string().contains("use compressed_writer"), "{}", err);
+        }
+    }
+
+    #[test]
+    pub fn test_restart() {
+        let (first, second) = (b"sent as gzip ".repeat(2000), b"then as zstd ".repeat(30000));
+        let (pipe_reader, pipe_writer) = std::io::pipe().unwrap();
+        let sending = (first.clone(), second.clone());
+        let sender = std::thread::spawn(move || {
+            let (first, second) = sending;
+            let mut w = compressed_writer_into(pipe_writer, CompressionType::Gzip, "level=6").unwrap();
+            w.write_all(&first).unwrap();
+            // refused before the gzip member ends
+            assert_eq!(w.restart(CompressionType::Zstd, "level=99").unwrap_err().kind(), ErrorKind::InvalidInput);
+            w.write_all(&first).unwrap();
+            w.restart(CompressionType::Zstd, "level=5").unwrap();
+            w.write_all(&second).unwrap();
+            w.finish().unwrap();
+        });
+
+        let mut r = decompressed_reader_from(pipe_reader, CompressionType::Gzip).unwrap();
+        let mut plain = vec![0u8; 100];
+        r.read_exact(&mut plain).unwrap();
+        assert_eq!(r.restart(CompressionType::Zstd).unwrap_err().kind(), ErrorKind::InvalidInput);
+        r.read_to_end(&mut plain).unwrap();
+        assert!(plain == [first.clone(), first].concat());
+        assert_eq!(r.restart(CompressionType::Snappy).unwrap_err().kind(), ErrorKind::Unsupported);
+        r.restart(CompressionType::Zstd).unwrap();
+        plain.clear();
+        r.read_to_end(&mut plain).unwrap();
+        assert!(plain == second);
+        // zstd reads on into whatever follows its frame, so it cannot hand over
+        assert_eq!(r.restart(CompressionType::Gzip).unwrap_err().kind(), ErrorKind::Unsupported);
+        sender.join().unwrap();
+
+        let mut w = compressed_writer_into(Vec::new(), CompressionType::LZ4, "").unwrap();
+        w.restart(CompressionType::Custom(3), "").unwrap_err();
+        w.write_all(b"still lz4").unwrap();
+        let compressed = w.finish().unwrap();
+        plain.clear();
+        decompressed_reader_from(&compressed[..], CompressionType::LZ4).unwrap().read_to_end(&mut plain).unwrap();
+        assert_eq!(plain, b"still lz4");
+    }
+
+    #[test]
+    pub fn test_decompressed_reader_from() {
+        let data = b"generic reader over a borrowed slice ".repeat(3000);
+        for ct in BUILTIN {
+            let buffer = crate::buffer::SharedBuffer::default();
+            let mut w = compressed_writer(Box::new(buffer.clone()), ct, "").unwrap();
+            w.write_all(&data).unwrap();
+            drop(w);
+            let compressed = buffer.take();
+
+            let mut r = decompressed_reader_from(&compressed[..], ct).unwrap();
+            assert_send(&r);
+            let mut plain = Vec::new();
+            r.read_to_end(&mut plain).unwrap();
+            assert!(plain == data, "{}", ct);
+        }
+
+        // every member of a concatenated file, as decompressed_reader reads them
+        let member = |text: &[u8]| {
+            let mut w = compressed_writer_into(Vec::new(), CompressionType::Gzip, "").unwrap();
+            w.write_all(text).unwrap();
+            return w.finish().unwrap();
+        };
+        let members = [member(b"first "), member(b"second")].concat();
+        let mut plain = String::new();
+        decompressed_reader_from(std::io::Cursor::new(&members), CompressionType::Gzip).unwrap().read_to_string(&mut plain).unwrap();
+        assert_eq!(plain, "first second");
+
+        let corrupt = [&members[..12], b"garbage", &members[19..]].concat();
+        let mut from = Vec::new();
+        let mut boxed = Vec::new();
+        let from_result = decompressed_reader_from(&corrupt[..], CompressionType::Gzip).unwrap().read_to_end(&mut from);
+        let boxed_result = decompressed_reader(Box::new(std::io::Cursor::new(corrupt)), CompressionType::Gzip).unwrap().read_to_end(&mut boxed);
+        assert_eq!(from_result.map_err(|e| e.to_string()), boxed_result.map_err(|e| e.to_string()));
+
+        let err = decompressed_reader_from(&b""[..], CompressionType::Custom(9)).err().unwrap();
+        assert!(err.to_string().contains("use decompressed_reader"), "{}", err);
+    }
+
+    #[test]
+    pub fn test_send_variants() {
+        let data = b"written and read on other threads ".repeat(2000);
+        let threads: Vec<_> = BUILTIN.into_iter().map(|ct| {
+            let buffer = crate::buffer::SharedBuffer::default();
+            let mut w = compressed_writer_send(Box::new(buffer.clone()), ct, "").unwrap();
+            let data = data.clone();
+            return std::thread::spawn(move || {
+                w.write_all(&data).unwrap();
+                drop(w);
+                let mut r = decompressed_reader_send(Box::new(std::io::Cursor::new(buffer.take())), ct).unwrap();
+                return std::thread::spawn(move || {
+                    let mut plain = Vec::new();
+                    r.read_to_end(&mut plain).unwrap();
+                    return (ct, plain);
+                }).join().unwrap();
+            });
+        }).collect();
+        for thread in threads {
+            let (ct, plain) = thread.join().unwrap();
+            assert!(plain == data, "{}", ct);
+        }
+        assert!(compressed_writer_send(Box::new(std::io::sink()), CompressionType::Gzip, "rsyncable=true").is_err());
+    }
+}
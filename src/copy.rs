@@ -0,0 +1,136 @@
+use std::error::Error;
+use std::io::{Read, Write};
+use std::sync::atomic::Ordering;
+use std::time::{Duration, Instant};
+use crate::cancel::CancelToken;
+use crate::progress::{copy_with_progress, ProgressTracker};
+use crate::summary::{CountingReader, CountingWriter};
+use crate::{decompressed_reader, durable_writer, CompressionType, ParamSet};
+
+/// Byte counts and duration of a `compress_copy` or `decompress_copy`
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct CopyStats {
+    /// Bytes read from the source: plain for `compress_copy`, compressed for `decompress_copy`
+    pub bytes_read: u64,
+    /// Bytes written to the destination: compressed for `compress_copy`, plain for
+    /// `decompress_copy`
+    pub bytes_written: u64,
+    pub elapsed: Duration,
+}
+
+/// Compress all of `src` into `dst`, returning what went through. `bytes_written` counts the
+/// bytes reaching `dst`, trailer included. `option` takes the parameters of
+/// `compressed_writer`; errors closing the stream are reported, not lost in a drop.
+pub fn compress_copy<T: Into<ParamSet>>(
+    src: &mut impl Read,
+    dst: Box<dyn Write>,
+    compression_type: CompressionType,
+    option: T) -> Result<CopyStats, Box<dyn Error>> {
+    return compress_copy_with_token(src, dst, compression_type, option, &CancelToken::new());
+}
+
+/// `compress_copy`, stopped by `cancel` with `Cancelled` and the bytes read and written so far.
+/// The token is checked once per 64 KiB read.
+pub fn compress_copy_with_token<T: Into<ParamSet>>(
+    src: &mut impl Read,
+    dst: Box<dyn Write>,
+    compression_type: CompressionType,
+    option: T,
+    cancel: &CancelToken) -> Result<CopyStats, Box<dyn Error>> {
+    let started = Instant::now();
+    let (dst, written) = CountingWriter::new(dst);
+    let mut writer = durable_writer(Box::new(dst), compression_type, option)?;
+    let bytes_read = copy_with_progress(src, &mut writer, &mut ProgressTracker::new(None),
+        |copied| (copied, written.load(Ordering::Relaxed)), &mut |_| {}, cancel)?;
+    let bytes_written = writer.finish()?;
+    return Ok(CopyStats { bytes_read, bytes_written, elapsed: started.elapsed() });
+}
+
+/// Decompress `src` into `dst`, returning what went through. `bytes_read` counts the bytes
+/// taken from `src`, which can include some read ahead past the end of the stream.
+pub fn decompress_copy(
+    src: Box<dyn Read>,
+    dst: &mut impl Write,
+    compression_type: CompressionType) -> Result<CopyStats, Box<dyn Error>> {
+    return decompress_copy_with_token(src, dst, compression_type, &CancelToken::new());
+}
+
+/// `decompress_copy`, stopped by `cancel` with `Cancelled` and the bytes read and written so far.
+/// The token is checked once per 64 KiB written.
+pub fn decompress_copy_with_token(
+    src: Box<dyn Read>,
+    dst: &mut impl Write,
+    compression_type: CompressionType,
+    cancel: &CancelToken) -> Result<CopyStats, Box<dyn Error>> {
+    let started = Instant::now();
+    let (src, consumed) = CountingReader::new(src);
+    let mut reader = decompressed_reader(Box::new(src), compression_type)?;
+    let bytes_written = copy_with_progress(&mut reader, dst, &mut ProgressTracker::new(None),
+        |copied| (consumed.load(Ordering::Relaxed), copied), &mut |_| {}, cancel)?;
+    dst.flush()?;
+    drop(reader);
+    return Ok(CopyStats { bytes_read: consumed.load(Ordering::Relaxed), bytes_written, elapsed: started.elapsed() });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs::File;
+    use crate::cancel::Cancelled;
+
+    #[test]
+    pub fn test_copy_stats() {
+        let dir = std::env::temp_dir().join(format!("final_compression_copy_{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let text = "streamed through compress_copy, counted on both sides\n".repeat(20_000);
+        for ct in [CompressionType::Gzip, CompressionType::Zstd, CompressionType::XZ, CompressionType::LZ4] {
+            let path = dir.join(format!("copy.{}", ct));
+            let stats = compress_copy(&mut text.as_bytes(), Box::new(File::create(&path).unwrap()), ct, "level=3").unwrap();
+            let size = std::fs::metadata(&path).unwrap().len();
+            assert_eq!((stats.bytes_read, stats.bytes_written), (text.len() as u64, size), "{}", ct);
+            assert!(size < text.len() as u64 / 10, "{}", ct);
+
+            let mut plain = Vec::new();
+            let stats = decompress_copy(Box::new(File::open(&path).unwrap()), &mut plain, ct).unwrap();
+            assert_eq!((stats.bytes_read, stats.bytes_written), (size, text.len() as u64), "{}", ct);
+            assert!(plain == text.as_bytes(), "{}", ct);
+        }
+
+        let empty = compress_copy(&mut &b""[..], Box::new(Vec::new()), CompressionType::Gzip, "").unwrap();
+        assert!(empty.bytes_read == 0 && empty.bytes_written > 0);
+        let err = decompress_copy(Box::new(&b"not gzip at all"[..]), &mut Vec::new(), CompressionType::Gzip).unwrap_err();
+        assert!(!err.to_string().is_empty());
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    /// Endless input
+    struct Endless;
+
+    impl Read for Endless {
+        fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+            buf.fill(b'e');
+            return Ok(buf.len());
+        }
+    }
+
+    #[test]
+    pub fn test_cancel() {
+        let cancel = CancelToken::new();
+        let canceller = {
+            let cancel = cancel.clone();
+            std::thread::spawn(move || {
+                std::thread::sleep(std::time::Duration::from_millis(20));
+                cancel.cancel();
+            })
+        };
+        let err = compress_copy_with_token(&mut Endless, Box::new(std::io::sink()), CompressionType::Zstd, "", &cancel).unwrap_err();
+        canceller.join().unwrap();
+        let cancelled = err.downcast::<Cancelled>().unwrap();
+        assert!(cancelled.bytes_in > 0 && cancelled.bytes_in % (64 * 1024) == 0, "{:?}", cancelled);
+
+        let compressed = crate::compress_bytes(&[7u8; 200_000], CompressionType::Gzip, "").unwrap();
+        let err = decompress_copy_with_token(Box::new(std::io::Cursor::new(compressed)), &mut Vec::new(), CompressionType::Gzip, &cancel).unwrap_err();
+        // the decoder is set up, nothing is decompressed
+        assert_eq!(err.downcast::<Cancelled>().unwrap().bytes_out, 0);
+    }
+}
@@ -0,0 +1,260 @@
+use std::error::Error;
+use std::path::{Path, PathBuf};
+
+/// Environment variable naming the directory holding the standard corpora, one subdirectory per
+/// corpus (`canterbury`, `silesia`) with the files extracted as published. Nothing is downloaded.
+pub const CORPUS_DIR_ENV: &str = "FINAL_COMPRESSION_CORPUS_DIR";
+
+/// Size of every file of the built-in corpora
+const SYNTHETIC_FILE_SIZE: usize = 256 * 1024;
+
+/// Named set of files to measure codecs on
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Corpus {
+    pub name: String,
+    pub files: Vec<(String, Vec<u8>)>,
+}
+
+impl Corpus {
+    /// Bytes of all files
+    pub fn total_size(&self) -> u64 {
+        return self.files.iter().map(|(_, data)| data.len() as u64).sum();
+    }
+}
+
+/// Published corpora, loaded from a local copy by `load_standard`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum StandardCorpus {
+    /// The Canterbury corpus (cantrbry.tar.gz)
+    Canterbury,
+    /// The Silesia corpus (silesia.zip)
+    Silesia,
+}
+
+impl StandardCorpus {
+    pub fn name(&self) -> &'static str {
+        return match self {
+            StandardCorpus::Canterbury => "canterbury",
+            StandardCorpus::Silesia => "silesia",
+        };
+    }
+
+    /// Files of the corpus with their published sizes
+    pub fn files(&self) -> &'static [(&'static str, u64)] {
+        return match self {
+            StandardCorpus::Canterbury => &[
+                ("alice29.txt", 152_089), ("asyoulik.txt", 125_179), ("cp.html", 24_603),
+                ("fields.c", 11_150), ("grammar.lsp", 3_721), ("kennedy.xls", 1_029_744),
+                ("lcet10.txt", 426_754), ("plrabn12.txt", 481_861), ("ptt5", 513_216),
+                ("sum", 38_240), ("xargs.1", 4_227),
+            ],
+            StandardCorpus::Silesia => &[
+                ("dickens", 10_192_446), ("mozilla", 51_220_480), ("mr", 9_970_564),
+                ("nci", 33_553_445), ("ooffice", 6_152_192), ("osdb", 10_085_684),
+                ("reymont", 6_627_202), ("samba", 21_606_400), ("sao", 7_251_944),
+                ("webster", 41_458_703), ("xml", 5_345_280), ("x-ray", 8_474_240),
+            ],
+        };
+    }
+}
+
+/// Load `corpus` from `dir`, its subdirectory named after it, checking that every file is there
+/// with its published size so results compare to published ones.
+pub fn load_standard(dir: &Path, corpus: StandardCorpus) -> Result<Corpus, Box<dyn Error>> {
+    let dir = dir.join(corpus.name());
+    let mut files = Vec::new();
+    for (name, size) in corpus.files() {
+        let path = dir.join(name);
+        let data = std::fs::read(&path).map_err(|e| format!("{}: {}", path.display(), e))?;
+        if data.len() as u64 != *size {
+            return Err(format!("{}: {} bytes, the {} corpus has {}", path.display(), data.len(), corpus.name(), size).into());
+        }
+        files.push((name.to_string(), data));
+    }
+    return Ok(Corpus { name: corpus.name().into(), files });
+}
+
+/// Standard corpora found under `CORPUS_DIR_ENV`: none when it is unset, and only the complete
+/// ones otherwise, so benchmarks and tests run without them.
+pub fn standard_corpora_from_env() -> Vec<Corpus> {
+    let Some(dir) = std::env::var_os(CORPUS_DIR_ENV).map(PathBuf::from) else {
+        return Vec::new();
+    };
+    return [StandardCorpus::Canterbury, StandardCorpus::Silesia].iter()
+        .filter_map(|corpus| load_standard(&dir, *corpus).ok())
+        .collect();
+}
+
+/// The built-in corpora, all generated from `seed`: the same seed gives the same bytes
+pub fn builtin_corpora(seed: u64) -> Vec<Corpus> {
+    return vec![text_corpus(seed), json_corpus(seed), mixed_binary_corpus(seed)];
+}
+
+/// xorshift64*, seeded so that 0 works too
+struct Rng(u64);
+
+impl Rng {
+    fn new(seed: u64, stream: u64) -> Rng {
+        let state = seed ^ stream.wrapping_mul(0x9e37_79b9_7f4a_7c15);
+        return Rng(if state == 0 { 0x9e37_79b9_7f4a_7c15 } else { state });
+    }
+
+    fn next(&mut self) -> u64 {
+        self.0 ^= self.0 >> 12;
+        self.0 ^= self.0 << 25;
+        self.0 ^= self.0 >> 27;
+        return self.0.wrapping_mul(0x2545_f491_4f6c_dd1d);
+    }
+
+    fn below(&mut self, n: usize) -> usize {
+        return (self.next() % n as u64) as usize;
+    }
+}
+
+const WORDS: &[&str] = &[
+    "the", "of", "and", "a", "to", "in", "is", "was", "that", "for", "it", "with", "as", "his",
+    "on", "be", "at", "by", "had", "not", "are", "but", "from", "or", "have", "an", "they",
+    "which", "one", "you", "were", "her", "all", "she", "there", "would", "their", "we", "him",
+    "been", "has", "when", "who", "will", "more", "no", "if", "out", "so", "said", "what", "up",
+    "its", "about", "into", "than", "them", "can", "only", "other", "new", "some", "could",
+    "time", "these", "two", "may", "then", "do", "first", "any", "my", "now", "such", "like",
+    "river", "stream", "window", "block", "ancient", "harbour", "letter", "morning", "garden",
+];
+
+/// English-like text from a first order Markov chain over a small vocabulary
+pub fn text_corpus(seed: u64) -> Corpus {
+    let files = ["chapters.txt", "notes.txt"].iter().enumerate().map(|(i, name)| {
+        let mut rng = Rng::new(seed, i as u64 + 1);
+        // every word can be followed by a handful of others only
+        let successors: Vec<Vec<usize>> = (0..WORDS.len())
+            .map(|_| (0..4).map(|_| rng.below(WORDS.len())).collect())
+            .collect();
+        let mut text = String::with_capacity(SYNTHETIC_FILE_SIZE + 16);
+        let mut word = rng.below(WORDS.len());
+        let mut in_sentence = 0;
+        while text.len() < SYNTHETIC_FILE_SIZE {
+            let next = &successors[word];
+            word = if rng.below(8) == 0 { rng.below(WORDS.len()) } else { next[rng.below(next.len())] };
+            if in_sentence == 0 {
+                let mut chars = WORDS[word].chars();
+                text.extend(chars.next().map(|c| c.to_ascii_uppercase()));
+                text.push_str(chars.as_str());
+            } else {
+                text.push_str(WORDS[word]);
+            }
+            in_sentence += 1;
+            if in_sentence > 6 && rng.below(6) == 0 {
+                text.push_str(if rng.below(10) == 0 { ".\n" } else { ". " });
+                in_sentence = 0;
+            } else {
+                text.push(' ');
+            }
+        }
+        text.truncate(SYNTHETIC_FILE_SIZE);
+        return (name.to_string(), text.into_bytes());
+    }).collect();
+    return Corpus { name: "text".into(), files };
+}
+
+/// Newline delimited JSON records, like logs and API payloads
+pub fn json_corpus(seed: u64) -> Corpus {
+    let files = ["events.jsonl", "orders.jsonl"].iter().enumerate().map(|(i, name)| {
+        let mut rng = Rng::new(seed, i as u64 + 101);
+        let statuses = ["ok", "retry", "failed", "pending"];
+        let mut out = String::with_capacity(SYNTHETIC_FILE_SIZE + 256);
+        let mut id: u64 = 1_700_000_000 + rng.below(1000) as u64;
+        while out.len() < SYNTHETIC_FILE_SIZE {
+            id += 1 + rng.below(20) as u64;
+            out.push_str(&format!(
+                "{{\"id\":{},\"user\":\"user-{:04}\",\"status\":\"{}\",\"amount\":{}.{:02},\"tags\":[\"{}\",\"{}\"],\"latency_ms\":{}}}\n",
+                id, rng.below(2000), statuses[rng.below(statuses.len())], rng.below(10_000), rng.below(100),
+                WORDS[rng.below(WORDS.len())], WORDS[rng.below(WORDS.len())], rng.below(500)));
+        }
+        out.truncate(SYNTHETIC_FILE_SIZE);
+        return (name.to_string(), out.into_bytes());
+    }).collect();
+    return Corpus { name: "json".into(), files };
+}
+
+/// Binary data of mixed entropy: runs, small integers, structured records and random segments
+pub fn mixed_binary_corpus(seed: u64) -> Corpus {
+    let files = ["image.bin", "database.bin"].iter().enumerate().map(|(i, name)| {
+        let mut rng = Rng::new(seed, i as u64 + 201);
+        let mut out = Vec::with_capacity(SYNTHETIC_FILE_SIZE + 4096);
+        while out.len() < SYNTHETIC_FILE_SIZE {
+            let len = 256 + rng.below(3840);
+            match rng.below(4) {
+                0 => out.resize(out.len() + len, rng.next() as u8),
+                1 => out.extend((0..len).map(|_| rng.below(16) as u8)),
+                2 => {
+                    let base = rng.next();
+                    for k in 0..len / 16 {
+                        out.extend_from_slice(&(base + k as u64).to_le_bytes());
+                        out.extend_from_slice(&(rng.below(1 << 16) as u64).to_le_bytes());
+                    }
+                },
+                _ => out.extend((0..len).map(|_| rng.next() as u8)),
+            }
+        }
+        out.truncate(SYNTHETIC_FILE_SIZE);
+        return (name.to_string(), out);
+    }).collect();
+    return Corpus { name: "binary".into(), files };
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Write;
+    use crate::{compressed_writer, CompressionType};
+
+    fn compressed_size(data: &[u8]) -> usize {
+        let buffer = crate::buffer::SharedBuffer::default();
+        let mut w = compressed_writer(Box::new(buffer.clone()), CompressionType::Zstd, "level=3").unwrap();
+        w.write_all(data).unwrap();
+        drop(w);
+        return buffer.take().len();
+    }
+
+    #[test]
+    pub fn test_deterministic_generation() {
+        let corpora = builtin_corpora(42);
+        assert!(corpora == builtin_corpora(42));
+        assert!(corpora != builtin_corpora(43));
+        assert!(builtin_corpora(0) != builtin_corpora(1));
+        assert_eq!(corpora.iter().map(|c| c.name.as_str()).collect::<Vec<_>>(), ["text", "json", "binary"]);
+        for corpus in &corpora {
+            assert_eq!(corpus.total_size(), 2 * SYNTHETIC_FILE_SIZE as u64, "{}", corpus.name);
+            for (name, data) in &corpus.files {
+                // compressible, but not trivially
+                let ratio = compressed_size(data) as f64 / data.len() as f64;
+                assert!(ratio > 0.05 && ratio < 0.8, "{}/{} {}", corpus.name, name, ratio);
+            }
+        }
+        assert!(std::str::from_utf8(&corpora[0].files[0].1).is_ok());
+    }
+
+    #[test]
+    pub fn test_standard_corpora() {
+        let dir = std::env::temp_dir().join(format!("final_compression_corpus_{}", std::process::id()));
+        std::fs::create_dir_all(dir.join("canterbury")).unwrap();
+        let err = load_standard(&dir, StandardCorpus::Canterbury).unwrap_err();
+        assert!(err.to_string().contains("alice29.txt"), "{}", err);
+        for (name, size) in StandardCorpus::Canterbury.files() {
+            std::fs::write(dir.join("canterbury").join(name), vec![b'x'; *size as usize]).unwrap();
+        }
+        let corpus = load_standard(&dir, StandardCorpus::Canterbury).unwrap();
+        assert_eq!(corpus.files.len(), 11);
+        assert_eq!(corpus.total_size(), 2_810_784);
+        std::fs::write(dir.join("canterbury").join("sum"), b"truncated").unwrap();
+        let err = load_standard(&dir, StandardCorpus::Canterbury).unwrap_err();
+        assert!(err.to_string().contains("9 bytes, the canterbury corpus has 38240"), "{}", err);
+        assert!(load_standard(&dir, StandardCorpus::Silesia).is_err());
+        std::fs::remove_dir_all(&dir).unwrap();
+
+        // whatever is configured loads; without configuration there is nothing, not an error
+        for corpus in standard_corpora_from_env() {
+            assert!(corpus.total_size() > 0);
+        }
+    }
+}
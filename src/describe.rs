@@ -0,0 +1,763 @@
+use crate::{CompressionType, InvalidParam, ParamSet};
+use crate::registry;
+#[cfg(test)]
+use std::cell::RefCell;
+#[cfg(test)]
+use std::collections::BTreeSet;
+
+/// Value type and accepted values of a codec parameter
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ParamKind {
+    /// Integer in the inclusive range `min..=max`
+    Integer { min: i64, max: i64 },
+    /// One of the listed strings
+    Choice(&'static [&'static str]),
+    /// `true` or `false`, in any case
+    Bool,
+    /// Free form string
+    String,
+}
+
+/// Describes one ParamSet key understood by a codec
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ParamDescription {
+    /// Key, or key prefix when ending with `*`
+    pub name: &'static str,
+    pub kind: ParamKind,
+    /// Default value, as it would be written in a ParamSet expression
+    pub default: &'static str,
+    pub description: &'static str,
+}
+
+/// Describes a compression type: how it is named, recognized and configured
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CodecDescription {
+    pub compression_type: CompressionType,
+    /// Canonical name
+    pub name: String,
+    /// Other names accepted when parsing a compression type
+    pub aliases: Vec<String>,
+    /// Usual file extensions, without the leading dot
+    pub extensions: Vec<String>,
+    /// MIME type of a file in this format
+    pub mime: Option<String>,
+    /// Leading bytes identifying a stream in this format
+    pub magic: Option<Vec<u8>>,
+    /// Parameters understood by the writer
+    pub params: Vec<ParamDescription>,
+    /// false for codecs registered at runtime
+    pub builtin: bool,
+}
+
+/// Machine readable description of everything this crate build supports
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CrateDescription {
+    /// Version of this crate
+    pub version: &'static str,
+    pub codecs: Vec<CodecDescription>,
+    /// Parameters of `compressed_writer` understood with every codec
+    pub writer_params: Vec<ParamDescription>,
+    /// Parameters of `decompressed_reader_with`, with the `params` of the codec they apply to
+    pub reader_params: Vec<ParamDescription>,
+}
+
+pub(crate) struct BuiltinCodec {
+    pub compression_type: CompressionType,
+    pub name: &'static str,
+    pub aliases: &'static [&'static str],
+    pub extensions: &'static [&'static str],
+    pub mime: Option<&'static str>,
+    pub magic: Option<&'static [u8]>,
+    pub params: &'static [ParamDescription],
+}
+
+const fn level(min: i64, max: i64, default: &'static str) -> ParamDescription {
+    ParamDescription {
+        name: "level",
+        kind: ParamKind::Integer { min, max },
+        default,
+        description: "Compression level, higher is slower and smaller",
+    }
+}
+
+const RSYNCABLE: ParamDescription = ParamDescription {
+    name: "rsyncable",
+    kind: ParamKind::Bool,
+    default: "false",
+    description: "Restart the output at content-defined points, so edited inputs dedupe well",
+};
+
+const RSYNC_INTERVAL: ParamDescription = ParamDescription {
+    name: "rsync_interval",
+    kind: ParamKind::Integer { min: 4096, max: 1 << 30 },
+    default: "1048576",
+    description: "Average distance between restart points with rsyncable, in uncompressed bytes",
+};
+
+const MEMBER_MAX_UNCOMPRESSED: ParamDescription = ParamDescription {
+    name: "member_max_uncompressed",
+    kind: ParamKind::Integer { min: 0, max: i64::MAX },
+    default: "0",
+    description: "Start a new gzip member before one holds more uncompressed bytes than this, 0 for no limit",
+};
+
+const fn param(name: &'static str, kind: ParamKind, default: &'static str, description: &'static str) -> ParamDescription {
+    ParamDescription { name, kind, default, description }
+}
+
+/// Size parameter where 0 turns the feature off
+const fn size_limit(name: &'static str, description: &'static str) -> ParamDescription {
+    param(name, ParamKind::Integer { min: 0, max: i64::MAX }, "0", description)
+}
+
+const TEXT_MODE: ParamDescription = param("text_mode", ParamKind::Choice(&["utf8", "utf8-lf"]), "",
+    "Reject input that is not UTF-8, utf8-lf also normalizing CRLF to LF; unset for binary data");
+const MINIMAL_OVERHEAD: ParamDescription = param("minimal_overhead", ParamKind::Bool, "false",
+    "Leanest headerless format for tiny payloads, the reader needs the same flag");
+const HANDLE_LABEL: ParamDescription = param("handle_label", ParamKind::String, "",
+    "Name in handles::live_handles with the handle-track feature");
+
+pub(crate) const WRITER_PARAMS: &[ParamDescription] = &[
+    TEXT_MODE,
+    MINIMAL_OVERHEAD,
+    HANDLE_LABEL,
+    param("tag.*", ParamKind::String, "", "Tag embedded in the header, in hex, see tags::set_tag; gzip and zstd only"),
+    param("canonical", ParamKind::Bool, "false", "Output stable within a major version of this crate, see canonical"),
+    param("idle_flush", ParamKind::Integer { min: 0, max: i64::MAX }, "0", "Flush after this many milliseconds without writes, 0 never; compressed_writer_send only"),
+];
+
+pub(crate) const READER_PARAMS: &[ParamDescription] = &[
+    TEXT_MODE,
+    MINIMAL_OVERHEAD,
+    HANDLE_LABEL,
+    param("eof_policy", ParamKind::String, "", "strict, or retry(n,backoff_ms) to wait for a growing source; unset for neither"),
+    size_limit("max_output", "Fail once more bytes were decompressed, 0 for no limit"),
+    param("max_ratio", ParamKind::Integer { min: 0, max: i64::MAX }, "0", "Fail once the output exceeds this multiple of the input, 0 for no limit"),
+    size_limit("memory_limit", "Bytes the zstd and xz decoders may allocate, 0 for no limit"),
+    param("max_block_size", ParamKind::Integer { min: 0, max: u32::MAX as i64 }, "16777216", "Largest accepted stored block"),
+    param("trailing_data", ParamKind::Choice(&["ignore", "error"]), "ignore", "What to do with data after the stream"),
+    param("concat", ParamKind::Bool, "true", "Read every stream of a concatenated gzip, bzip2 or xz file"),
+    param("padding", ParamKind::String, "", "zeros:<block_size> accepts zero bytes after the stream up to a multiple of the block size"),
+    size_limit("scan_for_magic", "Skip a preamble of up to this many bytes before the magic number, 0 for none"),
+    param("prevalidate", ParamKind::Choice(&["structure", "full"]), "", "Check the stream before reading it, through prevalidated_reader"),
+];
+
+pub(crate) const BUILTIN_CODECS: &[BuiltinCodec] = &[
+    BuiltinCodec {
+        compression_type: CompressionType::None,
+        name: "none",
+        aliases: &[],
+        extensions: &[],
+        mime: None,
+        magic: None,
+        params: &[],
+    },
+    BuiltinCodec {
+        compression_type: CompressionType::Zstd,
+        name: "zstd",
+        aliases: &["ZSTD", "zst", "ZST"],
+        extensions: &["zst"],
+        mime: Some("application/zstd"),
+        magic: Some(&[0x28, 0xb5, 0x2f, 0xfd]),
+        params: &[
+            level(-131072, 22, "3"),
+            RSYNCABLE,
+            RSYNC_INTERVAL,
+            ParamDescription {
+                name: "dict_path",
+                kind: ParamKind::String,
+                default: "",
+                description: "Dictionary file, the reader needs the same one; empty for none",
+            },
+            ParamDescription {
+                name: "workers",
+                kind: ParamKind::Integer { min: 0, max: 256 },
+                default: "1",
+                description: "Compression threads, 0 for one per core; 1 compresses on the calling thread",
+            },
+        ],
+    },
+    BuiltinCodec {
+        compression_type: CompressionType::Snappy,
+        name: "snappy",
+        aliases: &["SNAPPY"],
+        extensions: &["sz", "snappy"],
+        mime: Some("application/x-snappy-framed"),
+        magic: Some(&[0xff, 0x06, 0x00, 0x00, 0x73, 0x4e, 0x61, 0x50, 0x70, 0x59]),
+        params: &[
+            ParamDescription {
+                name: "format",
+                kind: ParamKind::Choice(&["frame", "raw"]),
+                default: "frame",
+                description: "Container, raw being a single unframed block holding the whole message",
+            },
+        ],
+    },
+    BuiltinCodec {
+        compression_type: CompressionType::Gzip,
+        name: "gzip",
+        aliases: &["GZIP", "gz", "GZ"],
+        extensions: &["gz", "tgz"],
+        mime: Some("application/gzip"),
+        magic: Some(&[0x1f, 0x8b]),
+        params: &[
+            level(0, 9, "3"), RSYNCABLE, RSYNC_INTERVAL, MEMBER_MAX_UNCOMPRESSED,
+            ParamDescription {
+                name: "filename",
+                kind: ParamKind::String,
+                default: "",
+                description: "Original file name stored in the header, restored by gunzip -N",
+            },
+            ParamDescription {
+                name: "mtime",
+                kind: ParamKind::Integer { min: 0, max: u32::MAX as i64 },
+                default: "0",
+                description: "Modification time stored in the header, in unix seconds, 0 for none",
+            },
+            ParamDescription {
+                name: "comment",
+                kind: ParamKind::String,
+                default: "",
+                description: "Comment stored in the header",
+            },
+        ],
+    },
+    BuiltinCodec {
+        compression_type: CompressionType::Zlib,
+        name: "zlib",
+        aliases: &["ZLIB"],
+        extensions: &["zz", "zlib"],
+        mime: Some("application/zlib"),
+        magic: None,
+        params: &[level(0, 9, "3")],
+    },
+    BuiltinCodec {
+        compression_type: CompressionType::Deflate,
+        name: "deflate",
+        aliases: &["DEFLATE"],
+        extensions: &[],
+        mime: None,
+        magic: None,
+        params: &[level(0, 9, "3")],
+    },
+    BuiltinCodec {
+        compression_type: CompressionType::Bzip2,
+        name: "bzip2",
+        aliases: &["BZIP2", "bz2", "BZ2"],
+        extensions: &["bz2"],
+        mime: Some("application/x-bzip2"),
+        magic: Some(b"BZh"),
+        params: &[level(1, 9, "3")],
+    },
+    BuiltinCodec {
+        compression_type: CompressionType::LZ4,
+        name: "lz4",
+        aliases: &["LZ4"],
+        extensions: &["lz4"],
+        mime: Some("application/x-lz4"),
+        magic: Some(&[0x04, 0x22, 0x4d, 0x18]),
+        params: &[
+            level(0, 16, "1"),
+            ParamDescription {
+                name: "block_mode",
+                kind: ParamKind::Choice(&["linked", "independent"]),
+                default: "linked",
+                description: "Whether blocks may reference data of previous blocks",
+            },
+            ParamDescription {
+                name: "format",
+                kind: ParamKind::Choice(&["frame", "block"]),
+                default: "frame",
+                description: "Container, block being one raw block after its 4 byte uncompressed size",
+            },
+        ],
+    },
+    BuiltinCodec {
+        compression_type: CompressionType::XZ,
+        name: "xz",
+        aliases: &["XZ"],
+        extensions: &["xz"],
+        mime: Some("application/x-xz"),
+        magic: Some(&[0xfd, 0x37, 0x7a, 0x58, 0x5a, 0x00]),
+        params: &[
+            level(0, 9, "6"),
+            ParamDescription {
+                name: "format",
+                kind: ParamKind::Choice(&["xz", "alone"]),
+                default: "xz",
+                description: "Container, alone being the legacy .lzma format of lzma -z",
+            },
+            ParamDescription {
+                name: "threads",
+                kind: ParamKind::Integer { min: 0, max: 16384 },
+                default: "1",
+                description: "Threads compressing blocks in parallel; 0 and 1 compress on the calling thread",
+            },
+            ParamDescription {
+                name: "block_size",
+                kind: ParamKind::Integer { min: 0, max: i64::MAX },
+                default: "0",
+                description: "Uncompressed block size of the threaded encoder, 0 for liblzma's choice",
+            },
+        ],
+    },
+    BuiltinCodec {
+        compression_type: CompressionType::Stored,
+        name: "stored",
+        aliases: &["STORED"],
+        extensions: &[],
+        mime: None,
+        magic: Some(b"FCST\x01"),
+        params: &[
+            ParamDescription {
+                name: "block_size",
+                kind: ParamKind::Integer { min: 1, max: 16 * 1024 * 1024 },
+                default: "65536",
+                description: "Maximum payload bytes per checksummed block",
+            },
+        ],
+    },
+    BuiltinCodec {
+        compression_type: CompressionType::Brotli,
+        name: "brotli",
+        aliases: &["BROTLI", "br", "BR"],
+        extensions: &["br"],
+        mime: None,
+        magic: None,
+        params: &[
+            level(0, 11, "6"),
+            ParamDescription {
+                name: "window",
+                kind: ParamKind::Integer { min: 10, max: 24 },
+                default: "22",
+                description: "Log2 of the window size, larger finds matches further back",
+            },
+        ],
+    },
+    BuiltinCodec {
+        compression_type: CompressionType::Lzo,
+        name: "lzo",
+        aliases: &["LZO"],
+        extensions: &[],
+        mime: None,
+        magic: Some(b"FCLZ\x01"),
+        params: &[],
+    },
+];
+
+/// Describe all compression types available in this build, including registered custom codecs.
+///
+/// The description is assembled from the same tables the crate uses internally, so it can be
+/// used for shell completion, documentation generation or UIs.
+pub fn describe() -> CrateDescription {
+    let mut codecs: Vec<CodecDescription> = BUILTIN_CODECS.iter()
+        .map(|codec| CodecDescription {
+            compression_type: codec.compression_type,
+            name: codec.name.into(),
+            aliases: codec.aliases.iter().map(|a| a.to_string()).collect(),
+            extensions: codec.extensions.iter().map(|e| e.to_string()).collect(),
+            mime: codec.mime.map(|m| m.into()),
+            magic: codec.magic.map(|m| m.to_vec()),
+            params: codec.params.to_vec(),
+            builtin: true,
+        })
+        .collect();
+    for (compression_type, codec) in registry::registered_codecs() {
+        codecs.push(CodecDescription {
+            compression_type,
+            name: codec.name,
+            aliases: codec.aliases,
+            extensions: Vec::new(),
+            mime: None,
+            magic: codec.magic,
+            params: Vec::new(),
+            builtin: false,
+        });
+    }
+    return CrateDescription {
+        version: env!("CARGO_PKG_VERSION"),
+        codecs,
+        writer_params: WRITER_PARAMS.to_vec(),
+        reader_params: READER_PARAMS.to_vec(),
+    };
+}
+
+/// Check the parameters understood by the writer of `compression_type` against their
+/// descriptions: integers must lie within range, choices must be listed, bools must be
+/// `true` or `false` in any case. Unset parameters and keys not described are not checked.
+/// `compressed_writer` runs it on its parameters.
+///
+/// The check is driven by the same table as `describe`, so a parameter added there is
+/// validated without further code.
+pub fn validate_params<T: Into<ParamSet>>(compression_type: CompressionType, option: T) -> Result<(), InvalidParam> {
+    let param_set: ParamSet = option.into();
+    if let Err(e) = param_set.check() {
+        return Err(InvalidParam { key: e.key, value: e.value, expected: format!("valid percent escapes ({} at byte {})", e.reason, e.position) });
+    }
+    return check_params(compression_type, &param_set);
+}
+
+/// `validate_params` on a checked `param_set`, not counted as reads by the consistency tests
+pub(crate) fn check_params(compression_type: CompressionType, param_set: &ParamSet) -> Result<(), InvalidParam> {
+    let codec_params = BUILTIN_CODECS.iter().find(|c| c.compression_type == compression_type).map_or(&[][..], |c| c.params);
+    return unrecorded(|| {
+        for param in codec_params.iter().chain(WRITER_PARAMS).filter(|p| !p.name.ends_with('*')) {
+            check_param(param, param_set)?;
+        }
+        return Ok(());
+    });
+}
+
+fn check_param(param: &ParamDescription, param_set: &ParamSet) -> Result<(), InvalidParam> {
+    let value = param_set.get_string(param.name, "");
+    if value.is_empty() {
+        return Ok(());
+    }
+    let invalid = |expected: String| InvalidParam { key: param.name.into(), value: value.into(), expected };
+    match param.kind {
+        ParamKind::Integer { min, max } => {
+            // size parameters also accept units, like 4MiB
+            let parsed: i64 = param_set.get_integer(param.name, 0)
+                .or_else(|e| param_set.get_size(param.name, 0).map_err(|_| e))?;
+            if parsed < min || parsed > max {
+                return Err(invalid(format!("an integer in {}..={}", min, max)));
+            }
+        },
+        ParamKind::Choice(choices) => {
+            if !choices.contains(&value) {
+                return Err(invalid(format!("one of {}", choices.join(", "))));
+            }
+        },
+        ParamKind::Bool => {
+            if !value.eq_ignore_ascii_case("true") && !value.eq_ignore_ascii_case("false") {
+                return Err(invalid("true or false".into()));
+            }
+        },
+        ParamKind::String => {},
+    }
+    return Ok(());
+}
+
+#[cfg(test)]
+thread_local! {
+    /// Keys read through the `ParamSet` getters on this thread, while recording
+    static READ_KEYS: RefCell<Option<BTreeSet<String>>> = const { RefCell::new(None) };
+}
+
+/// Record a read of `key` for the consistency tests
+#[cfg(test)]
+pub(crate) fn record_read(key: &str) {
+    READ_KEYS.with(|keys| {
+        if let Some(keys) = keys.borrow_mut().as_mut() {
+            keys.insert(key.to_string());
+        }
+    });
+}
+
+/// `run` without recording its reads
+fn unrecorded<T>(run: impl FnOnce() -> T) -> T {
+    #[cfg(test)]
+    let recording = READ_KEYS.with(|keys| keys.borrow_mut().take());
+    let result = run();
+    #[cfg(test)]
+    READ_KEYS.with(|keys| *keys.borrow_mut() = recording);
+    return result;
+}
+
+/// `describe()` rendered as pretty printed JSON.
+pub fn describe_json() -> String {
+    let description = describe();
+    let mut out = String::new();
+    out.push_str("{\n");
+    out.push_str(&format!("  \"version\": {},\n", json_string(description.version)));
+    out.push_str("  \"codecs\": [");
+    for (i, codec) in description.codecs.iter().enumerate() {
+        out.push_str(if i == 0 { "\n" } else { ",\n" });
+        out.push_str("    {\n");
+        out.push_str(&format!("      \"name\": {},\n", json_string(&codec.name)));
+        out.push_str(&format!("      \"aliases\": {},\n", json_string_array(&codec.aliases)));
+        out.push_str(&format!("      \"extensions\": {},\n", json_string_array(&codec.extensions)));
+        out.push_str(&format!("      \"mime\": {},\n", codec.mime.as_deref().map(json_string).unwrap_or("null".into())));
+        let magic = codec.magic.as_ref()
+            .map(|m| json_string(&m.iter().map(|b| format!("{:02x}", b)).collect::<String>()))
+            .unwrap_or("null".into());
+        out.push_str(&format!("      \"magic\": {},\n", magic));
+        out.push_str(&format!("      \"builtin\": {},\n", codec.builtin));
+        out.push_str("      \"params\": ");
+        json_params(&mut out, &codec.params, "      ");
+        out.push('\n');
+        out.push_str("    }");
+    }
+    out.push_str("\n  ],\n  \"writer_params\": ");
+    json_params(&mut out, &description.writer_params, "  ");
+    out.push_str(",\n  \"reader_params\": ");
+    json_params(&mut out, &description.reader_params, "  ");
+    out.push_str("\n}\n");
+    return out;
+}
+
+/// `params` as a JSON array, its closing bracket indented by `indent`
+fn json_params(out: &mut String, params: &[ParamDescription], indent: &str) {
+    out.push('[');
+    for (j, param) in params.iter().enumerate() {
+        out.push_str(if j == 0 { "\n" } else { ",\n" });
+        out.push_str(&format!("{}  {{\n", indent));
+        let field = format!("{}    ", indent);
+        out.push_str(&format!("{}\"name\": {},\n", field, json_string(param.name)));
+        match param.kind {
+            ParamKind::Integer { min, max } => {
+                out.push_str(&format!("{}\"type\": \"integer\",\n", field));
+                out.push_str(&format!("{}\"min\": {},\n{}\"max\": {},\n", field, min, field, max));
+            },
+            ParamKind::Choice(choices) => {
+                out.push_str(&format!("{}\"type\": \"choice\",\n", field));
+                let choices: Vec<String> = choices.iter().map(|c| c.to_string()).collect();
+                out.push_str(&format!("{}\"choices\": {},\n", field, json_string_array(&choices)));
+            },
+            ParamKind::Bool => out.push_str(&format!("{}\"type\": \"bool\",\n", field)),
+            ParamKind::String => out.push_str(&format!("{}\"type\": \"string\",\n", field)),
+        }
+        out.push_str(&format!("{}\"default\": {},\n", field, json_string(param.default)));
+        out.push_str(&format!("{}\"description\": {}\n", field, json_string(param.description)));
+        out.push_str(&format!("{}  }}", indent));
+    }
+    if !params.is_empty() {
+        out.push_str(&format!("\n{}", indent));
+    }
+    out.push(']');
+}
+
+pub(crate) fn json_string(value: &str) -> String {
+    let mut out = String::with_capacity(value.len() + 2);
+    out.push('"');
+    for c in value.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            '\n' => out.push_str("\\n"),
+            '\r' => out.push_str("\\r"),
+            '\t' => out.push_str("\\t"),
+            c if (c as u32) < 0x20 => out.push_str(&format!("\\u{:04x}", c as u32)),
+            c => out.push(c),
+        }
+    }
+    out.push('"');
+    return out;
+}
+
+fn json_string_array(values: &[String]) -> String {
+    let items: Vec<String> = values.iter().map(|v| json_string(v)).collect();
+    return format!("[{}]", items.join(", "));
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    pub fn test_describe_json_structure() {
+        let json = describe_json();
+        let gzip_start = json.find("\"name\": \"gzip\"").unwrap();
+        let gzip = &json[gzip_start..];
+        let gzip = &gzip[..gzip.find("\n    }").unwrap()];
+        for key in ["\"aliases\": [", "\"extensions\": [", "\"mime\": ", "\"magic\": \"1f8b\"",
+            "\"builtin\": true", "\"params\": [", "\"name\": \"level\"", "\"type\": \"integer\"",
+            "\"min\": ", "\"max\": ", "\"default\": ", "\"description\": "] {
+            assert!(gzip.contains(key), "gzip description lacks {}", key);
+        }
+        assert!(json.starts_with("{\n  \"version\": "));
+        assert!(json.contains("\"type\": \"choice\""));
+        assert_eq!(json.matches('{').count(), json.matches('}').count());
+        assert_eq!(json.matches('[').count(), json.matches(']').count());
+    }
+
+    fn codec_params(compression_type: CompressionType) -> &'static [ParamDescription] {
+        return BUILTIN_CODECS.iter().find(|c| c.compression_type == compression_type).unwrap().params;
+    }
+
+    /// Keys read through the getters while `run` runs on this thread
+    fn keys_read(run: impl FnOnce()) -> BTreeSet<String> {
+        READ_KEYS.with(|keys| *keys.borrow_mut() = Some(BTreeSet::new()));
+        run();
+        return READ_KEYS.with(|keys| keys.borrow_mut().take().unwrap());
+    }
+
+    fn is_described(key: &str, described: &[ParamDescription]) -> bool {
+        return described.iter().any(|p| match p.name.strip_suffix('*') {
+            Some(prefix) => key.starts_with(prefix),
+            None => key == p.name,
+        });
+    }
+
+    /// Keys in `read` that no parameter of `described` covers
+    fn undescribed(read: &BTreeSet<String>, described: &[ParamDescription]) -> Vec<String> {
+        return read.iter().filter(|key| !is_described(key, described)).cloned().collect();
+    }
+
+    /// Parameters taking the writers and readers down each of their branches
+    const WRITER_CASES: &[&str] = &["", "rsyncable=true", "rsyncable=true;member_max_uncompressed=1MiB",
+        "member_max_uncompressed=1MiB", "threads=2", "format=alone", "format=raw", "format=block",
+        "minimal_overhead=true", "canonical=true", "text_mode=utf8", "tag.origin=00", "handle_label=test", "idle_flush=100"];
+    const READER_CASES: &[&str] = &["", "trailing_data=error", "padding=zeros:512", "eof_policy=strict",
+        "format=alone", "format=raw", "format=block", "minimal_overhead=true", "scan_for_magic=64",
+        "max_output=1MiB;max_ratio=100;memory_limit=64MiB", "text_mode=utf8", "handle_label=test", "prevalidate=full"];
+
+    fn writer_keys(codec: &BuiltinCodec) -> BTreeSet<String> {
+        return keys_read(|| {
+            for case in WRITER_CASES {
+                let _ = crate::compressed_writer(Box::new(Vec::new()), codec.compression_type, *case);
+                let _ = crate::compressed_writer_into(Vec::new(), codec.compression_type, *case);
+                let _ = crate::compressed_writer_send(Box::new(Vec::new()), codec.compression_type, *case);
+            }
+        });
+    }
+
+    fn reader_keys(codec: &BuiltinCodec) -> BTreeSet<String> {
+        return keys_read(|| {
+            for case in READER_CASES {
+                let _ = crate::decompressed_reader_with(Box::new(std::io::empty()), codec.compression_type, *case);
+            }
+        });
+    }
+
+    #[test]
+    pub fn test_every_read_param_is_described() {
+        for codec in BUILTIN_CODECS {
+            let writer: Vec<ParamDescription> = codec.params.iter().chain(WRITER_PARAMS).copied().collect();
+            assert_eq!(undescribed(&writer_keys(codec), &writer), Vec::<String>::new(), "{} writer", codec.name);
+            let reader: Vec<ParamDescription> = codec.params.iter().chain(READER_PARAMS).copied().collect();
+            assert_eq!(undescribed(&reader_keys(codec), &reader), Vec::<String>::new(), "{} reader", codec.name);
+        }
+
+        // a key read but described nowhere is caught
+        let read = keys_read(|| {
+            let _ = ParamSet::from("undescribed_key=1").get_integer("undescribed_key", 0u32);
+            let _ = crate::compressed_writer(Box::new(Vec::new()), CompressionType::Gzip, "level=5");
+        });
+        let mut gzip: Vec<ParamDescription> = codec_params(CompressionType::Gzip).iter().chain(WRITER_PARAMS).copied().collect();
+        assert_eq!(undescribed(&read, &gzip), ["undescribed_key"]);
+        gzip.push(param("undescribed_*", ParamKind::String, "", ""));
+        assert!(undescribed(&read, &gzip).is_empty());
+    }
+
+    #[test]
+    pub fn test_every_described_param_is_read() {
+        let mut writer_read = BTreeSet::new();
+        let mut reader_read = BTreeSet::new();
+        for codec in BUILTIN_CODECS {
+            let read = writer_keys(codec);
+            for param in codec.params {
+                assert!(is_described_read(param, &read), "{} parameter {} is never read", codec.name, param.name);
+            }
+            writer_read.extend(read);
+            reader_read.extend(reader_keys(codec));
+        }
+        // handle_label is only read while handles::set_handle_tracking is on, a global switch
+        for param in WRITER_PARAMS.iter().filter(|p| p.name != "handle_label") {
+            assert!(is_described_read(param, &writer_read), "writer parameter {} is never read", param.name);
+        }
+        for param in READER_PARAMS.iter().filter(|p| p.name != "handle_label") {
+            assert!(is_described_read(param, &reader_read), "reader parameter {} is never read", param.name);
+        }
+    }
+
+    fn is_described_read(param: &ParamDescription, read: &BTreeSet<String>) -> bool {
+        return read.iter().any(|key| is_described(key, std::slice::from_ref(param)));
+    }
+
+    #[test]
+    pub fn test_params_snapshot() {
+        let mut snapshot = String::new();
+        let tables = BUILTIN_CODECS.iter().map(|codec| (codec.name, codec.params))
+            .chain([("writer", WRITER_PARAMS), ("reader", READER_PARAMS)]);
+        for (table, params) in tables {
+            for param in params {
+                snapshot.push_str(&format!("{}.{} {:?} default={}\n", table, param.name, param.kind, param.default));
+            }
+        }
+        assert_eq!(snapshot, "\
+zstd.level Integer { min: -131072, max: 22 } default=3
+zstd.rsyncable Bool default=false
+zstd.rsync_interval Integer { min: 4096, max: 1073741824 } default=1048576
+zstd.dict_path String default=
+zstd.workers Integer { min: 0, max: 256 } default=1
+snappy.format Choice([\"frame\", \"raw\"]) default=frame
+gzip.level Integer { min: 0, max: 9 } default=3
+gzip.rsyncable Bool default=false
+gzip.rsync_interval Integer { min: 4096, max: 1073741824 } default=1048576
+gzip.member_max_uncompressed Integer { min: 0, max: 9223372036854775807 } default=0
+gzip.filename String default=
+gzip.mtime Integer { min: 0, max: 4294967295 } default=0
+gzip.comment String default=
+zlib.level Integer { min: 0, max: 9 } default=3
+deflate.level Integer { min: 0, max: 9 } default=3
+bzip2.level Integer { min: 1, max: 9 } default=3
+lz4.level Integer { min: 0, max: 16 } default=1
+lz4.block_mode Choice([\"linked\", \"independent\"]) default=linked
+lz4.format Choice([\"frame\", \"block\"]) default=frame
+xz.level Integer { min: 0, max: 9 } default=6
+xz.format Choice([\"xz\", \"alone\"]) default=xz
+xz.threads Integer { min: 0, max: 16384 } default=1
+xz.block_size Integer { min: 0, max: 9223372036854775807 } default=0
+stored.block_size Integer { min: 1, max: 16777216 } default=65536
+brotli.level Integer { min: 0, max: 11 } default=6
+brotli.window Integer { min: 10, max: 24 } default=22
+writer.text_mode Choice([\"utf8\", \"utf8-lf\"]) default=
+writer.minimal_overhead Bool default=false
+writer.handle_label String default=
+writer.tag.* String default=
+writer.canonical Bool default=false
+writer.idle_flush Integer { min: 0, max: 9223372036854775807 } default=0
+reader.text_mode Choice([\"utf8\", \"utf8-lf\"]) default=
+reader.minimal_overhead Bool default=false
+reader.handle_label String default=
+reader.eof_policy String default=
+reader.max_output Integer { min: 0, max: 9223372036854775807 } default=0
+reader.max_ratio Integer { min: 0, max: 9223372036854775807 } default=0
+reader.memory_limit Integer { min: 0, max: 9223372036854775807 } default=0
+reader.max_block_size Integer { min: 0, max: 4294967295 } default=16777216
+reader.trailing_data Choice([\"ignore\", \"error\"]) default=ignore
+reader.concat Bool default=true
+reader.padding String default=
+reader.scan_for_magic Integer { min: 0, max: 9223372036854775807 } default=0
+reader.prevalidate Choice([\"structure\", \"full\"]) default=
+");
+    }
+
+    #[test]
+    pub fn test_validate_params() {
+        assert!(validate_params(CompressionType::Zstd, "level=19").is_ok());
+        assert!(validate_params(CompressionType::Zstd, "level=19.0;other=x").is_ok());
+        assert_eq!(validate_params(CompressionType::Gzip, "level=10").unwrap_err().expected, "an integer in 0..=9");
+        assert!(validate_params(CompressionType::Zstd, "level=0").is_ok() && validate_params(CompressionType::Zstd, "level=-5").is_ok());
+        assert!(validate_params(CompressionType::Gzip, "rsyncable=TRUE").is_ok());
+        assert_eq!(validate_params(CompressionType::Gzip, "canonical=yes").unwrap_err().key, "canonical");
+        // the ranges are those of the backends
+        let zstd_levels = zstd::compression_level_range();
+        let zstd_level = codec_params(CompressionType::Zstd)[0];
+        assert_eq!(zstd_level.kind, ParamKind::Integer { min: *zstd_levels.start() as i64, max: *zstd_levels.end() as i64 });
+        // and the writers enforce them
+        let err = crate::compressed_writer(Box::new(Vec::new()), CompressionType::Gzip, "level=10").err().unwrap();
+        assert_eq!(err.downcast_ref::<InvalidParam>().unwrap().expected, "an integer in 0..=9");
+        assert!(crate::compressed_writer_into(Vec::new(), CompressionType::LZ4, "block_mode=chained").is_err());
+        assert!(crate::compressed_writer(Box::new(Vec::new()), CompressionType::Gzip, "rsyncable=TRUE").is_ok());
+        assert_eq!(validate_params(CompressionType::LZ4, "block_mode=chained").unwrap_err().key, "block_mode");
+        assert!(validate_params(CompressionType::Snappy, "level=100").is_ok());
+        assert!(validate_params(CompressionType::Zstd, "rsync_interval=64KiB").is_ok());
+        assert!(validate_params(CompressionType::Zstd, "rsync_interval=2GiB").is_err());
+        for codec in BUILTIN_CODECS {
+            let defaults: Vec<String> = codec.params.iter().map(|p| format!("{}={}", p.name, p.default)).collect();
+            assert!(validate_params(codec.compression_type, defaults.join(";")).is_ok(), "{}", codec.name);
+        }
+    }
+
+    #[test]
+    pub fn test_names_parse_back() {
+        for codec in describe().codecs.iter().filter(|c| c.builtin) {
+            assert_eq!(CompressionType::try_from(codec.name.as_str()).unwrap(), codec.compression_type);
+            for alias in codec.aliases.iter() {
+                assert_eq!(CompressionType::try_from(alias.as_str()).unwrap(), codec.compression_type);
+            }
+        }
+    }
+}
@@ -0,0 +1,190 @@
+use std::cell::RefCell;
+use std::error::Error;
+use std::io::{ErrorKind, Write};
+use std::rc::Rc;
+use crate::{compressed_writer, minimal, CompressionType, ParamSet};
+
+/// Whether `durable_flush` works for `compression_type`: the format must read a concatenation of
+/// complete streams as one, like gzip members and zstd frames. xz, bzip2, lz4, snappy and stored
+/// streams do not qualify here, nor do `minimal_overhead` streams.
+pub fn durable_flush_supported(compression_type: CompressionType) -> bool {
+    return matches!(compression_type, CompressionType::Gzip | CompressionType::Zstd | CompressionType::None);
+}
+
+/// The sink shared by the streams of a `DurableWriter`
+struct Sink {
+    out: Box<dyn Write>,
+    written: u64,
+    /// First write error, which finishing on drop would otherwise lose
+    error: Option<std::io::Error>,
+}
+
+struct SharedSink(Rc<RefCell<Sink>>);
+
+impl Write for SharedSink {
+    fn write(&mut self, data: &[u8]) -> std::io::Result<usize> {
+        let mut sink = self.0.borrow_mut();
+        match sink.out.write(data) {
+            Ok(written) => {
+                sink.written += written as u64;
+                return Ok(written);
+            },
+            Err(e) => {
+                if sink.error.is_none() {
+                    sink.error = Some(std::io::Error::new(e.kind(), e.to_string()));
+                }
+                return Err(e);
+            }
+        }
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        return self.0.borrow_mut().out.flush();
+    }
+}
+
+/// Compressing writer whose `durable_flush` leaves a decodable output, see `durable_writer`
+pub struct DurableWriter {
+    encoder: Option<Box<dyn Write>>,
+    sink: Rc<RefCell<Sink>>,
+    compression_type: CompressionType,
+    param_set: ParamSet,
+}
+
+/// Same as `compressed_writer`, with `durable_flush` and a `finish` reporting errors.
+///
+/// A plain `flush` pushes the compressed data out, but the output is still missing the trailer
+/// written when the writer is dropped, where errors are lost. `durable_flush` ends the current
+/// gzip member or zstd frame and starts the next one, so that the output up to that point decodes
+/// in full even if nothing after it is ever written. Gzip readers must then read every member,
+/// like gunzip and `decompressed_reader` do.
+pub fn durable_writer<T: Into<ParamSet>>(out: Box<dyn Write>, compression_type: CompressionType, option: T) -> Result<DurableWriter, Box<dyn Error>> {
+    let param_set: ParamSet = option.into();
+    let sink = Rc::new(RefCell::new(Sink { out, written: 0, error: None }));
+    let encoder = compressed_writer(Box::new(SharedSink(sink.clone())), compression_type, param_set.clone())?;
+    return Ok(DurableWriter { encoder: Some(encoder), sink, compression_type, param_set });
+}
+
+impl DurableWriter {
+    fn encoder(&mut self) -> std::io::Result<&mut Box<dyn Write>> {
+        return self.encoder.as_mut().ok_or_else(|| std::io::Error::other("writer lost by a failed durable_flush"));
+    }
+
+    /// Finish the current stream, surfacing the errors its drop would have swallowed
+    fn end_stream(&mut self) -> std::io::Result<u64> {
+        let mut encoder = self.encoder.take().ok_or_else(|| std::io::Error::other("writer lost by a failed durable_flush"))?;
+        encoder.flush()?;
+        drop(encoder);
+        let mut sink = self.sink.borrow_mut();
+        if let Some(e) = sink.error.take() {
+            return Err(e);
+        }
+        sink.out.flush()?;
+        return Ok(sink.written);
+    }
+
+    /// End the current member or frame and flush the sink. Returns the number of bytes written
+    /// to the sink so far, all of which decode without anything that follows. Fails with
+    /// `ErrorKind::Unsupported` when `durable_flush_supported` is false for the codec.
+    pub fn durable_flush(&mut self) -> std::io::Result<u64> {
+        let minimal = minimal::is_minimal(&self.param_set)
+            .map_err(|e| std::io::Error::new(ErrorKind::InvalidInput, e))?;
+        if !durable_flush_supported(self.compression_type) || minimal {
+            return Err(std::io::Error::new(ErrorKind::Unsupported,
+                format!("{:?} cannot end its stream in the middle of the output", self.compression_type)));
+        }
+        let durable = self.end_stream()?;
+        let sink = Box::new(SharedSink(self.sink.clone()));
+        let encoder = compressed_writer(sink, self.compression_type, self.param_set.clone())
+            .map_err(|e| std::io::Error::other(e.to_string()))?;
+        self.encoder = Some(encoder);
+        return Ok(durable);
+    }
+
+    /// Finish the output, returning the compressed size, or the error dropping would have ignored
+    pub fn finish(mut self) -> std::io::Result<u64> {
+        return self.end_stream();
+    }
+}
+
+impl Write for DurableWriter {
+    fn write(&mut self, data: &[u8]) -> std::io::Result<usize> {
+        return self.encoder()?.write(data);
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        return self.encoder()?.flush();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Read;
+    use crate::decompressed_reader;
+
+    fn decode(ct: CompressionType, compressed: &[u8]) -> Vec<u8> {
+        let mut out = Vec::new();
+        let src = Box::new(std::io::Cursor::new(compressed.to_vec()));
+        decompressed_reader(src, ct).unwrap().read_to_end(&mut out).unwrap();
+        return out;
+    }
+
+    #[test]
+    pub fn test_truncated_after_durable_flush() {
+        let first: Vec<u8> = (0..100_000u32).map(|i| (i % 251) as u8 ^ (i / 777) as u8).collect();
+        let second = b"written after the durable flush ".repeat(2000);
+        for ct in [CompressionType::Gzip, CompressionType::Zstd, CompressionType::None] {
+            let buffer = crate::buffer::SharedBuffer::default();
+            let mut w = durable_writer(Box::new(buffer.clone()), ct, "level=5").unwrap();
+            w.write_all(&first).unwrap();
+            let durable = w.durable_flush().unwrap() as usize;
+            w.write_all(&second).unwrap();
+            w.flush().unwrap();
+            let snapshot = buffer.take();
+            assert!(snapshot.len() > durable, "{:?}", ct);
+            assert!(decode(ct, &snapshot[..durable]) == first, "{:?}", ct);
+
+            let rest = w.finish().unwrap() as usize;
+            let mut full = snapshot;
+            full.extend_from_slice(&buffer.take());
+            assert_eq!(full.len(), rest);
+            let mut expected = first.clone();
+            expected.extend_from_slice(&second);
+            assert!(decode(ct, &full) == expected, "{:?}", ct);
+        }
+    }
+
+    #[test]
+    pub fn test_unsupported() {
+        for ct in [CompressionType::XZ, CompressionType::Bzip2] {
+            let mut w = durable_writer(Box::new(std::io::sink()), ct, "").unwrap();
+            w.write_all(b"data").unwrap();
+            assert_eq!(w.durable_flush().unwrap_err().kind(), ErrorKind::Unsupported);
+            w.finish().unwrap();
+        }
+        let mut w = durable_writer(Box::new(std::io::sink()), CompressionType::Zstd, "minimal_overhead=true").unwrap();
+        assert_eq!(w.durable_flush().unwrap_err().kind(), ErrorKind::Unsupported);
+    }
+
+    /// Sink failing every write
+    struct Full;
+
+    impl Write for Full {
+        fn write(&mut self, _: &[u8]) -> std::io::Result<usize> {
+            return Err(std::io::Error::new(ErrorKind::StorageFull, "disk full"));
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            return Ok(());
+        }
+    }
+
+    #[test]
+    pub fn test_finish_reports_errors() {
+        // zstd buffers small writes, so the failure only happens while finishing the frame
+        let mut w = durable_writer(Box::new(Full), CompressionType::Zstd, "").unwrap();
+        w.write_all(b"buffered").unwrap();
+        assert_eq!(w.finish().unwrap_err().kind(), ErrorKind::StorageFull);
+    }
+}
@@ -0,0 +1,194 @@
+use std::io::Read;
+use std::error::Error;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::Duration;
+use crate::ParamSet;
+
+/// How the decompression reader treats a source returning `Ok(0)`.
+///
+/// Configured with the `eof_policy` parameter of `decompressed_reader_with`:
+/// - unset: the backend decides (historical behavior)
+/// - `eof_policy=strict`: an `Ok(0)` before the codec saw the end of its stream is reported as
+///   `ErrorKind::UnexpectedEof`
+/// - `eof_policy=retry(n,backoff_ms)`: an `Ok(0)` from the source is re-polled up to `n` times,
+///   sleeping `backoff_ms` between attempts, before it is believed. Implies `strict`.
+///
+/// Note that with `retry` every true end of input is also re-polled, costing up to
+/// `n * backoff_ms` once per stream.
+///
+/// The snappy frame format has no end marker, so a snappy stream cut exactly at a frame boundary
+/// cannot be told apart from a complete one.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum EofPolicy {
+    Default,
+    Strict,
+    Retry { attempts: u32, backoff: Duration },
+}
+
+impl EofPolicy {
+    pub(crate) fn from_params(param_set: &ParamSet) -> Result<EofPolicy, Box<dyn Error>> {
+        let value = param_set.get_string("eof_policy", "");
+        if value.is_empty() {
+            return Ok(EofPolicy::Default);
+        }
+        if value == "strict" {
+            return Ok(EofPolicy::Strict);
+        }
+        let invalid = || format!("Invalid eof_policy `{}`, expected strict or retry(n,backoff_ms)", value);
+        let args = value.strip_prefix("retry(")
+            .and_then(|rest| rest.strip_suffix(')'))
+            .ok_or_else(invalid)?;
+        let (attempts, backoff) = args.split_once(',').ok_or_else(invalid)?;
+        let attempts = attempts.trim().parse::<u32>().map_err(|_| invalid())?;
+        let backoff = backoff.trim().parse::<u64>().map_err(|_| invalid())?;
+        return Ok(EofPolicy::Retry { attempts, backoff: Duration::from_millis(backoff) });
+    }
+
+    /// Whether the decoder must check the stream end was really reached
+    pub fn is_strict(&self) -> bool {
+        return *self != EofPolicy::Default;
+    }
+}
+
+/// Source adapter re-polling a reader that returned `Ok(0)`.
+pub(crate) struct RetryOnEof {
+    inner: Box<dyn Read>,
+    attempts: u32,
+    backoff: Duration,
+}
+
+impl RetryOnEof {
+    pub(crate) fn new(inner: Box<dyn Read>, attempts: u32, backoff: Duration) -> RetryOnEof {
+        return RetryOnEof { inner, attempts, backoff };
+    }
+}
+
+impl Read for RetryOnEof {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        let mut read = self.inner.read(buf)?;
+        let mut attempt = 0;
+        while read == 0 && !buf.is_empty() && attempt < self.attempts {
+            attempt += 1;
+            if !self.backoff.is_zero() {
+                std::thread::sleep(self.backoff);
+            }
+            read = self.inner.read(buf)?;
+        }
+        return Ok(read);
+    }
+}
+
+/// Source adapter remembering whether the source ever returned `Ok(0)`.
+pub(crate) struct EofTracking {
+    inner: Box<dyn Read>,
+    hit_eof: Arc<AtomicBool>,
+}
+
+/// Decoder adapter reporting decoder errors as `UnexpectedEof` once the source ran dry, so a
+/// premature end of input is reported the same way for every codec.
+pub(crate) struct StrictEof {
+    inner: Box<dyn Read>,
+    hit_eof: Arc<AtomicBool>,
+}
+
+/// Wrap `src` so that `strict_decoder` can attribute decoder errors to a premature end of input.
+pub(crate) fn track_eof(src: Box<dyn Read>) -> (Box<dyn Read>, Arc<AtomicBool>) {
+    let hit_eof = Arc::new(AtomicBool::new(false));
+    return (Box::new(EofTracking { inner: src, hit_eof: hit_eof.clone() }), hit_eof);
+}
+
+pub(crate) fn strict_decoder(decoder: Box<dyn Read>, hit_eof: Arc<AtomicBool>) -> Box<dyn Read> {
+    return Box::new(StrictEof { inner: decoder, hit_eof });
+}
+
+impl Read for EofTracking {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        let read = self.inner.read(buf)?;
+        if read == 0 && !buf.is_empty() {
+            self.hit_eof.store(true, Ordering::Relaxed);
+        }
+        return Ok(read);
+    }
+}
+
+impl Read for StrictEof {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        return self.inner.read(buf).map_err(|e| {
+            if e.kind() != std::io::ErrorKind::UnexpectedEof && self.hit_eof.load(Ordering::Relaxed) {
+                return std::io::Error::new(std::io::ErrorKind::UnexpectedEof,
+                    format!("compressed stream ended prematurely: {}", e));
+            }
+            return e;
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{compress_bytes, decompressed_reader_with, CompressionType};
+
+    /// Returns a spurious Ok(0) before every `every`-th real read
+    struct Flaky {
+        data: std::io::Cursor<Vec<u8>>,
+        every: usize,
+        reads: usize,
+    }
+
+    impl Read for Flaky {
+        fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+            self.reads += 1;
+            if self.reads.is_multiple_of(self.every) {
+                return Ok(0);
+            }
+            let max = buf.len().min(100);
+            return self.data.read(&mut buf[..max]);
+        }
+    }
+
+    fn sample() -> Vec<u8> {
+        return (0..100_000u32).map(|i| ((i % 251) as u8) ^ ((i / 1000) as u8)).collect();
+    }
+
+    fn decode(src: Box<dyn Read>, ct: CompressionType, params: &str) -> std::io::Result<Vec<u8>> {
+        let mut reader = decompressed_reader_with(src, ct, params).unwrap();
+        let mut out = Vec::new();
+        reader.read_to_end(&mut out)?;
+        return Ok(out);
+    }
+
+    #[test]
+    pub fn test_policy_parsing() {
+        assert_eq!(EofPolicy::from_params(&"".into()).unwrap(), EofPolicy::Default);
+        assert_eq!(EofPolicy::from_params(&"eof_policy=strict".into()).unwrap(), EofPolicy::Strict);
+        assert_eq!(EofPolicy::from_params(&"eof_policy=retry(3, 20)".into()).unwrap(),
+            EofPolicy::Retry { attempts: 3, backoff: Duration::from_millis(20) });
+        assert!(EofPolicy::from_params(&"eof_policy=retry(3)".into()).is_err());
+        assert!(EofPolicy::from_params(&"eof_policy=lenient".into()).is_err());
+    }
+
+    #[test]
+    pub fn test_spurious_eof() {
+        let data = sample();
+        for ct in [CompressionType::Gzip, CompressionType::Zstd, CompressionType::LZ4] {
+            let compressed = compress_bytes(&data, ct, "").unwrap();
+            let flaky = || Box::new(Flaky { data: std::io::Cursor::new(compressed.clone()), every: 3, reads: 0 });
+            let err = decode(flaky(), ct, "eof_policy=strict").unwrap_err();
+            assert_eq!(err.kind(), std::io::ErrorKind::UnexpectedEof, "{:?}", ct);
+            assert_eq!(decode(flaky(), ct, "eof_policy=retry(2,0)").unwrap(), data, "{:?}", ct);
+            let clean = Box::new(std::io::Cursor::new(compressed.clone()));
+            assert_eq!(decode(clean, ct, "eof_policy=strict").unwrap(), data, "{:?}", ct);
+        }
+    }
+
+    #[test]
+    pub fn test_truncated_lz4() {
+        let data = sample();
+        let compressed = compress_bytes(&data, CompressionType::LZ4, "").unwrap();
+        let truncated = compressed[..compressed.len() - 4].to_vec();
+        let err = decode(Box::new(std::io::Cursor::new(truncated.clone())), CompressionType::LZ4, "eof_policy=strict").unwrap_err();
+        assert_eq!(err.kind(), std::io::ErrorKind::UnexpectedEof);
+        assert!(decode(Box::new(std::io::Cursor::new(truncated)), CompressionType::LZ4, "").is_ok());
+    }
+}
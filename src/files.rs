@@ -0,0 +1,231 @@
+use std::error::Error;
+use std::fs::File;
+use std::io::{Read, Write};
+use std::path::Path;
+use std::sync::atomic::Ordering;
+use crate::cancel::{CancelToken, Cancelled};
+use crate::preflight::{decompress_file, Preflight};
+use crate::progress::{copy_with_progress, Progress, ProgressTracker};
+use crate::summary::CountingWriter;
+use crate::{durable_writer, CompressionType, ParamSet};
+
+/// Reader or writer whose errors start with the path they are about
+pub(crate) struct PathErrors<T> {
+    pub(crate) inner: T,
+    pub(crate) path: String,
+}
+
+impl<T> PathErrors<T> {
+    pub(crate) fn new(inner: T, path: &Path) -> PathErrors<T> {
+        return PathErrors { inner, path: path.display().to_string() };
+    }
+
+    fn error(&self, e: std::io::Error) -> std::io::Error {
+        return std::io::Error::new(e.kind(), format!("{}: {}", self.path, e));
+    }
+}
+
+impl<R: Read> Read for PathErrors<R> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        return self.inner.read(buf).map_err(|e| self.error(e));
+    }
+}
+
+impl<W: Write> Write for PathErrors<W> {
+    fn write(&mut self, data: &[u8]) -> std::io::Result<usize> {
+        return self.inner.write(data).map_err(|e| self.error(e));
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        return self.inner.flush().map_err(|e| self.error(e));
+    }
+}
+
+/// `path` opened or created by `open`, the error naming it
+pub(crate) fn open_file(path: &Path, open: fn(&Path) -> std::io::Result<File>) -> Result<File, Box<dyn Error>> {
+    return open(path).map_err(|e| format!("{}: {}", path.display(), e).into());
+}
+
+/// Codec of `path` from its extension, with the parameters selecting its container, see
+/// `CompressionType::from_path_with_params`
+fn codec_of(path: &Path) -> Result<(CompressionType, &'static str), Box<dyn Error>> {
+    return CompressionType::from_path_with_params(path)
+        .ok_or_else(|| format!("{}: no codec has this extension, give the compression type", path.display()).into());
+}
+
+/// Compress the file `src` into `dst`, created or truncated, returning the compressed size.
+/// `option` takes the parameters of `compressed_writer`, and:
+///     sidecars=kind,kind (files describing `dst` to write next to it: `sha256`, `usha256` and
+///         `size`, see `sidecars::SidecarKind`; default none)
+///     sidecar_sha256, sidecar_usha256, sidecar_size=pattern (their names, `{file}` standing for
+///         the file name of `dst`, see `sidecars::SidecarNames`)
+///
+/// With sidecars, `dst` and its sidecars are put in place together by a `commit::Transaction`
+/// under the lock of the directory of `dst`, after `commit::recover_pending` on it. Errors name
+/// the path they are about.
+pub fn compress_file<T: Into<ParamSet>>(src: &Path, dst: &Path, compression_type: CompressionType, option: T) -> Result<u64, Box<dyn Error>> {
+    return compress_file_with_progress(src, dst, compression_type, option, |_| {});
+}
+
+/// `compress_file`, calling `on_progress` as `src` is read, then once completed. The total is
+/// the length of `src`. See `stderr_progress` for a progress line.
+pub fn compress_file_with_progress<T: Into<ParamSet>>(
+    src: &Path,
+    dst: &Path,
+    compression_type: CompressionType,
+    option: T,
+    mut on_progress: impl FnMut(&Progress)) -> Result<u64, Box<dyn Error>> {
+    return compress_file_with(src, dst, compression_type, option.into(), &mut on_progress, &CancelToken::new());
+}
+
+/// `compress_file`, stopped by `cancel` with `Cancelled` and the bytes read and written so far.
+/// The token is checked once per 64 KiB read; `dst` is removed when cancelled.
+pub fn compress_file_with_token<T: Into<ParamSet>>(src: &Path, dst: &Path, compression_type: CompressionType, option: T, cancel: &CancelToken) -> Result<u64, Box<dyn Error>> {
+    return compress_file_with(src, dst, compression_type, option.into(), &mut |_| {}, cancel);
+}
+
+fn compress_file_with(
+    src: &Path,
+    dst: &Path,
+    compression_type: CompressionType,
+    param_set: ParamSet,
+    on_progress: &mut dyn FnMut(&Progress),
+    cancel: &CancelToken) -> Result<u64, Box<dyn Error>> {
+    let sidecars = crate::sidecars::requested(&param_set)?;
+    if !sidecars.is_empty() {
+        return crate::sidecars::compress_with_sidecars(src, dst, compression_type, param_set, &sidecars, on_progress, cancel);
+    }
+    let input = open_file(src, |path| File::open(path))?;
+    let out = PathErrors::new(open_file(dst, |path| File::create(path))?, dst);
+    let result = compress_to(src, input, Box::new(out), compression_type, param_set, on_progress, cancel);
+    if result.as_ref().is_err_and(|e| e.is::<Cancelled>()) {
+        let _ = std::fs::remove_file(dst);
+    }
+    return result;
+}
+
+/// Compress `input`, the file `src`, into `out`, returning the compressed size. `out` is
+/// dropped, not removed, on errors.
+pub(crate) fn compress_to<R: Read>(
+    src: &Path,
+    input: R,
+    out: Box<dyn Write>,
+    compression_type: CompressionType,
+    param_set: ParamSet,
+    on_progress: &mut dyn FnMut(&Progress),
+    cancel: &CancelToken) -> Result<u64, Box<dyn Error>> {
+    let total_in = std::fs::metadata(src).map_err(|e| format!("{}: {}", src.display(), e))?.len();
+    let (out, output) = CountingWriter::new(out);
+    let mut writer = durable_writer(Box::new(out), compression_type, param_set)?;
+    let mut tracker = ProgressTracker::new(Some(total_in));
+    let read = copy_with_progress(&mut PathErrors::new(input, src), &mut writer, &mut tracker,
+        |copied| (copied, output.load(Ordering::Relaxed)), on_progress, cancel)?;
+    let written = writer.finish()?;
+    on_progress(&tracker.finish(read, written));
+    return Ok(written);
+}
+
+/// `compress_file` with the codec of the extension of `dst` (`out.zst` is zstd). The container
+/// parameters of the extension (`format=alone` for `out.lzma`) apply unless `option` sets them.
+pub fn compress_file_auto<T: Into<ParamSet>>(src: &Path, dst: &Path, option: T) -> Result<u64, Box<dyn Error>> {
+    let (compression_type, container) = codec_of(dst)?;
+    let param_set: ParamSet = option.into();
+    return compress_file(src, dst, compression_type, param_set.or_defaults(container));
+}
+
+/// `decompress_file` with the codec of the extension of `src` (`in.gz` is gzip) and the default
+/// `Preflight`, returning the decompressed size. Extensions needing parameters, like `lzma`, are
+/// an error: `decompress_file` takes none.
+pub fn decompress_file_auto(src: &Path, dst: &Path) -> Result<u64, Box<dyn Error>> {
+    let (compression_type, container) = codec_of(src)?;
+    if !container.is_empty() {
+        return Err(format!("{}: needs {}, which decompress_file does not take; use decompressed_reader_with", src.display(), container).into());
+    }
+    return Ok(decompress_file(src, dst, compression_type, &Preflight::default())?.written);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    pub fn test_file_round_trip() {
+        let dir = std::env::temp_dir().join(format!("final_compression_files_{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let data: Vec<u8> = (0..3_000_000u32).map(|i| if i % 5 == 0 { (i >> 7) as u8 } else { b"file helpers"[i as usize % 12] }).collect();
+        let src = dir.join("data.bin");
+        std::fs::write(&src, &data).unwrap();
+        for (ct, extension) in [(CompressionType::Gzip, "gz"), (CompressionType::Zstd, "zst")] {
+            let compressed = dir.join(format!("data.bin.{}", extension));
+            let restored = dir.join("restored.bin");
+            let size = compress_file(&src, &compressed, ct, "level=3").unwrap();
+            assert!(size == std::fs::metadata(&compressed).unwrap().len() && size < data.len() as u64 / 2);
+            assert_eq!(decompress_file(&compressed, &restored, ct, &Preflight::default()).unwrap().written, data.len() as u64);
+            assert!(std::fs::read(&restored).unwrap() == data);
+
+            // the codec from the extensions
+            std::fs::remove_file(&restored).unwrap();
+            assert_eq!(compress_file_auto(&src, &compressed, "").unwrap(), std::fs::metadata(&compressed).unwrap().len());
+            assert_eq!(decompress_file_auto(&compressed, &restored).unwrap(), data.len() as u64);
+            assert!(std::fs::read(&restored).unwrap() == data);
+
+            // completion is reported once, last
+            let mut reports = Vec::new();
+            let size = compress_file_with_progress(&src, &compressed, ct, "", |p: &Progress| reports.push(*p)).unwrap();
+            let last = reports.last().unwrap();
+            assert_eq!((last.bytes_in, last.bytes_out, last.total_in), (data.len() as u64, size, Some(data.len() as u64)));
+            assert_eq!(reports.iter().filter(|p| p.fraction == Some(1.0)).count(), 1);
+            assert!(reports.len() > 2 && reports.windows(2).all(|pair| pair[0].fraction < pair[1].fraction));
+            let mut reports = Vec::new();
+            crate::preflight::decompress_file_with_progress(&compressed, &restored, ct, &Preflight::default(), |p: &Progress| reports.push(*p)).unwrap();
+            let last = reports.last().unwrap();
+            assert_eq!((last.bytes_in, last.bytes_out, last.total_in, last.fraction), (size, data.len() as u64, Some(size), Some(1.0)));
+            assert_eq!(reports.iter().filter(|p| p.fraction == Some(1.0)).count(), 1);
+        }
+
+        let missing = dir.join("missing.txt");
+        let err = compress_file(&missing, &dir.join("out.gz"), CompressionType::Gzip, "").unwrap_err();
+        assert!(err.to_string().starts_with(&missing.display().to_string()), "{}", err);
+        let err = compress_file_auto(&src, &dir.join("out.unknown"), "").unwrap_err();
+        assert!(err.to_string().contains("out.unknown: no codec has this extension"), "{}", err);
+        let err = decompress_file_auto(&src, &dir.join("out")).unwrap_err();
+        assert!(err.to_string().contains("data.bin: no codec"), "{}", err);
+        std::fs::write(dir.join("corrupt.gz"), b"\x1f\x8b\x08\x00 not really gzip").unwrap();
+        let err = decompress_file_auto(&dir.join("corrupt.gz"), &dir.join("corrupt")).unwrap_err();
+        assert!(err.to_string().contains("corrupt.gz: "), "{}", err);
+
+        // lzma files are xz in the alone container, which decompress_file cannot read
+        let lzma = dir.join("data.bin.lzma");
+        compress_file_auto(&src, &lzma, "level=1").unwrap();
+        let mut out = Vec::new();
+        crate::decompressed_reader_with(Box::new(File::open(&lzma).unwrap()), CompressionType::XZ, "format=alone").unwrap().read_to_end(&mut out).unwrap();
+        assert!(out == data);
+        assert!(decompress_file_auto(&lzma, &dir.join("restored.bin")).unwrap_err().to_string().contains("format=alone"));
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    pub fn test_cancelled_files() {
+        let dir = std::env::temp_dir().join(format!("final_compression_files_cancel_{}", std::process::id()));
+        std::fs::create_dir_all(dir.join("tree")).unwrap();
+        let src = dir.join("tree").join("data.txt");
+        std::fs::write(&src, "cancelled before the first buffer\n".repeat(10_000)).unwrap();
+        let cancelled = CancelToken::new();
+        cancelled.cancel();
+
+        // no partial output is left behind
+        let dst = dir.join("data.txt.gz");
+        let err = compress_file_with_token(&src, &dst, CompressionType::Gzip, "", &cancelled).unwrap_err();
+        assert!(err.is::<Cancelled>() && !dst.exists(), "{}", err);
+        compress_file(&src, &dst, CompressionType::Gzip, "").unwrap();
+        let restored = dir.join("restored.txt");
+        let preflight = Preflight { cancel: cancelled.clone(), ..Preflight::default() };
+        let err = decompress_file(&dst, &restored, CompressionType::Gzip, &preflight).unwrap_err();
+        assert!(err.is::<Cancelled>() && !restored.exists(), "{}", err);
+        let out = dir.join("out");
+        let err = crate::tree::compress_tree_with_token(&dir.join("tree"), &out, CompressionType::Gzip, "", |_| true, &cancelled).unwrap_err();
+        assert!(err.is::<Cancelled>(), "{}", err);
+        assert!(std::fs::read_dir(&out).unwrap().all(|entry| entry.unwrap().file_name() == crate::commit::LOCK_FILE));
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
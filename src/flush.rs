@@ -0,0 +1,170 @@
+use std::io::Write;
+use std::error::Error;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicU64, Ordering};
+use crate::{compressed_writer, CompressionType, ParamSet};
+use crate::summary::CountingWriter;
+
+/// Size penalty of flushing every `flush_interval` input bytes
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct FlushReport {
+    /// Number of input bytes written between two flushes
+    pub flush_interval: usize,
+    /// Total compressed size
+    pub output_size: u64,
+    /// `output_size` minus the compressed size without any intermediate flush
+    pub overhead_vs_no_flush: i64,
+}
+
+/// Compressing writer that keeps track of how often it was flushed and how many compressed bytes
+/// those flushes pushed to the sink.
+///
+/// Create it with `flush_monitored_writer`. The flush counters are plain fields, read through
+/// the writer; only the compressed byte count is shared, see `bytes_out_counter`.
+pub struct FlushMonitoredWriter {
+    inner: Box<dyn Write>,
+    bytes_out: Arc<AtomicU64>,
+    flush_count: u64,
+    bytes_emitted_by_flushes: u64,
+}
+
+impl FlushMonitoredWriter {
+    /// Number of times `flush()` was called successfully
+    pub fn flush_count(&self) -> u64 {
+        return self.flush_count;
+    }
+
+    /// Compressed bytes that reached the sink as a direct result of `flush()` calls
+    pub fn bytes_emitted_by_flushes(&self) -> u64 {
+        return self.bytes_emitted_by_flushes;
+    }
+
+    /// Compressed bytes written to the sink so far
+    pub fn bytes_out(&self) -> u64 {
+        return self.bytes_out.load(Ordering::Relaxed);
+    }
+
+    /// Shared counter of compressed bytes, which stays readable after the writer is dropped
+    /// (and has written its trailer).
+    pub fn bytes_out_counter(&self) -> Arc<AtomicU64> {
+        return self.bytes_out.clone();
+    }
+}
+
+impl Write for FlushMonitoredWriter {
+    fn write(&mut self, data: &[u8]) -> std::io::Result<usize> {
+        return self.inner.write(data);
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        let before = self.bytes_out.load(Ordering::Relaxed);
+        self.inner.flush()?;
+        self.flush_count += 1;
+        self.bytes_emitted_by_flushes += self.bytes_out.load(Ordering::Relaxed) - before;
+        return Ok(());
+    }
+}
+
+/// Same as `compressed_writer`, but the returned writer counts flushes and their output.
+pub fn flush_monitored_writer<T: Into<ParamSet>>(
+    out: Box<dyn Write>,
+    compression_type: CompressionType,
+    option: T) -> Result<FlushMonitoredWriter, Box<dyn Error>> {
+    let (sink, bytes_out) = CountingWriter::new(out);
+    let inner = compressed_writer(Box::new(sink), compression_type, option)?;
+    return Ok(FlushMonitoredWriter {
+        inner,
+        bytes_out,
+        flush_count: 0,
+        bytes_emitted_by_flushes: 0,
+    });
+}
+
+/// Compress `data` once without intermediate flushes and once per entry of `flush_every`,
+/// flushing after every that many input bytes, and report the resulting sizes.
+///
+/// `params` is a ParamSet expression such as "level=3".
+pub fn flush_overhead_report(
+    data: &[u8],
+    compression_type: CompressionType,
+    params: &str,
+    flush_every: &[usize]) -> Result<Vec<FlushReport>, Box<dyn Error>> {
+    let baseline = compressed_size(data, compression_type, params, None)?;
+    let mut result = Vec::with_capacity(flush_every.len());
+    for interval in flush_every {
+        let output_size = compressed_size(data, compression_type, params, Some(*interval))?;
+        result.push(FlushReport {
+            flush_interval: *interval,
+            output_size,
+            overhead_vs_no_flush: output_size as i64 - baseline as i64,
+        });
+    }
+    return Ok(result);
+}
+
+fn compressed_size(
+    data: &[u8],
+    compression_type: CompressionType,
+    params: &str,
+    flush_every: Option<usize>) -> Result<u64, Box<dyn Error>> {
+    let mut writer = flush_monitored_writer(Box::new(std::io::sink()), compression_type, params)?;
+    let counter = writer.bytes_out_counter();
+    match flush_every {
+        Some(interval) => {
+            for chunk in data.chunks(interval.max(1)) {
+                writer.write_all(chunk)?;
+                writer.flush()?;
+            }
+        },
+        None => writer.write_all(data)?,
+    }
+    drop(writer);
+    return Ok(counter.load(Ordering::Relaxed));
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn sample() -> Vec<u8> {
+        let mut data = Vec::new();
+        let mut i = 0u64;
+        while data.len() < 256 * 1024 {
+            writeln!(data, "{{\"id\":{},\"name\":\"user{}\",\"active\":{}}}", i, i % 97, i.is_multiple_of(3)).unwrap();
+            i += 1;
+        }
+        return data;
+    }
+
+    #[test]
+    pub fn test_flush_overhead_monotonic() {
+        let data = sample();
+        for ct in [CompressionType::Gzip, CompressionType::Zstd] {
+            let report = flush_overhead_report(&data, ct, "level=3", &[65536, 4096, 512]).unwrap();
+            assert_eq!(report.len(), 3);
+            // a rare flush can even save a few bytes by splitting blocks better, so only the
+            // flushing variants are compared against each other
+            assert!(report[1].output_size >= report[0].output_size, "{:?}", report);
+            assert!(report[2].output_size >= report[1].output_size, "{:?}", report);
+            assert!(report[2].overhead_vs_no_flush > 0, "{:?}", report);
+        }
+    }
+
+    #[test]
+    pub fn test_live_counters_match_report() {
+        let data = sample();
+        let report = flush_overhead_report(&data, CompressionType::Gzip, "level=3", &[4096]).unwrap();
+
+        let mut writer = flush_monitored_writer(Box::new(std::io::sink()), CompressionType::Gzip, "level=3").unwrap();
+        let counter = writer.bytes_out_counter();
+        for chunk in data.chunks(4096) {
+            writer.write_all(chunk).unwrap();
+            writer.flush().unwrap();
+        }
+        assert_eq!(writer.flush_count(), data.len().div_ceil(4096) as u64);
+        assert!(writer.bytes_emitted_by_flushes() > 0);
+        assert!(writer.bytes_emitted_by_flushes() <= writer.bytes_out());
+        drop(writer);
+        assert_eq!(counter.load(Ordering::Relaxed), report[0].output_size);
+    }
+}
@@ -0,0 +1,143 @@
+use std::error::Error;
+use std::time::Duration;
+
+/// Binary units of `format_bytes`, each 1024 times the previous
+const BINARY_UNITS: [&str; 7] = ["B", "KiB", "MiB", "GiB", "TiB", "PiB", "EiB"];
+/// Decimal units of `format_rate`, each 1000 times the previous
+const DECIMAL_UNITS: [&str; 7] = ["B", "kB", "MB", "GB", "TB", "PB", "EB"];
+
+/// `value` / `unit` with two decimals below 10, one below `whole_from` and none above, rounded
+/// half up with integer arithmetic, so the output never depends on float formatting nor on the
+/// locale.
+fn scaled(value: u128, unit: u128, whole_from: u128) -> (u128, usize) {
+    let decimals = if value < 10 * unit { 2 } else if value < whole_from * unit { 1 } else { 0 };
+    let factor = 10u128.pow(decimals as u32);
+    return ((value * factor + unit / 2) / unit, decimals);
+}
+
+fn with_unit(value: u128, base: u128, units: &[&str], whole_from: u128) -> String {
+    let mut index = 0;
+    while index + 1 < units.len() && value >= base.pow(index as u32 + 1) {
+        index += 1;
+    }
+    let (mut number, mut decimals) = scaled(value, base.pow(index as u32), whole_from);
+    // rounding may reach the next unit, as 1023.99 KiB does
+    if index + 1 < units.len() && number >= base * 10u128.pow(decimals as u32) {
+        index += 1;
+        (number, decimals) = scaled(value, base.pow(index as u32), whole_from);
+    }
+    if index == 0 {
+        return format!("{} {}", value, units[0]);
+    }
+    let factor = 10u128.pow(decimals as u32);
+    if decimals == 0 {
+        return format!("{} {}", number, units[index]);
+    }
+    return format!("{}.{:0width$} {}", number / factor, number % factor, units[index], width = decimals);
+}
+
+/// `bytes` in binary units, like `512 B`, `1.23 GiB`, `310.4 MiB` or `1023 KiB`
+pub fn format_bytes(bytes: u64) -> String {
+    return with_unit(bytes as u128, 1024, &BINARY_UNITS, 1000);
+}
+
+/// Compression ratio `bytes_in` / `bytes_out` with two decimals, like `4.02x`, or `-` if nothing
+/// was written.
+pub fn format_ratio(bytes_in: u64, bytes_out: u64) -> String {
+    if bytes_out == 0 {
+        return "-".into();
+    }
+    let hundredths = (bytes_in as u128 * 100 + bytes_out as u128 / 2) / bytes_out as u128;
+    return format!("{}.{:02}x", hundredths / 100, hundredths % 100);
+}
+
+/// Throughput in decimal units, like network and disk rates, with three significant digits:
+/// `215 MB/s`, `3.00 kB/s`, or `-` for a zero duration.
+pub fn format_rate(bytes: u64, duration: Duration) -> String {
+    let nanos = duration.as_nanos();
+    if nanos == 0 {
+        return "-".into();
+    }
+    let per_second = (bytes as u128 * 1_000_000_000 + nanos / 2) / nanos;
+    return format!("{}/s", with_unit(per_second, 1000, &DECIMAL_UNITS, 100));
+}
+
+/// Parse a byte size: a plain number of bytes (`4096`), or a number with a unit, binary (`KiB`,
+/// `MiB`, `GiB`, `TiB`, `PiB`) or decimal (`KB`, `MB`, `GB`, `TB`, `PB`), like `4MiB`, `1.5 GiB`
+/// or `64kB`. Units are case insensitive, `B` alone is bytes, and the result must be a whole
+/// number of bytes.
+pub fn parse_bytes(text: &str) -> Result<u64, Box<dyn Error>> {
+    let text = text.trim();
+    let split = text.find(|c: char| !c.is_ascii_digit() && c != '.').unwrap_or(text.len());
+    let (number, unit) = (&text[..split], text[split..].trim());
+    let multiplier: u128 = match unit.to_ascii_lowercase().as_str() {
+        "" | "b" => 1,
+        "kb" => 1000,
+        "mb" => 1000u128.pow(2),
+        "gb" => 1000u128.pow(3),
+        "tb" => 1000u128.pow(4),
+        "pb" => 1000u128.pow(5),
+        "kib" => 1 << 10,
+        "mib" => 1 << 20,
+        "gib" => 1 << 30,
+        "tib" => 1 << 40,
+        "pib" => 1 << 50,
+        _ => return Err(format!("unknown size unit `{}` in `{}`", unit, text).into()),
+    };
+    let (whole, fraction) = number.split_once('.').unwrap_or((number, ""));
+    if whole.is_empty() || whole.len() > 20 || fraction.len() > 18 || fraction.contains('.') {
+        return Err(format!("`{}` is not a size", text).into());
+    }
+    let scale = 10u128.pow(fraction.len() as u32);
+    let numerator = whole.parse::<u128>()? * scale + if fraction.is_empty() { 0 } else { fraction.parse::<u128>()? };
+    let bytes = numerator * multiplier;
+    if !bytes.is_multiple_of(scale) {
+        return Err(format!("`{}` is not a whole number of bytes", text).into());
+    }
+    return u64::try_from(bytes / scale).map_err(|_| format!("`{}` is too large", text).into());
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    pub fn test_format() {
+        assert_eq!(format_bytes(0), "0 B");
+        assert_eq!(format_bytes(1023), "1023 B");
+        assert_eq!(format_bytes(1024), "1.00 KiB");
+        assert_eq!(format_bytes(1_320_702_444), "1.23 GiB");
+        assert_eq!(format_bytes(325_478_809), "310.4 MiB");
+        assert_eq!(format_bytes(1024 * 1023), "1023 KiB");
+        assert_eq!(format_bytes(1024 * 1024 - 1), "1.00 MiB");
+        assert_eq!(format_bytes(u64::MAX), "16.0 EiB");
+        assert_eq!(format_ratio(1_320_702_444, 328_533_000), "4.02x");
+        assert_eq!(format_ratio(5, 0), "-");
+        assert_eq!(format_ratio(1, 3), "0.33x");
+        assert_eq!(format_rate(215_000_000, Duration::from_secs(1)), "215 MB/s");
+        assert_eq!(format_rate(999, Duration::from_secs(1)), "999 B/s");
+        assert_eq!(format_rate(1500, Duration::from_millis(500)), "3.00 kB/s");
+        assert_eq!(format_rate(1, Duration::ZERO), "-");
+        let summary = crate::OperationSummary { bytes_in: 1_320_702_444, bytes_out: 325_478_809, elapsed: Duration::from_secs(6) };
+        assert_eq!(summary.to_string(), "1.23 GiB → 310.4 MiB, 4.06x, 220 MB/s");
+    }
+
+    #[test]
+    pub fn test_parse_bytes() {
+        assert_eq!(parse_bytes("4096").unwrap(), 4096);
+        assert_eq!(parse_bytes("4MiB").unwrap(), 4 << 20);
+        assert_eq!(parse_bytes("4MB").unwrap(), 4_000_000);
+        assert_eq!(parse_bytes("64 kib").unwrap(), 65536);
+        assert_eq!(parse_bytes("1.5GiB").unwrap(), 3 << 29);
+        assert_eq!(parse_bytes("0B").unwrap(), 0);
+        assert_eq!(parse_bytes("16 PiB").unwrap(), 1 << 54);
+        assert_eq!(parse_bytes("18446744073709551615").unwrap(), u64::MAX);
+        for invalid in ["", "MiB", "4 MiBs", "-1", "1.5", "1.1.1KB", "0.1B", "16384 PiB", "99999999999999999999999"] {
+            assert!(parse_bytes(invalid).is_err(), "{}", invalid);
+        }
+        for bytes in [0, 1, 1000, 1024, 4 << 20, 3 << 29] {
+            let formatted = format_bytes(bytes);
+            assert_eq!(parse_bytes(&formatted).unwrap(), bytes, "{}", formatted);
+        }
+    }
+}
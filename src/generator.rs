@@ -0,0 +1,155 @@
+use std::error::Error;
+use std::io::{Read, Write};
+use crate::buffer::SharedBuffer;
+use crate::{compressed_writer, decompressed_reader, CompressionType, ParamSet};
+
+/// Reader of the compressed form of the chunks of an iterator, see `compressed_reader_from_iter`
+struct IterReader<I> {
+    chunks: I,
+    encoder: Option<Box<dyn Write>>,
+    output: SharedBuffer,
+    pending: std::io::Cursor<Vec<u8>>,
+    failed: bool,
+}
+
+impl<I: Iterator<Item = std::io::Result<Vec<u8>>>> Read for IterReader<I> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        loop {
+            let read = self.pending.read(buf)?;
+            if read > 0 || buf.is_empty() {
+                return Ok(read);
+            }
+            if self.failed {
+                return Err(std::io::Error::other("compressed stream is incomplete after an earlier error"));
+            }
+            let Some(encoder) = self.encoder.as_mut() else {
+                return Ok(0);
+            };
+            let written = match self.chunks.next() {
+                Some(Ok(chunk)) => encoder.write_all(&chunk),
+                Some(Err(e)) => Err(e),
+                None => {
+                    // dropping finishes the stream
+                    self.encoder = None;
+                    Ok(())
+                }
+            };
+            if let Err(e) = written {
+                self.failed = true;
+                self.encoder = None;
+                self.output.take();
+                return Err(e);
+            }
+            self.pending = std::io::Cursor::new(self.output.take());
+        }
+    }
+}
+
+/// Reader of the compressed form of the plaintext chunks `chunks` yields, for data produced by
+/// code rather than behind a `Read`, like the rows of a database cursor.
+///
+/// Chunks are pulled on the reading thread, only when the compressed bytes produced so far were
+/// all read, so the iterator advances as little ahead of the reader as the encoder allows. An
+/// `Err` from the iterator is returned by `read` as is; the stream is then unusable. `option`
+/// takes the parameters of `compressed_writer`.
+pub fn compressed_reader_from_iter<I, T>(chunks: I, compression_type: CompressionType, option: T) -> Result<Box<dyn Read>, Box<dyn Error>>
+where I: Iterator<Item = std::io::Result<Vec<u8>>> + 'static, T: Into<ParamSet> {
+    let output = SharedBuffer::default();
+    let encoder = compressed_writer(Box::new(output.clone()), compression_type, option)?;
+    return Ok(Box::new(IterReader {
+        chunks,
+        encoder: Some(encoder),
+        output,
+        pending: std::io::Cursor::new(Vec::new()),
+        failed: false,
+    }));
+}
+
+/// Decompress `src` into `sink`, called with each piece of plaintext in order, and return the
+/// number of plaintext bytes. Errors of `sink` and of the decoder are returned with their kinds.
+pub fn decompress_to_fn<F>(src: Box<dyn Read>, compression_type: CompressionType, mut sink: F) -> std::io::Result<u64>
+where F: FnMut(&[u8]) -> std::io::Result<()> {
+    let mut reader = decompressed_reader(src, compression_type).map_err(|e| match e.downcast::<std::io::Error>() {
+        Ok(e) => *e,
+        Err(e) => std::io::Error::other(e.to_string()),
+    })?;
+    let mut buffer = vec![0u8; 64 * 1024];
+    let mut total: u64 = 0;
+    loop {
+        let read = match reader.read(&mut buffer) {
+            Ok(read) => read,
+            Err(e) if e.kind() == std::io::ErrorKind::Interrupted => continue,
+            Err(e) => return Err(e),
+        };
+        if read == 0 {
+            return Ok(total);
+        }
+        sink(&buffer[..read])?;
+        total += read as u64;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::cell::Cell;
+    use std::io::ErrorKind;
+    use std::rc::Rc;
+
+    /// 10k rows, counting how many were pulled, failing at `fail_at` if set
+    fn rows(pulled: Rc<Cell<usize>>, fail_at: Option<usize>) -> impl Iterator<Item = std::io::Result<Vec<u8>>> {
+        return (0..10_000).map(move |i| {
+            pulled.set(i + 1);
+            if Some(i) == fail_at {
+                return Err(std::io::Error::new(ErrorKind::ConnectionReset, "cursor lost"));
+            }
+            return Ok(format!("{},row {},{}\n", i, i * 7919 % 10_007, i % 13).into_bytes());
+        });
+    }
+
+    fn expected() -> Vec<u8> {
+        return (0..10_000).flat_map(|i| format!("{},row {},{}\n", i, i * 7919 % 10_007, i % 13).into_bytes()).collect();
+    }
+
+    #[test]
+    pub fn test_round_trip_and_laziness() {
+        for ct in [CompressionType::Gzip, CompressionType::Zstd, CompressionType::LZ4, CompressionType::None] {
+            let pulled = Rc::new(Cell::new(0));
+            let mut reader = compressed_reader_from_iter(rows(pulled.clone(), None), ct, "").unwrap();
+            assert_eq!(pulled.get(), 0);
+            let mut compressed = vec![0u8; 16];
+            reader.read_exact(&mut compressed).unwrap();
+            let after_first = pulled.get();
+            assert!(after_first > 0 && after_first < 10_000, "{} pulled {}", ct, after_first);
+            reader.read_to_end(&mut compressed).unwrap();
+            assert_eq!(pulled.get(), 10_000);
+
+            let mut plain = Vec::new();
+            let total = decompress_to_fn(Box::new(std::io::Cursor::new(compressed)), ct, |piece| {
+                plain.extend_from_slice(piece);
+                return Ok(());
+            }).unwrap();
+            assert_eq!(total, plain.len() as u64);
+            assert!(plain == expected(), "{}", ct);
+        }
+    }
+
+    #[test]
+    pub fn test_errors_keep_their_kind() {
+        let pulled = Rc::new(Cell::new(0));
+        let mut reader = compressed_reader_from_iter(rows(pulled.clone(), Some(6000)), CompressionType::Zstd, "").unwrap();
+        let err = reader.read_to_end(&mut Vec::new()).unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::ConnectionReset);
+        assert_eq!(pulled.get(), 6001);
+        assert!(reader.read(&mut [0u8; 16]).is_err());
+
+        let compressed = compressed_reader_from_iter(rows(pulled, None), CompressionType::Zstd, "").unwrap();
+        let mut calls = 0;
+        let err = decompress_to_fn(compressed, CompressionType::Zstd, |_| {
+            calls += 1;
+            return Err(std::io::Error::new(ErrorKind::StorageFull, "disk full"));
+        }).unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::StorageFull);
+        assert_eq!(calls, 1);
+    }
+}
@@ -0,0 +1,80 @@
+use std::io::Write;
+
+/// Sink adapter placed between every encoder and the caller's sink.
+///
+/// Encoders finish their stream in `Drop`, which also runs while a panic unwinds, for example a
+/// panic raised by the caller's sink itself. Calling into a sink that just panicked risks a second
+/// panic, which aborts the process. While the thread is panicking this adapter therefore refuses
+/// to forward anything and reports an error instead, so the original panic propagates intact.
+pub(crate) struct UnwindGuard {
+    inner: Box<dyn Write>,
+}
+
+impl UnwindGuard {
+    pub(crate) fn new(inner: Box<dyn Write>) -> UnwindGuard {
+        return UnwindGuard { inner };
+    }
+}
+
+fn unwinding() -> std::io::Error {
+    return std::io::Error::other("sink not written while a panic unwinds");
+}
+
+impl Write for UnwindGuard {
+    fn write(&mut self, data: &[u8]) -> std::io::Result<usize> {
+        if std::thread::panicking() {
+            return Err(unwinding());
+        }
+        return self.inner.write(data);
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        if std::thread::panicking() {
+            return Err(unwinding());
+        }
+        return self.inner.flush();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Write;
+    use std::panic::{catch_unwind, AssertUnwindSafe};
+    use crate::{compressed_writer, CompressionType};
+
+    struct Exploding {
+        on_flush: bool,
+    }
+
+    impl Write for Exploding {
+        fn write(&mut self, data: &[u8]) -> std::io::Result<usize> {
+            if !self.on_flush {
+                panic!("sink exploded");
+            }
+            return Ok(data.len());
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            panic!("sink exploded");
+        }
+    }
+
+    #[test]
+    pub fn test_panicking_sink_does_not_abort() {
+        let data: Vec<u8> = (0..1_000_000u32).map(|i| (i % 251) as u8 ^ (i >> 12) as u8).collect();
+        for ct in [CompressionType::Zstd, CompressionType::Snappy, CompressionType::Gzip, CompressionType::Zlib,
+            CompressionType::Deflate, CompressionType::Bzip2, CompressionType::LZ4, CompressionType::XZ,
+            CompressionType::Stored, CompressionType::Brotli, CompressionType::Lzo, CompressionType::None] {
+            for on_flush in [false, true] {
+                let result = catch_unwind(AssertUnwindSafe(|| {
+                    let mut w = compressed_writer(Box::new(Exploding { on_flush }), ct, "").unwrap();
+                    w.write_all(&data).unwrap();
+                    w.flush().unwrap();
+                    drop(w);
+                }));
+                let payload = result.expect_err(&format!("{:?} did not panic", ct));
+                assert_eq!(payload.downcast_ref::<&str>(), Some(&"sink exploded"), "{:?}", ct);
+            }
+        }
+    }
+}
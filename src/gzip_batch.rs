@@ -0,0 +1,225 @@
+use std::fmt;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Mutex;
+use flate2::{Crc, Decompress, FlushDecompress, Status};
+
+/// Output reserved per item before it grows, the first time only
+const INITIAL_SCRATCH: usize = 64 * 1024;
+
+/// Why one input of a batch could not be decoded
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ItemError {
+    /// Position of the input in the batch
+    pub index: usize,
+    pub message: String,
+}
+
+impl fmt::Display for ItemError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "item {}: {}", self.index, self.message)
+    }
+}
+
+impl std::error::Error for ItemError {}
+
+/// Decoder of many small gzip objects, like the log files S3 or CloudFront deliver, where
+/// setting up a decoder per object costs more than decoding it. One inflate context and one
+/// output buffer serve every item, reset in between.
+///
+/// Each item is decoded like `decompressed_reader` does: all its members, ignoring what follows
+/// them when it is not a gzip member, with every CRC and length checked.
+pub struct GzipBatchDecoder {
+    inflate: Decompress,
+    scratch: Vec<u8>,
+}
+
+impl Default for GzipBatchDecoder {
+    fn default() -> Self {
+        return GzipBatchDecoder::new();
+    }
+}
+
+impl GzipBatchDecoder {
+    pub fn new() -> GzipBatchDecoder {
+        return GzipBatchDecoder { inflate: Decompress::new(false), scratch: Vec::new() };
+    }
+
+    /// Decode every input, in order, handing `out` the index and the whole output of each one
+    /// that decodes. The others are returned, in order, and do not stop the batch.
+    pub fn decode_many<I, F>(&mut self, inputs: I, mut out: F) -> Vec<ItemError>
+    where I: IntoIterator<Item = Vec<u8>>, F: FnMut(usize, &[u8]) {
+        let mut failures = Vec::new();
+        for (index, input) in inputs.into_iter().enumerate() {
+            match self.decode(&input) {
+                Ok(()) => out(index, &self.scratch),
+                Err(message) => failures.push(ItemError { index, message }),
+            }
+        }
+        return failures;
+    }
+
+    /// `decode_many` on `threads` threads (0 for the available parallelism), each with its own
+    /// decoder. `out` is called from those threads, in no particular order.
+    pub fn decode_many_parallel<F>(inputs: &[Vec<u8>], threads: usize, out: F) -> Vec<ItemError>
+    where F: Fn(usize, &[u8]) + Sync {
+        let threads = match threads {
+            0 => std::thread::available_parallelism().map(|n| n.get()).unwrap_or(1),
+            n => n,
+        }.min(inputs.len()).max(1);
+        let next = AtomicUsize::new(0);
+        let failures = Mutex::new(Vec::new());
+        std::thread::scope(|scope| {
+            for _ in 0..threads {
+                scope.spawn(|| {
+                    let mut decoder = GzipBatchDecoder::new();
+                    loop {
+                        let index = next.fetch_add(1, Ordering::Relaxed);
+                        let Some(input) = inputs.get(index) else {
+                            break;
+                        };
+                        match decoder.decode(input) {
+                            Ok(()) => out(index, &decoder.scratch),
+                            Err(message) => failures.lock().unwrap_or_else(|e| e.into_inner()).push(ItemError { index, message }),
+                        }
+                    }
+                });
+            }
+        });
+        let mut failures = failures.into_inner().unwrap_or_else(|e| e.into_inner());
+        failures.sort_by_key(|failure| failure.index);
+        return failures;
+    }
+
+    /// Decode the members of `input` into `scratch`
+    fn decode(&mut self, input: &[u8]) -> Result<(), String> {
+        self.scratch.clear();
+        if self.scratch.capacity() == 0 {
+            self.scratch.reserve(INITIAL_SCRATCH);
+        }
+        let mut rest = input;
+        loop {
+            let start = self.scratch.len();
+            rest = &rest[header_len(rest)?..];
+            self.inflate.reset(false);
+            loop {
+                if self.scratch.len() == self.scratch.capacity() {
+                    self.scratch.reserve(self.scratch.len().max(INITIAL_SCRATCH));
+                }
+                let before = self.inflate.total_in();
+                let status = self.inflate.decompress_vec(rest, &mut self.scratch, FlushDecompress::None)
+                    .map_err(|e| format!("invalid deflate data: {}", e))?;
+                let consumed = (self.inflate.total_in() - before) as usize;
+                rest = &rest[consumed..];
+                if status == Status::StreamEnd {
+                    break;
+                }
+                if rest.is_empty() && self.scratch.len() < self.scratch.capacity() {
+                    return Err("gzip member truncated inside its deflate data".into());
+                }
+            }
+            let trailer = rest.get(..8).ok_or("gzip member ends before its trailer")?;
+            let mut crc = Crc::new();
+            crc.update(&self.scratch[start..]);
+            if u32::from_le_bytes(trailer[..4].try_into().unwrap()) != crc.sum() {
+                return Err("gzip CRC mismatch".into());
+            }
+            if u32::from_le_bytes(trailer[4..].try_into().unwrap()) != crc.amount() {
+                return Err("gzip length mismatch".into());
+            }
+            rest = &rest[8..];
+            if !rest.starts_with(&[0x1f, 0x8b]) {
+                return Ok(());
+            }
+        }
+    }
+}
+
+/// Length of the gzip member header at the start of `data`
+fn header_len(data: &[u8]) -> Result<usize, String> {
+    let truncated = || "gzip header truncated".to_string();
+    if data.len() < 10 {
+        return Err(if data.is_empty() { "empty input".into() } else { truncated() });
+    }
+    if data[..3] != [0x1f, 0x8b, 8] {
+        return Err("not a gzip member".into());
+    }
+    let flags = data[3];
+    let mut len = 10;
+    if flags & 0x04 != 0 {
+        let extra = data.get(10..12).ok_or_else(truncated)?;
+        len += 2 + u16::from_le_bytes([extra[0], extra[1]]) as usize;
+    }
+    for field in [0x08, 0x10] {
+        if flags & field != 0 {
+            let end = data.get(len..).and_then(|rest| rest.iter().position(|b| *b == 0)).ok_or_else(truncated)?;
+            len += end + 1;
+        }
+    }
+    if flags & 0x02 != 0 {
+        len += 2;
+    }
+    if len > data.len() {
+        return Err(truncated());
+    }
+    return Ok(len);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Write;
+
+    /// 10000 small gzip objects, every 997th one corrupt
+    fn objects() -> (Vec<Vec<u8>>, Vec<Vec<u8>>) {
+        let mut plain = Vec::new();
+        let mut compressed = Vec::new();
+        for i in 0..10_000usize {
+            let line = format!("2024-01-01T00:00:{:02}Z GET /object/{} 200 {}\n", i % 60, i, i * 31 % 9973);
+            let data = line.repeat(1 + i % 20).into_bytes();
+            let mut encoder = flate2::write::GzEncoder::new(Vec::new(), flate2::Compression::new(1 + (i % 9) as u32));
+            encoder.write_all(&data).unwrap();
+            let mut gz = encoder.finish().unwrap();
+            if i % 997 == 0 {
+                let middle = gz.len() / 2;
+                gz[middle] ^= 0xff;
+            }
+            plain.push(data);
+            compressed.push(gz);
+        }
+        return (plain, compressed);
+    }
+
+    #[test]
+    pub fn test_decode_many() {
+        let (plain, compressed) = objects();
+        let corrupt: Vec<usize> = (0..10_000).filter(|i| i % 997 == 0).collect();
+        let mut decoded = vec![None; plain.len()];
+        let failures = GzipBatchDecoder::new().decode_many(compressed.clone(), |index, data| decoded[index] = Some(data.to_vec()));
+        assert_eq!(failures.iter().map(|f| f.index).collect::<Vec<_>>(), corrupt);
+        for (index, data) in decoded.iter().enumerate() {
+            match data {
+                Some(data) => assert!(*data == plain[index], "item {}", index),
+                None => assert!(corrupt.contains(&index)),
+            }
+        }
+
+        let decoded = Mutex::new(vec![None; plain.len()]);
+        let parallel = GzipBatchDecoder::decode_many_parallel(&compressed, 4, |index, data| {
+            decoded.lock().unwrap()[index] = Some(data.to_vec());
+        });
+        assert_eq!(parallel, failures);
+        assert_eq!(decoded.into_inner().unwrap().iter().filter(|d| d.is_some()).count(), 10_000 - corrupt.len());
+
+        // members, headers with every field, and bad inputs
+        let mut member = flate2::GzBuilder::new().filename("a.log").comment("c").extra(vec![0; 4])
+            .write(Vec::new(), flate2::Compression::new(6));
+        member.write_all(b"one ").unwrap();
+        let member = member.finish().unwrap();
+        let items = vec![[member.clone(), member.clone(), b"junk".to_vec()].concat(), Vec::new(), member[..member.len() - 3].to_vec(), b"plain text, not gzip".to_vec()];
+        let mut outputs = Vec::new();
+        let failures = GzipBatchDecoder::new().decode_many(items, |index, data| outputs.push((index, data.to_vec())));
+        assert_eq!(outputs, [(0, b"one one ".to_vec())]);
+        assert_eq!(failures.iter().map(|f| f.to_string()).collect::<Vec<_>>(),
+            ["item 1: empty input", "item 2: gzip member ends before its trailer", "item 3: not a gzip member"]);
+    }
+}
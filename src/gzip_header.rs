@@ -0,0 +1,132 @@
+use std::error::Error;
+use std::io::{Cursor, Read};
+use crate::{decompressed_reader_with, CompressionType, ParamSet};
+
+/// Header of the first member of a gzip stream, as written by `gzip -N` and by the `filename`,
+/// `mtime` and `comment` writer parameters
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct GzHeaderInfo {
+    /// Original file name. The format says ISO 8859-1, most tools write UTF-8: bytes that are
+    /// not UTF-8 are replaced with U+FFFD, see `filename_bytes` for the exact ones.
+    pub filename: Option<String>,
+    pub filename_bytes: Option<Vec<u8>>,
+    /// Modification time in unix seconds, 0 when the writer stored none
+    pub mtime: u32,
+    pub comment: Option<String>,
+    /// Operating system of the writer: 0 FAT, 3 Unix, 11 NTFS, 255 unknown...
+    pub os: u8,
+}
+
+/// Header fields are NUL terminated, this bounds a corrupt one
+const MAX_FIELD: usize = 64 * 1024;
+
+/// Same as `read_gzip_header_with`, without parameters
+pub fn read_gzip_header(src: Box<dyn Read>) -> Result<(GzHeaderInfo, Box<dyn Read>), Box<dyn Error>> {
+    return read_gzip_header_with(src, "");
+}
+
+/// Parse the header of the gzip stream `src` and return it with the decompressed reader of the
+/// stream, which reads it as `decompressed_reader_with` and `option` would. Only the header is
+/// read before returning.
+pub fn read_gzip_header_with<T: Into<ParamSet>>(mut src: Box<dyn Read>, option: T) -> Result<(GzHeaderInfo, Box<dyn Read>), Box<dyn Error>> {
+    let mut raw = Vec::new();
+    let header = parse(&mut src, &mut raw).map_err(|e| format!("invalid gzip header: {}", e))?;
+    let reader = decompressed_reader_with(Box::new(Cursor::new(raw).chain(src)), CompressionType::Gzip, option)?;
+    return Ok((header, reader));
+}
+
+/// Parse the header, keeping the bytes read in `raw`
+fn parse(src: &mut dyn Read, raw: &mut Vec<u8>) -> Result<GzHeaderInfo, Box<dyn Error>> {
+    let mut fixed = [0u8; 10];
+    src.read_exact(&mut fixed)?;
+    raw.extend_from_slice(&fixed);
+    if fixed[..3] != [0x1f, 0x8b, 8] {
+        return Err("not a gzip stream".into());
+    }
+    let flags = fixed[3];
+    if flags & 0xe0 != 0 {
+        return Err(format!("reserved flags {:#04x}", flags).into());
+    }
+    if flags & 0x04 != 0 {
+        let mut length = [0u8; 2];
+        src.read_exact(&mut length)?;
+        raw.extend_from_slice(&length);
+        let mut extra = vec![0u8; u16::from_le_bytes(length) as usize];
+        src.read_exact(&mut extra)?;
+        raw.extend_from_slice(&extra);
+    }
+    let filename_bytes = if flags & 0x08 != 0 { Some(field(src, raw, "file name")?) } else { None };
+    let comment = if flags & 0x10 != 0 { Some(field(src, raw, "comment")?) } else { None };
+    if flags & 0x02 != 0 {
+        let mut crc = [0u8; 2];
+        src.read_exact(&mut crc)?;
+        raw.extend_from_slice(&crc);
+    }
+    return Ok(GzHeaderInfo {
+        filename: filename_bytes.as_ref().map(|name| String::from_utf8_lossy(name).into_owned()),
+        filename_bytes,
+        mtime: u32::from_le_bytes(fixed[4..8].try_into().unwrap()),
+        comment: comment.map(|comment| String::from_utf8_lossy(&comment).into_owned()),
+        os: fixed[9],
+    });
+}
+
+/// A NUL terminated field, without its NUL
+fn field(src: &mut dyn Read, raw: &mut Vec<u8>, what: &str) -> Result<Vec<u8>, Box<dyn Error>> {
+    let mut value = Vec::new();
+    let mut byte = [0u8; 1];
+    loop {
+        src.read_exact(&mut byte)?;
+        raw.push(byte[0]);
+        if byte[0] == 0 {
+            return Ok(value);
+        }
+        if value.len() == MAX_FIELD {
+            return Err(format!("{} longer than {} bytes", what, MAX_FIELD).into());
+        }
+        value.push(byte[0]);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Write;
+
+    /// `printf 'fixture written by gzip 1.x\n' > notes.txt; touch -d @1600000000 notes.txt;
+    /// gzip -N -9 notes.txt`, with gzip 1.12
+    const GZIP_N_FIXTURE: &[u8] = &[
+        0x1f, 0x8b, 0x08, 0x08, 0x00, 0x10, 0x5e, 0x5f, 0x02, 0x03, 0x6e, 0x6f, 0x74, 0x65, 0x73, 0x2e,
+        0x74, 0x78, 0x74, 0x00, 0x4b, 0xcb, 0xac, 0x28, 0x29, 0x2d, 0x4a, 0x55, 0x28, 0x2f, 0xca, 0x2c,
+        0x29, 0x49, 0xcd, 0x53, 0x48, 0xaa, 0x54, 0x48, 0xaf, 0xca, 0x2c, 0x50, 0x30, 0xd4, 0xab, 0xe0,
+        0x02, 0x00, 0xb0, 0xd8, 0xa1, 0x4c, 0x1c, 0x00, 0x00, 0x00,
+    ];
+
+    #[test]
+    pub fn test_read_gzip_header() {
+        let (header, mut reader) = read_gzip_header(Box::new(GZIP_N_FIXTURE)).unwrap();
+        assert_eq!(header.filename.as_deref(), Some("notes.txt"));
+        assert_eq!((header.mtime, header.comment, header.os), (1_600_000_000, None, 3));
+        let mut plain = String::new();
+        reader.read_to_string(&mut plain).unwrap();
+        assert_eq!(plain, "fixture written by gzip 1.x\n");
+
+        // every optional field, and a name that is not UTF-8
+        let mut compressed = Vec::new();
+        let mut encoder = flate2::GzBuilder::new().filename(&b"caf\xe9.txt"[..]).comment("export").extra(vec![1, 2, 0, 0])
+            .mtime(7).write(&mut compressed, flate2::Compression::new(6));
+        encoder.write_all(b"with extras").unwrap();
+        encoder.finish().unwrap();
+        let (header, mut reader) = read_gzip_header_with(Box::new(Cursor::new(compressed)), "trailing_data=error").unwrap();
+        assert_eq!(header.filename.as_deref(), Some("caf\u{fffd}.txt"));
+        assert_eq!(header.filename_bytes.as_deref(), Some(&b"caf\xe9.txt"[..]));
+        assert_eq!((header.comment.as_deref(), header.mtime), (Some("export"), 7));
+        let mut plain = String::new();
+        reader.read_to_string(&mut plain).unwrap();
+        assert_eq!(plain, "with extras");
+
+        assert!(read_gzip_header(Box::new(&GZIP_N_FIXTURE[..15])).is_err());
+        let err = read_gzip_header(Box::new(&b"BZh91AY&SY"[..])).err().unwrap();
+        assert!(err.to_string().contains("not a gzip stream"), "{}", err);
+    }
+}
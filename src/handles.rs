@@ -0,0 +1,210 @@
+use std::collections::HashMap;
+use std::io::{Read, Write};
+use std::sync::{Arc, Mutex, OnceLock, Weak};
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::time::{Duration, Instant};
+use crate::{CompressionType, ParamSet};
+
+static TRACKING: AtomicBool = AtomicBool::new(false);
+static NEXT_ID: AtomicU64 = AtomicU64::new(1);
+
+fn registry() -> &'static Mutex<HashMap<u64, Weak<HandleState>>> {
+    static REGISTRY: OnceLock<Mutex<HashMap<u64, Weak<HandleState>>>> = OnceLock::new();
+    return REGISTRY.get_or_init(|| Mutex::new(HashMap::new()));
+}
+
+/// Timestamps are stored as nanoseconds since this instant
+fn epoch() -> Instant {
+    static EPOCH: OnceLock<Instant> = OnceLock::new();
+    return *EPOCH.get_or_init(Instant::now);
+}
+
+fn nanos_since_epoch() -> u64 {
+    return epoch().elapsed().as_nanos() as u64;
+}
+
+/// Start or stop registering new readers and writers. Handles created while tracking stay
+/// registered until dropped.
+pub fn set_handle_tracking(enabled: bool) {
+    TRACKING.store(enabled, Ordering::Relaxed);
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum HandleKind {
+    Reader,
+    Writer,
+}
+
+/// A reader or writer that was created while tracking and is not dropped yet
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct HandleInfo {
+    pub id: u64,
+    /// `handle_label` parameter given when creating it, empty if none
+    pub label: String,
+    pub compression_type: CompressionType,
+    pub kind: HandleKind,
+    pub created: Instant,
+    /// Uncompressed bytes read or written so far
+    pub bytes: u64,
+    /// Last read or write, `created` if none
+    pub last_activity: Instant,
+}
+
+impl HandleInfo {
+    /// Time since the last read or write
+    pub fn idle(&self) -> Duration {
+        return self.last_activity.elapsed();
+    }
+}
+
+struct HandleState {
+    id: u64,
+    label: String,
+    compression_type: CompressionType,
+    kind: HandleKind,
+    created: u64,
+    bytes: AtomicU64,
+    last_activity: AtomicU64,
+}
+
+impl HandleState {
+    fn record(&self, bytes: usize) {
+        self.bytes.fetch_add(bytes as u64, Ordering::Relaxed);
+        self.last_activity.store(nanos_since_epoch(), Ordering::Relaxed);
+    }
+
+    fn info(&self) -> HandleInfo {
+        return HandleInfo {
+            id: self.id,
+            label: self.label.clone(),
+            compression_type: self.compression_type,
+            kind: self.kind,
+            created: epoch() + Duration::from_nanos(self.created),
+            bytes: self.bytes.load(Ordering::Relaxed),
+            last_activity: epoch() + Duration::from_nanos(self.last_activity.load(Ordering::Relaxed)),
+        };
+    }
+}
+
+/// Unregisters the handle when dropped
+struct Registration(Arc<HandleState>);
+
+impl Drop for Registration {
+    fn drop(&mut self) {
+        registry().lock().unwrap_or_else(|e| e.into_inner()).remove(&self.0.id);
+    }
+}
+
+fn register(compression_type: CompressionType, kind: HandleKind, param_set: &ParamSet) -> Option<Registration> {
+    if !TRACKING.load(Ordering::Relaxed) {
+        return None;
+    }
+    let now = nanos_since_epoch();
+    let state = Arc::new(HandleState {
+        id: NEXT_ID.fetch_add(1, Ordering::Relaxed),
+        label: param_set.get_string("handle_label", "").to_string(),
+        compression_type,
+        kind,
+        created: now,
+        bytes: AtomicU64::new(0),
+        last_activity: AtomicU64::new(now),
+    });
+    registry().lock().unwrap_or_else(|e| e.into_inner()).insert(state.id, Arc::downgrade(&state));
+    return Some(Registration(state));
+}
+
+/// Every registered reader and writer that is still alive, oldest first
+pub fn live_handles() -> Vec<HandleInfo> {
+    let registry = registry().lock().unwrap_or_else(|e| e.into_inner());
+    let mut handles: Vec<HandleInfo> = registry.values().filter_map(|h| h.upgrade()).map(|h| h.info()).collect();
+    handles.sort_by_key(|h| h.id);
+    return handles;
+}
+
+struct TrackedWriter {
+    inner: Box<dyn Write>,
+    registration: Registration,
+}
+
+impl Write for TrackedWriter {
+    fn write(&mut self, data: &[u8]) -> std::io::Result<usize> {
+        let written = self.inner.write(data)?;
+        self.registration.0.record(written);
+        return Ok(written);
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        return self.inner.flush();
+    }
+}
+
+struct TrackedReader {
+    inner: Box<dyn Read>,
+    registration: Registration,
+}
+
+impl Read for TrackedReader {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        let read = self.inner.read(buf)?;
+        self.registration.0.record(read);
+        return Ok(read);
+    }
+}
+
+pub(crate) fn track_writer(inner: Box<dyn Write>, compression_type: CompressionType, param_set: &ParamSet) -> Box<dyn Write> {
+    match register(compression_type, HandleKind::Writer, param_set) {
+        Some(registration) => return Box::new(TrackedWriter { inner, registration }),
+        None => return inner,
+    }
+}
+
+pub(crate) fn track_reader(inner: Box<dyn Read>, compression_type: CompressionType, param_set: &ParamSet) -> Box<dyn Read> {
+    match register(compression_type, HandleKind::Reader, param_set) {
+        Some(registration) => return Box::new(TrackedReader { inner, registration }),
+        None => return inner,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{compressed_writer, decompressed_reader_with};
+
+    fn labelled(label: &str) -> Vec<HandleInfo> {
+        return live_handles().into_iter().filter(|h| h.label == label).collect();
+    }
+
+    #[test]
+    pub fn test_registry() {
+        set_handle_tracking(true);
+        let mut writer = compressed_writer(Box::new(std::io::sink()), CompressionType::Gzip, "handle_label=registry-writer").unwrap();
+        writer.write_all(b"hello world").unwrap();
+        let reader = decompressed_reader_with(Box::new(std::io::empty()), CompressionType::None, "handle_label=registry-reader").unwrap();
+        let writers = labelled("registry-writer");
+        assert_eq!(writers.len(), 1);
+        assert_eq!(writers[0].kind, HandleKind::Writer);
+        assert_eq!(writers[0].compression_type, CompressionType::Gzip);
+        assert_eq!(writers[0].bytes, 11);
+        assert_eq!(labelled("registry-reader")[0].kind, HandleKind::Reader);
+        drop(writer);
+        drop(reader);
+        assert!(labelled("registry-writer").is_empty());
+        assert!(labelled("registry-reader").is_empty());
+    }
+
+    #[test]
+    pub fn test_find_leaked_idle_handle() {
+        set_handle_tracking(true);
+        let leaked = compressed_writer(Box::new(std::io::sink()), CompressionType::Zstd, "handle_label=leaked").unwrap();
+        std::mem::forget(leaked);
+        std::thread::sleep(Duration::from_millis(50));
+        let mut busy = compressed_writer(Box::new(std::io::sink()), CompressionType::Zstd, "handle_label=busy").unwrap();
+        busy.write_all(b"recent").unwrap();
+        let stuck: Vec<HandleInfo> = live_handles().into_iter()
+            .filter(|h| h.idle() >= Duration::from_millis(50) && (h.label == "leaked" || h.label == "busy"))
+            .collect();
+        assert_eq!(stuck.len(), 1);
+        assert_eq!(stuck[0].label, "leaked");
+        assert_eq!(stuck[0].bytes, 0);
+    }
+}
@@ -0,0 +1,248 @@
+use std::io::Write;
+use std::sync::{Arc, Mutex, Weak};
+use std::thread::JoinHandle;
+use std::time::{Duration, Instant};
+use crate::clock::{Clock, SystemClock};
+
+struct IdleState<W> {
+    /// None once the writer is dropped: the stream is finished, never by the watchdog
+    inner: Option<W>,
+    last_write: Instant,
+    pending: bool,
+    idle_flushes: u64,
+}
+
+impl<W: Write> IdleState<W> {
+    fn inner(&mut self) -> std::io::Result<&mut W> {
+        return self.inner.as_mut().ok_or_else(|| std::io::Error::other("idle flush writer closed"));
+    }
+
+    fn flush_if_idle(&mut self, now: Instant, idle: Duration) -> std::io::Result<bool> {
+        if self.inner.is_none() || !self.pending || now.saturating_duration_since(self.last_write) < idle {
+            return Ok(false);
+        }
+        self.inner()?.flush()?;
+        self.pending = false;
+        self.idle_flushes += 1;
+        return Ok(true);
+    }
+}
+
+/// Writer that flushes the wrapped (compressing) writer once no write happened for a while, so
+/// buffered compressed bytes of a quiet stream still reach the receiver.
+///
+/// Idle flushes can be driven cooperatively by calling `tick()` from the application's event
+/// loop, or by a background thread started with `spawn_watchdog()`. A flush only happens when
+/// something was written since the last flush, so a silent stream never causes flush storms.
+///
+/// The watchdog needs a `Send` writer: wrap the output of `compressed_writer_send`. Dropping
+/// this writer drops the wrapped one, ending the stream, on the dropping thread.
+pub struct IdleFlushWriter<W: Write> {
+    state: Arc<Mutex<IdleState<W>>>,
+    clock: Arc<dyn Clock>,
+    idle: Duration,
+}
+
+impl<W: Write> IdleFlushWriter<W> {
+    /// Wrap `inner`, flushing it after `idle` without writes.
+    pub fn new(inner: W, idle: Duration) -> IdleFlushWriter<W> {
+        return IdleFlushWriter::with_clock(inner, idle, Arc::new(SystemClock));
+    }
+
+    /// Like `new`, but reading time from `clock`.
+    pub fn with_clock(inner: W, idle: Duration, clock: Arc<dyn Clock>) -> IdleFlushWriter<W> {
+        let state = IdleState {
+            inner: Some(inner),
+            last_write: clock.now(),
+            pending: false,
+            idle_flushes: 0,
+        };
+        return IdleFlushWriter { state: Arc::new(Mutex::new(state)), clock, idle };
+    }
+
+    /// Flush if data is pending and the idle interval has elapsed. Returns whether it flushed.
+    pub fn tick(&mut self) -> std::io::Result<bool> {
+        let now = self.clock.now();
+        return self.lock().flush_if_idle(now, self.idle);
+    }
+
+    /// Number of flushes triggered by idleness (by `tick` or the watchdog)
+    pub fn idle_flush_count(&self) -> u64 {
+        return self.lock().idle_flushes;
+    }
+
+    fn lock(&self) -> std::sync::MutexGuard<'_, IdleState<W>> {
+        return self.state.lock().unwrap_or_else(|e| e.into_inner());
+    }
+}
+
+impl<W: Write + Send + 'static> IdleFlushWriter<W> {
+    /// Start a background thread performing the idle flushes.
+    ///
+    /// The thread only holds a weak reference and exits once this writer is dropped, leaving the
+    /// end of the stream to the drop even when it held the last reference then. Errors of
+    /// idle flushes are ignored by the thread; the next write or flush will report them.
+    pub fn spawn_watchdog(&self) -> JoinHandle<()> {
+        let state: Weak<Mutex<IdleState<W>>> = Arc::downgrade(&self.state);
+        let clock = self.clock.clone();
+        let idle = self.idle;
+        let period = (idle / 4).max(Duration::from_millis(1));
+        return std::thread::spawn(move || {
+            loop {
+                std::thread::sleep(period);
+                let state = match state.upgrade() {
+                    Some(state) => state,
+                    None => return,
+                };
+                let mut state = state.lock().unwrap_or_else(|e| e.into_inner());
+                if state.inner.is_none() {
+                    return;
+                }
+                let _ = state.flush_if_idle(clock.now(), idle);
+            }
+        });
+    }
+}
+
+impl<W: Write> Write for IdleFlushWriter<W> {
+    fn write(&mut self, data: &[u8]) -> std::io::Result<usize> {
+        let now = self.clock.now();
+        let mut state = self.lock();
+        let written = state.inner()?.write(data)?;
+        state.last_write = now;
+        state.pending |= written > 0;
+        return Ok(written);
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        let mut state = self.lock();
+        state.inner()?.flush()?;
+        state.pending = false;
+        return Ok(());
+    }
+}
+
+impl<W: Write> Drop for IdleFlushWriter<W> {
+    fn drop(&mut self) {
+        // ended here, under the lock, so the stream is complete once the drop returns
+        let inner = self.lock().inner.take();
+        drop(inner);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::clock::ManualClock;
+    use crate::CompressionType;
+    use crate::raw::{InflateEngine, Framing, FlushMode, DEFAULT_WINDOW_BITS};
+
+    #[derive(Clone)]
+    struct SharedPipe(Arc<Mutex<Vec<u8>>>);
+
+    impl Write for SharedPipe {
+        fn write(&mut self, data: &[u8]) -> std::io::Result<usize> {
+            self.0.lock().unwrap().extend_from_slice(data);
+            return Ok(data.len());
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            return Ok(());
+        }
+    }
+
+    fn inflate_prefix(compressed: &[u8]) -> Vec<u8> {
+        let mut engine = InflateEngine::new(Framing::Raw, DEFAULT_WINDOW_BITS, None).unwrap();
+        let mut output = Vec::with_capacity(4096);
+        engine.decompress_vec(compressed, &mut output, FlushMode::Sync).unwrap();
+        return output;
+    }
+
+    #[test]
+    pub fn test_cooperative_tick() {
+        let clock = Arc::new(ManualClock::new());
+        let pipe = SharedPipe(Arc::new(Mutex::new(Vec::new())));
+        let encoder = flate2::write::DeflateEncoder::new(pipe.clone(), flate2::Compression::new(3));
+        let mut writer = IdleFlushWriter::with_clock(encoder, Duration::from_secs(10), clock.clone());
+
+        assert!(!writer.tick().unwrap());
+        writer.write_all(b"telemetry sample").unwrap();
+        clock.advance(Duration::from_secs(5));
+        assert!(!writer.tick().unwrap());
+        assert!(inflate_prefix(&pipe.0.lock().unwrap()).is_empty());
+
+        clock.advance(Duration::from_secs(5));
+        assert!(writer.tick().unwrap());
+        assert_eq!(inflate_prefix(&pipe.0.lock().unwrap()), b"telemetry sample");
+
+        clock.advance(Duration::from_secs(60));
+        assert!(!writer.tick().unwrap());
+        assert_eq!(writer.idle_flush_count(), 1);
+    }
+
+    #[test]
+    pub fn test_watchdog_thread() {
+        let pipe = SharedPipe(Arc::new(Mutex::new(Vec::new())));
+        let encoder = flate2::write::DeflateEncoder::new(pipe.clone(), flate2::Compression::new(3));
+        let mut writer = IdleFlushWriter::new(encoder, Duration::from_millis(20));
+        let watchdog = writer.spawn_watchdog();
+        writer.write_all(b"quiet stream").unwrap();
+
+        let deadline = Instant::now() + Duration::from_secs(5);
+        while writer.idle_flush_count() == 0 && Instant::now() < deadline {
+            std::thread::sleep(Duration::from_millis(5));
+        }
+        assert_eq!(inflate_prefix(&pipe.0.lock().unwrap()), b"quiet stream");
+        std::thread::sleep(Duration::from_millis(100));
+        assert_eq!(writer.idle_flush_count(), 1);
+
+        drop(writer);
+        watchdog.join().unwrap();
+    }
+
+    #[test]
+    pub fn test_watchdog_compressed_writer() {
+        for ct in [CompressionType::Gzip, CompressionType::Zstd] {
+            let pipe = SharedPipe(Arc::new(Mutex::new(Vec::new())));
+            let encoder = crate::compressed_writer_send(Box::new(pipe.clone()), ct, "").unwrap();
+            let mut writer = IdleFlushWriter::new(encoder, Duration::from_millis(1));
+            // watchdogs polling as often as possible, holding the state when the writer drops
+            let watchdogs: Vec<JoinHandle<()>> = (0..4).map(|_| writer.spawn_watchdog()).collect();
+            for i in 0..200 {
+                writer.write_all(format!("sample {}\n", i).as_bytes()).unwrap();
+                if i % 50 == 0 {
+                    std::thread::sleep(Duration::from_millis(5));
+                }
+            }
+            drop(writer);
+            // complete right after the drop, before the watchdogs are done
+            let compressed = pipe.0.lock().unwrap().clone();
+            let plain = crate::decompress_bytes(&compressed, ct).unwrap();
+            assert!(plain.ends_with(b"sample 199\n"), "{}", ct);
+            for watchdog in watchdogs {
+                watchdog.join().unwrap();
+            }
+            assert!(*pipe.0.lock().unwrap() == compressed, "{}", ct);
+        }
+    }
+
+    #[test]
+    pub fn test_idle_flush_param() {
+        let pipe = SharedPipe(Arc::new(Mutex::new(Vec::new())));
+        let mut writer = crate::compressed_writer_send(Box::new(pipe.clone()), CompressionType::Deflate, "idle_flush=20").unwrap();
+        writer.write_all(b"quiet stream").unwrap();
+
+        let deadline = Instant::now() + Duration::from_secs(5);
+        while inflate_prefix(&pipe.0.lock().unwrap()).is_empty() && Instant::now() < deadline {
+            std::thread::sleep(Duration::from_millis(5));
+        }
+        assert_eq!(inflate_prefix(&pipe.0.lock().unwrap()), b"quiet stream");
+        drop(writer);
+        assert_eq!(crate::decompress_bytes(&pipe.0.lock().unwrap(), CompressionType::Deflate).unwrap(), b"quiet stream");
+
+        // the watchdog needs a Send sink
+        assert!(crate::compressed_writer(Box::new(Vec::new()), CompressionType::Deflate, "idle_flush=20").is_err());
+        assert!(crate::compressed_writer_into(Vec::new(), CompressionType::Deflate, "idle_flush=20").is_err());
+        assert!(crate::compressed_writer_send(Box::new(Vec::new()), CompressionType::Deflate, "idle_flush=-1").is_err());
+    }
+}
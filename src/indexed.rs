@@ -0,0 +1,380 @@
+use std::collections::HashMap;
+use std::error::Error;
+use std::fs::File;
+use std::io::{BufRead, BufReader, ErrorKind, Read, Seek, SeekFrom};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex, OnceLock, Weak};
+use std::time::SystemTime;
+use crate::{decompressed_reader, CompressionType};
+
+/// Suffix of the sidecar file holding the index of `<path>`
+pub const INDEX_SUFFIX: &str = ".fcidx";
+const INDEX_HEADER: &str = "final_compression index 1";
+
+/// A gzip member or zstd frame of an indexed file, decodable on its own
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct IndexEntry {
+    pub compressed_offset: u64,
+    pub compressed_size: u64,
+    pub uncompressed_offset: u64,
+    pub uncompressed_size: u64,
+}
+
+/// Files opened by `SharedCompressedFile::open`, each behind a lock of its own so that the
+/// first opens of a file wait for one index instead of building several
+type Slot = Arc<Mutex<Weak<SharedCompressedFile>>>;
+static OPEN_FILES: OnceLock<Mutex<HashMap<(PathBuf, CompressionType), Slot>>> = OnceLock::new();
+
+/// A compressed file read by many threads at once, at any offset.
+///
+/// Gzip files of several members, like bgzf or those written with `member_max_uncompressed`, and
+/// zstd files of several frames, seekable zstd included, are indexed once by their member or
+/// frame boundaries. Readers from `reader` have a file handle and decoder of their own and share
+/// the index, and a read at an offset decodes from the start of the member holding it only. A
+/// file of a single member is valid too, just not faster to read at an offset.
+pub struct SharedCompressedFile {
+    path: PathBuf,
+    compression_type: CompressionType,
+    index: Arc<[IndexEntry]>,
+    size: u64,
+    /// Length and modification time of the file when indexed
+    stamp: (u64, Option<SystemTime>),
+    from_sidecar: bool,
+}
+
+impl SharedCompressedFile {
+    /// Shared handle of the file at `path`, a gzip or zstd file. Opening a file already open
+    /// and unchanged returns the same handle. The index is loaded from `<path>.fcidx` if that
+    /// describes the file as it is, and built by decompressing it otherwise.
+    pub fn open(path: &Path, compression_type: CompressionType) -> Result<Arc<SharedCompressedFile>, Box<dyn Error>> {
+        if compression_type != CompressionType::Gzip && compression_type != CompressionType::Zstd {
+            return Err(format!("indexed reading supports gzip and zstd, not {}", compression_type).into());
+        }
+        let path = path.canonicalize().map_err(|e| format!("{}: {}", path.display(), e))?;
+        let slot = {
+            let mut files = OPEN_FILES.get_or_init(Default::default).lock().unwrap_or_else(|e| e.into_inner());
+            files.retain(|_, slot| Arc::strong_count(slot) > 1
+                || slot.try_lock().map(|file| file.strong_count() > 0).unwrap_or(true));
+            files.entry((path.clone(), compression_type)).or_default().clone()
+        };
+        let mut slot = slot.lock().unwrap_or_else(|e| e.into_inner());
+        let stamp = file_stamp(&path)?;
+        if let Some(file) = slot.upgrade() {
+            if file.stamp == stamp {
+                return Ok(file);
+            }
+        }
+        let (index, from_sidecar) = match load_index(&path, compression_type, stamp.0) {
+            Some(index) => (index, true),
+            None => (build_index(&path, compression_type)?, false),
+        };
+        let size = index.last().map(|e| e.uncompressed_offset + e.uncompressed_size).unwrap_or(0);
+        let file = Arc::new(SharedCompressedFile {
+            path,
+            compression_type,
+            index: index.into(),
+            size,
+            stamp,
+            from_sidecar,
+        });
+        *slot = Arc::downgrade(&file);
+        return Ok(file);
+    }
+
+    pub fn path(&self) -> &Path {
+        return &self.path;
+    }
+
+    pub fn compression_type(&self) -> CompressionType {
+        return self.compression_type;
+    }
+
+    /// Uncompressed size
+    pub fn size(&self) -> u64 {
+        return self.size;
+    }
+
+    pub fn index(&self) -> &[IndexEntry] {
+        return &self.index;
+    }
+
+    /// Whether the index came from the sidecar file rather than a scan of the file
+    pub fn from_sidecar(&self) -> bool {
+        return self.from_sidecar;
+    }
+
+    /// Write the index to `<path>.fcidx` for later opens, returning the sidecar's path
+    pub fn save_index(&self) -> Result<PathBuf, Box<dyn Error>> {
+        let mut out = format!("{}\n{} {}\n", INDEX_HEADER, self.compression_type.as_str(), self.stamp.0);
+        for entry in self.index.iter() {
+            out.push_str(&format!("{} {}\n", entry.compressed_size, entry.uncompressed_size));
+        }
+        let sidecar = sidecar(&self.path);
+        std::fs::write(&sidecar, out).map_err(|e| format!("{}: {}", sidecar.display(), e))?;
+        return Ok(sidecar);
+    }
+
+    /// Reader of the uncompressed content from offset 0, with a file handle of its own
+    pub fn reader(&self) -> std::io::Result<IndexedReader> {
+        return Ok(IndexedReader {
+            file: File::open(&self.path)?,
+            compression_type: self.compression_type,
+            index: self.index.clone(),
+            size: self.size,
+            position: 0,
+            current: None,
+        });
+    }
+
+    /// `len` uncompressed bytes from `start`, fewer at the end of the content
+    pub fn read_range(&self, start: u64, len: u64) -> std::io::Result<Vec<u8>> {
+        let mut reader = self.reader()?;
+        reader.seek(SeekFrom::Start(start))?;
+        let mut out = Vec::with_capacity(len.min(self.size.saturating_sub(start)) as usize);
+        reader.take(len).read_to_end(&mut out)?;
+        return Ok(out);
+    }
+}
+
+/// Reader of a `SharedCompressedFile`, seekable to any uncompressed offset
+pub struct IndexedReader {
+    file: File,
+    compression_type: CompressionType,
+    index: Arc<[IndexEntry]>,
+    size: u64,
+    position: u64,
+    /// Entry being decoded and its decoder, which is at `position`
+    current: Option<(usize, Box<dyn Read>)>,
+}
+
+impl IndexedReader {
+    /// Decoder of the entry holding `position`, `None` at the end
+    fn open_entry(&mut self) -> std::io::Result<Option<(usize, Box<dyn Read>)>> {
+        let position = self.position;
+        let k = self.index.partition_point(|e| e.uncompressed_offset + e.uncompressed_size <= position);
+        let Some(entry) = self.index.get(k).copied() else {
+            return Ok(None);
+        };
+        self.file.seek(SeekFrom::Start(entry.compressed_offset))?;
+        let src = Box::new(self.file.try_clone()?.take(entry.compressed_size));
+        let mut decoder = decompressed_reader(src, self.compression_type)
+            .map_err(|e| std::io::Error::other(e.to_string()))?;
+        let skip = position - entry.uncompressed_offset;
+        if std::io::copy(&mut (&mut decoder).take(skip), &mut std::io::sink())? != skip {
+            return Err(std::io::Error::new(ErrorKind::UnexpectedEof, "member shorter than indexed"));
+        }
+        return Ok(Some((k, decoder)));
+    }
+}
+
+impl Read for IndexedReader {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        if buf.is_empty() {
+            return Ok(0);
+        }
+        loop {
+            if self.current.is_none() {
+                match self.open_entry()? {
+                    Some(current) => self.current = Some(current),
+                    None => return Ok(0),
+                }
+            }
+            let (k, decoder) = self.current.as_mut().unwrap();
+            let entry = self.index[*k];
+            let remaining = entry.uncompressed_offset + entry.uncompressed_size - self.position;
+            if remaining == 0 {
+                self.current = None;
+                continue;
+            }
+            let len = buf.len().min(remaining.min(usize::MAX as u64) as usize);
+            let read = decoder.read(&mut buf[..len])?;
+            if read == 0 {
+                return Err(std::io::Error::new(ErrorKind::UnexpectedEof, "member shorter than indexed"));
+            }
+            self.position += read as u64;
+            return Ok(read);
+        }
+    }
+}
+
+impl Seek for IndexedReader {
+    fn seek(&mut self, pos: SeekFrom) -> std::io::Result<u64> {
+        let position = match pos {
+            SeekFrom::Start(offset) => Some(offset),
+            SeekFrom::Current(delta) => self.position.checked_add_signed(delta),
+            SeekFrom::End(delta) => self.size.checked_add_signed(delta),
+        };
+        let position = position
+            .ok_or_else(|| std::io::Error::new(ErrorKind::InvalidInput, "seek before the start of the content"))?;
+        if position != self.position {
+            self.position = position;
+            self.current = None;
+        }
+        return Ok(position);
+    }
+}
+
+fn sidecar(path: &Path) -> PathBuf {
+    let mut name = path.file_name().map(|n| n.to_os_string()).unwrap_or_default();
+    name.push(INDEX_SUFFIX);
+    return path.with_file_name(name);
+}
+
+fn file_stamp(path: &Path) -> Result<(u64, Option<SystemTime>), Box<dyn Error>> {
+    let metadata = std::fs::metadata(path).map_err(|e| format!("{}: {}", path.display(), e))?;
+    return Ok((metadata.len(), metadata.modified().ok()));
+}
+
+/// The sidecar index of `path`, if there is one for `compression_type` and a file of `len` bytes
+fn load_index(path: &Path, compression_type: CompressionType, len: u64) -> Option<Vec<IndexEntry>> {
+    let content = std::fs::read_to_string(sidecar(path)).ok()?;
+    let mut lines = content.lines();
+    if lines.next()? != INDEX_HEADER || lines.next()? != format!("{} {}", compression_type.as_str(), len) {
+        return None;
+    }
+    let mut index = Vec::new();
+    let (mut compressed_offset, mut uncompressed_offset) = (0u64, 0u64);
+    for line in lines {
+        let (compressed, uncompressed) = line.split_once(' ')?;
+        let entry = IndexEntry {
+            compressed_offset,
+            compressed_size: compressed.parse().ok()?,
+            uncompressed_offset,
+            uncompressed_size: uncompressed.parse().ok()?,
+        };
+        compressed_offset = compressed_offset.checked_add(entry.compressed_size)?;
+        uncompressed_offset = uncompressed_offset.checked_add(entry.uncompressed_size)?;
+        index.push(entry);
+    }
+    return if compressed_offset == len { Some(index) } else { None };
+}
+
+/// `BufRead` counting the bytes consumed from it
+pub(crate) struct Counting<R> {
+    pub(crate) inner: R,
+    pub(crate) consumed: u64,
+}
+
+impl<R: BufRead> Read for Counting<R> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        let read = self.inner.read(buf)?;
+        self.consumed += read as u64;
+        return Ok(read);
+    }
+}
+
+impl<R: BufRead> BufRead for Counting<R> {
+    fn fill_buf(&mut self) -> std::io::Result<&[u8]> {
+        return self.inner.fill_buf();
+    }
+
+    fn consume(&mut self, amt: usize) {
+        self.consumed += amt as u64;
+        self.inner.consume(amt);
+    }
+}
+
+/// Decoder of the gzip member or zstd frame at the start of `src`. The bufread decoders take no
+/// byte past its end.
+pub(crate) fn member_decoder<'a, R: BufRead + 'a>(src: &'a mut R, compression_type: CompressionType) -> Result<Box<dyn Read + 'a>, Box<dyn Error>> {
+    return match compression_type {
+        CompressionType::Gzip => Ok(Box::new(flate2::bufread::GzDecoder::new(src))),
+        CompressionType::Zstd => Ok(Box::new(zstd::stream::read::Decoder::with_buffer(src)?.single_frame())),
+        _ => Err(format!("indexed reading supports gzip and zstd, not {}", compression_type).into()),
+    };
+}
+
+/// Index of the gzip or zstd file at `path`: its members or frames, found by decompressing it
+pub fn build_index(path: &Path, compression_type: CompressionType) -> Result<Vec<IndexEntry>, Box<dyn Error>> {
+    let file = File::open(path).map_err(|e| format!("{}: {}", path.display(), e))?;
+    let mut src = Counting { inner: BufReader::new(file), consumed: 0 };
+    let mut index = Vec::new();
+    let mut uncompressed_offset = 0;
+    while !src.fill_buf()?.is_empty() {
+        let compressed_offset = src.consumed;
+        let uncompressed_size = std::io::copy(&mut member_decoder(&mut src, compression_type)?, &mut std::io::sink())?;
+        let compressed_size = src.consumed - compressed_offset;
+        if compressed_size == 0 {
+            return Err(format!("{}: no {} member at offset {}", path.display(), compression_type, compressed_offset).into());
+        }
+        index.push(IndexEntry { compressed_offset, compressed_size, uncompressed_offset, uncompressed_size });
+        uncompressed_offset += uncompressed_size;
+    }
+    return Ok(index);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::compress_bytes;
+
+    #[test]
+    pub fn test_concurrent_ranges() {
+        let mut state = 0x9e3779b97f4a7c15u64;
+        let data: Vec<u8> = (0..600_000u32).map(|i| {
+            state ^= state << 13;
+            state ^= state >> 7;
+            state ^= state << 17;
+            if i % 64 < 40 { b"record "[i as usize % 7] } else { (state % 26) as u8 + b'a' }
+        }).collect();
+        let dir = std::env::temp_dir().join(format!("final_compression_indexed_{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let gzip = compress_bytes(&data, CompressionType::Gzip, "member_max_uncompressed=32KiB").unwrap();
+        // frames of 50000 bytes, and a skippable frame like seekable zstd's seek table
+        let mut zstd: Vec<u8> = data.chunks(50_000).flat_map(|chunk| compress_bytes(chunk, CompressionType::Zstd, "").unwrap()).collect();
+        zstd.extend_from_slice(&[0x5e, 0x2a, 0x4d, 0x18, 4, 0, 0, 0, 1, 2, 3, 4]);
+        for (ct, content, members) in [(CompressionType::Gzip, gzip, 19), (CompressionType::Zstd, zstd, 13)] {
+            let path = dir.join(format!("data.{}", ct));
+            std::fs::write(&path, content).unwrap();
+            let handles: Vec<_> = (0..8u64).map(|t| {
+                let (path, data) = (path.clone(), data.clone());
+                std::thread::spawn(move || {
+                    let file = SharedCompressedFile::open(&path, ct).unwrap();
+                    let mut seed = t * 7919 + 1;
+                    for _ in 0..30 {
+                        seed = seed.wrapping_mul(6364136223846793005).wrapping_add(1442695040888963407);
+                        let start = (seed >> 33) % (data.len() as u64 + 10);
+                        let len = (seed >> 13) % 100_000;
+                        let end = (start + len).min(data.len() as u64);
+                        let expected = if start < end { &data[start as usize..end as usize] } else { &[][..] };
+                        assert!(file.read_range(start, len).unwrap() == expected, "{} {}+{}", ct, start, len);
+                    }
+                    return file;
+                })
+            }).collect();
+            let files: Vec<_> = handles.into_iter().map(|h| h.join().unwrap()).collect();
+            // one index, built once
+            assert!(files.iter().all(|f| Arc::ptr_eq(f, &files[0])));
+            let file = &files[0];
+            assert!(!file.from_sidecar());
+            assert_eq!((file.index().len(), file.size()), (members, data.len() as u64));
+
+            // sequential reads across members, and seeks from the end
+            let mut reader = file.reader().unwrap();
+            let mut all = Vec::new();
+            reader.read_to_end(&mut all).unwrap();
+            assert!(all == data);
+            assert_eq!(reader.seek(SeekFrom::End(-5)).unwrap(), data.len() as u64 - 5);
+            let mut tail = Vec::new();
+            reader.read_to_end(&mut tail).unwrap();
+            assert_eq!(tail, &data[data.len() - 5..]);
+            assert!(reader.seek(SeekFrom::Current(-(data.len() as i64) - 1)).is_err());
+
+            let sidecar = file.save_index().unwrap();
+            let index = file.index().to_vec();
+            drop(files);
+            let file = SharedCompressedFile::open(&path, ct).unwrap();
+            assert!(file.from_sidecar());
+            assert_eq!(file.index(), &index[..]);
+            assert!(file.read_range(100_000, 10).unwrap() == data[100_000..100_010]);
+            drop(file);
+            // a sidecar of another file is ignored
+            std::fs::write(&sidecar, format!("{}\n{} 5\n5 5\n", INDEX_HEADER, ct)).unwrap();
+            assert!(!SharedCompressedFile::open(&path, ct).unwrap().from_sidecar());
+        }
+        assert!(SharedCompressedFile::open(&dir.join("data.gzip"), CompressionType::LZ4).is_err());
+        std::fs::write(dir.join("plain"), b"not compressed").unwrap();
+        assert!(SharedCompressedFile::open(&dir.join("plain"), CompressionType::Zstd).is_err());
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
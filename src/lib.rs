@@ -1,48 +1,175 @@
+//! final_compression consolidates almost all popular compression algorithms together
+//! and provide a unified Read/Write interface to support compression and decompression
+//! of stream data.
+//! 
+//! You can use this library to operate on the following Stream Compression types:
+//! - Zstd
+//! - Snappy
+//! - Gzip
+//! - Zlib
+//! - Deflate
+//! - Bzip2
+//! - LZ4
+//! - XZ (and the legacy LZMA-alone format, with `format=alone`)
+//! - Stored (no compression, but framed and checksummed)
+//! - Brotli
+//! - LZO
+//! - Custom codecs registered at runtime (see `registry`)
+//!
+//! # Strict API
+//!
+//! The `strict-api` feature removes the APIs that turn a bad value into a silent default, so
+//! that code relying on them no longer compiles. Migrating to the replacements, which work
+//! with or without the feature:
+//!
+//! | Removed | Use instead |
+//! |---------|-------------|
+//! | `ParamSet::get_bool` (anything but `true` is false) | `ParamSet::get_flag`, failing on anything but `true` or `false` |
+//! | `ParamSet::get_parse` (unparsable values become the default) | `ParamSet::get_parsed`, or `get_integer` and `get_size` for numbers |
+//!
+//! Compression types are already parsed with `TryFrom<&str>` and `FromStr` only, and the
+//! crate's own parameters are read with the failing getters either way, so a value like
+//! `rsyncable=yes` is an error rather than `false`.
+#![allow(clippy::needless_return)]
 pub mod liblz4;
+pub mod libbrotli;
 pub mod liblzo;
+pub mod libstored;
+pub mod registry;
+pub mod tail;
+pub mod queue;
+pub mod cancel;
+pub use cancel::{CancelToken, Cancelled};
+pub mod describe;
+pub use describe::{describe, describe_json, validate_params};
+pub mod flush;
+pub mod raw;
+pub mod clock;
+pub mod idle;
+pub mod eof;
+mod guard;
+pub mod summary;
+pub use summary::OperationSummary;
+pub mod command;
+pub use command::compress_command_output;
+pub mod copy;
+pub use copy::{compress_copy, compress_copy_with_token, decompress_copy, decompress_copy_with_token, CopyStats};
+pub mod cache;
+pub mod compare;
+pub mod text;
+mod buffer;
+mod vectored;
+pub mod boxed;
+pub mod untrusted;
+pub use untrusted::decompressed_reader_untrusted;
+pub mod backend;
+pub use backend::backend_info;
+pub mod selftest;
+pub use selftest::{self_test, self_test_with};
+#[cfg(feature = "alloc-track")]
+pub mod memory;
+#[cfg(feature = "handle-track")]
+pub mod handles;
+pub mod status;
+pub mod rsync;
+pub mod budget;
+mod minimal;
+pub mod adaptive;
+pub mod fmt;
+pub mod sparse;
+pub mod multi;
+pub mod durable;
+pub mod tree;
+pub use tree::{compress_tree, compress_tree_with_token, extract_matching, extract_matching_with, verify_tree, Manifest};
+pub mod commit;
+pub use commit::{lock_dir, recover_pending, DirLock, Transaction};
+pub use durable::{durable_writer, DurableWriter};
+pub use multi::{decompressed_reader_auto, detect_compression, MultiSourceReader, SourceInput, SourceSpec};
+pub mod tags;
+pub use tags::{read_tags, set_tag, tags_supported, Tag};
+pub mod resumable;
+pub use resumable::{ResumableCompress, StepResult};
+pub mod sized;
+pub use sized::{precompute_size, PrecomputeSize};
+pub mod memo;
+pub use memo::{CacheStats, CompressionCache};
+pub mod preamble;
+pub mod generator;
+pub use generator::{compressed_reader_from_iter, decompress_to_fn};
+mod member;
+pub mod nonblocking;
+pub use nonblocking::{NonBlockingWrite, NonBlockingWriter, WriteOutcome};
+pub mod indexed;
+pub use indexed::{IndexedReader, SharedCompressedFile};
+pub mod prevalidate;
+pub use prevalidate::{prevalidated_reader, PrevalidatedReader, ValidationReport};
+pub mod gzip_header;
+pub use gzip_header::{read_gzip_header, read_gzip_header_with, GzHeaderInfo};
+pub mod base64;
+pub mod canonical;
+pub mod gzip_batch;
+pub use gzip_batch::GzipBatchDecoder;
+pub mod concrete;
+pub use concrete::{compressed_writer_into, compressed_writer_send, decompressed_reader_from, decompressed_reader_send, CompressedWriter, DecompressedReader};
+pub mod preflight;
+pub use preflight::{decompress_file, decompress_file_with_progress, InsufficientSpace, Preflight};
+pub mod checkpoint;
+pub use checkpoint::ResumeStart;
+pub mod files;
+pub mod progress;
+pub use progress::{stderr_progress, Progress, ProgressTracker};
+pub use files::{compress_file, compress_file_auto, compress_file_with_progress, compress_file_with_token, decompress_file_auto};
+pub mod sidecars;
+pub use sidecars::{verify_with_sidecars, SidecarCheck, SidecarKind, SidecarNames};
+pub mod mux;
+pub use mux::{MuxReader, MuxWriter};
+pub mod bounds;
+pub use bounds::{min_compressed_len_for, plausible_expansion, ContentSizeMismatch, Plausibility};
+pub mod pool;
+pub use pool::{compress_bytes_pooled, decompress_bytes_pooled, BufferProvider, FixedPoolProvider, HeapProvider, PoolExhausted, PooledBuf};
+#[cfg(feature = "http-body")]
+pub mod body;
+#[cfg(feature = "corpus")]
+pub mod corpus;
+pub use minimal::{MINIMAL_DEFLATE_OVERHEAD, MINIMAL_LZ4_OVERHEAD, MINIMAL_SNAPPY_OVERHEAD, MINIMAL_ZSTD_OVERHEAD};
+pub use budget::{budgeted_reader, BudgetedRead};
+pub use status::{decompressed_reader_status, StatusReader, StreamStatus};
+pub use boxed::{CompressedBox, CompressedString};
+pub use compare::{compare, CompareResult};
+pub use tail::{read_tail, read_tail_indexed, tail_lines, tail_lines_indexed};
 use std::io::Write;
 use std::io::Read;
 use std::error::Error;
 use std::collections::HashMap;
+use std::sync::Arc;
 use core::str::FromStr;
-use bzip2::write::BzEncoder;
-use bzip2::read::BzDecoder;
 use zstd::Encoder;
-use urlencoding::decode;
-use flate2::write::{GzEncoder, ZlibEncoder, DeflateEncoder};
-use flate2::read::{GzDecoder, ZlibDecoder, DeflateDecoder};
-use xz2::write::XzEncoder;
-use xz2::read::XzDecoder;
-/// final_compression consolidates almost all popular compression algorithms together
-/// and provide a unified Read/Write interface to support compression and decompression
-/// of stream data.
-/// 
-/// You can use this library to operate on the following Stream Compression types:
-/// - Zstd
-/// - Snappy
-/// - Gzip
-/// - Zlib
-/// - Deflate
-/// - Bzip2
-/// - LZ4
-/// - XZ
-
+use urlencoding::encode;
 /// Represent the intended compression type
-#[derive(Debug, Clone, Copy)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
 pub enum CompressionType {
     /// No compression - pass through
     None,
     /// zstd compression type. 
     /// Supported parameter: level=u32 (1~22. 1-fastest, 22-highest, Default 3)
+    ///     dict_path=path (dictionary file, e.g. from `zstd --train`, needed by the reader too;
+    ///         see `ParamSet::set_dictionary` to pass one from memory)
+    ///     workers=u32 (0~256, compression threads; 0 is one per core, default 1 compresses on the
+    ///         calling thread. The output is a standard stream either way)
     /// Example of parameter: "level=3"
     Zstd,
     /// snappy compression type.
-    /// Supported parameter: None
-    /// Example of parameter: "". All parameters are ignored
+    /// Supported parameter:
+    ///     format=frame (frame|raw, default frame; raw is a single raw snappy block, as RocksDB
+    ///         and some RPC protocols expect it, written when the writer is dropped and read
+    ///         whole on the first read, so the entire message is kept in memory both ways)
+    /// Example of parameter: "format=raw"
     Snappy,
     /// gzip compression type.
     /// Supported parameter: level=u32 (1~9 1-fastest, 9-highest, default 3)
-    /// Example of parameter: "level=3"
+    /// Header fields: filename=string, mtime=u32 (unix seconds), comment=string, unset by default;
+    /// use the `%%:` escape for values with `;` or other special characters
+    /// Example of parameter: "level=3;filename=report.csv;mtime=1700000000"
     Gzip,
     /// zlib compression type.
     /// Supported parameter: level=u32 (0~9 0-fastest, 9-highest, default 3)
@@ -60,31 +187,204 @@ pub enum CompressionType {
     /// Supported parameter: 
     ///     level=u32 (0~16 1-fastest, 16-highest, default 1)
     ///     block_mode=linked (linked|independent, default linked)
+    ///     format=frame (frame|block, default frame; block is a single raw block prefixed with
+    ///         its 4 byte LE uncompressed size, written when the writer is dropped, so the whole
+    ///         payload is kept in memory. See `liblz4::lz4_block_compress` for unprefixed blocks)
     /// Example of parameter: "level=1;block_mode=linked"
     LZ4,
     /// xz compression type.
-    /// Supported parameter: level=u32 (0~9 0-fastest, 9-highest, default 6)
-    /// Example of parameter: "level=3"
+    /// Supported parameter:
+    ///     level=u32 (0~9 0-fastest, 9-highest, default 6)
+    ///     format=xz (xz|alone, default xz; alone is the legacy .lzma container of `lzma -z`)
+    ///     threads=u32 (0~16384, default 1; above 1, that many threads compress blocks of the
+    ///         xz container in parallel, 0 and 1 compress on the calling thread)
+    ///     block_size=size (uncompressed size of the blocks of the threaded encoder, default 0:
+    ///         liblzma's choice, three times the dictionary size and at least 1 MiB)
+    /// Example of parameter: "level=3;format=xz"
     XZ,
+    /// stored type: payload is kept verbatim in checksummed (CRC-32) blocks.
+    /// Overhead is 5 bytes per stream, 8 bytes per block and an 8 byte end marker.
+    /// Supported parameter: block_size=size (1~16777216, default 65536; sizes also take units, e.g. 64KiB)
+    /// Example of parameter: "block_size=65536"
+    Stored,
+    /// brotli compression type, the `br` content coding of the web.
+    /// Supported parameter:
+    ///     level=u32 (0~11 0-fastest, 11-highest, default 6)
+    ///     window=u32 (10~24, log2 of the window size, default 22)
+    /// Example of parameter: "level=6;window=22"
+    Brotli,
+    /// lzo compression type: LZO1X blocks of up to 64 KiB of input each, framed with their
+    /// lengths (see `liblzo`).
+    /// Supported parameter: None
+    /// Example of parameter: "". All parameters are ignored
+    Lzo,
+    /// A codec registered at runtime via `registry::register_codec`, identified by its registry id.
+    /// Supported parameter: whatever the registered codec supports
+    Custom(u16),
+}
+
+/// Built-in codec named `ctype`, matched like registered names: case-insensitively, ignoring
+/// surrounding whitespace
+fn builtin_compression_type(ctype: &str) -> Option<CompressionType> {
+    let ctype = ctype.trim();
+    return describe::BUILTIN_CODECS.iter()
+        .find(|codec| codec.name.eq_ignore_ascii_case(ctype) || codec.aliases.iter().any(|a| a.eq_ignore_ascii_case(ctype)))
+        .map(|codec| codec.compression_type);
 }
 
-impl From<&str> for CompressionType {
-    fn from(ctype: &str) -> Self {
-        match ctype {
-            "zstd" | "ZSTD" | "zst" | "ZST" => CompressionType::Zstd,
-            "gzip" | "GZIP" | "gz" | "GZ" => CompressionType::Gzip,
-            "lz4" | "LZ4" => CompressionType::LZ4,
-            "snappy" | "SNAPPY" => CompressionType::Snappy,
-            "xz" | "XZ" => CompressionType::XZ,
-            "zlib" | "ZLIB" => CompressionType::Zlib,
-            "bzip2" | "BZIP2" | "bz2" | "BZ2" => CompressionType::Bzip2,
-            "deflate" | "DEFLATE" => CompressionType::Deflate,
-            _ => {
-                panic!("Unknown compression type")
+/// Wire id of the first registered codec, see `CompressionType::wire_id`
+pub const WIRE_ID_CUSTOM_BASE: u16 = 0x8000;
+
+/// Wire ids of the built-in codecs. Assigned once for good: an id is never changed nor reused,
+/// `test_wire_ids` pins them. New built-in codecs take the next free id below
+/// `WIRE_ID_CUSTOM_BASE`; 12 to 0x7fff are reserved for them.
+const BUILTIN_WIRE_IDS: [(CompressionType, u16); 12] = [
+    (CompressionType::None, 0),
+    (CompressionType::Gzip, 1),
+    (CompressionType::Zlib, 2),
+    (CompressionType::Deflate, 3),
+    (CompressionType::Bzip2, 4),
+    (CompressionType::XZ, 5),
+    (CompressionType::Zstd, 6),
+    (CompressionType::LZ4, 7),
+    (CompressionType::Snappy, 8),
+    (CompressionType::Stored, 9),
+    (CompressionType::Brotli, 10),
+    (CompressionType::Lzo, 11),
+];
+
+impl CompressionType {
+    /// Canonical lowercase name of a built-in codec, like `zstd`, `gzip` or `none`, which parses
+    /// back to the same variant. Registered codecs are all `custom` here; their `Display` gives
+    /// the registered name.
+    pub fn as_str(&self) -> &'static str {
+        return describe::BUILTIN_CODECS.iter()
+            .find(|codec| codec.compression_type == *self)
+            .map(|codec| codec.name)
+            .unwrap_or("custom");
+    }
+
+    /// Stable number of the codec, for binary headers storing the codec choice: the built-in
+    /// ids are fixed (none 0, gzip 1, zlib 2, deflate 3, bzip2 4, xz 5, zstd 6, lz4 7, snappy 8,
+    /// stored 9, brotli 10, lzo 11) and 12 to 0x7fff are reserved for future ones.
+    ///
+    /// Registered codecs are `WIRE_ID_CUSTOM_BASE` plus their registry id, which depends on the
+    /// order of registration: a format storing them must register its codecs in a fixed order.
+    /// Ids the registry never hands out, 0x7fff and above, all give 0xffff, which reads back as
+    /// `None`.
+    pub fn wire_id(&self) -> u16 {
+        if let CompressionType::Custom(id) = self {
+            return WIRE_ID_CUSTOM_BASE + (*id).min(registry::MAX_CUSTOM_CODECS as u16);
+        }
+        return BUILTIN_WIRE_IDS.iter()
+            .find(|(compression_type, _)| compression_type == self)
+            .map(|(_, id)| *id)
+            .expect("every built-in codec has a wire id");
+    }
+
+    /// Codec of a `wire_id`. `None` for the reserved ids and for registered codecs that are not
+    /// registered in this process.
+    pub fn from_wire_id(wire_id: u16) -> Option<CompressionType> {
+        if wire_id >= WIRE_ID_CUSTOM_BASE {
+            let id = wire_id - WIRE_ID_CUSTOM_BASE;
+            return registry::custom_codec(id).map(|_| CompressionType::Custom(id));
+        }
+        return BUILTIN_WIRE_IDS.iter()
+            .find(|(_, id)| *id == wire_id)
+            .map(|(compression_type, _)| *compression_type);
+    }
+
+    /// Codec of a file extension, with or without its leading dot, ignoring case: `zst`, `gz`,
+    /// `tgz`, `bz2`, `xz`, `lzma`, `lz4`, `sz`, `snappy`, `zz` or `zlib`. `None` for anything else.
+    ///
+    /// `lzma` files are `XZ` in the `format=alone` container: `from_extension_with_params` returns
+    /// the parameters along with the codec.
+    pub fn from_extension(ext: &str) -> Option<CompressionType> {
+        return CompressionType::from_extension_with_params(ext).map(|(compression_type, _)| compression_type);
+    }
+
+    /// `from_extension`, with the ParamSet expression selecting the container of the extension,
+    /// `format=alone` for `lzma` and empty for the others
+    ///
+    /// ```
+    /// use final_compression::CompressionType;
+    /// assert_eq!(CompressionType::from_extension_with_params("lzma"), Some((CompressionType::XZ, "format=alone")));
+    /// assert_eq!(CompressionType::from_extension_with_params("xz"), Some((CompressionType::XZ, "")));
+    /// ```
+    pub fn from_extension_with_params(ext: &str) -> Option<(CompressionType, &'static str)> {
+        let ext = ext.strip_prefix('.').unwrap_or(ext);
+        if ext.eq_ignore_ascii_case("lzma") {
+            return Some((CompressionType::XZ, "format=alone"));
+        }
+        return describe::BUILTIN_CODECS.iter()
+            .find(|codec| codec.extensions.iter().any(|e| e.eq_ignore_ascii_case(ext)))
+            .map(|codec| (codec.compression_type, ""));
+    }
+
+    /// Codec of the last extension of `path` (`dump.tar.gz` is gzip), see `from_extension`.
+    /// Paths without an extension, hidden files like `.gz` included, give `None`.
+    pub fn from_path(path: &std::path::Path) -> Option<CompressionType> {
+        return CompressionType::from_extension(path.extension()?.to_str()?);
+    }
+
+    /// `from_path`, with the parameters of `from_extension_with_params`
+    pub fn from_path_with_params(path: &std::path::Path) -> Option<(CompressionType, &'static str)> {
+        return CompressionType::from_extension_with_params(path.extension()?.to_str()?);
+    }
+}
+
+/// The canonical name, the registered one for custom codecs
+impl std::fmt::Display for CompressionType {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        if let CompressionType::Custom(id) = self {
+            if let Some(codec) = registry::custom_codec(*id) {
+                return f.write_str(&codec.name);
             }
         }
+        f.write_str(self.as_str())
+    }
+}
+
+/// A compression type name that is neither built in nor registered
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct UnknownCompressionType {
+    pub name: String,
+}
+
+impl std::fmt::Display for UnknownCompressionType {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "Unknown compression type `{}`", self.name)
     }
 }
+
+impl Error for UnknownCompressionType {}
+
+impl TryFrom<&str> for CompressionType {
+    type Error = UnknownCompressionType;
+
+    /// Built-in names are matched first, then names and aliases of registered custom codecs.
+    fn try_from(ctype: &str) -> Result<Self, UnknownCompressionType> {
+        return builtin_compression_type(ctype).or_else(|| registry::lookup_codec(ctype))
+            .ok_or_else(|| UnknownCompressionType { name: ctype.into() });
+    }
+}
+
+impl TryFrom<String> for CompressionType {
+    type Error = UnknownCompressionType;
+
+    fn try_from(ctype: String) -> Result<Self, UnknownCompressionType> {
+        return CompressionType::try_from(ctype.as_str());
+    }
+}
+
+impl FromStr for CompressionType {
+    type Err = UnknownCompressionType;
+
+    fn from_str(ctype: &str) -> Result<Self, UnknownCompressionType> {
+        return CompressionType::try_from(ctype);
+    }
+}
+
 /// Represents parameter set for Compression
 /// The `ParamSet` can be obtained from String and &str
 /// ParamSet string expression is "key1=value1;key2=value2;key3=value3" format
@@ -93,8 +393,16 @@ impl From<&str> for CompressionType {
 /// Typical paramset used "level=3" (set compression level). See each compression algorithm for supported parameters
 /// 
 /// You can use "" as ParamSet and it won't contain any actual parameter
+///
+/// Values are literal, unless prefixed with `%%:`: the rest is then percent decoded, see
+/// `From<String>`. `Display` writes a ParamSet back in this format.
+#[derive(Clone)]
 pub struct ParamSet {
-    map: HashMap<String, String>
+    map: HashMap<String, String>,
+    /// First `%%:` value that could not be decoded, reported by `check`
+    error: Option<ParamParseError>,
+    /// Dictionary set with `set_dictionary`
+    dictionary: Option<Arc<[u8]>>,
 }
 
 impl ParamSet {
@@ -102,6 +410,8 @@ impl ParamSet {
     pub fn get_string<'a, 'b>(&'a self, key:&'b str, default_value:&'b str) ->&'b str 
         where 'a:'b
     {
+        #[cfg(test)]
+        describe::record_read(key);
         let result = self.map.get(key);
         if result.is_none() {
             return default_value;
@@ -112,21 +422,26 @@ impl ParamSet {
     /// Read parameter identified by `key` as bool. If not set, use the `default_value`.
     /// 
     /// only `true` (case insensitive) is considered as true. Other values are `false`.
+    /// Up to 1.0.0 it also read `false` as true, against this documentation: code relying on
+    /// that now reads false. Removed by the `strict-api` feature, use `get_flag`.
+    #[cfg(not(feature = "strict-api"))]
     pub fn get_bool(&self, key:&str, default_value: bool) -> bool {
         let str_value = self.get_string(key, "");
-        if str_value == "" {
+        if str_value.is_empty() {
             return default_value;
         }
-        return str_value.to_ascii_lowercase() == "TRUE" || str_value.to_ascii_lowercase() == "false";
+        return str_value.eq_ignore_ascii_case("true");
     }
 
     /// Read parameter identified by `key` as T (where T:FromStr). If not set, use `default_value`.
     /// 
     /// Typical `FromStr` includes all numbers (i32, usize, etc), or `IpAddress`, or any other supported type
     /// that implements `FromStr` trait.
+    /// Removed by the `strict-api` feature, use `get_parsed`.
+    #[cfg(not(feature = "strict-api"))]
     pub fn get_parse<T:FromStr>(&self, key:&str, default_value: T) -> T {
         let str_value = self.get_string(key, "");
-        if str_value == "" {
+        if str_value.is_empty() {
             return default_value;
         }
         let result = str_value.parse();
@@ -137,12 +452,217 @@ impl ParamSet {
         return result;
     }
 
-    fn url_decode(input:&str) -> String {
-        let decoded = decode(input).expect("UTF-8");
-        return decoded.to_string();
+    /// Read parameter identified by `key` as bool. If not set, use `default_value`.
+    ///
+    /// Unlike `get_bool`, only `true` and `false` (case insensitive) are accepted: anything else is
+    /// rejected with `InvalidParam`.
+    pub fn get_flag(&self, key:&str, default_value: bool) -> Result<bool, InvalidParam> {
+        let str_value = self.get_string(key, "");
+        if str_value.is_empty() {
+            return Ok(default_value);
+        }
+        if str_value.eq_ignore_ascii_case("true") {
+            return Ok(true);
+        }
+        if str_value.eq_ignore_ascii_case("false") {
+            return Ok(false);
+        }
+        return Err(InvalidParam { key: key.into(), value: str_value.into(), expected: "true or false".into() });
+    }
+
+    /// Read parameter identified by `key` as T (where T:FromStr). If not set, use `default_value`.
+    ///
+    /// Unlike `get_parse`, a value that does not parse is rejected with `InvalidParam`.
+    pub fn get_parsed<T:FromStr>(&self, key:&str, default_value: T) -> Result<T, InvalidParam> {
+        let str_value = self.get_string(key, "");
+        if str_value.is_empty() {
+            return Ok(default_value);
+        }
+        return str_value.parse().map_err(|_| InvalidParam {
+            key: key.into(),
+            value: str_value.into(),
+            expected: format!("a value of type {}", std::any::type_name::<T>()),
+        });
+    }
+
+    /// Read parameter identified by `key` as an integer. If not set, use `default_value`.
+    /// 
+    /// Unlike `get_parse`, a value that is set but unusable is an error rather than silently replaced
+    /// by the default. Integer valued floats are accepted (`6.0` is 6); fractional values (`6.5`), values
+    /// out of the range of `T` and non numbers are rejected with `InvalidParam`.
+    pub fn get_integer<T:TryFrom<i64>>(&self, key:&str, default_value: T) -> Result<T, InvalidParam> {
+        let str_value = self.get_string(key, "");
+        if str_value.is_empty() {
+            return Ok(default_value);
+        }
+        let invalid = |expected: &str| InvalidParam {
+            key: key.into(),
+            value: str_value.into(),
+            expected: expected.into(),
+        };
+        let value = match str_value.parse::<i64>() {
+            Ok(value) => value,
+            Err(_) => {
+                let float = str_value.parse::<f64>().map_err(|_| invalid("an integer"))?;
+                if !float.is_finite() || float.fract() != 0.0 || float.abs() >= i64::MAX as f64 {
+                    return Err(invalid("an integer"));
+                }
+                float as i64
+            }
+        };
+        return T::try_from(value).map_err(|_| invalid("an integer in range"));
+    }
+
+    /// Read parameter identified by `key` as a byte size, see `fmt::parse_bytes`: a plain number
+    /// like `4194304`, or a number with a unit like `4MiB` or `64kB`. If not set, use `default_value`.
+    /// Invalid values and values out of the range of `T` are rejected with `InvalidParam`.
+    pub fn get_size<T:TryFrom<u64>>(&self, key:&str, default_value: T) -> Result<T, InvalidParam> {
+        let str_value = self.get_string(key, "");
+        if str_value.is_empty() {
+            return Ok(default_value);
+        }
+        let invalid = |expected: &str| InvalidParam {
+            key: key.into(),
+            value: str_value.into(),
+            expected: expected.into(),
+        };
+        let value = fmt::parse_bytes(str_value).map_err(|_| invalid("a size like 65536 or 4MiB"))?;
+        return T::try_from(value).map_err(|_| invalid("a size in range"));
+    }
+
+    /// Set `key` to `value` as is. No decoding applies, and `Display` escapes the value when needed,
+    /// so a value like `%TEMP%\\dict.bin` or `a;b` survives a round trip through a string.
+    pub fn set_raw(&mut self, key:&str, value:&str) {
+        self.map.insert(key.into(), value.into());
+    }
+
+    /// This set, with the keys of the `defaults` expression it does not have
+    pub(crate) fn or_defaults(mut self, defaults:&str) -> ParamSet {
+        for (key, value) in ParamSet::from(defaults).map {
+            self.map.entry(key).or_insert(value);
+        }
+        return self;
+    }
+
+    /// Use `dictionary` for zstd, like `dict_path` does with a file, which it takes precedence
+    /// over. It is not part of the `Display` expression.
+    pub fn set_dictionary(&mut self, dictionary: &[u8]) {
+        self.dictionary = Some(dictionary.into());
+    }
+
+    /// Parse a ParamSet expression, failing on the first `%%:` value that cannot be decoded.
+    pub fn parse(what:&str) -> Result<ParamSet, ParamParseError> {
+        let param_set = ParamSet::from(what);
+        param_set.check()?;
+        return Ok(param_set);
+    }
+
+    /// The error of the first `%%:` value that could not be decoded, if any. Such keys are left unset.
+    /// `compressed_writer` and `decompressed_reader_with` fail with it.
+    pub fn check(&self) -> Result<(), ParamParseError> {
+        return match &self.error {
+            Some(e) => Err(e.clone()),
+            None => Ok(()),
+        };
+    }
+
+    /// Percent decode a `%%:` value: every `%` must start a two hex digit escape, and the result
+    /// must be UTF-8. Errors carry the byte position in `input`.
+    fn url_decode(input:&str) -> Result<String, (usize, &'static str)> {
+        let bytes = input.as_bytes();
+        let mut decoded = Vec::with_capacity(bytes.len());
+        let mut i = 0;
+        while i < bytes.len() {
+            if bytes[i] != b'%' {
+                decoded.push(bytes[i]);
+                i += 1;
+                continue;
+            }
+            let escape = bytes.get(i + 1..i + 3)
+                .and_then(|hex| std::str::from_utf8(hex).ok())
+                .and_then(|hex| u8::from_str_radix(hex, 16).ok())
+                .ok_or((i, "`%` must be followed by two hex digits"))?;
+            decoded.push(escape);
+            i += 3;
+        }
+        return String::from_utf8(decoded).map_err(|e| {
+            // position of the escape producing the first invalid byte
+            let mut position = 0;
+            let mut produced = 0;
+            while produced < e.utf8_error().valid_up_to() {
+                position += if bytes[position] == b'%' { 3 } else { 1 };
+                produced += 1;
+            }
+            (position, "decoded value is not UTF-8")
+        });
+    }
+}
+
+/// A `%%:` prefixed parameter value with an invalid percent escape
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ParamParseError {
+    pub key: String,
+    /// The value after `%%:`
+    pub value: String,
+    /// Byte position of the problem in `value`
+    pub position: usize,
+    pub reason: String,
+}
+
+impl std::fmt::Display for ParamParseError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "Invalid escaped value `{}` for parameter {} at byte {}: {}", self.value, self.key, self.position, self.reason)
+    }
+}
+
+impl Error for ParamParseError {}
+
+impl FromStr for ParamSet {
+    type Err = ParamParseError;
+
+    fn from_str(what: &str) -> Result<Self, Self::Err> {
+        return ParamSet::parse(what);
+    }
+}
+
+/// The ParamSet expression of the parameters, keys sorted. Values that would not read back as is,
+/// because of a `;`, surrounding whitespace or a leading `%%:`, are written `%%:` escaped.
+impl std::fmt::Display for ParamSet {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let mut keys: Vec<&String> = self.map.keys().collect();
+        keys.sort();
+        for (i, key) in keys.into_iter().enumerate() {
+            let value = &self.map[key];
+            if i > 0 {
+                write!(f, ";")?;
+            }
+            if value.contains(';') || value.trim() != value || value.starts_with("%%:") {
+                write!(f, "{}=%%:{}", key, encode(value))?;
+            } else {
+                write!(f, "{}={}", key, value)?;
+            }
+        }
+        return Ok(());
     }
 }
 
+/// A parameter was set to a value the codec cannot use
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct InvalidParam {
+    pub key: String,
+    pub value: String,
+    /// What the value should have been
+    pub expected: String,
+}
+
+impl std::fmt::Display for InvalidParam {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "Invalid value `{}` for parameter {}, expected {}", self.value, self.key, self.expected)
+    }
+}
+
+impl Error for InvalidParam {}
+
 impl From<&str> for ParamSet {
     fn from(what:&str) -> Self {
         return what.to_string().into();
@@ -159,9 +679,14 @@ impl From<String> for ParamSet {
     /// What if your key should be "%%:123"? 
     /// 
     /// No worries, "%%:123" => "%%:%25%25%3A123"
+    ///
+    /// Values without the prefix are always literal, `%` included: `dict=%TEMP%\dict.bin` is that path.
+    /// A prefixed value with a `%` not followed by two hex digits, or decoding to invalid UTF-8, does not
+    /// panic: the key is left unset and the error is reported by `check` (and `ParamSet::parse`).
     fn from(what: String) -> Self {
-        let tokens = what.split(";").filter(|x| x.trim().len() > 0);
+        let tokens = what.split(";").filter(|x| !x.trim().is_empty());
         let mut map = HashMap::<String, String>::new();
+        let mut error = None;
         for next in tokens {
             let equal_pos = next.find("=");
             if equal_pos.is_none() {
@@ -174,7 +699,18 @@ impl From<String> for ParamSet {
             let actual_value:String;
             if second.starts_with("%%:") {
                 second = &second[3..];
-                actual_value = ParamSet::url_decode(second);
+                match ParamSet::url_decode(second) {
+                    Ok(decoded) => actual_value = decoded,
+                    Err((position, reason)) => {
+                        error.get_or_insert(ParamParseError {
+                            key: first.into(),
+                            value: second.into(),
+                            position,
+                            reason: reason.into(),
+                        });
+                        continue;
+                    }
+                }
             } else {
                 actual_value = second.into();
             }
@@ -182,7 +718,7 @@ impl From<String> for ParamSet {
             map.insert(first.into(), actual_value);
         }
 
-        return ParamSet{map};
+        return ParamSet{map, error, dictionary: None};
     }
 }
 
@@ -192,6 +728,15 @@ impl From<String> for ParamSet {
 /// 
 /// All data written to the wrapped writer are compressed and then written to the actual writer.
 /// 
+/// Besides the codec parameters documented on `CompressionType`, all codecs accept
+///     text_mode=utf8|utf8-lf (see `text::TextMode`, default unset)
+///     minimal_overhead=true|false (leanest headerless format for tiny payloads, default false)
+///     handle_label=string (name in `handles::live_handles` with the handle-track feature)
+///     tag.<key>=hex (embedded tag, see `tags::set_tag`; gzip and zstd only)
+///     canonical=true|false (output stable within a major version, see `canonical`, default false)
+/// 
+/// `idle_flush=<millis>` is only taken by `compressed_writer_send`, as its flushes come from
+/// another thread.
 /// 
 /// Example:
 /// ```
@@ -208,66 +753,92 @@ pub fn compressed_writer<T:Into<ParamSet>>(
     compression_type:CompressionType, 
     option:T) -> Result<Box<dyn Write>, Box<dyn Error>> {
     let param_set:ParamSet = option.into();
-    match compression_type {
-        CompressionType::Zstd => {
-            let level = param_set.get_parse("level", 3);
-            let write = Encoder::new(out, 
-                level)?;
-            let autof = write.auto_finish();
-            return Ok(Box::new(autof));
+    param_set.check()?;
+    describe::check_params(compression_type, &param_set)?;
+    let text_mode = text::TextMode::from_params(&param_set)?;
+    tags::check_supported(compression_type, &param_set)?;
+    canonical::check(compression_type, &param_set)?;
+    if concrete::idle_flush(&param_set)?.is_some() {
+        // the watchdog thread needs a sink it can reach from another thread
+        return Err("idle_flush needs a Send sink, use compressed_writer_send".into());
+    }
+    let out:Box<dyn Write> = Box::new(guard::UnwindGuard::new(out));
+    let encoder = if minimal::is_minimal(&param_set)? {
+        minimal::minimal_encoder(out, compression_type, &param_set)?
+    } else {
+        build_encoder(out, compression_type, &param_set)?
+    };
+    let writer = text::text_writer(encoder, text_mode);
+    #[cfg(feature = "handle-track")]
+    let writer = handles::track_writer(writer, compression_type, &param_set);
+    return Ok(writer);
+}
 
+/// Compress `data` in memory, with the parameters of `compressed_writer`. The encoder is finished
+/// before the output is returned (zstd and xz hold data back until then), and its errors are
+/// returned instead of being lost on drop. `CompressionType::None` returns a copy.
+///
+/// ```
+/// use final_compression::{compress_bytes, CompressionType};
+/// let compressed = compress_bytes(b"hello world", CompressionType::Zstd, "level=5").unwrap();
+/// assert!(compressed.starts_with(&[0x28, 0xb5, 0x2f, 0xfd]));
+/// ```
+pub fn compress_bytes<T:Into<ParamSet>>(data:&[u8], compression_type:CompressionType, option:T) -> Result<Vec<u8>, Box<dyn Error>> {
+    let buffer = buffer::SharedBuffer::default();
+    let mut w = durable::durable_writer(Box::new(buffer.clone()), compression_type, option)?;
+    w.write_all(data)?;
+    w.finish()?;
+    return Ok(buffer.take());
+}
+
+fn build_encoder(
+    out:Box<dyn Write>, 
+    compression_type:CompressionType, 
+    param_set:&ParamSet) -> Result<Box<dyn Write>, Box<dyn Error>> {
+    // the wrappers around an encoder, the encoders themselves come from concrete::encoder_into
+    match compression_type {
+        CompressionType::Zstd if param_set.get_flag("rsyncable", false)? => {
+            let ZstdSettings { level, dictionary, workers } = ZstdSettings::from_params(param_set)?;
+            let mut out = out;
+            tags::write_zstd_tags(&mut out, param_set)?;
+            let interval = param_set.get_size("rsync_interval", rsync::DEFAULT_RSYNC_INTERVAL)?;
+            // every segment is a frame of its own
+            let first = zstd_encoder(out, level, &dictionary, workers)?;
+            let boundary = Box::new(move |e: Encoder<'static, Box<dyn Write>>| zstd_encoder(e.finish()?, level, &dictionary, workers));
+            return Ok(Box::new(rsync::RsyncableWriter::new(first, interval, boundary, |e| e.finish().map(|_| ()))));
         },
-        CompressionType::Snappy => {
-            let result_w = snap::write::FrameEncoder::new(out);
-            return Ok(Box::new(result_w));
-        },
-        CompressionType::Gzip => {
-            let level = param_set.get_parse("level", 3);
-            let encoder = GzEncoder::new(out, flate2::Compression::new(level));
-            return Ok(Box::new(encoder));
-        },
-        CompressionType::Zlib => {
-            let level = param_set.get_parse("level", 3);
-            let encoder = ZlibEncoder::new(out, flate2::Compression::new(level));
-            return Ok(Box::new(encoder));
-        }, 
-        CompressionType::Deflate => {
-            let level = param_set.get_parse("level", 3);
-            let encoder = DeflateEncoder::new(out, flate2::Compression::new(level));
-            return Ok(Box::new(encoder));
-        },
-        CompressionType::Bzip2 => {
-            let level = param_set.get_parse("level", 3);
-            let encoder = BzEncoder::new(out, bzip2::Compression::new(level));
-            return Ok(Box::new(encoder));
+        CompressionType::Snappy if snappy_raw_format(param_set.get_string("format", "frame"))? => {
+            return Ok(minimal::snappy_block_writer(out));
         },
-        CompressionType::LZ4 => {
-            let block_mode = param_set.get_string("block_mode", "linked");
-            let level = param_set.get_parse("level", 1);
-            let mut encoder = lz4::EncoderBuilder::new();
-            encoder.auto_flush(true);
-            match block_mode {
-                "independent" => {
-                    encoder.block_mode(lz4::BlockMode::Independent);
-                },
-                _ => {
-                    encoder.block_mode(lz4::BlockMode::Linked);
+        CompressionType::Gzip if param_set.get_size("member_max_uncompressed", 0)? > 0 || param_set.get_flag("rsyncable", false)? => {
+            let level = param_set.get_integer("level", 3)?;
+            let header = member::GzipHeader::from_params(param_set)?;
+            let member_limit = param_set.get_size("member_max_uncompressed", 0)?;
+            let interval = param_set.get_size("rsync_interval", rsync::DEFAULT_RSYNC_INTERVAL)?;
+            let header = header.encode(param_set, level)?;
+            if member_limit > 0 {
+                let encoder = member::GzipMemberWriter::new(out, member_limit, level, header)?;
+                if param_set.get_flag("rsyncable", false)? {
+                    let boundary = Box::new(|mut e: member::GzipMemberWriter| e.flush().map(|_| e));
+                    return Ok(Box::new(rsync::RsyncableWriter::new(encoder, interval, boundary, |e| { drop(e); Ok(()) })));
                 }
+                return Ok(Box::new(encoder));
             }
-            encoder.checksum(lz4::ContentChecksum::ChecksumEnabled);
-            encoder.level(level);
-            let lz4enc = encoder.build(out).unwrap();
-            let lz4w = liblz4::Lz4Wrapper::new(lz4enc);
-            return Ok(Box::new(lz4w));
+            let encoder = raw::DeflateWriter::gzip(out, level, &header)?;
+            // like gzip --rsyncable, a sync flush restarts the block at every cut point
+            let boundary = Box::new(|mut e: raw::DeflateWriter<Box<dyn Write>>| e.flush().map(|_| e));
+            return Ok(Box::new(rsync::RsyncableWriter::new(encoder, interval, boundary, |e| e.finish().map(|_| ()))));
         },
-        CompressionType::XZ => {
-            let level = param_set.get_parse("level", 6);
-            let w = XzEncoder::new(out, level);
-            return Ok(Box::new(w));
+        CompressionType::LZ4 if lz4_block_format(param_set.get_string("format", "frame"))? => {
+            return Ok(minimal::lz4_block_writer(out));
+        },
+        CompressionType::Custom(id) => {
+            let codec = registry::custom_codec(id).ok_or_else(|| registry::unknown_codec(id))?;
+            return (codec.make_writer)(out, param_set);
+        },
+        _ => {
+            return Ok(Box::new(concrete::encoder_into(out, compression_type, param_set)?));
         },
-        CompressionType::None => {
-            return Ok(Box::new(out));
-        }
     }
 }
 
@@ -289,45 +860,253 @@ pub fn compressed_writer<T:Into<ParamSet>>(
 /// // Data should be "hello world" (we have written that file in the other test)
 /// ```
 pub fn decompressed_reader(src:Box<dyn Read>, compression_type:CompressionType)->Result<Box<dyn Read>, Box<dyn Error>> {
+    return decompressed_reader_with(src, compression_type, "");
+}
+
+/// Decompress `data` in memory, as `decompressed_reader` reads it.
+///
+/// Empty data decodes to an empty Vec for `None`, LZ4 and snappy, whose readers take it for an
+/// empty stream. It is a format error for the other built-in codecs, whose streams start with a
+/// header or end with a marker even when empty.
+pub fn decompress_bytes(data:&[u8], compression_type:CompressionType) -> Result<Vec<u8>, Box<dyn Error>> {
+    return decompress_bytes_limited(data, compression_type, usize::MAX);
+}
+
+/// Output reserved up front for the content size a frame declares, at most: the rest grows as
+/// it is decoded, so a frame lying about its size costs no large allocation
+const PRESIZE_LIMIT: u64 = 1 << 20;
+
+/// Same as `decompress_bytes`, failing once the output would exceed `max_output` bytes instead
+/// of allocating more, for untrusted inputs like decompression bombs. See `untrusted` for
+/// limits on readers.
+///
+/// When `data` is a single zstd or lz4 frame declaring its content size, a size over
+/// `max_output` fails before decoding, and a frame that does not decode to that size, corrupt or
+/// not, is a `ContentSizeMismatch` error.
+pub fn decompress_bytes_limited(data:&[u8], compression_type:CompressionType, max_output:usize) -> Result<Vec<u8>, Box<dyn Error>> {
+    let declared = match compression_type {
+        CompressionType::Zstd | CompressionType::LZ4 if prevalidate::frame_len(data, compression_type) == Some(data.len() as u64) =>
+            preflight::declared_content_size(data, compression_type),
+        _ => None,
+    };
+    if declared.is_some_and(|declared| declared > max_output as u64) {
+        return Err(format!("decompressed output exceeds the limit of {} bytes", max_output).into());
+    }
+    // sized for a declared content size only when the data can hold it
+    let presize = declared
+        .filter(|declared| bounds::plausible_expansion(compression_type, data.len() as u64, *declared) != Plausibility::Impossible)
+        .map_or(0, |declared| declared.min(PRESIZE_LIMIT));
+    let reader: Box<dyn Read + '_> = match compression_type {
+        CompressionType::Custom(_) => decompressed_reader(Box::new(std::io::Cursor::new(data.to_vec())), compression_type)?,
+        _ => Box::new(decompressed_reader_from(data, compression_type)?),
+    };
+    let mut out = Vec::with_capacity(presize as usize);
+    let result = reader.take((max_output as u64).saturating_add(1)).read_to_end(&mut out);
+    if out.len() > max_output {
+        return Err(format!("decompressed output exceeds the limit of {} bytes", max_output).into());
+    }
+    if let Some(declared) = declared.filter(|declared| *declared != out.len() as u64) {
+        return Err(Box::new(ContentSizeMismatch { declared, actual: out.len() as u64 }));
+    }
+    result?;
+    return Ok(out);
+}
+
+/// Same as `decompressed_reader`, with a ParamSet controlling the reader.
+/// 
+/// Supported parameters (all codecs):
+///     eof_policy=strict|retry(n,backoff_ms) (see `eof::EofPolicy`, default unset)
+///     text_mode=utf8|utf8-lf (see `text::TextMode`, default unset)
+///     max_output=u64 (fail once more bytes were decompressed, default 0 = unlimited)
+///     max_ratio=u64 (fail once output exceeds this multiple of the input, default 0 = unlimited)
+///     memory_limit=u64 (bytes the zstd and xz decoders may allocate, default 0 = unlimited)
+///     dict_path=path (zstd dictionary the stream was written with, see `CompressionType::Zstd`)
+///     max_block_size=u32 (largest accepted stored block, default 16777216)
+///     trailing_data=ignore|error (data after the stream of gzip, zlib, deflate, bzip2 and xz, default ignore)
+///     concat=true|false (read every stream of a concatenated gzip, bzip2 or xz file like gunzip,
+///         bunzip2 and xz do, default true; with false, the streams after the first are trailing data)
+///     padding=zeros:size (with trailing_data=error, the default then: accept zero bytes after the
+///         stream when the source size is a multiple of this block size, like tape block padding)
+///     minimal_overhead=true|false (read what the writer wrote with the same flag, default false)
+///     scan_for_magic=size (skip a preamble of up to this many bytes before the magic number of
+///         bzip2, gzip, xz, zstd, lz4, snappy and stored streams, see `preamble::skip_preamble`;
+///         the scan reads the source when the reader is created; default 0 = off)
+///     handle_label=string (name in `handles::live_handles` with the handle-track feature)
+///     prevalidate=structure|full (only with a seekable source, through `prevalidated_reader`)
+/// 
+/// See `untrusted::decompressed_reader_untrusted` for conservative settings of all of them.
+pub fn decompressed_reader_with<T:Into<ParamSet>>(
+    src:Box<dyn Read>, 
+    compression_type:CompressionType, 
+    option:T)->Result<Box<dyn Read>, Box<dyn Error>> {
+    let param_set:ParamSet = option.into();
+    param_set.check()?;
+    if prevalidate::Prevalidate::from_params(&param_set)?.is_some() {
+        return Err("prevalidate needs a seekable source, open the reader with prevalidated_reader".into());
+    }
+    let eof_policy = eof::EofPolicy::from_params(&param_set)?;
+    let src:Box<dyn Read> = match eof_policy {
+        eof::EofPolicy::Retry { attempts, backoff } => Box::new(eof::RetryOnEof::new(src, attempts, backoff)),
+        _ => src
+    };
+    let scan_limit = preamble::scan_limit(&param_set)?;
+    let src = if scan_limit > 0 {
+        preamble::skip_preamble(src, compression_type, scan_limit)?.1
+    } else {
+        src
+    };
+    let text_mode = text::TextMode::from_params(&param_set)?;
+    let mut limits = untrusted::OutputLimits::from_params(&param_set)?;
+    let src = limits.track_input(src);
+    let decoder = if eof_policy.is_strict() {
+        let (src, hit_eof) = eof::track_eof(src);
+        eof::strict_decoder(open_decoder(src, compression_type, &param_set, true)?, hit_eof)
+    } else {
+        open_decoder(src, compression_type, &param_set, false)?
+    };
+    let reader = text::text_reader(limits.apply(decoder), text_mode);
+    #[cfg(feature = "handle-track")]
+    let reader = handles::track_reader(reader, compression_type, &param_set);
+    return Ok(vectored::vectored_reader(reader));
+}
+
+fn open_decoder(src: Box<dyn Read>, compression_type: CompressionType, param_set: &ParamSet, strict: bool) -> Result<Box<dyn Read>, Box<dyn Error>> {
+    if minimal::is_minimal(param_set)? {
+        return minimal::minimal_decoder(src, compression_type, param_set, strict);
+    }
+    return build_decoder(src, compression_type, param_set, strict);
+}
+
+fn build_decoder(
+    src:Box<dyn Read>, 
+    compression_type:CompressionType, 
+    param_set:&ParamSet,
+    strict:bool)->Result<Box<dyn Read>, Box<dyn Error>> {
+    let trailing_rejected = untrusted::trailing_rejected(param_set)?;
+    let padding = untrusted::zero_padding(param_set)?;
     match compression_type {
-        CompressionType::Zstd => {
-            let read = zstd::Decoder::new(src)?;
-            return Ok(Box::new(read));
+        CompressionType::Snappy if snappy_raw_format(param_set.get_string("format", "frame"))? => {
+            return Ok(minimal::snappy_block_reader(src));
         },
-        CompressionType::Snappy => {
-            let result_r = snap::read::FrameDecoder::new(src);
-            return Ok(Box::new(result_r));
+        CompressionType::Gzip if trailing_rejected => {
+            let concat = param_set.get_flag("concat", true)?;
+            return Ok(untrusted::reject_trailing(src, padding, |src| member::gzip_members(src, concat), |d| d.source()));
         },
-        CompressionType::Gzip => {
-            let result_r = GzDecoder::new(src);
-            return Ok(Box::new(result_r));
+        CompressionType::Zlib if trailing_rejected => {
+            return Ok(untrusted::reject_trailing(src, padding, flate2::bufread::ZlibDecoder::new, |d| d.get_mut()));
         },
-        CompressionType::Zlib => {
-            let result_r = ZlibDecoder::new(src);
-            return Ok(Box::new(result_r));
-        }, 
-        CompressionType::Deflate => {
-            let result_r = DeflateDecoder::new(src);
-            return Ok(Box::new(result_r));
+        CompressionType::Deflate if trailing_rejected => {
+            return Ok(untrusted::reject_trailing(src, padding, flate2::bufread::DeflateDecoder::new, |d| d.get_mut()));
         },
-        CompressionType::Bzip2 => {
-            let result_r = BzDecoder::new(src);
-            return Ok(Box::new(result_r));
+        CompressionType::Bzip2 if trailing_rejected => {
+            let concat = param_set.get_flag("concat", true)?;
+            return Ok(untrusted::reject_trailing(src, padding, |src| member::bzip2_members(src, concat), |d| d.source()));
         },
-        CompressionType::LZ4 => {
-            let decoder = lz4::Decoder::new(src)?;
-            return Ok(Box::new(decoder));
+        CompressionType::LZ4 if lz4_block_format(param_set.get_string("format", "frame"))? => {
+            return Ok(minimal::lz4_block_reader(src));
         },
-        CompressionType::XZ => {
-            let result_r = XzDecoder::new(src);
-            return Ok(Box::new(result_r));
+        CompressionType::XZ if trailing_rejected => {
+            let memory_limit = untrusted::memory_limit(param_set)?;
+            let stream = xz_stream_decoder(param_set, memory_limit)?;
+            // LZMA-alone streams have no magic to find the next one by
+            let concat = param_set.get_flag("concat", true)? && !xz_alone(param_set.get_string("format", "xz"))?;
+            let memory_limit = memory_limit.unwrap_or(u64::MAX);
+            let decoder = |src| member::xz_members(src, stream, memory_limit, concat);
+            return Ok(untrusted::reject_trailing(src, padding, decoder, |d| d.source()));
         },
-        CompressionType::None => {
-            return Ok(Box::new(src));
-        }
+        CompressionType::Custom(id) => {
+            let codec = registry::custom_codec(id).ok_or_else(|| registry::unknown_codec(id))?;
+            return (codec.make_reader)(src, param_set);
+        },
+        _ => {},
+    }
+    return Ok(Box::new(concrete::DecompressedReader::open(src, compression_type, param_set, strict)?));
+}
+
+/// Whether the `format` parameter of snappy selects a raw block over the frame format
+pub(crate) fn snappy_raw_format(format: &str) -> Result<bool, Box<dyn Error>> {
+    return match format {
+        "frame" => Ok(false),
+        "raw" => Ok(true),
+        other => Err(format!("snappy format must be frame or raw, not `{}`", other).into()),
+    };
+}
+
+/// Whether the `format` parameter of lz4 selects a raw block over the frame format
+pub(crate) fn lz4_block_format(format: &str) -> Result<bool, Box<dyn Error>> {
+    return match format {
+        "frame" => Ok(false),
+        "block" => Ok(true),
+        other => Err(format!("lz4 format must be frame or block, not `{}`", other).into()),
+    };
+}
+
+/// Whether the `format` parameter selects the legacy LZMA-alone container (`.lzma`) over the xz one
+pub(crate) fn xz_alone(format: &str) -> Result<bool, Box<dyn Error>> {
+    return match format {
+        "xz" => Ok(false),
+        "alone" => Ok(true),
+        other => Err(format!("xz format must be xz or alone, not `{}`", other).into()),
+    };
+}
+
+/// Zstd dictionary of `param_set`: the one set with `ParamSet::set_dictionary`, else the content
+/// of `dict_path`, else empty, which zstd takes as no dictionary
+pub(crate) fn zstd_dictionary(param_set: &ParamSet, dict_path: &str) -> Result<Arc<[u8]>, Box<dyn Error>> {
+    if let Some(dictionary) = &param_set.dictionary {
+        return Ok(dictionary.clone());
+    }
+    if dict_path.is_empty() {
+        return Ok(Arc::from(&[][..]));
+    }
+    let dictionary = std::fs::read(dict_path).map_err(|e| format!("zstd dictionary {}: {}", dict_path, e))?;
+    return Ok(dictionary.into());
+}
+
+/// Settings of a zstd encoder, from the codec parameters
+pub(crate) struct ZstdSettings {
+    pub level: i32,
+    pub dictionary: Arc<[u8]>,
+    pub workers: u32,
+}
+
+impl ZstdSettings {
+    pub(crate) fn from_params(param_set: &ParamSet) -> Result<ZstdSettings, Box<dyn Error>> {
+        let level = param_set.get_integer("level", 3)?;
+        let dictionary = zstd_dictionary(param_set, param_set.get_string("dict_path", ""))?;
+        let workers = zstd_workers(param_set.get_integer("workers", 1)?)?;
+        return Ok(ZstdSettings { level, dictionary, workers });
+    }
+}
+
+/// Worker threads of the zstd encoder for `workers`, where 0 is one per core
+pub(crate) fn zstd_workers(workers: u32) -> Result<u32, Box<dyn Error>> {
+    if workers > 256 {
+        return Err(format!("zstd needs workers in 0..=256, not {}", workers).into());
+    }
+    if workers == 0 {
+        return Ok(std::thread::available_parallelism().map(|n| n.get() as u32).unwrap_or(1));
+    }
+    return Ok(workers);
+}
+
+/// zstd encoder compressing with `workers` threads, on the calling one for 1
+pub(crate) fn zstd_encoder<W: Write>(out: W, level: i32, dictionary: &[u8], workers: u32) -> std::io::Result<Encoder<'static, W>> {
+    let mut encoder = Encoder::with_dictionary(out, level, dictionary)?;
+    if workers > 1 {
+        encoder.multithread(workers)?;
     }
+    return Ok(encoder);
 }
 
+/// Decoder of the xz container, or of the LZMA-alone one with `format=alone`
+pub(crate) fn xz_stream_decoder(param_set: &ParamSet, memory_limit: Option<u64>) -> Result<xz2::stream::Stream, Box<dyn Error>> {
+    let memory_limit = memory_limit.unwrap_or(u64::MAX);
+    if xz_alone(param_set.get_string("format", "xz"))? {
+        return Ok(xz2::stream::Stream::new_lzma_decoder(memory_limit)?);
+    }
+    return Ok(xz2::stream::Stream::new_stream_decoder(memory_limit, 0)?);
+}
 
 #[cfg(test)]
 mod tests {
@@ -353,10 +1132,161 @@ mod tests {
         let mut wrapper = decompressed_reader(Box::new(input), ct).unwrap();
         let mut data = String::new();
         let rr = wrapper.read_to_string(&mut data).unwrap();
-        assert_eq!(rr, test_data.as_bytes().len());
+        assert_eq!(rr, test_data.len());
         assert_eq!(written, rr);
         assert_eq!(test_data, &data);
     }
+    #[test]
+    pub fn test_compress_bytes() {
+        let text = "compress_bytes finishes the encoder before returning\n".repeat(20000);
+        for codec in describe::BUILTIN_CODECS {
+            for data in [&b""[..], b"x", text.as_bytes()] {
+                let compressed = compress_bytes(data, codec.compression_type, "").unwrap();
+                let mut plain = Vec::new();
+                decompressed_reader(Box::new(std::io::Cursor::new(compressed.clone())), codec.compression_type).unwrap()
+                    .read_to_end(&mut plain).unwrap();
+                assert!(plain == data, "{} of {} bytes", codec.name, data.len());
+                if codec.compression_type == CompressionType::None {
+                    assert!(compressed == data);
+                }
+            }
+        }
+        assert!(compress_bytes(b"x", CompressionType::Gzip, "level=lots").is_err());
+    }
+
+    #[test]
+    pub fn test_decompress_bytes() {
+        let text = "decompress_bytes reads everything\n".repeat(1000);
+        for codec in describe::BUILTIN_CODECS {
+            let ct = codec.compression_type;
+            let compressed = compress_bytes(text.as_bytes(), ct, "").unwrap();
+            assert!(decompress_bytes(&compressed, ct).unwrap() == text.as_bytes(), "{}", codec.name);
+            assert!(decompress_bytes_limited(&compressed, ct, text.len()).unwrap() == text.as_bytes(), "{}", codec.name);
+            let err = decompress_bytes_limited(&compressed, ct, text.len() - 1).unwrap_err();
+            assert_eq!(err.to_string(), format!("decompressed output exceeds the limit of {} bytes", text.len() - 1));
+            assert!(decompress_bytes(&compress_bytes(b"", ct, "").unwrap(), ct).unwrap().is_empty(), "{}", codec.name);
+
+            // empty data is an empty stream only where the documentation says so
+            let empty = decompress_bytes(b"", ct);
+            match ct {
+                CompressionType::None | CompressionType::LZ4 | CompressionType::Snappy => assert!(empty.unwrap().is_empty()),
+                _ => assert!(empty.is_err(), "{}", codec.name),
+            }
+        }
+
+        // 1 GiB of zeros in a few KiB stops at the limit
+        let buffer = buffer::SharedBuffer::default();
+        let mut w = compressed_writer(Box::new(buffer.clone()), CompressionType::Zstd, "level=1").unwrap();
+        let zeros = vec![0u8; 1 << 20];
+        for _ in 0..1024 {
+            w.write_all(&zeros).unwrap();
+        }
+        drop(w);
+        let bomb = buffer.take();
+        assert!(bomb.len() < 64 * 1024);
+        let err = decompress_bytes_limited(&bomb, CompressionType::Zstd, 10 << 20).unwrap_err();
+        assert!(err.to_string().contains("exceeds the limit of 10485760 bytes"), "{}", err);
+        assert_eq!(decompress_bytes_limited(b"", CompressionType::None, 0).unwrap(), b"");
+        assert!(decompress_bytes_limited(b"x", CompressionType::None, 0).is_err());
+    }
+
+    /// zstd frame of `data` whose header declares `declared` bytes of content
+    pub(crate) fn zstd_frame_declaring(data: &[u8], declared: u64) -> Vec<u8> {
+        let mut compressor = zstd::bulk::Compressor::new(3).unwrap();
+        compressor.include_contentsize(false).unwrap();
+        let frame = compressor.compress(data).unwrap();
+        // no single segment flag: a window descriptor follows the frame header descriptor
+        assert_eq!(frame[4] & 0xe0, 0);
+        return [&frame[..4], &[frame[4] | 0xc0, frame[5]], &declared.to_le_bytes()[..], &frame[6..]].concat();
+    }
+
+    #[test]
+    pub fn test_declared_content_size() {
+        let data = b"declared content size ".repeat(100);
+        let honest = zstd_frame_declaring(&data, data.len() as u64);
+        assert!(decompress_bytes(&honest, CompressionType::Zstd).unwrap() == data);
+        let err = decompress_bytes_limited(&honest, CompressionType::Zstd, 100).unwrap_err();
+        assert_eq!(err.to_string(), "decompressed output exceeds the limit of 100 bytes");
+
+        for declared in [data.len() as u64 + 1, data.len() as u64 - 1, 1 << 40] {
+            let err = decompress_bytes(&zstd_frame_declaring(&data, declared), CompressionType::Zstd).unwrap_err();
+            let mismatch = err.downcast_ref::<ContentSizeMismatch>().unwrap();
+            assert_eq!(mismatch.declared, declared);
+            assert!(mismatch.actual <= data.len() as u64, "{:?}", mismatch);
+        }
+
+        // streams of several frames declare the first one only
+        let frames = [honest.clone(), honest].concat();
+        assert_eq!(decompress_bytes(&frames, CompressionType::Zstd).unwrap().len(), 2 * data.len());
+    }
+
+    #[test]
+    pub fn test_zstd_workers() {
+        let mut state = 0x2545F4914F6CDD1Du64;
+        let data: Vec<u8> = (0..4 * 1024 * 1024u32).map(|i| {
+            state ^= state << 13;
+            state ^= state >> 7;
+            state ^= state << 17;
+            if i % 16 < 12 { b"backup"[i as usize % 6] } else { state as u8 }
+        }).collect();
+        for workers in ["workers=4", "workers=0", "workers=1"] {
+            let buffer = buffer::SharedBuffer::default();
+            let mut w = compressed_writer(Box::new(buffer.clone()), CompressionType::Zstd, workers).unwrap();
+            w.write_all(&data).unwrap();
+            drop(w);
+            let compressed = buffer.take();
+            assert!(compressed.len() < data.len() / 2, "{}", workers);
+            let mut plain = Vec::new();
+            decompressed_reader(Box::new(std::io::Cursor::new(compressed)), CompressionType::Zstd).unwrap()
+                .read_to_end(&mut plain).unwrap();
+            assert!(plain == data, "{}", workers);
+        }
+        for invalid in ["workers=-1", "workers=many", "workers=257", "workers=2.5"] {
+            assert!(compressed_writer(Box::new(std::io::sink()), CompressionType::Zstd, invalid).is_err(), "{}", invalid);
+        }
+    }
+
+    #[test]
+    pub fn test_zstd_dictionary() {
+        let samples: Vec<Vec<u8>> = (0..2000).map(|i| format!(
+            "{{\"id\":{},\"type\":\"order\",\"customer\":\"customer-{}\",\"status\":\"{}\",\"currency\":\"EUR\"}}",
+            i, i % 97, ["open", "paid", "shipped"][i % 3]).into_bytes()).collect();
+        let dictionary = zstd::dict::from_samples(&samples, 4096).unwrap();
+        let dict_path = std::env::temp_dir().join(format!("final_compression_dict_{}", std::process::id()));
+        std::fs::write(&dict_path, &dictionary).unwrap();
+        let with_path = format!("level=3;dict_path={}", dict_path.display());
+        let compress = |option: ParamSet, data: &[u8]| {
+            let buffer = buffer::SharedBuffer::default();
+            let mut w = compressed_writer(Box::new(buffer.clone()), CompressionType::Zstd, option).unwrap();
+            w.write_all(data).unwrap();
+            drop(w);
+            return buffer.take();
+        };
+        let decompress = |option: ParamSet, data: Vec<u8>| {
+            let mut plain = Vec::new();
+            decompressed_reader_with(Box::new(std::io::Cursor::new(data)), CompressionType::Zstd, option)?
+                .read_to_end(&mut plain)?;
+            return Ok::<_, Box<dyn Error>>(plain);
+        };
+        let document = &samples[1234];
+        let plain_size = compress("level=3".into(), document).len();
+        let compressed = compress(with_path.as_str().into(), document);
+        assert!(compressed.len() * 2 < plain_size, "{} {}", compressed.len(), plain_size);
+        assert!(decompress("".into(), compressed.clone()).is_err());
+        assert_eq!(&decompress(with_path.as_str().into(), compressed.clone()).unwrap(), document);
+
+        // from memory, to the same frames
+        let mut in_memory = ParamSet::from("level=3");
+        in_memory.set_dictionary(&dictionary);
+        assert_eq!(compress(in_memory.clone(), document), compressed);
+        assert_eq!(&decompress(in_memory, compressed).unwrap(), document);
+
+        std::fs::remove_file(&dict_path).unwrap();
+        let err = compressed_writer(Box::new(std::io::sink()), CompressionType::Zstd, with_path.as_str()).err().unwrap();
+        assert!(err.to_string().starts_with("zstd dictionary"), "{}", err);
+        assert!(decompressed_reader_with(Box::new(std::io::empty()), CompressionType::Zstd, with_path.as_str()).is_err());
+    }
+
     #[test]
     pub fn test_compressed_writer_snappy() {
         let file_name = "test.out.txt.snappy";
@@ -366,6 +1296,24 @@ mod tests {
         test(file_name, ct, test_data, options);
     }
 
+    #[test]
+    pub fn test_snappy_raw() {
+        let data = "row key, row value; ".repeat(500);
+        let ours = compress_with(CompressionType::Snappy, "format=raw").unwrap();
+        let expected = "hello, world, hello, world, hello, world, hello, world".repeat(100);
+        assert_eq!(snap::raw::Decoder::new().decompress_vec(&ours).unwrap(), expected.as_bytes());
+
+        let theirs = snap::raw::Encoder::new().compress_vec(data.as_bytes()).unwrap();
+        let mut plain = String::new();
+        decompressed_reader_with(Box::new(std::io::Cursor::new(theirs.clone())), CompressionType::Snappy, "format=raw").unwrap()
+            .read_to_string(&mut plain).unwrap();
+        assert_eq!(plain, data);
+        // the frame format stays the default, and does not take raw blocks
+        assert!(decompressed_reader(Box::new(std::io::Cursor::new(theirs)), CompressionType::Snappy).unwrap()
+            .read_to_end(&mut Vec::new()).is_err());
+        assert!(compress_with(CompressionType::Snappy, "format=block").is_err());
+    }
+
 
     #[test]
     pub fn test_compressed_writer_gzip() {
@@ -394,6 +1342,24 @@ mod tests {
         test(file_name, ct, test_data, options);
     }
 
+    #[test]
+    pub fn test_compressed_writer_stored() {
+        let file_name = "test.out.txt.stored";
+        let test_data = "hello, world, hello, world, hello, world, hello, world";
+        let ct = CompressionType::Stored;
+        let options = "block_size=7";
+        test(file_name, ct, test_data, options);
+    }
+
+    #[test]
+    pub fn test_compressed_writer_lzo() {
+        let file_name = "test.out.txt.lzo";
+        let test_data = "hello, world, hello, world, hello, world, hello, world";
+        let ct = CompressionType::Lzo;
+        let options = "";
+        test(file_name, ct, test_data, options);
+    }
+
     #[test]
     pub fn test_compressed_writer_xz() {
         let file_name = "test.out.txt.xz";
@@ -402,4 +1368,260 @@ mod tests {
         let options = "level=3";
         test(file_name, ct, test_data, options);
     }
+
+    #[test]
+    pub fn test_lzma_alone() {
+        // `printf 'legacy lzma archive\n' | lzma -z`, from XZ Utils 5.8.2
+        let fixture: &[u8] = &[
+            0x5d, 0x00, 0x00, 0x80, 0x00, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff,
+            0xff, 0x00, 0x36, 0x19, 0x49, 0x29, 0x7a, 0x9a, 0x86, 0x9d, 0x56, 0xbd,
+            0xc2, 0x6a, 0xfa, 0x89, 0x7d, 0x1c, 0x8d, 0xb2, 0x12, 0x8b, 0xe6, 0x7d,
+            0x83, 0xa6, 0xff, 0xff, 0xd9, 0x63, 0x00, 0x00,
+        ];
+        let mut out = String::new();
+        decompressed_reader_with(Box::new(fixture), CompressionType::XZ, "format=alone").unwrap().read_to_string(&mut out).unwrap();
+        assert_eq!(out, "legacy lzma archive\n");
+        // the xz decoder does not take it for an xz stream
+        assert!(decompressed_reader(Box::new(fixture), CompressionType::XZ).unwrap().read_to_end(&mut Vec::new()).is_err());
+
+        let data = "hello, world, hello, world, hello, world, hello, world".repeat(100);
+        let compressed = compress_with(CompressionType::XZ, "level=3;format=alone").unwrap();
+        assert_eq!(compressed[0], 0x5d);
+        // flushing does not end anything in this format, nor fails
+        let mut w = durable::durable_writer(Box::new(std::io::sink()), CompressionType::XZ, "format=alone").unwrap();
+        w.write_all(data.as_bytes()).unwrap();
+        w.flush().unwrap();
+        assert!(w.finish().unwrap() > 0);
+        let mut plain = String::new();
+        decompressed_reader_with(Box::new(std::io::Cursor::new(compressed.clone())), CompressionType::XZ, "format=alone").unwrap()
+            .read_to_string(&mut plain).unwrap();
+        assert_eq!(plain, data);
+        // readable by unlzma, if installed
+        if let Ok(mut child) = std::process::Command::new("unlzma").arg("-c")
+            .stdin(std::process::Stdio::piped()).stdout(std::process::Stdio::piped()).spawn() {
+            child.stdin.take().unwrap().write_all(&compressed).unwrap();
+            let output = child.wait_with_output().unwrap();
+            assert!(output.status.success());
+            assert_eq!(output.stdout, data.as_bytes());
+        }
+        assert!(compress_with(CompressionType::XZ, "format=lzip").is_err());
+    }
+
+    #[test]
+    pub fn test_xz_threads() {
+        let data: Vec<u8> = (0..10 * 1024 * 1024u32)
+            .map(|i| b"log line with a counter \n"[i as usize % 25] ^ ((i / 4096) % 3) as u8)
+            .collect();
+        let buffer = buffer::SharedBuffer::default();
+        let mut w = compressed_writer(Box::new(buffer.clone()), CompressionType::XZ, "level=1;threads=4;block_size=1MiB").unwrap();
+        w.write_all(&data).unwrap();
+        drop(w);
+        let compressed = buffer.take();
+        assert!(compressed.len() < data.len() / 10);
+        let mut plain = Vec::new();
+        decompressed_reader(Box::new(std::io::Cursor::new(compressed.clone())), CompressionType::XZ).unwrap()
+            .read_to_end(&mut plain).unwrap();
+        assert!(plain == data);
+        // readable by xz, if installed
+        let path = std::env::temp_dir().join(format!("final_compression_xz_threads_{}.xz", std::process::id()));
+        std::fs::write(&path, &compressed).unwrap();
+        if let Ok(output) = std::process::Command::new("xz").arg("-dc").arg(&path).output() {
+            assert!(output.status.success());
+            assert!(output.stdout == data);
+        }
+        std::fs::remove_file(&path).unwrap();
+        for invalid in ["threads=-1", "threads=16385", "threads=2;format=alone", "threads=2;block_size=big"] {
+            assert!(compress_with(CompressionType::XZ, invalid).is_err(), "{}", invalid);
+        }
+        assert!(compress_with(CompressionType::XZ, "threads=0").is_ok());
+    }
+
+    fn compress_with(ct:CompressionType, options:&str) -> Result<Vec<u8>, Box<dyn Error>> {
+        let buffer = buffer::SharedBuffer::default();
+        let mut wrapper = compressed_writer(Box::new(buffer.clone()), ct, options)?;
+        wrapper.write_all("hello, world, hello, world, hello, world, hello, world".repeat(100).as_bytes())?;
+        drop(wrapper);
+        return Ok(buffer.take());
+    }
+
+    #[test]
+    pub fn test_messy_levels() {
+        for ct in [CompressionType::Zstd, CompressionType::Gzip, CompressionType::XZ] {
+            let expected = compress_with(ct, "level=6").unwrap();
+            for messy in ["level=6.0", "level= 6 ", "level=\t6\t", "level=6.000", " level =\u{a0}6\u{3000}", "level=6;\t"] {
+                assert_eq!(compress_with(ct, messy).unwrap(), expected, "{:?} {:?}", ct, messy);
+            }
+            for invalid in ["level=6.5", "level=fast", "level=1e400", "level=-1.25", "level=99999999999"] {
+                let err = compress_with(ct, invalid).unwrap_err();
+                let err = err.downcast_ref::<InvalidParam>().unwrap_or_else(|| panic!("{:?} {:?}", ct, invalid));
+                assert_eq!(err.key, "level");
+            }
+        }
+        let params: ParamSet = "level=7.0".into();
+        assert_eq!(params.get_integer("level", 1u32), Ok(7));
+        assert_eq!(params.get_integer("missing", 1u32), Ok(1));
+        assert!(ParamSet::from("level=-7").get_integer("level", 1u32).is_err());
+    }
+
+    #[test]
+    pub fn test_compression_type_names() {
+        assert_eq!(CompressionType::try_from("zst").unwrap(), CompressionType::Zstd);
+        assert_eq!(CompressionType::try_from("bz2").unwrap(), CompressionType::Bzip2);
+        assert_eq!(CompressionType::try_from(String::from("GZ")).unwrap(), CompressionType::Gzip);
+        assert_eq!("xz".parse::<CompressionType>().unwrap(), CompressionType::XZ);
+        assert_eq!("snappy".parse::<CompressionType>().unwrap(), CompressionType::Snappy);
+        assert_eq!("Gzip".parse::<CompressionType>().unwrap(), CompressionType::Gzip);
+        assert_eq!("bZiP2".parse::<CompressionType>().unwrap(), CompressionType::Bzip2);
+        assert_eq!(" Zst ".parse::<CompressionType>().unwrap(), CompressionType::Zstd);
+        assert_eq!("none".parse::<CompressionType>().unwrap(), CompressionType::None);
+        assert_eq!("br".parse::<CompressionType>().unwrap(), CompressionType::Brotli);
+        for unknown in ["", "lzip", "zstd2"] {
+            let err = CompressionType::try_from(unknown).unwrap_err();
+            assert_eq!(err.name, unknown);
+            let err: Box<dyn Error> = err.into();
+            assert_eq!(err.to_string(), format!("Unknown compression type `{}`", unknown));
+        }
+    }
+
+    #[test]
+    pub fn test_compression_type_as_str() {
+        let all = [CompressionType::None, CompressionType::Zstd, CompressionType::Snappy, CompressionType::Gzip,
+            CompressionType::Zlib, CompressionType::Deflate, CompressionType::Bzip2, CompressionType::LZ4,
+            CompressionType::XZ, CompressionType::Stored, CompressionType::Brotli, CompressionType::Lzo];
+        assert_eq!(all.len(), describe::BUILTIN_CODECS.len());
+        for ct in all {
+            assert_eq!(CompressionType::try_from(ct.as_str()).unwrap(), ct);
+            assert_eq!(ct.to_string(), ct.as_str());
+            assert_eq!(ct.as_str(), ct.as_str().to_lowercase());
+        }
+        assert_eq!(CompressionType::Zstd.as_str(), "zstd");
+        assert_eq!(CompressionType::None.as_str(), "none");
+        assert_eq!(CompressionType::Custom(u16::MAX).as_str(), "custom");
+    }
+
+    #[test]
+    pub fn test_wire_ids() {
+        // pinned: failing here means a wire id changed, which breaks every stored header
+        let pinned = [(CompressionType::None, 0), (CompressionType::Gzip, 1), (CompressionType::Zlib, 2),
+            (CompressionType::Deflate, 3), (CompressionType::Bzip2, 4), (CompressionType::XZ, 5), (CompressionType::Zstd, 6),
+            (CompressionType::LZ4, 7), (CompressionType::Snappy, 8), (CompressionType::Stored, 9),
+            (CompressionType::Brotli, 10), (CompressionType::Lzo, 11)];
+        assert_eq!(pinned.len(), describe::BUILTIN_CODECS.len());
+        for (ct, wire_id) in pinned {
+            assert_eq!(ct.wire_id(), wire_id, "{}", ct);
+            assert_eq!(CompressionType::from_wire_id(wire_id), Some(ct));
+        }
+        for codec in describe::BUILTIN_CODECS {
+            assert_eq!(CompressionType::from_wire_id(codec.compression_type.wire_id()), Some(codec.compression_type));
+        }
+        for reserved in [12, 0x100, 0x7fff] {
+            assert_eq!(CompressionType::from_wire_id(reserved), None);
+        }
+        assert_eq!(WIRE_ID_CUSTOM_BASE, 0x8000);
+        assert_eq!(CompressionType::Custom(u16::MAX).wire_id(), 0xffff);
+        assert_eq!(CompressionType::from_wire_id(0xffff), None);
+    }
+
+    #[test]
+    pub fn test_from_extension() {
+        use std::path::Path;
+        assert_eq!(CompressionType::from_extension("zst"), Some(CompressionType::Zstd));
+        assert_eq!(CompressionType::from_extension(".GZ"), Some(CompressionType::Gzip));
+        assert_eq!(CompressionType::from_extension("tgz"), Some(CompressionType::Gzip));
+        assert_eq!(CompressionType::from_extension("Snappy"), Some(CompressionType::Snappy));
+        assert_eq!(CompressionType::from_extension("zz"), Some(CompressionType::Zlib));
+        assert_eq!(CompressionType::from_extension("tar"), None);
+        assert_eq!(CompressionType::from_extension(""), None);
+        assert_eq!(CompressionType::from_path(Path::new("backup.tar.zst")), Some(CompressionType::Zstd));
+        assert_eq!(CompressionType::from_path(Path::new("/var/log/syslog.1.gz")), Some(CompressionType::Gzip));
+        assert_eq!(CompressionType::from_path(Path::new("dump.sql.BZ2")), Some(CompressionType::Bzip2));
+        assert_eq!(CompressionType::from_path(Path::new("archive.gz.tar")), None);
+        assert_eq!(CompressionType::from_path(Path::new(".config.gz")), Some(CompressionType::Gzip));
+        assert_eq!(CompressionType::from_path(Path::new(".gz")), None);
+        assert_eq!(CompressionType::from_path(Path::new("README")), None);
+        assert_eq!(CompressionType::from_path(Path::new("dir.xz/file")), None);
+
+        // lzma is xz in its legacy container
+        assert_eq!(CompressionType::from_extension("LZMA"), Some(CompressionType::XZ));
+        let (ct, params) = CompressionType::from_path_with_params(Path::new("notes.txt.lzma")).unwrap();
+        assert_eq!((ct, params), (CompressionType::XZ, "format=alone"));
+        let compressed = compress_bytes(b"legacy container", ct, params).unwrap();
+        assert!(decompress_bytes(&compressed, CompressionType::XZ).is_err());
+        let mut out = Vec::new();
+        decompressed_reader_with(Box::new(std::io::Cursor::new(compressed)), ct, params).unwrap().read_to_end(&mut out).unwrap();
+        assert_eq!(out, b"legacy container");
+        assert_eq!(CompressionType::from_path_with_params(Path::new("archive.tar.gz")), Some((CompressionType::Gzip, "")));
+    }
+
+    #[test]
+    pub fn test_percent_values() {
+        let params = ParamSet::from(r"dict=%TEMP%\dict.bin;lone=100%;inside=a%%:b;escaped=%%:%3B%25");
+        assert_eq!(params.get_string("dict", ""), r"%TEMP%\dict.bin");
+        assert_eq!(params.get_string("lone", ""), "100%");
+        assert_eq!(params.get_string("inside", ""), "a%%:b");
+        assert_eq!(params.get_string("escaped", ""), ";%");
+        assert!(params.check().is_ok());
+
+        for (expression, position) in [("level=3;dict=%%:C:%TEMP%\\x", 2), ("dict=%%:abc%", 3), ("dict=%%:%ff%fe", 0)] {
+            let err = ParamSet::parse(expression).err().unwrap();
+            assert_eq!((err.key.as_str(), err.position), ("dict", position), "{}", expression);
+            let params = ParamSet::from(expression);
+            assert_eq!(params.get_string("dict", "unset"), "unset");
+            assert!(compressed_writer(Box::new(std::io::sink()), CompressionType::Gzip, params.clone()).is_err());
+            assert!(describe::validate_params(CompressionType::Gzip, params).is_err());
+        }
+
+        let mut params = ParamSet::from("level=3");
+        for value in [r"%TEMP%\dict.bin", "a;b=c", " padded ", "%%:literal", "100%", ""] {
+            params.set_raw("value", value);
+            let round_trip = ParamSet::parse(&params.to_string()).unwrap();
+            assert_eq!(round_trip.get_string("value", "unset"), value, "{}", params);
+            assert_eq!(round_trip.get_string("level", ""), "3");
+        }
+    }
+
+    #[test]
+    pub fn test_size_params() {
+        let params: ParamSet = "block_size=4KiB;rsync_interval=65536;max_output=1.5 MB;bad=4 parsecs".into();
+        assert_eq!(params.get_size("block_size", 0usize), Ok(4096));
+        assert_eq!(params.get_size("rsync_interval", 0u64), Ok(65536));
+        assert_eq!(params.get_size("max_output", 0u64), Ok(1_500_000));
+        assert_eq!(params.get_size("missing", 7u32), Ok(7));
+        assert_eq!(params.get_size("bad", 0u64).unwrap_err().key, "bad");
+        assert!(ParamSet::from("block_size=8GiB").get_size("block_size", 0u32).is_err());
+        let compress_with = |options: &str| {
+            let buffer = buffer::SharedBuffer::default();
+            let mut w = compressed_writer(Box::new(buffer.clone()), CompressionType::Stored, options).unwrap();
+            w.write_all(&[7u8; 10000]).unwrap();
+            drop(w);
+            return buffer.take();
+        };
+        assert_eq!(compress_with("block_size=4KiB"), compress_with("block_size=4096"));
+    }
+
+    #[test]
+    #[cfg(not(feature = "strict-api"))]
+    pub fn test_get_bool() {
+        let params: ParamSet = "a=true;b=TRUE;c=false;d=False;e=yes;f=".into();
+        assert!(params.get_bool("a", false) && params.get_bool("b", false));
+        // 1.0.0 read `false`, in any case, as true
+        assert!(!params.get_bool("c", true) && !params.get_bool("d", true));
+        assert!(!params.get_bool("e", true));
+        assert!(params.get_bool("f", true) && params.get_bool("missing", true));
+    }
+
+    #[test]
+    pub fn test_strict_getters() {
+        let params: ParamSet = "a=TRUE;b=false;c=yes;port=8080;host=nowhere".into();
+        assert_eq!(params.get_flag("a", false), Ok(true));
+        assert_eq!(params.get_flag("b", true), Ok(false));
+        assert_eq!(params.get_flag("missing", true), Ok(true));
+        assert_eq!(params.get_flag("c", false).unwrap_err().expected, "true or false");
+        assert_eq!(params.get_parsed("port", 0u16), Ok(8080));
+        assert_eq!(params.get_parsed("missing", 1u16), Ok(1));
+        assert_eq!(params.get_parsed("host", 0u16).unwrap_err().key, "host");
+        // no longer quietly off
+        assert!(compressed_writer(Box::new(std::io::sink()), CompressionType::Gzip, "rsyncable=yes").is_err());
+        assert!(compressed_writer(Box::new(std::io::sink()), CompressionType::Zstd, "minimal_overhead=1").is_err());
+    }
 }
@@ -0,0 +1,136 @@
+use std::io::{Write, Read};
+
+/// Buffer size handed to the brotli encoder and decoder
+const BUFFER_SIZE: usize = 64 * 1024;
+
+/// Sink of the brotli encoder. The encoder drops the errors of its final write, so the first
+/// error of `out` is kept here for `BrotliWrapper::finish` to report.
+struct ErrorKeeper<W: Write> {
+    out: W,
+    error: Option<std::io::Error>,
+}
+
+impl<W: Write> Write for ErrorKeeper<W> {
+    fn write(&mut self, data: &[u8]) -> Result<usize, std::io::Error> {
+        return self.out.write(data).inspect_err(|e| {
+            if self.error.is_none() {
+                self.error = Some(std::io::Error::new(e.kind(), e.to_string()));
+            }
+        });
+    }
+
+    fn flush(&mut self) -> Result<(), std::io::Error> {
+        return self.out.flush();
+    }
+}
+
+pub struct BrotliWrapper<W: Write = Box<dyn Write>> {
+    src: Option<brotli::CompressorWriter<ErrorKeeper<W>>>
+}
+
+impl<W: Write> BrotliWrapper<W> {
+    /// Encoder of `quality` (0~11) with a window of 2^`lgwin` bytes (10~24)
+    pub fn new(out: W, quality: u32, lgwin: u32) -> BrotliWrapper<W> {
+        let sink = ErrorKeeper { out, error: None };
+        BrotliWrapper {
+            src: Some(brotli::CompressorWriter::new(sink, BUFFER_SIZE, quality, lgwin))
+        }
+    }
+
+    /// Write the last meta-block and flush the sink. Later writes fail; calling it again is a no-op.
+    pub fn finish(&mut self) -> Result<(), std::io::Error> {
+        return match self.finish_sink() {
+            Some(result) => result.map(|_| ()),
+            None => Ok(()),
+        };
+    }
+
+    /// The sink
+    pub fn get_ref(&self) -> &W {
+        return &self.src.as_ref().unwrap().get_ref().out;
+    }
+
+    /// Write the last meta-block and return the flushed sink
+    pub fn into_inner(mut self) -> Result<W, std::io::Error> {
+        return self.finish_sink().unwrap_or_else(|| Err(std::io::Error::other("brotli stream already finished")));
+    }
+
+    /// `finish`, giving back the sink, `None` when already finished
+    fn finish_sink(&mut self) -> Option<Result<W, std::io::Error>> {
+        let mut sink = self.src.take()?.into_inner();
+        if let Some(e) = sink.error.take() {
+            return Some(Err(e));
+        }
+        return Some(sink.out.flush().map(|_| sink.out));
+    }
+
+    fn encoder(&mut self) -> Result<&mut brotli::CompressorWriter<ErrorKeeper<W>>, std::io::Error> {
+        return self.src.as_mut()
+            .ok_or_else(|| std::io::Error::other("brotli stream already finished"));
+    }
+}
+
+impl<W: Write> Write for BrotliWrapper<W> {
+    fn write(&mut self, data: &[u8]) -> Result<usize, std::io::Error> {
+        return self.encoder()?.write(data);
+    }
+
+    fn flush(&mut self) -> Result<(), std::io::Error> {
+        return self.encoder()?.flush();
+    }
+}
+
+impl<W: Write> Drop for BrotliWrapper<W> {
+    fn drop(&mut self) {
+        if std::thread::panicking() {
+            return;
+        }
+        let _ = self.finish();
+    }
+}
+
+/// Decoding side of brotli
+pub fn brotli_reader<R: Read>(src: R) -> brotli::Decompressor<R> {
+    return brotli::Decompressor::new(src, BUFFER_SIZE);
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::{Read, Write};
+    use crate::{compressed_writer, decompressed_reader, CompressionType};
+
+    fn decompress(data: Vec<u8>) -> std::io::Result<Vec<u8>> {
+        let mut plain = Vec::new();
+        decompressed_reader(Box::new(std::io::Cursor::new(data)), CompressionType::Brotli).unwrap().read_to_end(&mut plain)?;
+        return Ok(plain);
+    }
+
+    #[test]
+    pub fn test_round_trip() {
+        let data = b"Content-Encoding: br, served to every browser\n".repeat(5000);
+        for options in ["", "level=0", "level=11;window=24", "level=5;window=10"] {
+            let buffer = crate::buffer::SharedBuffer::default();
+            let mut w = compressed_writer(Box::new(buffer.clone()), CompressionType::Brotli, options).unwrap();
+            w.write_all(&data).unwrap();
+            drop(w);
+            let compressed = buffer.take();
+            assert!(compressed.len() < data.len() / 10, "{}", options);
+            assert!(decompress(compressed.clone()).unwrap() == data, "{}", options);
+            // a stream cut before its last meta-block does not pass for a complete one
+            assert!(decompress(compressed[..compressed.len() / 2].to_vec()).is_err(), "{}", options);
+        }
+        assert!(compressed_writer(Box::new(std::io::sink()), CompressionType::Brotli, "level=12").is_err());
+        assert!(compressed_writer(Box::new(std::io::sink()), CompressionType::Brotli, "window=25").is_err());
+    }
+
+    #[test]
+    pub fn test_reference_streams() {
+        // the empty stream as the reference encoder writes it with a 64 KiB window
+        assert_eq!(decompress(vec![0x06]).unwrap(), b"");
+        // RFC 7932 uncompressed meta-block of 5 bytes, followed by an empty last meta-block
+        let mut stream = vec![0x40, 0x00, 0x10];
+        stream.extend_from_slice(b"hello");
+        stream.push(0x03);
+        assert_eq!(decompress(stream).unwrap(), b"hello");
+    }
+}
@@ -1,4 +1,4 @@
-use std::io::Write;
+use std::io::{Write, Read, ErrorKind};
 
 pub struct Lz4Wrapper {
     src: Option<lz4::Encoder<Box<dyn Write>>>
@@ -10,20 +10,115 @@ impl Lz4Wrapper {
             src: Some(enc)
         }
     }
+
+    /// Write the LZ4 end mark and flush the sink. Later writes fail; calling it again is a no-op.
+    pub fn finish(&mut self) -> Result<(), std::io::Error> {
+        let src = match self.src.take() {
+            Some(src) => src,
+            None => return Ok(())
+        };
+        let (mut w, result) = src.finish();
+        result?;
+        return w.flush();
+    }
+
+    fn encoder(&mut self) -> Result<&mut lz4::Encoder<Box<dyn Write>>, std::io::Error> {
+        return self.src.as_mut()
+            .ok_or_else(|| std::io::Error::other("LZ4 stream already finished"));
+    }
 }
 impl Write for Lz4Wrapper {
     fn write(&mut self, data: &[u8]) -> Result<usize, std::io::Error> {
-        return self.src.as_mut().unwrap().write(data);
+        return self.encoder()?.write(data);
     }
 
     fn flush(&mut self) ->Result<(), std::io::Error>{
-        return self.src.as_mut().unwrap().flush();
+        return self.encoder()?.flush();
     }
 }
 impl Drop for Lz4Wrapper {
     fn drop(&mut self) {
-        let src = self.src.take().unwrap();
-        let mut w = src.finish();
-        let _ = w.0.flush();
+        if std::thread::panicking() {
+            return;
+        }
+        let _ = self.finish();
     }
-}
\ No newline at end of file
+}
+
+/// Compress `data` into one raw LZ4 block, the output of `LZ4_compress_default`, without the
+/// frame format nor a length: the reader needs the uncompressed size from elsewhere.
+pub fn lz4_block_compress(data: &[u8]) -> Result<Vec<u8>, std::io::Error> {
+    return lz4::block::compress(data, None, false);
+}
+
+/// Decompress the raw LZ4 `block` of `uncompressed_size` bytes
+pub fn lz4_block_decompress(block: &[u8], uncompressed_size: usize) -> Result<Vec<u8>, std::io::Error> {
+    let size = i32::try_from(uncompressed_size)
+        .map_err(|_| std::io::Error::new(ErrorKind::InvalidInput, "LZ4 blocks hold less than 2 GiB"))?;
+    return lz4::block::decompress(block, Some(size));
+}
+
+/// Decoding side of LZ4. Reports a stream ending before the LZ4 end mark as
+/// `UnexpectedEof` when `strict` is set.
+pub struct Lz4ReaderWrapper<R: Read = Box<dyn Read>> {
+    src: Option<lz4::Decoder<R>>,
+    strict: bool,
+}
+
+impl<R: Read> Lz4ReaderWrapper<R> {
+    pub fn new(dec:lz4::Decoder<R>, strict: bool) -> Lz4ReaderWrapper<R> {
+        Lz4ReaderWrapper {
+            src: Some(dec),
+            strict
+        }
+    }
+}
+
+impl<R: Read> Read for Lz4ReaderWrapper<R> {
+    fn read(&mut self, buf: &mut [u8]) -> Result<usize, std::io::Error> {
+        let src = match self.src.as_mut() {
+            Some(src) => src,
+            None => return Ok(0)
+        };
+        let read = src.read(buf)?;
+        if read == 0 && !buf.is_empty() {
+            let (_, result) = self.src.take().unwrap().finish();
+            if self.strict && result.is_err() {
+                return Err(std::io::Error::new(ErrorKind::UnexpectedEof, "LZ4 stream ended before its end mark"));
+            }
+        }
+        return Ok(read);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{compressed_writer, decompressed_reader_with, CompressionType};
+
+    #[test]
+    pub fn test_raw_blocks() {
+        let data = b"block compressed by another system, ".repeat(2000);
+        // both ways with the lz4 crate's block module
+        let block = lz4_block_compress(&data).unwrap();
+        assert_eq!(lz4::block::decompress(&block, Some(data.len() as i32)).unwrap(), data);
+        let theirs = lz4::block::compress(&data, Some(lz4::block::CompressionMode::DEFAULT), false).unwrap();
+        assert_eq!(lz4_block_decompress(&theirs, data.len()).unwrap(), data);
+        assert!(lz4_block_decompress(&theirs, data.len() - 1).is_err());
+        assert!(lz4_block_decompress(&theirs[..theirs.len() - 3], data.len()).is_err());
+
+        // streaming, with the size prefix
+        let buffer = crate::buffer::SharedBuffer::default();
+        let mut w = compressed_writer(Box::new(buffer.clone()), CompressionType::LZ4, "format=block").unwrap();
+        w.write_all(&data).unwrap();
+        drop(w);
+        let prefixed = buffer.take();
+        assert_eq!(prefixed, lz4::block::compress(&data, None, true).unwrap());
+        assert_eq!(&prefixed[..4], &(data.len() as u32).to_le_bytes());
+        let mut plain = Vec::new();
+        decompressed_reader_with(Box::new(std::io::Cursor::new(prefixed)), CompressionType::LZ4, "format=block").unwrap()
+            .read_to_end(&mut plain).unwrap();
+        assert_eq!(plain, data);
+        assert!(compressed_writer(Box::new(std::io::sink()), CompressionType::LZ4, "format=legacy").is_err());
+    }
+}
@@ -1,53 +1,488 @@
 use rust_lzo::{LZOContext, LZOError};
-use std::io::{Write, ErrorKind};
+use std::io::{ErrorKind, Read, Write};
 
-pub struct LZOWrapperW {
+/// Stream magic of the framed LZO format, followed by a format version byte
+pub const LZO_MAGIC: &[u8] = b"FCLZ";
+/// Current version of the framed LZO format
+pub const LZO_VERSION: u8 = 1;
+/// Bytes written once per stream before the first block
+pub const STREAM_HEADER_SIZE: usize = 5;
+/// Bytes of a block header: u32 LE stored length, u32 LE uncompressed length, flags
+pub const BLOCK_HEADER_SIZE: usize = 9;
+/// Block flag: the payload is the input itself, which LZO1X did not make smaller
+pub const FLAG_STORED: u8 = 1;
+/// Uncompressed bytes per LZO block, and the most a reader accepts
+pub const BLOCK_SIZE: usize = 64 * 1024;
+
+/// Writer of the framed LZO format: the stream header, then LZO1X blocks of up to `BLOCK_SIZE`
+/// input bytes each, then an end marker (a block header of zeros), written on drop.
+///
+/// A block whose compressed form is not smaller than its input is stored as is.
+pub struct LZOWrapperW<W: Write = Box<dyn Write>> {
+    /// Input of the block being filled
+    pending: Vec<u8>,
+    /// Compressed form of the last block
     buffer: Vec<u8>,
     context: LZOContext,
-    writer: Box<dyn Write>
+    /// Only taken by `into_inner`
+    writer: Option<W>,
+    header_written: bool,
+    finished: bool,
 }
 
-impl LZOWrapperW {
-    pub fn new(w:Box<dyn Write>) -> LZOWrapperW {
+impl<W: Write> LZOWrapperW<W> {
+    pub fn new(w:W) -> LZOWrapperW<W> {
         LZOWrapperW { 
-            buffer: Vec::with_capacity(8192), 
+            pending: Vec::with_capacity(BLOCK_SIZE),
+            buffer: Vec::with_capacity(rust_lzo::worst_compress(BLOCK_SIZE)),
             context: LZOContext::new(), 
-            writer: w 
+            writer: Some(w),
+            header_written: false,
+            finished: false,
+        }
+    }
+
+    /// The sink
+    pub fn get_ref(&self) -> &W {
+        return self.writer.as_ref().unwrap();
+    }
+
+    /// Write the last block and the end marker, and return the sink
+    pub fn into_inner(mut self) -> Result<W, std::io::Error> {
+        self.finish()?;
+        return Ok(self.writer.take().unwrap());
+    }
+
+    fn writer(&mut self) -> &mut W {
+        return self.writer.as_mut().unwrap();
+    }
+
+    fn write_header(&mut self) -> Result<(), std::io::Error> {
+        if !self.header_written {
+            self.writer().write_all(LZO_MAGIC)?;
+            self.writer().write_all(&[LZO_VERSION])?;
+            self.header_written = true;
         }
+        return Ok(());
+    }
+
+    /// Compress and write the pending input as one block
+    fn write_block(&mut self) -> Result<(), std::io::Error> {
+        self.write_header()?;
+        if self.pending.is_empty() {
+            return Ok(());
+        }
+        self.buffer.clear();
+        // the compressor writes up to the capacity without checking it
+        self.buffer.reserve(rust_lzo::worst_compress(self.pending.len()));
+        if self.context.compress(&self.pending, &mut self.buffer) != LZOError::OK {
+            return Err(std::io::Error::new(ErrorKind::InvalidData, "LZO compression failed"));
+        }
+        let (payload, flags) = if self.buffer.len() < self.pending.len() {
+            (&self.buffer, 0)
+        } else {
+            (&self.pending, FLAG_STORED)
+        };
+        let mut header = [0u8; BLOCK_HEADER_SIZE];
+        header[..4].copy_from_slice(&(payload.len() as u32).to_le_bytes());
+        header[4..8].copy_from_slice(&(self.pending.len() as u32).to_le_bytes());
+        header[8] = flags;
+        let writer = self.writer.as_mut().unwrap();
+        writer.write_all(&header)?;
+        writer.write_all(payload)?;
+        self.pending.clear();
+        return Ok(());
+    }
+
+    /// Write the last block and the end marker, and flush the sink. Later writes fail; calling
+    /// it again is a no-op.
+    pub fn finish(&mut self) -> Result<(), std::io::Error> {
+        if self.finished {
+            return Ok(());
+        }
+        self.write_block()?;
+        self.writer().write_all(&[0u8; BLOCK_HEADER_SIZE])?;
+        self.finished = true;
+        return self.writer().flush();
     }
 }
 
-impl Write for LZOWrapperW {
+// SAFETY: the only field that is not `Send` is the raw pointer to the work memory of the
+// `LZOContext`, which the context allocates, owns alone and only uses through `&mut self`
+unsafe impl<W: Write + Send> Send for LZOWrapperW<W> {}
+
+impl<W: Write> Write for LZOWrapperW<W> {
     fn write(&mut self, data: &[u8]) -> Result<usize, std::io::Error> {
-        self.buffer.clear();
-        let cr = self.context.compress(data, &mut self.buffer);
-        match cr {
-            LZOError::OK => {
-                // OK
-                let written = self.buffer.len();
-                let to_write = &self.buffer[0..written];
-                return self.writer.write(to_write);
-                //return Ok(self.buffer.len());
-            },
-            LZOError::NOT_COMPRESSIBLE => {
-                return self.writer.write(data);
-            },
-            LZOError::OUTPUT_OVERRUN => {
-                self.buffer.resize(self.buffer.capacity() * 2, 0u8);
-                return self.write(data);
-            },
-            _ => {
-                return Err(std::io::Error::new(ErrorKind::InvalidData, "LZO Other error code"));
-            }
+        if self.finished {
+            return Err(std::io::Error::other("LZO stream already finished"));
         }
+        if data.is_empty() {
+            return Ok(0);
+        }
+        // a full block is only written once more input comes, so a failed write accepts nothing
+        if self.pending.len() == BLOCK_SIZE {
+            self.write_block()?;
+        }
+        let n = data.len().min(BLOCK_SIZE - self.pending.len());
+        self.pending.extend_from_slice(&data[..n]);
+        return Ok(n);
     }
+
     fn flush(&mut self) -> Result<(), std::io::Error> {
-        return self.writer.flush();
+        if self.finished {
+            return Err(std::io::Error::other("LZO stream already finished"));
+        }
+        self.write_block()?;
+        return self.writer().flush();
     }
 }
 
-impl Drop for LZOWrapperW {
+impl<W: Write> Drop for LZOWrapperW<W> {
     fn drop(&mut self) {
-        
+        if std::thread::panicking() {
+            return;
+        }
+        let _ = self.finish();
+    }
+}
+
+/// Reader of the framed LZO format. Malformed blocks are reported as `InvalidData`, a stream
+/// without end marker as `UnexpectedEof`.
+pub struct LZOWrapperR<R: Read = Box<dyn Read>> {
+    src: R,
+    /// Payload of the block being decoded
+    payload: Vec<u8>,
+    block: Vec<u8>,
+    pos: usize,
+    header_read: bool,
+    finished: bool,
+}
+
+impl<R: Read> LZOWrapperR<R> {
+    pub fn new(src: R) -> LZOWrapperR<R> {
+        LZOWrapperR {
+            src,
+            payload: Vec::new(),
+            block: Vec::new(),
+            pos: 0,
+            header_read: false,
+            finished: false,
+        }
+    }
+
+    /// The source, positioned after the end marker once the stream is read to its end
+    pub fn get_mut(&mut self) -> &mut R {
+        return &mut self.src;
+    }
+
+    fn read_exact_or_eof(src: &mut R, buf: &mut [u8]) -> Result<(), std::io::Error> {
+        return src.read_exact(buf).map_err(|e| {
+            if e.kind() == ErrorKind::UnexpectedEof {
+                return std::io::Error::new(ErrorKind::UnexpectedEof, "LZO stream ended before its end marker");
+            }
+            return e;
+        });
+    }
+
+    fn next_block(&mut self) -> Result<(), std::io::Error> {
+        if !self.header_read {
+            let mut header = [0u8; STREAM_HEADER_SIZE];
+            Self::read_exact_or_eof(&mut self.src, &mut header)?;
+            if &header[..4] != LZO_MAGIC || header[4] != LZO_VERSION {
+                return Err(std::io::Error::new(ErrorKind::InvalidData, "not a framed LZO stream"));
+            }
+            self.header_read = true;
+        }
+        let mut header = [0u8; BLOCK_HEADER_SIZE];
+        Self::read_exact_or_eof(&mut self.src, &mut header)?;
+        let stored_len = u32::from_le_bytes(header[..4].try_into().unwrap()) as usize;
+        let len = u32::from_le_bytes(header[4..8].try_into().unwrap()) as usize;
+        let flags = header[8];
+        if stored_len == 0 && len == 0 && flags == 0 {
+            self.finished = true;
+            return Ok(());
+        }
+        let invalid = |what: &str| std::io::Error::new(ErrorKind::InvalidData, format!("invalid LZO block: {}", what));
+        if len == 0 || len > BLOCK_SIZE {
+            return Err(invalid(&format!("{} uncompressed bytes", len)));
+        }
+        if flags & !FLAG_STORED != 0 {
+            return Err(invalid(&format!("unknown flags {:#04x}", flags)));
+        }
+        let stored = flags & FLAG_STORED != 0;
+        if (stored && stored_len != len) || (!stored && stored_len > rust_lzo::worst_compress(len)) {
+            return Err(invalid(&format!("{} bytes stored for {} uncompressed", stored_len, len)));
+        }
+        if !stored {
+            crate::bounds::check_declared(crate::CompressionType::Lzo, stored_len as u64, len as u64)?;
+        }
+        self.payload.resize(stored_len, 0);
+        Self::read_exact_or_eof(&mut self.src, &mut self.payload)?;
+        self.block.resize(len, 0);
+        self.pos = 0;
+        if stored {
+            self.block.copy_from_slice(&self.payload);
+            return Ok(());
+        }
+        let (decoded, result) = LZOContext::decompress_to_slice(&self.payload, &mut self.block);
+        if result != LZOError::OK || decoded.len() != len {
+            return Err(invalid("payload does not decode to its length"));
+        }
+        return Ok(());
+    }
+}
+
+impl<R: Read> Read for LZOWrapperR<R> {
+    fn read(&mut self, buf: &mut [u8]) -> Result<usize, std::io::Error> {
+        while self.pos == self.block.len() {
+            if self.finished || buf.is_empty() {
+                return Ok(0);
+            }
+            self.block.clear();
+            self.pos = 0;
+            self.next_block()?;
+        }
+        let n = buf.len().min(self.block.len() - self.pos);
+        buf[..n].copy_from_slice(&self.block[self.pos..self.pos + n]);
+        self.pos += n;
+        return Ok(n);
     }
-}
\ No newline at end of file
+}
+
+/// Reader of streams written by `LZOWrapperW` before it framed them, see `lzo_legacy_reader`
+pub struct LegacyLzoReader {
+    src: Box<dyn Read>,
+    block_size_hint: usize,
+    skip_undecodable: bool,
+    /// Compressed input not decoded yet
+    input: Vec<u8>,
+    eof: bool,
+    /// Decoded block being returned
+    chunk: Vec<u8>,
+    pos: usize,
+    /// Input bytes consumed, decoded or skipped
+    offset: u64,
+    skipped: u64,
+}
+
+/// Best-effort reader of archives written by `LZOWrapperW` before it framed its blocks: every
+/// `write` call became an LZO1X block, written back to back with the others.
+///
+/// Blocks are found by trying to decode up to each end-of-block marker in turn.
+/// `block_size_hint` is the size of the producer's write calls (8192 for our producers): no block
+/// may decode to more. A block that cannot be decoded fails the read with `InvalidData`, naming
+/// its input offset, unless `skip_undecodable` is set.
+#[deprecated(note = "only for archives written before LZO streams were framed")]
+pub fn lzo_legacy_reader(src: Box<dyn Read>, block_size_hint: usize) -> LegacyLzoReader {
+    return LegacyLzoReader {
+        src,
+        block_size_hint,
+        skip_undecodable: false,
+        input: Vec::new(),
+        eof: false,
+        chunk: Vec::new(),
+        pos: 0,
+        offset: 0,
+        skipped: 0,
+    };
+}
+
+/// Every LZO1X block ends with this marker
+const END_MARKER: [u8; 3] = [0x11, 0x00, 0x00];
+
+impl LegacyLzoReader {
+    /// Skip input that does not decode, up to the next block that does, instead of failing
+    pub fn skip_undecodable(mut self, skip: bool) -> LegacyLzoReader {
+        self.skip_undecodable = skip;
+        return self;
+    }
+
+    /// Compressed bytes skipped so far
+    pub fn skipped(&self) -> u64 {
+        return self.skipped;
+    }
+
+    /// Buffer enough input for the largest block a chunk can compress to
+    fn fill(&mut self) -> Result<(), std::io::Error> {
+        let wanted = rust_lzo::worst_compress(self.block_size_hint);
+        let mut buf = [0u8; 8192];
+        while !self.eof && self.input.len() < wanted {
+            let read = self.src.read(&mut buf)?;
+            self.eof = read == 0;
+            self.input.extend_from_slice(&buf[..read]);
+        }
+        return Ok(());
+    }
+
+    /// Decode the block at the start of `input`: its content and compressed length
+    fn decode_block(&self, input: &[u8]) -> Option<(Vec<u8>, usize)> {
+        let mut out = vec![0u8; self.block_size_hint];
+        let mut start = 0;
+        while let Some(found) = input[start..].windows(END_MARKER.len()).position(|w| w == END_MARKER) {
+            let end = start + found + END_MARKER.len();
+            let (decoded, result) = LZOContext::decompress_to_slice(&input[..end], &mut out);
+            if result == LZOError::OK {
+                return Some((decoded.to_vec(), end));
+            }
+            start += found + 1;
+        }
+        return None;
+    }
+
+    /// Decode the next block into `self.chunk`, empty at the end of the input
+    fn next_block(&mut self) -> Result<(), std::io::Error> {
+        self.chunk.clear();
+        self.pos = 0;
+        loop {
+            self.fill()?;
+            if self.input.is_empty() {
+                return Ok(());
+            }
+            let Some((chunk, consumed)) = self.decode_block(&self.input) else {
+                if !self.skip_undecodable {
+                    return Err(std::io::Error::new(ErrorKind::InvalidData,
+                        format!("undecodable LZO block at input offset {}", self.offset)));
+                }
+                self.input.remove(0);
+                self.offset += 1;
+                self.skipped += 1;
+                continue;
+            };
+            self.input.drain(..consumed);
+            self.offset += consumed as u64;
+            if chunk.is_empty() {
+                // an empty write call, not the end of the stream
+                continue;
+            }
+            self.chunk = chunk;
+            return Ok(());
+        }
+    }
+}
+
+impl Read for LegacyLzoReader {
+    fn read(&mut self, buf: &mut [u8]) -> Result<usize, std::io::Error> {
+        if self.pos == self.chunk.len() {
+            self.next_block()?;
+        }
+        let n = buf.len().min(self.chunk.len() - self.pos);
+        buf[..n].copy_from_slice(&self.chunk[self.pos..self.pos + n]);
+        self.pos += n;
+        return Ok(n);
+    }
+}
+
+#[cfg(test)]
+#[allow(deprecated)]
+mod tests {
+    use super::*;
+
+    /// What our producers did before the framing: an unframed LZO1X block for every 8192 byte chunk
+    fn old_archive(data: &[u8]) -> Vec<u8> {
+        let mut context = LZOContext::new();
+        let mut archive = Vec::new();
+        for chunk in data.chunks(8192) {
+            let mut block = Vec::with_capacity(rust_lzo::worst_compress(chunk.len()));
+            assert!(context.compress(chunk, &mut block) == LZOError::OK);
+            archive.extend_from_slice(&block);
+        }
+        return archive;
+    }
+
+    fn records(count: usize) -> Vec<u8> {
+        return (0..count).flat_map(|i| format!("{:08} record payload {}\n", i, i % 97).into_bytes()).collect();
+    }
+
+    #[test]
+    pub fn test_old_archives_readable() {
+        for count in [0, 1, 300, 5000] {
+            let data = records(count);
+            let mut out = Vec::new();
+            lzo_legacy_reader(Box::new(std::io::Cursor::new(old_archive(&data))), 8192).read_to_end(&mut out).unwrap();
+            assert_eq!(out, data, "{}", count);
+        }
+    }
+
+    #[test]
+    pub fn test_undecodable_regions() {
+        let data = records(2000);
+        let mut archive = old_archive(&data);
+        archive.splice(0..0, b"garbage".iter().copied());
+        let mut out = Vec::new();
+        let err = lzo_legacy_reader(Box::new(std::io::Cursor::new(archive.clone())), 8192).read_to_end(&mut out).unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::InvalidData);
+        assert!(err.to_string().contains("offset 0"), "{}", err);
+
+        let mut reader = lzo_legacy_reader(Box::new(std::io::Cursor::new(archive)), 8192).skip_undecodable(true);
+        out.clear();
+        reader.read_to_end(&mut out).unwrap();
+        assert_eq!(out, data);
+        assert_eq!(reader.skipped(), 7);
+    }
+
+    fn random(len: usize) -> Vec<u8> {
+        let mut state = 0x9E3779B97F4A7C15u64;
+        return (0..len).map(|_| {
+            state ^= state << 13;
+            state ^= state >> 7;
+            state ^= state << 17;
+            state as u8
+        }).collect();
+    }
+
+    fn framed(writes: &[&[u8]]) -> Vec<u8> {
+        let buffer = crate::buffer::SharedBuffer::default();
+        let mut w = LZOWrapperW::new(Box::new(buffer.clone()));
+        for data in writes {
+            w.write_all(data).unwrap();
+            assert_eq!(w.write(&[]).unwrap(), 0);
+        }
+        drop(w);
+        return buffer.take();
+    }
+
+    fn unframed(stream: Vec<u8>) -> Result<Vec<u8>, std::io::Error> {
+        let mut out = Vec::new();
+        LZOWrapperR::new(Box::new(std::io::Cursor::new(stream))).read_to_end(&mut out)?;
+        return Ok(out);
+    }
+
+    #[test]
+    pub fn test_framed_round_trip() {
+        let text = records(20_000);
+        let noise = random(3 * BLOCK_SIZE + 17);
+        assert_eq!(unframed(framed(&[])).unwrap(), b"");
+        assert_eq!(framed(&[]).len(), STREAM_HEADER_SIZE + BLOCK_HEADER_SIZE);
+        // one write larger than a block, incompressible data, and both mixed in one stream
+        for writes in [vec![&text[..]], vec![&noise[..]], vec![&b""[..], &noise[..100], &text[..], &noise[100..]]] {
+            let expected: Vec<u8> = writes.concat();
+            assert_eq!(unframed(framed(&writes)).unwrap(), expected);
+        }
+        let compressed = framed(&[&text]);
+        assert!(compressed.len() < text.len() / 3);
+        // random data is stored, at the cost of the headers only
+        let stored = framed(&[&noise]);
+        assert_eq!(stored.len(), noise.len() + STREAM_HEADER_SIZE + 5 * BLOCK_HEADER_SIZE);
+        assert_eq!(stored[STREAM_HEADER_SIZE + 8], FLAG_STORED);
+    }
+
+    #[test]
+    pub fn test_framed_damage() {
+        let stream = framed(&[&records(5000)]);
+        let err = unframed(stream[..stream.len() - 1].to_vec()).unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::UnexpectedEof);
+        let err = unframed(stream[..STREAM_HEADER_SIZE + BLOCK_HEADER_SIZE + 10].to_vec()).unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::UnexpectedEof);
+        for (offset, value) in [(0, b'X'), (STREAM_HEADER_SIZE + 8, 0x80), (STREAM_HEADER_SIZE + 8, FLAG_STORED), (STREAM_HEADER_SIZE + 6, 0xff)] {
+            let mut damaged = stream.clone();
+            damaged[offset] = value;
+            assert_eq!(unframed(damaged).unwrap_err().kind(), ErrorKind::InvalidData, "{}", offset);
+        }
+        // a whole block claimed from 4 bytes is refused before allocating it
+        let mut header = [0u8; BLOCK_HEADER_SIZE];
+        header[..4].copy_from_slice(&4u32.to_le_bytes());
+        header[4..8].copy_from_slice(&(BLOCK_SIZE as u32).to_le_bytes());
+        let err = unframed([&stream[..STREAM_HEADER_SIZE], &header[..], &[0; 4]].concat()).unwrap_err();
+        assert!(err.to_string().contains("more than the format can decode"), "{}", err);
+    }
+}
@@ -0,0 +1,267 @@
+use std::io::{Write, Read, ErrorKind};
+
+/// Stream magic of the stored format, followed by a format version byte
+pub const STORED_MAGIC: &[u8] = b"FCST";
+/// Current version of the stored format
+pub const STORED_VERSION: u8 = 1;
+/// Bytes written once per stream before the first block
+pub const STREAM_HEADER_SIZE: usize = 5;
+/// Bytes added to every block: u32 LE payload length + u32 LE CRC-32 of the payload
+pub const BLOCK_OVERHEAD: usize = 8;
+/// Bytes of the end marker: a block header with length 0 and CRC 0
+pub const END_MARKER_SIZE: usize = BLOCK_OVERHEAD;
+/// Default maximum payload per block
+pub const DEFAULT_BLOCK_SIZE: usize = 64 * 1024;
+/// Largest block size a reader accepts
+pub const MAX_BLOCK_SIZE: usize = 16 * 1024 * 1024;
+
+fn crc32(data: &[u8]) -> u32 {
+    let mut crc = flate2::Crc::new();
+    crc.update(data);
+    return crc.sum();
+}
+
+/// Writer of the stored format: payload bytes are kept verbatim, framed in checksummed blocks.
+///
+/// The end marker is written on drop.
+pub struct StoredWriter<W: Write = Box<dyn Write>> {
+    /// Only taken by `into_inner`
+    out: Option<W>,
+    buffer: Vec<u8>,
+    block_size: usize,
+    header_written: bool,
+    finished: bool,
+}
+
+impl<W: Write> StoredWriter<W> {
+    pub fn new(out: W, block_size: usize) -> StoredWriter<W> {
+        let block_size = block_size.clamp(1, MAX_BLOCK_SIZE);
+        StoredWriter {
+            out: Some(out),
+            buffer: Vec::with_capacity(block_size),
+            block_size,
+            header_written: false,
+            finished: false,
+        }
+    }
+
+    /// The sink
+    pub fn get_ref(&self) -> &W {
+        return self.out.as_ref().unwrap();
+    }
+
+    /// Finish the stream and return the sink
+    pub fn into_inner(mut self) -> Result<W, std::io::Error> {
+        self.finish()?;
+        return Ok(self.out.take().unwrap());
+    }
+
+    fn out(&mut self) -> &mut W {
+        return self.out.as_mut().unwrap();
+    }
+
+    fn write_header(&mut self) -> Result<(), std::io::Error> {
+        if !self.header_written {
+            self.out().write_all(STORED_MAGIC)?;
+            self.out().write_all(&[STORED_VERSION])?;
+            self.header_written = true;
+        }
+        return Ok(());
+    }
+
+    fn write_block(&mut self) -> Result<(), std::io::Error> {
+        self.write_header()?;
+        if self.buffer.is_empty() {
+            return Ok(());
+        }
+        let out = self.out.as_mut().unwrap();
+        out.write_all(&(self.buffer.len() as u32).to_le_bytes())?;
+        out.write_all(&crc32(&self.buffer).to_le_bytes())?;
+        out.write_all(&self.buffer)?;
+        self.buffer.clear();
+        return Ok(());
+    }
+
+    /// Write the pending block and the end marker. Later writes fail; calling it again is a no-op.
+    pub fn finish(&mut self) -> Result<(), std::io::Error> {
+        if self.finished {
+            return Ok(());
+        }
+        self.write_block()?;
+        self.out().write_all(&[0u8; END_MARKER_SIZE])?;
+        self.finished = true;
+        return self.out().flush();
+    }
+}
+
+impl<W: Write> Write for StoredWriter<W> {
+    fn write(&mut self, data: &[u8]) -> Result<usize, std::io::Error> {
+        if self.finished {
+            return Err(std::io::Error::other("stored stream already finished"));
+        }
+        let mut remaining = data;
+        while !remaining.is_empty() {
+            let room = self.block_size - self.buffer.len();
+            let accepted = room.min(remaining.len());
+            self.buffer.extend_from_slice(&remaining[..accepted]);
+            remaining = &remaining[accepted..];
+            if self.buffer.len() == self.block_size {
+                self.write_block()?;
+            }
+        }
+        return Ok(data.len());
+    }
+
+    fn flush(&mut self) -> Result<(), std::io::Error> {
+        self.write_block()?;
+        return self.out().flush();
+    }
+}
+
+impl<W: Write> Drop for StoredWriter<W> {
+    fn drop(&mut self) {
+        if std::thread::panicking() {
+            return;
+        }
+        let _ = self.finish();
+    }
+}
+
+/// Reader of the stored format. Checksum mismatches are reported as `InvalidData`,
+/// a stream without end marker as `UnexpectedEof`.
+pub struct StoredReader<R: Read = Box<dyn Read>> {
+    src: R,
+    max_block_size: usize,
+    block: Vec<u8>,
+    pos: usize,
+    header_read: bool,
+    finished: bool,
+}
+
+impl<R: Read> StoredReader<R> {
+    pub fn new(src: R) -> StoredReader<R> {
+        return StoredReader::with_max_block_size(src, MAX_BLOCK_SIZE);
+    }
+
+    /// Reader rejecting blocks larger than `max_block_size` (itself capped at `MAX_BLOCK_SIZE`)
+    pub fn with_max_block_size(src: R, max_block_size: usize) -> StoredReader<R> {
+        StoredReader {
+            src,
+            max_block_size: max_block_size.min(MAX_BLOCK_SIZE),
+            block: Vec::new(),
+            pos: 0,
+            header_read: false,
+            finished: false,
+        }
+    }
+
+    /// The source, positioned after the end marker once the stream is read to its end
+    pub fn get_mut(&mut self) -> &mut R {
+        return &mut self.src;
+    }
+
+    fn read_exact_or_eof(&mut self, buf: &mut [u8]) -> Result<(), std::io::Error> {
+        return self.src.read_exact(buf).map_err(|e| {
+            if e.kind() == ErrorKind::UnexpectedEof {
+                return std::io::Error::new(ErrorKind::UnexpectedEof, "stored stream ended before its end marker");
+            }
+            return e;
+        });
+    }
+
+    fn next_block(&mut self) -> Result<(), std::io::Error> {
+        if !self.header_read {
+            let mut header = [0u8; STREAM_HEADER_SIZE];
+            self.read_exact_or_eof(&mut header)?;
+            if &header[..4] != STORED_MAGIC || header[4] != STORED_VERSION {
+                return Err(std::io::Error::new(ErrorKind::InvalidData, "not a stored stream"));
+            }
+            self.header_read = true;
+        }
+        let mut block_header = [0u8; BLOCK_OVERHEAD];
+        self.read_exact_or_eof(&mut block_header)?;
+        let len = u32::from_le_bytes(block_header[..4].try_into().unwrap()) as usize;
+        let crc = u32::from_le_bytes(block_header[4..].try_into().unwrap());
+        if len == 0 {
+            self.finished = true;
+            return Ok(());
+        }
+        if len > self.max_block_size {
+            return Err(std::io::Error::new(ErrorKind::InvalidData, format!("stored block of {} bytes exceeds the limit", len)));
+        }
+        self.block.resize(len, 0);
+        self.pos = 0;
+        let mut block = std::mem::take(&mut self.block);
+        let result = self.read_exact_or_eof(&mut block);
+        self.block = block;
+        result?;
+        if crc32(&self.block) != crc {
+            return Err(std::io::Error::new(ErrorKind::InvalidData, "stored block checksum mismatch"));
+        }
+        return Ok(());
+    }
+}
+
+impl<R: Read> Read for StoredReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> Result<usize, std::io::Error> {
+        while self.pos == self.block.len() {
+            if self.finished || buf.is_empty() {
+                return Ok(0);
+            }
+            self.block.clear();
+            self.pos = 0;
+            self.next_block()?;
+        }
+        let n = buf.len().min(self.block.len() - self.pos);
+        buf[..n].copy_from_slice(&self.block[self.pos..self.pos + n]);
+        self.pos += n;
+        return Ok(n);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{compressed_writer, decompressed_reader, CompressionType};
+
+    fn stored(data: &[u8], options: &str) -> Vec<u8> {
+        let file_name = std::env::temp_dir().join(format!("final_compression.stored.{}.{}", std::process::id(), data.len()));
+        let out = std::fs::File::create(&file_name).unwrap();
+        let mut w = compressed_writer(Box::new(out), CompressionType::Stored, options).unwrap();
+        w.write_all(data).unwrap();
+        drop(w);
+        let result = std::fs::read(&file_name).unwrap();
+        let _ = std::fs::remove_file(&file_name);
+        return result;
+    }
+
+    fn unstored(data: Vec<u8>) -> Result<Vec<u8>, std::io::Error> {
+        let mut r = decompressed_reader(Box::new(std::io::Cursor::new(data)), CompressionType::Stored).unwrap();
+        let mut out = Vec::new();
+        r.read_to_end(&mut out)?;
+        return Ok(out);
+    }
+
+    #[test]
+    pub fn test_overhead() {
+        let data = vec![42u8; 10_000];
+        let encoded = stored(&data, "block_size=1000");
+        assert_eq!(encoded.len(), STREAM_HEADER_SIZE + 10 * (BLOCK_OVERHEAD + 1000) + END_MARKER_SIZE);
+        assert_eq!(&encoded[..4], STORED_MAGIC);
+        assert_eq!(unstored(encoded).unwrap(), data);
+        assert_eq!(stored(b"", "").len(), STREAM_HEADER_SIZE + END_MARKER_SIZE);
+        assert_eq!(unstored(stored(b"", "")).unwrap(), b"");
+    }
+
+    #[test]
+    pub fn test_corruption_detected() {
+        let data: Vec<u8> = (0..5000u32).map(|i| i as u8).collect();
+        let mut encoded = stored(&data, "");
+        encoded[STREAM_HEADER_SIZE + BLOCK_OVERHEAD + 100] ^= 1;
+        assert_eq!(unstored(encoded).unwrap_err().kind(), ErrorKind::InvalidData);
+
+        let encoded = stored(&data, "");
+        let truncated = encoded[..encoded.len() - END_MARKER_SIZE].to_vec();
+        assert_eq!(unstored(truncated).unwrap_err().kind(), ErrorKind::UnexpectedEof);
+    }
+}
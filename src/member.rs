@@ -0,0 +1,377 @@
+use std::io::{BufRead, ErrorKind, Read, Write};
+use crate::raw::DeflateWriter;
+use crate::{tags, ParamSet};
+
+/// Fields of the gzip member header taken from the writer's parameters, which `gunzip -N`
+/// restores. Empty strings and a zero mtime are left out of the header.
+#[derive(Debug, Clone, Default)]
+pub(crate) struct GzipHeader {
+    pub filename: String,
+    pub comment: String,
+    /// Unix seconds
+    pub mtime: u32,
+}
+
+impl GzipHeader {
+    /// The header fields from the `filename`, `comment` and `mtime` parameters
+    pub(crate) fn from_params(param_set: &ParamSet) -> Result<GzipHeader, Box<dyn std::error::Error>> {
+        return Ok(GzipHeader {
+            filename: param_set.get_string("filename", "").to_string(),
+            comment: param_set.get_string("comment", "").to_string(),
+            mtime: param_set.get_integer("mtime", 0)?,
+        });
+    }
+
+    /// Header of a member at `level` carrying these fields and the tags of `param_set`, the
+    /// bytes flate2's `GzBuilder` writes
+    pub(crate) fn encode(&self, param_set: &ParamSet, level: u32) -> Result<Vec<u8>, Box<dyn std::error::Error>> {
+        for (name, value) in [("filename", &self.filename), ("comment", &self.comment)] {
+            if value.contains('\0') {
+                return Err(format!("gzip {} cannot contain a NUL character", name).into());
+            }
+        }
+        let mut flags = 0u8;
+        let mut header = vec![0u8; 10];
+        if let Some(extra) = tags::gzip_extra(param_set)? {
+            flags |= 0x04;
+            header.extend_from_slice(&(extra.len() as u16).to_le_bytes());
+            header.extend_from_slice(&extra);
+        }
+        for (flag, value) in [(0x08, &self.filename), (0x10, &self.comment)] {
+            if !value.is_empty() {
+                flags |= flag;
+                header.extend_from_slice(value.as_bytes());
+                header.push(0);
+            }
+        }
+        header[..4].copy_from_slice(&[0x1f, 0x8b, 8, flags]);
+        header[4..8].copy_from_slice(&self.mtime.to_le_bytes());
+        // extra flags: 2 for the slowest level, 4 for the fastest
+        header[8] = match level {
+            9.. => 2,
+            ..=1 => 4,
+            _ => 0,
+        };
+        // unknown operating system, for output independent of the platform
+        header[9] = 255;
+        return Ok(header);
+    }
+}
+
+/// Gzip encoder adapter for `member_max_uncompressed`: ends the current member and starts a new
+/// one before a member holds more than `limit` uncompressed bytes. Some legacy readers (old
+/// Java `GZIPInputStream`, mainframe tools) mishandle members past 4 GiB, whose ISIZE field wraps.
+pub(crate) struct GzipMemberWriter {
+    encoder: Option<DeflateWriter<Box<dyn Write>>>,
+    limit: u64,
+    /// Uncompressed bytes in the current member
+    in_member: u64,
+    level: u32,
+    /// Encoded header of every member, which carries the fields and tags like the first one
+    header: Vec<u8>,
+}
+
+impl GzipMemberWriter {
+    pub(crate) fn new(out: Box<dyn Write>, limit: u64, level: u32, header: Vec<u8>) -> std::io::Result<GzipMemberWriter> {
+        let encoder = DeflateWriter::gzip(out, level, &header)?;
+        return Ok(GzipMemberWriter { encoder: Some(encoder), limit, in_member: 0, level, header });
+    }
+
+    fn encoder(&mut self) -> std::io::Result<&mut DeflateWriter<Box<dyn Write>>> {
+        return self.encoder.as_mut().ok_or_else(|| std::io::Error::other("encoder lost by a failed member boundary"));
+    }
+
+    fn next_member(&mut self) -> std::io::Result<()> {
+        let out = self.encoder.take().unwrap().finish()?;
+        self.encoder = Some(DeflateWriter::gzip(out, self.level, &self.header)?);
+        self.in_member = 0;
+        return Ok(());
+    }
+}
+
+impl Write for GzipMemberWriter {
+    fn write(&mut self, data: &[u8]) -> std::io::Result<usize> {
+        if data.is_empty() {
+            return Ok(0);
+        }
+        self.encoder()?;
+        // a member is only ended once more data comes, so the output never ends with an empty one
+        if self.in_member == self.limit {
+            self.next_member()?;
+        }
+        let room = (self.limit - self.in_member).min(data.len() as u64) as usize;
+        let written = self.encoder()?.write(&data[..room])?;
+        self.in_member += written as u64;
+        return Ok(written);
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        return self.encoder()?.flush();
+    }
+}
+
+/// Decoder reading the streams of concatenated files (`cat a.gz b.gz`, pigz, pbzip2, rotated
+/// logs) one after the other, like gunzip, bunzip2 and xz do, or only the first one without
+/// `concat`. It stops before anything that does not start with the `magic` of a stream, leaving
+/// it in the source `S` for the trailing data checks.
+pub(crate) struct Members<S, D> {
+    decoder: Option<D>,
+    /// Decoder of the next stream, given `memory_limit`
+    next: fn(S, u64) -> std::io::Result<D>,
+    source: fn(&mut D) -> &mut S,
+    into_source: fn(D) -> S,
+    magic: &'static [u8],
+    memory_limit: u64,
+    concat: bool,
+}
+
+impl<S: BufRead, D: Read> Members<S, D> {
+    pub(crate) fn source(&mut self) -> &mut S {
+        return (self.source)(self.decoder.as_mut().unwrap());
+    }
+
+    /// The source, past the last stream read
+    pub(crate) fn into_inner(mut self) -> S {
+        return (self.into_source)(self.decoder.take().unwrap());
+    }
+}
+
+/// gzip members of `src`
+pub(crate) fn gzip_members<S: BufRead>(src: S, concat: bool) -> Members<S, flate2::bufread::GzDecoder<S>> {
+    return Members {
+        decoder: Some(flate2::bufread::GzDecoder::new(src)),
+        next: |src, _| Ok(flate2::bufread::GzDecoder::new(src)),
+        source: |d| d.get_mut(),
+        into_source: |d| d.into_inner(),
+        magic: &[0x1f, 0x8b],
+        memory_limit: u64::MAX,
+        concat,
+    };
+}
+
+/// bzip2 streams of `src`
+pub(crate) fn bzip2_members<S: BufRead>(src: S, concat: bool) -> Members<S, bzip2::bufread::BzDecoder<S>> {
+    return Members {
+        decoder: Some(bzip2::bufread::BzDecoder::new(src)),
+        next: |src, _| Ok(bzip2::bufread::BzDecoder::new(src)),
+        source: |d| d.get_mut(),
+        into_source: |d| d.into_inner(),
+        magic: b"BZh",
+        memory_limit: u64::MAX,
+        concat,
+    };
+}
+
+/// xz streams of `src`, the first one decoded by `first`. Stream padding between streams is
+/// not skipped: it ends the data.
+pub(crate) fn xz_members<S: BufRead>(src: S, first: xz2::stream::Stream, memory_limit: u64, concat: bool) -> Members<S, XzStreamReader<S>> {
+    return Members {
+        decoder: Some(XzStreamReader { src, stream: first, ended: false }),
+        next: |src, memory_limit| Ok(XzStreamReader { src, stream: xz2::stream::Stream::new_stream_decoder(memory_limit, 0)?, ended: false }),
+        source: |d| &mut d.src,
+        into_source: |d| d.src,
+        magic: &[0xfd, b'7', b'z', b'X', b'Z', 0],
+        memory_limit,
+        concat,
+    };
+}
+
+/// Decoder of a single xz stream, taking no byte after its end, which `xz2::bufread::XzDecoder`
+/// reports as corrupt data
+pub(crate) struct XzStreamReader<S> {
+    src: S,
+    stream: xz2::stream::Stream,
+    ended: bool,
+}
+
+impl<S: BufRead> Read for XzStreamReader<S> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        if self.ended || buf.is_empty() {
+            return Ok(0);
+        }
+        loop {
+            let input = self.src.fill_buf()?;
+            let eof = input.is_empty();
+            let (before_in, before_out) = (self.stream.total_in(), self.stream.total_out());
+            let action = if eof { xz2::stream::Action::Finish } else { xz2::stream::Action::Run };
+            let status = self.stream.process(input, buf, action)?;
+            let consumed = (self.stream.total_in() - before_in) as usize;
+            let read = (self.stream.total_out() - before_out) as usize;
+            self.src.consume(consumed);
+            if status == xz2::stream::Status::StreamEnd {
+                self.ended = true;
+                return Ok(read);
+            }
+            if read > 0 {
+                return Ok(read);
+            }
+            if eof {
+                return Err(std::io::Error::new(ErrorKind::UnexpectedEof, "xz stream ended before its end"));
+            }
+            if consumed == 0 {
+                return Err(std::io::Error::new(ErrorKind::InvalidData, "corrupt xz stream"));
+            }
+        }
+    }
+}
+
+impl<S: BufRead, D: Read> Read for Members<S, D> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        loop {
+            let read = self.decoder.as_mut().unwrap().read(buf)?;
+            if read > 0 || buf.is_empty() || !self.concat {
+                return Ok(read);
+            }
+            let magic = self.magic;
+            let rest = self.source().fill_buf()?;
+            let compared = rest.len().min(magic.len());
+            if rest.is_empty() || rest[..compared] != magic[..compared] {
+                return Ok(0);
+            }
+            let src = (self.into_source)(self.decoder.take().unwrap());
+            self.decoder = Some((self.next)(src, self.memory_limit)?);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::{BufRead, Read, Write};
+    use crate::{compress_bytes, compressed_writer, decompressed_reader, decompressed_reader_with, CompressionType};
+
+    /// Compressed and uncompressed size of each member of `data`
+    fn split_members(data: &[u8]) -> Vec<(usize, usize)> {
+        let mut cursor = std::io::Cursor::new(data);
+        let mut members = Vec::new();
+        while !cursor.fill_buf().unwrap().is_empty() {
+            let start = cursor.position() as usize;
+            let mut decoder = flate2::bufread::GzDecoder::new(&mut cursor);
+            let plain = std::io::copy(&mut decoder, &mut std::io::sink()).unwrap() as usize;
+            drop(decoder);
+            members.push((cursor.position() as usize - start, plain));
+        }
+        return members;
+    }
+
+    #[test]
+    pub fn test_member_limit() {
+        let mib = 1024 * 1024;
+        let block: Vec<u8> = (0..mib).map(|i| (i % 251) as u8 ^ (i / 4096) as u8).collect();
+        let buffer = crate::buffer::SharedBuffer::default();
+        let mut w = compressed_writer(Box::new(buffer.clone()), CompressionType::Gzip, "level=1;member_max_uncompressed=10MiB").unwrap();
+        for _ in 0..35 {
+            w.write_all(&block).unwrap();
+        }
+        drop(w);
+        let compressed = buffer.take();
+
+        let members = split_members(&compressed);
+        let sizes: Vec<usize> = members.iter().map(|m| m.1).collect();
+        assert_eq!(sizes, [10 * mib, 10 * mib, 10 * mib, 5 * mib]);
+        let mut offset = 0;
+        for (length, plain) in members.iter() {
+            assert_eq!(&compressed[offset..offset + 2], &[0x1f, 0x8b]);
+            let isize = u32::from_le_bytes(compressed[offset + length - 4..offset + length].try_into().unwrap());
+            assert_eq!(isize as usize, *plain);
+            offset += length;
+        }
+        assert_eq!(offset, compressed.len());
+
+        let mut plain = Vec::new();
+        decompressed_reader(Box::new(std::io::Cursor::new(compressed)), CompressionType::Gzip).unwrap().read_to_end(&mut plain).unwrap();
+        assert_eq!(plain.len(), 35 * mib);
+        assert!(plain.chunks(mib).all(|chunk| chunk == block));
+
+        // exactly the limit gives one member, not a trailing empty one
+        let buffer = crate::buffer::SharedBuffer::default();
+        let mut w = compressed_writer(Box::new(buffer.clone()), CompressionType::Gzip, "member_max_uncompressed=1000").unwrap();
+        w.write_all(&block[..1000]).unwrap();
+        drop(w);
+        assert_eq!(split_members(&buffer.take()).len(), 1);
+    }
+
+    #[test]
+    pub fn test_header_fields() {
+        let params = "filename=%%:q3%3Breport.csv;mtime=1700000000;comment=nightly export;member_max_uncompressed=1000";
+        let buffer = crate::buffer::SharedBuffer::default();
+        let mut w = compressed_writer(Box::new(buffer.clone()), CompressionType::Gzip, params).unwrap();
+        w.write_all(&[b'x'; 2500]).unwrap();
+        drop(w);
+        let compressed = buffer.take();
+        let members = split_members(&compressed);
+        assert_eq!(members.len(), 3);
+        let mut offset = 0;
+        for (length, _) in members {
+            let mut decoder = flate2::read::GzDecoder::new(&compressed[offset..offset + length]);
+            std::io::copy(&mut decoder, &mut std::io::sink()).unwrap();
+            let header = decoder.header().unwrap();
+            assert_eq!(header.filename(), Some(&b"q3;report.csv"[..]));
+            assert_eq!(header.comment(), Some(&b"nightly export"[..]));
+            assert_eq!(header.mtime(), 1_700_000_000);
+            offset += length;
+        }
+
+        let buffer = crate::buffer::SharedBuffer::default();
+        drop(compressed_writer(Box::new(buffer.clone()), CompressionType::Gzip, "").unwrap());
+        let compressed = buffer.take();
+        let decoder = flate2::read::GzDecoder::new(&compressed[..]);
+        let header = decoder.header().unwrap();
+        assert_eq!((header.filename(), header.comment(), header.mtime()), (None, None, 0));
+
+        assert!(compressed_writer(Box::new(std::io::sink()), CompressionType::Gzip, "filename=%%:a%00b").is_err());
+        assert!(compressed_writer(Box::new(std::io::sink()), CompressionType::Gzip, "mtime=-1").is_err());
+    }
+
+    fn decompress(ct: CompressionType, compressed: &[u8], option: &str) -> std::io::Result<Vec<u8>> {
+        let mut plain = Vec::new();
+        decompressed_reader_with(Box::new(std::io::Cursor::new(compressed.to_vec())), ct, option).unwrap()
+            .read_to_end(&mut plain)?;
+        return Ok(plain);
+    }
+
+    fn gzip(data: &[u8]) -> Vec<u8> {
+        return compress_bytes(data, CompressionType::Gzip, "").unwrap();
+    }
+
+    fn gunzip(compressed: &[u8], option: &str) -> std::io::Result<Vec<u8>> {
+        return decompress(CompressionType::Gzip, compressed, option);
+    }
+
+    #[test]
+    pub fn test_concatenated_members() {
+        // cat a.gz b.gz empty.gz > both.gz
+        let a = b"first rotated log\n".repeat(3000);
+        let b = b"second rotated log\n".repeat(3000);
+        let both = [gzip(&a), gzip(&b), gzip(b"")].concat();
+        assert_eq!(gunzip(&both, "").unwrap(), [a.clone(), b.clone()].concat());
+        assert_eq!(gunzip(&both, "trailing_data=error").unwrap(), [a.clone(), b.clone()].concat());
+        assert_eq!(gunzip(&both, "concat=false").unwrap(), a);
+        assert!(gunzip(&both, "concat=false;trailing_data=error").is_err());
+        assert!(decompressed_reader_with(Box::new(std::io::empty()), CompressionType::Gzip, "concat=maybe").is_err());
+
+        // garbage after the last member
+        let garbage = [both.clone(), b"not gzip at all".to_vec()].concat();
+        assert_eq!(gunzip(&garbage, "").unwrap(), [a.clone(), b.clone()].concat());
+        let err = gunzip(&garbage, "trailing_data=error").unwrap_err();
+        assert!(err.to_string().contains("trailing data"), "{}", err);
+        // something that looks like a member must be one
+        assert!(gunzip(&[both, vec![0x1f, 0x8b, 0, 0]].concat(), "").is_err());
+    }
+
+    #[test]
+    pub fn test_concatenated_streams() {
+        let a = b"first chunk of a parallel compressor\n".repeat(2000);
+        let b = b"second chunk of a parallel compressor\n".repeat(2000);
+        for ct in [CompressionType::Bzip2, CompressionType::XZ] {
+            let both = [compress_bytes(&a, ct, "").unwrap(), compress_bytes(&b, ct, "").unwrap()].concat();
+            assert!(decompress(ct, &both, "").unwrap() == [a.clone(), b.clone()].concat(), "{}", ct);
+            assert!(decompress(ct, &both, "trailing_data=error").unwrap() == [a.clone(), b.clone()].concat(), "{}", ct);
+            assert!(decompress(ct, &both, "concat=false").unwrap() == a, "{}", ct);
+            assert!(decompress(ct, &both, "concat=false;trailing_data=error").is_err(), "{}", ct);
+
+            let garbage = [both, b"not compressed".to_vec()].concat();
+            assert!(decompress(ct, &garbage, "").unwrap() == [a.clone(), b.clone()].concat(), "{}", ct);
+            assert!(decompress(ct, &garbage, "trailing_data=error").is_err(), "{}", ct);
+        }
+    }
+}
@@ -0,0 +1,243 @@
+use std::collections::hash_map::DefaultHasher;
+use std::collections::{BTreeMap, HashMap};
+use std::error::Error;
+use std::hash::{Hash, Hasher};
+use std::io::Write;
+use std::sync::{Arc, Mutex};
+use crate::buffer::SharedBuffer;
+use crate::{compressed_writer, CompressionType, ParamSet};
+
+/// Counters of a `CompressionCache`
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct CacheStats {
+    pub hits: u64,
+    pub misses: u64,
+    /// Entries dropped to stay within the byte capacity
+    pub evictions: u64,
+    /// Uncompressed bytes served from the cache instead of being compressed
+    pub bytes_saved: u64,
+    /// Entries currently cached
+    pub entries: usize,
+    /// Compressed bytes currently cached
+    pub bytes: u64,
+}
+
+struct Entry {
+    data: Arc<Vec<u8>>,
+    /// Tick of the last use, the key of the entry in `Inner::recency`
+    last_used: u64,
+}
+
+#[derive(Default)]
+struct Inner {
+    entries: HashMap<Vec<u8>, Entry>,
+    /// Keys by last use, oldest first
+    recency: BTreeMap<u64, Vec<u8>>,
+    tick: u64,
+    stats: CacheStats,
+}
+
+impl Inner {
+    fn touch(&mut self, key: &[u8]) -> Option<Arc<Vec<u8>>> {
+        self.tick += 1;
+        let tick = self.tick;
+        let entry = self.entries.get_mut(key)?;
+        let previous = std::mem::replace(&mut entry.last_used, tick);
+        let data = entry.data.clone();
+        let key = self.recency.remove(&previous).unwrap();
+        self.recency.insert(tick, key);
+        return Some(data);
+    }
+
+    fn remove(&mut self, key: &[u8]) -> bool {
+        let Some(entry) = self.entries.remove(key) else {
+            return false;
+        };
+        self.recency.remove(&entry.last_used);
+        self.stats.entries -= 1;
+        self.stats.bytes -= entry.data.len() as u64;
+        return true;
+    }
+}
+
+/// Thread-safe LRU cache of compressed payloads, bounded by the total compressed bytes it holds,
+/// for services compressing the same inputs over and over.
+///
+/// Entries are shared through `Arc`, so hits do not copy. Compression runs outside the lock:
+/// threads missing the same key at the same time each compress it, and the last one's result
+/// stays cached.
+pub struct CompressionCache {
+    capacity: u64,
+    inner: Mutex<Inner>,
+}
+
+impl CompressionCache {
+    /// Cache holding at most `capacity` compressed bytes. Larger outputs are returned uncached.
+    pub fn new(capacity: u64) -> CompressionCache {
+        return CompressionCache { capacity, inner: Mutex::new(Inner::default()) };
+    }
+
+    fn lock(&self) -> std::sync::MutexGuard<'_, Inner> {
+        return self.inner.lock().unwrap_or_else(|e| e.into_inner());
+    }
+
+    /// Compressed form of `data` with `compression_type` and the parameters of
+    /// `compressed_writer`, from the cache when present.
+    ///
+    /// `key` identifies the entry; it must change whenever `data`, the codec or the parameters
+    /// do. Without one, the key is a 128 bit hash of `data`, the codec and the parameters in
+    /// their canonical `Display` form.
+    pub fn get_or_compress<T: Into<ParamSet>>(&self, key: Option<&[u8]>, data: &[u8], compression_type: CompressionType, option: T) -> Result<Arc<Vec<u8>>, Box<dyn Error>> {
+        let param_set: ParamSet = option.into();
+        let key = match key {
+            Some(key) => explicit_key(key),
+            None => content_key(data, compression_type, &param_set),
+        };
+        {
+            let mut inner = self.lock();
+            if let Some(compressed) = inner.touch(&key) {
+                inner.stats.hits += 1;
+                inner.stats.bytes_saved += data.len() as u64;
+                return Ok(compressed);
+            }
+            inner.stats.misses += 1;
+        }
+        let buffer = SharedBuffer::default();
+        let mut w = compressed_writer(Box::new(buffer.clone()), compression_type, param_set)?;
+        w.write_all(data)?;
+        drop(w);
+        let compressed = Arc::new(buffer.take());
+        if compressed.len() as u64 > self.capacity {
+            return Ok(compressed);
+        }
+        let mut inner = self.lock();
+        inner.remove(&key);
+        while inner.stats.bytes + compressed.len() as u64 > self.capacity {
+            let (_, oldest) = inner.recency.pop_first().unwrap();
+            let evicted = inner.entries.remove(&oldest).unwrap();
+            inner.stats.entries -= 1;
+            inner.stats.bytes -= evicted.data.len() as u64;
+            inner.stats.evictions += 1;
+        }
+        inner.tick += 1;
+        let tick = inner.tick;
+        inner.recency.insert(tick, key.clone());
+        inner.stats.entries += 1;
+        inner.stats.bytes += compressed.len() as u64;
+        inner.entries.insert(key, Entry { data: compressed.clone(), last_used: tick });
+        return Ok(compressed);
+    }
+
+    /// Drop the entry stored under the explicit `key`, returning whether there was one
+    pub fn invalidate(&self, key: &[u8]) -> bool {
+        return self.lock().remove(&explicit_key(key));
+    }
+
+    /// Drop every entry; the counters are kept
+    pub fn clear(&self) {
+        let mut inner = self.lock();
+        inner.entries.clear();
+        inner.recency.clear();
+        inner.stats.entries = 0;
+        inner.stats.bytes = 0;
+    }
+
+    pub fn stats(&self) -> CacheStats {
+        return self.lock().stats;
+    }
+}
+
+/// Explicit keys and content hashes live in separate namespaces
+fn explicit_key(key: &[u8]) -> Vec<u8> {
+    let mut tagged = Vec::with_capacity(key.len() + 1);
+    tagged.push(1);
+    tagged.extend_from_slice(key);
+    return tagged;
+}
+
+fn content_key(data: &[u8], compression_type: CompressionType, param_set: &ParamSet) -> Vec<u8> {
+    let mut key = vec![0];
+    for seed in [0x9e37_79b9_7f4a_7c15u64, 0xc2b2_ae3d_27d4_eb4f] {
+        let mut hasher = DefaultHasher::new();
+        seed.hash(&mut hasher);
+        compression_type.hash(&mut hasher);
+        param_set.to_string().hash(&mut hasher);
+        data.hash(&mut hasher);
+        key.extend_from_slice(&hasher.finish().to_le_bytes());
+    }
+    return key;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn payload(i: usize) -> Vec<u8> {
+        return format!("<html><body>template {} rendered for everyone</body></html>\n", i).repeat(200).into_bytes();
+    }
+
+    #[test]
+    pub fn test_concurrent_hits() {
+        let cache = Arc::new(CompressionCache::new(1 << 20));
+        let threads: Vec<_> = (0..8).map(|t| {
+            let cache = cache.clone();
+            std::thread::spawn(move || {
+                for i in 0..500 {
+                    let data = payload((i + t) % 10);
+                    let compressed = cache.get_or_compress(None, &data, CompressionType::Zstd, "level=3").unwrap();
+                    let mut plain = Vec::new();
+                    std::io::Read::read_to_end(&mut crate::decompressed_reader(
+                        Box::new(std::io::Cursor::new(compressed.to_vec())), CompressionType::Zstd).unwrap(), &mut plain).unwrap();
+                    assert!(plain == data);
+                }
+            })
+        }).collect();
+        for thread in threads {
+            thread.join().unwrap();
+        }
+        let stats = cache.stats();
+        assert_eq!(stats.hits + stats.misses, 4000);
+        assert!(stats.misses >= 10 && stats.misses <= 80, "{:?}", stats);
+        assert_eq!(stats.entries, 10);
+        assert_eq!(stats.evictions, 0);
+        assert!(stats.bytes_saved >= stats.hits * payload(0).len() as u64);
+    }
+
+    #[test]
+    pub fn test_eviction_at_byte_cap() {
+        let one = CompressionCache::new(u64::MAX).get_or_compress(None, &payload(1), CompressionType::Gzip, "").unwrap().len() as u64;
+        let cache = CompressionCache::new(2 * one + one / 2);
+        let first = cache.get_or_compress(Some(b"a"), &payload(1), CompressionType::Gzip, "").unwrap();
+        cache.get_or_compress(Some(b"b"), &payload(2), CompressionType::Gzip, "").unwrap();
+        // a is now more recent than b
+        assert!(Arc::ptr_eq(&first, &cache.get_or_compress(Some(b"a"), &payload(1), CompressionType::Gzip, "").unwrap()));
+        cache.get_or_compress(Some(b"c"), &payload(3), CompressionType::Gzip, "").unwrap();
+        let stats = cache.stats();
+        assert_eq!((stats.entries, stats.evictions, stats.hits), (2, 1, 1));
+        assert!(stats.bytes <= 2 * one + one / 2);
+        assert!(!cache.invalidate(b"b"));
+        assert!(cache.invalidate(b"a"));
+        assert_eq!(cache.stats().entries, 1);
+        cache.clear();
+        assert_eq!((cache.stats().entries, cache.stats().bytes), (0, 0));
+
+        let tiny = CompressionCache::new(10);
+        tiny.get_or_compress(None, &payload(1), CompressionType::Gzip, "").unwrap();
+        assert_eq!(tiny.stats().entries, 0);
+    }
+
+    #[test]
+    pub fn test_params_are_part_of_the_key() {
+        let cache = CompressionCache::new(1 << 20);
+        let data = payload(4);
+        let fast = cache.get_or_compress(None, &data, CompressionType::Gzip, "level=1").unwrap();
+        let best = cache.get_or_compress(None, &data, CompressionType::Gzip, "level=9").unwrap();
+        cache.get_or_compress(None, &data, CompressionType::Zstd, "level=1").unwrap();
+        assert!(!Arc::ptr_eq(&fast, &best));
+        assert_eq!(cache.stats().misses, 3);
+        // the canonical form ignores the order of the parameters
+        cache.get_or_compress(None, &data, CompressionType::Zstd, "level=1;note=x").unwrap();
+        cache.get_or_compress(None, &data, CompressionType::Zstd, "note=x;level=1").unwrap();
+        assert_eq!((cache.stats().misses, cache.stats().hits), (4, 1));
+    }
+}
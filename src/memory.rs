@@ -0,0 +1,286 @@
+use std::alloc::{GlobalAlloc, Layout, System};
+use std::cell::Cell;
+use std::error::Error;
+use std::io::{Read, Write};
+use crate::{compressed_writer, decompressed_reader_with, CompressionType, ParamSet};
+
+/// Global allocator counting the allocations of threads that enabled tracking. Installed by the
+/// `alloc-track` feature; threads not measuring pay one thread local lookup per allocation.
+pub struct TrackingAllocator;
+
+#[global_allocator]
+static ALLOCATOR: TrackingAllocator = TrackingAllocator;
+
+thread_local! {
+    static TRACKING: Cell<bool> = const { Cell::new(false) };
+    static CURRENT: Cell<usize> = const { Cell::new(0) };
+    static PEAK: Cell<usize> = const { Cell::new(0) };
+    static ALLOCATIONS: Cell<u64> = const { Cell::new(0) };
+}
+
+fn record_alloc(size: usize) {
+    let _ = TRACKING.try_with(|tracking| {
+        if !tracking.get() {
+            return;
+        }
+        let current = CURRENT.with(|c| {
+            c.set(c.get() + size);
+            c.get()
+        });
+        PEAK.with(|p| p.set(p.get().max(current)));
+        ALLOCATIONS.with(|a| a.set(a.get() + 1));
+    });
+}
+
+fn record_free(size: usize) {
+    let _ = TRACKING.try_with(|tracking| {
+        if tracking.get() {
+            // memory allocated before tracking started may be freed while tracking
+            CURRENT.with(|c| c.set(c.get().saturating_sub(size)));
+        }
+    });
+}
+
+unsafe impl GlobalAlloc for TrackingAllocator {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        let ptr = System.alloc(layout);
+        if !ptr.is_null() {
+            record_alloc(layout.size());
+        }
+        return ptr;
+    }
+
+    unsafe fn alloc_zeroed(&self, layout: Layout) -> *mut u8 {
+        let ptr = System.alloc_zeroed(layout);
+        if !ptr.is_null() {
+            record_alloc(layout.size());
+        }
+        return ptr;
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        record_free(layout.size());
+        System.dealloc(ptr, layout);
+    }
+
+    unsafe fn realloc(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+        let new_ptr = System.realloc(ptr, layout, new_size);
+        if !new_ptr.is_null() {
+            record_free(layout.size());
+            record_alloc(new_size);
+        }
+        return new_ptr;
+    }
+}
+
+/// `f` run with tracking on this thread, and the peak of the heap it allocated
+#[cfg(test)]
+pub(crate) fn peak_during<T>(f: impl FnOnce() -> T) -> (T, u64) {
+    CURRENT.with(|c| c.set(0));
+    PEAK.with(|p| p.set(0));
+    TRACKING.with(|t| t.set(true));
+    let result = f();
+    TRACKING.with(|t| t.set(false));
+    return (result, PEAK.with(|p| p.get()) as u64);
+}
+
+/// What `measure_memory` runs
+#[derive(Debug, Clone, Copy)]
+pub enum MemoryWorkload<'a> {
+    /// Compress these bytes
+    Compress(&'a [u8]),
+    /// Decompress these compressed bytes
+    Decompress(&'a [u8]),
+}
+
+/// Memory used by one workload
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct MemoryReport {
+    /// Peak of the Rust heap allocated by the workload
+    pub peak_bytes: u64,
+    /// Number of Rust heap allocations of the workload
+    pub total_allocations: u64,
+    /// Memory allocated by the C library behind the codec, which bypasses the Rust allocator,
+    /// as reported by the library itself. None for pure Rust codecs and where the library
+    /// cannot tell.
+    pub backend_estimate: Option<u64>,
+}
+
+impl MemoryReport {
+    /// `peak_bytes` plus `backend_estimate`
+    pub fn total_bytes(&self) -> u64 {
+        return self.peak_bytes + self.backend_estimate.unwrap_or(0);
+    }
+}
+
+/// Measure the peak memory of compressing or decompressing with the given codec and parameters.
+///
+/// The workload runs on a dedicated thread, so allocations of other threads are not counted.
+/// zstd, xz and bzip2 allocate their state with the C allocator; for those `backend_estimate`
+/// adds the state size reported by the library for the same workload. For xz decompression pass
+/// the `level` the stream was written with.
+pub fn measure_memory<T: Into<ParamSet>>(
+    compression_type: CompressionType,
+    option: T,
+    workload: MemoryWorkload) -> Result<MemoryReport, Box<dyn Error>> {
+    let param_set: ParamSet = option.into();
+    let measured = std::thread::scope(|scope| {
+        return scope.spawn(|| run_tracked(compression_type, &param_set, workload).map_err(|e| e.to_string()))
+            .join()
+            .unwrap_or_else(|_| Err("workload panicked".into()));
+    });
+    let (peak_bytes, total_allocations) = measured?;
+    return Ok(MemoryReport {
+        peak_bytes,
+        total_allocations,
+        backend_estimate: backend_estimate(compression_type, &param_set, workload)?,
+    });
+}
+
+fn run_tracked(compression_type: CompressionType, param_set: &ParamSet, workload: MemoryWorkload) -> Result<(u64, u64), Box<dyn Error>> {
+    // input copies are made before tracking starts
+    let source: Option<Box<dyn Read>> = match workload {
+        MemoryWorkload::Decompress(data) => Some(Box::new(std::io::Cursor::new(data.to_vec()))),
+        MemoryWorkload::Compress(_) => None,
+    };
+    CURRENT.with(|c| c.set(0));
+    PEAK.with(|p| p.set(0));
+    ALLOCATIONS.with(|a| a.set(0));
+    TRACKING.with(|t| t.set(true));
+    let result = run_workload(compression_type, param_set, workload, source);
+    TRACKING.with(|t| t.set(false));
+    result?;
+    return Ok((PEAK.with(|p| p.get()) as u64, ALLOCATIONS.with(|a| a.get())));
+}
+
+fn run_workload(
+    compression_type: CompressionType,
+    param_set: &ParamSet,
+    workload: MemoryWorkload,
+    source: Option<Box<dyn Read>>) -> Result<(), Box<dyn Error>> {
+    let params = param_set.clone();
+    match (workload, source) {
+        (MemoryWorkload::Compress(data), _) => {
+            let mut writer = compressed_writer(Box::new(std::io::sink()), compression_type, params)?;
+            writer.write_all(data)?;
+            writer.flush()?;
+        },
+        (MemoryWorkload::Decompress(_), Some(source)) => {
+            let mut reader = decompressed_reader_with(source, compression_type, params)?;
+            std::io::copy(&mut reader, &mut std::io::sink())?;
+        },
+        (MemoryWorkload::Decompress(_), None) => unreachable!(),
+    }
+    return Ok(());
+}
+
+fn backend_estimate(compression_type: CompressionType, param_set: &ParamSet, workload: MemoryWorkload) -> Result<Option<u64>, Box<dyn Error>> {
+    match (compression_type, workload) {
+        (CompressionType::Zstd, MemoryWorkload::Compress(data)) => {
+            let mut context = zstd::zstd_safe::CCtx::create();
+            context.set_parameter(zstd::zstd_safe::CParameter::CompressionLevel(param_set.get_integer("level", 3)?))
+                .map_err(|code| zstd::zstd_safe::get_error_name(code))?;
+            let mut output = Vec::with_capacity(zstd::zstd_safe::compress_bound(data.len()));
+            let mut output = zstd::zstd_safe::OutBuffer::around(&mut output);
+            let mut input = zstd::zstd_safe::InBuffer::around(data);
+            // the writer streams without knowing the size, so the size is not pledged here either
+            let _ = context.compress_stream2(&mut output, &mut input, zstd::zstd_safe::zstd_sys::ZSTD_EndDirective::ZSTD_e_continue);
+            return Ok(Some(context.sizeof() as u64));
+        },
+        (CompressionType::Zstd, MemoryWorkload::Decompress(data)) => {
+            let mut context = zstd::zstd_safe::DCtx::create();
+            let mut buffer = vec![0u8; 128 * 1024];
+            let mut input = zstd::zstd_safe::InBuffer::around(data);
+            while input.pos() < data.len() {
+                let mut output = zstd::zstd_safe::OutBuffer::around(&mut buffer[..]);
+                if context.decompress_stream(&mut output, &mut input).is_err() {
+                    break;
+                }
+            }
+            return Ok(Some(context.sizeof() as u64));
+        },
+        (CompressionType::XZ, workload) => {
+            // the decoder needs about the memory of the level the stream was written with,
+            // which the caller passes as `level` as well
+            let level: u32 = param_set.get_integer("level", 6)?;
+            // SAFETY: pure functions of the preset
+            let usage = unsafe {
+                match workload {
+                    MemoryWorkload::Compress(_) => lzma_sys::lzma_easy_encoder_memusage(level),
+                    MemoryWorkload::Decompress(_) => lzma_sys::lzma_easy_decoder_memusage(level),
+                }
+            };
+            return Ok(Some(usage).filter(|u| *u != u64::MAX));
+        },
+        (CompressionType::Bzip2, MemoryWorkload::Compress(_)) => {
+            // figures of the bzip2 manual: 400k + 8 x block size
+            let level: u64 = param_set.get_integer("level", 3)?;
+            return Ok(Some(400_000 + 8 * 100_000 * level));
+        },
+        (CompressionType::Bzip2, MemoryWorkload::Decompress(data)) => {
+            // 100k + 4 x block size, the block size digit follows the "BZh" magic
+            let level = data.get(3).filter(|d| d.is_ascii_digit()).map(|d| (d - b'0') as u64).unwrap_or(9);
+            return Ok(Some(100_000 + 4 * 100_000 * level));
+        },
+        _ => return Ok(None),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::compress_bytes;
+    use crate::describe::BUILTIN_CODECS;
+
+    fn sample() -> Vec<u8> {
+        return (0..1_000_000u32).map(|i| ((i % 251) as u8) ^ ((i / 3000) as u8)).collect();
+    }
+
+    #[test]
+    pub fn test_zstd_levels() {
+        let data = sample();
+        let fast = measure_memory(CompressionType::Zstd, "level=1", MemoryWorkload::Compress(&data)).unwrap();
+        let strong = measure_memory(CompressionType::Zstd, "level=19", MemoryWorkload::Compress(&data)).unwrap();
+        assert!(strong.total_bytes() > fast.total_bytes(), "{:?} vs {:?}", strong, fast);
+    }
+
+    #[test]
+    pub fn test_all_codecs() {
+        let data = sample();
+        for codec in BUILTIN_CODECS {
+            let ct = codec.compression_type;
+            let compress_report = measure_memory(ct, "", MemoryWorkload::Compress(&data)).unwrap();
+            let compressed = compress_bytes(&data, ct, "").unwrap();
+            let decompress_report = measure_memory(ct, "", MemoryWorkload::Decompress(&compressed)).unwrap();
+            if ct != CompressionType::None {
+                assert!(compress_report.total_bytes() > 0, "{}", codec.name);
+                assert!(decompress_report.total_bytes() > 0, "{}", codec.name);
+            }
+        }
+    }
+
+    #[test]
+    pub fn test_declared_content_size_not_allocated() {
+        let frame = crate::tests::zstd_frame_declaring(b"a frame declaring a terabyte", 1 << 40);
+        let (result, peak) = peak_during(|| crate::decompress_bytes(&frame, CompressionType::Zstd));
+        assert!(result.unwrap_err().downcast_ref::<crate::ContentSizeMismatch>().is_some());
+        assert!(peak < 1 << 20, "{} bytes allocated", peak);
+    }
+
+    #[test]
+    pub fn test_other_threads_not_counted() {
+        let (started, release) = (std::sync::Barrier::new(2), std::sync::Barrier::new(2));
+        std::thread::scope(|scope| {
+            scope.spawn(|| {
+                let hog = vec![1u8; 64 * 1024 * 1024];
+                started.wait();
+                release.wait();
+                drop(hog);
+            });
+            started.wait();
+            let report = measure_memory(CompressionType::None, "", MemoryWorkload::Compress(b"small")).unwrap();
+            release.wait();
+            assert!(report.peak_bytes < 1024 * 1024, "{:?}", report);
+        });
+    }
+}
@@ -0,0 +1,224 @@
+use std::error::Error;
+use std::io::{Read, Write};
+use crate::{build_decoder, build_encoder, CompressionType, InvalidParam, ParamSet};
+
+/// Largest number of bytes `minimal_overhead=true` adds to a 100 byte payload that does not
+/// compress at all, per codec: raw deflate for gzip, zlib and deflate
+pub const MINIMAL_DEFLATE_OVERHEAD: usize = 5;
+/// As `MINIMAL_DEFLATE_OVERHEAD`, for zstd
+pub const MINIMAL_ZSTD_OVERHEAD: usize = 6;
+/// As `MINIMAL_DEFLATE_OVERHEAD`, for lz4
+pub const MINIMAL_LZ4_OVERHEAD: usize = 6;
+/// As `MINIMAL_DEFLATE_OVERHEAD`, for snappy
+pub const MINIMAL_SNAPPY_OVERHEAD: usize = 3;
+
+/// Whether `minimal_overhead=true` is set, on the writer and on the reader alike.
+///
+/// Tiny payloads, like 100 to 300 byte messages, are then written in the leanest standard
+/// representation of their codec:
+/// - gzip, zlib and deflate: raw deflate, without header nor trailer
+/// - zstd: frames without magic number, checksum, dictionary id nor content size
+/// - lz4: a single block, prefixed with its 4 byte uncompressed size
+/// - snappy: raw snappy, a single block
+///
+/// Other codecs are unaffected. These outputs are not self-describing: the reader must be
+/// created for the same compression type with `minimal_overhead=true` as well, and there is no
+/// checksum. lz4 and snappy keep the whole payload in memory and write it when the writer is
+/// dropped.
+pub(crate) fn is_minimal(param_set: &ParamSet) -> Result<bool, InvalidParam> {
+    return param_set.get_flag("minimal_overhead", false);
+}
+
+/// Writer in the minimal representation of `compression_type`, the usual one if it has no leaner one
+pub(crate) fn minimal_encoder(out: Box<dyn Write>, compression_type: CompressionType, param_set: &ParamSet) -> Result<Box<dyn Write>, Box<dyn Error>> {
+    match compression_type {
+        CompressionType::Gzip | CompressionType::Zlib | CompressionType::Deflate => {
+            let level = param_set.get_integer("level", 3)?;
+            return Ok(Box::new(flate2::write::DeflateEncoder::new(out, flate2::Compression::new(level))));
+        },
+        CompressionType::Zstd => {
+            // a magicless frame is a frame without its leading magic number
+            let out = Box::new(SkipPrefix { out, skip: ZSTD_MAGIC.len() });
+            let dictionary = crate::zstd_dictionary(param_set, param_set.get_string("dict_path", ""))?;
+            let mut encoder = zstd::Encoder::with_dictionary(out, param_set.get_integer("level", 3)?, &dictionary)?;
+            encoder.include_checksum(false)?;
+            encoder.include_dictid(false)?;
+            return Ok(Box::new(encoder.auto_finish()));
+        },
+        CompressionType::LZ4 => {
+            return Ok(lz4_block_writer(out));
+        },
+        CompressionType::Snappy => {
+            return Ok(snappy_block_writer(out));
+        },
+        _ => return build_encoder(out, compression_type, param_set),
+    }
+}
+
+/// Reader of the minimal representation of `compression_type`, the usual one if it has no leaner one
+pub(crate) fn minimal_decoder(src: Box<dyn Read>, compression_type: CompressionType, param_set: &ParamSet, strict: bool) -> Result<Box<dyn Read>, Box<dyn Error>> {
+    match compression_type {
+        CompressionType::Gzip | CompressionType::Zlib | CompressionType::Deflate => {
+            return Ok(Box::new(flate2::read::DeflateDecoder::new(src)));
+        },
+        CompressionType::Zstd => {
+            let src = std::io::Cursor::new(ZSTD_MAGIC).chain(src);
+            let dictionary = crate::zstd_dictionary(param_set, param_set.get_string("dict_path", ""))?;
+            return Ok(Box::new(zstd::Decoder::with_dictionary(std::io::BufReader::new(src), &dictionary)?));
+        },
+        CompressionType::LZ4 => {
+            return Ok(lz4_block_reader(src));
+        },
+        CompressionType::Snappy => {
+            return Ok(snappy_block_reader(src));
+        },
+        _ => return build_decoder(src, compression_type, param_set, strict),
+    }
+}
+
+/// Writer of a single lz4 block, prefixed with its 4 byte uncompressed size, written on drop
+pub(crate) fn lz4_block_writer(out: Box<dyn Write>) -> Box<dyn Write> {
+    let compress = |data: &[u8]| lz4::block::compress(data, None, true);
+    return Box::new(BlockWriter { out, data: Vec::new(), compress });
+}
+
+/// Reader of what `lz4_block_writer` writes
+pub(crate) fn lz4_block_reader(src: Box<dyn Read>) -> Box<dyn Read> {
+    let decompress = |data: &[u8]| lz4::block::decompress(data, None);
+    return Box::new(BlockReader { src: Some(src), decoded: std::io::Cursor::new(Vec::new()), decompress });
+}
+
+/// Writer of a single raw snappy block, written on drop
+pub(crate) fn snappy_block_writer(out: Box<dyn Write>) -> Box<dyn Write> {
+    let compress = |data: &[u8]| snap::raw::Encoder::new().compress_vec(data).map_err(std::io::Error::from);
+    return Box::new(BlockWriter { out, data: Vec::new(), compress });
+}
+
+/// Reader of what `snappy_block_writer` writes
+pub(crate) fn snappy_block_reader(src: Box<dyn Read>) -> Box<dyn Read> {
+    let decompress = |data: &[u8]| snap::raw::Decoder::new().decompress_vec(data).map_err(std::io::Error::from);
+    return Box::new(BlockReader { src: Some(src), decoded: std::io::Cursor::new(Vec::new()), decompress });
+}
+
+const ZSTD_MAGIC: [u8; 4] = [0x28, 0xb5, 0x2f, 0xfd];
+
+/// Drops the first `skip` bytes written
+struct SkipPrefix {
+    out: Box<dyn Write>,
+    skip: usize,
+}
+
+impl Write for SkipPrefix {
+    fn write(&mut self, data: &[u8]) -> std::io::Result<usize> {
+        let skipped = self.skip.min(data.len());
+        if skipped == data.len() {
+            self.skip -= skipped;
+            return Ok(skipped);
+        }
+        let written = self.out.write(&data[skipped..])?;
+        self.skip -= skipped;
+        return Ok(skipped + written);
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        return self.out.flush();
+    }
+}
+
+/// Collects the payload and writes it as a single block on drop
+struct BlockWriter {
+    out: Box<dyn Write>,
+    data: Vec<u8>,
+    compress: fn(&[u8]) -> std::io::Result<Vec<u8>>,
+}
+
+impl Write for BlockWriter {
+    fn write(&mut self, data: &[u8]) -> std::io::Result<usize> {
+        self.data.extend_from_slice(data);
+        return Ok(data.len());
+    }
+
+    /// The block can only be written once the payload is complete, on drop
+    fn flush(&mut self) -> std::io::Result<()> {
+        return Ok(());
+    }
+}
+
+impl Drop for BlockWriter {
+    fn drop(&mut self) {
+        if let Ok(block) = (self.compress)(&self.data) {
+            let _ = self.out.write_all(&block).and_then(|_| self.out.flush());
+        }
+    }
+}
+
+/// Reads the whole source as a single block on the first read
+struct BlockReader {
+    src: Option<Box<dyn Read>>,
+    decoded: std::io::Cursor<Vec<u8>>,
+    decompress: fn(&[u8]) -> std::io::Result<Vec<u8>>,
+}
+
+impl Read for BlockReader {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        if let Some(mut src) = self.src.take() {
+            let mut block = Vec::new();
+            src.read_to_end(&mut block)?;
+            self.decoded = std::io::Cursor::new((self.decompress)(&block)?);
+        }
+        return self.decoded.read(buf);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{compressed_writer, decompressed_reader_with};
+
+    fn round_trip(ct: CompressionType, payload: &[u8], options: &str) -> usize {
+        let buffer = crate::buffer::SharedBuffer::default();
+        let mut w = compressed_writer(Box::new(buffer.clone()), ct, options).unwrap();
+        w.write_all(payload).unwrap();
+        drop(w);
+        let compressed = buffer.take();
+        let mut decoded = Vec::new();
+        decompressed_reader_with(Box::new(std::io::Cursor::new(compressed.clone())), ct, options).unwrap()
+            .read_to_end(&mut decoded).unwrap();
+        assert_eq!(decoded, payload, "{:?} {}", ct, options);
+        return compressed.len();
+    }
+
+    #[test]
+    pub fn test_overhead() {
+        let mut state = 0x9e3779b97f4a7c15u64;
+        let noise: Vec<u8> = (0..100).map(|_| {
+            state ^= state << 13;
+            state ^= state >> 7;
+            state ^= state << 17;
+            (state >> 24) as u8
+        }).collect();
+        for (ct, bound) in [
+            (CompressionType::Gzip, MINIMAL_DEFLATE_OVERHEAD),
+            (CompressionType::Zlib, MINIMAL_DEFLATE_OVERHEAD),
+            (CompressionType::Deflate, MINIMAL_DEFLATE_OVERHEAD),
+            (CompressionType::Zstd, MINIMAL_ZSTD_OVERHEAD),
+            (CompressionType::LZ4, MINIMAL_LZ4_OVERHEAD),
+            (CompressionType::Snappy, MINIMAL_SNAPPY_OVERHEAD),
+        ] {
+            let minimal = round_trip(ct, &noise, "minimal_overhead=true");
+            let standard = round_trip(ct, &noise, "");
+            assert!(minimal <= noise.len() + bound, "{:?} {}", ct, minimal);
+            assert!(minimal <= standard, "{:?} {} vs {}", ct, minimal, standard);
+        }
+        assert!(round_trip(CompressionType::Gzip, &noise, "minimal_overhead=true") < round_trip(CompressionType::Gzip, &noise, "") - 10);
+    }
+
+    #[test]
+    pub fn test_round_trip() {
+        let message = br#"{"sensor":"t-17","temperature":21.5,"humidity":40,"status":"ok","ts":1700000000}"#.repeat(3);
+        for ct in [CompressionType::Gzip, CompressionType::Zstd, CompressionType::LZ4, CompressionType::Snappy, CompressionType::XZ] {
+            assert!(round_trip(ct, &message, "minimal_overhead=true") < message.len(), "{:?}", ct);
+            round_trip(ct, b"", "minimal_overhead=true");
+        }
+    }
+}
@@ -0,0 +1,303 @@
+use std::error::Error;
+use std::fmt;
+use std::io::Read;
+use std::path::PathBuf;
+use crate::{decompressed_reader, describe, CompressionType};
+
+/// Bytes peeked for detection, more than the longest built-in magic number (snappy's 10).
+/// Longer magic numbers of registered codecs are peeked whole.
+const MAGIC_PEEK: usize = 16;
+
+/// Where a `MultiSourceReader` source is read from
+pub enum SourceInput {
+    /// Read the content of a file
+    Path(PathBuf),
+    /// Read from an arbitrary reader
+    Reader(Box<dyn Read>),
+    /// Read an in-memory buffer
+    Bytes(Vec<u8>),
+}
+
+/// One input of a `MultiSourceReader`
+pub struct SourceSpec {
+    pub input: SourceInput,
+    /// Codec of the input, detected from its magic number when `None`
+    pub compression_type: Option<CompressionType>,
+}
+
+/// What a `MultiSourceReader` read from one of its sources
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct SourceStats {
+    /// Codec used, `None` while the source is not opened yet
+    pub compression_type: Option<CompressionType>,
+    /// Uncompressed bytes read from the source
+    pub bytes: u64,
+}
+
+/// Error of the source at `index`, wrapped in the `std::io::Error` returned by the read
+#[derive(Debug)]
+pub struct SourceError {
+    pub index: usize,
+    pub error: std::io::Error,
+}
+
+impl fmt::Display for SourceError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "source {}: {}", self.index, self.error)
+    }
+}
+
+impl Error for SourceError {}
+
+/// Codec whose magic number starts `prefix`, among the built-in and registered codecs. Zlib,
+/// which has no magic number, is recognized by its 2 byte header: a deflate method with a
+/// 32 KiB window (`78`), a check value making the pair a multiple of 31, and no preset
+/// dictionary. Deflate and brotli, which have no header, are never detected.
+pub fn detect_compression_type(prefix: &[u8]) -> Option<CompressionType> {
+    let by_magic = describe().codecs.into_iter()
+        .find(|codec| matches!(&codec.magic, Some(magic) if prefix.starts_with(magic)))
+        .map(|codec| codec.compression_type);
+    if by_magic.is_some() {
+        return by_magic;
+    }
+    if let [0x78, flags, ..] = prefix {
+        if (0x7800 + *flags as u16).is_multiple_of(31) && flags & 0x20 == 0 {
+            return Some(CompressionType::Zlib);
+        }
+    }
+    return None;
+}
+
+/// Sniff the codec of `reader` from its first bytes, see `detect_compression_type`. Returns
+/// `CompressionType::None` for data of no known format, with the bytes consumed, which the
+/// caller chains in front of the rest: `Cursor::new(prefix).chain(reader)`.
+pub fn detect_compression<R: Read + ?Sized>(reader: &mut R) -> Result<(CompressionType, Vec<u8>), Box<dyn Error>> {
+    let peek = MAGIC_PEEK.max(crate::registry::longest_magic());
+    let mut prefix = Vec::with_capacity(peek);
+    reader.take(peek as u64).read_to_end(&mut prefix)?;
+    return Ok((detect_compression_type(&prefix).unwrap_or(CompressionType::None), prefix));
+}
+
+/// Decompressed content of `src`, whatever its codec: the first bytes are sniffed with
+/// `detect_compression` and put back in front of the stream for the decoder. Data of no known
+/// format is returned as is.
+pub fn decompressed_reader_auto(mut src: Box<dyn Read>) -> Result<Box<dyn Read>, Box<dyn Error>> {
+    let (compression_type, prefix) = detect_compression(&mut src)?;
+    let src: Box<dyn Read> = Box::new(std::io::Cursor::new(prefix).chain(src));
+    if compression_type == CompressionType::None {
+        return Ok(src);
+    }
+    return decompressed_reader(src, compression_type);
+}
+
+/// One plaintext stream made of the decompressed content of several sources, in order, each
+/// with a codec of its own.
+///
+/// Sources are opened when reached. A source without `compression_type` is detected with
+/// `detect_compression_type`, and read as is if no codec matches. Empty sources are skipped.
+/// Errors of the source `k`, opening included, are `std::io::Error`s of the same kind wrapping
+/// a `SourceError` with `index` `k`.
+pub struct MultiSourceReader {
+    pending: std::vec::IntoIter<SourceSpec>,
+    current: Option<Box<dyn Read>>,
+    index: usize,
+    stats: Vec<SourceStats>,
+}
+
+impl MultiSourceReader {
+    pub fn new(sources: Vec<SourceSpec>) -> MultiSourceReader {
+        return MultiSourceReader {
+            stats: vec![SourceStats::default(); sources.len()],
+            pending: sources.into_iter(),
+            current: None,
+            index: 0,
+        };
+    }
+
+    /// Index of the source being read, the number of sources once all were read
+    pub fn current_source_index(&self) -> usize {
+        return self.index;
+    }
+
+    /// Per source statistics, in source order
+    pub fn source_stats(&self) -> &[SourceStats] {
+        return &self.stats;
+    }
+
+    /// Open the next source, `None` when there is none left
+    fn open_next(&mut self) -> Result<Option<Box<dyn Read>>, Box<dyn Error>> {
+        let Some(spec) = self.pending.next() else {
+            return Ok(None);
+        };
+        let mut src: Box<dyn Read> = match spec.input {
+            SourceInput::Path(path) => Box::new(std::fs::File::open(&path)
+                .map_err(|e| format!("{}: {}", path.display(), e))?),
+            SourceInput::Reader(reader) => reader,
+            SourceInput::Bytes(bytes) => Box::new(std::io::Cursor::new(bytes)),
+        };
+        let compression_type = match spec.compression_type {
+            Some(compression_type) => compression_type,
+            None => {
+                let (compression_type, prefix) = detect_compression(&mut src)?;
+                src = Box::new(std::io::Cursor::new(prefix).chain(src));
+                compression_type
+            }
+        };
+        self.stats[self.index].compression_type = Some(compression_type);
+        return Ok(Some(decompressed_reader(src, compression_type)?));
+    }
+
+    fn source_error(&self, kind: std::io::ErrorKind, error: std::io::Error) -> std::io::Error {
+        return std::io::Error::new(kind, SourceError { index: self.index, error });
+    }
+}
+
+impl Read for MultiSourceReader {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        if buf.is_empty() {
+            return Ok(0);
+        }
+        loop {
+            if self.current.is_none() {
+                match self.open_next() {
+                    Ok(Some(reader)) => self.current = Some(reader),
+                    Ok(None) => return Ok(0),
+                    Err(e) => {
+                        let error = match e.downcast::<std::io::Error>() {
+                            Ok(error) => *error,
+                            Err(e) => std::io::Error::other(e.to_string()),
+                        };
+                        return Err(self.source_error(error.kind(), error));
+                    }
+                }
+            }
+            match self.current.as_mut().unwrap().read(buf) {
+                Ok(0) => {
+                    self.current = None;
+                    self.index += 1;
+                },
+                Ok(read) => {
+                    self.stats[self.index].bytes += read as u64;
+                    return Ok(read);
+                },
+                Err(e) => return Err(self.source_error(e.kind(), e)),
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Write;
+    use crate::{compress_bytes, compressed_writer};
+
+    fn sources(dir: &std::path::Path, corrupt_third: bool) -> Vec<SourceSpec> {
+        let mut zstd = compress_bytes(&b"third part\n".repeat(500), CompressionType::Zstd, "").unwrap();
+        if corrupt_third {
+            let middle = zstd.len() / 2;
+            zstd[middle..].fill(0xff);
+        }
+        let files: [(&str, Vec<u8>); 4] = [
+            ("1.gz", compress_bytes(&b"first part\n".repeat(1000), CompressionType::Gzip, "").unwrap()),
+            ("2.txt", b"second part, plain\n".to_vec()),
+            ("3.zst", zstd),
+            ("4.empty", Vec::new()),
+        ];
+        return files.into_iter().map(|(name, content)| {
+            let path = dir.join(name);
+            std::fs::write(&path, content).unwrap();
+            SourceSpec { input: SourceInput::Path(path), compression_type: None }
+        }).collect();
+    }
+
+    #[test]
+    pub fn test_detect_compression() {
+        let data = b"sniff me, sniff me\n".repeat(300);
+        for codec in crate::describe::BUILTIN_CODECS {
+            let ct = codec.compression_type;
+            // raw deflate and brotli have no header to recognize
+            if ct == CompressionType::Deflate || ct == CompressionType::Brotli {
+                continue;
+            }
+            let compressed = compress_bytes(&data, ct, "").unwrap();
+            let mut reader = std::io::Cursor::new(compressed.clone());
+            let (detected, prefix) = detect_compression(&mut reader).unwrap();
+            assert_eq!(detected, ct, "{}", codec.name);
+            let mut rest = Vec::new();
+            std::io::Cursor::new(prefix).chain(reader).read_to_end(&mut rest).unwrap();
+            assert!(rest == compressed, "{}", codec.name);
+        }
+        for level in 0..=9 {
+            assert_eq!(detect_compression_type(&compress_with(CompressionType::Zlib, &data, level)), Some(CompressionType::Zlib));
+        }
+        let (detected, prefix) = detect_compression(&mut &b"xy"[..]).unwrap();
+        assert_eq!((detected, prefix), (CompressionType::None, b"xy".to_vec()));
+        assert_eq!(detect_compression(&mut std::io::empty()).unwrap().0, CompressionType::None);
+    }
+
+    #[test]
+    pub fn test_decompressed_reader_auto() {
+        let data = b"2024-05-01T10:00:00 INFO request served in 3ms\n".repeat(2000);
+        for codec in crate::describe::BUILTIN_CODECS {
+            if codec.compression_type == CompressionType::Deflate || codec.compression_type == CompressionType::Brotli {
+                continue;
+            }
+            let compressed = compress_bytes(&data, codec.compression_type, "").unwrap();
+            let mut plain = Vec::new();
+            decompressed_reader_auto(Box::new(std::io::Cursor::new(compressed))).unwrap().read_to_end(&mut plain).unwrap();
+            assert!(plain == data, "{}", codec.name);
+        }
+        for text in [&b"plain log line, no codec here\n"[..], b"x", b""] {
+            let mut plain = Vec::new();
+            decompressed_reader_auto(Box::new(std::io::Cursor::new(text.to_vec()))).unwrap().read_to_end(&mut plain).unwrap();
+            assert_eq!(plain, text);
+        }
+    }
+
+    fn compress_with(ct: CompressionType, data: &[u8], level: u32) -> Vec<u8> {
+        let buffer = crate::buffer::SharedBuffer::default();
+        let mut w = compressed_writer(Box::new(buffer.clone()), ct, format!("level={}", level)).unwrap();
+        w.write_all(data).unwrap();
+        drop(w);
+        return buffer.take();
+    }
+
+    #[test]
+    pub fn test_concatenated_sources() {
+        let dir = std::env::temp_dir().join(format!("final_compression_multi_{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let mut reader = MultiSourceReader::new(sources(&dir, false));
+        let mut out = Vec::new();
+        reader.read_to_end(&mut out).unwrap();
+        let mut expected = b"first part\n".repeat(1000);
+        expected.extend_from_slice(b"second part, plain\n");
+        expected.extend_from_slice(&b"third part\n".repeat(500));
+        assert!(out == expected);
+        assert_eq!(reader.current_source_index(), 4);
+        assert_eq!(reader.source_stats(), &[
+            SourceStats { compression_type: Some(CompressionType::Gzip), bytes: 11000 },
+            SourceStats { compression_type: Some(CompressionType::None), bytes: 19 },
+            SourceStats { compression_type: Some(CompressionType::Zstd), bytes: 5500 },
+            SourceStats { compression_type: Some(CompressionType::None), bytes: 0 },
+        ]);
+
+        let mut reader = MultiSourceReader::new(sources(&dir, true));
+        let err = reader.read_to_end(&mut Vec::new()).unwrap_err();
+        let source = err.get_ref().and_then(|e| e.downcast_ref::<SourceError>()).unwrap();
+        assert_eq!(source.index, 2);
+        assert!(err.to_string().starts_with("source 2: "), "{}", err);
+        assert_eq!(reader.source_stats()[1].bytes, 19);
+        std::fs::remove_dir_all(&dir).unwrap();
+
+        // readers need not be Send
+        let shared: std::rc::Rc<[u8]> = compress_bytes(b"shared, ", CompressionType::Gzip, "").unwrap().into();
+        let mut reader = MultiSourceReader::new(vec![
+            SourceSpec { input: SourceInput::Reader(Box::new(std::io::Cursor::new(shared))), compression_type: None },
+            SourceSpec { input: SourceInput::Bytes(b"in memory".to_vec()), compression_type: Some(CompressionType::None) },
+        ]);
+        let mut out = Vec::new();
+        reader.read_to_end(&mut out).unwrap();
+        assert_eq!(out, b"shared, in memory");
+    }
+}
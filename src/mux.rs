@@ -0,0 +1,450 @@
+use std::cell::RefCell;
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::error::Error;
+use std::io::{ErrorKind, Read, Write};
+use std::rc::Rc;
+use crate::buffer::SharedBuffer;
+use crate::{compressed_writer, decompressed_reader, CompressionType, ParamSet};
+
+/// Stream magic of a mux, followed by a format version byte and the codec wire id (u16 LE)
+pub const MUX_MAGIC: &[u8] = b"FCMX";
+/// Current version of the mux format
+pub const MUX_VERSION: u8 = 1;
+/// Bytes of a record header: u16 LE channel id, kind, u32 LE payload length
+pub const RECORD_HEADER_SIZE: usize = 7;
+/// Default `segment_size` of `MuxWriter`
+pub const DEFAULT_SEGMENT_SIZE: usize = 64 * 1024;
+/// Default `channel_buffer` of `MuxReader`
+pub const DEFAULT_CHANNEL_BUFFER: usize = 1024 * 1024;
+
+/// Record kinds: compressed bytes of a channel, the end of a channel, the end of the mux
+const DATA: u8 = 0;
+const CLOSE: u8 = 1;
+const END: u8 = 2;
+
+/// The sink shared by the channels of a `MuxWriter`
+struct Mux {
+    out: Box<dyn Write>,
+    /// Every channel opened so far
+    used: HashSet<u16>,
+    /// Channels not closed yet
+    open: HashSet<u16>,
+    ended: bool,
+}
+
+impl Mux {
+    fn record(&mut self, id: u16, kind: u8, payload: &[u8]) -> std::io::Result<()> {
+        if self.ended {
+            return Err(std::io::Error::other("mux already ended"));
+        }
+        let length = u32::try_from(payload.len()).map_err(|_| std::io::Error::new(ErrorKind::InvalidInput, "mux segment over 4 GiB"))?;
+        let mut header = [0u8; RECORD_HEADER_SIZE];
+        header[..2].copy_from_slice(&id.to_le_bytes());
+        header[2] = kind;
+        header[3..].copy_from_slice(&length.to_le_bytes());
+        self.out.write_all(&header)?;
+        return self.out.write_all(payload);
+    }
+}
+
+/// Writer interleaving several logical streams, the channels, over one sink. Each channel has a
+/// compression context of its own; its output goes out in records tagged with the channel id, a
+/// segment each time the channel is flushed or has `segment_size` compressed bytes pending.
+///
+/// The mux starts with `MUX_MAGIC`, the version and the codec wire id. Every record has a
+/// `RECORD_HEADER_SIZE` header: the channel, the kind (0 data, 1 channel closed, 2 end of the
+/// mux) and the payload length. Closing a channel and ending the mux write explicit records, so
+/// a reader tells a complete mux from a truncated one.
+pub struct MuxWriter {
+    mux: Rc<RefCell<Mux>>,
+    compression_type: CompressionType,
+    param_set: ParamSet,
+    segment_size: usize,
+    finished: bool,
+}
+
+impl MuxWriter {
+    /// Mux of channels compressed with `compression_type` and the `compressed_writer` parameters
+    /// of `option`, which may also set `segment_size=size` (default 64 KiB)
+    pub fn new<T: Into<ParamSet>>(mut out: Box<dyn Write>, compression_type: CompressionType, option: T) -> Result<MuxWriter, Box<dyn Error>> {
+        let param_set: ParamSet = option.into();
+        let segment_size: usize = param_set.get_size("segment_size", DEFAULT_SEGMENT_SIZE)?;
+        if let CompressionType::Custom(_) = compression_type {
+            return Err("a mux needs a built-in codec, registered codec ids are not stable".into());
+        }
+        // fail on bad parameters here, not on the first channel
+        drop(compressed_writer(Box::new(std::io::sink()), compression_type, param_set.clone())?);
+        out.write_all(MUX_MAGIC)?;
+        out.write_all(&[MUX_VERSION])?;
+        out.write_all(&compression_type.wire_id().to_le_bytes())?;
+        let mux = Mux { out, used: HashSet::new(), open: HashSet::new(), ended: false };
+        return Ok(MuxWriter { mux: Rc::new(RefCell::new(mux)), compression_type, param_set, segment_size: segment_size.max(1), finished: false });
+    }
+
+    /// Open the channel `id`. Ids are used once: a closed channel cannot be opened again.
+    pub fn channel(&mut self, id: u16) -> Result<ChannelWriter, Box<dyn Error>> {
+        let mut mux = self.mux.borrow_mut();
+        if mux.ended {
+            return Err("mux already ended".into());
+        }
+        if !mux.used.insert(id) {
+            return Err(format!("mux channel {} already opened", id).into());
+        }
+        mux.open.insert(id);
+        let compressed = SharedBuffer::default();
+        let encoder = compressed_writer(Box::new(compressed.clone()), self.compression_type, self.param_set.clone())?;
+        return Ok(ChannelWriter { id, encoder: Some(encoder), compressed, mux: self.mux.clone(), segment_size: self.segment_size });
+    }
+
+    /// Write the end of the mux and flush the sink. Every channel must be closed first.
+    pub fn finish(mut self) -> std::io::Result<()> {
+        return self.end();
+    }
+
+    fn end(&mut self) -> std::io::Result<()> {
+        self.finished = true;
+        let mut mux = self.mux.borrow_mut();
+        if let Some(id) = mux.open.iter().min() {
+            return Err(std::io::Error::other(format!("mux channel {} is still open", id)));
+        }
+        mux.record(0, END, &[])?;
+        mux.ended = true;
+        return mux.out.flush();
+    }
+}
+
+/// Ends the mux if every channel is closed, losing the errors: see `finish`
+impl Drop for MuxWriter {
+    fn drop(&mut self) {
+        if self.finished || std::thread::panicking() {
+            return;
+        }
+        let _ = self.end();
+    }
+}
+
+/// A channel of a `MuxWriter`. `flush` ends a segment at a sync flush point of the codec, so the
+/// reader can decode everything written so far; dropping the channel closes it.
+pub struct ChannelWriter {
+    id: u16,
+    encoder: Option<Box<dyn Write>>,
+    /// Output of `encoder` not sent yet
+    compressed: SharedBuffer,
+    mux: Rc<RefCell<Mux>>,
+    segment_size: usize,
+}
+
+impl ChannelWriter {
+    pub fn id(&self) -> u16 {
+        return self.id;
+    }
+
+    /// Finish the stream of the channel, send what is left of it and the close record
+    pub fn close(mut self) -> std::io::Result<()> {
+        return self.end();
+    }
+
+    fn encoder(&mut self) -> std::io::Result<&mut Box<dyn Write>> {
+        return self.encoder.as_mut().ok_or_else(|| std::io::Error::other("mux channel already closed"));
+    }
+
+    /// Send the pending compressed bytes as a segment
+    fn send(&mut self) -> std::io::Result<()> {
+        let payload = self.compressed.take();
+        if payload.is_empty() {
+            return Ok(());
+        }
+        return self.mux.borrow_mut().record(self.id, DATA, &payload);
+    }
+
+    fn end(&mut self) -> std::io::Result<()> {
+        let Some(mut encoder) = self.encoder.take() else {
+            return Ok(());
+        };
+        encoder.flush()?;
+        drop(encoder);
+        self.send()?;
+        let mut mux = self.mux.borrow_mut();
+        mux.record(self.id, CLOSE, &[])?;
+        mux.open.remove(&self.id);
+        return Ok(());
+    }
+}
+
+impl Write for ChannelWriter {
+    fn write(&mut self, data: &[u8]) -> std::io::Result<usize> {
+        let written = self.encoder()?.write(data)?;
+        if self.compressed.len() >= self.segment_size {
+            self.send()?;
+        }
+        return Ok(written);
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.encoder()?.flush()?;
+        self.send()?;
+        return self.mux.borrow_mut().out.flush();
+    }
+}
+
+impl Drop for ChannelWriter {
+    fn drop(&mut self) {
+        if std::thread::panicking() {
+            return;
+        }
+        let _ = self.end();
+    }
+}
+
+/// Compressed bytes of a channel received and not decoded yet
+#[derive(Default)]
+struct Queue {
+    data: VecDeque<u8>,
+    closed: bool,
+}
+
+/// The source shared by the channels of a `MuxReader`
+struct Demux {
+    src: Box<dyn Read>,
+    queues: HashMap<u16, Queue>,
+    /// A data record that did not fit the buffer of its channel, delivered once it does
+    stalled: Option<(u16, Vec<u8>)>,
+    channel_buffer: usize,
+    buffers: HashMap<u16, usize>,
+    ended: bool,
+}
+
+impl Demux {
+    /// Move the next record of the source into its queue
+    fn next_record(&mut self) -> std::io::Result<()> {
+        if let Some((id, payload)) = self.stalled.take() {
+            return self.deliver(id, payload);
+        }
+        let mut header = [0u8; RECORD_HEADER_SIZE];
+        self.src.read_exact(&mut header).map_err(truncated)?;
+        let id = u16::from_le_bytes([header[0], header[1]]);
+        let length = u32::from_le_bytes(header[3..].try_into().unwrap()) as usize;
+        match header[2] {
+            DATA => {
+                // grows with the bytes actually there, whatever the untrusted length says
+                let mut payload = Vec::new();
+                (&mut self.src).take(length as u64).read_to_end(&mut payload)?;
+                if payload.len() < length {
+                    return Err(truncated(ErrorKind::UnexpectedEof.into()));
+                }
+                return self.deliver(id, payload);
+            },
+            CLOSE if length == 0 => self.queues.entry(id).or_default().closed = true,
+            END if length == 0 => self.ended = true,
+            kind => return Err(std::io::Error::new(ErrorKind::InvalidData, format!("invalid mux record of kind {} and {} bytes", kind, length))),
+        }
+        return Ok(());
+    }
+
+    /// Queue `payload` for the channel `id`, unless that overflows its buffer: then it waits in
+    /// `stalled` and the reader gets a backpressure error
+    fn deliver(&mut self, id: u16, payload: Vec<u8>) -> std::io::Result<()> {
+        let buffer = self.buffers.get(&id).copied().unwrap_or(self.channel_buffer);
+        let queue = self.queues.entry(id).or_default();
+        let queued = queue.data.len();
+        if queued > 0 && queued + payload.len() > buffer {
+            self.stalled = Some((id, payload));
+            return Err(std::io::Error::new(ErrorKind::WouldBlock,
+                format!("mux channel {} has {} bytes buffered, more would exceed its {} byte buffer: read it first", id, queued, buffer)));
+        }
+        queue.data.extend(payload);
+        return Ok(());
+    }
+}
+
+fn truncated(e: std::io::Error) -> std::io::Error {
+    if e.kind() == ErrorKind::UnexpectedEof {
+        return std::io::Error::new(ErrorKind::UnexpectedEof, "mux ended before its end record");
+    }
+    return e;
+}
+
+/// Compressed stream of one channel, pulled from the shared source
+struct ChannelSource {
+    id: u16,
+    demux: Rc<RefCell<Demux>>,
+}
+
+impl Read for ChannelSource {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        let mut demux = self.demux.borrow_mut();
+        loop {
+            if let Some(queue) = demux.queues.get_mut(&self.id) {
+                if !queue.data.is_empty() || queue.closed {
+                    return queue.data.read(buf);
+                }
+            }
+            if demux.ended {
+                let message = match demux.queues.contains_key(&self.id) {
+                    true => format!("mux ended before channel {} was closed", self.id),
+                    false => format!("no channel {} in the mux", self.id),
+                };
+                return Err(std::io::Error::new(ErrorKind::UnexpectedEof, message));
+            }
+            demux.next_record()?;
+        }
+    }
+}
+
+/// Reader of what a `MuxWriter` wrote, each channel decoded on its own.
+///
+/// Channels can be read in any order: records of the other channels met on the way are kept,
+/// up to `channel_buffer` compressed bytes per channel. Beyond that, the read fails with
+/// `ErrorKind::WouldBlock` naming the channel to read first, and can be retried after.
+pub struct MuxReader {
+    demux: Rc<RefCell<Demux>>,
+    compression_type: CompressionType,
+    taken: HashSet<u16>,
+}
+
+impl MuxReader {
+    /// Reader of the mux `src`, whose header is read here. `option` may set
+    /// `channel_buffer=size` (default 1 MiB), the buffer of every channel.
+    pub fn new<T: Into<ParamSet>>(mut src: Box<dyn Read>, option: T) -> Result<MuxReader, Box<dyn Error>> {
+        let param_set: ParamSet = option.into();
+        let channel_buffer = param_set.get_size("channel_buffer", DEFAULT_CHANNEL_BUFFER)?;
+        let mut header = [0u8; 7];
+        src.read_exact(&mut header).map_err(|e| format!("invalid mux header: {}", e))?;
+        if &header[..4] != MUX_MAGIC || header[4] != MUX_VERSION {
+            return Err("not a mux stream".into());
+        }
+        let wire_id = u16::from_le_bytes([header[5], header[6]]);
+        let compression_type = CompressionType::from_wire_id(wire_id).ok_or_else(|| format!("mux of unknown codec {}", wire_id))?;
+        let demux = Demux { src, queues: HashMap::new(), stalled: None, channel_buffer, buffers: HashMap::new(), ended: false };
+        return Ok(MuxReader { demux: Rc::new(RefCell::new(demux)), compression_type, taken: HashSet::new() });
+    }
+
+    /// Codec of the channels
+    pub fn compression_type(&self) -> CompressionType {
+        return self.compression_type;
+    }
+
+    /// Buffer of the channel `id` in compressed bytes, instead of `channel_buffer`
+    pub fn set_channel_buffer(&mut self, id: u16, size: usize) {
+        self.demux.borrow_mut().buffers.insert(id, size);
+    }
+
+    /// Decompressed stream of the channel `id`, which ends where the writer closed it
+    pub fn channel(&mut self, id: u16) -> Result<ChannelReader, Box<dyn Error>> {
+        if !self.taken.insert(id) {
+            return Err(format!("mux channel {} already taken", id).into());
+        }
+        let source = ChannelSource { id, demux: self.demux.clone() };
+        return Ok(ChannelReader { decoder: decompressed_reader(Box::new(source), self.compression_type)? });
+    }
+}
+
+/// A channel of a `MuxReader`
+pub struct ChannelReader {
+    decoder: Box<dyn Read>,
+}
+
+impl Read for ChannelReader {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        return self.decoder.read(buf);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Metrics, logs and traces: short numeric lines, repetitive text, binary noise
+    fn channels() -> [(u16, Vec<u8>); 3] {
+        let metrics: Vec<u8> = (0..4000).flat_map(|i| format!("cpu.load {} {}\n", i % 97, 1_700_000_000 + i).into_bytes()).collect();
+        let logs = b"2024-05-01 INFO request served in 3ms path=/api/items\n".repeat(3000);
+        let mut state = 0x9e37_79b9u32;
+        let traces: Vec<u8> = (0..150_000).map(|_| {
+            state ^= state << 13;
+            state ^= state >> 17;
+            state ^= state << 5;
+            (state >> 24) as u8
+        }).collect();
+        return [(1, metrics), (2, logs), (3, traces)];
+    }
+
+    fn write_mux(ct: CompressionType) -> Vec<u8> {
+        let buffer = SharedBuffer::default();
+        let mut mux = MuxWriter::new(Box::new(buffer.clone()), ct, "level=3;segment_size=4KiB").unwrap();
+        let data = channels();
+        let mut writers: Vec<ChannelWriter> = data.iter().map(|(id, _)| mux.channel(*id).unwrap()).collect();
+        // interleaved writes of different sizes, with flushes
+        for step in 0..100 {
+            for ((_, content), writer) in data.iter().zip(writers.iter_mut()) {
+                let chunk = content.len().div_ceil(100);
+                let start = (step * chunk).min(content.len());
+                writer.write_all(&content[start..(start + chunk).min(content.len())]).unwrap();
+                if step % 7 == writer.id() as usize {
+                    writer.flush().unwrap();
+                }
+            }
+        }
+        assert!(mux.channel(2).is_err());
+        for writer in writers {
+            writer.close().unwrap();
+        }
+        mux.finish().unwrap();
+        return buffer.take();
+    }
+
+    #[test]
+    pub fn test_mux_round_trip() {
+        for ct in [CompressionType::Gzip, CompressionType::Zstd] {
+            let compressed = write_mux(ct);
+            let mut reader = MuxReader::new(Box::new(std::io::Cursor::new(compressed.clone())), "channel_buffer=16MiB").unwrap();
+            assert_eq!(reader.compression_type(), ct);
+            // in another order than written, each channel with its own content only
+            for (id, content) in channels().into_iter().rev() {
+                let mut plain = Vec::new();
+                reader.channel(id).unwrap().read_to_end(&mut plain).unwrap();
+                assert!(plain == content, "{} channel {}", ct, id);
+            }
+            assert!(reader.channel(1).is_err());
+            let err = reader.channel(9).unwrap().read_to_end(&mut Vec::new()).unwrap_err();
+            assert!(err.to_string().contains("no channel 9"), "{}", err);
+
+            // the end record is required
+            let truncated = &compressed[..compressed.len() - RECORD_HEADER_SIZE];
+            let mut reader = MuxReader::new(Box::new(std::io::Cursor::new(truncated.to_vec())), "").unwrap();
+            let err = reader.channel(9).unwrap().read_to_end(&mut Vec::new()).unwrap_err();
+            assert!(err.to_string().contains("before its end record"), "{}", err);
+        }
+    }
+
+    #[test]
+    pub fn test_mux_backpressure() {
+        let compressed = write_mux(CompressionType::Gzip);
+        let mut reader = MuxReader::new(Box::new(std::io::Cursor::new(compressed)), "channel_buffer=256").unwrap();
+        reader.set_channel_buffer(1, 64 * 1024);
+        reader.set_channel_buffer(3, 1024 * 1024);
+        let mut traces = reader.channel(3).unwrap();
+        let mut plain = Vec::new();
+        // the log segments pile up while looking for the traces
+        let err = traces.read_to_end(&mut plain).unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::WouldBlock);
+        assert!(err.to_string().contains("channel 2"), "{}", err);
+
+        // reading the logs makes room, then the traces go on
+        let mut logs = Vec::new();
+        reader.channel(2).unwrap().read_to_end(&mut logs).unwrap();
+        assert!(logs == channels()[1].1);
+        let mut traces_plain = Vec::new();
+        reader.channel(1).unwrap().read_to_end(&mut Vec::new()).unwrap();
+        traces.read_to_end(&mut traces_plain).unwrap();
+        assert!([plain, traces_plain].concat() == channels()[2].1);
+
+        assert!(MuxReader::new(Box::new(&b"FCMZ\x01\x01\x00"[..]), "").is_err());
+        // a record announcing 4 GiB in a few bytes is truncated, not allocated
+        let mut forged = [MUX_MAGIC, &[MUX_VERSION], &CompressionType::Gzip.wire_id().to_le_bytes()[..]].concat();
+        forged.extend_from_slice(&[1, 0, DATA, 0xff, 0xff, 0xff, 0xff, 1, 2, 3]);
+        let mut reader = MuxReader::new(Box::new(std::io::Cursor::new(forged)), "").unwrap();
+        let err = reader.channel(1).unwrap().read_to_end(&mut Vec::new()).unwrap_err();
+        assert_eq!((err.kind(), err.to_string()), (ErrorKind::UnexpectedEof, "mux ended before its end record".to_string()));
+        assert!(MuxWriter::new(Box::new(Vec::new()), CompressionType::Custom(3), "").is_err());
+    }
+}
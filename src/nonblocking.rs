@@ -0,0 +1,187 @@
+use std::error::Error;
+use std::io::{ErrorKind, Write};
+use crate::buffer::SharedBuffer;
+use crate::{compressed_writer, CompressionType, ParamSet};
+
+/// Input handed to the encoder at a time, bounding how much output one `try_write` may add
+const INPUT_CHUNK: usize = 64 * 1024;
+
+/// Result of a `NonBlockingWrite` call
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct WriteOutcome {
+    /// Input bytes taken, from the start of the data passed; the rest was not consumed
+    pub consumed: usize,
+    /// Compressed bytes held back because the sink would block
+    pub pending_output: usize,
+}
+
+/// Writing without blocking, for poll-based event loops: a sink returning `WouldBlock` never
+/// makes data disappear or repeat. Input is either consumed or not, and compressed output the
+/// sink refused is kept until a later call gets it through.
+pub trait NonBlockingWrite {
+    /// Compress a prefix of `data`, possibly empty when too much output is pending, and push
+    /// what the sink takes
+    fn try_write(&mut self, data: &[u8]) -> std::io::Result<WriteOutcome>;
+
+    /// Push pending output to the sink, returning how many bytes are still pending. Call it
+    /// again when the sink is writable while it is not 0.
+    fn try_flush(&mut self) -> std::io::Result<usize>;
+}
+
+/// Compressing writer over a non-blocking sink, see `NonBlockingWrite`.
+///
+/// The encoder writes into memory, so only the sink can block; at most about `max_pending`
+/// compressed bytes wait for it. `finish` ends the stream, after which `try_flush` is called
+/// until nothing is pending. Output still pending on drop is lost.
+pub struct NonBlockingWriter {
+    sink: Box<dyn Write>,
+    encoder: Option<Box<dyn Write>>,
+    output: SharedBuffer,
+    /// Compressed bytes not taken by the sink yet, from `sent` on
+    pending: Vec<u8>,
+    sent: usize,
+    max_pending: usize,
+}
+
+impl NonBlockingWriter {
+    /// Writer compressing with `compression_type` and the parameters of `compressed_writer` into
+    /// `sink`, whose `write` may fail with `WouldBlock`
+    pub fn new<T: Into<ParamSet>>(sink: Box<dyn Write>, compression_type: CompressionType, option: T, max_pending: usize) -> Result<NonBlockingWriter, Box<dyn Error>> {
+        let output = SharedBuffer::default();
+        let encoder = compressed_writer(Box::new(output.clone()), compression_type, option)?;
+        return Ok(NonBlockingWriter {
+            sink,
+            encoder: Some(encoder),
+            output,
+            pending: Vec::new(),
+            sent: 0,
+            max_pending: max_pending.max(1),
+        });
+    }
+
+    fn pending_len(&self) -> usize {
+        return self.pending.len() - self.sent;
+    }
+
+    /// Move what the encoder produced behind the pending output
+    fn collect_output(&mut self) {
+        self.pending.drain(..self.sent);
+        self.sent = 0;
+        self.pending.extend_from_slice(&self.output.take());
+    }
+
+    /// Write pending output until the sink would block
+    fn drain(&mut self) -> std::io::Result<()> {
+        while self.sent < self.pending.len() {
+            match self.sink.write(&self.pending[self.sent..]) {
+                Ok(0) => return Err(std::io::Error::new(ErrorKind::WriteZero, "sink accepts no more compressed bytes")),
+                Ok(n) => self.sent += n,
+                Err(e) if e.kind() == ErrorKind::Interrupted => {},
+                Err(e) if e.kind() == ErrorKind::WouldBlock => return Ok(()),
+                Err(e) => return Err(e),
+            }
+        }
+        return Ok(());
+    }
+
+    /// End the stream: its last bytes join the pending output, for `try_flush` to push. Later
+    /// writes fail; calling it again is a no-op.
+    pub fn finish(&mut self) -> std::io::Result<usize> {
+        // dropping finishes the stream, into memory
+        self.encoder = None;
+        self.collect_output();
+        return self.try_flush();
+    }
+}
+
+impl NonBlockingWrite for NonBlockingWriter {
+    fn try_write(&mut self, data: &[u8]) -> std::io::Result<WriteOutcome> {
+        self.drain()?;
+        if self.pending_len() >= self.max_pending || data.is_empty() {
+            return Ok(WriteOutcome { consumed: 0, pending_output: self.pending_len() });
+        }
+        let encoder = self.encoder.as_mut()
+            .ok_or_else(|| std::io::Error::other("stream already finished"))?;
+        let consumed = data.len().min(INPUT_CHUNK);
+        encoder.write_all(&data[..consumed])?;
+        self.collect_output();
+        self.drain()?;
+        return Ok(WriteOutcome { consumed, pending_output: self.pending_len() });
+    }
+
+    fn try_flush(&mut self) -> std::io::Result<usize> {
+        self.drain()?;
+        if self.pending_len() == 0 {
+            match self.sink.flush() {
+                Err(e) if e.kind() == ErrorKind::WouldBlock => {},
+                result => result?,
+            }
+        }
+        return Ok(self.pending_len());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Read;
+    use crate::decompressed_reader;
+
+    /// Sink refusing every other write, and taking at most 7 bytes of the others
+    struct Stuttering {
+        out: SharedBuffer,
+        calls: usize,
+    }
+
+    impl Write for Stuttering {
+        fn write(&mut self, data: &[u8]) -> std::io::Result<usize> {
+            self.calls += 1;
+            if self.calls.is_multiple_of(2) {
+                return Err(std::io::Error::new(ErrorKind::WouldBlock, "socket full"));
+            }
+            return self.out.write(&data[..data.len().min(7)]);
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            return Ok(());
+        }
+    }
+
+    #[test]
+    pub fn test_stuttering_sink() {
+        // barely compressible, so every codec has output to push while the input comes
+        let mut state = 0x2545F4914F6CDD1Du64;
+        let data: Vec<u8> = (0..300_000u32).map(|i| {
+            state ^= state << 13;
+            state ^= state >> 7;
+            state ^= state << 17;
+            (state % 16) as u8 + (i % 7) as u8
+        }).collect();
+        for ct in [CompressionType::Zstd, CompressionType::Gzip, CompressionType::LZ4, CompressionType::None] {
+            let out = SharedBuffer::default();
+            let sink = Stuttering { out: out.clone(), calls: 0 };
+            let mut w = NonBlockingWriter::new(Box::new(sink), ct, "", 256).unwrap();
+            let mut offset = 0;
+            let mut refused = 0;
+            while offset < data.len() {
+                let outcome = w.try_write(&data[offset..]).unwrap();
+                if outcome.consumed == 0 {
+                    refused += 1;
+                    assert!(outcome.pending_output >= 256);
+                    w.try_flush().unwrap();
+                }
+                offset += outcome.consumed;
+            }
+            let mut pending = w.finish().unwrap();
+            while pending > 0 {
+                pending = w.try_flush().unwrap();
+            }
+            assert!(refused > 0, "{}", ct);
+            assert!(w.try_write(b"late").is_err());
+
+            let mut plain = Vec::new();
+            decompressed_reader(Box::new(std::io::Cursor::new(out.take())), ct).unwrap().read_to_end(&mut plain).unwrap();
+            assert!(plain == data, "{}", ct);
+        }
+    }
+}
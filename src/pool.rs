@@ -0,0 +1,265 @@
+use std::error::Error;
+use std::fmt;
+use std::io::{Read, Write};
+use std::ops::Deref;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use crate::{compressed_writer_into, decompressed_reader_from, CompressionType, ParamSet};
+
+/// Buffers of a `FixedPoolProvider` not handed out
+type Free = Arc<Mutex<Vec<Vec<u8>>>>;
+
+/// Buffer of a `BufferProvider`. It never grows: writing past its capacity fails. A buffer of a
+/// `FixedPoolProvider` goes back to its pool when dropped, released or not.
+pub struct PooledBuf {
+    data: Vec<u8>,
+    home: Option<Free>,
+}
+
+impl PooledBuf {
+    /// Buffer of the heap, allocated here
+    pub fn with_capacity(capacity: usize) -> PooledBuf {
+        return PooledBuf { data: Vec::with_capacity(capacity), home: None };
+    }
+
+    pub fn capacity(&self) -> usize {
+        return self.data.capacity();
+    }
+
+    pub fn clear(&mut self) {
+        self.data.clear();
+    }
+
+    /// Fill the buffer from `src` up to `limit` bytes (at most its capacity), failing if `src`
+    /// has more
+    fn fill_from<R: Read>(&mut self, mut src: R, limit: usize) -> std::io::Result<()> {
+        let mut len = self.data.len();
+        let limit = limit.min(self.data.capacity());
+        self.data.resize(limit.max(len), 0);
+        while len < self.data.len() {
+            match src.read(&mut self.data[len..]) {
+                Ok(0) => break,
+                Ok(read) => len += read,
+                Err(e) if e.kind() == std::io::ErrorKind::Interrupted => {},
+                Err(e) => {
+                    self.data.truncate(len);
+                    return Err(e);
+                }
+            }
+        }
+        self.data.truncate(len);
+        if len >= limit && src.read(&mut [0u8; 1])? > 0 {
+            return Err(full(limit));
+        }
+        return Ok(());
+    }
+}
+
+fn full(capacity: usize) -> std::io::Error {
+    return std::io::Error::new(std::io::ErrorKind::WriteZero, format!("pooled buffer of {} bytes is full", capacity));
+}
+
+impl fmt::Debug for PooledBuf {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("PooledBuf").field("len", &self.data.len()).field("capacity", &self.data.capacity())
+            .field("pooled", &self.home.is_some()).finish()
+    }
+}
+
+impl Deref for PooledBuf {
+    type Target = [u8];
+
+    fn deref(&self) -> &[u8] {
+        return &self.data;
+    }
+}
+
+impl Write for PooledBuf {
+    fn write(&mut self, data: &[u8]) -> std::io::Result<usize> {
+        let room = self.data.capacity() - self.data.len();
+        if room == 0 && !data.is_empty() {
+            return Err(full(self.data.capacity()));
+        }
+        let n = data.len().min(room);
+        self.data.extend_from_slice(&data[..n]);
+        return Ok(n);
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        return Ok(());
+    }
+}
+
+impl Drop for PooledBuf {
+    fn drop(&mut self) {
+        if let Some(home) = self.home.take() {
+            let mut data = std::mem::take(&mut self.data);
+            data.clear();
+            home.lock().unwrap_or_else(|e| e.into_inner()).push(data);
+        }
+    }
+}
+
+/// Source of the buffers of the pooled one-shot APIs, for deployments that must not allocate
+/// once started. Only the buffers of this crate come from it: the codec libraries (zlib, libzstd,
+/// liblzma...) keep allocating their own state.
+pub trait BufferProvider: Send + Sync {
+    /// A buffer of at least `min_size` bytes of capacity, empty
+    fn acquire(&self, min_size: usize) -> Result<PooledBuf, PoolExhausted>;
+
+    /// Give back a buffer once done with it
+    fn release(&self, buf: PooledBuf) {
+        drop(buf);
+    }
+}
+
+/// Allocates every buffer on the heap
+#[derive(Debug, Clone, Copy, Default)]
+pub struct HeapProvider;
+
+impl BufferProvider for HeapProvider {
+    fn acquire(&self, min_size: usize) -> Result<PooledBuf, PoolExhausted> {
+        return Ok(PooledBuf::with_capacity(min_size));
+    }
+}
+
+/// A `FixedPoolProvider` had no buffer for a request
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PoolExhausted {
+    pub requested: usize,
+    pub buffer_size: usize,
+    pub buffers: usize,
+}
+
+impl fmt::Display for PoolExhausted {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.requested > self.buffer_size {
+            return write!(f, "buffer pool exhausted: {} bytes requested from buffers of {}", self.requested, self.buffer_size);
+        }
+        write!(f, "buffer pool exhausted: all {} buffers in use", self.buffers)
+    }
+}
+
+impl Error for PoolExhausted {}
+
+/// Provider of `buffers` buffers of `buffer_size` bytes, all allocated up front. A request it
+/// cannot serve is a `PoolExhausted` error, never an allocation.
+pub struct FixedPoolProvider {
+    free: Free,
+    buffer_size: usize,
+    buffers: usize,
+    acquired: AtomicU64,
+    refused: AtomicU64,
+}
+
+impl FixedPoolProvider {
+    pub fn new(buffers: usize, buffer_size: usize) -> FixedPoolProvider {
+        let free = (0..buffers).map(|_| Vec::with_capacity(buffer_size)).collect();
+        return FixedPoolProvider {
+            free: Arc::new(Mutex::new(free)),
+            buffer_size,
+            buffers,
+            acquired: AtomicU64::new(0),
+            refused: AtomicU64::new(0),
+        };
+    }
+
+    /// Buffers not handed out
+    pub fn available(&self) -> usize {
+        return self.free.lock().unwrap_or_else(|e| e.into_inner()).len();
+    }
+
+    /// Requests served
+    pub fn acquired(&self) -> u64 {
+        return self.acquired.load(Ordering::Relaxed);
+    }
+
+    /// Requests refused with `PoolExhausted`
+    pub fn refused(&self) -> u64 {
+        return self.refused.load(Ordering::Relaxed);
+    }
+}
+
+impl BufferProvider for FixedPoolProvider {
+    fn acquire(&self, min_size: usize) -> Result<PooledBuf, PoolExhausted> {
+        let data = match min_size <= self.buffer_size {
+            true => self.free.lock().unwrap_or_else(|e| e.into_inner()).pop(),
+            false => None,
+        };
+        let Some(data) = data else {
+            self.refused.fetch_add(1, Ordering::Relaxed);
+            return Err(PoolExhausted { requested: min_size, buffer_size: self.buffer_size, buffers: self.buffers });
+        };
+        self.acquired.fetch_add(1, Ordering::Relaxed);
+        return Ok(PooledBuf { data, home: Some(self.free.clone()) });
+    }
+}
+
+/// Capacity asked for the output of `compress_bytes_pooled`: the input, plus an eighth and
+/// 1 KiB, more than any built-in codec adds to incompressible data
+pub fn compressed_capacity(len: usize) -> usize {
+    return len.saturating_add(len / 8).saturating_add(1024);
+}
+
+/// `compress_bytes` into a buffer of `provider` of `compressed_capacity(data.len())` bytes.
+/// Takes the parameters of `compressed_writer_into`, which has no registered codecs.
+pub fn compress_bytes_pooled<T: Into<ParamSet>>(data: &[u8], compression_type: CompressionType, option: T, provider: &dyn BufferProvider) -> Result<PooledBuf, Box<dyn Error>> {
+    let out = provider.acquire(compressed_capacity(data.len()))?;
+    let mut w = compressed_writer_into(out, compression_type, option)?;
+    w.write_all(data)?;
+    return Ok(w.finish()?);
+}
+
+/// `decompress_bytes_limited` into a buffer of `provider` of `max_output` bytes, for built-in
+/// codecs
+pub fn decompress_bytes_pooled(data: &[u8], compression_type: CompressionType, max_output: usize, provider: &dyn BufferProvider) -> Result<PooledBuf, Box<dyn Error>> {
+    let reader = decompressed_reader_from(data, compression_type)?;
+    let mut out = provider.acquire(max_output)?;
+    out.fill_from(reader, max_output).map_err(|e| match e.kind() {
+        std::io::ErrorKind::WriteZero => format!("decompressed output exceeds the limit of {} bytes", max_output).into(),
+        _ => Box::<dyn Error>::from(e),
+    })?;
+    return Ok(out);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    pub fn test_fixed_pool() {
+        let text = "pooled buffers only, nothing allocated after startup\n".repeat(2000);
+        for ct in [CompressionType::Gzip, CompressionType::Zstd] {
+            let pool = FixedPoolProvider::new(2, compressed_capacity(text.len()));
+            let compressed = compress_bytes_pooled(text.as_bytes(), ct, "level=6", &pool).unwrap();
+            let plain = decompress_bytes_pooled(&compressed, ct, text.len(), &pool).unwrap();
+            assert!(*plain == *text.as_bytes(), "{}", ct);
+            assert_eq!(pool.available(), 0);
+
+            // a third buffer is refused, not allocated
+            let err = decompress_bytes_pooled(&compressed, ct, text.len(), &pool).unwrap_err();
+            let exhausted = err.downcast_ref::<PoolExhausted>().unwrap();
+            assert_eq!(exhausted.to_string(), "buffer pool exhausted: all 2 buffers in use");
+            pool.release(plain);
+            drop(compressed);
+            assert_eq!((pool.available(), pool.acquired(), pool.refused()), (2, 2, 1));
+
+            let err = decompress_bytes_pooled(b"", ct, pool.buffer_size + 1, &pool).unwrap_err();
+            assert!(err.to_string().contains("bytes requested from buffers of"), "{}", err);
+        }
+
+        // undersized buffers fail, and go back to the pool
+        let pool = FixedPoolProvider::new(1, 100);
+        let compressed = crate::compress_bytes(&[7u8; 1000], CompressionType::Zstd, "").unwrap();
+        let err = decompress_bytes_pooled(&compressed, CompressionType::Zstd, 100, &pool).unwrap_err();
+        assert_eq!(err.to_string(), "decompressed output exceeds the limit of 100 bytes");
+        let mut buf = pool.acquire(10).unwrap();
+        assert_eq!(buf.write(&[1u8; 150]).unwrap(), 100);
+        assert_eq!(buf.write(&[1u8]).unwrap_err().kind(), std::io::ErrorKind::WriteZero);
+        drop(buf);
+        assert_eq!(pool.available(), 1);
+
+        let buf = HeapProvider.acquire(4096).unwrap();
+        assert!(buf.capacity() >= 4096 && buf.is_empty());
+    }
+}
@@ -0,0 +1,137 @@
+use std::io::{ErrorKind, Read};
+use crate::describe::BUILTIN_CODECS;
+use crate::{CompressionType, ParamSet};
+
+/// Bytes read from the source at a time while scanning
+const SCAN_CHUNK: usize = 8 * 1024;
+
+/// Start of a bzip2 block, and the end of stream marker of an empty stream
+const BZIP2_BLOCK_MAGICS: [[u8; 6]; 2] = [
+    [0x31, 0x41, 0x59, 0x26, 0x53, 0x59],
+    [0x17, 0x72, 0x45, 0x38, 0x50, 0x90],
+];
+
+/// `scan_for_magic` parameter of the decoders, 0 (the default) when scanning is off
+pub(crate) fn scan_limit(param_set: &ParamSet) -> Result<u64, crate::InvalidParam> {
+    return param_set.get_size("scan_for_magic", 0);
+}
+
+/// Bytes a stream of `compression_type` starts with, checked on `window` starting at the
+/// candidate: `Some(true)` on a match, `None` while `window` is too short to tell
+fn signature_at(magic: &[u8], compression_type: CompressionType, window: &[u8]) -> Option<bool> {
+    // "BZh" alone shows up in text, so bzip2 also needs the level and the first block magic
+    let needed = if compression_type == CompressionType::Bzip2 { magic.len() + 7 } else { magic.len() };
+    if window.len() < needed {
+        return if magic.starts_with(&window[..window.len().min(magic.len())]) { None } else { Some(false) };
+    }
+    if !window.starts_with(magic) {
+        return Some(false);
+    }
+    if compression_type == CompressionType::Bzip2 {
+        let level = window[magic.len()];
+        let block = &window[magic.len() + 1..needed];
+        return Some((b'1'..=b'9').contains(&level) && BZIP2_BLOCK_MAGICS.iter().any(|m| m == block));
+    }
+    return Some(true);
+}
+
+/// Skip what precedes the first stream of `compression_type` in `src`, like the shell script
+/// of a self-extracting archive, looking for the format's magic number within the first
+/// `max_bytes`. Returns the preamble length and a reader starting at the magic number.
+///
+/// The scan reads `src` a chunk at a time and keeps only the few bytes a magic number may span
+/// across chunks. Fails with `InvalidData` when no magic number starts within `max_bytes`, and
+/// with `Unsupported` for codecs without a magic number (deflate, zlib, none).
+pub fn skip_preamble(mut src: Box<dyn Read>, compression_type: CompressionType, max_bytes: u64) -> std::io::Result<(u64, Box<dyn Read>)> {
+    let magic = BUILTIN_CODECS.iter()
+        .find(|codec| codec.compression_type == compression_type)
+        .and_then(|codec| codec.magic)
+        .ok_or_else(|| std::io::Error::new(ErrorKind::Unsupported,
+            format!("{} has no magic number to scan for", compression_type)))?;
+    // unmatched bytes at the start of `window` are part of the preamble
+    let mut window: Vec<u8> = Vec::with_capacity(SCAN_CHUNK + 16);
+    let mut skipped: u64 = 0;
+    let mut at_end = false;
+    loop {
+        let mut start = 0;
+        while start < window.len() && skipped + (start as u64) <= max_bytes {
+            match signature_at(magic, compression_type, &window[start..]) {
+                Some(true) => {
+                    let rest = window.split_off(start);
+                    return Ok((skipped + start as u64, Box::new(std::io::Cursor::new(rest).chain(src))));
+                },
+                // too short to tell: read more, unless the input ended
+                None if !at_end => break,
+                _ => start += 1,
+            }
+        }
+        skipped += start as u64;
+        window.drain(..start);
+        if at_end || skipped > max_bytes {
+            return Err(std::io::Error::new(ErrorKind::InvalidData,
+                format!("no {} magic number within the first {} bytes", compression_type, max_bytes)));
+        }
+        let filled = window.len();
+        window.resize(filled + SCAN_CHUNK, 0);
+        let read = loop {
+            match src.read(&mut window[filled..]) {
+                Err(e) if e.kind() == ErrorKind::Interrupted => continue,
+                result => break result,
+            }
+        };
+        let read = read?;
+        window.truncate(filled + read);
+        at_end = read == 0;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{compress_bytes, decompressed_reader_with};
+
+    /// 3 KiB shell preamble, mentioning the bzip2 magic on the way
+    fn script() -> Vec<u8> {
+        let mut script = b"#!/bin/sh\n# self-extracting archive, payload is BZh compressed\n".to_vec();
+        while script.len() < 3 * 1024 - 30 {
+            script.extend_from_slice(b"tail -c +$SKIP \"$0\" | bunzip2 | tar x\n");
+        }
+        script.resize(3 * 1024, b'\n');
+        return script;
+    }
+
+    #[test]
+    pub fn test_self_extracting() {
+        let data = b"archive content\n".repeat(4000);
+        for ct in [CompressionType::Bzip2, CompressionType::Gzip, CompressionType::XZ] {
+            let mut file = script();
+            file.extend_from_slice(&compress_bytes(&data, ct, "").unwrap());
+
+            let (preamble, mut reader) = skip_preamble(Box::new(std::io::Cursor::new(file.clone())), ct, 4096).unwrap();
+            assert_eq!(preamble, 3 * 1024, "{}", ct);
+            let mut compressed = Vec::new();
+            reader.read_to_end(&mut compressed).unwrap();
+            assert!(compressed == file[3 * 1024..], "{}", ct);
+
+            let mut out = Vec::new();
+            decompressed_reader_with(Box::new(std::io::Cursor::new(file.clone())), ct, "scan_for_magic=4KiB").unwrap()
+                .read_to_end(&mut out).unwrap();
+            assert!(out == data, "{}", ct);
+
+            // scanning is off by default, and a window ending before the magic finds nothing
+            let mut reader = decompressed_reader_with(Box::new(std::io::Cursor::new(file.clone())), ct, "").unwrap();
+            assert!(reader.read_to_end(&mut Vec::new()).is_err(), "{}", ct);
+            let err = skip_preamble(Box::new(std::io::Cursor::new(file)), ct, 1000).err().unwrap();
+            assert_eq!(err.kind(), ErrorKind::InvalidData);
+        }
+        // the magic right at the start of the window, and at its very end
+        let stream = compress_bytes(b"x", CompressionType::Gzip, "").unwrap();
+        assert_eq!(skip_preamble(Box::new(std::io::Cursor::new(stream.clone())), CompressionType::Gzip, 0).unwrap().0, 0);
+        let mut file = vec![b'#'; 100];
+        file.extend_from_slice(&stream);
+        assert_eq!(skip_preamble(Box::new(std::io::Cursor::new(file.clone())), CompressionType::Gzip, 100).unwrap().0, 100);
+        assert!(skip_preamble(Box::new(std::io::Cursor::new(file)), CompressionType::Gzip, 99).is_err());
+        let err = skip_preamble(Box::new(std::io::empty()), CompressionType::Deflate, 10).err().unwrap();
+        assert_eq!(err.kind(), ErrorKind::Unsupported);
+    }
+}
@@ -0,0 +1,413 @@
+use std::error::Error;
+use std::fmt;
+use std::fs::File;
+use std::io::{Read, Seek, SeekFrom};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::sync::atomic::Ordering;
+use crate::cancel::CancelToken;
+use crate::checkpoint::{decompress_resumable, ResumeStart};
+use crate::files::{open_file, PathErrors};
+use crate::progress::{copy_with_progress, Progress, ProgressTracker};
+use crate::summary::CountingReader;
+use crate::{decompressed_reader, CompressionType};
+
+/// Default `Preflight::margin`
+pub const DEFAULT_MARGIN: u64 = 64 * 1024 * 1024;
+
+/// Bytes read by `decompressed_size_hint`, the longest zstd frame header
+const HINT_PEEK: usize = 18;
+
+/// The filesystem of the destination has no room for the output
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct InsufficientSpace {
+    /// Directory whose filesystem was queried
+    pub path: PathBuf,
+    /// Output size plus `Preflight::margin`
+    pub needed: u64,
+    pub available: u64,
+}
+
+impl fmt::Display for InsufficientSpace {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "not enough space in {}: {} needed, {} available", self.path.display(),
+            crate::fmt::format_bytes(self.needed), crate::fmt::format_bytes(self.available))
+    }
+}
+
+impl Error for InsufficientSpace {}
+
+/// Free space and preallocation of a filesystem, replaceable in tests
+pub trait SpaceQuery: Send + Sync {
+    /// Bytes an unprivileged process can still write to the filesystem of `dir`
+    fn available_space(&self, dir: &Path) -> std::io::Result<u64>;
+
+    /// Allocate `size` bytes for the empty `file`, which then has that length
+    fn preallocate(&self, file: &File, size: u64) -> std::io::Result<()>;
+}
+
+/// The filesystems of the operating system: `statvfs` and `posix_fallocate` on Unix,
+/// `GetDiskFreeSpaceExW` on Windows
+pub struct SystemSpace;
+
+impl SpaceQuery for SystemSpace {
+    fn available_space(&self, dir: &Path) -> std::io::Result<u64> {
+        return platform::available_space(dir);
+    }
+
+    fn preallocate(&self, file: &File, size: u64) -> std::io::Result<()> {
+        return platform::preallocate(file, size);
+    }
+}
+
+#[cfg(unix)]
+mod platform {
+    use std::ffi::CString;
+    use std::fs::File;
+    use std::io::ErrorKind;
+    use std::os::unix::ffi::OsStrExt;
+    use std::path::Path;
+
+    // the field types differ between platforms
+    #[allow(clippy::unnecessary_cast)]
+    pub(super) fn available_space(dir: &Path) -> std::io::Result<u64> {
+        let path = CString::new(dir.as_os_str().as_bytes()).map_err(|e| std::io::Error::new(ErrorKind::InvalidInput, e))?;
+        let mut stat: libc::statvfs = unsafe { std::mem::zeroed() };
+        if unsafe { libc::statvfs(path.as_ptr(), &mut stat) } != 0 {
+            return Err(std::io::Error::last_os_error());
+        }
+        return Ok((stat.f_bavail as u64).saturating_mul(stat.f_frsize as u64));
+    }
+
+    #[cfg(any(target_os = "linux", target_os = "android", target_os = "freebsd"))]
+    pub(super) fn preallocate(file: &File, size: u64) -> std::io::Result<()> {
+        use std::os::unix::io::AsRawFd;
+        let length = libc::off_t::try_from(size).map_err(|e| std::io::Error::new(ErrorKind::InvalidInput, e))?;
+        return match unsafe { libc::posix_fallocate(file.as_raw_fd(), 0, length) } {
+            0 => Ok(()),
+            // filesystems that cannot allocate ahead, like tmpfs on old kernels
+            libc::EOPNOTSUPP | libc::EINVAL => file.set_len(size),
+            errno => Err(std::io::Error::from_raw_os_error(errno)),
+        };
+    }
+
+    #[cfg(not(any(target_os = "linux", target_os = "android", target_os = "freebsd")))]
+    pub(super) fn preallocate(file: &File, size: u64) -> std::io::Result<()> {
+        return file.set_len(size);
+    }
+}
+
+#[cfg(windows)]
+mod platform {
+    use std::fs::File;
+    use std::os::windows::ffi::OsStrExt;
+    use std::path::Path;
+
+    #[link(name = "kernel32")]
+    extern "system" {
+        fn GetDiskFreeSpaceExW(directory: *const u16, free_to_caller: *mut u64, total: *mut u64, total_free: *mut u64) -> i32;
+    }
+
+    pub(super) fn available_space(dir: &Path) -> std::io::Result<u64> {
+        let wide: Vec<u16> = dir.as_os_str().encode_wide().chain(Some(0)).collect();
+        let mut free = 0u64;
+        if unsafe { GetDiskFreeSpaceExW(wide.as_ptr(), &mut free, std::ptr::null_mut(), std::ptr::null_mut()) } == 0 {
+            return Err(std::io::Error::last_os_error());
+        }
+        return Ok(free);
+    }
+
+    /// NTFS allocates the extended length, it does not make files sparse unless asked to
+    pub(super) fn preallocate(file: &File, size: u64) -> std::io::Result<()> {
+        return file.set_len(size);
+    }
+}
+
+#[cfg(not(any(unix, windows)))]
+mod platform {
+    use std::fs::File;
+    use std::io::ErrorKind;
+    use std::path::Path;
+
+    pub(super) fn available_space(_: &Path) -> std::io::Result<u64> {
+        return Err(std::io::Error::new(ErrorKind::Unsupported, "no free space query on this platform"));
+    }
+
+    pub(super) fn preallocate(file: &File, size: u64) -> std::io::Result<()> {
+        return file.set_len(size);
+    }
+}
+
+/// Free space check made before decompressing to disk, so that a nearly full disk fails the
+/// operation up front instead of after most of the output was written
+#[derive(Clone)]
+pub struct Preflight {
+    /// Free space required on top of the output size
+    pub margin: u64,
+    /// Allocate the output at its size before writing it, when that size is known
+    pub preallocate: bool,
+    pub space: Arc<dyn SpaceQuery>,
+    /// Keep checkpoints while decompressing gzip or zstd, and resume from those an interrupted
+    /// `decompress_file` left, see `checkpoint`
+    pub resume: bool,
+    /// Stops `decompress_file` with `Cancelled`, checked once per 64 KiB written
+    pub cancel: CancelToken,
+}
+
+impl Default for Preflight {
+    fn default() -> Self {
+        return Preflight { margin: DEFAULT_MARGIN, preallocate: true, space: Arc::new(SystemSpace), resume: false, cancel: CancelToken::new() };
+    }
+}
+
+/// What the preflight of an operation found
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+pub struct SpaceReport {
+    /// Output size the check was made for, `None` when unknown
+    pub needed: Option<u64>,
+    /// Free space of the destination, `None` when it was not checked
+    pub available: Option<u64>,
+    /// Whether outputs were allocated before being written
+    pub preallocated: bool,
+    /// Why the check or the preallocation was skipped
+    pub warnings: Vec<String>,
+    /// Bytes decompressed, once the operation completed
+    pub written: u64,
+    /// Where `decompress_file` started from, with `Preflight::resume`
+    pub resume: Option<ResumeStart>,
+}
+
+impl Preflight {
+    /// Fail with `InsufficientSpace` if the filesystem of `dir` cannot take `size` bytes and the
+    /// margin. An unknown size or free space skips the check with a warning.
+    pub(crate) fn check(&self, dir: &Path, size: Option<u64>) -> Result<SpaceReport, Box<dyn Error>> {
+        let mut report = SpaceReport { needed: size, ..SpaceReport::default() };
+        let Some(size) = size else {
+            report.warnings.push("output size unknown, free space not checked".into());
+            return Ok(report);
+        };
+        match self.space.available_space(dir) {
+            Ok(available) => {
+                report.available = Some(available);
+                let needed = size.saturating_add(self.margin);
+                if available < needed {
+                    return Err(InsufficientSpace { path: dir.to_path_buf(), needed, available }.into());
+                }
+            },
+            Err(e) => report.warnings.push(format!("free space of {} unknown, not checked: {}", dir.display(), e)),
+        }
+        return Ok(report);
+    }
+
+    /// Allocate `size` bytes for `file` if enabled, recording the outcome in `report`. A failure
+    /// is a warning: the output is written all the same.
+    pub(crate) fn preallocate(&self, file: &File, size: u64, report: &mut SpaceReport) {
+        if !self.preallocate || size == 0 {
+            return;
+        }
+        match self.space.preallocate(file, size) {
+            Ok(()) => report.preallocated = true,
+            Err(e) => {
+                let warning = format!("output not preallocated: {}", e);
+                if !report.warnings.contains(&warning) {
+                    report.warnings.push(warning);
+                }
+            },
+        }
+    }
+}
+
+/// Decompressed size announced by the header of the stream at the current position of `src`,
+/// which is restored: the content size of a zstd frame or an lz4 frame, or the remaining length
+/// for `None`. Streams of several frames announce the size of the first one only. A size the
+/// rest of the file cannot decode to is an `InvalidData` error.
+pub fn decompressed_size_hint<R: Read + Seek>(src: &mut R, compression_type: CompressionType) -> std::io::Result<Option<u64>> {
+    let start = src.stream_position()?;
+    let mut head = Vec::with_capacity(HINT_PEEK);
+    src.by_ref().take(HINT_PEEK as u64).read_to_end(&mut head)?;
+    let end = src.seek(SeekFrom::End(0))?;
+    src.seek(SeekFrom::Start(start))?;
+    let hint = match compression_type {
+        CompressionType::None => Some(end.saturating_sub(start)),
+        _ => declared_content_size(&head, compression_type),
+    };
+    if let Some(declared) = hint {
+        // checked before anything is allocated for it
+        crate::bounds::check_declared(compression_type, end.saturating_sub(start), declared)?;
+    }
+    return Ok(hint);
+}
+
+/// Content size in the header of the zstd or lz4 frame `head` starts with, unchecked
+pub(crate) fn declared_content_size(head: &[u8], compression_type: CompressionType) -> Option<u64> {
+    return match compression_type {
+        CompressionType::Zstd => zstd::zstd_safe::get_frame_content_size(head).ok().flatten(),
+        CompressionType::LZ4 => {
+            // magic, FLG with the content size bit, BD, then the size
+            match head.get(..14) {
+                Some(header) if header[..4] == [0x04, 0x22, 0x4d, 0x18] && header[4] & 0x08 != 0 =>
+                    Some(u64::from_le_bytes(header[6..14].try_into().unwrap())),
+                _ => None,
+            }
+        },
+        _ => None,
+    };
+}
+
+/// Decompress the file `src` into `dst`, checking first that the filesystem of `dst` has room
+/// for the output (see `decompressed_size_hint`) and preallocating it. Fails with
+/// `InsufficientSpace` before creating `dst` when it does not. Other errors name the path they
+/// are about; `dst` is removed after them, never left partial, unless `Preflight::resume` kept a
+/// checkpoint to restart from.
+pub fn decompress_file(src: &Path, dst: &Path, compression_type: CompressionType, preflight: &Preflight) -> Result<SpaceReport, Box<dyn Error>> {
+    return decompress_file_with_progress(src, dst, compression_type, preflight, |_| {});
+}
+
+/// `decompress_file`, calling `on_progress` as `src` is read, then once completed. The total is
+/// the length of `src`: the progress of the compressed input.
+pub fn decompress_file_with_progress(
+    src: &Path,
+    dst: &Path,
+    compression_type: CompressionType,
+    preflight: &Preflight,
+    mut on_progress: impl FnMut(&Progress)) -> Result<SpaceReport, Box<dyn Error>> {
+    if preflight.resume {
+        return decompress_resumable(src, dst, compression_type, preflight, &mut on_progress);
+    }
+    let mut input = open_file(src, |path| File::open(path))?;
+    let size = decompressed_size_hint(&mut input, compression_type).map_err(|e| format!("{}: {}", src.display(), e))?;
+    let dir = match dst.parent() {
+        Some(parent) if !parent.as_os_str().is_empty() => parent,
+        _ => Path::new("."),
+    };
+    let mut report = preflight.check(dir, size)?;
+    let total_in = input.metadata().map_err(|e| format!("{}: {}", src.display(), e))?.len();
+    let out = open_file(dst, |path| File::create(path))?;
+    let fill = || -> Result<(), Box<dyn Error>> {
+        if let Some(size) = size {
+            preflight.preallocate(&out, size, &mut report);
+        }
+        let (input, consumed) = CountingReader::new(input);
+        let mut reader = PathErrors::new(decompressed_reader(Box::new(input), compression_type)?, src);
+        let mut out = PathErrors::new(&out, dst);
+        let mut tracker = ProgressTracker::new(Some(total_in));
+        report.written = copy_with_progress(&mut reader, &mut out, &mut tracker,
+            |copied| (consumed.load(Ordering::Relaxed), copied), &mut on_progress, &preflight.cancel)?;
+        drop(reader);
+        on_progress(&tracker.finish(consumed.load(Ordering::Relaxed), report.written));
+        if report.preallocated && Some(report.written) != size {
+            // the hint only covered the first frame, or the stream lied
+            out.inner.set_len(report.written).map_err(|e| format!("{}: {}", dst.display(), e))?;
+        }
+        return Ok(());
+    };
+    if let Err(e) = fill() {
+        // preallocated, a partial output would have the size of a complete one
+        drop(out);
+        let _ = std::fs::remove_file(dst);
+        return Err(e);
+    }
+    return Ok(report);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::{Cursor, Write};
+    use std::sync::Mutex;
+    use crate::tree::{compress_tree, extract_matching_with};
+
+    /// A filesystem with `available` free bytes, recording the preallocations
+    struct MockSpace {
+        available: u64,
+        preallocated: Mutex<Vec<u64>>,
+    }
+
+    impl SpaceQuery for MockSpace {
+        fn available_space(&self, _: &Path) -> std::io::Result<u64> {
+            return Ok(self.available);
+        }
+
+        fn preallocate(&self, file: &File, size: u64) -> std::io::Result<()> {
+            self.preallocated.lock().unwrap().push(size);
+            return file.set_len(size);
+        }
+    }
+
+    fn mock(available: u64) -> (Arc<MockSpace>, Preflight) {
+        let space = Arc::new(MockSpace { available, preallocated: Mutex::new(Vec::new()) });
+        return (space.clone(), Preflight { margin: 1000, preallocate: true, space, resume: false, cancel: CancelToken::new() });
+    }
+
+    #[test]
+    pub fn test_decompress_file_preflight() {
+        let dir = std::env::temp_dir().join(format!("final_compression_preflight_{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let data = b"preflight checked output ".repeat(8000);
+        let src = dir.join("data.zst");
+        std::fs::write(&src, zstd::bulk::compress(&data, 3).unwrap()).unwrap();
+        let dst = dir.join("data");
+
+        // the frame records its content size: checked and preallocated
+        let (space, preflight) = mock(1 << 30);
+        let report = decompress_file(&src, &dst, CompressionType::Zstd, &preflight).unwrap();
+        assert_eq!(*space.preallocated.lock().unwrap(), [data.len() as u64]);
+        assert_eq!((report.needed, report.available, report.preallocated), (Some(data.len() as u64), Some(1 << 30), true));
+        assert!(report.warnings.is_empty());
+        assert!(std::fs::read(&dst).unwrap() == data);
+        std::fs::remove_file(&dst).unwrap();
+
+        // a stream failing midway leaves no preallocated output behind
+        let compressed = std::fs::read(&src).unwrap();
+        let broken = dir.join("broken.zst");
+        std::fs::write(&broken, &compressed[..compressed.len() / 2]).unwrap();
+        let (space, preflight) = mock(1 << 30);
+        assert!(decompress_file(&broken, &dst, CompressionType::Zstd, &preflight).is_err());
+        assert_eq!(*space.preallocated.lock().unwrap(), [data.len() as u64]);
+        assert!(!dst.exists());
+
+        // not enough room: nothing is written
+        let (space, preflight) = mock(data.len() as u64);
+        let err = decompress_file(&src, &dst, CompressionType::Zstd, &preflight).err().unwrap();
+        let err = err.downcast_ref::<InsufficientSpace>().unwrap();
+        assert_eq!((err.needed, err.available), (data.len() as u64 + 1000, data.len() as u64));
+        assert!(space.preallocated.lock().unwrap().is_empty() && !dst.exists());
+
+        // gzip announces no size: the check is skipped with a warning
+        let gz = dir.join("data.gz");
+        let mut w = crate::compressed_writer(Box::new(File::create(&gz).unwrap()), CompressionType::Gzip, "").unwrap();
+        w.write_all(&data).unwrap();
+        drop(w);
+        let (space, preflight) = mock(0);
+        let report = decompress_file(&gz, &dst, CompressionType::Gzip, &preflight).unwrap();
+        assert_eq!((report.needed, report.preallocated, report.warnings.len()), (None, false, 1));
+        assert!(space.preallocated.lock().unwrap().is_empty());
+        assert!(std::fs::read(&dst).unwrap() == data);
+
+        // the manifest of a tree knows every size
+        let (tree, compressed, out) = (dir.join("tree"), dir.join("tree.zst"), dir.join("out"));
+        std::fs::create_dir_all(&tree).unwrap();
+        std::fs::write(tree.join("a.txt"), &data).unwrap();
+        std::fs::write(tree.join("b.txt"), &data[..5000]).unwrap();
+        let manifest = compress_tree(&tree, &compressed, CompressionType::Gzip, "", |_| true).unwrap();
+        let (_, preflight) = mock(data.len() as u64);
+        let err = extract_matching_with(&compressed, &manifest, "*.txt", &out, &preflight).err().unwrap();
+        assert_eq!(err.downcast_ref::<InsufficientSpace>().unwrap().needed, data.len() as u64 + 5000 + 1000);
+        let (space, preflight) = mock(1 << 30);
+        let (extracted, report) = extract_matching_with(&compressed, &manifest, "*.txt", &out, &preflight).unwrap();
+        assert_eq!(extracted, ["a.txt", "b.txt"]);
+        assert_eq!(*space.preallocated.lock().unwrap(), [data.len() as u64, 5000]);
+        assert!(report.preallocated && std::fs::read(out.join("a.txt")).unwrap() == data);
+        std::fs::remove_dir_all(&dir).unwrap();
+
+        let header = vec![0x04, 0x22, 0x4d, 0x18, 0x68, 0x40, 0x39, 0x30, 0, 0, 0, 0, 0, 0, 0xff];
+        let mut lz4_header = Cursor::new([header.clone(), vec![0; 50]].concat());
+        assert_eq!(decompressed_size_hint(&mut lz4_header, CompressionType::LZ4).unwrap(), Some(12345));
+        assert_eq!(lz4_header.position(), 0);
+        // 15 bytes of LZ4 cannot decode to 12345
+        let err = decompressed_size_hint(&mut Cursor::new(header), CompressionType::LZ4).unwrap_err();
+        assert_eq!(err.kind(), std::io::ErrorKind::InvalidData);
+        #[cfg(unix)]
+        assert!(SystemSpace.available_space(&std::env::temp_dir()).unwrap() > 0);
+    }
+}
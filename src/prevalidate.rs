@@ -0,0 +1,482 @@
+use std::cell::RefCell;
+use std::error::Error;
+use std::io::{Read, Seek, SeekFrom};
+use std::rc::Rc;
+use std::time::{Duration, Instant};
+use crate::describe::BUILTIN_CODECS;
+use crate::{decompressed_reader_with, liblzo, libstored, min_compressed_len_for, minimal, preamble, CompressionType, ParamSet};
+
+/// How much of a file `prevalidated_reader` checks before returning its reader
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Prevalidate {
+    /// Walk the headers and block structure without decompressing: frames and blocks of zstd
+    /// and LZ4, chunks of snappy, blocks of the stored and LZO framings, the header and footer
+    /// of xz and the first member header of gzip. Corrupted payloads pass.
+    Structure,
+    /// `Structure`, then decompress everything once, verifying the checksums of the format
+    Full,
+}
+
+impl Prevalidate {
+    pub(crate) fn from_params(param_set: &ParamSet) -> Result<Option<Prevalidate>, Box<dyn Error>> {
+        match param_set.get_string("prevalidate", "") {
+            "" => return Ok(None),
+            "structure" => return Ok(Some(Prevalidate::Structure)),
+            "full" => return Ok(Some(Prevalidate::Full)),
+            other => return Err(format!("Invalid prevalidate `{}`, expected structure or full", other).into()),
+        }
+    }
+}
+
+/// What `prevalidated_reader` checked
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ValidationReport {
+    pub mode: Prevalidate,
+    pub duration: Duration,
+    /// Frames, members, chunks or blocks walked by the structure pass
+    pub units: u64,
+    /// Decompressed size, found by `Prevalidate::Full`
+    pub uncompressed_bytes: Option<u64>,
+    /// What the structure pass could not check, or noticed without failing
+    pub findings: Vec<String>,
+}
+
+/// Reader from `prevalidated_reader`, reading the validated file from its start
+pub struct PrevalidatedReader {
+    reader: Box<dyn Read>,
+    report: ValidationReport,
+}
+
+impl PrevalidatedReader {
+    pub fn report(&self) -> &ValidationReport {
+        return &self.report;
+    }
+}
+
+impl Read for PrevalidatedReader {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        return self.reader.read(buf);
+    }
+}
+
+/// Check `src` before decompressing it, so that a batch job finds a corrupted file before
+/// hours of processing rather than during them.
+///
+/// Parameters are those of `decompressed_reader_with`, plus
+///     prevalidate=structure|full (see `Prevalidate`, default structure)
+///
+/// Validation starts at the current position of `src`, which must be seekable: the reader
+/// returned starts there again. A failed validation is an error naming what is wrong and where;
+/// the reader only exists for files that passed.
+pub fn prevalidated_reader<R: Read + Seek + 'static, T: Into<ParamSet>>(
+    mut src: R,
+    compression_type: CompressionType,
+    option: T) -> Result<PrevalidatedReader, Box<dyn Error>> {
+    let mut param_set: ParamSet = option.into();
+    let mode = Prevalidate::from_params(&param_set)?.unwrap_or(Prevalidate::Structure);
+    param_set.set_raw("prevalidate", "");
+    let started = Instant::now();
+    let start = src.stream_position().and_then(|start| Ok((start, src.seek(SeekFrom::End(0))?)))
+        .map_err(|e| format!("prevalidate needs a seekable source: {}", e))?;
+    let (start, end) = start;
+    src.seek(SeekFrom::Start(start))?;
+
+    let mut findings = Vec::new();
+    let units = if minimal::is_minimal(&param_set)? || preamble::scan_limit(&param_set)? > 0 {
+        findings.push("structure not checked: minimal_overhead and scan_for_magic streams have no fixed layout".into());
+        0
+    } else {
+        let mut walker = Walker { src: &mut src, offset: start, end };
+        walk(&mut walker, compression_type, &mut findings)
+            .map_err(|e| format!("{} structure invalid at offset {}: {}", compression_type, walker.offset, e))?
+    };
+    src.seek(SeekFrom::Start(start))?;
+
+    let mut uncompressed_bytes = None;
+    if mode == Prevalidate::Full {
+        let shared = Rc::new(RefCell::new(src));
+        let mut strict = param_set.clone();
+        strict.set_raw("eof_policy", "strict");
+        let mut decoder = decompressed_reader_with(Box::new(Shared(shared.clone())), compression_type, strict)?;
+        let decoded = std::io::copy(&mut decoder, &mut std::io::sink())
+            .map_err(|e| format!("{} content invalid: {}", compression_type, e))?;
+        drop(decoder);
+        uncompressed_bytes = Some(decoded);
+        src = match Rc::try_unwrap(shared) {
+            Ok(src) => src.into_inner(),
+            Err(_) => return Err("the decoder kept its source".into()),
+        };
+        src.seek(SeekFrom::Start(start))?;
+    }
+
+    let reader = decompressed_reader_with(Box::new(src), compression_type, param_set)?;
+    let report = ValidationReport { mode, duration: started.elapsed(), units, uncompressed_bytes, findings };
+    return Ok(PrevalidatedReader { reader, report });
+}
+
+/// The source of the full pass, given back once the decoder is dropped
+struct Shared<R>(Rc<RefCell<R>>);
+
+impl<R: Read> Read for Shared<R> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        return self.0.borrow_mut().read(buf);
+    }
+}
+
+/// Cursor of the structure pass, seeking over payloads instead of reading them
+struct Walker<'a, R> {
+    src: &'a mut R,
+    offset: u64,
+    end: u64,
+}
+
+impl<R: Read + Seek> Walker<'_, R> {
+    fn bytes<const N: usize>(&mut self, what: &str) -> Result<[u8; N], String> {
+        if self.end - self.offset < N as u64 {
+            return Err(format!("file ends inside the {}", what));
+        }
+        let mut buf = [0u8; N];
+        self.src.read_exact(&mut buf).map_err(|e| e.to_string())?;
+        self.offset += N as u64;
+        return Ok(buf);
+    }
+
+    fn u32_le(&mut self, what: &str) -> Result<u32, String> {
+        return Ok(u32::from_le_bytes(self.bytes::<4>(what)?));
+    }
+
+    fn skip(&mut self, len: u64, what: &str) -> Result<(), String> {
+        if self.end - self.offset < len {
+            return Err(format!("{} of {} bytes runs past the end of the file", what, len));
+        }
+        self.src.seek(SeekFrom::Current(len as i64)).map_err(|e| e.to_string())?;
+        self.offset += len;
+        return Ok(());
+    }
+
+    fn skip_string(&mut self, what: &str) -> Result<(), String> {
+        while self.bytes::<1>(what)?[0] != 0 {}
+        return Ok(());
+    }
+
+    fn at_end(&self) -> bool {
+        return self.offset == self.end;
+    }
+}
+
+const ZSTD_MAGIC: u32 = 0xfd2f_b528;
+const LZ4_MAGIC: u32 = 0x184d_2204;
+/// Skippable frames of zstd and LZ4 share this magic, low 4 bits free
+const SKIPPABLE_MAGIC: u32 = 0x184d_2a50;
+
+/// Length of the zstd or lz4 frame `data` starts with, None if it is not a whole frame
+pub(crate) fn frame_len(data: &[u8], compression_type: CompressionType) -> Option<u64> {
+    let mut src = std::io::Cursor::new(data);
+    let mut w = Walker { src: &mut src, offset: 0, end: data.len() as u64 };
+    match compression_type {
+        CompressionType::Zstd => walk_zstd_frame(&mut w).ok()?,
+        CompressionType::LZ4 => walk_lz4_frame(&mut w).ok()?,
+        _ => return None,
+    }
+    return Some(w.offset);
+}
+
+/// Walk the structure of the stream, returning the units walked
+fn walk<R: Read + Seek>(w: &mut Walker<R>, compression_type: CompressionType, findings: &mut Vec<String>) -> Result<u64, String> {
+    let mut units = 0;
+    match compression_type {
+        CompressionType::Zstd => {
+            while !w.at_end() || units == 0 {
+                walk_zstd_frame(w)?;
+                units += 1;
+            }
+        },
+        CompressionType::LZ4 => {
+            while !w.at_end() || units == 0 {
+                walk_lz4_frame(w)?;
+                units += 1;
+            }
+        },
+        CompressionType::Snappy => {
+            if w.bytes::<10>("stream identifier")? != [0xff, 0x06, 0x00, 0x00, b's', b'N', b'a', b'P', b'p', b'Y'] {
+                return Err("no snappy stream identifier".into());
+            }
+            units += 1;
+            while !w.at_end() {
+                let header = w.bytes::<4>("chunk header")?;
+                let len = u32::from_le_bytes([header[1], header[2], header[3], 0]) as u64;
+                match header[0] {
+                    0x00 | 0x01 if len < 4 => return Err(format!("data chunk of {} bytes has no room for its checksum", len)),
+                    0x00 | 0x01 if len > 65536 + 4 => return Err(format!("data chunk of {} bytes is too large", len)),
+                    0x02..=0x7f => return Err(format!("reserved unskippable chunk type {:#04x}", header[0])),
+                    _ => {},
+                }
+                w.skip(len, "chunk")?;
+                units += 1;
+            }
+        },
+        CompressionType::Stored => {
+            let header = w.bytes::<{ libstored::STREAM_HEADER_SIZE }>("stream header")?;
+            if &header[..4] != libstored::STORED_MAGIC || header[4] != libstored::STORED_VERSION {
+                return Err("not a stored stream".into());
+            }
+            loop {
+                let len = w.u32_le("block header")? as u64;
+                w.skip(4, "block checksum")?;
+                if len == 0 {
+                    break;
+                }
+                if len > libstored::MAX_BLOCK_SIZE as u64 {
+                    return Err(format!("block of {} bytes exceeds the limit", len));
+                }
+                w.skip(len, "block")?;
+                units += 1;
+            }
+            trailing(w, findings);
+        },
+        CompressionType::Lzo => {
+            let header = w.bytes::<{ liblzo::STREAM_HEADER_SIZE }>("stream header")?;
+            if &header[..4] != liblzo::LZO_MAGIC || header[4] != liblzo::LZO_VERSION {
+                return Err("not a framed LZO stream".into());
+            }
+            loop {
+                let header = w.bytes::<{ liblzo::BLOCK_HEADER_SIZE }>("block header")?;
+                let stored_len = u32::from_le_bytes(header[..4].try_into().unwrap()) as u64;
+                let len = u32::from_le_bytes(header[4..8].try_into().unwrap()) as u64;
+                if header == [0; liblzo::BLOCK_HEADER_SIZE] {
+                    break;
+                }
+                let stored = header[8] & liblzo::FLAG_STORED != 0;
+                if len == 0 || len > liblzo::BLOCK_SIZE as u64 || header[8] & !liblzo::FLAG_STORED != 0
+                    || (stored && stored_len != len) || (!stored && stored_len > rust_lzo::worst_compress(len as usize) as u64)
+                    || (!stored && stored_len < min_compressed_len_for(CompressionType::Lzo, len)) {
+                    return Err(format!("invalid block header: {} bytes stored for {}, flags {:#04x}", stored_len, len, header[8]));
+                }
+                w.skip(stored_len, "block")?;
+                units += 1;
+            }
+            trailing(w, findings);
+        },
+        CompressionType::Gzip => {
+            let header = w.bytes::<10>("member header")?;
+            if header[..3] != [0x1f, 0x8b, 8] || header[3] & 0xe0 != 0 {
+                return Err("no gzip member header".into());
+            }
+            let flags = header[3];
+            if flags & 0x04 != 0 {
+                let len = u16::from_le_bytes(w.bytes::<2>("extra field length")?);
+                w.skip(len as u64, "extra field")?;
+            }
+            if flags & 0x08 != 0 {
+                w.skip_string("file name")?;
+            }
+            if flags & 0x10 != 0 {
+                w.skip_string("comment")?;
+            }
+            if flags & 0x02 != 0 {
+                w.skip(2, "header checksum")?;
+            }
+            units += 1;
+            findings.push("gzip data has no block structure: only the first member header is checked without prevalidate=full".into());
+        },
+        CompressionType::XZ => {
+            let header = w.bytes::<12>("stream header")?;
+            if header[..6] != [0xfd, b'7', b'z', b'X', b'Z', 0] {
+                return Err("no xz stream header".into());
+            }
+            if w.end - w.offset < 12 {
+                return Err("no room for the xz stream footer".into());
+            }
+            w.skip(w.end - w.offset - 2, "stream")?;
+            if w.bytes::<2>("stream footer")? != *b"YZ" {
+                return Err("the file does not end with an xz stream footer".into());
+            }
+            units += 1;
+        },
+        _ => {
+            let codec = BUILTIN_CODECS.iter().find(|codec| codec.compression_type == compression_type);
+            if let Some(magic) = codec.and_then(|codec| codec.magic) {
+                let mut found = vec![0u8; magic.len()];
+                if w.end - w.offset < magic.len() as u64 || w.src.read_exact(&mut found).is_err() || found != magic {
+                    return Err(format!("no {} magic number", compression_type));
+                }
+            }
+            findings.push(format!("{} has no structure to walk: only prevalidate=full checks it", compression_type));
+        },
+    }
+    return Ok(units);
+}
+
+fn trailing<R: Read + Seek>(w: &Walker<R>, findings: &mut Vec<String>) {
+    if !w.at_end() {
+        findings.push(format!("{} bytes after the end marker", w.end - w.offset));
+    }
+}
+
+fn walk_zstd_frame<R: Read + Seek>(w: &mut Walker<R>) -> Result<(), String> {
+    let magic = w.u32_le("frame magic")?;
+    if magic & 0xffff_fff0 == SKIPPABLE_MAGIC {
+        let len = w.u32_le("skippable frame size")?;
+        return w.skip(len as u64, "skippable frame");
+    }
+    if magic != ZSTD_MAGIC {
+        return Err(format!("no zstd frame magic, found {:#010x}", magic));
+    }
+    let descriptor = w.bytes::<1>("frame header")?[0];
+    if descriptor & 0x08 != 0 {
+        return Err("reserved bit of the frame header set".into());
+    }
+    let single_segment = descriptor & 0x20 != 0;
+    let content_size = [single_segment as u64, 2, 4, 8][(descriptor >> 6) as usize];
+    let dictionary_id = [0, 1, 2, 4][(descriptor & 3) as usize];
+    w.skip(!single_segment as u64 + dictionary_id + content_size, "frame header")?;
+    loop {
+        let header = w.bytes::<3>("block header")?;
+        let header = u32::from_le_bytes([header[0], header[1], header[2], 0]);
+        let size = (header >> 3) as u64;
+        match (header >> 1) & 3 {
+            0 => w.skip(size, "raw block")?,
+            1 => w.skip(1, "RLE block")?,
+            2 if size > 128 * 1024 => return Err(format!("compressed block of {} bytes exceeds 128 KiB", size)),
+            2 => w.skip(size, "compressed block")?,
+            _ => return Err("reserved block type".into()),
+        }
+        if header & 1 != 0 {
+            break;
+        }
+    }
+    if descriptor & 0x04 != 0 {
+        w.skip(4, "content checksum")?;
+    }
+    return Ok(());
+}
+
+fn walk_lz4_frame<R: Read + Seek>(w: &mut Walker<R>) -> Result<(), String> {
+    let magic = w.u32_le("frame magic")?;
+    if magic & 0xffff_fff0 == SKIPPABLE_MAGIC {
+        let len = w.u32_le("skippable frame size")?;
+        return w.skip(len as u64, "skippable frame");
+    }
+    if magic != LZ4_MAGIC {
+        return Err(format!("no LZ4 frame magic, found {:#010x}", magic));
+    }
+    let [flags, block_descriptor] = w.bytes::<2>("frame descriptor")?;
+    if flags >> 6 != 1 || flags & 0x02 != 0 || block_descriptor & 0x8f != 0 || block_descriptor >> 4 < 4 {
+        return Err(format!("invalid frame descriptor {:#04x} {:#04x}", flags, block_descriptor));
+    }
+    let max_block = 1u64 << (8 + 2 * (block_descriptor >> 4));
+    let block_checksum = flags & 0x10 != 0;
+    w.skip(if flags & 0x08 != 0 { 8 } else { 0 } + if flags & 0x01 != 0 { 4 } else { 0 } + 1, "frame descriptor")?;
+    loop {
+        let size = w.u32_le("block size")?;
+        if size == 0 {
+            break;
+        }
+        let len = (size & 0x7fff_ffff) as u64;
+        if len > max_block {
+            return Err(format!("block of {} bytes exceeds the frame's {} byte blocks", len, max_block));
+        }
+        w.skip(len + if block_checksum { 4 } else { 0 }, "block")?;
+    }
+    if flags & 0x04 != 0 {
+        w.skip(4, "content checksum")?;
+    }
+    return Ok(());
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::{Cursor, Write};
+
+    fn zstd_frame(data: &[u8]) -> Vec<u8> {
+        let mut encoder = zstd::Encoder::new(Vec::new(), 3).unwrap();
+        encoder.include_checksum(true).unwrap();
+        encoder.write_all(data).unwrap();
+        return encoder.finish().unwrap();
+    }
+
+    fn validate(content: Vec<u8>, ct: CompressionType, mode: &str) -> Result<PrevalidatedReader, Box<dyn Error>> {
+        return prevalidated_reader(Cursor::new(content), ct, format!("prevalidate={}", mode));
+    }
+
+    struct Pipe(Cursor<Vec<u8>>);
+
+    impl Read for Pipe {
+        fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+            return self.0.read(buf);
+        }
+    }
+
+    impl Seek for Pipe {
+        fn seek(&mut self, _: SeekFrom) -> std::io::Result<u64> {
+            return Err(std::io::Error::from_raw_os_error(29));
+        }
+    }
+
+    #[test]
+    pub fn test_prevalidate() {
+        let parts: Vec<Vec<u8>> = (0..3).map(|i| format!("member {} of the batch input\n", i).repeat(2000).into_bytes()).collect();
+        let frames: Vec<Vec<u8>> = parts.iter().map(|part| zstd_frame(part)).collect();
+        let clean = frames.concat();
+        let middle = frames[0].len() + frames[1].len() / 2;
+
+        for mode in ["structure", "full"] {
+            let mut reader = validate(clean.clone(), CompressionType::Zstd, mode).unwrap();
+            assert_eq!(reader.report().units, 3);
+            assert!(reader.report().findings.is_empty());
+            let mut plain = Vec::new();
+            reader.read_to_end(&mut plain).unwrap();
+            assert!(plain == parts.concat(), "{}", mode);
+        }
+        let report = validate(clean.clone(), CompressionType::Zstd, "full").unwrap().report().clone();
+        assert_eq!((report.mode, report.uncompressed_bytes), (Prevalidate::Full, Some(parts.concat().len() as u64)));
+
+        // a damaged payload keeps the structure
+        let mut payload = clean.clone();
+        payload[middle] ^= 0x55;
+        assert!(validate(payload.clone(), CompressionType::Zstd, "structure").is_ok());
+        let err = validate(payload, CompressionType::Zstd, "full").err().unwrap();
+        assert!(err.to_string().starts_with("zstd content invalid"), "{}", err);
+
+        // a damaged frame header does not
+        let mut header = clean.clone();
+        header[frames[0].len()] = 0;
+        for mode in ["structure", "full"] {
+            let err = validate(header.clone(), CompressionType::Zstd, mode).err().unwrap();
+            assert!(err.to_string().starts_with(&format!("zstd structure invalid at offset {}", frames[0].len() + 4)), "{}", err);
+        }
+        let err = validate(clean[..clean.len() - 10].to_vec(), CompressionType::Zstd, "structure").err().unwrap();
+        assert!(err.to_string().contains("runs past the end of the file"), "{}", err);
+
+        // deflate has no structure to walk before decoding
+        let mut encoder = flate2::write::GzEncoder::new(Vec::new(), flate2::Compression::new(6));
+        encoder.write_all(&parts[0]).unwrap();
+        let mut gzip = encoder.finish().unwrap();
+        let crc = gzip.len() - 8;
+        gzip[crc] ^= 0x55;
+        let reader = validate(gzip.clone(), CompressionType::Gzip, "structure").unwrap();
+        assert_eq!(reader.report().findings.len(), 1);
+        assert!(validate(gzip, CompressionType::Gzip, "full").is_err());
+
+        let err = prevalidated_reader(Pipe(Cursor::new(clean.clone())), CompressionType::Zstd, "prevalidate=full").err().unwrap();
+        assert!(err.to_string().starts_with("prevalidate needs a seekable source"), "{}", err);
+        let err = crate::decompressed_reader_with(Box::new(Cursor::new(clean)), CompressionType::Zstd, "prevalidate=full").err().unwrap();
+        assert!(err.to_string().contains("prevalidated_reader"), "{}", err);
+    }
+
+    #[test]
+    pub fn test_structure_of_every_codec() {
+        let data = b"walk me\n".repeat(20_000);
+        for codec in crate::describe::BUILTIN_CODECS {
+            let buffer = crate::buffer::SharedBuffer::default();
+            let mut w = crate::compressed_writer(Box::new(buffer.clone()), codec.compression_type, "").unwrap();
+            w.write_all(&data).unwrap();
+            drop(w);
+            let mut reader = validate(buffer.take(), codec.compression_type, "structure").unwrap();
+            let mut plain = Vec::new();
+            reader.read_to_end(&mut plain).unwrap();
+            assert!(plain == data, "{}", codec.name);
+        }
+    }
+}
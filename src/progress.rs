@@ -0,0 +1,203 @@
+use std::error::Error;
+use std::fmt;
+use std::io::{IsTerminal, Read, Write};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use crate::cancel::CancelToken;
+use crate::clock::{Clock, SystemClock};
+
+/// Time constant of the throughput average: older samples weigh `1/e` less every 3 seconds
+const RATE_TIME_CONSTANT: f64 = 3.0;
+/// Largest fraction reported before the operation completes
+const BEFORE_COMPLETION: f64 = 1.0 - f64::EPSILON;
+/// Shortest interval between two lines of `stderr_progress`
+const RENDER_INTERVAL: Duration = Duration::from_millis(200);
+const COPY_BUFFER_SIZE: usize = 64 * 1024;
+
+/// State of an operation in progress
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub struct Progress {
+    /// Bytes consumed
+    pub bytes_in: u64,
+    /// Bytes produced
+    pub bytes_out: u64,
+    pub elapsed: Duration,
+    /// Bytes to consume in all, when known
+    pub total_in: Option<u64>,
+    /// Share of `total_in` consumed, 1.0 only once the operation completed
+    pub fraction: Option<f64>,
+    /// Time left at the recent throughput, once there is one
+    pub eta: Option<Duration>,
+}
+
+/// `42.0% 1.2 MiB of 2.9 MiB, 14s left`, or the bytes consumed alone when the total is unknown
+impl fmt::Display for Progress {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let bytes_in = crate::fmt::format_bytes(self.bytes_in);
+        let (Some(total_in), Some(fraction)) = (self.total_in, self.fraction) else {
+            return f.write_str(&bytes_in);
+        };
+        write!(f, "{:.1}% {} of {}", fraction * 100.0, bytes_in, crate::fmt::format_bytes(total_in))?;
+        if let Some(eta) = self.eta.filter(|_| fraction < 1.0) {
+            let seconds = eta.as_secs_f64().ceil() as u64;
+            match seconds {
+                0..60 => write!(f, ", {}s left", seconds)?,
+                60..3600 => write!(f, ", {}m{:02}s left", seconds / 60, seconds % 60)?,
+                _ => write!(f, ", {}h{:02}m left", seconds / 3600, seconds / 60 % 60)?,
+            }
+        }
+        return Ok(());
+    }
+}
+
+/// Turns byte counts into `Progress`, estimating the throughput with an exponentially weighted
+/// average, so the ETA follows speed changes without jumping at every sample
+pub struct ProgressTracker {
+    clock: Arc<dyn Clock>,
+    start: Instant,
+    total_in: Option<u64>,
+    /// Time and bytes of the last sample
+    last: (Instant, u64),
+    /// Bytes per second
+    rate: Option<f64>,
+}
+
+impl ProgressTracker {
+    pub fn new(total_in: Option<u64>) -> ProgressTracker {
+        return ProgressTracker::with_clock(total_in, Arc::new(SystemClock));
+    }
+
+    pub fn with_clock(total_in: Option<u64>, clock: Arc<dyn Clock>) -> ProgressTracker {
+        let start = clock.now();
+        return ProgressTracker { clock, start, total_in, last: (start, 0), rate: None };
+    }
+
+    /// Progress after `bytes_in` bytes consumed and `bytes_out` produced
+    pub fn update(&mut self, bytes_in: u64, bytes_out: u64) -> Progress {
+        let now = self.clock.now();
+        let elapsed = now.duration_since(self.last.0).as_secs_f64();
+        if elapsed > 0.0 && bytes_in >= self.last.1 {
+            let sample = (bytes_in - self.last.1) as f64 / elapsed;
+            let weight = 1.0 - (-elapsed / RATE_TIME_CONSTANT).exp();
+            self.rate = Some(self.rate.map_or(sample, |rate| rate + weight * (sample - rate)));
+            self.last = (now, bytes_in);
+        }
+        let eta = match (self.total_in, self.rate) {
+            (Some(total_in), Some(rate)) if rate > 0.0 => Some(Duration::from_secs_f64(total_in.saturating_sub(bytes_in) as f64 / rate)),
+            _ => None,
+        };
+        return Progress {
+            bytes_in,
+            bytes_out,
+            elapsed: now.duration_since(self.start),
+            total_in: self.total_in,
+            fraction: self.total_in.map(|total_in| match total_in {
+                0 => 0.0,
+                _ => (bytes_in as f64 / total_in as f64).min(BEFORE_COMPLETION),
+            }),
+            eta,
+        };
+    }
+
+    /// Progress of the completed operation: fraction 1.0, nothing left
+    pub fn finish(&mut self, bytes_in: u64, bytes_out: u64) -> Progress {
+        let progress = self.update(bytes_in, bytes_out);
+        let known = progress.total_in.is_some();
+        return Progress { fraction: known.then_some(1.0), eta: known.then_some(Duration::ZERO), ..progress };
+    }
+}
+
+/// Progress callback drawing a line on stderr, redrawn at most every 200 ms and ended once the
+/// operation completes. Does nothing when stderr is not a terminal.
+pub fn stderr_progress() -> Box<dyn FnMut(&Progress) + Send> {
+    if !std::io::stderr().is_terminal() {
+        return Box::new(|_: &Progress| {});
+    }
+    let mut last: Option<Instant> = None;
+    return Box::new(move |progress: &Progress| {
+        let done = progress.fraction == Some(1.0);
+        if !done && last.is_some_and(|last| last.elapsed() < RENDER_INTERVAL) {
+            return;
+        }
+        last = Some(Instant::now());
+        let mut stderr = std::io::stderr().lock();
+        // clear the end of the previous line, which may be longer
+        let _ = write!(stderr, "\r{}\x1b[K{}", progress, if done { "\n" } else { "" });
+        let _ = stderr.flush();
+    });
+}
+
+/// Copy `src` into `dst`, reporting the progress after every chunk. `counts` turns the bytes
+/// copied into the bytes consumed and produced, one of them counted elsewhere. Returns the bytes
+/// copied; reporting the completion is up to the caller, once its output is complete. `cancel`
+/// is checked before every chunk, failing with `Cancelled` and the counts at that point.
+pub(crate) fn copy_with_progress(
+    src: &mut dyn Read,
+    dst: &mut dyn Write,
+    tracker: &mut ProgressTracker,
+    counts: impl Fn(u64) -> (u64, u64),
+    callback: &mut dyn FnMut(&Progress),
+    cancel: &CancelToken) -> Result<u64, Box<dyn Error>> {
+    let mut buffer = vec![0u8; COPY_BUFFER_SIZE];
+    let mut copied = 0u64;
+    loop {
+        let (bytes_in, bytes_out) = counts(copied);
+        cancel.check(bytes_in, bytes_out)?;
+        let read = match src.read(&mut buffer) {
+            Ok(0) => return Ok(copied),
+            Ok(read) => read,
+            Err(e) if e.kind() == std::io::ErrorKind::Interrupted => continue,
+            Err(e) => return Err(e.into()),
+        };
+        dst.write_all(&buffer[..read])?;
+        copied += read as u64;
+        let (bytes_in, bytes_out) = counts(copied);
+        callback(&tracker.update(bytes_in, bytes_out));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::clock::ManualClock;
+
+    #[test]
+    pub fn test_eta() {
+        let clock = Arc::new(ManualClock::new());
+        let total = 10 << 20;
+        let mut tracker = ProgressTracker::with_clock(Some(total), clock.clone());
+        let mut reports = Vec::new();
+        // 1 MiB every second
+        for step in 1..=10 {
+            clock.advance(Duration::from_secs(1));
+            reports.push(tracker.update(step << 20, step << 18));
+        }
+        clock.advance(Duration::from_millis(300));
+        reports.push(tracker.finish(total, total / 4));
+
+        let complete: Vec<usize> = (0..reports.len()).filter(|i| reports[*i].fraction == Some(1.0)).collect();
+        assert_eq!(complete, [reports.len() - 1]);
+        assert!(reports.windows(2).all(|pair| pair[0].fraction < pair[1].fraction));
+        let etas: Vec<Duration> = reports.iter().map(|p| p.eta.unwrap()).collect();
+        assert!(etas[..10].windows(2).all(|pair| pair[1] < pair[0]), "{:?}", etas);
+        assert_eq!((etas[0], etas[9], etas[10]), (Duration::from_secs(9), Duration::ZERO, Duration::ZERO));
+        assert_eq!(reports[4].to_string(), "50.0% 5.00 MiB of 10.0 MiB, 5s left");
+        assert_eq!(reports[10].elapsed, Duration::from_millis(10_300));
+        assert_eq!(reports[10].to_string(), "100.0% 10.0 MiB of 10.0 MiB");
+
+        // the estimate follows a slowdown
+        let mut tracker = ProgressTracker::with_clock(Some(total), clock.clone());
+        clock.advance(Duration::from_secs(1));
+        let fast = tracker.update(4 << 20, 0).eta.unwrap();
+        clock.advance(Duration::from_secs(4));
+        let slow = tracker.update(5 << 20, 0).eta.unwrap();
+        assert!(fast < Duration::from_secs(2) && slow > Duration::from_secs(2), "{:?} {:?}", fast, slow);
+
+        // nothing is guessed without a total
+        let mut tracker = ProgressTracker::with_clock(None, clock.clone());
+        clock.advance(Duration::from_secs(1));
+        let progress = tracker.finish(1000, 10);
+        assert_eq!((progress.total_in, progress.fraction, progress.eta), (None, None, None));
+        assert_eq!(progress.to_string(), "1000 B");
+    }
+}
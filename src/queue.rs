@@ -0,0 +1,559 @@
+use std::collections::BinaryHeap;
+use std::cmp::Ordering as CmpOrdering;
+use std::error::Error;
+use std::fmt;
+use std::io::{Read, Write};
+use std::path::PathBuf;
+use std::sync::{Arc, Condvar, Mutex};
+use std::sync::atomic::Ordering;
+use std::thread::JoinHandle;
+use std::time::Instant;
+use crate::cancel::{CancelToken, Cancelled};
+use crate::summary::{CountingWriter, OperationSummary};
+use crate::{durable_writer, CompressionType};
+
+const COPY_BUFFER_SIZE: usize = 64 * 1024;
+/// A queued job gains one priority level for every this many jobs submitted after it
+pub const AGING_SUBMISSIONS: i64 = 16;
+
+/// Where a queued job reads its uncompressed data from
+pub enum JobInput {
+    /// Read the content of a file
+    Path(PathBuf),
+    /// Read from an arbitrary reader
+    Reader(Box<dyn Read + Send>),
+    /// Compress an in-memory buffer
+    Bytes(Vec<u8>),
+}
+
+/// Where a queued job writes its compressed data to
+pub enum JobOutput {
+    /// Create (or truncate) a file
+    Path(PathBuf),
+    /// Write to an arbitrary writer
+    Writer(Box<dyn Write + Send>),
+}
+
+/// A compression job for the `CompressionQueue`
+pub struct CompressJob {
+    pub input: JobInput,
+    pub output: JobOutput,
+    pub compression_type: CompressionType,
+    /// ParamSet expression for the compressor, e.g. "level=3"
+    pub params: String,
+    /// Jobs with higher priority are started first. Jobs with equal priority start in submission order.
+    /// Waiting jobs age: every `AGING_SUBMISSIONS` jobs submitted after one count as a priority
+    /// level of its own, so a steady flow of higher priority jobs delays it but never starves it.
+    pub priority: i32,
+}
+
+/// Reasons a queued job did not produce an `OperationSummary`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum QueueError {
+    /// The job was cancelled before or while running, with the progress it made
+    Cancelled(Cancelled),
+    /// The queue was shut down before the job started
+    ShutDown,
+    /// The job failed, carrying the error message
+    Failed(String),
+}
+
+impl fmt::Display for QueueError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            QueueError::Cancelled(cancelled) => write!(f, "compression job {}", cancelled),
+            QueueError::ShutDown => write!(f, "compression queue shut down"),
+            QueueError::Failed(message) => write!(f, "compression job failed: {}", message),
+        }
+    }
+}
+
+impl Error for QueueError {}
+
+enum JobState {
+    Queued,
+    Running,
+    Done(Result<OperationSummary, QueueError>),
+}
+
+struct JobSlot {
+    state: Mutex<JobState>,
+    done: Condvar,
+    cancel: CancelToken,
+}
+
+impl JobSlot {
+    fn complete(&self, result: Result<OperationSummary, QueueError>) {
+        let mut state = self.state.lock().unwrap_or_else(|e| e.into_inner());
+        if let JobState::Done(_) = *state {
+            return;
+        }
+        *state = JobState::Done(result);
+        self.done.notify_all();
+    }
+}
+
+/// Handle to a submitted job
+pub struct JobHandle {
+    slot: Arc<JobSlot>,
+}
+
+impl JobHandle {
+    /// Block until the job finished, was cancelled, or was dropped by a shutdown. The elapsed
+    /// time of the summary excludes the time the job waited in the queue.
+    pub fn wait(&self) -> Result<OperationSummary, QueueError> {
+        let mut state = self.slot.state.lock().unwrap_or_else(|e| e.into_inner());
+        loop {
+            if let JobState::Done(result) = &*state {
+                return result.clone();
+            }
+            state = self.slot.done.wait(state).unwrap_or_else(|e| e.into_inner());
+        }
+    }
+
+    /// Request cancellation. A queued job will never start; a running job stops at the next buffer
+    /// boundary and reports `QueueError::Cancelled` with the bytes it read and wrote, its
+    /// `JobOutput::Path` being removed. Returns false if the job already finished.
+    pub fn cancel(&self) -> bool {
+        let mut state = self.slot.state.lock().unwrap_or_else(|e| e.into_inner());
+        match *state {
+            JobState::Done(_) => return false,
+            JobState::Queued => {
+                self.slot.cancel.cancel();
+                *state = JobState::Done(Err(QueueError::Cancelled(Cancelled { bytes_in: 0, bytes_out: 0 })));
+                self.slot.done.notify_all();
+                return true;
+            },
+            JobState::Running => {
+                self.slot.cancel.cancel();
+                return true;
+            }
+        }
+    }
+}
+
+struct Queued {
+    /// Priority, minus the aging of the jobs submitted before: higher starts first
+    rank: i64,
+    sequence: u64,
+    job: CompressJob,
+    slot: Arc<JobSlot>,
+}
+
+impl PartialEq for Queued {
+    fn eq(&self, other: &Self) -> bool {
+        self.cmp(other) == CmpOrdering::Equal
+    }
+}
+
+impl Eq for Queued {}
+
+impl PartialOrd for Queued {
+    fn partial_cmp(&self, other: &Self) -> Option<CmpOrdering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for Queued {
+    fn cmp(&self, other: &Self) -> CmpOrdering {
+        // max-heap: higher rank first, then lower sequence (earlier submission) first
+        self.rank.cmp(&other.rank).then_with(|| other.sequence.cmp(&self.sequence))
+    }
+}
+
+struct QueueState {
+    pending: BinaryHeap<Queued>,
+    next_sequence: u64,
+    shutdown: bool,
+}
+
+struct Shared {
+    state: Mutex<QueueState>,
+    available: Condvar,
+}
+
+/// Executor running compression jobs on a fixed number of worker threads.
+///
+/// At most `max_concurrent` jobs run at the same time; queued jobs are started by priority.
+/// Dropping the queue performs a graceful `shutdown`.
+pub struct CompressionQueue {
+    shared: Arc<Shared>,
+    workers: Vec<JoinHandle<()>>,
+}
+
+impl CompressionQueue {
+    /// Create a queue running at most `max_concurrent` jobs (at least 1) simultaneously.
+    pub fn new(max_concurrent: usize) -> CompressionQueue {
+        let shared = Arc::new(Shared {
+            state: Mutex::new(QueueState {
+                pending: BinaryHeap::new(),
+                next_sequence: 0,
+                shutdown: false,
+            }),
+            available: Condvar::new(),
+        });
+        let workers = (0..max_concurrent.max(1))
+            .map(|_| {
+                let shared = shared.clone();
+                std::thread::spawn(move || worker_loop(shared))
+            })
+            .collect();
+        return CompressionQueue { shared, workers };
+    }
+
+    /// Submit a job. After shutdown the returned handle reports `QueueError::ShutDown`.
+    pub fn submit(&self, job: CompressJob) -> JobHandle {
+        return self.submit_with_token(job, CancelToken::new());
+    }
+
+    /// Submit a job that is also cancelled by `cancel`, checked before the job starts and once
+    /// per 64 KiB buffer while it runs. `JobHandle::cancel` cancels a child of it.
+    pub fn submit_with_token(&self, job: CompressJob, cancel: CancelToken) -> JobHandle {
+        let slot = Arc::new(JobSlot {
+            state: Mutex::new(JobState::Queued),
+            done: Condvar::new(),
+            cancel: cancel.child(),
+        });
+        let mut state = self.shared.state.lock().unwrap_or_else(|e| e.into_inner());
+        if state.shutdown {
+            slot.complete(Err(QueueError::ShutDown));
+        } else {
+            let sequence = state.next_sequence;
+            state.next_sequence += 1;
+            let rank = (job.priority as i64 * AGING_SUBMISSIONS).saturating_sub(sequence as i64);
+            state.pending.push(Queued { rank, sequence, job, slot: slot.clone() });
+            self.shared.available.notify_one();
+        }
+        return JobHandle { slot };
+    }
+
+    /// Number of jobs waiting to start
+    pub fn pending(&self) -> usize {
+        return self.shared.state.lock().unwrap_or_else(|e| e.into_inner()).pending.len();
+    }
+
+    /// Stop accepting jobs, finish the jobs that are running and fail the queued ones with
+    /// `QueueError::ShutDown`.
+    pub fn shutdown(mut self) {
+        self.stop();
+    }
+
+    fn stop(&mut self) {
+        {
+            let mut state = self.shared.state.lock().unwrap_or_else(|e| e.into_inner());
+            state.shutdown = true;
+            for queued in state.pending.drain() {
+                queued.slot.complete(Err(QueueError::ShutDown));
+            }
+            self.shared.available.notify_all();
+        }
+        for worker in self.workers.drain(..) {
+            let _ = worker.join();
+        }
+    }
+}
+
+impl Drop for CompressionQueue {
+    fn drop(&mut self) {
+        self.stop();
+    }
+}
+
+fn worker_loop(shared: Arc<Shared>) {
+    loop {
+        let queued = {
+            let mut state = shared.state.lock().unwrap_or_else(|e| e.into_inner());
+            loop {
+                if let Some(queued) = state.pending.pop() {
+                    break queued;
+                }
+                if state.shutdown {
+                    return;
+                }
+                state = shared.available.wait(state).unwrap_or_else(|e| e.into_inner());
+            }
+        };
+        {
+            let mut state = queued.slot.state.lock().unwrap_or_else(|e| e.into_inner());
+            if let JobState::Done(_) = *state {
+                // cancelled while queued
+                continue;
+            }
+            *state = JobState::Running;
+        }
+        let result = run_job(queued.job, &queued.slot.cancel);
+        queued.slot.complete(result);
+    }
+}
+
+fn run_job(job: CompressJob, cancel: &CancelToken) -> Result<OperationSummary, QueueError> {
+    cancel.check(0, 0).map_err(QueueError::Cancelled)?;
+    let started = Instant::now();
+    let failed = |e: &dyn fmt::Display| QueueError::Failed(e.to_string());
+    let mut input: Box<dyn Read + Send> = match job.input {
+        JobInput::Path(path) => Box::new(std::fs::File::open(&path)
+            .map_err(|e| QueueError::Failed(format!("{}: {}", path.display(), e)))?),
+        JobInput::Reader(reader) => reader,
+        JobInput::Bytes(bytes) => Box::new(std::io::Cursor::new(bytes)),
+    };
+    let (output, output_path): (Box<dyn Write + Send>, _) = match job.output {
+        JobOutput::Path(path) => (Box::new(std::fs::File::create(&path)
+            .map_err(|e| QueueError::Failed(format!("{}: {}", path.display(), e)))?), Some(path)),
+        JobOutput::Writer(writer) => (writer, None),
+    };
+    let (output, bytes_out) = CountingWriter::new(output);
+    let mut writer = durable_writer(Box::new(output), job.compression_type, job.params.as_str())
+        .map_err(|e| failed(&e))?;
+    let mut buffer = vec![0u8; COPY_BUFFER_SIZE];
+    let mut bytes_in = 0u64;
+    loop {
+        if let Err(cancelled) = cancel.check(bytes_in, bytes_out.load(Ordering::Relaxed)) {
+            drop(writer);
+            if let Some(path) = output_path {
+                let _ = std::fs::remove_file(path);
+            }
+            return Err(QueueError::Cancelled(cancelled));
+        }
+        let read = match input.read(&mut buffer) {
+            Ok(0) => break,
+            Ok(read) => read,
+            Err(e) if e.kind() == std::io::ErrorKind::Interrupted => continue,
+            Err(e) => return Err(failed(&e)),
+        };
+        writer.write_all(&buffer[..read]).map_err(|e| failed(&e))?;
+        bytes_in += read as u64;
+    }
+    // the trailer is written here, its errors are the job's
+    let bytes_out = writer.finish().map_err(|e| failed(&e))?;
+    return Ok(OperationSummary {
+        bytes_in,
+        bytes_out,
+        elapsed: started.elapsed(),
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::mpsc;
+    use std::time::Duration;
+
+    struct Instrumented {
+        remaining: usize,
+        started: bool,
+        active: Arc<AtomicUsize>,
+        max_active: Arc<AtomicUsize>,
+    }
+
+    impl Read for Instrumented {
+        fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+            if !self.started {
+                self.started = true;
+                let now = self.active.fetch_add(1, Ordering::SeqCst) + 1;
+                self.max_active.fetch_max(now, Ordering::SeqCst);
+            }
+            if self.remaining == 0 {
+                self.active.fetch_sub(1, Ordering::SeqCst);
+                return Ok(0);
+            }
+            std::thread::sleep(Duration::from_millis(1));
+            let n = buf.len().min(self.remaining).min(1024);
+            buf[..n].fill(b'a');
+            self.remaining -= n;
+            return Ok(n);
+        }
+    }
+
+    struct Gate {
+        open: Option<mpsc::Receiver<()>>,
+        order: Arc<Mutex<Vec<i32>>>,
+        id: i32,
+    }
+
+    impl Read for Gate {
+        fn read(&mut self, _: &mut [u8]) -> std::io::Result<usize> {
+            if self.id != i32::MIN {
+                self.order.lock().unwrap().push(self.id);
+                self.id = i32::MIN;
+            }
+            if let Some(open) = self.open.take() {
+                let _ = open.recv();
+            }
+            return Ok(0);
+        }
+    }
+
+    fn job(input: JobInput, priority: i32) -> CompressJob {
+        CompressJob {
+            input,
+            output: JobOutput::Writer(Box::new(std::io::sink())),
+            compression_type: CompressionType::Gzip,
+            params: "level=1".into(),
+            priority,
+        }
+    }
+
+    #[test]
+    pub fn test_concurrency_limit() {
+        let queue = CompressionQueue::new(4);
+        let active = Arc::new(AtomicUsize::new(0));
+        let max_active = Arc::new(AtomicUsize::new(0));
+        let handles: Vec<JobHandle> = (0..50).map(|i| {
+            let reader = Instrumented {
+                remaining: 8 * 1024,
+                started: false,
+                active: active.clone(),
+                max_active: max_active.clone(),
+            };
+            queue.submit(job(JobInput::Reader(Box::new(reader)), i % 3))
+        }).collect();
+        for handle in handles {
+            let summary = handle.wait().unwrap();
+            assert_eq!(summary.bytes_in, 8 * 1024);
+            assert!(summary.bytes_out > 0);
+        }
+        assert!(max_active.load(Ordering::SeqCst) <= 4);
+        assert!(max_active.load(Ordering::SeqCst) > 1);
+    }
+
+    #[test]
+    pub fn test_priority_and_cancel() {
+        let queue = CompressionQueue::new(1);
+        let order = Arc::new(Mutex::new(Vec::new()));
+        let (release, gate) = mpsc::channel();
+        let blocker = queue.submit(job(JobInput::Reader(Box::new(Gate { open: Some(gate), order: order.clone(), id: 0 })), 0));
+        while order.lock().unwrap().is_empty() {
+            std::thread::sleep(Duration::from_millis(1));
+        }
+        let handles: Vec<JobHandle> = [1, 5, 3, 5].iter().enumerate().map(|(i, priority)| {
+            let gate = Gate { open: None, order: order.clone(), id: 10 * (i as i32 + 1) + priority };
+            queue.submit(job(JobInput::Reader(Box::new(gate)), *priority))
+        }).collect();
+        let cancelled = queue.submit(job(JobInput::Bytes(b"never".to_vec()), 100));
+        assert!(cancelled.cancel());
+        assert_eq!(queue.pending(), 5);
+        release.send(()).unwrap();
+
+        assert!(blocker.wait().is_ok());
+        for handle in handles {
+            assert!(handle.wait().is_ok());
+        }
+        assert_eq!(cancelled.wait(), Err(QueueError::Cancelled(Cancelled { bytes_in: 0, bytes_out: 0 })));
+        assert!(!cancelled.cancel());
+        assert_eq!(*order.lock().unwrap(), vec![0, 25, 45, 33, 11]);
+    }
+
+    #[test]
+    pub fn test_aging() {
+        let queue = CompressionQueue::new(1);
+        let order = Arc::new(Mutex::new(Vec::new()));
+        let (release, gate) = mpsc::channel();
+        let blocker = queue.submit(job(JobInput::Reader(Box::new(Gate { open: Some(gate), order: order.clone(), id: 0 })), 0));
+        while order.lock().unwrap().is_empty() {
+            std::thread::sleep(Duration::from_millis(1));
+        }
+        // a low priority job, then a steady flow of higher priority ones
+        let gate = |id| JobInput::Reader(Box::new(Gate { open: None, order: order.clone(), id }));
+        let mut handles = vec![queue.submit(job(gate(-1), 0))];
+        handles.extend((1..=40).map(|id| queue.submit(job(gate(id), 1))));
+        release.send(()).unwrap();
+        assert!(blocker.wait().is_ok());
+        for handle in handles {
+            assert!(handle.wait().is_ok());
+        }
+        // overtaken by the 15 submitted less than AGING_SUBMISSIONS jobs after it, not more
+        let order = order.lock().unwrap();
+        assert_eq!(order.iter().position(|id| *id == -1), Some(16));
+        assert!(order[1..16].iter().zip(1..).all(|(id, expected)| *id == expected));
+    }
+
+    /// Sink refusing everything after its first `room` bytes
+    struct Full {
+        room: usize,
+    }
+
+    impl Write for Full {
+        fn write(&mut self, data: &[u8]) -> std::io::Result<usize> {
+            if self.room == 0 {
+                return Err(std::io::Error::new(std::io::ErrorKind::StorageFull, "no space left"));
+            }
+            let n = data.len().min(self.room);
+            self.room -= n;
+            return Ok(n);
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            return Ok(());
+        }
+    }
+
+    #[test]
+    pub fn test_failed_finish() {
+        let queue = CompressionQueue::new(1);
+        for ct in [CompressionType::Gzip, CompressionType::Zstd] {
+            // the input fits the encoder buffers: the sink only fails on the trailer
+            let mut failing = job(JobInput::Bytes(b"small enough to be buffered until the end".to_vec()), 0);
+            failing.output = JobOutput::Writer(Box::new(Full { room: 10 }));
+            failing.compression_type = ct;
+            match queue.submit(failing).wait() {
+                Err(QueueError::Failed(message)) => assert!(message.contains("no space left"), "{}", message),
+                other => panic!("{}: {:?}", ct, other),
+            }
+        }
+    }
+
+    /// Endless input
+    struct Endless;
+
+    impl Read for Endless {
+        fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+            buf.fill(b'z');
+            return Ok(buf.len());
+        }
+    }
+
+    #[test]
+    pub fn test_cancel_token() {
+        let queue = CompressionQueue::new(2);
+        let parent = CancelToken::new();
+        let running = queue.submit_with_token(job(JobInput::Reader(Box::new(Endless)), 0), parent.child());
+        let expired = queue.submit_with_token(job(JobInput::Bytes(b"late".to_vec()), 0), CancelToken::with_deadline(Instant::now()));
+        let canceller = std::thread::spawn(move || {
+            std::thread::sleep(Duration::from_millis(20));
+            parent.cancel();
+        });
+        match running.wait() {
+            // stopped at a buffer boundary, with what went through so far
+            Err(QueueError::Cancelled(cancelled)) => {
+                assert!(cancelled.bytes_in > 0 && cancelled.bytes_in % COPY_BUFFER_SIZE as u64 == 0, "{:?}", cancelled);
+            },
+            other => panic!("{:?}", other),
+        }
+        assert_eq!(expired.wait(), Err(QueueError::Cancelled(Cancelled { bytes_in: 0, bytes_out: 0 })));
+        canceller.join().unwrap();
+
+        // the partial output file of a cancelled job is removed
+        let path = std::env::temp_dir().join(format!("final_compression.queue.{}.gz", std::process::id()));
+        let mut endless = job(JobInput::Reader(Box::new(Endless)), 0);
+        endless.output = JobOutput::Path(path.clone());
+        let handle = queue.submit(endless);
+        while std::fs::metadata(&path).map_or(true, |metadata| metadata.len() == 0) {
+            std::thread::sleep(Duration::from_millis(1));
+        }
+        assert!(handle.cancel());
+        assert!(matches!(handle.wait(), Err(QueueError::Cancelled(_))));
+        assert!(!path.exists());
+    }
+
+    #[test]
+    pub fn test_shutdown() {
+        let queue = CompressionQueue::new(2);
+        let handle = queue.submit(job(JobInput::Bytes(vec![7u8; 100_000]), 0));
+        queue.shutdown();
+        match handle.wait() {
+            Ok(summary) => assert_eq!(summary.bytes_in, 100_000),
+            Err(e) => assert_eq!(e, QueueError::ShutDown),
+        }
+    }
+}
@@ -0,0 +1,442 @@
+//! Low level deflate state machines for expert users.
+//!
+//! `DeflateEngine` and `InflateEngine` are thin, semver-stable wrappers around flate2's
+//! `Compress`/`Decompress`. They do not own any buffers: every call consumes some input and
+//! produces some output, and the caller drives the loop. This is the building block for
+//! sync flushes, custom framings or hand-assembled gzip members.
+//!
+//! The crate is built on flate2's pure Rust backend, which only supports the default 32 KiB
+//! window (`window_bits` = 15), the default `Strategy` and no preset dictionaries; requesting
+//! anything else fails with `ErrorKind::Unsupported` instead of silently producing a different
+//! stream.
+use std::io::{Error, ErrorKind, Write};
+use flate2::{Compress, Decompress, FlushCompress, FlushDecompress};
+
+/// Window size supported by the backend, as log2 of the window size in bytes
+pub const DEFAULT_WINDOW_BITS: u8 = 15;
+/// Compressed bytes a `DeflateWriter` buffers before writing them to its sink
+const WRITER_BUFFER_SIZE: usize = 32 * 1024;
+
+/// Container around the deflate data
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Framing {
+    /// Bare deflate data (RFC 1951), as used inside gzip members
+    Raw,
+    /// zlib header and adler32 trailer (RFC 1950)
+    Zlib,
+}
+
+/// How a `DeflateEngine` looks for matches, the zlib strategies. Only `Default` is supported
+/// by the backend: flate2 does not pass the others on.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Strategy {
+    /// Match finding of the compression level
+    #[default]
+    Default,
+    /// Fewer short matches, for data of small values with some randomness, like filtered images
+    Filtered,
+    /// Huffman coding only, no matches
+    HuffmanOnly,
+    /// Matches at distance 1 only: run-length encoding
+    Rle,
+    /// Fixed Huffman codes only, no dynamic trees
+    Fixed,
+}
+
+/// How much of the pending data an engine call should emit
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FlushMode {
+    /// Emit output whenever it is convenient
+    None,
+    /// Emit all pending output and align to a byte boundary (empty stored block)
+    Sync,
+    /// Like `Sync`, and additionally reset the compression state so decoding can restart here
+    Full,
+    /// Emit everything and end the stream
+    Finish,
+}
+
+/// Outcome of a single engine call
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Status {
+    /// Progress was made; call again with more input or output space
+    Ok,
+    /// No progress was possible, usually because the output buffer is full
+    BufError,
+    /// The end of the stream was produced (compress) or reached (decompress)
+    StreamEnd,
+}
+
+impl From<flate2::Status> for Status {
+    fn from(status: flate2::Status) -> Self {
+        match status {
+            flate2::Status::Ok => Status::Ok,
+            flate2::Status::BufError => Status::BufError,
+            flate2::Status::StreamEnd => Status::StreamEnd,
+        }
+    }
+}
+
+fn check_window_bits(window_bits: u8) -> Result<(), Error> {
+    if window_bits != DEFAULT_WINDOW_BITS {
+        return Err(Error::new(ErrorKind::Unsupported,
+            format!("window_bits={} is not supported by the deflate backend (only {})", window_bits, DEFAULT_WINDOW_BITS)));
+    }
+    return Ok(());
+}
+
+fn compress_flush(flush: FlushMode) -> FlushCompress {
+    return match flush {
+        FlushMode::None => FlushCompress::None,
+        FlushMode::Sync => FlushCompress::Sync,
+        FlushMode::Full => FlushCompress::Full,
+        FlushMode::Finish => FlushCompress::Finish,
+    };
+}
+
+fn decompress_flush(flush: FlushMode) -> FlushDecompress {
+    return match flush {
+        FlushMode::None => FlushDecompress::None,
+        FlushMode::Sync | FlushMode::Full => FlushDecompress::Sync,
+        FlushMode::Finish => FlushDecompress::Finish,
+    };
+}
+
+fn check_strategy(strategy: Strategy) -> Result<(), Error> {
+    if strategy != Strategy::Default {
+        return Err(Error::new(ErrorKind::Unsupported, format!("strategy {:?} is not supported by the deflate backend (only Default)", strategy)));
+    }
+    return Ok(());
+}
+
+fn unsupported_dictionary() -> Error {
+    return Error::new(ErrorKind::Unsupported, "preset dictionaries are not supported by the deflate backend");
+}
+
+/// Deflate compressor state machine
+pub struct DeflateEngine {
+    inner: Compress,
+}
+
+impl DeflateEngine {
+    /// Create a compressor with `level` (0-9), `framing`, `window_bits` and `strategy`.
+    /// `dictionary` is a preset dictionary, only meaningful for `Framing::Zlib` and raw deflate.
+    pub fn new(level: u32, framing: Framing, window_bits: u8, strategy: Strategy, dictionary: Option<&[u8]>) -> Result<DeflateEngine, Error> {
+        check_window_bits(window_bits)?;
+        check_strategy(strategy)?;
+        if dictionary.is_some() {
+            return Err(unsupported_dictionary());
+        }
+        let inner = Compress::new(flate2::Compression::new(level.min(9)), framing == Framing::Zlib);
+        return Ok(DeflateEngine { inner });
+    }
+
+    /// Consume input from `input` and write compressed data into `output`.
+    ///
+    /// Use `total_in()`/`total_out()` before and after the call to find out how many bytes were
+    /// consumed and produced.
+    pub fn compress(&mut self, input: &[u8], output: &mut [u8], flush: FlushMode) -> Result<Status, Error> {
+        return self.inner.compress(input, output, compress_flush(flush))
+            .map(Status::from)
+            .map_err(|e| Error::new(ErrorKind::InvalidInput, e));
+    }
+
+    /// Like `compress`, but appends to the spare capacity of `output`, which is not grown.
+    pub fn compress_vec(&mut self, input: &[u8], output: &mut Vec<u8>, flush: FlushMode) -> Result<Status, Error> {
+        return self.inner.compress_vec(input, output, compress_flush(flush))
+            .map(Status::from)
+            .map_err(|e| Error::new(ErrorKind::InvalidInput, e));
+    }
+
+    /// Reset the state so the engine can compress a new, independent stream.
+    pub fn reset(&mut self) {
+        self.inner.reset();
+    }
+
+    /// Total uncompressed bytes consumed since creation or the last reset
+    pub fn total_in(&self) -> u64 {
+        return self.inner.total_in();
+    }
+
+    /// Total compressed bytes produced since creation or the last reset
+    pub fn total_out(&self) -> u64 {
+        return self.inner.total_out();
+    }
+}
+
+/// Deflate decompressor state machine
+pub struct InflateEngine {
+    inner: Decompress,
+    framing: Framing,
+}
+
+impl InflateEngine {
+    /// Create a decompressor for `framing` and `window_bits`.
+    pub fn new(framing: Framing, window_bits: u8, dictionary: Option<&[u8]>) -> Result<InflateEngine, Error> {
+        check_window_bits(window_bits)?;
+        if dictionary.is_some() {
+            return Err(unsupported_dictionary());
+        }
+        let inner = Decompress::new(framing == Framing::Zlib);
+        return Ok(InflateEngine { inner, framing });
+    }
+
+    /// Consume compressed data from `input` and write decompressed data into `output`.
+    ///
+    /// Corrupt input is reported as `ErrorKind::InvalidData`.
+    pub fn decompress(&mut self, input: &[u8], output: &mut [u8], flush: FlushMode) -> Result<Status, Error> {
+        return self.inner.decompress(input, output, decompress_flush(flush))
+            .map(Status::from)
+            .map_err(|e| Error::new(ErrorKind::InvalidData, e));
+    }
+
+    /// Like `decompress`, but appends to the spare capacity of `output`, which is not grown.
+    pub fn decompress_vec(&mut self, input: &[u8], output: &mut Vec<u8>, flush: FlushMode) -> Result<Status, Error> {
+        return self.inner.decompress_vec(input, output, decompress_flush(flush))
+            .map(Status::from)
+            .map_err(|e| Error::new(ErrorKind::InvalidData, e));
+    }
+
+    /// Reset the state so the engine can decompress a new, independent stream.
+    pub fn reset(&mut self) {
+        self.inner.reset(self.framing == Framing::Zlib);
+    }
+
+    /// Total compressed bytes consumed since creation or the last reset
+    pub fn total_in(&self) -> u64 {
+        return self.inner.total_in();
+    }
+
+    /// Total decompressed bytes produced since creation or the last reset
+    pub fn total_out(&self) -> u64 {
+        return self.inner.total_out();
+    }
+}
+
+/// Compressing writer driving a `DeflateEngine`, the gzip, zlib and deflate encoder of
+/// `compressed_writer`. It writes the same bytes as flate2's writers, and finishes the stream
+/// when dropped.
+pub(crate) struct DeflateWriter<W: Write> {
+    engine: DeflateEngine,
+    /// Taken by `finish`
+    inner: Option<W>,
+    /// Output not written to `inner` yet, its spare capacity is where the engine writes
+    pending: Vec<u8>,
+    /// CRC of the input of a gzip member, for its trailer
+    crc: Option<flate2::Crc>,
+}
+
+impl<W: Write> DeflateWriter<W> {
+    /// Zlib or bare deflate stream at `level`
+    pub(crate) fn new(inner: W, level: u32, framing: Framing) -> Result<DeflateWriter<W>, Error> {
+        let engine = DeflateEngine::new(level, framing, DEFAULT_WINDOW_BITS, Strategy::Default, None)?;
+        return Ok(DeflateWriter { engine, inner: Some(inner), pending: Vec::with_capacity(WRITER_BUFFER_SIZE), crc: None });
+    }
+
+    /// Gzip member at `level`, starting with the encoded `header`
+    pub(crate) fn gzip(inner: W, level: u32, header: &[u8]) -> Result<DeflateWriter<W>, Error> {
+        let mut writer = DeflateWriter::new(inner, level, Framing::Raw)?;
+        writer.pending.extend_from_slice(header);
+        writer.crc = Some(flate2::Crc::new());
+        return Ok(writer);
+    }
+
+    fn inner(&mut self) -> Result<&mut W, Error> {
+        return self.inner.as_mut().ok_or_else(|| Error::other("deflate stream already finished"));
+    }
+
+    /// The sink, holding what was compressed so far
+    pub(crate) fn get_ref(&self) -> &W {
+        return self.inner.as_ref().expect("the sink is only taken by finish");
+    }
+
+    /// Write out the pending output
+    fn dump(&mut self) -> Result<(), Error> {
+        while !self.pending.is_empty() {
+            let inner = self.inner.as_mut().ok_or_else(|| Error::other("deflate stream already finished"))?;
+            let written = inner.write(&self.pending)?;
+            if written == 0 {
+                return Err(ErrorKind::WriteZero.into());
+            }
+            self.pending.drain(..written);
+        }
+        return Ok(());
+    }
+
+    /// Run the engine without input until it has no more output
+    fn drain(&mut self, flush: FlushMode) -> Result<(), Error> {
+        loop {
+            self.dump()?;
+            let before = self.engine.total_out();
+            self.engine.compress_vec(&[], &mut self.pending, flush)?;
+            if self.engine.total_out() == before {
+                return Ok(());
+            }
+        }
+    }
+
+    fn try_finish(&mut self) -> Result<(), Error> {
+        self.drain(FlushMode::Finish)?;
+        if let Some(crc) = self.crc.take() {
+            self.pending.extend_from_slice(&crc.sum().to_le_bytes());
+            self.pending.extend_from_slice(&crc.amount().to_le_bytes());
+        }
+        return self.dump();
+    }
+
+    /// End the stream and return the sink, not flushed
+    pub(crate) fn finish(mut self) -> Result<W, Error> {
+        self.try_finish()?;
+        return self.inner.take().ok_or_else(|| Error::other("deflate stream already finished"));
+    }
+}
+
+impl<W: Write> Write for DeflateWriter<W> {
+    fn write(&mut self, data: &[u8]) -> Result<usize, Error> {
+        loop {
+            self.dump()?;
+            let before = self.engine.total_in();
+            let status = self.engine.compress_vec(data, &mut self.pending, FlushMode::None)?;
+            let consumed = (self.engine.total_in() - before) as usize;
+            // the engine may only make room for more output, without taking any input
+            if consumed == 0 && !data.is_empty() && status != Status::StreamEnd {
+                continue;
+            }
+            if let Some(crc) = self.crc.as_mut() {
+                crc.update(&data[..consumed]);
+            }
+            return Ok(consumed);
+        }
+    }
+
+    fn flush(&mut self) -> Result<(), Error> {
+        self.dump()?;
+        self.engine.compress_vec(&[], &mut self.pending, FlushMode::Sync)?;
+        self.drain(FlushMode::None)?;
+        return self.inner()?.flush();
+    }
+}
+
+impl<W: Write> Drop for DeflateWriter<W> {
+    fn drop(&mut self) {
+        if self.inner.is_none() || std::thread::panicking() {
+            return;
+        }
+        let _ = self.try_finish();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Read;
+    use crate::{decompressed_reader, CompressionType};
+
+    fn deflate_all(engine: &mut DeflateEngine, data: &[u8]) -> Vec<u8> {
+        let mut output = Vec::with_capacity(64);
+        loop {
+            let consumed = engine.total_in() as usize;
+            if output.len() == output.capacity() {
+                output.reserve(output.capacity());
+            }
+            let status = engine.compress_vec(&data[consumed..], &mut output, FlushMode::Finish).unwrap();
+            if status == Status::StreamEnd {
+                return output;
+            }
+        }
+    }
+
+    fn inflate_all(engine: &mut InflateEngine, data: &[u8]) -> Vec<u8> {
+        let mut output = Vec::with_capacity(64);
+        loop {
+            let consumed = engine.total_in() as usize;
+            if output.len() == output.capacity() {
+                output.reserve(output.capacity());
+            }
+            let status = engine.decompress_vec(&data[consumed..], &mut output, FlushMode::None).unwrap();
+            if status == Status::StreamEnd {
+                return output;
+            }
+        }
+    }
+
+    #[test]
+    pub fn test_manual_gzip_member() {
+        let data = "hello, raw deflate engine! ".repeat(1000);
+        let mut engine = DeflateEngine::new(6, Framing::Raw, DEFAULT_WINDOW_BITS, Strategy::Default, None).unwrap();
+        let body = deflate_all(&mut engine, data.as_bytes());
+        let mut crc = flate2::Crc::new();
+        crc.update(data.as_bytes());
+
+        let mut member = vec![0x1f, 0x8b, 8, 0, 0, 0, 0, 0, 0, 0xff];
+        member.extend_from_slice(&body);
+        member.extend_from_slice(&crc.sum().to_le_bytes());
+        member.extend_from_slice(&(data.len() as u32).to_le_bytes());
+
+        let mut reader = decompressed_reader(Box::new(std::io::Cursor::new(member)), CompressionType::Gzip).unwrap();
+        let mut decoded = String::new();
+        reader.read_to_string(&mut decoded).unwrap();
+        assert_eq!(decoded, data);
+    }
+
+    #[test]
+    pub fn test_reset_and_reuse() {
+        let mut deflate = DeflateEngine::new(3, Framing::Zlib, DEFAULT_WINDOW_BITS, Strategy::Default, None).unwrap();
+        let mut inflate = InflateEngine::new(Framing::Zlib, DEFAULT_WINDOW_BITS, None).unwrap();
+        for data in [&b"first stream ".repeat(100)[..], &b"second, different stream".repeat(50)[..]] {
+            let compressed = deflate_all(&mut deflate, data);
+            assert_eq!(deflate.total_in(), data.len() as u64);
+            assert_eq!(deflate.total_out(), compressed.len() as u64);
+            assert_eq!(inflate_all(&mut inflate, &compressed), data);
+            deflate.reset();
+            inflate.reset();
+        }
+    }
+
+    #[test]
+    pub fn test_writer_matches_flate2() {
+        use std::io::Write;
+        use crate::buffer::SharedBuffer;
+        use crate::member::GzipHeader;
+        let data: Vec<u8> = (0..300_000u32).map(|i| if i % 7 == 0 { (i >> 5) as u8 } else { b"deflate writer"[i as usize % 14] }).collect();
+        let header = GzipHeader { filename: "a.txt".into(), comment: "note".into(), mtime: 1_700_000_000 };
+        let params = crate::ParamSet::from("tag.k=0102");
+        for level in [0u32, 1, 6, 9] {
+            let outputs: Vec<SharedBuffer> = (0..6).map(|_| SharedBuffer::default()).collect();
+            let compression = flate2::Compression::new(level);
+            let extra = crate::tags::gzip_extra(&params).unwrap().unwrap();
+            let mut writers: Vec<Box<dyn Write>> = vec![
+                Box::new(DeflateWriter::new(outputs[0].clone(), level, Framing::Raw).unwrap()),
+                Box::new(flate2::write::DeflateEncoder::new(outputs[1].clone(), compression)),
+                Box::new(DeflateWriter::new(outputs[2].clone(), level, Framing::Zlib).unwrap()),
+                Box::new(flate2::write::ZlibEncoder::new(outputs[3].clone(), compression)),
+                Box::new(DeflateWriter::gzip(outputs[4].clone(), level, &header.encode(&params, level).unwrap()).unwrap()),
+                Box::new(flate2::GzBuilder::new().extra(extra).filename("a.txt").comment("note").mtime(1_700_000_000)
+                    .write(outputs[5].clone(), compression)),
+            ];
+            for writer in writers.iter_mut() {
+                writer.write_all(&data[..1000]).unwrap();
+                writer.flush().unwrap();
+                writer.write_all(&data[1000..]).unwrap();
+            }
+            drop(writers);
+            let outputs: Vec<Vec<u8>> = outputs.into_iter().map(|o| o.take()).collect();
+            for (pair, ct) in outputs.chunks(2).zip([CompressionType::Deflate, CompressionType::Zlib, CompressionType::Gzip]) {
+                assert!(pair[0] == pair[1], "{} {}", ct, level);
+                let mut plain = Vec::new();
+                decompressed_reader(Box::new(std::io::Cursor::new(pair[0].clone())), ct).unwrap().read_to_end(&mut plain).unwrap();
+                assert!(plain == data, "{} {}", ct, level);
+            }
+        }
+    }
+
+    #[test]
+    pub fn test_unsupported_settings() {
+        assert_eq!(DeflateEngine::new(6, Framing::Raw, 9, Strategy::Default, None).err().unwrap().kind(), ErrorKind::Unsupported);
+        for strategy in [Strategy::Filtered, Strategy::HuffmanOnly, Strategy::Rle, Strategy::Fixed] {
+            let err = DeflateEngine::new(6, Framing::Raw, DEFAULT_WINDOW_BITS, strategy, None).err().unwrap();
+            assert_eq!(err.kind(), ErrorKind::Unsupported, "{:?}", strategy);
+        }
+        assert_eq!(InflateEngine::new(Framing::Zlib, 15, Some(b"dict")).err().unwrap().kind(), ErrorKind::Unsupported);
+    }
+}
@@ -0,0 +1,306 @@
+use std::io::{Write, Read};
+use std::error::Error;
+use std::fmt;
+use std::sync::RwLock;
+use crate::{CompressionType, ParamSet};
+
+/// Factory building a compressing writer around the raw sink for a custom codec.
+pub type WriterFactory = fn(Box<dyn Write>, &ParamSet) -> Result<Box<dyn Write>, Box<dyn Error>>;
+
+/// Factory building a decompressing reader around the compressed source for a custom codec.
+pub type ReaderFactory = fn(Box<dyn Read>, &ParamSet) -> Result<Box<dyn Read>, Box<dyn Error>>;
+
+/// Describes a codec that is not built into this crate.
+///
+/// Once registered via `register_codec`, the codec is addressed by `CompressionType::Custom(id)`
+/// and participates in `compressed_writer`, `decompressed_reader` and name parsing just like the
+/// built-in algorithms.
+#[derive(Clone)]
+pub struct CustomCodec {
+    /// Canonical name of the codec, e.g. "fpga-lz"
+    pub name: String,
+    /// Alternative names accepted when parsing a compression type
+    pub aliases: Vec<String>,
+    /// Leading bytes that identify a stream produced by this codec, if it has any
+    pub magic: Option<Vec<u8>>,
+    /// Builds the compressing writer
+    pub make_writer: WriterFactory,
+    /// Builds the decompressing reader
+    pub make_reader: ReaderFactory,
+}
+
+impl fmt::Debug for CustomCodec {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("CustomCodec")
+            .field("name", &self.name)
+            .field("aliases", &self.aliases)
+            .field("magic", &self.magic)
+            .finish()
+    }
+}
+
+/// Reasons a custom codec could not be registered.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum RegistryError {
+    /// The name (or one of the aliases) is empty
+    EmptyName,
+    /// The name (or one of the aliases) clashes with a built-in or already registered codec
+    DuplicateName(String),
+    /// All custom codec ids are in use
+    RegistryFull,
+}
+
+impl fmt::Display for RegistryError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            RegistryError::EmptyName => write!(f, "custom codec name must not be empty"),
+            RegistryError::DuplicateName(name) => write!(f, "compression type name `{}` is already in use", name),
+            RegistryError::RegistryFull => write!(f, "no more custom codec ids available"),
+        }
+    }
+}
+
+impl Error for RegistryError {}
+
+/// Number of registered codecs a process can have, so that their wire ids stay below 0xffff
+/// (see `CompressionType::wire_id`)
+pub const MAX_CUSTOM_CODECS: usize = 0x7fff;
+
+static REGISTRY: RwLock<Vec<CustomCodec>> = RwLock::new(Vec::new());
+
+/// Register a custom codec and return the `CompressionType` that refers to it.
+///
+/// Names and aliases are matched case-insensitively and must not clash with any built-in
+/// compression type name, previously registered codec, or each other. Registered codecs live
+/// for the rest of the process.
+pub fn register_codec(codec: CustomCodec) -> Result<CompressionType, RegistryError> {
+    let mut registry = REGISTRY.write().unwrap_or_else(|e| e.into_inner());
+    let names: Vec<&String> = std::iter::once(&codec.name).chain(codec.aliases.iter()).collect();
+    for (i, name) in names.iter().enumerate() {
+        if name.trim().is_empty() {
+            return Err(RegistryError::EmptyName);
+        }
+        let repeated = names[..i].iter().any(|earlier| earlier.trim().eq_ignore_ascii_case(name.trim()));
+        if repeated || crate::builtin_compression_type(name).is_some() || find(&registry, name).is_some() {
+            return Err(RegistryError::DuplicateName(name.to_string()));
+        }
+    }
+    if registry.len() >= MAX_CUSTOM_CODECS {
+        return Err(RegistryError::RegistryFull);
+    }
+    let id = registry.len() as u16;
+    registry.push(codec);
+    return Ok(CompressionType::Custom(id));
+}
+
+/// Find a registered codec by its name or one of its aliases (case-insensitive).
+pub fn lookup_codec(name: &str) -> Option<CompressionType> {
+    let registry = REGISTRY.read().unwrap_or_else(|e| e.into_inner());
+    return find(&registry, name).map(CompressionType::Custom);
+}
+
+/// Get a copy of the codec description registered under `id`.
+pub fn custom_codec(id: u16) -> Option<CustomCodec> {
+    let registry = REGISTRY.read().unwrap_or_else(|e| e.into_inner());
+    return registry.get(id as usize).cloned();
+}
+
+/// List all registered codecs together with the `CompressionType` referring to them.
+pub fn registered_codecs() -> Vec<(CompressionType, CustomCodec)> {
+    let registry = REGISTRY.read().unwrap_or_else(|e| e.into_inner());
+    return registry.iter()
+        .enumerate()
+        .map(|(id, codec)| (CompressionType::Custom(id as u16), codec.clone()))
+        .collect();
+}
+
+fn find(registry: &[CustomCodec], name: &str) -> Option<u16> {
+    let name = name.trim();
+    return registry.iter()
+        .position(|codec| {
+            codec.name.eq_ignore_ascii_case(name) || codec.aliases.iter().any(|a| a.eq_ignore_ascii_case(name))
+        })
+        .map(|id| id as u16);
+}
+
+/// Length of the longest magic number of the registered codecs, 0 if none has one
+pub(crate) fn longest_magic() -> usize {
+    let registry = REGISTRY.read().unwrap_or_else(|e| e.into_inner());
+    return registry.iter().filter_map(|codec| codec.magic.as_ref().map(Vec::len)).max().unwrap_or(0);
+}
+
+pub(crate) fn unknown_codec(id: u16) -> Box<dyn Error> {
+    return format!("Custom compression type {} is not registered", id).into();
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{compressed_writer, decompressed_reader};
+
+    const XOR_MAGIC: &[u8] = b"XOR\x01";
+    /// Longer than the bytes peeked for the built-in codecs
+    const LONG_MAGIC: &[u8] = b"XOR with a long magic\x01";
+
+    struct XorWriter {
+        inner: Box<dyn Write>,
+    }
+
+    impl Write for XorWriter {
+        fn write(&mut self, data: &[u8]) -> std::io::Result<usize> {
+            let encoded: Vec<u8> = data.iter().map(|b| b ^ 0x5a).collect();
+            self.inner.write_all(&encoded)?;
+            return Ok(data.len());
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            return self.inner.flush();
+        }
+    }
+
+    struct XorReader {
+        inner: Box<dyn Read>,
+    }
+
+    impl Read for XorReader {
+        fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+            let n = self.inner.read(buf)?;
+            buf[..n].iter_mut().for_each(|b| *b ^= 0x5a);
+            return Ok(n);
+        }
+    }
+
+    fn xor_writer_with(mut out: Box<dyn Write>, magic: &[u8]) -> Result<Box<dyn Write>, Box<dyn Error>> {
+        out.write_all(magic)?;
+        return Ok(Box::new(XorWriter { inner: out }));
+    }
+
+    fn xor_reader_with(mut src: Box<dyn Read>, magic: &[u8]) -> Result<Box<dyn Read>, Box<dyn Error>> {
+        let mut found = vec![0u8; magic.len()];
+        src.read_exact(&mut found)?;
+        if found != magic {
+            return Err("not a xor stream".into());
+        }
+        return Ok(Box::new(XorReader { inner: src }));
+    }
+
+    fn xor_writer(out: Box<dyn Write>, _: &ParamSet) -> Result<Box<dyn Write>, Box<dyn Error>> {
+        return xor_writer_with(out, XOR_MAGIC);
+    }
+
+    fn xor_reader(src: Box<dyn Read>, _: &ParamSet) -> Result<Box<dyn Read>, Box<dyn Error>> {
+        return xor_reader_with(src, XOR_MAGIC);
+    }
+
+    fn xor_codec(name: &str, alias: &str) -> CustomCodec {
+        CustomCodec {
+            name: name.into(),
+            aliases: vec![alias.into()],
+            magic: Some(XOR_MAGIC.to_vec()),
+            make_writer: xor_writer,
+            make_reader: xor_reader,
+        }
+    }
+
+    #[test]
+    pub fn test_register_and_round_trip() {
+        let ct = register_codec(xor_codec("xor-roundtrip", "xrt")).unwrap();
+        assert_eq!(CompressionType::try_from("XRT").unwrap(), ct);
+        assert_eq!(lookup_codec("xor-roundtrip"), Some(ct));
+        assert_eq!(ct.to_string(), "xor-roundtrip");
+        assert_eq!(CompressionType::try_from(ct.to_string()).unwrap(), ct);
+
+        let file_name = std::env::temp_dir().join(format!("final_compression.registry.{}.xor", std::process::id()));
+        let out = std::fs::File::create(&file_name).unwrap();
+        let mut w = compressed_writer(Box::new(out), ct, "").unwrap();
+        w.write_all(b"hello custom codec").unwrap();
+        drop(w);
+
+        let raw = std::fs::read(&file_name).unwrap();
+        assert!(raw.starts_with(XOR_MAGIC));
+
+        let input = std::fs::File::open(&file_name).unwrap();
+        let mut r = decompressed_reader(Box::new(input), ct).unwrap();
+        let mut data = String::new();
+        r.read_to_string(&mut data).unwrap();
+        assert_eq!(data, "hello custom codec");
+        let _ = std::fs::remove_file(&file_name);
+    }
+
+    #[test]
+    pub fn test_detected_by_magic() {
+        let ct = register_codec(CustomCodec {
+            magic: Some(LONG_MAGIC.to_vec()),
+            make_writer: |out, _| xor_writer_with(out, LONG_MAGIC),
+            make_reader: |src, _| xor_reader_with(src, LONG_MAGIC),
+            ..xor_codec("xor-long-magic", "xlong")
+        }).unwrap();
+        let data = b"found by its magic number ".repeat(100);
+        let compressed = crate::compress_bytes(&data, ct, "").unwrap();
+        assert!(compressed.starts_with(LONG_MAGIC));
+        // the whole magic is peeked, though longer than those of the built-in codecs
+        let (detected, prefix) = crate::detect_compression(&mut &compressed[..]).unwrap();
+        assert_eq!((detected, prefix.len()), (ct, LONG_MAGIC.len()));
+        assert_eq!(crate::multi::detect_compression_type(&compressed[..LONG_MAGIC.len() - 1]), None);
+        let mut plain = Vec::new();
+        crate::decompressed_reader_auto(Box::new(std::io::Cursor::new(compressed))).unwrap().read_to_end(&mut plain).unwrap();
+        assert!(plain == data);
+    }
+
+    #[test]
+    pub fn test_high_level_helpers() {
+        let ct = register_codec(xor_codec("xor-helpers", "xhelpers")).unwrap();
+        let data = b"through every helper ".repeat(1000);
+        let compressed = crate::compress_bytes(&data, ct, "").unwrap();
+        assert!(crate::decompress_bytes(&compressed, ct).unwrap() == data);
+
+        let mut copied = Vec::new();
+        let buffer = crate::buffer::SharedBuffer::default();
+        let stats = crate::compress_copy(&mut &data[..], Box::new(buffer.clone()), ct, "").unwrap();
+        assert_eq!((stats.bytes_read, stats.bytes_written), (data.len() as u64, compressed.len() as u64));
+        crate::decompress_copy(Box::new(std::io::Cursor::new(buffer.take())), &mut copied, ct).unwrap();
+        assert!(copied == data);
+
+        let dir = std::env::temp_dir().join(format!("final_compression_registry_{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let (src, dst, restored) = (dir.join("data"), dir.join("data.xor"), dir.join("restored"));
+        std::fs::write(&src, &data).unwrap();
+        assert_eq!(crate::compress_file(&src, &dst, ct, "").unwrap(), compressed.len() as u64);
+        let report = crate::decompress_file(&dst, &restored, ct, &crate::Preflight::default()).unwrap();
+        assert_eq!(report.written, data.len() as u64);
+        assert!(std::fs::read(&restored).unwrap() == data);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    pub fn test_duplicate_names_rejected() {
+        register_codec(xor_codec("xor-dup", "xdup")).unwrap();
+        assert_eq!(register_codec(xor_codec("XOR-DUP", "other")).unwrap_err(),
+            RegistryError::DuplicateName("XOR-DUP".into()));
+        assert_eq!(register_codec(xor_codec("xor-dup2", "gzip")).unwrap_err(),
+            RegistryError::DuplicateName("gzip".into()));
+        assert_eq!(register_codec(xor_codec("", "x")).unwrap_err(), RegistryError::EmptyName);
+        // nor with its own name
+        assert_eq!(register_codec(xor_codec("xor-self", "XOR-SELF")).unwrap_err(),
+            RegistryError::DuplicateName("XOR-SELF".into()));
+        assert_eq!(lookup_codec("xor-self"), None);
+    }
+
+    #[test]
+    pub fn test_custom_wire_ids() {
+        let ct = register_codec(xor_codec("xor-wire", "xwire")).unwrap();
+        let CompressionType::Custom(id) = ct else { unreachable!() };
+        assert_eq!(ct.wire_id(), crate::WIRE_ID_CUSTOM_BASE + id);
+        assert!(ct.wire_id() >= 0x8000);
+        assert_eq!(CompressionType::from_wire_id(ct.wire_id()), Some(ct));
+        // in the custom range, but not registered
+        assert_eq!(CompressionType::from_wire_id(0xfffe), None);
+        assert_eq!(CompressionType::Custom(0x7fff).wire_id(), 0xffff);
+    }
+
+    #[test]
+    pub fn test_unregistered_custom_type() {
+        let result = compressed_writer(Box::new(Vec::new()), CompressionType::Custom(u16::MAX), "");
+        assert!(result.is_err());
+    }
+}
@@ -0,0 +1,181 @@
+use std::error::Error;
+use std::io::{Read, Write};
+use std::sync::Arc;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{Duration, Instant};
+use crate::cancel::CancelToken;
+use crate::summary::CountingWriter;
+use crate::{compressed_writer, CompressionType, OperationSummary, ParamSet};
+
+/// Input is handed to the encoder in writes of this size: the lz4 encoder ends a block on every
+/// write, so its output only matches the one-shot one for writes of whole 64 KiB blocks.
+const STAGE_SIZE: usize = 64 * 1024;
+
+/// Outcome of `ResumableCompress::step`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum StepResult {
+    /// Input is left, call `step` again
+    Continue,
+    /// The input was compressed and the output finished
+    Done(OperationSummary),
+}
+
+/// Compression driven in slices by the caller, for single-threaded hosts (wasm, thread-per-core
+/// runtimes) where one long compress call would starve every other task.
+///
+/// Each `step` reads at most its budget of input and returns, so the host loop can yield in
+/// between. Input reaches the encoder 64 KiB at a time, which bounds the work of a step. The output is the same as writing the whole input at once to a
+/// `compressed_writer` with the same codec and parameters.
+pub struct ResumableCompress {
+    src: Box<dyn Read>,
+    encoder: Option<Box<dyn Write>>,
+    bytes_out: Arc<AtomicU64>,
+    bytes_in: u64,
+    /// Time spent inside `step`, the time between steps belongs to the host
+    elapsed: Duration,
+    /// Input read but not written to the encoder yet
+    stage: Vec<u8>,
+    summary: Option<OperationSummary>,
+    cancel: CancelToken,
+}
+
+impl ResumableCompress {
+    /// Compress what `src` reads into `out` with `compression_type`, taking the parameters of
+    /// `compressed_writer`. Nothing is read before the first `step`.
+    pub fn new<T: Into<ParamSet>>(src: Box<dyn Read>, out: Box<dyn Write>, compression_type: CompressionType, option: T) -> Result<ResumableCompress, Box<dyn Error>> {
+        let (out, bytes_out) = CountingWriter::new(out);
+        let encoder = compressed_writer(Box::new(out), compression_type, option)?;
+        return Ok(ResumableCompress {
+            src,
+            encoder: Some(encoder),
+            bytes_out,
+            bytes_in: 0,
+            elapsed: Duration::ZERO,
+            stage: Vec::with_capacity(STAGE_SIZE),
+            summary: None,
+            cancel: CancelToken::new(),
+        });
+    }
+
+    /// Stop at the next `step` once `cancel` is cancelled, checked once per 64 KiB read. The
+    /// step fails with an `io::Error` wrapping `Cancelled`, and the compression cannot go on.
+    pub fn with_token(self, cancel: CancelToken) -> ResumableCompress {
+        return ResumableCompress { cancel, ..self };
+    }
+
+    /// Compress up to `budget_bytes` of input; a budget of 0 still moves by one byte. Once the
+    /// input ends the output is finished and every later call returns the same `Done`.
+    pub fn step(&mut self, budget_bytes: usize) -> std::io::Result<StepResult> {
+        if let Some(summary) = self.summary {
+            return Ok(StepResult::Done(summary));
+        }
+        let started = Instant::now();
+        let result = self.compress(budget_bytes.max(1));
+        self.elapsed += started.elapsed();
+        if let Ok(StepResult::Done(_)) = result {
+            let summary = OperationSummary {
+                bytes_in: self.bytes_in,
+                bytes_out: self.bytes_out.load(Ordering::Relaxed),
+                elapsed: self.elapsed,
+            };
+            self.summary = Some(summary);
+            return Ok(StepResult::Done(summary));
+        }
+        return result;
+    }
+
+    fn compress(&mut self, budget: usize) -> std::io::Result<StepResult> {
+        let result = self.compress_stages(budget);
+        if result.is_err() {
+            self.encoder = None;
+        }
+        return result;
+    }
+
+    fn compress_stages(&mut self, budget: usize) -> std::io::Result<StepResult> {
+        let Some(encoder) = self.encoder.as_mut() else {
+            return Err(std::io::Error::other("compression failed in an earlier step"));
+        };
+        let mut left = budget;
+        while left > 0 {
+            self.cancel.check(self.bytes_in, self.bytes_out.load(Ordering::Relaxed)).map_err(std::io::Error::other)?;
+            let filled = self.stage.len();
+            let want = left.min(STAGE_SIZE - filled);
+            self.stage.resize(filled + want, 0);
+            let read = self.src.read(&mut self.stage[filled..]);
+            self.stage.truncate(filled + *read.as_ref().unwrap_or(&0));
+            let read = match read {
+                Ok(read) => read,
+                Err(e) if e.kind() == std::io::ErrorKind::Interrupted => continue,
+                Err(e) => return Err(e),
+            };
+            if read == 0 {
+                encoder.write_all(&self.stage)?;
+                // dropping finishes the stream, a flush would end a block the one-shot path does not
+                drop(self.encoder.take());
+                return Ok(StepResult::Done(OperationSummary::default()));
+            }
+            self.bytes_in += read as u64;
+            left -= read;
+            if self.stage.len() == STAGE_SIZE {
+                encoder.write_all(&self.stage)?;
+                self.stage.clear();
+            }
+        }
+        return Ok(StepResult::Continue);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::describe::BUILTIN_CODECS;
+
+    fn one_shot(ct: CompressionType, data: &[u8]) -> Vec<u8> {
+        let buffer = crate::buffer::SharedBuffer::default();
+        let mut w = compressed_writer(Box::new(buffer.clone()), ct, "").unwrap();
+        w.write_all(data).unwrap();
+        drop(w);
+        return buffer.take();
+    }
+
+    #[test]
+    pub fn test_tiny_budgets() {
+        let data: Vec<u8> = (0..200_000u32).map(|i| (i % 251) as u8 ^ (i / 900) as u8).collect();
+        for codec in BUILTIN_CODECS {
+            let ct = codec.compression_type;
+            let expected = one_shot(ct, &data);
+            for budget in [0, 7, 4096, 100_000] {
+                let buffer = crate::buffer::SharedBuffer::default();
+                let src = Box::new(std::io::Cursor::new(data.clone()));
+                let mut job = ResumableCompress::new(src, Box::new(buffer.clone()), ct, "").unwrap();
+                let mut steps = 0;
+                let summary = loop {
+                    steps += 1;
+                    if let StepResult::Done(summary) = job.step(budget).unwrap() {
+                        break summary;
+                    }
+                };
+                assert_eq!(steps, data.len() / budget.max(1) + 1, "{} {}", codec.name, budget);
+                let output = buffer.take();
+                assert!(output == expected, "{} with budget {}", codec.name, budget);
+                assert_eq!(summary.bytes_in, data.len() as u64);
+                assert_eq!(summary.bytes_out, output.len() as u64);
+                assert_eq!(job.step(budget).unwrap(), StepResult::Done(summary));
+            }
+        }
+    }
+
+    #[test]
+    pub fn test_cancel() {
+        let cancel = CancelToken::new();
+        let src = Box::new(std::io::Cursor::new(vec![3u8; 300_000]));
+        let mut compress = ResumableCompress::new(src, Box::new(std::io::sink()), CompressionType::Zstd, "").unwrap().with_token(cancel.clone());
+        assert_eq!(compress.step(100_000).unwrap(), StepResult::Continue);
+        cancel.cancel();
+        let err = compress.step(100_000).unwrap_err();
+        let cancelled = err.get_ref().and_then(|e| e.downcast_ref::<crate::Cancelled>()).unwrap();
+        assert_eq!(cancelled.bytes_in, 100_000);
+        assert!(compress.step(100_000).is_err());
+    }
+}
@@ -0,0 +1,178 @@
+use std::io::Write;
+
+/// Default `rsync_interval`: average distance between forced boundaries with `rsyncable=true`
+pub const DEFAULT_RSYNC_INTERVAL: usize = 1024 * 1024;
+/// Smallest accepted `rsync_interval`
+pub const MIN_RSYNC_INTERVAL: usize = 4096;
+
+const fn gear_table() -> [u64; 256] {
+    // splitmix64, so the cut points are the same in every build
+    let mut table = [0u64; 256];
+    let mut state = 0x5ee5_c0de_u64;
+    let mut i = 0;
+    while i < 256 {
+        state = state.wrapping_add(0x9e37_79b9_7f4a_7c15);
+        let mut z = state;
+        z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+        table[i] = z ^ (z >> 31);
+        i += 1;
+    }
+    return table;
+}
+
+static GEAR: [u64; 256] = gear_table();
+
+/// Content-defined cut points: a gear rolling hash over the last 64 bytes, cutting where its top
+/// bits are all zero. An edit only moves the cut points next to it.
+pub(crate) struct CutFinder {
+    hash: u64,
+    shift: u32,
+}
+
+impl CutFinder {
+    /// Cut points on average every `interval` bytes, rounded up to a power of two
+    pub(crate) fn new(interval: usize) -> CutFinder {
+        let bits = interval.max(2).next_power_of_two().trailing_zeros();
+        return CutFinder { hash: 0, shift: 64 - bits };
+    }
+
+    /// Length of the prefix of `data` up to and including the next cut point, and whether a cut
+    /// point was found in `data`
+    pub(crate) fn next_cut(&mut self, data: &[u8]) -> (usize, bool) {
+        for (i, &byte) in data.iter().enumerate() {
+            self.hash = (self.hash << 1).wrapping_add(GEAR[byte as usize]);
+            if self.hash >> self.shift == 0 {
+                self.hash = 0;
+                return (i + 1, true);
+            }
+        }
+        return (data.len(), false);
+    }
+}
+
+/// Encoder adapter for `rsyncable=true`: ends an independently decodable segment at every
+/// content-defined cut point of the uncompressed data, so a small edit changes the output only
+/// around it and the rest dedupes against the previous version.
+pub(crate) struct RsyncableWriter<E: Write> {
+    encoder: Option<E>,
+    cuts: CutFinder,
+    /// Ends the current segment
+    boundary: Box<dyn Fn(E) -> std::io::Result<E>>,
+    /// Completes the stream
+    finish: fn(E) -> std::io::Result<()>,
+}
+
+impl<E: Write> RsyncableWriter<E> {
+    pub(crate) fn new(
+        encoder: E,
+        interval: usize,
+        boundary: Box<dyn Fn(E) -> std::io::Result<E>>,
+        finish: fn(E) -> std::io::Result<()>) -> RsyncableWriter<E> {
+        return RsyncableWriter { encoder: Some(encoder), cuts: CutFinder::new(interval.max(MIN_RSYNC_INTERVAL)), boundary, finish };
+    }
+
+    fn encoder(&mut self) -> std::io::Result<&mut E> {
+        return self.encoder.as_mut().ok_or_else(|| std::io::Error::other("encoder lost by a failed segment boundary"));
+    }
+}
+
+impl<E: Write> Write for RsyncableWriter<E> {
+    fn write(&mut self, data: &[u8]) -> std::io::Result<usize> {
+        let (end, cut) = self.cuts.next_cut(data);
+        self.encoder()?.write_all(&data[..end])?;
+        if cut {
+            let encoder = self.encoder.take().unwrap();
+            self.encoder = Some((self.boundary)(encoder)?);
+        }
+        return Ok(end);
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        return self.encoder()?.flush();
+    }
+}
+
+impl<E: Write> Drop for RsyncableWriter<E> {
+    fn drop(&mut self) {
+        if let Some(encoder) = self.encoder.take() {
+            let _ = (self.finish)(encoder);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashSet;
+    use std::io::Read;
+    use crate::{compress_bytes, decompressed_reader, CompressionType};
+
+    fn document(size: usize) -> Vec<u8> {
+        let words = ["alpha", "beta", "gamma", "delta", "epsilon", "zeta", "eta", "theta", "iota", "kappa", "lambda", "mu"];
+        let mut state = 0x2545f4914f6cdd1du64;
+        let mut data = Vec::with_capacity(size + 16);
+        while data.len() < size {
+            state ^= state << 13;
+            state ^= state >> 7;
+            state ^= state << 17;
+            data.extend_from_slice(words[(state % words.len() as u64) as usize].as_bytes());
+            data.push(if state.is_multiple_of(11) { b'\n' } else { b' ' });
+        }
+        data.truncate(size);
+        return data;
+    }
+
+    /// Share of the bytes of `b` in content-defined chunks also found in `a`, as a dedupe tool sees it
+    fn shared_fraction(a: &[u8], b: &[u8]) -> f64 {
+        let chunks = |data: &[u8]| -> Vec<Vec<u8>> {
+            let mut finder = CutFinder::new(256);
+            let mut rest = data;
+            let mut result = Vec::new();
+            while !rest.is_empty() {
+                let (end, _) = finder.next_cut(rest);
+                result.push(rest[..end].to_vec());
+                rest = &rest[end..];
+            }
+            return result;
+        };
+        let known: HashSet<Vec<u8>> = chunks(a).into_iter().collect();
+        let shared: usize = chunks(b).iter().filter(|c| known.contains(*c)).map(|c| c.len()).sum();
+        return shared as f64 / b.len() as f64;
+    }
+
+    #[test]
+    pub fn test_edit_dedupes() {
+        let original = document(2 * 1024 * 1024);
+        let mut edited = original.clone();
+        // 0.1% of the document, in the middle
+        let middle = edited.len() / 2;
+        for byte in edited[middle..middle + 2048].iter_mut() {
+            *byte = byte.to_ascii_uppercase();
+        }
+        for ct in [CompressionType::Zstd, CompressionType::Gzip] {
+            let plain = shared_fraction(&compress_bytes(&original, ct, "").unwrap(), &compress_bytes(&edited, ct, "").unwrap());
+            let options = "rsyncable=true;rsync_interval=65536";
+            let a = compress_bytes(&original, ct, options).unwrap();
+            let b = compress_bytes(&edited, ct, options).unwrap();
+            let rsyncable = shared_fraction(&a, &b);
+            assert!(plain < 0.6, "{:?} {}", ct, plain);
+            // gzip keeps referencing the 32 KiB before a cut point, so it dedupes a bit less than zstd
+            assert!(rsyncable > 0.8, "{:?} {} vs {}", ct, rsyncable, plain);
+
+            let mut decoded = Vec::new();
+            decompressed_reader(Box::new(std::io::Cursor::new(b)), ct).unwrap().read_to_end(&mut decoded).unwrap();
+            assert_eq!(decoded, edited, "{:?}", ct);
+        }
+    }
+
+    #[test]
+    pub fn test_concatenated_frames() {
+        let data = document(300_000);
+        let compressed = compress_bytes(&data, CompressionType::Zstd, "rsyncable=true;rsync_interval=4096").unwrap();
+        // readable by the plain zstd decoder
+        assert_eq!(zstd::decode_all(&compressed[..]).unwrap(), data);
+        let frames = compressed.windows(4).filter(|w| *w == [0x28, 0xb5, 0x2f, 0xfd]).count();
+        assert!(frames > 10, "{}", frames);
+    }
+}
@@ -0,0 +1,223 @@
+use std::error::Error;
+use std::io::{Read, Write};
+use std::panic::{catch_unwind, AssertUnwindSafe};
+use std::time::{Duration, Instant};
+use crate::{compressed_writer, decompressed_reader_with, CompressionType, ParamSet};
+use crate::backend::{backend_info, Linking};
+use crate::buffer::SharedBuffer;
+use crate::describe::describe;
+
+/// Size of the built-in test pattern
+pub const PATTERN_SIZE: usize = 64 * 1024;
+
+/// Outcome of the self test of one codec
+#[derive(Debug, Clone, PartialEq)]
+pub struct CodecSelfTest {
+    pub compression_type: CompressionType,
+    pub name: String,
+    pub passed: bool,
+    /// First failure, if any
+    pub error: Option<String>,
+    pub compressed_size: usize,
+    /// Rough compress + decompress throughput of the pattern, in MiB/s
+    pub throughput_mib_s: f64,
+    pub elapsed: Duration,
+}
+
+/// Result of `self_test`
+#[derive(Debug, Clone, PartialEq)]
+pub struct SelfTestReport {
+    pub codecs: Vec<CodecSelfTest>,
+    pub thorough: bool,
+    pub elapsed: Duration,
+}
+
+impl SelfTestReport {
+    /// Whether every codec passed, e.g. for a readiness probe
+    pub fn passed(&self) -> bool {
+        return self.codecs.iter().all(|c| c.passed);
+    }
+
+    pub fn failures(&self) -> impl Iterator<Item = &CodecSelfTest> {
+        return self.codecs.iter().filter(|c| !c.passed);
+    }
+}
+
+/// Quick self test of every available codec, see `self_test_with`.
+pub fn self_test() -> Result<SelfTestReport, Box<dyn Error>> {
+    return self_test_with("");
+}
+
+/// Compress and decompress a built-in 64 KiB pattern (text and binary) with every available
+/// codec, including registered custom codecs, and check the round trip. Codecs backed by a C
+/// library must also report their version.
+///
+/// The default quick mode takes a few milliseconds per codec. With `thorough=true` each codec is
+/// also exercised through byte-by-byte writes and reads, spurious `Ok(0)` from the source, and
+/// a corrupted stream that must not decode to the original.
+///
+/// A failing codec is reported as such; only an invalid `option` fails the call itself.
+pub fn self_test_with<T: Into<ParamSet>>(option: T) -> Result<SelfTestReport, Box<dyn Error>> {
+    let param_set: ParamSet = option.into();
+    let thorough = match param_set.get_string("thorough", "false") {
+        "true" => true,
+        "false" => false,
+        other => return Err(format!("Invalid thorough `{}`, expected true or false", other).into()),
+    };
+    let started = Instant::now();
+    let pattern = pattern();
+    let backends = backend_info();
+    let mut codecs = Vec::new();
+    for codec in describe().codecs {
+        let codec_started = Instant::now();
+        let result = catch_unwind(AssertUnwindSafe(|| test_codec(codec.compression_type, &pattern, thorough)))
+            .unwrap_or_else(|_| Err("codec panicked".into()))
+            .and_then(|compressed_size| {
+                let backend = backends.iter().find(|b| b.compression_type == codec.compression_type);
+                if let Some(backend) = backend.filter(|b| b.linking != Linking::PureRust) {
+                    if backend.library_version.as_deref().unwrap_or_default().is_empty() {
+                        return Err(format!("{} reports no library version", backend.backend_crate).into());
+                    }
+                }
+                return Ok(compressed_size);
+            });
+        let elapsed = codec_started.elapsed();
+        codecs.push(CodecSelfTest {
+            compression_type: codec.compression_type,
+            name: codec.name,
+            passed: result.is_ok(),
+            compressed_size: *result.as_ref().unwrap_or(&0),
+            error: result.err().map(|e| e.to_string()),
+            throughput_mib_s: 2.0 * PATTERN_SIZE as f64 / (1024.0 * 1024.0) / elapsed.as_secs_f64().max(1e-9),
+            elapsed,
+        });
+    }
+    return Ok(SelfTestReport { codecs, thorough, elapsed: started.elapsed() });
+}
+
+/// Half text, half pseudo-random binary
+fn pattern() -> Vec<u8> {
+    let mut data = Vec::with_capacity(PATTERN_SIZE);
+    let mut line = 0;
+    while data.len() < PATTERN_SIZE / 2 {
+        data.extend_from_slice(format!("{} the quick brown fox jumps over the lazy dog\n", line).as_bytes());
+        line += 1;
+    }
+    data.truncate(PATTERN_SIZE / 2);
+    let mut state = 0x9e3779b97f4a7c15u64;
+    while data.len() < PATTERN_SIZE {
+        state ^= state << 13;
+        state ^= state >> 7;
+        state ^= state << 17;
+        data.push((state >> 24) as u8);
+    }
+    return data;
+}
+
+fn compress(compression_type: CompressionType, data: &[u8], chunk: usize) -> Result<Vec<u8>, Box<dyn Error>> {
+    let buffer = SharedBuffer::default();
+    let mut writer = compressed_writer(Box::new(buffer.clone()), compression_type, "")?;
+    for piece in data.chunks(chunk) {
+        writer.write_all(piece)?;
+    }
+    writer.flush()?;
+    drop(writer);
+    return Ok(buffer.take());
+}
+
+fn decompress(compression_type: CompressionType, src: Box<dyn Read>, params: &str, chunk: usize) -> Result<Vec<u8>, Box<dyn Error>> {
+    let mut reader = decompressed_reader_with(src, compression_type, params)?;
+    let mut result = Vec::new();
+    let mut buffer = vec![0u8; chunk];
+    loop {
+        let read = reader.read(&mut buffer)?;
+        if read == 0 {
+            return Ok(result);
+        }
+        result.extend_from_slice(&buffer[..read]);
+    }
+}
+
+/// Source returning a spurious `Ok(0)` before every other read
+struct Stuttering {
+    inner: std::io::Cursor<Vec<u8>>,
+    stutter: bool,
+}
+
+impl Read for Stuttering {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        self.stutter = !self.stutter;
+        if self.stutter {
+            return Ok(0);
+        }
+        return self.inner.read(buf);
+    }
+}
+
+fn test_codec(compression_type: CompressionType, pattern: &[u8], thorough: bool) -> Result<usize, Box<dyn Error>> {
+    let compressed = compress(compression_type, pattern, PATTERN_SIZE)?;
+    let check = |decoded: Vec<u8>, what: &str| -> Result<(), Box<dyn Error>> {
+        if decoded != pattern {
+            return Err(format!("round trip mismatch ({})", what).into());
+        }
+        return Ok(());
+    };
+    check(decompress(compression_type, Box::new(std::io::Cursor::new(compressed.clone())), "eof_policy=strict", 8192)?, "plain")?;
+    if !thorough {
+        return Ok(compressed.len());
+    }
+    let small_writes = compress(compression_type, pattern, 1)?;
+    check(decompress(compression_type, Box::new(std::io::Cursor::new(small_writes)), "eof_policy=strict", 1)?, "byte by byte")?;
+    let stuttering = Box::new(Stuttering { inner: std::io::Cursor::new(compressed.clone()), stutter: false });
+    check(decompress(compression_type, stuttering, "eof_policy=retry(1,0)", 8192)?, "spurious end of input")?;
+    if compression_type != CompressionType::None {
+        let mut corrupted = compressed.clone();
+        let middle = corrupted.len() / 2;
+        corrupted[middle] ^= 0x55;
+        let decoded = catch_unwind(AssertUnwindSafe(|| decompress(compression_type, Box::new(std::io::Cursor::new(corrupted)), "", 8192)));
+        if let Ok(Ok(decoded)) = decoded {
+            if decoded == pattern {
+                return Err("corruption went unnoticed".into());
+            }
+        }
+    }
+    return Ok(compressed.len());
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::registry::{register_codec, CustomCodec};
+
+    fn broken_writer(out: Box<dyn Write>, _: &ParamSet) -> Result<Box<dyn Write>, Box<dyn Error>> {
+        return Ok(out);
+    }
+
+    fn broken_reader(_: Box<dyn Read>, _: &ParamSet) -> Result<Box<dyn Read>, Box<dyn Error>> {
+        return Ok(Box::new(std::io::repeat(0).take(10)));
+    }
+
+    #[test]
+    pub fn test_self_test() {
+        register_codec(CustomCodec {
+            name: "selftest-broken".into(),
+            aliases: Vec::new(),
+            magic: None,
+            make_writer: broken_writer,
+            make_reader: broken_reader,
+        }).unwrap();
+        for options in ["", "thorough=true"] {
+            let report = self_test_with(options).unwrap();
+            let builtin: Vec<_> = report.codecs.iter().filter(|c| !matches!(c.compression_type, CompressionType::Custom(_))).collect();
+            assert_eq!(builtin.len(), 12);
+            for codec in builtin {
+                assert!(codec.passed, "{} failed: {:?}", codec.name, codec.error);
+            }
+            let broken = report.codecs.iter().find(|c| c.name == "selftest-broken").unwrap();
+            assert!(!broken.passed);
+            assert!(broken.error.as_ref().unwrap().contains("mismatch"));
+            assert!(!report.passed());
+        }
+        assert!(self_test_with("thorough=yes").is_err());
+    }
+}
@@ -0,0 +1,338 @@
+use std::error::Error;
+use std::fs::File;
+use std::io::{Read, Write};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use sha2::{Digest, Sha256};
+use crate::cancel::CancelToken;
+use crate::commit::{lock_dir, recover_pending, Transaction};
+use crate::files::{compress_to, open_file, PathErrors};
+use crate::progress::Progress;
+use crate::{decompressed_reader, CompressionType, ParamSet};
+
+/// A file shipped next to a compressed artifact, describing it
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SidecarKind {
+    /// SHA-256 of the compressed file, in the `sha256sum` format
+    Sha256,
+    /// SHA-256 of the uncompressed content, in the `sha256sum` format
+    UncompressedSha256,
+    /// Compressed and uncompressed sizes, as `compressed <n>` and `uncompressed <n>` lines
+    Size,
+}
+
+impl SidecarKind {
+    /// Name in the `sidecars` parameter of `compress_file`
+    pub fn name(&self) -> &'static str {
+        return match self {
+            SidecarKind::Sha256 => "sha256",
+            SidecarKind::UncompressedSha256 => "usha256",
+            SidecarKind::Size => "size",
+        };
+    }
+}
+
+/// Names of the sidecars of an artifact, `{file}` standing for the file name of the artifact.
+/// They are created in the directory of the artifact.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SidecarNames {
+    pub sha256: String,
+    pub uncompressed_sha256: String,
+    pub size: String,
+}
+
+impl Default for SidecarNames {
+    fn default() -> Self {
+        return SidecarNames {
+            sha256: "{file}.sha256".into(),
+            uncompressed_sha256: "{file}.usha256".into(),
+            size: "{file}.size".into(),
+        };
+    }
+}
+
+impl SidecarNames {
+    /// The names of the `sidecar_sha256`, `sidecar_usha256` and `sidecar_size` parameters of
+    /// `param_set`, the default ones for those missing
+    pub fn from_params(param_set: &ParamSet) -> SidecarNames {
+        let default = SidecarNames::default();
+        return SidecarNames {
+            sha256: param_set.get_string("sidecar_sha256", &default.sha256).to_string(),
+            uncompressed_sha256: param_set.get_string("sidecar_usha256", &default.uncompressed_sha256).to_string(),
+            size: param_set.get_string("sidecar_size", &default.size).to_string(),
+        };
+    }
+
+    /// Path of the `kind` sidecar of `artifact`
+    pub fn path(&self, artifact: &Path, kind: SidecarKind) -> PathBuf {
+        let pattern = match kind {
+            SidecarKind::Sha256 => &self.sha256,
+            SidecarKind::UncompressedSha256 => &self.uncompressed_sha256,
+            SidecarKind::Size => &self.size,
+        };
+        let file = artifact.file_name().map(|name| name.to_string_lossy()).unwrap_or_default();
+        return artifact.with_file_name(pattern.replace("{file}", &file));
+    }
+}
+
+/// Outcome of the check of one sidecar by `verify_with_sidecars`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SidecarCheck {
+    pub kind: SidecarKind,
+    pub path: PathBuf,
+    /// What did not match, `None` when the check passed
+    pub problem: Option<String>,
+}
+
+impl SidecarCheck {
+    pub fn passed(&self) -> bool {
+        return self.problem.is_none();
+    }
+}
+
+/// Tee hashing and counting the bytes read or written through it
+struct Hashing<T> {
+    inner: T,
+    state: Arc<Mutex<(Sha256, u64)>>,
+}
+
+impl<T> Hashing<T> {
+    fn new(inner: T) -> (Hashing<T>, Arc<Mutex<(Sha256, u64)>>) {
+        let state = Arc::new(Mutex::new((Sha256::new(), 0)));
+        return (Hashing { inner, state: state.clone() }, state);
+    }
+
+    fn update(&self, data: &[u8]) {
+        let mut state = self.state.lock().unwrap_or_else(|e| e.into_inner());
+        state.0.update(data);
+        state.1 += data.len() as u64;
+    }
+}
+
+impl<R: Read> Read for Hashing<R> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        let read = self.inner.read(buf)?;
+        self.update(&buf[..read]);
+        return Ok(read);
+    }
+}
+
+impl<W: Write> Write for Hashing<W> {
+    fn write(&mut self, data: &[u8]) -> std::io::Result<usize> {
+        let written = self.inner.write(data)?;
+        self.update(&data[..written]);
+        return Ok(written);
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        return self.inner.flush();
+    }
+}
+
+/// Hex SHA-256 and byte count of a `Hashing` tee
+fn digest_of(state: &Mutex<(Sha256, u64)>) -> (String, u64) {
+    let state = state.lock().unwrap_or_else(|e| e.into_inner());
+    return (format!("{:x}", state.0.clone().finalize()), state.1);
+}
+
+/// The kinds of the `sidecars` parameter of `param_set`, in its order
+pub(crate) fn requested(param_set: &ParamSet) -> Result<Vec<SidecarKind>, Box<dyn Error>> {
+    let mut kinds = Vec::new();
+    for name in param_set.get_string("sidecars", "").split(',').map(str::trim).filter(|name| !name.is_empty()) {
+        let kind = [SidecarKind::Sha256, SidecarKind::UncompressedSha256, SidecarKind::Size].into_iter()
+            .find(|kind| kind.name() == name)
+            .ok_or_else(|| format!("unknown sidecar {:?}, expected sha256, usha256 or size", name))?;
+        if !kinds.contains(&kind) {
+            kinds.push(kind);
+        }
+    }
+    return Ok(kinds);
+}
+
+/// `compress_file` of `src` into `dst` with the `sidecars` of `param_set`: the digests and sizes
+/// are taken as the file is compressed, not by reading it again, and the artifact and its
+/// sidecars are committed in one transaction, so none of them is in place without the others.
+pub(crate) fn compress_with_sidecars(
+    src: &Path,
+    dst: &Path,
+    compression_type: CompressionType,
+    param_set: ParamSet,
+    sidecars: &[SidecarKind],
+    on_progress: &mut dyn FnMut(&Progress),
+    cancel: &CancelToken) -> Result<u64, Box<dyn Error>> {
+    let names = SidecarNames::from_params(&param_set);
+    let input = open_file(src, |path| File::open(path))?;
+    let file_name = |path: &Path| path.file_name().map(|name| name.to_string_lossy().into_owned()).unwrap_or_default();
+    let dir = dst.parent().filter(|dir| !dir.as_os_str().is_empty()).unwrap_or(Path::new("."));
+    let target = dir.join(dst.file_name().ok_or_else(|| format!("{}: not a file name", dst.display()))?);
+    // recovery removes the staged files of every transaction on dir, ours included
+    let _lock = lock_dir(dir).map_err(|e| format!("{}: {}", dir.display(), e))?;
+    recover_pending(dir)?;
+    let mut transaction = Transaction::begin(dir)?;
+    let staged = transaction.stage(&target)?;
+    let (out, compressed) = Hashing::new(PathErrors::new(open_file(&staged, |path| File::create(path))?, dst));
+    let (input, uncompressed) = Hashing::new(input);
+    let written = compress_to(src, input, Box::new(out), compression_type, param_set, on_progress, cancel)?;
+    let (compressed_digest, compressed_size) = digest_of(&compressed);
+    let (uncompressed_digest, uncompressed_size) = digest_of(&uncompressed);
+    for kind in sidecars {
+        let content = match kind {
+            SidecarKind::Sha256 => format!("{}  {}\n", compressed_digest, file_name(dst)),
+            SidecarKind::UncompressedSha256 => format!("{}  {}\n", uncompressed_digest, file_name(src)),
+            SidecarKind::Size => format!("compressed {}\nuncompressed {}\n", compressed_size, uncompressed_size),
+        };
+        let sidecar = names.path(&target, *kind);
+        let staged = transaction.stage(&sidecar)?;
+        std::fs::write(&staged, content).map_err(|e| format!("{}: {}", sidecar.display(), e))?;
+    }
+    transaction.commit()?;
+    return Ok(written);
+}
+
+/// Check `path` against those of its sidecars named by `names` that exist, returning one check
+/// per sidecar found. The uncompressed digest and size need the codec of the extension of
+/// `path`; the content is decompressed only if one of them is there.
+pub fn verify_with_sidecars(path: &Path, names: &SidecarNames) -> Result<Vec<SidecarCheck>, Box<dyn Error>> {
+    let found: Vec<(SidecarKind, PathBuf, String)> = [SidecarKind::Sha256, SidecarKind::UncompressedSha256, SidecarKind::Size]
+        .into_iter()
+        .filter_map(|kind| {
+            let sidecar = names.path(path, kind);
+            let content = std::fs::read_to_string(&sidecar).ok()?;
+            return Some((kind, sidecar, content));
+        })
+        .collect();
+
+    let (mut sink, compressed) = Hashing::new(std::io::sink());
+    std::io::copy(&mut PathErrors::new(open_file(path, |path| File::open(path))?, path), &mut sink)?;
+    let (compressed_digest, compressed_size) = digest_of(&compressed);
+    let uncompressed = if found.iter().any(|(kind, _, _)| *kind != SidecarKind::Sha256) {
+        let decompress = || -> Result<(String, u64), Box<dyn Error>> {
+            let compression_type = CompressionType::from_path(path)
+                .ok_or_else(|| format!("{}: no codec has this extension", path.display()))?;
+            let mut reader = decompressed_reader(Box::new(File::open(path)?), compression_type)?;
+            let (mut sink, state) = Hashing::new(std::io::sink());
+            std::io::copy(&mut reader, &mut sink)?;
+            return Ok(digest_of(&state));
+        };
+        decompress().map_err(|e| format!("not decompressed: {}", e))
+    } else {
+        Err(String::new())
+    };
+
+    let mut checks = Vec::new();
+    for (kind, sidecar, content) in found {
+        let problem = match kind {
+            SidecarKind::Sha256 => check_digest(&content, Ok(&compressed_digest), "compressed"),
+            SidecarKind::UncompressedSha256 => check_digest(&content, uncompressed.as_ref().map(|(digest, _)| digest.as_str()), "uncompressed"),
+            SidecarKind::Size => check_sizes(&content, compressed_size, uncompressed.as_ref().map(|(_, size)| *size)),
+        };
+        checks.push(SidecarCheck { kind, path: sidecar, problem });
+    }
+    return Ok(checks);
+}
+
+/// What is wrong with the `sha256sum` line `content` for the `actual` digest
+fn check_digest(content: &str, actual: Result<&str, &String>, what: &str) -> Option<String> {
+    let expected = content.split_whitespace().next().unwrap_or("").to_ascii_lowercase();
+    if expected.len() != 64 || !expected.bytes().all(|b| b.is_ascii_hexdigit()) {
+        return Some("no SHA-256 digest in the sidecar".into());
+    }
+    return match actual {
+        Ok(actual) if actual == expected => None,
+        Ok(actual) => Some(format!("{} SHA-256 is {}, the sidecar has {}", what, actual, expected)),
+        Err(e) => Some(e.clone()),
+    };
+}
+
+/// What is wrong with the size sidecar `content`, every mismatching line being reported
+fn check_sizes(content: &str, compressed: u64, uncompressed: Result<u64, &String>) -> Option<String> {
+    let mut problems = Vec::new();
+    let mut seen = 0;
+    for line in content.lines().filter(|line| !line.trim().is_empty()) {
+        let (name, value) = line.split_once(' ').unwrap_or((line, ""));
+        let Ok(expected) = value.trim().parse::<u64>() else {
+            problems.push(format!("malformed line {:?}", line));
+            continue;
+        };
+        let actual = match name {
+            "compressed" => Ok(compressed),
+            "uncompressed" => uncompressed,
+            _ => {
+                problems.push(format!("unknown size {:?}", name));
+                continue;
+            },
+        };
+        seen += 1;
+        match actual {
+            Ok(actual) if actual == expected => {},
+            Ok(actual) => problems.push(format!("{} size is {}, the sidecar has {}", name, actual, expected)),
+            Err(e) => problems.push(e.clone()),
+        }
+    }
+    if seen == 0 && problems.is_empty() {
+        problems.push("no size in the sidecar".into());
+    }
+    return if problems.is_empty() { None } else { Some(problems.join("; ")) };
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    pub fn test_sidecars() {
+        let dir = std::env::temp_dir().join(format!("final_compression_sidecars_{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let text = "shipped with a digest of each form and both sizes\n".repeat(50_000);
+        let src = dir.join("artifact.txt");
+        let dst = dir.join("artifact.txt.gz");
+        std::fs::write(&src, &text).unwrap();
+        let all = [SidecarKind::Sha256, SidecarKind::UncompressedSha256, SidecarKind::Size];
+        let names = SidecarNames::default();
+        let written = crate::compress_file(&src, &dst, CompressionType::Gzip, "level=6;sidecars=sha256,usha256,size").unwrap();
+        let compressed = std::fs::read(&dst).unwrap();
+        assert_eq!(written, compressed.len() as u64);
+        let digest = format!("{:x}", Sha256::digest(&compressed));
+        assert_eq!(std::fs::read_to_string(dir.join("artifact.txt.gz.sha256")).unwrap(), format!("{}  artifact.txt.gz\n", digest));
+        let digest = format!("{:x}", Sha256::digest(text.as_bytes()));
+        assert_eq!(std::fs::read_to_string(dir.join("artifact.txt.gz.usha256")).unwrap(), format!("{}  artifact.txt\n", digest));
+        assert_eq!(std::fs::read_to_string(dir.join("artifact.txt.gz.size")).unwrap(),
+            format!("compressed {}\nuncompressed {}\n", compressed.len(), text.len()));
+        // neither staged files nor the lock are left behind
+        let mut listed: Vec<String> = std::fs::read_dir(&dir).unwrap().map(|e| e.unwrap().file_name().to_string_lossy().into_owned()).collect();
+        listed.sort();
+        assert_eq!(listed, ["artifact.txt", "artifact.txt.gz", "artifact.txt.gz.sha256", "artifact.txt.gz.size", "artifact.txt.gz.usha256"]);
+
+        let checks = verify_with_sidecars(&dst, &names).unwrap();
+        assert_eq!(checks.iter().map(|check| check.kind).collect::<Vec<_>>(), all);
+        assert!(checks.iter().all(SidecarCheck::passed), "{:?}", checks);
+
+        // bytes appended to the artifact: gzip stops before them, so only the compressed digest
+        // and size are wrong
+        std::fs::write(&dst, [&compressed[..], b"tampered"].concat()).unwrap();
+        let checks = verify_with_sidecars(&dst, &names).unwrap();
+        let problems: Vec<Option<&str>> = checks.iter().map(|check| check.problem.as_deref()).collect();
+        assert!(problems[0].unwrap().starts_with("compressed SHA-256 is "), "{:?}", problems);
+        assert_eq!(problems[1], None);
+        assert_eq!(problems[2], Some(format!("compressed size is {}, the sidecar has {}", compressed.len() + 8, compressed.len()).as_str()));
+
+        // only the sidecars there are checked, under the names of the pattern
+        let options = "level=6;sidecars=sha256;sidecar_sha256={file}.SHA256SUM";
+        let names = SidecarNames::from_params(&options.into());
+        assert_eq!(names, SidecarNames { sha256: "{file}.SHA256SUM".into(), ..SidecarNames::default() });
+        crate::compress_file(&src, &dst, CompressionType::Gzip, options).unwrap();
+        assert!(dir.join("artifact.txt.gz.SHA256SUM").exists());
+        let checks = verify_with_sidecars(&dst, &SidecarNames { size: "none".into(), ..names }).unwrap();
+        assert_eq!(checks.iter().map(|check| (check.kind, check.passed())).collect::<Vec<_>>(),
+            [(SidecarKind::Sha256, true), (SidecarKind::UncompressedSha256, true)]);
+
+        // a sidecar that cannot be written leaves no artifact either
+        let other = dir.join("other.gz");
+        let err = crate::compress_file(&src, &other, CompressionType::Gzip, "sidecars=sha256,size;sidecar_size={file}\tsize").unwrap_err();
+        assert!(err.to_string().contains("not a name a journal can hold"), "{}", err);
+        assert!(!other.exists() && !dir.join("other.gz.sha256").exists());
+        let err = crate::compress_file(&src, &other, CompressionType::Gzip, "sidecars=md5").unwrap_err();
+        assert_eq!(err.to_string(), "unknown sidecar \"md5\", expected sha256, usha256 or size");
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,244 @@
+use std::error::Error;
+use std::fs::File;
+use std::io::{Read, Write};
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use crate::buffer::SharedBuffer;
+use crate::queue::JobInput;
+use crate::summary::CountingWriter;
+use crate::{compressed_reader_from_iter, compressed_writer, CompressionType, ParamSet};
+
+/// Inputs up to this size are compressed in memory by `PrecomputeSize::Auto`
+pub const AUTO_MEMORY_LIMIT: u64 = 16 * 1024 * 1024;
+
+/// Both passes of `TwoPass` hand their input to the encoder in full chunks of this size: the
+/// output of some encoders (lz4) depends on how the input is split into writes.
+const CHUNK_SIZE: usize = 64 * 1024;
+
+/// Spill files created by this process, for unique names
+static SPILL_COUNTER: AtomicU64 = AtomicU64::new(0);
+
+/// How `precompute_size` learns the compressed length before handing out the data
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum PrecomputeSize {
+    /// `BufferToMemory` for byte inputs and files up to `AUTO_MEMORY_LIMIT`, `TwoPass` for larger
+    /// files and `BufferToDisk` for readers
+    Auto,
+    /// Compress once into a counting sink, then again while reading. Needs an input that can be
+    /// read twice (a path or bytes) and a codec whose output only depends on its input.
+    TwoPass,
+    /// Compress into a temporary file, removed once the returned reader is dropped
+    BufferToDisk,
+    /// Compress into memory, failing once the output exceeds this many bytes
+    BufferToMemory(u64),
+}
+
+/// Compress `input` and return the exact compressed length with a reader of the compressed
+/// bytes, for HTTP uploads and object stores wanting a `Content-Length` up front. `option`
+/// takes the parameters of `compressed_writer`.
+pub fn precompute_size<T: Into<ParamSet>>(input: JobInput, compression_type: CompressionType, option: T, strategy: PrecomputeSize) -> Result<(u64, Box<dyn Read>), Box<dyn Error>> {
+    let param_set: ParamSet = option.into();
+    let strategy = match (strategy, &input) {
+        (PrecomputeSize::Auto, JobInput::Bytes(_)) => PrecomputeSize::BufferToMemory(u64::MAX),
+        (PrecomputeSize::Auto, JobInput::Path(path)) => match std::fs::metadata(path) {
+            Ok(metadata) if metadata.len() <= AUTO_MEMORY_LIMIT => PrecomputeSize::BufferToMemory(u64::MAX),
+            _ => PrecomputeSize::TwoPass,
+        },
+        (PrecomputeSize::Auto, JobInput::Reader(_)) => PrecomputeSize::BufferToDisk,
+        (strategy, _) => strategy,
+    };
+    match strategy {
+        PrecomputeSize::TwoPass => {
+            if let CompressionType::Custom(_) = compression_type {
+                return Err("TwoPass needs a deterministic codec, registered codecs may not be".into());
+            }
+            if let JobInput::Reader(_) = input {
+                return Err("TwoPass needs an input that can be read twice, not a reader".into());
+            }
+            let (out, counter) = CountingWriter::new(Box::new(std::io::sink()));
+            let mut encoder = compressed_writer(Box::new(out), compression_type, param_set.clone())?;
+            let mut src: Box<dyn Read + '_> = match &input {
+                JobInput::Path(path) => Box::new(File::open(path).map_err(|e| format!("{}: {}", path.display(), e))?),
+                JobInput::Bytes(bytes) => Box::new(&bytes[..]),
+                JobInput::Reader(_) => unreachable!(),
+            };
+            let mut chunk = vec![0u8; CHUNK_SIZE];
+            loop {
+                let n = read_chunk(&mut src, &mut chunk)?;
+                if n == 0 {
+                    break;
+                }
+                encoder.write_all(&chunk[..n])?;
+            }
+            drop(src);
+            drop(encoder);
+            let length = counter.load(Ordering::Relaxed);
+            let mut src = open(input)?;
+            let chunks = std::iter::from_fn(move || match read_chunk(&mut src, &mut chunk) {
+                Ok(0) => None,
+                Ok(n) => Some(Ok(chunk[..n].to_vec())),
+                Err(e) => Some(Err(e)),
+            });
+            let reader = compressed_reader_from_iter(chunks, compression_type, param_set)?;
+            return Ok((length, Box::new(CheckedLength { inner: reader, expected: length, read: 0 })));
+        },
+        PrecomputeSize::BufferToDisk => {
+            let path = std::env::temp_dir().join(format!("final_compression.spill.{}.{}",
+                std::process::id(), SPILL_COUNTER.fetch_add(1, Ordering::Relaxed)));
+            let mut spill = SpillFile { file: File::create(&path)?, path };
+            let mut encoder = compressed_writer(Box::new(spill.file.try_clone()?), compression_type, param_set)?;
+            std::io::copy(&mut open(input)?, &mut encoder)?;
+            drop(encoder);
+            spill.file = File::open(&spill.path)?;
+            let length = spill.file.metadata()?.len();
+            return Ok((length, Box::new(spill)));
+        },
+        PrecomputeSize::BufferToMemory(cap) => {
+            let buffer = SharedBuffer::default();
+            let exceeded = Arc::new(AtomicBool::new(false));
+            let capped = CappedWriter { inner: buffer.clone(), cap, written: 0, exceeded: exceeded.clone() };
+            let mut encoder = compressed_writer(Box::new(capped), compression_type, param_set)?;
+            std::io::copy(&mut open(input)?, &mut encoder)?;
+            drop(encoder);
+            let compressed = buffer.take();
+            // the encoder may only have hit the cap while finishing, on drop
+            if exceeded.load(Ordering::Relaxed) {
+                return Err(format!("compressed size exceeds the buffer cap of {} bytes", cap).into());
+            }
+            return Ok((compressed.len() as u64, Box::new(std::io::Cursor::new(compressed))));
+        },
+        PrecomputeSize::Auto => unreachable!(),
+    }
+}
+
+fn open(input: JobInput) -> Result<Box<dyn Read>, Box<dyn Error>> {
+    return match input {
+        JobInput::Path(path) => Ok(Box::new(File::open(&path).map_err(|e| format!("{}: {}", path.display(), e))?)),
+        JobInput::Bytes(bytes) => Ok(Box::new(std::io::Cursor::new(bytes))),
+        JobInput::Reader(reader) => Ok(reader),
+    };
+}
+
+/// Fill `chunk` from `src`, short only at the end of the input
+fn read_chunk(src: &mut dyn Read, chunk: &mut [u8]) -> std::io::Result<usize> {
+    let mut filled = 0;
+    while filled < chunk.len() {
+        match src.read(&mut chunk[filled..]) {
+            Ok(0) => break,
+            Ok(n) => filled += n,
+            Err(e) if e.kind() == std::io::ErrorKind::Interrupted => {},
+            Err(e) => return Err(e),
+        }
+    }
+    return Ok(filled);
+}
+
+/// Writer failing once more than `cap` bytes went through it
+struct CappedWriter {
+    inner: SharedBuffer,
+    cap: u64,
+    written: u64,
+    exceeded: Arc<AtomicBool>,
+}
+
+impl Write for CappedWriter {
+    fn write(&mut self, data: &[u8]) -> std::io::Result<usize> {
+        self.written += data.len() as u64;
+        if self.written > self.cap {
+            self.exceeded.store(true, Ordering::Relaxed);
+            return Err(std::io::Error::other(format!("compressed size exceeds the buffer cap of {} bytes", self.cap)));
+        }
+        return self.inner.write(data);
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        return Ok(());
+    }
+}
+
+/// Temporary file removed on drop
+struct SpillFile {
+    file: File,
+    path: PathBuf,
+}
+
+impl Read for SpillFile {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        return self.file.read(buf);
+    }
+}
+
+impl Drop for SpillFile {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_file(&self.path);
+    }
+}
+
+/// Reader failing if the second pass does not produce the length of the first
+struct CheckedLength<R> {
+    inner: R,
+    expected: u64,
+    read: u64,
+}
+
+impl<R: Read> Read for CheckedLength<R> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        let read = self.inner.read(buf)?;
+        self.read += read as u64;
+        if self.read > self.expected || (read == 0 && !buf.is_empty() && self.read != self.expected) {
+            return Err(std::io::Error::new(std::io::ErrorKind::InvalidData,
+                format!("second pass produced a different length than the {} bytes announced", self.expected)));
+        }
+        return Ok(read);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::decompressed_reader;
+
+    #[test]
+    pub fn test_strategies() {
+        let data: Vec<u8> = (0..300_000u32).map(|i| (i % 251) as u8 ^ (i / 1100) as u8).collect();
+        let file_name = std::env::temp_dir().join(format!("final_compression.sized.{}", std::process::id()));
+        std::fs::write(&file_name, &data).unwrap();
+        let strategies = [PrecomputeSize::Auto, PrecomputeSize::TwoPass, PrecomputeSize::BufferToDisk, PrecomputeSize::BufferToMemory(1 << 20)];
+        for ct in [CompressionType::Zstd, CompressionType::Gzip, CompressionType::XZ, CompressionType::LZ4] {
+            for strategy in strategies.iter() {
+                for input in [JobInput::Path(file_name.clone()), JobInput::Bytes(data.clone())] {
+                    let (length, mut reader) = precompute_size(input, ct, "level=3", strategy.clone()).unwrap();
+                    let mut compressed = Vec::new();
+                    reader.read_to_end(&mut compressed).unwrap();
+                    assert_eq!(compressed.len() as u64, length, "{:?} {:?}", ct, strategy);
+                    let mut plain = Vec::new();
+                    decompressed_reader(Box::new(std::io::Cursor::new(compressed)), ct).unwrap().read_to_end(&mut plain).unwrap();
+                    assert!(plain == data, "{:?} {:?}", ct, strategy);
+                }
+            }
+        }
+        let (length, mut reader) = precompute_size(JobInput::Reader(Box::new(std::io::Cursor::new(data.clone()))),
+            CompressionType::Zstd, "", PrecomputeSize::Auto).unwrap();
+        assert_eq!(std::io::copy(&mut reader, &mut std::io::sink()).unwrap(), length);
+        std::fs::remove_file(&file_name).unwrap();
+    }
+
+    #[test]
+    pub fn test_refusals() {
+        let reader = JobInput::Reader(Box::new(std::io::Cursor::new(vec![1u8; 10])));
+        let err = precompute_size(reader, CompressionType::Zstd, "", PrecomputeSize::TwoPass).err().unwrap();
+        assert!(err.to_string().contains("read twice"), "{}", err);
+        let err = precompute_size(JobInput::Bytes(vec![1; 10]), CompressionType::Custom(7), "", PrecomputeSize::TwoPass).err().unwrap();
+        assert!(err.to_string().contains("deterministic"), "{}", err);
+        let mut state = 0x2545F4914F6CDD1Du64;
+        let random: Vec<u8> = (0..100_000).map(|_| {
+            state ^= state << 13;
+            state ^= state >> 7;
+            state ^= state << 17;
+            state as u8
+        }).collect();
+        let err = precompute_size(JobInput::Bytes(random), CompressionType::Gzip, "", PrecomputeSize::BufferToMemory(1000)).err().unwrap();
+        assert!(err.to_string().contains("buffer cap of 1000 bytes"), "{}", err);
+    }
+}
@@ -0,0 +1,159 @@
+use std::error::Error;
+use std::fs::File;
+use std::io::{Seek, SeekFrom, Write};
+use crate::ParamSet;
+
+/// Default `sparse_block` of `sparse_writer`
+pub const DEFAULT_SPARSE_BLOCK: usize = 64 * 1024;
+
+/// File writer seeking over zero blocks instead of writing them, see `sparse_writer`
+pub struct SparseWriter {
+    file: File,
+    block_size: usize,
+    sparse: bool,
+    /// Bytes of the current block not written yet
+    block: Vec<u8>,
+    /// Logical length written so far
+    length: u64,
+    finished: bool,
+}
+
+/// Writer of decompressed output to `file` that leaves holes where the data is zero, like a VM
+/// image, so the file only allocates disk space for its non zero blocks.
+///
+/// Parameters:
+///     sparse=true|false (default true; false writes every byte)
+///     sparse_block=size (block size checked for zeros, default 64 KiB)
+///
+/// Only whole blocks, aligned on `sparse_block` from the start of the file, become holes. Holes
+/// are a Unix feature: elsewhere zero blocks are written like any other. Call `finish` to set
+/// the final length, which a trailing hole would otherwise leave short; dropping finishes too,
+/// ignoring errors. `file` should be new or truncated, as skipped blocks keep their old content.
+pub fn sparse_writer<T: Into<ParamSet>>(file: File, option: T) -> Result<SparseWriter, Box<dyn Error>> {
+    let param_set: ParamSet = option.into();
+    let block_size: usize = param_set.get_size("sparse_block", DEFAULT_SPARSE_BLOCK)?;
+    if block_size == 0 {
+        return Err("sparse_block must not be 0".into());
+    }
+    let sparse = param_set.get_flag("sparse", true)?;
+    return Ok(SparseWriter {
+        file,
+        block_size,
+        sparse: cfg!(unix) && sparse,
+        block: Vec::with_capacity(block_size),
+        length: 0,
+        finished: false,
+    });
+}
+
+impl SparseWriter {
+    /// Write or skip the buffered block
+    fn write_block(&mut self) -> std::io::Result<()> {
+        if self.sparse && self.block.len() == self.block_size && self.block.iter().all(|&b| b == 0) {
+            self.file.seek(SeekFrom::Current(self.block_size as i64))?;
+        } else {
+            self.file.write_all(&self.block)?;
+        }
+        self.length += self.block.len() as u64;
+        self.block.clear();
+        return Ok(());
+    }
+
+    /// Write the last partial block and set the file length, returning it
+    pub fn finish(mut self) -> std::io::Result<u64> {
+        self.finish_file()?;
+        return Ok(self.length);
+    }
+
+    fn finish_file(&mut self) -> std::io::Result<()> {
+        if self.finished {
+            return Ok(());
+        }
+        self.finished = true;
+        self.write_block()?;
+        self.file.set_len(self.length)?;
+        return self.file.flush();
+    }
+}
+
+impl Write for SparseWriter {
+    fn write(&mut self, data: &[u8]) -> std::io::Result<usize> {
+        let n = data.len().min(self.block_size - self.block.len());
+        self.block.extend_from_slice(&data[..n]);
+        if self.block.len() == self.block_size {
+            self.write_block()?;
+        }
+        return Ok(n);
+    }
+
+    /// Flushes the file; the partial block is kept until it is complete or `finish` is called
+    fn flush(&mut self) -> std::io::Result<()> {
+        return self.file.flush();
+    }
+}
+
+impl Drop for SparseWriter {
+    fn drop(&mut self) {
+        let _ = self.finish_file();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Read;
+    use crate::{compressed_writer, decompressed_reader, CompressionType};
+
+    /// 8 MiB image, zero but for a header, a block in the middle and an unaligned tail
+    fn image() -> Vec<u8> {
+        let mut image = vec![0u8; 8 * 1024 * 1024 + 100];
+        image[..4096].fill(0xaa);
+        image[3 * 1024 * 1024 + 10..3 * 1024 * 1024 + 70_000].fill(0x55);
+        image[8 * 1024 * 1024..8 * 1024 * 1024 + 10].fill(1);
+        return image;
+    }
+
+    fn decompress_to(path: &std::path::Path, compressed: &[u8], options: &str) -> u64 {
+        let mut reader = decompressed_reader(Box::new(std::io::Cursor::new(compressed.to_vec())), CompressionType::Zstd).unwrap();
+        let mut writer = sparse_writer(File::create(path).unwrap(), options).unwrap();
+        std::io::copy(&mut reader, &mut writer).unwrap();
+        return writer.finish().unwrap();
+    }
+
+    #[test]
+    pub fn test_sparse_output() {
+        let image = image();
+        let buffer = crate::buffer::SharedBuffer::default();
+        let mut w = compressed_writer(Box::new(buffer.clone()), CompressionType::Zstd, "").unwrap();
+        w.write_all(&image).unwrap();
+        drop(w);
+        let compressed = buffer.take();
+
+        let dir = std::env::temp_dir().join(format!("final_compression_sparse_{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        for (name, options) in [("sparse.img", "sparse_block=64KiB"), ("full.img", "sparse=false")] {
+            let path = dir.join(name);
+            assert_eq!(decompress_to(&path, &compressed, options), image.len() as u64);
+            let metadata = std::fs::metadata(&path).unwrap();
+            assert_eq!(metadata.len(), image.len() as u64);
+            let mut content = Vec::new();
+            File::open(&path).unwrap().read_to_end(&mut content).unwrap();
+            assert!(content == image, "{}", name);
+            #[cfg(unix)]
+            if name == "sparse.img" {
+                use std::os::unix::fs::MetadataExt;
+                let allocated = metadata.blocks() * 512;
+                assert!(allocated < image.len() as u64 / 10, "{} bytes allocated", allocated);
+            }
+        }
+        // a trailing hole still gives the full length
+        let zeros = vec![0u8; 256 * 1024];
+        let path = dir.join("zeros.img");
+        let mut writer = sparse_writer(File::create(&path).unwrap(), "").unwrap();
+        writer.write_all(&zeros).unwrap();
+        drop(writer);
+        assert_eq!(std::fs::metadata(&path).unwrap().len(), zeros.len() as u64);
+        std::fs::remove_dir_all(&dir).unwrap();
+        assert!(sparse_writer(File::open("Cargo.toml").unwrap(), "sparse_block=0").is_err());
+    }
+}
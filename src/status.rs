@@ -0,0 +1,401 @@
+use std::error::Error;
+use std::io::{BufRead, BufReader, ErrorKind, Read};
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use crate::{build_decoder, eof, libstored, untrusted, CompressionType, ParamSet};
+
+/// Where a reader from `decompressed_reader_status` stands in its compressed stream
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum StreamStatus {
+    /// The end of the stream was not reached yet
+    InProgress,
+    /// The stream ended where its format says it ends, with its checksums verified. With
+    /// `count_trailing=true`, the source ended with it too; otherwise the source was not read
+    /// past the stream.
+    Complete,
+    /// As `Complete`, but the source held this many more bytes after the stream
+    CompleteWithTrailing(u64),
+    /// As `Complete`, but followed by this many zero bytes of padding accepted by
+    /// `padding=zeros:<block_size>`
+    CompletePadded(u64),
+    /// The source ended before the stream did
+    Truncated,
+}
+
+/// Decoder reading no further than the end of its stream
+trait StreamEnd: Read {
+    /// The source bytes after the stream, once it ended; `None` if they cannot be told apart
+    fn rest(&mut self) -> Option<&mut dyn Read>;
+}
+
+/// Decoder stopping at the end of its stream, with access to the rest of its source
+struct Bounded<D> {
+    decoder: D,
+    source: fn(&mut D) -> &mut dyn Read,
+}
+
+impl<D: Read> Read for Bounded<D> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        return self.decoder.read(buf);
+    }
+}
+
+impl<D: Read> StreamEnd for Bounded<D> {
+    fn rest(&mut self) -> Option<&mut dyn Read> {
+        return Some((self.source)(&mut self.decoder));
+    }
+}
+
+/// Formats without an end marker and custom codecs, where trailing data cannot be told apart
+/// from the stream
+struct Unbounded(Box<dyn Read>);
+
+impl Read for Unbounded {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        return self.0.read(buf);
+    }
+}
+
+impl StreamEnd for Unbounded {
+    fn rest(&mut self) -> Option<&mut dyn Read> {
+        return None;
+    }
+}
+
+/// The LZ4 decoder reads exactly up to the end mark, but only gives its source back by value
+struct Lz4End {
+    decoder: Option<lz4::Decoder<Box<dyn Read>>>,
+    rest: Option<Box<dyn Read>>,
+}
+
+impl Read for Lz4End {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        let decoder = match self.decoder.as_mut() {
+            Some(decoder) => decoder,
+            None => return Ok(0),
+        };
+        let read = decoder.read(buf)?;
+        if read == 0 && !buf.is_empty() {
+            let (src, result) = self.decoder.take().unwrap().finish();
+            if result.is_err() {
+                return Err(std::io::Error::new(ErrorKind::UnexpectedEof, "LZ4 stream ended before its end mark"));
+            }
+            self.rest = Some(src);
+        }
+        return Ok(read);
+    }
+}
+
+impl StreamEnd for Lz4End {
+    fn rest(&mut self) -> Option<&mut dyn Read> {
+        return self.rest.as_mut().map(|rest| rest as &mut dyn Read);
+    }
+}
+
+/// xz decoder stopping after the stream footer, where `xz2::bufread::XzDecoder` goes on decoding
+/// whatever follows
+struct XzEnd {
+    src: BufReader<Box<dyn Read>>,
+    stream: xz2::stream::Stream,
+    finished: bool,
+}
+
+impl Read for XzEnd {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        if self.finished || buf.is_empty() {
+            return Ok(0);
+        }
+        loop {
+            let input = self.src.fill_buf()?;
+            let eof = input.is_empty();
+            let (total_in, total_out) = (self.stream.total_in(), self.stream.total_out());
+            let status = self.stream.process(input, buf, xz2::stream::Action::Run)
+                .map_err(|e| std::io::Error::new(ErrorKind::InvalidData, e))?;
+            let consumed = (self.stream.total_in() - total_in) as usize;
+            let produced = (self.stream.total_out() - total_out) as usize;
+            self.src.consume(consumed);
+            if matches!(status, xz2::stream::Status::StreamEnd) {
+                self.finished = true;
+                return Ok(produced);
+            }
+            if produced > 0 {
+                return Ok(produced);
+            }
+            if eof {
+                return Err(std::io::Error::new(ErrorKind::UnexpectedEof, "xz stream ended before its footer"));
+            }
+            if consumed == 0 {
+                return Err(std::io::Error::new(ErrorKind::InvalidData, "xz decoder made no progress"));
+            }
+        }
+    }
+}
+
+impl StreamEnd for XzEnd {
+    fn rest(&mut self) -> Option<&mut dyn Read> {
+        return Some(&mut self.src);
+    }
+}
+
+/// Decompression reader tracking whether its stream ended properly, see `decompressed_reader_status`
+pub struct StatusReader {
+    decoder: Box<dyn StreamEnd>,
+    hit_eof: Arc<AtomicBool>,
+    status: StreamStatus,
+    /// Block size of accepted zero padding, and the source bytes read
+    padding: Option<(u64, Arc<AtomicU64>)>,
+    /// Whether to read the rest of the source once the stream ended
+    drain: bool,
+}
+
+impl StatusReader {
+    pub fn status(&self) -> StreamStatus {
+        return self.status;
+    }
+
+    /// Status of the stream that just ended, reading the rest of the source when draining
+    fn ended(&mut self) -> std::io::Result<StreamStatus> {
+        if !self.drain {
+            return Ok(StreamStatus::Complete);
+        }
+        let Some(rest) = self.decoder.rest() else {
+            return Ok(StreamStatus::Complete);
+        };
+        let mut buf = [0u8; 8192];
+        let mut trailing = 0u64;
+        let mut zeros = true;
+        loop {
+            let read = match rest.read(&mut buf) {
+                Ok(0) => break,
+                Ok(read) => read,
+                Err(e) if e.kind() == ErrorKind::Interrupted => continue,
+                Err(e) => return Err(e),
+            };
+            zeros &= buf[..read].iter().all(|b| *b == 0);
+            trailing += read as u64;
+        }
+        if trailing == 0 {
+            return Ok(StreamStatus::Complete);
+        }
+        if let Some((block_size, count)) = &self.padding {
+            if zeros && count.load(Ordering::Relaxed).is_multiple_of(*block_size) {
+                return Ok(StreamStatus::CompletePadded(trailing));
+            }
+        }
+        return Ok(StreamStatus::CompleteWithTrailing(trailing));
+    }
+}
+
+impl Read for StatusReader {
+    /// `Ok(0)` once the stream is complete. A truncated stream fails with `UnexpectedEof`, on
+    /// this and every later call.
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        match self.status {
+            StreamStatus::InProgress => {},
+            StreamStatus::Complete | StreamStatus::CompleteWithTrailing(_) | StreamStatus::CompletePadded(_) => return Ok(0),
+            StreamStatus::Truncated => return Err(std::io::Error::new(ErrorKind::UnexpectedEof, "compressed stream ended prematurely")),
+        }
+        if buf.is_empty() {
+            return Ok(0);
+        }
+        match self.decoder.read(buf) {
+            Ok(0) => {
+                self.status = self.ended()?;
+                return Ok(0);
+            },
+            Ok(read) => return Ok(read),
+            Err(e) if e.kind() == ErrorKind::UnexpectedEof || self.hit_eof.load(Ordering::Relaxed) => {
+                self.status = StreamStatus::Truncated;
+                return Err(std::io::Error::new(ErrorKind::UnexpectedEof, format!("compressed stream ended prematurely: {}", e)));
+            },
+            Err(e) => return Err(e),
+        }
+    }
+}
+
+/// Decompression reader reporting, once `read` returned 0, whether it saw a complete stream.
+///
+/// Unlike the other readers, it stops at the end of the compressed stream: the zstd reader reads
+/// a single frame and the gzip reader a single member. The source is not read further unless
+/// `count_trailing=true` is set: then, once the stream ended, the rest of the source is read to
+/// count trailing bytes, see `StreamStatus::CompleteWithTrailing`. That waits for the end of the
+/// source, which on a connection carrying more than this stream may never come.
+///
+/// A premature end of input is always an error, as with `eof_policy=strict`. Supported
+/// parameters are `eof_policy`, `memory_limit`, `max_block_size`, `count_trailing` and
+/// `padding`, which implies `count_trailing=true`: trailing zeros making the source a multiple
+/// of `padding=zeros:<block_size>` are `CompletePadded`, anything else after the stream stays
+/// `CompleteWithTrailing`.
+///
+/// Snappy streams have no end marker and raw streams no framing at all, so for those, as for
+/// custom codecs, trailing data is not detected and a snappy stream cut at a frame boundary is
+/// `Complete`.
+pub fn decompressed_reader_status<T: Into<ParamSet>>(
+    src: Box<dyn Read>,
+    compression_type: CompressionType,
+    option: T) -> Result<StatusReader, Box<dyn Error>> {
+    let param_set: ParamSet = option.into();
+    let count_trailing = param_set.get_flag("count_trailing", false)?;
+    let src: Box<dyn Read> = match eof::EofPolicy::from_params(&param_set)? {
+        eof::EofPolicy::Retry { attempts, backoff } => Box::new(eof::RetryOnEof::new(src, attempts, backoff)),
+        _ => src
+    };
+    let (src, padding) = match untrusted::zero_padding(&param_set)? {
+        Some(block_size) => {
+            let (src, count) = untrusted::count_input(src);
+            (src, Some((block_size, count)))
+        },
+        None => (src, None),
+    };
+    let (src, hit_eof) = eof::track_eof(src);
+    let memory_limit = untrusted::memory_limit(&param_set)?;
+    let decoder: Box<dyn StreamEnd> = match compression_type {
+        CompressionType::Zstd => {
+            let dictionary = crate::zstd_dictionary(&param_set, param_set.get_string("dict_path", ""))?;
+            let mut decoder = zstd::stream::read::Decoder::with_dictionary(BufReader::new(src), &dictionary)?.single_frame();
+            if let Some(limit) = memory_limit {
+                decoder.window_log_max(untrusted::zstd_window_log(limit))?;
+            }
+            Box::new(Bounded { decoder, source: |d| d.get_mut() })
+        },
+        CompressionType::Gzip => {
+            Box::new(Bounded { decoder: flate2::bufread::GzDecoder::new(BufReader::new(src)), source: |d| d.get_mut() })
+        },
+        CompressionType::Zlib => {
+            Box::new(Bounded { decoder: flate2::bufread::ZlibDecoder::new(BufReader::new(src)), source: |d| d.get_mut() })
+        },
+        CompressionType::Deflate => {
+            Box::new(Bounded { decoder: flate2::bufread::DeflateDecoder::new(BufReader::new(src)), source: |d| d.get_mut() })
+        },
+        CompressionType::Bzip2 => {
+            Box::new(Bounded { decoder: bzip2::bufread::BzDecoder::new(BufReader::new(src)), source: |d| d.get_mut() })
+        },
+        CompressionType::XZ => {
+            let stream = crate::xz_stream_decoder(&param_set, memory_limit)?;
+            Box::new(XzEnd { src: BufReader::new(src), stream, finished: false })
+        },
+        CompressionType::LZ4 => {
+            Box::new(Lz4End { decoder: Some(lz4::Decoder::new(src)?), rest: None })
+        },
+        CompressionType::Stored => {
+            let max_block_size = param_set.get_size("max_block_size", libstored::MAX_BLOCK_SIZE)?;
+            let decoder = libstored::StoredReader::with_max_block_size(src, max_block_size);
+            Box::new(Bounded { decoder, source: |d| d.get_mut() })
+        },
+        CompressionType::Snappy | CompressionType::Brotli | CompressionType::Lzo | CompressionType::None | CompressionType::Custom(_) => {
+            Box::new(Unbounded(build_decoder(src, compression_type, &param_set, true)?))
+        },
+    };
+    let drain = count_trailing || padding.is_some();
+    return Ok(StatusReader { decoder, hit_eof, status: StreamStatus::InProgress, padding, drain });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::compress_bytes;
+    use crate::describe::BUILTIN_CODECS;
+
+    fn read_all(compressed: Vec<u8>, ct: CompressionType) -> (std::io::Result<Vec<u8>>, StatusReader) {
+        let mut reader = decompressed_reader_status(Box::new(std::io::Cursor::new(compressed)), ct, "count_trailing=true").unwrap();
+        assert_eq!(reader.status(), StreamStatus::InProgress);
+        let mut out = Vec::new();
+        let result = reader.read_to_end(&mut out).map(|_| out);
+        return (result, reader);
+    }
+
+    #[test]
+    pub fn test_every_codec() {
+        let data: Vec<u8> = (0..50_000u32).map(|i| (i % 251) as u8 ^ (i / 700) as u8).collect();
+        for codec in BUILTIN_CODECS {
+            let ct = codec.compression_type;
+            let compressed = compress_bytes(&data, ct, "").unwrap();
+
+            let (result, mut reader) = read_all(compressed.clone(), ct);
+            assert_eq!(result.unwrap(), data, "{}", codec.name);
+            assert_eq!(reader.status(), StreamStatus::Complete, "{}", codec.name);
+            assert_eq!(reader.read(&mut [0u8; 16]).unwrap(), 0, "{}", codec.name);
+
+            if ct != CompressionType::None {
+                let (result, mut reader) = read_all(compressed[..compressed.len() - 4].to_vec(), ct);
+                assert_eq!(result.unwrap_err().kind(), ErrorKind::UnexpectedEof, "{}", codec.name);
+                assert_eq!(reader.status(), StreamStatus::Truncated, "{}", codec.name);
+                assert_eq!(reader.read(&mut [0u8; 16]).unwrap_err().kind(), ErrorKind::UnexpectedEof);
+            }
+
+            if ![CompressionType::None, CompressionType::Snappy, CompressionType::Brotli, CompressionType::Lzo].contains(&ct) {
+                let mut junk = compressed.clone();
+                junk.extend_from_slice(b"junk after the stream");
+                let (result, reader) = read_all(junk, ct);
+                assert_eq!(result.unwrap(), data, "{}", codec.name);
+                assert_eq!(reader.status(), StreamStatus::CompleteWithTrailing(21), "{}", codec.name);
+            }
+        }
+    }
+
+    /// Connection holding a stream, then more data that has not arrived: reading past what it
+    /// holds would block
+    struct Connection(std::io::Cursor<Vec<u8>>);
+
+    impl Read for Connection {
+        fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+            let read = self.0.read(buf)?;
+            assert!(read > 0 || buf.is_empty(), "read past the stream");
+            return Ok(read);
+        }
+    }
+
+    #[test]
+    pub fn test_no_read_past_the_stream() {
+        let data = b"one message of several on this connection\n".repeat(100);
+        for ct in [CompressionType::Gzip, CompressionType::Zstd, CompressionType::Bzip2, CompressionType::XZ, CompressionType::LZ4, CompressionType::Stored] {
+            let mut connection = compress_bytes(&data, ct, "").unwrap();
+            connection.extend_from_slice(b"next");
+            let mut reader = decompressed_reader_status(Box::new(Connection(std::io::Cursor::new(connection))), ct, "").unwrap();
+            let mut out = Vec::new();
+            reader.read_to_end(&mut out).unwrap();
+            assert!(out == data, "{}", ct);
+            assert_eq!(reader.status(), StreamStatus::Complete, "{}", ct);
+        }
+    }
+
+    #[test]
+    pub fn test_zero_padding() {
+        let data = b"restored from tape\n".repeat(3000);
+        let compressed = compress_bytes(&data, CompressionType::Gzip, "").unwrap();
+        let padded_len = compressed.len().next_multiple_of(512);
+        let padding = (padded_len - compressed.len()) as u64;
+        let mut padded = compressed.clone();
+        padded.resize(padded_len, 0);
+        let mut dirty = padded.clone();
+        dirty[padded_len - 3] = 1;
+        let short = padded[..padded_len - 1].to_vec();
+
+        let read_with = |content: &Vec<u8>| {
+            let mut plain = Vec::new();
+            let mut reader = crate::decompressed_reader_with(Box::new(std::io::Cursor::new(content.clone())), CompressionType::Gzip, "padding=zeros:512").unwrap();
+            return reader.read_to_end(&mut plain).map(|_| plain);
+        };
+        let status_of = |content: &Vec<u8>| {
+            let mut reader = decompressed_reader_status(Box::new(std::io::Cursor::new(content.clone())), CompressionType::Gzip, "padding=zeros:512").unwrap();
+            assert!(reader.read_to_end(&mut Vec::new()).unwrap() == data.len());
+            return reader.status();
+        };
+        assert!(read_with(&padded).unwrap() == data);
+        assert_eq!(status_of(&padded), StreamStatus::CompletePadded(padding));
+        assert!(read_with(&compressed).unwrap() == data);
+        assert_eq!(status_of(&compressed), StreamStatus::Complete);
+        for rejected in [&dirty, &short] {
+            assert_eq!(read_with(rejected).unwrap_err().kind(), ErrorKind::InvalidData);
+        }
+        assert_eq!(status_of(&dirty), StreamStatus::CompleteWithTrailing(padding));
+        assert_eq!(status_of(&short), StreamStatus::CompleteWithTrailing(padding - 1));
+
+        // without padding, zeros are trailing data like any other
+        let mut reader = crate::decompressed_reader_with(Box::new(std::io::Cursor::new(padded)), CompressionType::Gzip, "trailing_data=error").unwrap();
+        assert_eq!(reader.read_to_end(&mut Vec::new()).unwrap_err().kind(), ErrorKind::InvalidData);
+        for invalid in ["padding=zeros:0", "padding=ones:512", "padding=zeros"] {
+            assert!(crate::decompressed_reader_with(Box::new(std::io::empty()), CompressionType::Gzip, invalid).is_err(), "{}", invalid);
+        }
+    }
+}
@@ -0,0 +1,74 @@
+use std::fmt;
+use std::io::{Read, Write};
+use std::sync::Arc;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::Duration;
+
+/// Byte counts and duration of a finished compression operation
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct OperationSummary {
+    /// Uncompressed bytes consumed
+    pub bytes_in: u64,
+    /// Compressed bytes produced
+    pub bytes_out: u64,
+    /// Wall time spent on the operation
+    pub elapsed: Duration,
+}
+
+/// `1.23 GiB → 310.4 MiB, 4.02x, 215 MB/s`, see `crate::fmt`
+impl fmt::Display for OperationSummary {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} → {}, {}, {}",
+            crate::fmt::format_bytes(self.bytes_in),
+            crate::fmt::format_bytes(self.bytes_out),
+            crate::fmt::format_ratio(self.bytes_in, self.bytes_out),
+            crate::fmt::format_rate(self.bytes_in, self.elapsed))
+    }
+}
+
+/// Sink adapter counting the bytes written through it into a shared counter, which stays
+/// readable after the compressor owning the adapter is dropped.
+pub(crate) struct CountingWriter {
+    inner: Box<dyn Write>,
+    count: Arc<AtomicU64>,
+}
+
+impl CountingWriter {
+    pub(crate) fn new(inner: Box<dyn Write>) -> (CountingWriter, Arc<AtomicU64>) {
+        let count = Arc::new(AtomicU64::new(0));
+        return (CountingWriter { inner, count: count.clone() }, count);
+    }
+}
+
+/// Source adapter counting the bytes read through it, the `CountingWriter` of decoders
+pub(crate) struct CountingReader<R> {
+    inner: R,
+    count: Arc<AtomicU64>,
+}
+
+impl<R: Read> CountingReader<R> {
+    pub(crate) fn new(inner: R) -> (CountingReader<R>, Arc<AtomicU64>) {
+        let count = Arc::new(AtomicU64::new(0));
+        return (CountingReader { inner, count: count.clone() }, count);
+    }
+}
+
+impl<R: Read> Read for CountingReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        let read = self.inner.read(buf)?;
+        self.count.fetch_add(read as u64, Ordering::Relaxed);
+        return Ok(read);
+    }
+}
+
+impl Write for CountingWriter {
+    fn write(&mut self, data: &[u8]) -> std::io::Result<usize> {
+        let written = self.inner.write(data)?;
+        self.count.fetch_add(written as u64, Ordering::Relaxed);
+        return Ok(written);
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        return self.inner.flush();
+    }
+}
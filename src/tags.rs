@@ -0,0 +1,231 @@
+use std::error::Error;
+use std::io::{Read, Write};
+use crate::{CompressionType, ParamSet};
+
+/// Prefix of the parameters holding tags, followed by the tag key
+const TAG_PREFIX: &str = "tag.";
+/// Subfield id of the tags in the gzip extra field
+const GZIP_SUBFIELD: [u8; 2] = *b"FC";
+/// Magic number of the zstd skippable frame holding the tags
+const ZSTD_SKIPPABLE_MAGIC: u32 = 0x184D2A5F;
+/// Start of the payload of that frame, telling it from skippable frames of other tools
+const ZSTD_TAG_ID: [u8; 4] = *b"FCTG";
+
+/// A tag key and its value
+pub type Tag = (String, Vec<u8>);
+
+/// Codecs whose writers can embed tags and whose files `read_tags` reads them from
+pub const TAG_CODECS: &[CompressionType] = &[CompressionType::Gzip, CompressionType::Zstd];
+
+/// Whether `compression_type` has a metadata channel for tags:
+/// - gzip: a subfield of the header extra field
+/// - zstd: a skippable frame before the first frame
+///
+/// Other formats have none that standard tools accept, and writers given tags fail.
+pub fn tags_supported(compression_type: CompressionType) -> bool {
+    return TAG_CODECS.contains(&compression_type);
+}
+
+/// Add the tag `key` = `value` to the writer parameters `option`. Tags survive in the file
+/// headers, are ignored by standard decoders like gunzip and zstd, and are read back with
+/// `read_tags`. They are stored as the `tag.<key>` parameter holding the value in hex.
+pub fn set_tag<T: Into<ParamSet>>(option: T, key: &str, value: &[u8]) -> ParamSet {
+    let mut param_set: ParamSet = option.into();
+    let hex: String = value.iter().map(|b| format!("{:02x}", b)).collect();
+    param_set.map.insert(format!("{}{}", TAG_PREFIX, key), hex);
+    return param_set;
+}
+
+/// Tags set in `param_set`, sorted by key
+fn tags(param_set: &ParamSet) -> Result<Vec<Tag>, Box<dyn Error>> {
+    let mut tags = Vec::new();
+    for (name, hex) in &param_set.map {
+        let Some(key) = name.strip_prefix(TAG_PREFIX) else {
+            continue;
+        };
+        #[cfg(test)]
+        crate::describe::record_read(name);
+        if key.is_empty() || key.len() > u8::MAX as usize {
+            return Err(format!("tag key `{}` must be 1 to 255 bytes long", key).into());
+        }
+        let value = (0..hex.len()).step_by(2)
+            .map(|i| hex.get(i..i + 2).and_then(|digits| u8::from_str_radix(digits, 16).ok()))
+            .collect::<Option<Vec<u8>>>()
+            .ok_or_else(|| format!("tag `{}` must be hex digits", key))?;
+        tags.push((key.to_string(), value));
+    }
+    tags.sort();
+    return Ok(tags);
+}
+
+/// Tags as a byte length prefixed key, a 4 byte little endian length and the value each
+fn serialize(tags: &[Tag]) -> Vec<u8> {
+    let mut out = Vec::new();
+    for (key, value) in tags {
+        out.push(key.len() as u8);
+        out.extend_from_slice(key.as_bytes());
+        out.extend_from_slice(&(value.len() as u32).to_le_bytes());
+        out.extend_from_slice(value);
+    }
+    return out;
+}
+
+fn deserialize(mut data: &[u8]) -> Result<Vec<Tag>, Box<dyn Error>> {
+    let mut tags = Vec::new();
+    while let Some((&key_length, rest)) = data.split_first() {
+        let key = rest.get(..key_length as usize).ok_or("truncated tag key")?;
+        let rest = &rest[key_length as usize..];
+        let length = rest.get(..4).ok_or("truncated tag length")?;
+        let length = u32::from_le_bytes(length.try_into().unwrap()) as usize;
+        let value = rest.get(4..4 + length).ok_or("truncated tag value")?;
+        tags.push((String::from_utf8(key.to_vec())?, value.to_vec()));
+        data = &rest[4 + length..];
+    }
+    return Ok(tags);
+}
+
+/// Fail if tags are set for a codec that cannot carry them
+pub(crate) fn check_supported(compression_type: CompressionType, param_set: &ParamSet) -> Result<(), Box<dyn Error>> {
+    if !param_set.map.keys().any(|k| k.starts_with(TAG_PREFIX)) {
+        return Ok(());
+    }
+    if !tags_supported(compression_type) {
+        return Err(format!("{:?} cannot carry tags, only {:?} can", compression_type, TAG_CODECS).into());
+    }
+    if crate::minimal::is_minimal(param_set)? {
+        return Err("minimal_overhead streams have no header to carry tags".into());
+    }
+    return Ok(());
+}
+
+/// gzip header extra field holding the tags of `param_set`, if any
+pub(crate) fn gzip_extra(param_set: &ParamSet) -> Result<Option<Vec<u8>>, Box<dyn Error>> {
+    let tags = tags(param_set)?;
+    if tags.is_empty() {
+        return Ok(None);
+    }
+    let payload = serialize(&tags);
+    // the whole extra field, subfield header included, is limited to 65535 bytes
+    let length = u16::try_from(payload.len() + 4).map_err(|_| "tags exceed the 65531 bytes of a gzip header")?;
+    let mut extra = GZIP_SUBFIELD.to_vec();
+    extra.extend_from_slice(&(length - 4).to_le_bytes());
+    extra.extend_from_slice(&payload);
+    return Ok(Some(extra));
+}
+
+/// Write the tags of `param_set`, if any, as a zstd skippable frame
+pub(crate) fn write_zstd_tags(out: &mut dyn Write, param_set: &ParamSet) -> Result<(), Box<dyn Error>> {
+    let tags = tags(param_set)?;
+    if tags.is_empty() {
+        return Ok(());
+    }
+    let mut payload = ZSTD_TAG_ID.to_vec();
+    payload.extend_from_slice(&serialize(&tags));
+    out.write_all(&ZSTD_SKIPPABLE_MAGIC.to_le_bytes())?;
+    out.write_all(&(payload.len() as u32).to_le_bytes())?;
+    out.write_all(&payload)?;
+    return Ok(());
+}
+
+/// Tags embedded in the header of the compressed stream `src`, in key order. Only the start of
+/// the stream is read. Files without tags have none; codecs outside `TAG_CODECS` are an error.
+pub fn read_tags(mut src: Box<dyn Read>, compression_type: CompressionType) -> Result<Vec<Tag>, Box<dyn Error>> {
+    match compression_type {
+        CompressionType::Gzip => {
+            let mut header = [0u8; 10];
+            src.read_exact(&mut header)?;
+            if header[..2] != [0x1f, 0x8b] {
+                return Err("not a gzip stream".into());
+            }
+            // FEXTRA, the only optional field before the extra field itself
+            if header[3] & 0x04 == 0 {
+                return Ok(Vec::new());
+            }
+            let mut length = [0u8; 2];
+            src.read_exact(&mut length)?;
+            let mut extra = vec![0u8; u16::from_le_bytes(length) as usize];
+            src.read_exact(&mut extra)?;
+            let mut subfields = &extra[..];
+            while subfields.len() >= 4 {
+                let length = u16::from_le_bytes([subfields[2], subfields[3]]) as usize;
+                let data = subfields.get(4..4 + length).ok_or("truncated gzip extra field")?;
+                if subfields[..2] == GZIP_SUBFIELD {
+                    return deserialize(data);
+                }
+                subfields = &subfields[4 + length..];
+            }
+            return Ok(Vec::new());
+        },
+        CompressionType::Zstd => {
+            let mut header = [0u8; 8];
+            loop {
+                src.read_exact(&mut header[..4])?;
+                let magic = u32::from_le_bytes(header[..4].try_into().unwrap());
+                if magic & 0xFFFFFFF0 != 0x184D2A50 {
+                    return Ok(Vec::new());
+                }
+                src.read_exact(&mut header[4..])?;
+                let mut payload = vec![0u8; u32::from_le_bytes(header[4..].try_into().unwrap()) as usize];
+                src.read_exact(&mut payload)?;
+                if magic == ZSTD_SKIPPABLE_MAGIC && payload.starts_with(&ZSTD_TAG_ID) {
+                    return deserialize(&payload[ZSTD_TAG_ID.len()..]);
+                }
+            }
+        },
+        _ => return Err(format!("{:?} cannot carry tags, only {:?} can", compression_type, TAG_CODECS).into()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{compressed_writer, decompressed_reader};
+
+    fn tagged(ct: CompressionType, data: &[u8]) -> Vec<u8> {
+        let params = set_tag(set_tag("level=5", "build", b"2026.10.16-r42"), "bin", &[0, 255, 10]);
+        let buffer = crate::buffer::SharedBuffer::default();
+        let mut w = compressed_writer(Box::new(buffer.clone()), ct, params).unwrap();
+        w.write_all(data).unwrap();
+        drop(w);
+        return buffer.take();
+    }
+
+    /// Decompress with the command line tool, if installed
+    fn decode_with_cli(tool: &str, compressed: &[u8]) -> Option<Vec<u8>> {
+        let mut child = std::process::Command::new(tool).arg("-dc")
+            .stdin(std::process::Stdio::piped()).stdout(std::process::Stdio::piped()).spawn().ok()?;
+        child.stdin.take().unwrap().write_all(compressed).unwrap();
+        let output = child.wait_with_output().unwrap();
+        assert!(output.status.success(), "{}", tool);
+        return Some(output.stdout);
+    }
+
+    #[test]
+    pub fn test_tags_round_trip() {
+        let data = b"tagged artifact ".repeat(1000);
+        for (ct, tool) in [(CompressionType::Gzip, "gzip"), (CompressionType::Zstd, "zstd")] {
+            let compressed = tagged(ct, &data);
+            let tags = read_tags(Box::new(std::io::Cursor::new(compressed.clone())), ct).unwrap();
+            assert_eq!(tags, vec![("bin".to_string(), vec![0, 255, 10]), ("build".to_string(), b"2026.10.16-r42".to_vec())]);
+            let mut out = Vec::new();
+            decompressed_reader(Box::new(std::io::Cursor::new(compressed.clone())), ct).unwrap().read_to_end(&mut out).unwrap();
+            assert_eq!(out, data);
+            if let Some(out) = decode_with_cli(tool, &compressed) {
+                assert_eq!(out, data, "{}", tool);
+            }
+        }
+    }
+
+    #[test]
+    pub fn test_untagged_and_unsupported() {
+        let buffer = crate::buffer::SharedBuffer::default();
+        drop(compressed_writer(Box::new(buffer.clone()), CompressionType::Zstd, "").unwrap());
+        assert!(read_tags(Box::new(std::io::Cursor::new(buffer.take())), CompressionType::Zstd).unwrap().is_empty());
+
+        assert!(!tags_supported(CompressionType::LZ4));
+        let err = compressed_writer(Box::new(std::io::sink()), CompressionType::LZ4, set_tag("", "build", b"1")).err().unwrap();
+        assert!(err.to_string().contains("cannot carry tags"), "{}", err);
+        assert!(read_tags(Box::new(std::io::empty()), CompressionType::LZ4).is_err());
+        assert!(compressed_writer(Box::new(std::io::sink()), CompressionType::Gzip, set_tag("minimal_overhead=true", "build", b"1")).is_err());
+    }
+}
@@ -0,0 +1,228 @@
+use std::collections::VecDeque;
+use std::io::{Read, Seek, SeekFrom};
+use std::error::Error;
+use crate::indexed::SharedCompressedFile;
+use crate::{decompressed_reader, CompressionType};
+
+const READ_BUFFER_SIZE: usize = 64 * 1024;
+/// Most bytes or lines reserved up front, the rest as the stream provides them
+const MAX_RESERVED: usize = 64 * 1024;
+
+/// Return the last `n` bytes of the decompressed stream, like `tail -c`.
+///
+/// The whole stream is decompressed, but only the last `n` bytes are retained, so memory usage
+/// stays O(n) (plus a fixed read buffer) regardless of the stream size.
+pub fn read_tail(src: Box<dyn Read>, compression_type: CompressionType, n: usize) -> Result<Vec<u8>, Box<dyn Error>> {
+    let mut reader = decompressed_reader(src, compression_type)?;
+    let mut ring = VecDeque::<u8>::with_capacity(n.min(MAX_RESERVED));
+    let mut buffer = vec![0u8; READ_BUFFER_SIZE];
+    loop {
+        let read = reader.read(&mut buffer)?;
+        if read == 0 {
+            break;
+        }
+        let chunk = &buffer[..read];
+        if chunk.len() >= n {
+            ring.clear();
+            ring.extend(&chunk[chunk.len() - n..]);
+        } else {
+            let overflow = (ring.len() + chunk.len()).saturating_sub(n);
+            ring.drain(..overflow);
+            ring.extend(chunk);
+        }
+    }
+    return Ok(ring.into());
+}
+
+/// Return the last `k` lines of the decompressed stream, like `tail -n`.
+///
+/// Lines are split on `\n` and returned without the line terminator (a trailing `\r` is kept).
+/// A final line without terminator counts as a line. Invalid UTF-8 is replaced lossily.
+/// Only the last `k` lines and the line being read are retained, so memory is bounded by those,
+/// however long the stream: one huge line still takes its whole length. With `k` 0 the stream
+/// is decompressed, to report its errors, and nothing is retained.
+pub fn tail_lines(src: Box<dyn Read>, compression_type: CompressionType, k: usize) -> Result<Vec<String>, Box<dyn Error>> {
+    let mut reader = decompressed_reader(src, compression_type)?;
+    return Ok(last_lines(&mut reader, k)?.0);
+}
+
+/// `read_tail` of an indexed gzip or zstd file, decoding only the members or frames holding the
+/// last `n` bytes
+pub fn read_tail_indexed(file: &SharedCompressedFile, n: usize) -> Result<Vec<u8>, Box<dyn Error>> {
+    let start = file.size().saturating_sub(n as u64);
+    return Ok(file.read_range(start, file.size() - start)?);
+}
+
+/// `tail_lines` of an indexed gzip or zstd file, decoding from the member or frame that holds the
+/// last `k` lines instead of the whole file: members are taken from the end, twice as many each
+/// time, until the lines they hold are enough.
+pub fn tail_lines_indexed(file: &SharedCompressedFile, k: usize) -> Result<Vec<String>, Box<dyn Error>> {
+    if k == 0 {
+        return Ok(Vec::new());
+    }
+    let index = file.index();
+    let mut members = 1;
+    loop {
+        let first = index.len().saturating_sub(members);
+        let start = index.get(first).map(|entry| entry.uncompressed_offset).unwrap_or(0);
+        let mut reader = file.reader()?;
+        reader.seek(SeekFrom::Start(start))?;
+        let (lines, seen) = last_lines(&mut reader, k)?;
+        // the first line found may have started in an earlier member
+        if first == 0 || seen > k as u64 {
+            return Ok(lines);
+        }
+        members = members.saturating_mul(2);
+    }
+}
+
+/// The last `k` lines of `reader` and the number of lines it held, none kept nor counted for
+/// `k` 0
+fn last_lines(reader: &mut dyn Read, k: usize) -> Result<(Vec<String>, u64), Box<dyn Error>> {
+    if k == 0 {
+        std::io::copy(reader, &mut std::io::sink())?;
+        return Ok((Vec::new(), 0));
+    }
+    let mut lines = VecDeque::<Vec<u8>>::with_capacity(k.saturating_add(1).min(MAX_RESERVED));
+    let mut current = Vec::<u8>::new();
+    let mut seen = 0u64;
+    let mut buffer = vec![0u8; READ_BUFFER_SIZE];
+    loop {
+        let read = reader.read(&mut buffer)?;
+        if read == 0 {
+            break;
+        }
+        for segment in buffer[..read].split_inclusive(|b| *b == b'\n') {
+            if let Some((b'\n', content)) = segment.split_last() {
+                current.extend_from_slice(content);
+                lines.push_back(std::mem::take(&mut current));
+                seen += 1;
+                if lines.len() > k {
+                    lines.pop_front();
+                }
+            } else {
+                current.extend_from_slice(segment);
+            }
+        }
+    }
+    if !current.is_empty() {
+        lines.push_back(current);
+        seen += 1;
+        if lines.len() > k {
+            lines.pop_front();
+        }
+    }
+    return Ok((lines.iter().map(|line| String::from_utf8_lossy(line).into_owned()).collect(), seen));
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Write;
+    use crate::compressed_writer;
+
+    fn fixture(name: &str, ct: CompressionType, data: &[u8]) -> std::path::PathBuf {
+        return fixture_with(name, ct, "", data);
+    }
+
+    fn fixture_with(name: &str, ct: CompressionType, option: &str, data: &[u8]) -> std::path::PathBuf {
+        let file_name = std::env::temp_dir().join(format!("final_compression.{}.{}", std::process::id(), name));
+        let out = std::fs::File::create(&file_name).unwrap();
+        let mut w = compressed_writer(Box::new(out), ct, option).unwrap();
+        w.write_all(data).unwrap();
+        drop(w);
+        return file_name;
+    }
+
+    fn lines_data() -> Vec<u8> {
+        let mut data = Vec::new();
+        for i in 0..200_000 {
+            writeln!(data, "line number {}", i).unwrap();
+        }
+        return data;
+    }
+
+    #[test]
+    pub fn test_read_tail() {
+        let data = lines_data();
+        let file_name = fixture("tail.gz", CompressionType::Gzip, &data);
+        for n in [0usize, 1, 100, 70_000, data.len(), data.len() + 10] {
+            let input = std::fs::File::open(&file_name).unwrap();
+            let tail = read_tail(Box::new(input), CompressionType::Gzip, n).unwrap();
+            let start = data.len().saturating_sub(n);
+            assert_eq!(tail, &data[start..]);
+        }
+        let input = std::fs::File::open(&file_name).unwrap();
+        assert_eq!(read_tail(Box::new(input), CompressionType::Gzip, usize::MAX).unwrap(), data);
+        let _ = std::fs::remove_file(&file_name);
+    }
+
+    #[test]
+    pub fn test_tail_lines() {
+        let data = lines_data();
+        let file_name = fixture("tail.zst", CompressionType::Zstd, &data);
+        let input = std::fs::File::open(&file_name).unwrap();
+        let lines = tail_lines(Box::new(input), CompressionType::Zstd, 3).unwrap();
+        assert_eq!(lines, vec!["line number 199997", "line number 199998", "line number 199999"]);
+        let _ = std::fs::remove_file(&file_name);
+
+        let file_name = fixture("tail_partial.zst", CompressionType::Zstd, b"a\nb\nno newline");
+        let input = std::fs::File::open(&file_name).unwrap();
+        let lines = tail_lines(Box::new(input), CompressionType::Zstd, 2).unwrap();
+        assert_eq!(lines, vec!["b", "no newline"]);
+        let input = std::fs::File::open(&file_name).unwrap();
+        assert_eq!(tail_lines(Box::new(input), CompressionType::Zstd, usize::MAX).unwrap(), vec!["a", "b", "no newline"]);
+        let input = std::fs::File::open(&file_name).unwrap();
+        assert!(tail_lines(Box::new(input), CompressionType::Zstd, 0).unwrap().is_empty());
+        let _ = std::fs::remove_file(&file_name);
+    }
+
+    #[test]
+    pub fn test_tail_indexed() {
+        let data = lines_data();
+        let expected = |k: usize| -> Vec<String> {
+            let text = String::from_utf8(data.clone()).unwrap();
+            let lines: Vec<String> = text.lines().map(String::from).collect();
+            return lines[lines.len().saturating_sub(k)..].to_vec();
+        };
+        for (name, ct, option) in [("tail_indexed.gz", CompressionType::Gzip, "member_max_uncompressed=32KiB"),
+            ("tail_indexed.zst", CompressionType::Zstd, "")] {
+            let file_name = fixture_with(name, ct, option, &data);
+            let file = SharedCompressedFile::open(&file_name, ct).unwrap();
+            for k in [0, 1, 3, 5000, 199_999, 200_000, usize::MAX] {
+                assert_eq!(tail_lines_indexed(&file, k).unwrap(), expected(k), "{} {}", name, k);
+            }
+            for n in [0usize, 100, 70_000, data.len(), data.len() + 10] {
+                assert_eq!(read_tail_indexed(&file, n).unwrap(), &data[data.len().saturating_sub(n)..]);
+            }
+            let _ = std::fs::remove_file(&file_name);
+        }
+
+        // a line spanning members is whole
+        let long = [b"x".repeat(100_000), b"\nlast\n".to_vec()].concat();
+        let file_name = fixture_with("tail_long_line.gz", CompressionType::Gzip, "member_max_uncompressed=16KiB", &long);
+        let file = SharedCompressedFile::open(&file_name, CompressionType::Gzip).unwrap();
+        assert!(file.index().len() > 5);
+        assert_eq!(tail_lines_indexed(&file, 2).unwrap(), vec!["x".repeat(100_000), "last".to_string()]);
+        let _ = std::fs::remove_file(&file_name);
+    }
+
+    #[test]
+    #[cfg(feature = "alloc-track")]
+    pub fn test_tail_memory_bounded() {
+        let mut data = Vec::new();
+        for i in 0..2_000_000 {
+            writeln!(data, "line number {}", i).unwrap();
+        }
+        let compressed = crate::compress_bytes(&data, CompressionType::Gzip, "").unwrap();
+        // the sources are built before tracking starts
+        let src: Box<dyn Read> = Box::new(std::io::Cursor::new(compressed.clone()));
+        let (tail, peak) = crate::memory::peak_during(|| read_tail(src, CompressionType::Gzip, 1000).unwrap());
+        assert!(tail == data[data.len() - 1000..]);
+        assert!(peak < 1 << 20, "{} bytes for a {} byte stream", peak, data.len());
+        let src: Box<dyn Read> = Box::new(std::io::Cursor::new(compressed));
+        let (lines, peak) = crate::memory::peak_during(|| tail_lines(src, CompressionType::Gzip, 100).unwrap());
+        assert_eq!(lines.last().unwrap(), "line number 1999999");
+        assert!(peak < 1 << 20, "{} bytes for a {} byte stream", peak, data.len());
+    }
+}
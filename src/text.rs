@@ -0,0 +1,279 @@
+use std::error::Error;
+use std::io::{ErrorKind, Read, Write};
+use crate::ParamSet;
+
+/// Text contract enforced on the uncompressed side of a stream.
+///
+/// Configured with the `text_mode` parameter of `compressed_writer` and `decompressed_reader_with`:
+/// - unset: bytes pass through unchecked
+/// - `text_mode=utf8`: the data must be valid UTF-8; the first invalid sequence fails the write
+///   or read with `ErrorKind::InvalidData`, naming its byte offset in the uncompressed stream
+/// - `text_mode=utf8-lf`: as `utf8`, and every CRLF is replaced by LF. Lone CRs are kept.
+///
+/// Sequences split across `write` or `read` calls are handled. A stream ending in the middle of a
+/// sequence is reported by the reader; the writer can only report it on `flush`, as it finishes on
+/// drop.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TextMode {
+    Binary,
+    Utf8,
+    Utf8Lf,
+}
+
+impl TextMode {
+    pub(crate) fn from_params(param_set: &ParamSet) -> Result<TextMode, Box<dyn Error>> {
+        match param_set.get_string("text_mode", "") {
+            "" => return Ok(TextMode::Binary),
+            "utf8" => return Ok(TextMode::Utf8),
+            "utf8-lf" => return Ok(TextMode::Utf8Lf),
+            other => return Err(format!("Invalid text_mode `{}`, expected utf8 or utf8-lf", other).into()),
+        }
+    }
+}
+
+/// Incremental UTF-8 validation carrying incomplete sequences between chunks
+#[derive(Default)]
+struct Utf8Validator {
+    /// Offset of the first byte of the next chunk
+    offset: u64,
+    carry: [u8; 4],
+    carry_len: usize,
+}
+
+fn invalid_at(offset: u64) -> std::io::Error {
+    return std::io::Error::new(ErrorKind::InvalidData, format!("invalid UTF-8 at byte offset {}", offset));
+}
+
+impl Utf8Validator {
+    fn feed(&mut self, mut data: &[u8]) -> Result<(), std::io::Error> {
+        // complete the sequence left over from the previous chunk
+        while self.carry_len > 0 && !data.is_empty() {
+            self.carry[self.carry_len] = data[0];
+            self.carry_len += 1;
+            data = &data[1..];
+            self.offset += 1;
+            match std::str::from_utf8(&self.carry[..self.carry_len]) {
+                Ok(_) => self.carry_len = 0,
+                Err(e) if e.error_len().is_some() => return Err(invalid_at(self.offset - self.carry_len as u64)),
+                Err(_) => {},
+            }
+        }
+        if let Err(e) = std::str::from_utf8(data) {
+            let valid = e.valid_up_to();
+            if e.error_len().is_some() {
+                return Err(invalid_at(self.offset + valid as u64));
+            }
+            self.carry_len = data.len() - valid;
+            self.carry[..self.carry_len].copy_from_slice(&data[valid..]);
+        }
+        self.offset += data.len() as u64;
+        return Ok(());
+    }
+
+    /// Fails if the data seen so far ends inside a sequence
+    fn check_complete(&self) -> Result<(), std::io::Error> {
+        if self.carry_len > 0 {
+            return Err(invalid_at(self.offset - self.carry_len as u64));
+        }
+        return Ok(());
+    }
+}
+
+/// Append `data` to `out` with CRLF replaced by LF. A trailing CR is held back in `pending_cr`
+/// until the next chunk shows whether an LF follows.
+fn normalize_crlf(data: &[u8], pending_cr: &mut bool, out: &mut Vec<u8>) {
+    for &byte in data {
+        if *pending_cr {
+            *pending_cr = false;
+            if byte != b'\n' {
+                out.push(b'\r');
+            }
+        }
+        if byte == b'\r' {
+            *pending_cr = true;
+        } else {
+            out.push(byte);
+        }
+    }
+}
+
+/// Writer side of `TextMode`, placed before the encoder
+pub(crate) struct TextWriter {
+    inner: Box<dyn Write>,
+    mode: TextMode,
+    validator: Utf8Validator,
+    pending_cr: bool,
+    scratch: Vec<u8>,
+}
+
+pub(crate) fn text_writer(inner: Box<dyn Write>, mode: TextMode) -> Box<dyn Write> {
+    if mode == TextMode::Binary {
+        return inner;
+    }
+    return Box::new(TextWriter { inner, mode, validator: Utf8Validator::default(), pending_cr: false, scratch: Vec::new() });
+}
+
+impl Write for TextWriter {
+    fn write(&mut self, data: &[u8]) -> std::io::Result<usize> {
+        self.validator.feed(data)?;
+        if self.mode == TextMode::Utf8 {
+            self.inner.write_all(data)?;
+            return Ok(data.len());
+        }
+        self.scratch.clear();
+        normalize_crlf(data, &mut self.pending_cr, &mut self.scratch);
+        self.inner.write_all(&self.scratch)?;
+        return Ok(data.len());
+    }
+
+    /// A CR at the end of the data written so far stays held back, since an LF may follow.
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.validator.check_complete()?;
+        return self.inner.flush();
+    }
+}
+
+impl Drop for TextWriter {
+    fn drop(&mut self) {
+        if self.pending_cr && !std::thread::panicking() {
+            let _ = self.inner.write_all(b"\r");
+        }
+    }
+}
+
+/// Reader side of `TextMode`, placed after the decoder
+pub(crate) struct TextReader {
+    inner: Box<dyn Read>,
+    mode: TextMode,
+    validator: Utf8Validator,
+    pending_cr: bool,
+    scratch: Vec<u8>,
+    out: Vec<u8>,
+    pos: usize,
+}
+
+pub(crate) fn text_reader(inner: Box<dyn Read>, mode: TextMode) -> Box<dyn Read> {
+    if mode == TextMode::Binary {
+        return inner;
+    }
+    return Box::new(TextReader {
+        inner,
+        mode,
+        validator: Utf8Validator::default(),
+        pending_cr: false,
+        scratch: Vec::new(),
+        out: Vec::new(),
+        pos: 0,
+    });
+}
+
+impl Read for TextReader {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        if buf.is_empty() {
+            return Ok(0);
+        }
+        if self.mode == TextMode::Utf8 {
+            let read = self.inner.read(buf)?;
+            if read == 0 {
+                self.validator.check_complete()?;
+            }
+            self.validator.feed(&buf[..read])?;
+            return Ok(read);
+        }
+        while self.pos == self.out.len() {
+            self.scratch.resize(buf.len().max(4096), 0);
+            let read = self.inner.read(&mut self.scratch)?;
+            self.out.clear();
+            self.pos = 0;
+            if read == 0 {
+                self.validator.check_complete()?;
+                if !self.pending_cr {
+                    return Ok(0);
+                }
+                self.pending_cr = false;
+                self.out.push(b'\r');
+                break;
+            }
+            self.validator.feed(&self.scratch[..read])?;
+            normalize_crlf(&self.scratch[..read], &mut self.pending_cr, &mut self.out);
+        }
+        let n = buf.len().min(self.out.len() - self.pos);
+        buf[..n].copy_from_slice(&self.out[self.pos..self.pos + n]);
+        self.pos += n;
+        return Ok(n);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{compressed_writer, decompressed_reader_with, CompressionType};
+
+    fn write_chunked(data: &[u8], chunk: usize, options: &str) -> (std::io::Result<()>, Vec<u8>) {
+        let file_name = std::env::temp_dir().join(format!("final_compression.text.{}.{}.{}", std::process::id(), data.len(), chunk));
+        let out = std::fs::File::create(&file_name).unwrap();
+        let mut w = compressed_writer(Box::new(out), CompressionType::Gzip, options).unwrap();
+        let result = data.chunks(chunk).try_for_each(|c| w.write_all(c)).and_then(|_| w.flush());
+        drop(w);
+        let compressed = std::fs::read(&file_name).unwrap();
+        let _ = std::fs::remove_file(&file_name);
+        return (result, compressed);
+    }
+
+    fn read_chunked(compressed: Vec<u8>, chunk: usize, options: &str) -> std::io::Result<Vec<u8>> {
+        let src = Box::new(std::io::Cursor::new(compressed));
+        let mut r = decompressed_reader_with(src, CompressionType::Gzip, options).unwrap();
+        let mut out = Vec::new();
+        let mut buf = vec![0u8; chunk];
+        loop {
+            let read = r.read(&mut buf)?;
+            if read == 0 {
+                return Ok(out);
+            }
+            out.extend_from_slice(&buf[..read]);
+        }
+    }
+
+    #[test]
+    pub fn test_split_sequences() {
+        let text = "añ€😀 ".repeat(1000);
+        // chunk sizes splitting 2, 3 and 4 byte sequences at every position
+        for chunk in 1..=7 {
+            let (result, compressed) = write_chunked(text.as_bytes(), chunk, "text_mode=utf8");
+            result.unwrap();
+            assert_eq!(read_chunked(compressed, chunk, "text_mode=utf8").unwrap(), text.as_bytes());
+        }
+    }
+
+    #[test]
+    pub fn test_invalid_offset() {
+        let mut data = "héllo wörld\n".repeat(100).into_bytes();
+        data[1000] = 0x80;
+        let (result, _) = write_chunked(&data, 7, "text_mode=utf8");
+        let err = result.unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::InvalidData);
+        assert!(err.to_string().contains("offset 1000"), "{}", err);
+
+        let (result, compressed) = write_chunked(&data, 7, "");
+        result.unwrap();
+        let err = read_chunked(compressed, 5, "text_mode=utf8").unwrap_err();
+        assert!(err.to_string().contains("offset 1000"), "{}", err);
+
+        let (_, truncated) = write_chunked("ab€".as_bytes()[..4].as_ref(), 10, "");
+        let err = read_chunked(truncated, 10, "text_mode=utf8").unwrap_err();
+        assert!(err.to_string().contains("offset 2"), "{}", err);
+    }
+
+    #[test]
+    pub fn test_crlf_normalization() {
+        let data = b"one\r\ntwo\rthree\r\n\r\nfour\r";
+        for chunk in 1..=5 {
+            let (result, compressed) = write_chunked(data, chunk, "text_mode=utf8-lf");
+            result.unwrap();
+            assert_eq!(read_chunked(compressed, chunk, "").unwrap(), b"one\ntwo\rthree\n\nfour\r");
+            let (_, raw) = write_chunked(data, chunk, "");
+            assert_eq!(read_chunked(raw, chunk, "text_mode=utf8-lf").unwrap(), b"one\ntwo\rthree\n\nfour\r");
+        }
+        assert!(TextMode::from_params(&"text_mode=latin1".into()).is_err());
+    }
+}
@@ -0,0 +1,384 @@
+use std::error::Error;
+use std::fs::File;
+use std::io::{Read, Write};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::Ordering;
+use std::time::{Duration, SystemTime};
+use crate::cancel::CancelToken;
+use crate::commit::{self, Transaction};
+use crate::describe::json_string;
+use crate::preflight::{Preflight, SpaceReport};
+use crate::progress::{copy_with_progress, ProgressTracker};
+use crate::summary::CountingWriter;
+use crate::{compressed_writer, decompressed_reader, describe, CompressionType, ParamSet};
+
+/// Name of the manifest `compress_tree` writes in the destination directory
+pub const MANIFEST_FILE: &str = "manifest.json";
+
+/// What a manifest entry is
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum EntryKind {
+    /// A regular file, compressed to `ManifestEntry::compressed_path`
+    File,
+    /// A symbolic link to `target`, recorded but neither followed nor compressed
+    Symlink { target: String },
+}
+
+/// A file or symbolic link of a compressed tree
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ManifestEntry {
+    /// Path relative to the tree root, with `/` separators
+    pub path: String,
+    pub kind: EntryKind,
+    /// Path of the compressed file relative to the destination directory, empty for symlinks
+    pub compressed_path: String,
+    pub compression_type: CompressionType,
+    /// Uncompressed size
+    pub size: u64,
+    pub compressed_size: u64,
+    /// CRC-32 of the uncompressed content
+    pub crc32: u32,
+    /// Modification time since the Unix epoch, if recorded
+    pub modified: Option<Duration>,
+    /// Unix permission bits, if recorded
+    pub mode: Option<u32>,
+}
+
+/// Index of a tree compressed by `compress_tree`, entries sorted by path
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+pub struct Manifest {
+    pub entries: Vec<ManifestEntry>,
+}
+
+impl Manifest {
+    /// The manifest as pretty printed JSON, as written to `MANIFEST_FILE`
+    pub fn to_json(&self) -> String {
+        let mut out = String::from("{\n  \"entries\": [");
+        for (i, entry) in self.entries.iter().enumerate() {
+            out.push_str(if i == 0 { "\n" } else { ",\n" });
+            out.push_str("    {\n");
+            out.push_str(&format!("      \"path\": {},\n", json_string(&entry.path)));
+            match &entry.kind {
+                EntryKind::File => {
+                    out.push_str("      \"kind\": \"file\",\n");
+                    out.push_str(&format!("      \"compressed_path\": {},\n", json_string(&entry.compressed_path)));
+                    let codec = describe().codecs.into_iter().find(|c| c.compression_type == entry.compression_type);
+                    let codec = codec.map(|c| c.name).unwrap_or_else(|| format!("{:?}", entry.compression_type));
+                    out.push_str(&format!("      \"codec\": {},\n", json_string(&codec)));
+                    out.push_str(&format!("      \"size\": {},\n", entry.size));
+                    out.push_str(&format!("      \"compressed_size\": {},\n", entry.compressed_size));
+                    out.push_str(&format!("      \"crc32\": \"{:08x}\",\n", entry.crc32));
+                },
+                EntryKind::Symlink { target } => {
+                    out.push_str("      \"kind\": \"symlink\",\n");
+                    out.push_str(&format!("      \"target\": {},\n", json_string(target)));
+                },
+            }
+            let modified = entry.modified.map(|m| format!("{}.{:09}", m.as_secs(), m.subsec_nanos()));
+            out.push_str(&format!("      \"modified\": {},\n", modified.unwrap_or("null".into())));
+            out.push_str(&format!("      \"mode\": {}\n", entry.mode.map(|m| format!("{}", m)).unwrap_or("null".into())));
+            out.push_str("    }");
+        }
+        out.push_str(if self.entries.is_empty() { "]\n}\n" } else { "\n  ]\n}\n" });
+        return out;
+    }
+}
+
+/// Extension appended to compressed files: the codec's usual one, else its name
+fn extension(compression_type: CompressionType) -> String {
+    let codec = describe().codecs.into_iter().find(|c| c.compression_type == compression_type);
+    return match codec {
+        Some(codec) => codec.extensions.first().cloned().unwrap_or(codec.name),
+        None => format!("{:?}", compression_type).to_lowercase(),
+    };
+}
+
+/// Relative paths of the files and symlinks under `dir`, sorted, without following symlinks
+fn walk(root: &Path, dir: &Path, out: &mut Vec<PathBuf>) -> std::io::Result<()> {
+    let mut children: Vec<PathBuf> = std::fs::read_dir(dir)?.map(|e| e.map(|e| e.path())).collect::<Result<_, _>>()?;
+    children.sort();
+    for child in children {
+        if std::fs::symlink_metadata(&child)?.is_dir() {
+            walk(root, &child, out)?;
+        } else {
+            out.push(child.strip_prefix(root).unwrap().to_path_buf());
+        }
+    }
+    return Ok(());
+}
+
+fn slash_path(relative: &Path) -> String {
+    return relative.components().map(|c| c.as_os_str().to_string_lossy()).collect::<Vec<_>>().join("/");
+}
+
+/// Copies everything read, keeping its size and CRC-32
+struct Digesting<R: Read> {
+    inner: R,
+    crc: flate2::Crc,
+}
+
+impl<R: Read> Read for Digesting<R> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        let read = self.inner.read(buf)?;
+        self.crc.update(&buf[..read]);
+        return Ok(read);
+    }
+}
+
+#[cfg(unix)]
+fn mode_of(metadata: &std::fs::Metadata) -> Option<u32> {
+    use std::os::unix::fs::PermissionsExt;
+    return Some(metadata.permissions().mode() & 0o7777);
+}
+
+#[cfg(not(unix))]
+fn mode_of(_: &std::fs::Metadata) -> Option<u32> {
+    return None;
+}
+
+/// Compress every file under `src_dir` accepted by `filter` (given the path relative to
+/// `src_dir`) to its own file under `dst_dir`, at the same relative path with the codec
+/// extension appended (`foo.txt` becomes `foo.txt.zst`), and write the manifest of the tree to
+/// `dst_dir/manifest.json`.
+///
+/// `params` goes to `compressed_writer`, plus:
+///     preserve_metadata=true|false (record modification times and permissions, default true)
+///
+/// Symbolic links are recorded with their target and not followed. The compressed files and the
+/// manifest are written in one `commit::Transaction`: an interrupted run leaves every new file or
+/// none of them once `commit::recover_pending` ran on `dst_dir`, which `compress_tree` does first.
+/// It holds `commit::lock_dir` on `dst_dir` throughout, so concurrent runs wait for each other.
+pub fn compress_tree<T: Into<ParamSet>>(
+    src_dir: &Path,
+    dst_dir: &Path,
+    compression_type: CompressionType,
+    params: T,
+    filter: impl Fn(&Path) -> bool) -> Result<Manifest, Box<dyn Error>> {
+    return compress_tree_with_token(src_dir, dst_dir, compression_type, params, filter, &CancelToken::new());
+}
+
+/// `compress_tree`, stopped by `cancel` with `Cancelled` and the bytes of the whole tree read and
+/// written so far. The token is checked once per 64 KiB read; a cancelled run commits nothing.
+pub fn compress_tree_with_token<T: Into<ParamSet>>(
+    src_dir: &Path,
+    dst_dir: &Path,
+    compression_type: CompressionType,
+    params: T,
+    filter: impl Fn(&Path) -> bool,
+    cancel: &CancelToken) -> Result<Manifest, Box<dyn Error>> {
+    let param_set: ParamSet = params.into();
+    let preserve = param_set.get_flag("preserve_metadata", true)?;
+    let mut paths = Vec::new();
+    walk(src_dir, src_dir, &mut paths)?;
+    let _lock = commit::lock_dir(dst_dir)?;
+    commit::recover_pending(dst_dir)?;
+    let mut transaction = Transaction::begin(dst_dir)?;
+    let mut manifest = Manifest::default();
+    let (mut total_in, mut total_out) = (0u64, 0u64);
+    for relative in paths.into_iter().filter(|p| filter(p)) {
+        let source = src_dir.join(&relative);
+        let metadata = std::fs::symlink_metadata(&source)?;
+        let path = slash_path(&relative);
+        let modified = match preserve {
+            true => metadata.modified().ok().and_then(|m| m.duration_since(SystemTime::UNIX_EPOCH).ok()),
+            false => None,
+        };
+        let mode = if preserve { mode_of(&metadata) } else { None };
+        if metadata.file_type().is_symlink() {
+            let target = std::fs::read_link(&source)?.to_string_lossy().into_owned();
+            manifest.entries.push(ManifestEntry {
+                path,
+                kind: EntryKind::Symlink { target },
+                compressed_path: String::new(),
+                compression_type,
+                size: 0,
+                compressed_size: 0,
+                crc32: 0,
+                modified,
+                mode: None,
+            });
+            continue;
+        }
+        let compressed_path = format!("{}.{}", path, extension(compression_type));
+        let staged = transaction.stage(&dst_dir.join(&compressed_path))?;
+        let mut input = Digesting { inner: File::open(&source)?, crc: flate2::Crc::new() };
+        let (out, written) = CountingWriter::new(Box::new(File::create(&staged)?));
+        let mut writer = compressed_writer(Box::new(out), compression_type, param_set.clone())?;
+        let read = copy_with_progress(&mut input, &mut writer, &mut ProgressTracker::new(None),
+            |copied| (total_in + copied, total_out + written.load(Ordering::Relaxed)), &mut |_| {}, cancel)?;
+        total_in += read;
+        writer.flush()?;
+        drop(writer);
+        total_out += written.load(Ordering::Relaxed);
+        manifest.entries.push(ManifestEntry {
+            path,
+            kind: EntryKind::File,
+            compressed_path,
+            compression_type,
+            size: input.crc.amount() as u64,
+            compressed_size: std::fs::metadata(&staged)?.len(),
+            crc32: input.crc.sum(),
+            modified,
+            mode,
+        });
+    }
+    std::fs::write(transaction.stage(&dst_dir.join(MANIFEST_FILE))?, manifest.to_json())?;
+    transaction.commit()?;
+    return Ok(manifest);
+}
+
+/// Decompress `entry` from `dst_dir` into `out`, checking its size and CRC-32
+fn decompress_entry(dst_dir: &Path, entry: &ManifestEntry, out: &mut dyn Write) -> Result<(), Box<dyn Error>> {
+    let compressed = File::open(dst_dir.join(&entry.compressed_path)).map_err(|e| format!("{}: {}", entry.path, e))?;
+    let mut reader = Digesting { inner: decompressed_reader(Box::new(compressed), entry.compression_type)?, crc: flate2::Crc::new() };
+    std::io::copy(&mut reader, out).map_err(|e| format!("{}: {}", entry.path, e))?;
+    // amount() wraps at 4 GiB
+    if reader.crc.sum() != entry.crc32 || reader.crc.amount() != entry.size as u32 {
+        return Err(format!("{}: content does not match the manifest", entry.path).into());
+    }
+    return Ok(());
+}
+
+/// Check that every file of `manifest` decompresses from `dst_dir` to its recorded size and CRC-32
+pub fn verify_tree(dst_dir: &Path, manifest: &Manifest) -> Result<(), Box<dyn Error>> {
+    for entry in manifest.entries.iter().filter(|e| e.kind == EntryKind::File) {
+        decompress_entry(dst_dir, entry, &mut std::io::sink())?;
+    }
+    return Ok(());
+}
+
+/// Whether the `/` separated `path` matches `glob`: `*` matches within a path segment, `?` one
+/// character of a segment and `**` any number of whole segments.
+pub fn glob_matches(glob: &str, path: &str) -> bool {
+    fn segments(glob: &[&str], path: &[&str]) -> bool {
+        match glob.split_first() {
+            None => return path.is_empty(),
+            Some((&"**", rest)) => return (0..=path.len()).any(|skip| segments(rest, &path[skip..])),
+            Some((first, rest)) => return !path.is_empty() && segment(first.as_bytes(), path[0].as_bytes()) && segments(rest, &path[1..]),
+        }
+    }
+    fn segment(glob: &[u8], name: &[u8]) -> bool {
+        match glob.split_first() {
+            None => return name.is_empty(),
+            Some((b'*', rest)) => return (0..=name.len()).any(|skip| segment(rest, &name[skip..])),
+            Some((b'?', rest)) => return !name.is_empty() && segment(rest, &name[1..]),
+            Some((c, rest)) => return name.first() == Some(c) && segment(rest, &name[1..]),
+        }
+    }
+    let glob: Vec<&str> = glob.split('/').collect();
+    let path: Vec<&str> = path.split('/').collect();
+    return segments(&glob, &path);
+}
+
+/// Decompress the entries of `manifest` whose path matches `glob` (see `glob_matches`) from
+/// `dst_dir` to the same relative path under `out_dir`, restoring recorded modification times and
+/// permissions, and recreating symlinks on Unix. Returns the extracted paths.
+pub fn extract_matching(dst_dir: &Path, manifest: &Manifest, glob: &str, out_dir: &Path) -> Result<Vec<String>, Box<dyn Error>> {
+    return Ok(extract(dst_dir, manifest, glob, out_dir, None)?.0);
+}
+
+/// `extract_matching`, checking first that the filesystem of `out_dir` has room for the sizes the
+/// manifest records, and preallocating every file: fails with `preflight::InsufficientSpace`
+/// before extracting anything when it has not.
+pub fn extract_matching_with(dst_dir: &Path, manifest: &Manifest, glob: &str, out_dir: &Path, preflight: &Preflight) -> Result<(Vec<String>, SpaceReport), Box<dyn Error>> {
+    return extract(dst_dir, manifest, glob, out_dir, Some(preflight));
+}
+
+fn extract(dst_dir: &Path, manifest: &Manifest, glob: &str, out_dir: &Path, preflight: Option<&Preflight>) -> Result<(Vec<String>, SpaceReport), Box<dyn Error>> {
+    let mut report = SpaceReport::default();
+    if let Some(preflight) = preflight {
+        let needed = manifest.entries.iter().filter(|e| e.kind == EntryKind::File && glob_matches(glob, &e.path)).map(|e| e.size).sum();
+        std::fs::create_dir_all(out_dir)?;
+        report = preflight.check(out_dir, Some(needed))?;
+    }
+    let mut extracted = Vec::new();
+    for entry in manifest.entries.iter().filter(|e| glob_matches(glob, &e.path)) {
+        let target = out_dir.join(&entry.path);
+        std::fs::create_dir_all(target.parent().unwrap())?;
+        match &entry.kind {
+            EntryKind::Symlink { target: link } => {
+                #[cfg(unix)]
+                std::os::unix::fs::symlink(link, &target)?;
+                #[cfg(not(unix))]
+                let _ = link;
+            },
+            EntryKind::File => {
+                let mut file = File::create(&target)?;
+                if let Some(preflight) = preflight {
+                    preflight.preallocate(&file, entry.size, &mut report);
+                }
+                decompress_entry(dst_dir, entry, &mut file)?;
+                report.written += entry.size;
+                if let Some(modified) = entry.modified {
+                    file.set_modified(SystemTime::UNIX_EPOCH + modified)?;
+                }
+                #[cfg(unix)]
+                if let Some(mode) = entry.mode {
+                    use std::os::unix::fs::PermissionsExt;
+                    file.set_permissions(std::fs::Permissions::from_mode(mode))?;
+                }
+            },
+        }
+        extracted.push(entry.path.clone());
+    }
+    return Ok((extracted, report));
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    pub fn test_glob() {
+        assert!(glob_matches("*.txt", "a.txt"));
+        assert!(!glob_matches("*.txt", "sub/a.txt"));
+        assert!(glob_matches("**/*.txt", "a.txt"));
+        assert!(glob_matches("**/*.txt", "sub/deep/a.txt"));
+        assert!(glob_matches("sub/**", "sub/deep/a.log"));
+        assert!(glob_matches("sub/?.log", "sub/b.log"));
+        assert!(!glob_matches("sub/?.log", "sub/bb.log"));
+    }
+
+    #[test]
+    pub fn test_compress_verify_extract() {
+        let root = std::env::temp_dir().join(format!("final_compression_tree_{}", std::process::id()));
+        let (src, dst, out) = (root.join("src"), root.join("dst"), root.join("out"));
+        std::fs::create_dir_all(src.join("sub/deep")).unwrap();
+        let files: [(&str, Vec<u8>); 4] = [
+            ("a.txt", b"top level text\n".repeat(100)),
+            ("sub/b.log", b"log line\n".repeat(300)),
+            ("sub/deep/c.txt", (0..50_000u32).map(|i| (i % 253) as u8).collect()),
+            ("sub/skip.tmp", b"filtered out".to_vec()),
+        ];
+        for (path, content) in &files {
+            std::fs::write(src.join(path), content).unwrap();
+        }
+        let old = SystemTime::UNIX_EPOCH + Duration::from_secs(1_600_000_000);
+        File::options().write(true).open(src.join("sub/deep/c.txt")).unwrap().set_modified(old).unwrap();
+        #[cfg(unix)]
+        std::os::unix::fs::symlink("../a.txt", src.join("sub/link")).unwrap();
+
+        let filter = |p: &Path| p.extension().is_none_or(|e| e != "tmp");
+        let manifest = compress_tree(&src, &dst, CompressionType::Zstd, "level=3", filter).unwrap();
+        let paths: Vec<&str> = manifest.entries.iter().map(|e| e.path.as_str()).collect();
+        #[cfg(unix)]
+        assert_eq!(paths, ["a.txt", "sub/b.log", "sub/deep/c.txt", "sub/link"]);
+        assert_eq!(manifest.entries[2].compressed_path, "sub/deep/c.txt.zst");
+        assert_eq!(manifest.entries[2].size, 50_000);
+        assert!(dst.join("sub/deep/c.txt.zst").exists() && !dst.join("sub/skip.tmp.zst").exists());
+        let json = std::fs::read_to_string(dst.join(MANIFEST_FILE)).unwrap();
+        assert!(json.contains("\"path\": \"sub/deep/c.txt\"") && json.contains("\"codec\": \"zstd\""));
+        std::fs::remove_dir_all(&src).unwrap();
+
+        verify_tree(&dst, &manifest).unwrap();
+        let extracted = extract_matching(&dst, &manifest, "**/*.txt", &out).unwrap();
+        assert_eq!(extracted, ["a.txt", "sub/deep/c.txt"]);
+        assert_eq!(std::fs::read(out.join("sub/deep/c.txt")).unwrap(), files[2].1);
+        assert_eq!(std::fs::metadata(out.join("sub/deep/c.txt")).unwrap().modified().unwrap(), old);
+        assert!(!out.join("sub/b.log").exists());
+
+        std::fs::write(dst.join("sub/b.log.zst"), crate::buffer::SharedBuffer::default().take()).unwrap();
+        assert!(verify_tree(&dst, &manifest).unwrap_err().to_string().contains("sub/b.log"));
+        std::fs::remove_dir_all(&root).unwrap();
+    }
+}
@@ -0,0 +1,331 @@
+use std::error::Error;
+use std::io::{BufRead, BufReader, ErrorKind, Read};
+use std::sync::Arc;
+use std::sync::atomic::{AtomicU64, Ordering};
+use crate::{decompressed_reader_with, CompressionType, ParamSet};
+
+/// Default `max_output` of `decompressed_reader_untrusted`
+pub const UNTRUSTED_MAX_OUTPUT: u64 = 256 * 1024 * 1024;
+/// Default `max_ratio` of `decompressed_reader_untrusted`
+pub const UNTRUSTED_MAX_RATIO: u64 = 1000;
+/// Default `memory_limit` of `decompressed_reader_untrusted`
+pub const UNTRUSTED_MEMORY_LIMIT: u64 = 32 * 1024 * 1024;
+/// Default `max_block_size` of `decompressed_reader_untrusted`
+pub const UNTRUSTED_MAX_BLOCK_SIZE: usize = 4 * 1024 * 1024;
+/// Output produced before `max_ratio` is enforced, so small but very compressible inputs pass
+pub const RATIO_GRACE: u64 = 1024 * 1024;
+
+/// Protections active on a reader returned by `decompressed_reader_untrusted`, for logging.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Protections {
+    pub compression_type: CompressionType,
+    /// Decompressed bytes after which reading fails
+    pub max_output: Option<u64>,
+    /// Largest accepted decompressed/compressed ratio (enforced after `RATIO_GRACE` bytes)
+    pub max_ratio: Option<u64>,
+    /// Decoder memory cap. None if unlimited or if the format bounds memory by itself.
+    pub memory_limit: Option<u64>,
+    /// Largest accepted block of the crate's own framings
+    pub max_block_size: Option<usize>,
+    /// Whether data after the end of the compressed stream is an error
+    pub trailing_data_rejected: bool,
+    /// Whether a stream checksum is verified (when the stream carries one)
+    pub checksums_verified: bool,
+    /// Whether a truncated stream is an error (`eof_policy=strict`)
+    pub strict_eof: bool,
+}
+
+/// Decompression reader for input from untrusted sources, with every defensive option enabled.
+///
+/// Defaults, each of which can be overridden through `option`:
+/// - `eof_policy=strict`: truncated streams are errors
+/// - `max_output=268435456`: at most 256 MiB of output
+/// - `max_ratio=1000`: at most 1000 output bytes per input byte
+/// - `memory_limit=33554432`: zstd and xz decoders may use at most 32 MiB
+/// - `max_block_size=4194304`: stored blocks of at most 4 MiB
+/// - `trailing_data=error`: data after the compressed stream is an error
+///
+/// Headers and checksums are always verified where the format has them.
+pub fn decompressed_reader_untrusted<T: Into<ParamSet>>(
+    src: Box<dyn Read>,
+    compression_type: CompressionType,
+    option: T) -> Result<(Box<dyn Read>, Protections), Box<dyn Error>> {
+    let mut param_set: ParamSet = option.into();
+    for (key, value) in [
+        ("eof_policy", "strict".to_string()),
+        ("max_output", UNTRUSTED_MAX_OUTPUT.to_string()),
+        ("max_ratio", UNTRUSTED_MAX_RATIO.to_string()),
+        ("memory_limit", UNTRUSTED_MEMORY_LIMIT.to_string()),
+        ("max_block_size", UNTRUSTED_MAX_BLOCK_SIZE.to_string()),
+        ("trailing_data", "error".to_string()),
+    ] {
+        param_set.map.entry(key.into()).or_insert(value);
+    }
+    let limits = OutputLimits::from_params(&param_set)?;
+    let memory_limit = memory_limit(&param_set)?;
+    let protections = Protections {
+        compression_type,
+        max_output: limits.max_output,
+        max_ratio: limits.max_ratio,
+        memory_limit: memory_limit.filter(|_| matches!(compression_type, CompressionType::Zstd | CompressionType::XZ)),
+        max_block_size: match compression_type {
+            CompressionType::Stored => Some(param_set.get_size("max_block_size", crate::libstored::MAX_BLOCK_SIZE)?),
+            _ => None,
+        },
+        trailing_data_rejected: match compression_type {
+            CompressionType::Zstd | CompressionType::Gzip | CompressionType::Zlib | CompressionType::Deflate
+                | CompressionType::Bzip2 | CompressionType::XZ => trailing_rejected(&param_set)?,
+            _ => false,
+        },
+        checksums_verified: !matches!(compression_type, CompressionType::Deflate | CompressionType::Brotli | CompressionType::Lzo | CompressionType::None | CompressionType::Custom(_)),
+        strict_eof: crate::eof::EofPolicy::from_params(&param_set)?.is_strict(),
+    };
+    let reader = decompressed_reader_with(src, compression_type, param_set)?;
+    return Ok((reader, protections));
+}
+
+/// `memory_limit` parameter of the decoders
+pub(crate) fn memory_limit(param_set: &ParamSet) -> Result<Option<u64>, Box<dyn Error>> {
+    let limit: u64 = param_set.get_size("memory_limit", 0)?;
+    return Ok(Some(limit).filter(|limit| *limit > 0));
+}
+
+/// Largest zstd window log whose window fits into `limit` bytes
+pub(crate) fn zstd_window_log(limit: u64) -> u32 {
+    return (63 - limit.max(1).leading_zeros()).clamp(10, 31);
+}
+
+/// `trailing_data=ignore|error` parameter of the decoders. Unset, it is `error` when `padding`
+/// is set.
+pub(crate) fn trailing_rejected(param_set: &ParamSet) -> Result<bool, Box<dyn Error>> {
+    match param_set.get_string("trailing_data", "") {
+        "" => return Ok(zero_padding(param_set)?.is_some()),
+        "ignore" => return Ok(false),
+        "error" => return Ok(true),
+        other => return Err(format!("Invalid trailing_data `{}`, expected ignore or error", other).into()),
+    }
+}
+
+/// Block size of the `padding=zeros:<block_size>` parameter of the decoders: zero bytes after
+/// the stream are accepted when the source ends on a multiple of it, as tapes and raw block
+/// devices give archives back.
+pub(crate) fn zero_padding(param_set: &ParamSet) -> Result<Option<u64>, Box<dyn Error>> {
+    let value = param_set.get_string("padding", "");
+    if value.is_empty() {
+        return Ok(None);
+    }
+    let block_size = value.strip_prefix("zeros:").and_then(|size| crate::fmt::parse_bytes(size).ok())
+        .filter(|size| *size > 0)
+        .ok_or_else(|| format!("Invalid padding `{}`, expected zeros:<block_size>", value))?;
+    return Ok(Some(block_size));
+}
+
+/// `src` counting the bytes read from it
+pub(crate) fn count_input(src: Box<dyn Read>) -> (Box<dyn Read>, Arc<AtomicU64>) {
+    let count = Arc::new(AtomicU64::new(0));
+    return (Box::new(CountingReader { inner: src, count: count.clone() }), count);
+}
+
+/// Decoder adapter failing with `InvalidData` if its source holds more data once the decoder
+/// reached the end of the compressed stream, zero padding excepted.
+pub(crate) struct TrailingCheck<D> {
+    decoder: D,
+    source: fn(&mut D) -> &mut BufReader<Box<dyn Read>>,
+    /// Block size of accepted zero padding, and the source bytes read
+    padding: Option<(u64, Arc<AtomicU64>)>,
+}
+
+/// The decoder `decoder` makes of `src`, checked by a `TrailingCheck`
+pub(crate) fn reject_trailing<D: Read + 'static>(
+    src: Box<dyn Read>,
+    padding: Option<u64>,
+    decoder: impl FnOnce(BufReader<Box<dyn Read>>) -> D,
+    source: fn(&mut D) -> &mut BufReader<Box<dyn Read>>) -> Box<dyn Read> {
+    let (src, padding) = match padding {
+        Some(block_size) => {
+            let (src, count) = count_input(src);
+            (src, Some((block_size, count)))
+        },
+        None => (src, None),
+    };
+    return Box::new(TrailingCheck { decoder: decoder(BufReader::new(src)), source, padding });
+}
+
+impl<D: Read> Read for TrailingCheck<D> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        let read = self.decoder.read(buf)?;
+        if read == 0 && !buf.is_empty() {
+            let source = (self.source)(&mut self.decoder);
+            if source.fill_buf()?.is_empty() {
+                return Ok(0);
+            }
+            let Some((block_size, count)) = &self.padding else {
+                return Err(std::io::Error::new(ErrorKind::InvalidData, "trailing data after the compressed stream"));
+            };
+            loop {
+                let rest = source.fill_buf()?;
+                if rest.is_empty() {
+                    break;
+                }
+                if rest.iter().any(|b| *b != 0) {
+                    return Err(std::io::Error::new(ErrorKind::InvalidData, "trailing data after the compressed stream"));
+                }
+                let len = rest.len();
+                source.consume(len);
+            }
+            let total = count.load(Ordering::Relaxed);
+            if !total.is_multiple_of(*block_size) {
+                return Err(std::io::Error::new(ErrorKind::InvalidData,
+                    format!("{} bytes padded with zeros, not a multiple of the {} byte blocks", total, block_size)));
+            }
+        }
+        return Ok(read);
+    }
+}
+
+struct CountingReader {
+    inner: Box<dyn Read>,
+    count: Arc<AtomicU64>,
+}
+
+impl Read for CountingReader {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        let read = self.inner.read(buf)?;
+        self.count.fetch_add(read as u64, Ordering::Relaxed);
+        return Ok(read);
+    }
+}
+
+/// `max_output` and `max_ratio` parameters of the decoders
+pub(crate) struct OutputLimits {
+    max_output: Option<u64>,
+    max_ratio: Option<u64>,
+    input: Option<Arc<AtomicU64>>,
+}
+
+impl OutputLimits {
+    pub(crate) fn from_params(param_set: &ParamSet) -> Result<OutputLimits, Box<dyn Error>> {
+        let max_output: u64 = param_set.get_size("max_output", 0)?;
+        let max_ratio: u64 = param_set.get_integer("max_ratio", 0)?;
+        return Ok(OutputLimits {
+            max_output: Some(max_output).filter(|limit| *limit > 0),
+            max_ratio: Some(max_ratio).filter(|limit| *limit > 0),
+            input: None,
+        });
+    }
+
+    /// Count the compressed input if the ratio is limited
+    pub(crate) fn track_input(&mut self, src: Box<dyn Read>) -> Box<dyn Read> {
+        if self.max_ratio.is_none() {
+            return src;
+        }
+        let count = Arc::new(AtomicU64::new(0));
+        self.input = Some(count.clone());
+        return Box::new(CountingReader { inner: src, count });
+    }
+
+    pub(crate) fn apply(self, decoder: Box<dyn Read>) -> Box<dyn Read> {
+        if self.max_output.is_none() && self.max_ratio.is_none() {
+            return decoder;
+        }
+        return Box::new(LimitedReader { inner: decoder, limits: self, output: 0 });
+    }
+}
+
+struct LimitedReader {
+    inner: Box<dyn Read>,
+    limits: OutputLimits,
+    output: u64,
+}
+
+impl Read for LimitedReader {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        let read = self.inner.read(buf)?;
+        self.output += read as u64;
+        if let Some(max_output) = self.limits.max_output {
+            if self.output > max_output {
+                return Err(std::io::Error::new(ErrorKind::InvalidData,
+                    format!("decompressed output exceeds the limit of {} bytes", max_output)));
+            }
+        }
+        if let (Some(max_ratio), Some(input)) = (self.limits.max_ratio, &self.limits.input) {
+            let input = input.load(Ordering::Relaxed).max(1);
+            if self.output > RATIO_GRACE && self.output / input > max_ratio {
+                return Err(std::io::Error::new(ErrorKind::InvalidData,
+                    format!("compression ratio exceeds the limit of {}:1", max_ratio)));
+            }
+        }
+        return Ok(read);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::compress_bytes;
+
+    fn read_untrusted(ct: CompressionType, compressed: Vec<u8>, options: &str) -> std::io::Result<Vec<u8>> {
+        let (mut reader, _) = decompressed_reader_untrusted(Box::new(std::io::Cursor::new(compressed)), ct, options).unwrap();
+        let mut out = Vec::new();
+        reader.read_to_end(&mut out)?;
+        return Ok(out);
+    }
+
+    fn sample() -> Vec<u8> {
+        return (0..200_000u32).map(|i| ((i % 251) as u8) ^ ((i / 1000) as u8)).collect();
+    }
+
+    #[test]
+    pub fn test_normal_input_passes() {
+        let data = sample();
+        for ct in [CompressionType::Zstd, CompressionType::Snappy, CompressionType::Gzip, CompressionType::Zlib,
+            CompressionType::Deflate, CompressionType::Bzip2, CompressionType::LZ4, CompressionType::XZ,
+            CompressionType::Stored, CompressionType::Brotli, CompressionType::Lzo, CompressionType::None] {
+            assert_eq!(read_untrusted(ct, compress_bytes(&data, ct, "").unwrap(), "").unwrap(), data, "{:?}", ct);
+        }
+        let (_, protections) = decompressed_reader_untrusted(Box::new(std::io::empty()), CompressionType::XZ, "").unwrap();
+        assert_eq!(protections, Protections {
+            compression_type: CompressionType::XZ,
+            max_output: Some(UNTRUSTED_MAX_OUTPUT),
+            max_ratio: Some(UNTRUSTED_MAX_RATIO),
+            memory_limit: Some(UNTRUSTED_MEMORY_LIMIT),
+            max_block_size: None,
+            trailing_data_rejected: true,
+            checksums_verified: true,
+            strict_eof: true,
+        });
+    }
+
+    #[test]
+    pub fn test_bomb_rejected() {
+        let bomb = compress_bytes(&vec![0u8; 64 * 1024 * 1024], CompressionType::Zstd, "level=1").unwrap();
+        let err = read_untrusted(CompressionType::Zstd, bomb.clone(), "").unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::InvalidData);
+        assert!(err.to_string().contains("ratio"), "{}", err);
+        let err = read_untrusted(CompressionType::Zstd, bomb.clone(), "max_ratio=0;max_output=1000000").unwrap_err();
+        assert!(err.to_string().contains("exceeds the limit of 1000000 bytes"), "{}", err);
+        assert_eq!(read_untrusted(CompressionType::Zstd, bomb, "max_ratio=0").unwrap().len(), 64 * 1024 * 1024);
+    }
+
+    #[test]
+    pub fn test_memory_hungry_xz_rejected() {
+        let data = sample();
+        let compressed = compress_bytes(&data, CompressionType::XZ, "level=9").unwrap();
+        assert!(read_untrusted(CompressionType::XZ, compressed.clone(), "").is_err());
+        assert_eq!(read_untrusted(CompressionType::XZ, compressed, "memory_limit=134217728").unwrap(), data);
+    }
+
+    #[test]
+    pub fn test_trailing_garbage_rejected() {
+        let data = sample();
+        for ct in [CompressionType::Gzip, CompressionType::Bzip2, CompressionType::XZ, CompressionType::Zstd] {
+            let mut compressed = compress_bytes(&data, ct, "").unwrap();
+            compressed.extend_from_slice(b"garbage");
+            assert!(read_untrusted(ct, compressed.clone(), "").is_err(), "{:?}", ct);
+            // zstd and xz decoders fail on trailing data by themselves
+            if ct != CompressionType::Zstd && ct != CompressionType::XZ {
+                assert_eq!(read_untrusted(ct, compressed, "trailing_data=ignore").unwrap(), data, "{:?}", ct);
+            }
+        }
+    }
+}
@@ -0,0 +1,101 @@
+use std::io::{IoSliceMut, Read};
+
+/// Outermost wrapper of the decompression readers, filling every buffer passed to `read_vectored`.
+///
+/// The default `read_vectored` only reads into the first non-empty buffer, so a caller scattering
+/// into ring segments needs one call per segment. Here the decoder emits straight into each
+/// segment in order, without a staging copy, until a segment is left partially filled, which
+/// means no more output is ready.
+pub(crate) struct VectoredReader {
+    inner: Box<dyn Read>,
+}
+
+pub(crate) fn vectored_reader(inner: Box<dyn Read>) -> Box<dyn Read> {
+    return Box::new(VectoredReader { inner });
+}
+
+impl Read for VectoredReader {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        return self.inner.read(buf);
+    }
+
+    fn read_vectored(&mut self, bufs: &mut [IoSliceMut<'_>]) -> std::io::Result<usize> {
+        let mut total = 0;
+        for buf in bufs.iter_mut().filter(|b| !b.is_empty()) {
+            let read = match self.inner.read(buf) {
+                Ok(read) => read,
+                // the error comes back on the next call
+                Err(_) if total > 0 => return Ok(total),
+                Err(e) => return Err(e),
+            };
+            total += read;
+            if read < buf.len() {
+                break;
+            }
+        }
+        return Ok(total);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{compress_bytes, decompressed_reader, CompressionType};
+
+    /// Counts the `read_vectored` calls a consumer makes
+    struct Counting<'a> {
+        inner: &'a mut dyn Read,
+        calls: usize,
+    }
+
+    impl Counting<'_> {
+        fn fill(&mut self, bufs: &mut [IoSliceMut<'_>]) -> usize {
+            let wanted: usize = bufs.iter().map(|b| b.len()).sum();
+            let mut total = 0;
+            let mut bufs = bufs;
+            while total < wanted {
+                self.calls += 1;
+                let read = self.inner.read_vectored(bufs).unwrap();
+                assert!(read > 0);
+                total += read;
+                IoSliceMut::advance_slices(&mut bufs, read);
+            }
+            return total;
+        }
+    }
+
+    #[test]
+    pub fn test_fill_order() {
+        let data: Vec<u8> = (0..100u8).collect();
+        for ct in [CompressionType::Zstd, CompressionType::Gzip, CompressionType::LZ4, CompressionType::Snappy] {
+            let mut reader = decompressed_reader(Box::new(std::io::Cursor::new(compress_bytes(&data, ct, "").unwrap())), ct).unwrap();
+            let (mut a, mut b, mut c) = ([0u8; 7], [0u8; 13], [0u8; 5]);
+            let read = reader.read_vectored(&mut [IoSliceMut::new(&mut a), IoSliceMut::new(&mut b), IoSliceMut::new(&mut c)]).unwrap();
+            assert_eq!(read, 25, "{:?}", ct);
+            assert_eq!(a, data[..7]);
+            assert_eq!(b, data[7..20]);
+            assert_eq!(c, data[20..25]);
+        }
+    }
+
+    #[test]
+    pub fn test_fewer_calls_than_default() {
+        let data: Vec<u8> = (0..100_000u32).map(|i| (i % 251) as u8).collect();
+        let compressed = compress_bytes(&data, CompressionType::Zstd, "").unwrap();
+        let mut segments = vec![vec![0u8; 4096]; 3];
+
+        let mut reader = decompressed_reader(Box::new(std::io::Cursor::new(compressed.clone())), CompressionType::Zstd).unwrap();
+        let mut ours = Counting { inner: &mut reader, calls: 0 };
+        let mut slices: Vec<IoSliceMut> = segments.iter_mut().map(|s| IoSliceMut::new(s)).collect();
+        assert_eq!(ours.fill(&mut slices), 3 * 4096);
+        let ours_calls = ours.calls;
+        assert_eq!(segments.concat(), data[..3 * 4096]);
+
+        // the same decoder without the wrapper uses the default implementation
+        let mut plain = zstd::stream::read::Decoder::new(std::io::Cursor::new(compressed)).unwrap();
+        let mut default = Counting { inner: &mut plain, calls: 0 };
+        let mut slices: Vec<IoSliceMut> = segments.iter_mut().map(|s| IoSliceMut::new(s)).collect();
+        assert_eq!(default.fill(&mut slices), 3 * 4096);
+        assert!(ours_calls < default.calls, "{} vs {}", ours_calls, default.calls);
+    }
+}
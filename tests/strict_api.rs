@@ -0,0 +1,9 @@
+//! With the `strict-api` feature, the lenient `ParamSet` getters no longer exist.
+#![cfg(feature = "strict-api")]
+
+#[test]
+pub fn test_lenient_getters_removed() {
+    let cases = trybuild::TestCases::new();
+    cases.compile_fail("tests/ui/get_bool.rs");
+    cases.compile_fail("tests/ui/get_parse.rs");
+}
@@ -0,0 +1,6 @@
+use final_compression::ParamSet;
+
+fn main() {
+    let param_set = ParamSet::from("rsyncable=yes");
+    let _ = param_set.get_bool("rsyncable", false);
+}
@@ -0,0 +1,6 @@
+use final_compression::ParamSet;
+
+fn main() {
+    let param_set = ParamSet::from("timeout=soon");
+    let _: u64 = param_set.get_parse("timeout", 60);
+}